@@ -0,0 +1,180 @@
+//! Throughput and latency of the proxy, from the client codec through the
+//! session to a scripted backend, which is the mock server of the integration
+//! tests. Run with `cargo bench`, or `cargo bench -- <name>` for the scenarios
+//! whose names contain it.
+//!
+//! The latency that the proxy adds is that of a search that misses the cache,
+//! less that of the same search sent to the backend directly. Both are in the
+//! same group, so that criterion reports them side by side.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use common::MockAction;
+use criterion::{criterion_group, criterion_main, Criterion};
+use ldap3_proto::proto::*;
+use ldap_proxy::proxy::BasicLdapClient;
+use ldap_proxy::{AppState, DnConfig, DEFAULT_BACKEND};
+use tokio::runtime::Runtime;
+
+fn search(filter: LdapFilter) -> LdapSearchRequest {
+    LdapSearchRequest {
+        base: "o=example".to_string(),
+        scope: LdapSearchScope::Subtree,
+        aliases: LdapDerefAliases::Never,
+        sizelimit: 0,
+        timelimit: 0,
+        typesonly: false,
+        filter,
+        attrs: vec![],
+    }
+}
+
+fn uid(i: u64) -> LdapFilter {
+    LdapFilter::Equality("uid".to_string(), i.to_string())
+}
+
+async fn proxy_search(client: &mut common::TestClient, msgid: i32, sr: LdapSearchRequest) {
+    client.send(msgid, LdapOp::SearchRequest(sr)).await;
+    loop {
+        match client.recv().await.map(|msg| msg.op) {
+            Some(LdapOp::SearchResultDone(res)) => {
+                assert_eq!(res.code, LdapResultCode::Success);
+                return;
+            }
+            Some(LdapOp::SearchResultEntry(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
+
+// A proxy in front of a backend that returns one entry for every search.
+async fn proxy() -> Arc<AppState> {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| {
+            let LdapOp::SearchRequest(sr) = &msg.op else {
+                return MockAction::Disconnect;
+            };
+            MockAction::Reply(vec![
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: format!("uid=demo,{}", sr.base),
+                        attributes: vec![LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec![b"Demo User".to_vec()],
+                        }],
+                    }),
+                    ctrl: vec![],
+                },
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                },
+            ])
+        }),
+    )
+    .await;
+    let binddn_map = BTreeMap::from([("cn=bench".to_string(), DnConfig::default())]);
+    Arc::new(common::app_state(addr, connector, binddn_map))
+}
+
+fn bench_proxy(c: &mut Criterion) {
+    let rt = Runtime::new().expect("runtime");
+    let app_state = rt.block_on(proxy());
+
+    let backend = rt.block_on(async {
+        let pool = &app_state.backend_pools[DEFAULT_BACKEND];
+        let client = BasicLdapClient::connect(&app_state, pool)
+            .await
+            .expect("connect");
+        let lbr = LdapBindRequest {
+            dn: "cn=bench".to_string(),
+            cred: LdapBindCred::Simple("password".to_string()),
+        };
+        client.bind(lbr, vec![]).await.expect("bind");
+        client
+    });
+    let mut client = rt.block_on(async {
+        let mut client = common::connect(app_state.clone());
+        assert_eq!(client.bind(1, "cn=bench").await, LdapResultCode::Success);
+        client
+    });
+    let mut msgid = 1;
+    // Each filter is new, so that every search misses the cache.
+    let mut next_uid = 0;
+
+    let mut group = c.benchmark_group("proxy");
+    group.bench_function("backend search", |b| {
+        b.iter_custom(|iters| {
+            rt.block_on(async {
+                let started = Instant::now();
+                for _ in 0..iters {
+                    next_uid += 1;
+                    let results = backend.search(search(uid(next_uid)), vec![], None, None);
+                    assert!(results.await.is_ok());
+                }
+                started.elapsed()
+            })
+        })
+    });
+
+    group.bench_function("bind", |b| {
+        b.iter_custom(|iters| {
+            rt.block_on(async {
+                let started = Instant::now();
+                for _ in 0..iters {
+                    msgid += 1;
+                    let code = client.bind(msgid, "cn=bench").await;
+                    assert_eq!(code, LdapResultCode::Success);
+                }
+                started.elapsed()
+            })
+        })
+    });
+
+    group.bench_function("search, cache miss", |b| {
+        b.iter_custom(|iters| {
+            rt.block_on(async {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    msgid += 1;
+                    next_uid += 1;
+                    let sr = search(uid(next_uid));
+                    let started = Instant::now();
+                    proxy_search(&mut client, msgid, sr).await;
+                    elapsed += started.elapsed();
+                }
+                elapsed
+            })
+        })
+    });
+
+    group.bench_function("search, cache hit", |b| {
+        let filter = LdapFilter::Present("objectClass".to_string());
+        b.iter_custom(|iters| {
+            rt.block_on(async {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    msgid += 1;
+                    let sr = search(filter.clone());
+                    let started = Instant::now();
+                    proxy_search(&mut client, msgid, sr).await;
+                    elapsed += started.elapsed();
+                }
+                elapsed
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_proxy);
+criterion_main!(benches);
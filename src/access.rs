@@ -0,0 +1,60 @@
+//! The decision of who may bind and what they may search for, for programs
+//! that embed the proxy as a library and authorize clients some other way,
+//! such as by asking an external service. The policy is consulted after the
+//! restrictions of the config have been checked, so it can refuse what they
+//! would allow but never allow what they refuse.
+
+use std::future::{ready, Future};
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use ldap3_proto::proto::LdapSearchRequest;
+
+use crate::DnConfig;
+
+/// The future of a decision. The policy may await other services to make it.
+pub type DecisionFuture<'a> = Pin<Box<dyn Future<Output = Decision> + Send + 'a>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    /// Refuse with insufficientAccessRights, and this message.
+    Deny(String),
+}
+
+/// A bind that the config allows.
+#[derive(Debug, Clone, Copy)]
+pub struct BindAccess<'a> {
+    /// The normalised bind DN.
+    pub dn: &'a str,
+    pub client_address: SocketAddr,
+    pub config: &'a DnConfig,
+}
+
+/// A search that the config allows, before its limits are capped and its
+/// attributes restricted.
+#[derive(Debug, Clone, Copy)]
+pub struct SearchAccess<'a> {
+    /// The normalised DN of the session.
+    pub dn: &'a str,
+    pub config: &'a DnConfig,
+    pub request: &'a LdapSearchRequest,
+}
+
+/// Decides binds and searches. Both are allowed unless the policy overrides
+/// them.
+pub trait AccessPolicy: Send + Sync {
+    fn bind<'a>(&'a self, _bind: BindAccess<'a>) -> DecisionFuture<'a> {
+        Box::pin(ready(Decision::Allow))
+    }
+
+    fn search<'a>(&'a self, _search: SearchAccess<'a>) -> DecisionFuture<'a> {
+        Box::pin(ready(Decision::Allow))
+    }
+}
+
+/// The default policy, which leaves every decision to the config.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConfigAccessPolicy;
+
+impl AccessPolicy for ConfigAccessPolicy {}
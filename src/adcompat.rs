@@ -0,0 +1,75 @@
+//! The reasons that Active Directory gives for refusing a bind. AD answers
+//! every refused simple bind with invalidCredentials, and puts the reason in
+//! its diagnostic message as a hex code after "data", as in
+//! `80090308: LdapErr: DSID-0C09044E, comment: AcceptSecurityContext error, data 52e, v4563`.
+//!
+//! With ad_compat the reason is written to the audit log and counted, and the
+//! client is sent a plain message in place of the diagnostic one. A user that
+//! doesn't exist is reported to the client as bad credentials, so that the
+//! message can't be used to find out which accounts exist.
+
+/// Why AD refused a bind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdBindFailure {
+    NoSuchUser,
+    InvalidCredentials,
+    /// Outside the account's logon hours.
+    LogonHours,
+    /// From a workstation that the account may not log on from.
+    Workstation,
+    PasswordExpired,
+    AccountDisabled,
+    AccountExpired,
+    PasswordMustChange,
+    AccountLocked,
+}
+
+impl AdBindFailure {
+    /// The reason in the diagnostic message of a refused bind, if it has one
+    /// that is known.
+    pub fn parse(message: &str) -> Option<Self> {
+        let (_, rest) = message.split_once("data ")?;
+        let code = rest.split(|c: char| !c.is_ascii_hexdigit()).next()?;
+        match code.to_ascii_lowercase().as_str() {
+            "525" => Some(AdBindFailure::NoSuchUser),
+            "52e" => Some(AdBindFailure::InvalidCredentials),
+            "530" => Some(AdBindFailure::LogonHours),
+            "531" => Some(AdBindFailure::Workstation),
+            "532" => Some(AdBindFailure::PasswordExpired),
+            "533" => Some(AdBindFailure::AccountDisabled),
+            "701" => Some(AdBindFailure::AccountExpired),
+            "773" => Some(AdBindFailure::PasswordMustChange),
+            "775" => Some(AdBindFailure::AccountLocked),
+            _ => None,
+        }
+    }
+
+    /// The name of the reason, for the audit log and metrics.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            AdBindFailure::NoSuchUser => "no_such_user",
+            AdBindFailure::InvalidCredentials => "invalid_credentials",
+            AdBindFailure::LogonHours => "logon_hours",
+            AdBindFailure::Workstation => "workstation",
+            AdBindFailure::PasswordExpired => "password_expired",
+            AdBindFailure::AccountDisabled => "account_disabled",
+            AdBindFailure::AccountExpired => "account_expired",
+            AdBindFailure::PasswordMustChange => "password_must_change",
+            AdBindFailure::AccountLocked => "account_locked",
+        }
+    }
+
+    /// The message that the client is sent.
+    pub fn message(&self) -> &'static str {
+        match self {
+            AdBindFailure::NoSuchUser | AdBindFailure::InvalidCredentials => "invalid credentials",
+            AdBindFailure::LogonHours => "logon is not permitted at this time",
+            AdBindFailure::Workstation => "logon is not permitted from this workstation",
+            AdBindFailure::PasswordExpired => "password expired",
+            AdBindFailure::AccountDisabled => "account disabled",
+            AdBindFailure::AccountExpired => "account expired",
+            AdBindFailure::PasswordMustChange => "password must be changed",
+            AdBindFailure::AccountLocked => "account locked",
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//! An HTTP API for operators, on an address of its own, for looking inside a
+//! running proxy and acting on it. Every request must carry the admin token as
+//! `Authorization: Bearer <token>`, and every response is JSON, except the
+//! cache as LDIF.
+//!
+//! * `GET /sessions` lists the live client sessions and their bind DNs.
+//! * `POST /sessions/<id>/disconnect` ends a session.
+//! * `GET /cache` shows the size of the cache, and its hits and misses.
+//! * `GET /cache/ldif` writes every cached search and its entries as LDIF.
+//! * `POST /cache/flush` removes every cached search.
+//! * `GET /backends` shows each backend address, if it is in rotation, and how
+//!   quickly it connects, completes its handshake and answers binds.
+//! * `GET /metrics` shows every metric.
+//!
+//! Only enough of HTTP/1.1 is spoken for tools such as curl: one request per
+//! connection, without a body.
+
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::{debug, info};
+
+use crate::config::Secret;
+use crate::latency::{publish_latency, STAGES};
+use crate::ldif::cache_ldif;
+use crate::AppState;
+
+/// Requests with a longer head than this are refused.
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// The time a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) struct Request {
+    pub method: String,
+    pub path: String,
+    authorization: Option<String>,
+}
+
+// Read the request line and headers. None if the request is not one that can
+// be answered.
+pub(crate) async fn read_request<R: AsyncRead + Unpin>(r: R) -> Option<Request> {
+    let mut r = BufReader::new(r).take(MAX_REQUEST_BYTES as u64);
+    let mut line = String::new();
+    r.read_line(&mut line).await.ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+    parts
+        .next()
+        .filter(|version| version.starts_with("HTTP/"))?;
+
+    let mut authorization = None;
+    loop {
+        line.clear();
+        if r.read_line(&mut line).await.ok()? == 0 {
+            // The head ended early, or was too long.
+            return None;
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+    Some(Request {
+        method,
+        path,
+        authorization,
+    })
+}
+
+// Compare in time that doesn't depend on where the values differ.
+fn token_matches(authorization: Option<&str>, token: &Secret) -> bool {
+    let Some(presented) = authorization.and_then(|value| value.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    let (presented, token) = (presented.as_bytes(), token.expose().as_bytes());
+    presented.len() == token.len()
+        && presented
+            .iter()
+            .zip(token)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn backends(app_state: &AppState) -> Value {
+    let pools: serde_json::Map<String, Value> = app_state
+        .backend_pools
+        .iter()
+        .map(|(name, pool)| {
+            let backends: Vec<Value> = pool
+                .backends
+                .iter()
+                .map(|backend| {
+                    let addrs: Vec<Value> = backend
+                        .addrs()
+                        .iter()
+                        .map(|addr| {
+                            let latency = backend.latency().get(addr).unwrap_or_default();
+                            let stages: serde_json::Map<String, Value> = STAGES
+                                .iter()
+                                .map(|stage| {
+                                    let (attempts, errors) = latency.counts(*stage);
+                                    let stats = json!({
+                                        "average_us": latency.average_us(*stage).map(|us| us as u64),
+                                        "attempts": attempts,
+                                        "errors": errors,
+                                    });
+                                    (stage.name().to_string(), stats)
+                                })
+                                .collect();
+                            json!({
+                                "address": addr.to_string(),
+                                "available": app_state.breakers.allow(addr),
+                                "healthy": !app_state.breakers.is_unhealthy(addr),
+                                "latency": stages,
+                                "error_rate": latency.error_rate,
+                            })
+                        })
+                        .collect();
+                    json!({
+                        "url": backend.url.to_string(),
+                        "open_connections": backend
+                            .open_connections()
+                            .load(std::sync::atomic::Ordering::Relaxed),
+                        "addresses": addrs,
+                    })
+                })
+                .collect();
+            (name.clone(), Value::Array(backends))
+        })
+        .collect();
+    Value::Object(pools)
+}
+
+fn cache(app_state: &AppState) -> Value {
+    // Nothing is changed, so this is not committed.
+    let (entries, bytes) = app_state
+        .cache
+        .write()
+        .iter()
+        .fold((0, 0), |(entries, bytes), (_, v)| {
+            (entries + 1, bytes + v.size())
+        });
+    json!({
+        "entries": entries,
+        "bytes": bytes,
+        "hits": app_state.metrics.get("cache_hits_total", &[]),
+        "misses": app_state.metrics.get("cache_misses_total", &[]),
+    })
+}
+
+fn flush_cache(app_state: &AppState) -> Value {
+    let mut cache_write_txn = app_state.cache.write();
+    let entries = cache_write_txn.iter().count();
+    cache_write_txn.clear();
+    cache_write_txn.commit();
+    app_state.cache_index.clear();
+    info!("Flushed {} cached searches by request", entries);
+    json!({ "flushed": entries })
+}
+
+fn disconnect(app_state: &AppState, id: &str) -> (u16, Value) {
+    match id.parse() {
+        Ok(id) if app_state.sessions.disconnect(id) => {
+            info!(session = id, "Disconnecting session by request");
+            (200, json!({ "disconnected": id }))
+        }
+        _ => (404, json!({ "error": "no such session" })),
+    }
+}
+
+// The segments of a path, without its query.
+pub(crate) fn path_segments(path: &str) -> Vec<&str> {
+    let path = path.split('?').next().unwrap_or_default();
+    path.split('/').filter(|s| !s.is_empty()).collect()
+}
+
+// The body of a response.
+enum Body {
+    Json(Value),
+    Ldif(String),
+}
+
+fn route(app_state: &AppState, method: &str, path: &str) -> (u16, Body) {
+    let segments = path_segments(path);
+    let (status, body) = match (method, segments.as_slice()) {
+        ("GET", ["sessions"]) => (200, json!(app_state.sessions.list())),
+        ("POST", ["sessions", id, "disconnect"]) => disconnect(app_state, id),
+        ("GET", ["cache"]) => (200, cache(app_state)),
+        ("GET", ["cache", "ldif"]) => return (200, Body::Ldif(cache_ldif(app_state))),
+        ("POST", ["cache", "flush"]) => (200, flush_cache(app_state)),
+        ("GET", ["backends"]) => (200, backends(app_state)),
+        ("GET", ["metrics"]) => {
+            publish_latency(app_state);
+            (200, json!(app_state.metrics.snapshot()))
+        }
+        (_, ["sessions"] | ["sessions", _, "disconnect"] | ["cache"] | ["cache", "flush"])
+        | (_, ["cache", "ldif"] | ["backends"] | ["metrics"]) => {
+            (405, json!({ "error": "method not allowed" }))
+        }
+        _ => (404, json!({ "error": "not found" })),
+    };
+    (status, Body::Json(body))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+/// Answer one request on a connection to the admin API.
+pub async fn admin_process<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    app_state: &AppState,
+    token: &Secret,
+) {
+    let (r, mut w) = tokio::io::split(stream);
+    let (status, body) = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(r)).await {
+        Ok(Some(request)) if token_matches(request.authorization.as_deref(), token) => {
+            debug!(method = %request.method, path = %request.path, "Admin request");
+            route(app_state, &request.method, &request.path)
+        }
+        Ok(Some(_)) => (401, Body::Json(json!({ "error": "unauthorized" }))),
+        Ok(None) => (400, Body::Json(json!({ "error": "bad request" }))),
+        Err(_) => return,
+    };
+    match body {
+        Body::Json(body) => write_response(&mut w, status, &body).await,
+        Body::Ldif(body) => write_body(&mut w, status, "text/plain; charset=utf-8", &body).await,
+    }
+}
+
+/// Send a JSON response, and close the connection.
+pub(crate) async fn write_response<W: AsyncWrite + Unpin>(w: &mut W, status: u16, body: &Value) {
+    write_body(w, status, "application/json", &body.to_string()).await;
+}
+
+async fn write_body<W: AsyncWrite + Unpin>(w: &mut W, status: u16, content_type: &str, body: &str) {
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        reason(status),
+        content_type,
+        body.len()
+    );
+    if status == 401 {
+        response.push_str("WWW-Authenticate: Bearer\r\n");
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    let _ = w.write_all(response.as_bytes()).await;
+    let _ = w.shutdown().await;
+}
@@ -0,0 +1,149 @@
+//! Rewriting of the attributes of search results for a DN, so that applications
+//! written for one kind of directory can use another. An attribute can be
+//! renamed, such as `sAMAccountName` to `uid`, and its values rewritten by a
+//! regex, such as to change the domain of `mail`.
+//!
+//! Renamed attributes are named as the backend knows them in the requested
+//! attributes and the filters of searches, so a client that asks for `uid`
+//! gets the `sAMAccountName` of each entry, under the name `uid`.
+
+use ldap3_proto::proto::{LdapSearchRequest, LdapSearchResultEntry};
+use ldap3_proto::LdapFilter;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+#[derive(Debug, Deserialize)]
+struct AttrRewriteConfig {
+    attribute: String,
+    rename: Option<String>,
+    regex: Option<String>,
+    replace: Option<String>,
+}
+
+/// A rewrite of one attribute of search results.
+#[derive(Debug, Clone)]
+pub struct AttrRewrite {
+    attribute: String,
+    rename: Option<String>,
+    // The regex, and the template that its matches are replaced with, in which
+    // `$1` or `${name}` are the groups it captured.
+    regex: Option<(Regex, String)>,
+}
+
+// Split the options, such as ";binary", from an attribute.
+fn split_options(atype: &str) -> (&str, &str) {
+    match atype.find(';') {
+        Some(idx) => atype.split_at(idx),
+        None => (atype, ""),
+    }
+}
+
+impl AttrRewrite {
+    fn compile(config: AttrRewriteConfig) -> Result<Self, String> {
+        let regex = match (config.regex, config.replace) {
+            (Some(regex), Some(replace)) => {
+                let regex = Regex::new(&regex).map_err(|e| {
+                    format!("invalid regex for attribute '{}': {}", config.attribute, e)
+                })?;
+                Some((regex, replace))
+            }
+            (None, None) => None,
+            _ => {
+                return Err(format!(
+                    "the rewrite of attribute '{}' needs both regex and replace",
+                    config.attribute
+                ))
+            }
+        };
+        if config.rename.is_none() && regex.is_none() {
+            return Err(format!(
+                "the rewrite of attribute '{}' needs rename, or regex and replace",
+                config.attribute
+            ));
+        }
+        Ok(AttrRewrite {
+            attribute: config.attribute,
+            rename: config.rename,
+            regex,
+        })
+    }
+}
+
+/// Apply the rewrites, in order, to the attributes of an entry.
+pub fn rewrite_entry(rewrites: &[AttrRewrite], entry: &mut LdapSearchResultEntry) {
+    for rewrite in rewrites {
+        for attr in entry.attributes.iter_mut() {
+            let (name, options) = split_options(&attr.atype);
+            if !name.eq_ignore_ascii_case(&rewrite.attribute) {
+                continue;
+            }
+            if let Some(rename) = rewrite.rename.as_ref() {
+                attr.atype = format!("{}{}", rename, options);
+            }
+            if let Some((regex, replace)) = rewrite.regex.as_ref() {
+                for value in attr.vals.iter_mut() {
+                    if let Ok(text) = std::str::from_utf8(value) {
+                        *value = regex.replace_all(text, replace).into_owned().into_bytes();
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The backend's name for an attribute that the client named, undoing the
+// renames in reverse order.
+fn backend_attr(rewrites: &[AttrRewrite], atype: &mut String) {
+    for rewrite in rewrites.iter().rev() {
+        let (name, options) = split_options(atype);
+        if rewrite
+            .rename
+            .as_ref()
+            .is_some_and(|rename| rename.eq_ignore_ascii_case(name))
+        {
+            *atype = format!("{}{}", rewrite.attribute, options);
+        }
+    }
+}
+
+fn backend_filter(rewrites: &[AttrRewrite], filter: &mut LdapFilter) {
+    match filter {
+        LdapFilter::And(filters) | LdapFilter::Or(filters) => {
+            for filter in filters.iter_mut() {
+                backend_filter(rewrites, filter);
+            }
+        }
+        LdapFilter::Not(filter) => backend_filter(rewrites, filter),
+        LdapFilter::Equality(atype, _)
+        | LdapFilter::GreaterOrEqual(atype, _)
+        | LdapFilter::LessOrEqual(atype, _)
+        | LdapFilter::Approx(atype, _)
+        | LdapFilter::Substring(atype, _)
+        | LdapFilter::Present(atype) => backend_attr(rewrites, atype),
+        LdapFilter::Extensible(assertion) => {
+            if let Some(atype) = assertion.type_.as_mut() {
+                backend_attr(rewrites, atype);
+            }
+        }
+    }
+}
+
+/// Name renamed attributes as the backend knows them, in the attributes and
+/// filter of a search. Rewritten values are not changed back, so filters on
+/// them may not match.
+pub fn rewrite_search(rewrites: &[AttrRewrite], sr: &mut LdapSearchRequest) {
+    for atype in sr.attrs.iter_mut() {
+        backend_attr(rewrites, atype);
+    }
+    backend_filter(rewrites, &mut sr.filter);
+}
+
+impl<'de> Deserialize<'de> for AttrRewrite {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let config = AttrRewriteConfig::deserialize(deserializer)?;
+        AttrRewrite::compile(config).map_err(serde::de::Error::custom)
+    }
+}
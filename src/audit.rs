@@ -0,0 +1,419 @@
+//! An audit log of client operations, for environments that must keep a record
+//! of who read and changed what. Each operation is written once it completes,
+//! as one JSON object per line, to a file or to the local syslog.
+//!
+//! Records are written by a thread of their own, so that a slow disk doesn't
+//! hold up sessions. The values of search filters can be redacted, as they
+//! often name the people being looked up, and the same redaction can be
+//! applied to the filters that the proxy logs.
+
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use hashbrown::HashMap;
+use ldap3_proto::proto::{LdapMsg, LdapOp, LdapResult, LdapSearchScope};
+use ldap3_proto::LdapFilter;
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+/// The syslog socket of most unix systems.
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+// The authpriv facility, at the info level.
+const SYSLOG_PRIORITY: u8 = 10 * 8 + 6;
+
+/// How the values of search filters are written to logs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterValues {
+    /// As they are.
+    #[default]
+    Plain,
+    /// Each replaced with `*`.
+    Mask,
+    /// Each replaced with the start of its SHA-256 hash, after `#`, so that
+    /// searches for the same value can be matched up.
+    Hash,
+    /// Only the first few characters of each, followed by `...`.
+    Truncate,
+}
+
+// The characters of a value that are kept when it is truncated.
+const TRUNCATED_LENGTH: usize = 3;
+
+impl FilterValues {
+    fn write(&self, value: &str) -> String {
+        match self {
+            FilterValues::Plain => escape_value(value),
+            FilterValues::Mask => "*".to_string(),
+            FilterValues::Hash => {
+                let hash: String = sha256(value.as_bytes())[..8]
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+                format!("#{}", hash)
+            }
+            FilterValues::Truncate if value.chars().count() <= TRUNCATED_LENGTH => {
+                escape_value(value)
+            }
+            FilterValues::Truncate => {
+                let start: String = value.chars().take(TRUNCATED_LENGTH).collect();
+                format!("{}...", escape_value(&start))
+            }
+        }
+    }
+}
+
+/// Where audit records are written. One of path or syslog must be set.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuditConfig {
+    /// Append records to this file.
+    pub path: Option<PathBuf>,
+    /// Send records to the local syslog, with the authpriv facility.
+    #[serde(default)]
+    pub syslog: bool,
+    /// Replace the values of search filters with `*`.
+    #[serde(default)]
+    pub redact_filter_values: bool,
+    /// How the values of search filters are written, which overrides
+    /// redact_filter_values.
+    pub filter_values: Option<FilterValues>,
+}
+
+// Sends each record as a datagram to syslog.
+struct Syslog(UnixDatagram);
+
+impl Write for Syslog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = String::from_utf8_lossy(buf);
+        let message = format!("<{}>ldap-proxy: {}", SYSLOG_PRIORITY, line.trim_end());
+        self.0.send(message.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct AuditLog {
+    tx: mpsc::Sender<String>,
+    filter_values: FilterValues,
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditConfig) -> io::Result<Self> {
+        let writer: Box<dyn Write + Send> = match (&config.path, config.syslog) {
+            (Some(path), false) => Box::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            ),
+            (None, true) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(SYSLOG_SOCKET)?;
+                Box::new(Syslog(socket))
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the audit log needs one of path or syslog",
+                ))
+            }
+        };
+        let filter_values = config
+            .filter_values
+            .unwrap_or(if config.redact_filter_values {
+                FilterValues::Mask
+            } else {
+                FilterValues::Plain
+            });
+        Ok(Self::to_writer(writer, filter_values))
+    }
+
+    /// Write the records, one per line, to this writer.
+    pub fn to_writer(mut writer: Box<dyn Write + Send>, filter_values: FilterValues) -> Self {
+        let (tx, rx) = mpsc::channel::<String>();
+        std::thread::spawn(move || {
+            for line in rx {
+                let written = writer
+                    .write_all(line.as_bytes())
+                    .and_then(|_| writer.flush());
+                if let Err(e) = written {
+                    error!(?e, "Unable to write to the audit log");
+                }
+            }
+        });
+        AuditLog { tx, filter_values }
+    }
+
+    fn write(&self, record: &AuditRecord) {
+        match serde_json::to_string(record) {
+            Ok(mut line) => {
+                line.push('\n');
+                // This only fails once the writer can't write any more.
+                let _ = self.tx.send(line);
+            }
+            Err(e) => error!(?e, "Unable to serialise an audit record"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AuditRecord<'a> {
+    // Seconds since the unix epoch.
+    time: f64,
+    client: String,
+    bind_dn: &'a str,
+    msgid: i32,
+    op: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dn: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    oid: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entries: Option<usize>,
+    duration_ms: f64,
+    cache_hit: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+}
+
+// An operation that has started, but not yet completed.
+struct Pending {
+    started: Instant,
+    bind_dn: String,
+    op: &'static str,
+    dn: Option<String>,
+    scope: Option<&'static str>,
+    filter: Option<String>,
+    oid: Option<String>,
+    entries: usize,
+    cache_hit: bool,
+    reason: Option<&'static str>,
+}
+
+fn escape_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '*' => escaped.push_str("\\2a"),
+            '(' => escaped.push_str("\\28"),
+            ')' => escaped.push_str("\\29"),
+            '\\' => escaped.push_str("\\5c"),
+            '\0' => escaped.push_str("\\00"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Write a filter in the string form of RFC 4515, with each value replaced by
+/// `*` if it is redacted.
+pub fn filter_string(filter: &LdapFilter, redact: bool) -> String {
+    let values = if redact {
+        FilterValues::Mask
+    } else {
+        FilterValues::Plain
+    };
+    redacted_filter_string(filter, values)
+}
+
+/// Write a filter in the string form of RFC 4515, with each value written as
+/// `values` says.
+pub fn redacted_filter_string(filter: &LdapFilter, values: FilterValues) -> String {
+    let value = |v: &str| values.write(v);
+    match filter {
+        LdapFilter::And(filters) => format!(
+            "(&{})",
+            filters
+                .iter()
+                .map(|f| redacted_filter_string(f, values))
+                .collect::<String>()
+        ),
+        LdapFilter::Or(filters) => format!(
+            "(|{})",
+            filters
+                .iter()
+                .map(|f| redacted_filter_string(f, values))
+                .collect::<String>()
+        ),
+        LdapFilter::Not(filter) => format!("(!{})", redacted_filter_string(filter, values)),
+        LdapFilter::Equality(a, v) => format!("({}={})", a, value(v)),
+        LdapFilter::GreaterOrEqual(a, v) => format!("({}>={})", a, value(v)),
+        LdapFilter::LessOrEqual(a, v) => format!("({}<={})", a, value(v)),
+        LdapFilter::Approx(a, v) => format!("({}~={})", a, value(v)),
+        LdapFilter::Present(a) => format!("({}=*)", a),
+        LdapFilter::Substring(a, sub) => {
+            let mut parts = vec![sub.initial.as_deref().map(value).unwrap_or_default()];
+            parts.extend(sub.any.iter().map(|v| value(v)));
+            parts.push(sub.final_.as_deref().map(value).unwrap_or_default());
+            format!("({}={})", a, parts.join("*"))
+        }
+        LdapFilter::Extensible(assertion) => format!(
+            "({}{}{}:={})",
+            assertion.type_.as_deref().unwrap_or_default(),
+            if assertion.dn_attributes { ":dn" } else { "" },
+            assertion
+                .matching_rule
+                .as_deref()
+                .map(|rule| format!(":{}", rule))
+                .unwrap_or_default(),
+            value(&assertion.match_value)
+        ),
+    }
+}
+
+pub(crate) fn scope_name(scope: &LdapSearchScope) -> &'static str {
+    match scope {
+        LdapSearchScope::Base => "base",
+        LdapSearchScope::OneLevel => "one",
+        LdapSearchScope::Subtree => "sub",
+        LdapSearchScope::Children => "children",
+    }
+}
+
+// The result of a final response, or None if more responses will follow.
+fn final_result(op: &LdapOp) -> Option<&LdapResult> {
+    match op {
+        LdapOp::BindResponse(resp) => Some(&resp.res),
+        LdapOp::ExtendedResponse(resp) => Some(&resp.res),
+        LdapOp::SearchResultDone(result)
+        | LdapOp::ModifyResponse(result)
+        | LdapOp::AddResponse(result)
+        | LdapOp::DelResponse(result)
+        | LdapOp::ModifyDNResponse(result)
+        | LdapOp::CompareResult(result) => Some(result),
+        _ => None,
+    }
+}
+
+/// The audit of one client session, which matches the responses sent to the
+/// client with the requests they answer.
+pub struct SessionAudit {
+    log: Arc<AuditLog>,
+    client: SocketAddr,
+    pending: HashMap<i32, Pending>,
+}
+
+impl SessionAudit {
+    pub fn new(log: Arc<AuditLog>, client: SocketAddr) -> Self {
+        SessionAudit {
+            log,
+            client,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// A request from the client, by the DN that the session is bound as, or
+    /// would be by a bind.
+    pub fn request(&mut self, msg: &LdapMsg, bind_dn: &str) {
+        let (op, dn, scope, filter, oid) = match &msg.op {
+            LdapOp::BindRequest(lbr) => ("bind", Some(lbr.dn.as_str()), None, None, None),
+            LdapOp::SearchRequest(sr) => (
+                "search",
+                Some(sr.base.as_str()),
+                Some(scope_name(&sr.scope)),
+                Some(redacted_filter_string(&sr.filter, self.log.filter_values)),
+                None,
+            ),
+            LdapOp::ModifyRequest(lmr) => ("modify", Some(lmr.dn.as_str()), None, None, None),
+            LdapOp::AddRequest(lar) => ("add", Some(lar.dn.as_str()), None, None, None),
+            LdapOp::DelRequest(dn) => ("delete", Some(dn.as_str()), None, None, None),
+            LdapOp::ModifyDNRequest(lmdr) => {
+                ("modify_dn", Some(lmdr.dn.as_str()), None, None, None)
+            }
+            LdapOp::CompareRequest(lcr) => ("compare", Some(lcr.dn.as_str()), None, None, None),
+            LdapOp::ExtendedRequest(ler) => ("extended", None, None, None, Some(ler.name.as_str())),
+            LdapOp::AbandonRequest(_) => ("abandon", None, None, None, None),
+            LdapOp::UnbindRequest => ("unbind", None, None, None, None),
+            _ => ("unknown", None, None, None, None),
+        };
+        let pending = Pending {
+            started: Instant::now(),
+            bind_dn: bind_dn.to_string(),
+            op,
+            dn: dn.map(str::to_string),
+            scope,
+            filter,
+            oid: oid.map(str::to_string),
+            entries: 0,
+            cache_hit: false,
+            reason: None,
+        };
+        // These have no response.
+        if matches!(msg.op, LdapOp::AbandonRequest(_) | LdapOp::UnbindRequest) {
+            self.write(msg.msgid, pending, None);
+        } else {
+            self.pending.insert(msg.msgid, pending);
+        }
+    }
+
+    /// The results of a search were read from the cache.
+    pub fn cache_hit(&mut self, msgid: i32) {
+        if let Some(pending) = self.pending.get_mut(&msgid) {
+            pending.cache_hit = true;
+        }
+    }
+
+    /// Why the backend refused a request, where the proxy could tell.
+    pub fn reason(&mut self, msgid: i32, reason: &'static str) {
+        if let Some(pending) = self.pending.get_mut(&msgid) {
+            pending.reason = Some(reason);
+        }
+    }
+
+    /// A response sent to the client. The operation is written to the log once
+    /// its final response is sent.
+    pub fn response(&mut self, msg: &LdapMsg) {
+        if matches!(msg.op, LdapOp::SearchResultEntry(_)) {
+            if let Some(pending) = self.pending.get_mut(&msg.msgid) {
+                pending.entries += 1;
+            }
+            return;
+        }
+        let Some(result) = final_result(&msg.op) else {
+            return;
+        };
+        if let Some(pending) = self.pending.remove(&msg.msgid) {
+            self.write(msg.msgid, pending, Some(result));
+        }
+    }
+
+    fn write(&self, msgid: i32, pending: Pending, result: Option<&LdapResult>) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        let record = AuditRecord {
+            time,
+            client: self.client.ip().to_string(),
+            bind_dn: &pending.bind_dn,
+            msgid,
+            op: pending.op,
+            dn: pending.dn.as_deref(),
+            scope: pending.scope,
+            filter: pending.filter.as_deref(),
+            oid: pending.oid.as_deref(),
+            result: result.map(|result| format!("{:?}", result.code)),
+            entries: (pending.op == "search").then_some(pending.entries),
+            duration_ms: pending.started.elapsed().as_secs_f64() * 1000.0,
+            cache_hit: pending.cache_hit,
+            reason: pending.reason,
+        };
+        self.log.write(&record);
+    }
+}
@@ -0,0 +1,113 @@
+//! A cache of successful simple binds, for applications that open a new
+//! connection and bind for every request. A bind that matches a recent
+//! successful one, on a pooled connection that is still bound as that DN, is
+//! answered by the proxy without the backend verifying the password again.
+//!
+//! Passwords are never kept. Each bind is remembered as a salted PBKDF2 hash of
+//! its password, for a short time, and is forgotten when the backend refuses
+//! the DN's credentials or the entry is written to.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkcs5::pbkdf2_hmac;
+use rand::Rng;
+use tracing::error;
+
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+// Enough to make recovering passwords from a memory dump slow, without making
+// each cached bind noticeably slower than a round trip to the backend.
+const ITERATIONS: usize = 4096;
+
+struct CachedBind {
+    salt: [u8; SALT_LEN],
+    hash: [u8; HASH_LEN],
+    valid_until: Instant,
+}
+
+fn hash_password(password: &str, salt: &[u8]) -> Option<[u8; HASH_LEN]> {
+    let mut hash = [0; HASH_LEN];
+    match pbkdf2_hmac(
+        password.as_bytes(),
+        salt,
+        ITERATIONS,
+        MessageDigest::sha256(),
+        &mut hash,
+    ) {
+        Ok(()) => Some(hash),
+        Err(e) => {
+            error!(?e, "Unable to hash a bind password");
+            None
+        }
+    }
+}
+
+pub struct BindCache {
+    ttl: Duration,
+    binds: Mutex<HashMap<String, CachedBind>>,
+}
+
+impl BindCache {
+    /// Remember binds for `ttl` after they succeed.
+    pub fn new(ttl: Duration) -> Self {
+        BindCache {
+            ttl,
+            binds: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remember a successful bind of a normalised DN.
+    pub fn insert(&self, dn: &str, password: &str) {
+        let salt: [u8; SALT_LEN] = rand::thread_rng().gen();
+        let Some(hash) = hash_password(password, &salt) else {
+            return;
+        };
+        let now = Instant::now();
+        let mut binds = self.binds.lock().unwrap_or_else(|e| e.into_inner());
+        // Forget the binds that have expired while we are here.
+        binds.retain(|_, bind| bind.valid_until > now);
+        binds.insert(
+            dn.to_string(),
+            CachedBind {
+                salt,
+                hash,
+                valid_until: now + self.ttl,
+            },
+        );
+    }
+
+    /// If this DN bound with this password within the TTL.
+    pub fn verify(&self, dn: &str, password: &str) -> bool {
+        let (salt, expected) = {
+            let binds = self.binds.lock().unwrap_or_else(|e| e.into_inner());
+            match binds.get(dn) {
+                Some(bind) if bind.valid_until > Instant::now() => (bind.salt, bind.hash),
+                _ => return false,
+            }
+        };
+        // The hash is computed without the lock held, as it is slow on purpose.
+        hash_password(password, &salt).is_some_and(|hash| memcmp::eq(&hash, &expected))
+    }
+
+    /// Forget the bind of a DN, such as when its password may have changed.
+    pub fn remove(&self, dn: &str) {
+        self.binds
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(dn);
+    }
+
+    /// The number of binds that are remembered.
+    pub fn len(&self) -> usize {
+        self.binds.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,129 @@
+//! Circuit breaking for backend connection attempts. Each backend address tracks
+//! its consecutive connection failures. Once these reach the threshold the
+//! breaker for that address opens, and no connections are attempted to it until
+//! a backoff has elapsed. The backoff doubles with each further failure, up to
+//! the configured maximum.
+//!
+//! Addresses that fail an active health check are also kept out of rotation,
+//! until a later check of them succeeds.
+
+use hashbrown::{HashMap, HashSet};
+use rand::Rng;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreakers {
+    failure_threshold: u32,
+    max_backoff: Duration,
+    states: Mutex<HashMap<SocketAddr, BreakerState>>,
+    unhealthy: Mutex<HashSet<SocketAddr>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(failure_threshold: u32, max_backoff: Duration) -> Self {
+        CircuitBreakers {
+            failure_threshold: failure_threshold.max(1),
+            max_backoff,
+            states: Mutex::new(HashMap::new()),
+            unhealthy: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// If a connection may be attempted to this address now. Once the backoff
+    /// of an open breaker has elapsed, attempts are allowed again to probe the
+    /// address.
+    pub fn allow(&self, addr: &SocketAddr) -> bool {
+        if self
+            .unhealthy
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(addr)
+        {
+            return false;
+        }
+        let states = self.states.lock().unwrap_or_else(|e| e.into_inner());
+        match states.get(addr).and_then(|state| state.open_until) {
+            Some(open_until) => open_until <= Instant::now(),
+            None => true,
+        }
+    }
+
+    /// Return an address to rotation after it passed a health check. True if
+    /// it was out of rotation.
+    pub fn mark_healthy(&self, addr: &SocketAddr) -> bool {
+        self.unhealthy
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(addr)
+    }
+
+    /// Take an address out of rotation after it failed a health check. True if
+    /// it was in rotation.
+    pub fn mark_unhealthy(&self, addr: &SocketAddr) -> bool {
+        self.unhealthy
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(*addr)
+    }
+
+    /// If an address is out of rotation after failing a health check.
+    pub fn is_unhealthy(&self, addr: &SocketAddr) -> bool {
+        self.unhealthy
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(addr)
+    }
+
+    /// The number of addresses out of rotation after failing health checks.
+    pub fn unhealthy_count(&self) -> usize {
+        self.unhealthy
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .len()
+    }
+
+    pub fn record_success(&self, addr: &SocketAddr) {
+        let mut states = self.states.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(state) = states.remove(addr) {
+            if state.open_until.is_some() {
+                info!(%addr, "backend circuit breaker closed");
+            }
+        }
+    }
+
+    pub fn record_failure(&self, addr: &SocketAddr) {
+        let mut states = self.states.lock().unwrap_or_else(|e| e.into_inner());
+        let state = states.entry(*addr).or_default();
+        state.failures = state.failures.saturating_add(1);
+
+        if state.failures < self.failure_threshold {
+            return;
+        }
+
+        // Double the backoff for each failure past the threshold, with jitter so
+        // that many proxies don't all probe the backend at the same moment.
+        let exponent = (state.failures - self.failure_threshold).min(16);
+        let backoff = BASE_BACKOFF
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff)
+            .mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+
+        if state.open_until.is_none() {
+            info!(%addr, failures = %state.failures, ?backoff, "backend circuit breaker opened");
+        } else {
+            info!(%addr, failures = %state.failures, ?backoff, "backend circuit breaker remains open");
+        }
+        state.open_until = Some(Instant::now() + backoff);
+    }
+}
@@ -0,0 +1,145 @@
+//! An index of the cached searches by their base, so that a write only has to
+//! look at the searches that could contain the entry it changed, rather than
+//! at every search in the cache.
+//!
+//! Bases are kept as their RDNs from the root down, so that the searches based
+//! at an entry's ancestors, and those based below it, are each found without a
+//! scan. The cache evicts entries without telling the index, so keys that are
+//! no longer cached are pruned when expired entries are swept.
+//!
+//! The index also keeps the size of each search's results, and their total,
+//! so that the size of the cache is known without walking it.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use hashbrown::{HashMap, HashSet};
+
+use crate::dn::{normalize_dn, parent_dn};
+use crate::proxy::SearchCacheKey;
+
+// The RDNs of a normalised DN, from the root down.
+fn rdns_from_root(dn: &str) -> Vec<String> {
+    let mut rdns = Vec::new();
+    let mut current = dn;
+    while let Some(parent) = parent_dn(current) {
+        rdns.push(current[..current.len() - parent.len() - 1].to_string());
+        current = parent;
+    }
+    if !current.is_empty() {
+        rdns.push(current.to_string());
+    }
+    rdns.reverse();
+    rdns
+}
+
+fn base_rdns(key: &SearchCacheKey) -> Vec<String> {
+    let base = normalize_dn(&key.search.base).unwrap_or_else(|_| key.search.base.to_lowercase());
+    rdns_from_root(&base)
+}
+
+// The keys of each base, when they were indexed, and the size of their results.
+type Bases = BTreeMap<Vec<String>, HashMap<SearchCacheKey, (Instant, usize)>>;
+
+#[derive(Default)]
+struct Indexed {
+    bases: Bases,
+    // The sizes of every indexed search, added up.
+    bytes: usize,
+}
+
+#[derive(Default)]
+pub struct CacheIndex {
+    indexed: Mutex<Indexed>,
+}
+
+impl CacheIndex {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Indexed> {
+        self.indexed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Index a search that was added to the cache, with the size of its
+    /// results.
+    pub fn insert(&self, key: &SearchCacheKey, size: usize) {
+        let mut indexed = self.lock();
+        let replaced = indexed
+            .bases
+            .entry(base_rdns(key))
+            .or_default()
+            .insert(key.clone(), (Instant::now(), size));
+        indexed.bytes += size;
+        if let Some((_, replaced)) = replaced {
+            indexed.bytes -= replaced;
+        }
+    }
+
+    /// Forget a search that was removed from the cache. Returns the size of
+    /// its results, if it was indexed.
+    pub fn remove(&self, key: &SearchCacheKey) -> Option<usize> {
+        let rdns = base_rdns(key);
+        let mut indexed = self.lock();
+        let keys = indexed.bases.get_mut(&rdns)?;
+        let removed = keys.remove(key).map(|(_, size)| size);
+        if keys.is_empty() {
+            indexed.bases.remove(&rdns);
+        }
+        if let Some(size) = removed {
+            indexed.bytes -= size;
+        }
+        removed
+    }
+
+    /// The searches based at a normalised DN, at its ancestors, or below it.
+    /// These are the only searches that a write to the DN could change.
+    pub fn candidates(&self, dn: &str) -> Vec<SearchCacheKey> {
+        let rdns = rdns_from_root(dn);
+        let indexed = self.lock();
+        let bases = &indexed.bases;
+        let ancestors = (0..rdns.len()).filter_map(|depth| bases.get(&rdns[..depth]));
+        let within = bases
+            .range(rdns.clone()..)
+            .take_while(|(base, _)| base.starts_with(&rdns))
+            .map(|(_, keys)| keys);
+        ancestors
+            .chain(within)
+            .flat_map(|keys| keys.keys().cloned())
+            .collect()
+    }
+
+    /// Forget the searches that were indexed before `before`, and are no
+    /// longer in the cache.
+    pub fn prune(&self, live: &HashSet<&SearchCacheKey>, before: Instant) {
+        let mut indexed = self.lock();
+        let mut pruned = 0;
+        for keys in indexed.bases.values_mut() {
+            keys.retain(|key, (at, size)| {
+                let keep = *at >= before || live.contains(key);
+                if !keep {
+                    pruned += *size;
+                }
+                keep
+            });
+        }
+        indexed.bases.retain(|_, keys| !keys.is_empty());
+        indexed.bytes -= pruned;
+    }
+
+    pub fn clear(&self) {
+        *self.lock() = Indexed::default();
+    }
+
+    /// The number of searches that are indexed.
+    pub fn len(&self) -> usize {
+        self.lock().bases.values().map(HashMap::len).sum()
+    }
+
+    /// The size of the results of every indexed search.
+    pub fn bytes(&self) -> usize {
+        self.lock().bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,135 @@
+//! Mapping of client certificates to the service accounts they bind as. Entries
+//! of the cert_map are keyed by one of:
+//!
+//! * `sha256:<hex>` - the sha256 fingerprint of the certificate
+//! * the subject DN, in RFC 4514 order, eg `CN=host1,O=Example`
+//! * `dns:<name>`, `email:<address>` or `uri:<uri>` - a subject alternative name
+//!
+//! and are checked in that order.
+
+use ldap3_proto::proto::{LdapBindCred, LdapBindRequest, SaslCredentials};
+use openssl::hash::MessageDigest;
+use openssl::x509::X509Ref;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use crate::config::Secret;
+use crate::DnConfig;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CertMapEntry {
+    /// The DN that the proxy binds to the backend as, for clients with this
+    /// certificate.
+    pub bind_dn: String,
+    /// Without a password the proxy binds with SASL EXTERNAL, asking for the
+    /// authorization identity `dn:<bind_dn>`. The backend authenticates the
+    /// proxy by its ldap_client_cert, and must allow it to act as bind_dn.
+    #[serde(default)]
+    pub bind_password: Option<Secret>,
+    /// The restrictions of the session, as for the bind maps.
+    #[serde(flatten)]
+    pub config: DnConfig,
+}
+
+impl CertMapEntry {
+    /// The bind that the proxy sends to the backend for this entry.
+    pub fn backend_bind(&self) -> LdapBindRequest {
+        let cred = match self.bind_password.as_ref() {
+            Some(password) => LdapBindCred::Simple(password.expose().to_string()),
+            None => LdapBindCred::SASL(SaslCredentials {
+                mechanism: "EXTERNAL".to_string(),
+                credentials: format!("dn:{}", self.bind_dn).into_bytes(),
+            }),
+        };
+        LdapBindRequest {
+            dn: match self.bind_password {
+                Some(_) => self.bind_dn.clone(),
+                None => "".to_string(),
+            },
+            cred,
+        }
+    }
+}
+
+pub type CertMap = BTreeMap<String, CertMapEntry>;
+
+/// What happens to clients that present a certificate with no cert_map entry,
+/// when client certificates are required.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnmappedCertPolicy {
+    /// The tls handshake fails.
+    #[default]
+    RejectHandshake,
+    /// The handshake succeeds, but every bind fails.
+    RejectBind,
+}
+
+/// The details of a client certificate that it may be mapped by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertificate {
+    pub fingerprint: String,
+    pub subject: String,
+    pub sans: Vec<String>,
+}
+
+impl ClientCertificate {
+    pub fn from_x509(cert: &X509Ref) -> Self {
+        let fingerprint = cert
+            .digest(MessageDigest::sha256())
+            .map(|digest| {
+                let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+                format!("sha256:{}", hex)
+            })
+            .unwrap_or_default();
+
+        // Certificates list the most significant rdn first, which is the reverse
+        // of how ldap writes DNs.
+        let rdns: Vec<_> = cert
+            .subject_name()
+            .entries()
+            .map(|entry| {
+                let name = entry.object().nid().short_name().unwrap_or("UNKNOWN");
+                let value = entry
+                    .data()
+                    .as_utf8()
+                    .map(|v| v.to_string())
+                    .unwrap_or_default();
+                format!("{}={}", name, value)
+            })
+            .collect();
+        let subject = rdns.into_iter().rev().collect::<Vec<_>>().join(",");
+
+        let sans = cert
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| {
+                        if let Some(dns) = name.dnsname() {
+                            Some(format!("dns:{}", dns))
+                        } else if let Some(email) = name.email() {
+                            Some(format!("email:{}", email))
+                        } else {
+                            name.uri().map(|uri| format!("uri:{}", uri))
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        ClientCertificate {
+            fingerprint,
+            subject,
+            sans,
+        }
+    }
+
+    /// Find the cert_map entry for this certificate.
+    pub fn lookup<'a>(&self, cert_map: &'a CertMap) -> Option<&'a CertMapEntry> {
+        std::iter::once(&self.fingerprint)
+            .chain(std::iter::once(&self.subject))
+            .chain(self.sans.iter())
+            .find_map(|key| cert_map.get(key))
+    }
+}
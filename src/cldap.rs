@@ -0,0 +1,194 @@
+//! Connectionless LDAP (RFC 1798) over UDP, which Windows clients use to ping
+//! domain controllers before using them ([MS-ADTS] 6.3.3). A ping is a search
+//! of the root DSE for the Netlogon attribute, whose value describes the DC,
+//! its domain and the client's site, and is answered in one datagram.
+//!
+//! Pings are relayed to the CLDAP port of the default backend pool's
+//! addresses, and the first answer is relayed back, so that the client learns
+//! of the domain as the DC describes it. Pings aren't answered from forest
+//! data of the proxy's own, so a ping that no backend answers goes unanswered,
+//! and any other datagram is dropped. The answers are larger than the pings,
+//! so a responder that anyone can reach can reflect them at a spoofed address,
+//! which cldap_allowed_networks limits.
+//!
+//! Pings are relayed on one socket for each backend address, and at most
+//! MAX_PENDING_PINGS at once, so that a flood of them can't use up the
+//! proxy's sockets.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ldap3_proto::proto::{LdapOp, LdapSearchScope};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::Decoder;
+use tracing::debug;
+
+use crate::codec::{replace_message_ids, ClientCodec};
+use crate::{network_contains, AppState, DEFAULT_BACKEND};
+
+// The largest answer that is relayed, which is the most that UDP can carry.
+const MAX_DATAGRAM: usize = 65_535;
+
+/// Is the datagram a Netlogon ping, a base search of the root DSE asking for
+/// only the Netlogon attribute?
+pub fn is_netlogon_ping(datagram: &[u8]) -> bool {
+    let mut buf = BytesMut::from(datagram);
+    let Ok(Some(request)) = ClientCodec::new(Some(datagram.len())).decode(&mut buf) else {
+        return false;
+    };
+    match request.msg.op {
+        LdapOp::SearchRequest(sr) => {
+            sr.base.is_empty()
+                && sr.scope == LdapSearchScope::Base
+                && !sr.attrs.is_empty()
+                && sr
+                    .attrs
+                    .iter()
+                    .all(|attr| attr.eq_ignore_ascii_case("netlogon"))
+        }
+        _ => false,
+    }
+}
+
+/// The most pings that are relayed at once. Datagrams that arrive while this
+/// many are waiting for their answers are dropped.
+pub const MAX_PENDING_PINGS: usize = 256;
+
+// A socket that pings are relayed to one backend address on, with the pings
+// that are waiting for their answers by the message id that they were sent
+// with.
+struct RelaySocket {
+    socket: UdpSocket,
+    pending: Mutex<HashMap<i32, oneshot::Sender<Vec<u8>>>>,
+}
+
+/// The sockets that pings are relayed on, one for each backend address. The
+/// pings of different clients may have the same message id, so each is sent
+/// with an id of the relay's own, and its answer is given back the client's.
+#[derive(Default)]
+pub struct CldapRelay {
+    sockets: Mutex<HashMap<SocketAddr, Arc<RelaySocket>>>,
+    next_msgid: AtomicI32,
+}
+
+impl CldapRelay {
+    async fn socket(&self, addr: SocketAddr) -> Option<Arc<RelaySocket>> {
+        if let Some(socket) = self.sockets.lock().ok()?.get(&addr) {
+            return Some(socket.clone());
+        }
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await.ok()?;
+        socket.connect(addr).await.ok()?;
+        let socket = Arc::new(RelaySocket {
+            socket,
+            pending: Mutex::new(HashMap::new()),
+        });
+        // Another ping may have made one meanwhile, in which case it is used.
+        Some(
+            self.sockets
+                .lock()
+                .ok()?
+                .entry(addr)
+                .or_insert(socket)
+                .clone(),
+        )
+    }
+
+    // Send the ping to the address, returning its answer.
+    async fn relay_to(
+        &self,
+        datagram: &[u8],
+        addr: SocketAddr,
+        timeout: Duration,
+    ) -> Option<Vec<u8>> {
+        let msgid = self.next_msgid.fetch_add(1, Ordering::Relaxed) & i32::MAX;
+        let (client_msgid, ping) = replace_message_ids(datagram, msgid)?;
+        let relay = self.socket(addr).await?;
+        let (answer_tx, mut answer_rx) = oneshot::channel();
+        relay.pending.lock().ok()?.insert(msgid, answer_tx);
+
+        // Whichever waiting ping receives an answer hands it to the ping that
+        // it belongs to, which may be itself.
+        let receive = async {
+            relay.socket.send(&ping).await?;
+            let mut answer = vec![0; MAX_DATAGRAM];
+            loop {
+                tokio::select! {
+                    received = &mut answer_rx => {
+                        return Ok::<_, std::io::Error>(received.ok());
+                    }
+                    len = relay.socket.recv(&mut answer) => {
+                        let answer = &answer[..len?];
+                        let sender = replace_message_ids(answer, 0).and_then(|(msgid, _)| {
+                            relay.pending.lock().ok()?.remove(&msgid)
+                        });
+                        if let Some(sender) = sender {
+                            let _ = sender.send(answer.to_vec());
+                        }
+                    }
+                }
+            }
+        };
+        let answer = match tokio::time::timeout(timeout, receive).await {
+            Ok(Ok(answer)) => answer,
+            Ok(Err(e)) => {
+                debug!(?e, %addr, "cldap ping failed");
+                None
+            }
+            Err(_) => {
+                debug!(%addr, "cldap ping timed out");
+                None
+            }
+        };
+        if let Ok(mut pending) = relay.pending.lock() {
+            pending.remove(&msgid);
+        }
+        replace_message_ids(&answer?, client_msgid).map(|(_, answer)| answer)
+    }
+}
+
+/// The answer to a datagram from a client, if it is a ping that a backend
+/// answered. Each address of the default pool is tried in turn. Clients that
+/// may not connect over TCP may not ping either.
+pub async fn cldap_answer(app_state: &AppState, datagram: &[u8], from: IpAddr) -> Option<Vec<u8>> {
+    let permitted = app_state.client_network_permitted(from)
+        && (app_state.cldap_allowed_networks.is_empty()
+            || network_contains(&app_state.cldap_allowed_networks, from));
+    if !permitted || !is_netlogon_ping(datagram) {
+        app_state
+            .metrics
+            .incr("cldap_pings_total", &[("result", "dropped")]);
+        return None;
+    }
+    let pool = app_state.backend_pools.get(DEFAULT_BACKEND)?;
+    for backend in pool.order() {
+        for mut addr in backend.addrs_in_order() {
+            if !app_state.breakers.allow(&addr) {
+                continue;
+            }
+            addr.set_port(app_state.cldap_port);
+            let answer = app_state
+                .cldap_relay
+                .relay_to(datagram, addr, pool.timeouts.connect)
+                .await;
+            if let Some(answer) = answer {
+                app_state
+                    .metrics
+                    .incr("cldap_pings_total", &[("result", "answered")]);
+                return Some(answer);
+            }
+        }
+    }
+    app_state
+        .metrics
+        .incr("cldap_pings_total", &[("result", "unanswered")]);
+    None
+}
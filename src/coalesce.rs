@@ -0,0 +1,125 @@
+//! Searches that miss the cache at the same time share one backend search.
+//! The first to miss leads, and the others with the same cache key wait for
+//! its results, which they are answered with as if from the cache. A leader
+//! that ends without results that could be cached, because its search failed
+//! or was cut short, lets the others go and search for themselves.
+//!
+//! Without this, the expiry of a popular entry sends every client that asks
+//! for it in the time that the backend takes to answer to the backend.
+//!
+//! The background refreshes of expired entries that are still answered with
+//! are kept apart, so that each entry is refreshed once at a time whether or
+//! not searches are coalesced.
+
+use std::sync::Mutex;
+
+use hashbrown::{HashMap, HashSet};
+use tokio::sync::watch;
+
+use crate::proxy::{CachedValue, SearchCacheKey};
+
+type Results = Option<CachedValue>;
+
+/// The backend searches in flight, by their cache key.
+#[derive(Default)]
+pub struct SearchFlights {
+    flights: Mutex<HashMap<SearchCacheKey, watch::Receiver<Results>>>,
+}
+
+/// Whether a search leads, or waits for a leader.
+pub enum Flight<'a> {
+    Leader(FlightLeader<'a>),
+    Follower(watch::Receiver<Results>),
+}
+
+impl SearchFlights {
+    /// Join the flight of the key, leading it if there isn't one.
+    pub fn join(&self, key: &SearchCacheKey) -> Flight<'_> {
+        let mut flights = self.flights.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(rx) = flights.get(key) {
+            return Flight::Follower(rx.clone());
+        }
+        let (tx, rx) = watch::channel(None);
+        flights.insert(key.clone(), rx);
+        Flight::Leader(FlightLeader {
+            flights: self,
+            key: Box::new(key.clone()),
+            tx,
+        })
+    }
+
+    /// The searches that are in flight.
+    pub fn len(&self) -> usize {
+        self.flights.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The search that the others of its key wait for. They are let go when it is
+/// dropped, with its results if it completed.
+pub struct FlightLeader<'a> {
+    flights: &'a SearchFlights,
+    key: Box<SearchCacheKey>,
+    tx: watch::Sender<Results>,
+}
+
+impl FlightLeader<'_> {
+    /// Answer the waiting searches with the results.
+    pub fn complete(self, results: CachedValue) {
+        // There may be no one waiting.
+        let _ = self.tx.send(Some(results));
+    }
+}
+
+impl Drop for FlightLeader<'_> {
+    fn drop(&mut self) {
+        let mut flights = self
+            .flights
+            .flights
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        flights.remove(self.key.as_ref());
+    }
+}
+
+/// Wait for the leader of a flight, returning its results if it completed.
+pub async fn follow(mut rx: watch::Receiver<Results>) -> Option<CachedValue> {
+    match rx.wait_for(Option::is_some).await {
+        Ok(results) => results.clone(),
+        Err(_) => None,
+    }
+}
+
+/// The cached searches that are being refreshed in the background.
+#[derive(Default)]
+pub struct CacheRefreshes {
+    keys: Mutex<HashSet<SearchCacheKey>>,
+}
+
+impl CacheRefreshes {
+    /// Start to refresh the key, returning false if it already is.
+    pub fn start(&self, key: &SearchCacheKey) -> bool {
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        if keys.contains(key) {
+            return false;
+        }
+        keys.insert(key.clone())
+    }
+
+    pub fn finish(&self, key: &SearchCacheKey) {
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        keys.remove(key);
+    }
+
+    /// The refreshes in flight.
+    pub fn len(&self) -> usize {
+        self.keys.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,707 @@
+//! Framing for messages from clients. This checks the length in the header of
+//! each message before it is buffered and parsed, so that an oversized request
+//! is refused as soon as its header arrives rather than after it has been read
+//! into memory.
+//!
+//! It also decodes SASL bind requests, and the server side sort and virtual
+//! list view controls, which ldap3_proto does not, and removes controls that
+//! ldap3_proto can't decode rather than failing the request. The controls that
+//! were removed are kept as they were sent, so that those the proxy relays can
+//! be forwarded. The BackendCodec does the same for the responses of backends.
+
+use ldap3_proto::control::{LdapControl, ServerSortRequet};
+use ldap3_proto::proto::{
+    LdapBindCred, LdapBindRequest, LdapMsg, LdapOp, LdapResultCode, SaslCredentials,
+};
+use ldap3_proto::{LdapCodec, DEFAULT_MAX_BER_SIZE};
+use std::io;
+
+use crate::controls::{control_oid, SUPPORTED_CONTROLS};
+use crate::ppolicy::{PasswordPolicyResponse, PolicyError, PolicyWarning, OID_PASSWORD_POLICY};
+use crate::sort::{SortRequest, OID_SERVER_SORT, OID_SORT_RESULT};
+use crate::vlv::{VlvRequest, VlvResponse, VlvTarget, OID_VLV_REQUEST, OID_VLV_RESPONSE};
+use tokio_util::bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+pub struct ClientCodec {
+    inner: LdapCodec,
+    max_ber_size: usize,
+}
+
+/// A request from a client.
+#[derive(Debug)]
+pub struct ClientRequest {
+    pub msg: LdapMsg,
+    /// The oids of critical controls that were removed from the request, because
+    /// they are not supported. The request must be refused.
+    pub unsupported_critical_controls: Vec<String>,
+    /// The oids of all controls that were removed from the request.
+    pub removed_controls: Vec<String>,
+    /// The server side sort control, which is handled by the proxy.
+    pub server_sort: Option<SortRequest>,
+    /// The virtual list view control, which is handled by the proxy.
+    pub vlv: Option<VlvRequest>,
+    /// The controls that were removed from the request, as they were sent.
+    pub raw_controls: Vec<RawControl>,
+}
+
+/// A control that ldap3_proto can't encode or decode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawControl {
+    pub oid: String,
+    pub critical: bool,
+    pub value: Option<Vec<u8>>,
+}
+
+impl RawControl {
+    fn encode(&self) -> Vec<u8> {
+        let mut control = ber(0x04, self.oid.as_bytes());
+        if self.critical {
+            control.extend([0x01, 0x01, 0xff]);
+        }
+        if let Some(value) = self.value.as_ref() {
+            control.extend(ber(0x04, value));
+        }
+        ber(0x30, &control)
+    }
+}
+
+/// A message to or from a backend, with the controls that ldap3_proto can't
+/// encode or decode.
+#[derive(Debug)]
+pub struct BackendMsg {
+    pub msg: LdapMsg,
+    pub raw_controls: Vec<RawControl>,
+}
+
+impl From<LdapMsg> for BackendMsg {
+    fn from(msg: LdapMsg) -> Self {
+        BackendMsg {
+            msg,
+            raw_controls: Vec::new(),
+        }
+    }
+}
+
+/// A response to a client. The virtual list view response control is kept
+/// beside the message, as ldap3_proto has no way to represent it.
+#[derive(Debug)]
+pub struct ClientResponse {
+    pub msg: LdapMsg,
+    pub vlv: Option<VlvResponse>,
+    pub raw_controls: Vec<RawControl>,
+}
+
+impl From<LdapMsg> for ClientResponse {
+    fn from(msg: LdapMsg) -> Self {
+        ClientResponse {
+            msg,
+            vlv: None,
+            raw_controls: Vec::new(),
+        }
+    }
+}
+
+impl From<BackendMsg> for ClientResponse {
+    fn from(msg: BackendMsg) -> Self {
+        ClientResponse {
+            msg: msg.msg,
+            vlv: None,
+            raw_controls: msg.raw_controls,
+        }
+    }
+}
+
+impl ClientCodec {
+    pub fn new(max_ber_size: Option<usize>) -> Self {
+        ClientCodec {
+            inner: LdapCodec::new(max_ber_size),
+            max_ber_size: max_ber_size.unwrap_or(DEFAULT_MAX_BER_SIZE),
+        }
+    }
+}
+
+/// The total length of the ber element at the start of buf, including its
+/// header. None if not enough of the header has arrived yet.
+fn ber_length(buf: &[u8]) -> Option<u64> {
+    let first = *buf.get(1)?;
+    if first & 0x80 == 0 {
+        return Some(2 + u64::from(first));
+    }
+
+    let octets = usize::from(first & 0x7f);
+    if octets == 0 || octets > 8 {
+        // Indefinite lengths are not allowed in ldap, and nothing we would accept
+        // needs more than 8 octets of length.
+        return Some(u64::MAX);
+    }
+
+    let length = buf.get(2..2 + octets)?;
+    let length = length
+        .iter()
+        .fold(0u64, |acc, octet| (acc << 8) | u64::from(*octet));
+    Some(length.saturating_add(2 + octets as u64))
+}
+
+/// The tag, contents and remainder of the ber element at the start of buf.
+fn element(buf: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *buf.first()?;
+    let total = usize::try_from(ber_length(buf)?).ok()?;
+    let header = match buf[1] {
+        short if short & 0x80 == 0 => 2,
+        long => 2 + usize::from(long & 0x7f),
+    };
+    if buf.len() < total {
+        return None;
+    }
+    Some((tag, &buf[header..total], &buf[total..]))
+}
+
+fn integer(contents: &[u8]) -> Option<i32> {
+    if contents.is_empty() || contents.len() > 4 {
+        return None;
+    }
+    let sign = if contents[0] & 0x80 == 0 { 0 } else { -1 };
+    Some(
+        contents
+            .iter()
+            .fold(sign, |acc: i32, octet| (acc << 8) | i32::from(*octet)),
+    )
+}
+
+/// Decode a complete message if it is a bind request with SASL credentials.
+/// Controls on the bind are not decoded.
+fn sasl_bind(frame: &[u8]) -> Option<LdapMsg> {
+    let Some((0x30, msg, _)) = element(frame) else {
+        return None;
+    };
+    let Some((0x02, msgid, rest)) = element(msg) else {
+        return None;
+    };
+    let Some((0x60, bind, _)) = element(rest) else {
+        return None;
+    };
+    let Some((0x02, [3], bind)) = element(bind) else {
+        return None;
+    };
+    let Some((0x04, dn, bind)) = element(bind) else {
+        return None;
+    };
+    let Some((0xa3, sasl, _)) = element(bind) else {
+        return None;
+    };
+    let Some((0x04, mechanism, sasl)) = element(sasl) else {
+        return None;
+    };
+    let credentials = match element(sasl) {
+        Some((0x04, credentials, _)) => credentials.to_vec(),
+        _ => Vec::new(),
+    };
+
+    Some(LdapMsg {
+        msgid: integer(msgid)?,
+        op: LdapOp::BindRequest(LdapBindRequest {
+            dn: String::from_utf8(dn.to_vec()).ok()?,
+            cred: LdapBindCred::SASL(SaslCredentials {
+                mechanism: String::from_utf8(mechanism.to_vec()).ok()?,
+                credentials,
+            }),
+        }),
+        ctrl: vec![],
+    })
+}
+
+fn push_length(buf: &mut Vec<u8>, length: usize) {
+    if length < 0x80 {
+        buf.push(length as u8);
+    } else {
+        let octets: Vec<u8> = length
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|octet| *octet == 0)
+            .collect();
+        buf.push(0x80 | octets.len() as u8);
+        buf.extend(octets);
+    }
+}
+
+// The controls of a request, as the client sent them.
+struct ScannedControls {
+    // The message without the controls that are not supported, if there were any.
+    rebuilt: Option<Vec<u8>>,
+    // The oids of the controls that were marked critical.
+    critical: Vec<String>,
+    // The oids of the controls that are not supported.
+    removed: Vec<String>,
+    server_sort: Option<SortRequest>,
+    vlv: Option<VlvRequest>,
+    // The controls that are not supported, as they were sent.
+    raw: Vec<RawControl>,
+}
+
+fn utf8(contents: &[u8]) -> Option<String> {
+    String::from_utf8(contents.to_vec()).ok()
+}
+
+/// Decode the value of a server side sort control (RFC 2891 1.1).
+fn sort_keys(value: &[u8]) -> Option<Vec<ServerSortRequet>> {
+    let Some((0x30, mut keys, _)) = element(value) else {
+        return None;
+    };
+    let mut decoded = Vec::new();
+    while !keys.is_empty() {
+        let (0x30, key, next) = element(keys)? else {
+            return None;
+        };
+        keys = next;
+        let (0x04, attribute_name, mut rest) = element(key)? else {
+            return None;
+        };
+        let mut sort_key = ServerSortRequet {
+            attribute_name: utf8(attribute_name)?,
+            ordering_rule: None,
+            reverse_order: false,
+        };
+        while !rest.is_empty() {
+            let (tag, contents, next) = element(rest)?;
+            rest = next;
+            match (tag, contents) {
+                (0x80, rule) => sort_key.ordering_rule = Some(utf8(rule)?),
+                (0x81, [reverse]) => sort_key.reverse_order = *reverse != 0,
+                _ => return None,
+            }
+        }
+        decoded.push(sort_key);
+    }
+    (!decoded.is_empty()).then_some(decoded)
+}
+
+/// Decode the value of a virtual list view control. The context id is not
+/// used, as none is ever issued.
+fn vlv_request(value: &[u8], critical: bool) -> Option<VlvRequest> {
+    let Some((0x30, request, _)) = element(value) else {
+        return None;
+    };
+    let (0x02, before_count, rest) = element(request)? else {
+        return None;
+    };
+    let (0x02, after_count, rest) = element(rest)? else {
+        return None;
+    };
+    let target = match element(rest)? {
+        (0xa0, by_offset, _) => {
+            let (0x02, offset, rest) = element(by_offset)? else {
+                return None;
+            };
+            let (0x02, content_count, _) = element(rest)? else {
+                return None;
+            };
+            VlvTarget::ByOffset {
+                offset: integer(offset)?,
+                content_count: integer(content_count)?,
+            }
+        }
+        (0x81, value, _) => VlvTarget::GreaterOrEqual(value.to_vec()),
+        _ => return None,
+    };
+    Some(VlvRequest {
+        before_count: usize::try_from(integer(before_count)?).ok()?,
+        after_count: usize::try_from(integer(after_count)?).ok()?,
+        target,
+        critical,
+    })
+}
+
+/// The value of a password policy response control.
+pub fn password_policy_response(value: &[u8]) -> Option<PasswordPolicyResponse> {
+    let Some((0x30, mut response, _)) = element(value) else {
+        return None;
+    };
+    let mut decoded = PasswordPolicyResponse::default();
+    while !response.is_empty() {
+        let (tag, contents, rest) = element(response)?;
+        response = rest;
+        match tag {
+            0xa0 => {
+                decoded.warning = match element(contents)? {
+                    (0x80, seconds, _) => {
+                        Some(PolicyWarning::TimeBeforeExpiration(integer(seconds)?))
+                    }
+                    (0x81, binds, _) => Some(PolicyWarning::GraceAuthNsRemaining(integer(binds)?)),
+                    _ => return None,
+                }
+            }
+            0x81 => decoded.error = PolicyError::from_code(integer(contents)?),
+            _ => return None,
+        }
+    }
+    Some(decoded)
+}
+
+/// Give each of the messages in buf, which must hold only whole messages as a
+/// CLDAP datagram does, the message id msgid. Returns the id of the first
+/// message, and the messages as they are with the new id.
+pub fn replace_message_ids(mut buf: &[u8], msgid: i32) -> Option<(i32, Vec<u8>)> {
+    let mut first = None;
+    let mut replaced = Vec::with_capacity(buf.len());
+    while !buf.is_empty() {
+        let Some((0x30, msg, rest)) = element(buf) else {
+            return None;
+        };
+        let Some((0x02, old, op)) = element(msg) else {
+            return None;
+        };
+        first = first.or(Some(integer(old)?));
+        let mut rebuilt = ber_integer(0x02, i64::from(msgid));
+        rebuilt.extend_from_slice(op);
+        replaced.extend(ber(0x30, &rebuilt));
+        buf = rest;
+    }
+    Some((first?, replaced))
+}
+
+// The value of a control, after its criticality.
+fn control_value(rest: &[u8]) -> Option<&[u8]> {
+    let value = match element(rest) {
+        Some((0x01, _, rest)) => element(rest),
+        value => value,
+    };
+    match value {
+        Some((0x04, value, _)) => Some(value),
+        _ => None,
+    }
+}
+
+/// Find the critical controls of a complete message, and rebuild it without the
+/// controls that are not supported. None if the message has no controls.
+fn scan_controls(frame: &[u8]) -> Option<ScannedControls> {
+    let Some((0x30, msg, _)) = element(frame) else {
+        return None;
+    };
+    let (_, _, rest) = element(msg)?;
+    let (_, _, rest) = element(rest)?;
+    let Some((0xa0, mut controls, _)) = element(rest) else {
+        return None;
+    };
+    let msgid_and_op = &msg[..msg.len() - rest.len()];
+
+    let mut kept = Vec::new();
+    let mut removed = Vec::new();
+    let mut critical = Vec::new();
+    let mut server_sort = None;
+    let mut vlv = None;
+    let mut raw = Vec::new();
+    while !controls.is_empty() {
+        let (tag, control, next) = element(controls)?;
+        let encoded = &controls[..controls.len() - next.len()];
+        controls = next;
+        if tag != 0x30 {
+            return None;
+        }
+        let Some((0x04, oid, rest)) = element(control) else {
+            return None;
+        };
+        let oid = String::from_utf8_lossy(oid).into_owned();
+        let is_critical = matches!(element(rest), Some((0x01, [value], _)) if *value != 0);
+        if is_critical {
+            critical.push(oid.clone());
+        }
+        if oid == OID_SERVER_SORT {
+            // Rather than kept, as ldap3_proto can't decode it.
+            match control_value(rest).and_then(sort_keys) {
+                Some(keys) => {
+                    server_sort = Some(SortRequest {
+                        keys,
+                        critical: is_critical,
+                    })
+                }
+                None => removed.push(oid),
+            }
+        } else if oid == OID_VLV_REQUEST {
+            match control_value(rest).and_then(|value| vlv_request(value, is_critical)) {
+                Some(request) => vlv = Some(request),
+                None => removed.push(oid),
+            }
+        } else if SUPPORTED_CONTROLS.contains(&oid.as_str())
+            // ldap3_proto would decode the response as the request.
+            && !(oid == OID_PASSWORD_POLICY && control_value(rest).is_some())
+        {
+            kept.extend_from_slice(encoded);
+        } else {
+            raw.push(RawControl {
+                oid: oid.clone(),
+                critical: is_critical,
+                value: control_value(rest).map(<[u8]>::to_vec),
+            });
+            removed.push(oid);
+        }
+    }
+    if removed.is_empty() && server_sort.is_none() && vlv.is_none() {
+        return Some(ScannedControls {
+            rebuilt: None,
+            critical,
+            removed,
+            server_sort,
+            vlv,
+            raw,
+        });
+    }
+
+    let mut body = msgid_and_op.to_vec();
+    if !kept.is_empty() {
+        body.push(0xa0);
+        push_length(&mut body, kept.len());
+        body.extend(kept);
+    }
+    let mut rebuilt = vec![0x30];
+    push_length(&mut rebuilt, body.len());
+    rebuilt.extend(body);
+    Some(ScannedControls {
+        rebuilt: Some(rebuilt),
+        critical,
+        removed,
+        server_sort,
+        vlv,
+        raw,
+    })
+}
+
+// ldap3_proto loses the criticality of controls that have no value, so it is
+// restored from what the client sent.
+fn restore_criticality(msg: &mut LdapMsg, critical: &[String]) {
+    for ctrl in msg.ctrl.iter_mut() {
+        let oid = control_oid(ctrl);
+        if let LdapControl::ManageDsaIT { criticality }
+        | LdapControl::PasswordPolicyRequest { criticality } = ctrl
+        {
+            *criticality |= critical.iter().any(|c| c == oid);
+        }
+    }
+}
+
+impl Decoder for ClientCodec {
+    type Item = ClientRequest;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(length) = ber_length(buf) {
+            if length > self.max_ber_size as u64 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "request too large",
+                ));
+            }
+            if let Some(frame) = buf.get(..length as usize) {
+                if let Some(msg) = sasl_bind(frame) {
+                    buf.advance(length as usize);
+                    return Ok(Some(ClientRequest {
+                        msg,
+                        unsupported_critical_controls: Vec::new(),
+                        removed_controls: Vec::new(),
+                        server_sort: None,
+                        vlv: None,
+                        raw_controls: Vec::new(),
+                    }));
+                }
+                if let Some(scanned) = scan_controls(frame) {
+                    let msg = match scanned.rebuilt {
+                        Some(rebuilt) => {
+                            buf.advance(length as usize);
+                            self.inner.decode(&mut BytesMut::from(rebuilt.as_slice()))?
+                        }
+                        None => self.inner.decode(buf)?,
+                    };
+                    let mut msg = msg.ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "invalid request")
+                    })?;
+                    restore_criticality(&mut msg, &scanned.critical);
+                    let unsupported_critical_controls = scanned
+                        .critical
+                        .into_iter()
+                        .filter(|oid| scanned.removed.contains(oid))
+                        .collect();
+                    return Ok(Some(ClientRequest {
+                        msg,
+                        unsupported_critical_controls,
+                        removed_controls: scanned.removed,
+                        server_sort: scanned.server_sort,
+                        vlv: scanned.vlv,
+                        raw_controls: scanned.raw,
+                    }));
+                }
+            }
+        }
+        Ok(self.inner.decode(buf)?.map(|msg| ClientRequest {
+            msg,
+            unsupported_critical_controls: Vec::new(),
+            removed_controls: Vec::new(),
+            server_sort: None,
+            vlv: None,
+            raw_controls: Vec::new(),
+        }))
+    }
+}
+
+fn ber(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    push_length(&mut encoded, contents.len());
+    encoded.extend_from_slice(contents);
+    encoded
+}
+
+// An integer, or enumerated, in its shortest two's complement encoding.
+fn ber_integer(tag: u8, value: i64) -> Vec<u8> {
+    let octets = value.to_be_bytes();
+    let start = (0..octets.len() - 1)
+        .find(|&i| !matches!((octets[i], octets[i + 1] & 0x80), (0, 0) | (0xff, 0x80)))
+        .unwrap_or(octets.len() - 1);
+    ber(tag, &octets[start..])
+}
+
+fn response_control(oid: &str, value: &[u8]) -> Vec<u8> {
+    let mut control = ber(0x04, oid.as_bytes());
+    control.extend(ber(0x04, value));
+    ber(0x30, &control)
+}
+
+/// The sort result control as RFC 2891 1.2 has it. ldap3_proto encodes the
+/// attribute type without its tag, which clients can't decode.
+fn sort_result_control(code: LdapResultCode) -> Vec<u8> {
+    let value = ber(0x30, &ber_integer(0x0a, code as i64));
+    response_control(OID_SORT_RESULT, &value)
+}
+
+fn vlv_response_control(response: &VlvResponse) -> Vec<u8> {
+    let mut value = ber_integer(0x02, i64::from(response.target_position));
+    value.extend(ber_integer(0x02, i64::from(response.content_count)));
+    value.extend(ber_integer(0x0a, response.result));
+    response_control(OID_VLV_RESPONSE, &ber(0x30, &value))
+}
+
+// Encode a message, with the controls that ldap3_proto can't encode added to
+// those that it can.
+fn encode_with_controls(
+    inner: &mut LdapCodec,
+    mut msg: LdapMsg,
+    mut extra: Vec<Vec<u8>>,
+    buf: &mut BytesMut,
+) -> io::Result<()> {
+    let mut sort_results = Vec::new();
+    msg.ctrl.retain(|ctrl| match ctrl {
+        LdapControl::ServerSortResult { sort_result } => {
+            sort_results.push(sort_result_control(sort_result.result_code.clone()));
+            false
+        }
+        _ => true,
+    });
+    extra.splice(0..0, sort_results);
+    if extra.is_empty() {
+        return inner.encode(msg, buf);
+    }
+
+    let mut encoded = BytesMut::new();
+    inner.encode(msg, &mut encoded)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid message");
+    let (_, body, _) = element(&encoded).ok_or_else(invalid)?;
+    let (_, _, rest) = element(body).ok_or_else(invalid)?;
+    let (_, _, rest) = element(rest).ok_or_else(invalid)?;
+    let mut controls = match element(rest) {
+        Some((0xa0, controls, _)) => controls.to_vec(),
+        _ => Vec::new(),
+    };
+    controls.extend(extra.concat());
+    let mut rebuilt = body[..body.len() - rest.len()].to_vec();
+    rebuilt.extend(ber(0xa0, &controls));
+    buf.extend_from_slice(&ber(0x30, &rebuilt));
+    Ok(())
+}
+
+impl Encoder<ClientResponse> for ClientCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, response: ClientResponse, buf: &mut BytesMut) -> io::Result<()> {
+        let mut extra: Vec<_> = response.vlv.iter().map(vlv_response_control).collect();
+        extra.extend(response.raw_controls.iter().map(RawControl::encode));
+        encode_with_controls(&mut self.inner, response.msg, extra, buf)
+    }
+}
+
+impl Encoder<LdapMsg> for ClientCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: LdapMsg, buf: &mut BytesMut) -> io::Result<()> {
+        self.encode(ClientResponse::from(msg), buf)
+    }
+}
+
+/// Framing for the connections to backends. As with clients, oversized
+/// responses are refused once their header arrives. Controls in responses that
+/// ldap3_proto can't decode are kept raw, rather than failing the response.
+pub struct BackendCodec {
+    inner: LdapCodec,
+    max_ber_size: usize,
+}
+
+impl BackendCodec {
+    pub fn new(max_ber_size: Option<usize>) -> Self {
+        BackendCodec {
+            inner: LdapCodec::new(max_ber_size),
+            max_ber_size: max_ber_size.unwrap_or(DEFAULT_MAX_BER_SIZE),
+        }
+    }
+}
+
+impl Decoder for BackendCodec {
+    type Item = BackendMsg;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if ber_length(buf).is_some_and(|length| length > self.max_ber_size as u64) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response too large",
+            ));
+        }
+        let frame = ber_length(buf)
+            .and_then(|length| usize::try_from(length).ok())
+            .and_then(|length| buf.get(..length).map(|frame| (length, frame)));
+        if let Some((length, frame)) = frame {
+            if let Some(ScannedControls {
+                rebuilt: Some(rebuilt),
+                raw,
+                ..
+            }) = scan_controls(frame)
+            {
+                buf.advance(length);
+                let msg = self
+                    .inner
+                    .decode(&mut BytesMut::from(rebuilt.as_slice()))?
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "invalid response")
+                    })?;
+                return Ok(Some(BackendMsg {
+                    msg,
+                    raw_controls: raw,
+                }));
+            }
+        }
+        Ok(self.inner.decode(buf)?.map(BackendMsg::from))
+    }
+}
+
+impl Encoder<BackendMsg> for BackendCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: BackendMsg, buf: &mut BytesMut) -> io::Result<()> {
+        let extra = msg.raw_controls.iter().map(RawControl::encode).collect();
+        encode_with_controls(&mut self.inner, msg.msg, extra, buf)
+    }
+}
+
+impl Encoder<LdapMsg> for BackendCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: LdapMsg, buf: &mut BytesMut) -> io::Result<()> {
+        self.encode(BackendMsg::from(msg), buf)
+    }
+}
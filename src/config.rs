@@ -0,0 +1,486 @@
+//! Loading of the config, with overrides so that secrets don't need to be in
+//! the TOML file. From highest to lowest precedence, values come from:
+//!
+//! * command line flags
+//! * environment variables, named `LDAP_PROXY__` followed by the path to the
+//!   field in upper case, with `__` between the parts of the path, such as
+//!   `LDAP_PROXY__TLS_KEY`. The parts are lowercased, so tables with keys that
+//!   aren't lowercase names can't be reached. The values are strings.
+//! * files named by the `*_file` variant of a secret field, for example
+//!   `bind_password_file`, or by `LDAP_PROXY__..._FILE` environment variables.
+//!   A trailing newline is removed from the contents.
+//! * the TOML file, in which the strings may refer to environment variables
+//!   as `${NAME}` (`$${` is a literal `${`), and a string that starts with
+//!   `file:` is replaced by the contents of the file at the path that
+//!   follows, like a `*_file` field.
+//!
+//! Secret fields are held in `Secret`, which is redacted from Debug output.
+//!
+//! Bind map entries and `binddn_patterns` may also be kept in the `*.toml`
+//! files of the directory named by `include_dir`, which are read in the order
+//! of their names and added to those of the TOML file before any overrides.
+//!
+//! Bind map entries and `binddn_patterns` that name a `profile` are given the
+//! settings of that table of `profiles` that they don't set themselves.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use hashbrown::HashMap;
+use serde::Deserialize;
+use toml::{Table, Value};
+use zeroize::Zeroize;
+
+use crate::Config;
+
+/// The prefix of environment variables that override the config.
+pub const ENV_PREFIX: &str = "LDAP_PROXY__";
+
+/// Fields that hold secrets, and so may be read from a `*_file`.
+pub const SECRET_FIELDS: &[&str] = &[
+    "bind_password",
+    "admin_token",
+    "upstream_pool_neutral_password",
+    "anonymous_bind_password",
+    "service_bind_password",
+];
+
+const FILE_SUFFIX: &str = "_file";
+
+// Strings that start with this are replaced by the contents of a file.
+const FILE_PREFIX: &str = "file:";
+
+// The key of the directory of files with more bind map entries.
+const INCLUDE_DIR: &str = "include_dir";
+
+// The key of the table of named settings shared by bind map entries.
+const PROFILES: &str = "profiles";
+
+/// A secret value from the config. It is never shown in Debug output, and is
+/// zeroed when dropped.
+#[derive(Clone, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Secret(value.to_string())
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// Where a config value came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    File(PathBuf),
+    Env(String),
+    SecretFile(PathBuf),
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::File(path) => write!(f, "config file '{}'", path.display()),
+            ConfigSource::Env(name) => write!(f, "environment variable {}", name),
+            ConfigSource::SecretFile(path) => write!(f, "secret file '{}'", path.display()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    pub source: ConfigSource,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid value from {}: {}", self.source, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// An override of the value at path.
+struct Override {
+    path: Vec<String>,
+    value: String,
+    source: ConfigSource,
+}
+
+/// The annotated example config at the top of the README, which shows every
+/// option.
+pub fn example_config() -> &'static str {
+    include_str!("../README.md")
+        .split("```\n")
+        .nth(1)
+        .unwrap_or_default()
+}
+
+/// Read a secret from a file, without the newline that ends it.
+pub fn read_secret_file(path: &Path) -> Result<String, ConfigError> {
+    let mut contents = std::fs::read_to_string(path).map_err(|e| ConfigError {
+        source: ConfigSource::SecretFile(path.to_path_buf()),
+        message: e.to_string(),
+    })?;
+    if contents.ends_with('\n') {
+        contents.pop();
+        if contents.ends_with('\r') {
+            contents.pop();
+        }
+    }
+    Ok(contents)
+}
+
+fn secret_field(key: &str) -> Option<&str> {
+    key.strip_suffix(FILE_SUFFIX)
+        .filter(|field| SECRET_FIELDS.contains(field))
+}
+
+// Find the `*_file` variants of secret fields in the TOML, and remove them.
+fn take_secret_files(table: &mut Table, path: &mut Vec<String>, found: &mut Vec<Override>) {
+    let file_keys: Vec<String> = table
+        .keys()
+        .filter(|key| secret_field(key).is_some())
+        .cloned()
+        .collect();
+    for key in file_keys {
+        if let Some(Value::String(file)) = table.remove(&key) {
+            let mut field = path.clone();
+            field.extend(secret_field(&key).map(str::to_string));
+            found.push(Override {
+                path: field,
+                source: ConfigSource::SecretFile(PathBuf::from(&file)),
+                value: file,
+            });
+        }
+    }
+    for (key, value) in table.iter_mut() {
+        if let Value::Table(inner) = value {
+            path.push(key.clone());
+            take_secret_files(inner, path, found);
+            path.pop();
+        }
+    }
+}
+
+fn set_path(table: &mut Table, over: &Override) -> Result<(), ConfigError> {
+    let Some((last, parents)) = over.path.split_last() else {
+        return Ok(());
+    };
+    let mut table = table;
+    for part in parents {
+        let next = table
+            .entry(part.clone())
+            .or_insert_with(|| Value::Table(Table::new()));
+        table = match next {
+            Value::Table(inner) => inner,
+            _ => {
+                return Err(ConfigError {
+                    source: over.source.clone(),
+                    message: format!("{} is not a table", part),
+                })
+            }
+        };
+    }
+    table.insert(last.clone(), Value::String(over.value.clone()));
+    Ok(())
+}
+
+// Add the bind map entries and binddn_patterns of the files in the include
+// directory to the config. A DN may only have one bind map entry, across all
+// of the files.
+fn merge_fragments(base: &mut Table, path: &Path) -> Result<(), ConfigError> {
+    let dir = match base.get(INCLUDE_DIR) {
+        None => return Ok(()),
+        Some(Value::String(dir)) => path.parent().unwrap_or(Path::new("")).join(dir),
+        Some(_) => {
+            return Err(ConfigError {
+                source: ConfigSource::File(path.to_path_buf()),
+                message: format!("{} is not a path", INCLUDE_DIR),
+            })
+        }
+    };
+    let mut fragments: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .map_err(|e| ConfigError {
+            source: ConfigSource::File(dir.clone()),
+            message: e.to_string(),
+        })?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|fragment| {
+            let hidden = fragment
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+            !hidden && fragment.extension().is_some_and(|ext| ext == "toml") && fragment.is_file()
+        })
+        .collect();
+    fragments.sort();
+
+    for fragment in fragments {
+        let error = |message: String| ConfigError {
+            source: ConfigSource::File(fragment.clone()),
+            message,
+        };
+        let contents = std::fs::read_to_string(&fragment).map_err(|e| error(e.to_string()))?;
+        let table: Table = toml::from_str(&contents).map_err(|e| error(e.to_string()))?;
+        for (key, value) in table {
+            match value {
+                Value::Array(patterns) if key == "binddn_patterns" => {
+                    match base.entry(key).or_insert_with(|| Value::Array(Vec::new())) {
+                        Value::Array(existing) => existing.extend(patterns),
+                        _ => return Err(error("binddn_patterns is not an array".to_string())),
+                    }
+                }
+                // Bind map entries are the tables keyed by a DN.
+                Value::Table(_) if key.contains('=') => {
+                    if base.contains_key(&key) {
+                        return Err(error(format!("{} already has a bind map entry", key)));
+                    }
+                    base.insert(key, value);
+                }
+                _ => {
+                    return Err(error(format!(
+                        "{} can't be set in an included file, only bind map entries and binddn_patterns",
+                        key
+                    )))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Give the bind map entries and binddn_patterns that name a profile the
+// settings of the profile that they don't have.
+fn resolve_profiles(base: &mut Table, path: &Path) -> Result<(), ConfigError> {
+    let error = |message: String| ConfigError {
+        source: ConfigSource::File(path.to_path_buf()),
+        message,
+    };
+    let profiles = match base.remove(PROFILES) {
+        None => Table::new(),
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => return Err(error(format!("{} is not a table", PROFILES))),
+    };
+    let resolve = |entry: &mut Table| -> Result<(), ConfigError> {
+        let name = match entry.remove("profile") {
+            None => return Ok(()),
+            Some(Value::String(name)) => name,
+            Some(_) => return Err(error("profile is not a string".to_string())),
+        };
+        let profile = match profiles.get(&name) {
+            Some(Value::Table(profile)) => profile,
+            Some(_) => return Err(error(format!("profile {} is not a table", name))),
+            None => return Err(error(format!("there is no profile named {}", name))),
+        };
+        if profile.contains_key("profile") {
+            return Err(error(format!("profile {} can't use another profile", name)));
+        }
+        for (key, value) in profile {
+            if !entry.contains_key(key) {
+                entry.insert(key.clone(), value.clone());
+            }
+        }
+        Ok(())
+    };
+
+    for (key, value) in base.iter_mut() {
+        match value {
+            Value::Table(entry) if key.contains('=') => resolve(entry)?,
+            Value::Array(patterns) if key == "binddn_patterns" => {
+                for pattern in patterns.iter_mut() {
+                    if let Value::Table(entry) = pattern {
+                        resolve(entry)?;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Replace the `${NAME}` references in a string with the values of the
+// variables.
+fn substitute_vars(
+    value: &str,
+    vars: &HashMap<String, String>,
+    path: &Path,
+) -> Result<String, ConfigError> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find('}') else {
+            return Err(ConfigError {
+                source: ConfigSource::File(path.to_path_buf()),
+                message: format!("unterminated ${{ in '{}'", value),
+            });
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        let var = vars.get(name).ok_or_else(|| ConfigError {
+            source: ConfigSource::Env(name.to_string()),
+            message: "is not set".to_string(),
+        })?;
+        out.push_str(var);
+        rest = &rest[start + 3 + len..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn substitute(
+    value: &mut Value,
+    vars: &HashMap<String, String>,
+    path: &Path,
+) -> Result<(), ConfigError> {
+    match value {
+        Value::String(string) => {
+            let mut substituted = substitute_vars(string, vars, path)?;
+            if let Some(file) = substituted.strip_prefix(FILE_PREFIX) {
+                substituted = read_secret_file(Path::new(file))?;
+            }
+            *string = substituted;
+        }
+        Value::Array(values) => {
+            for value in values.iter_mut() {
+                substitute(value, vars, path)?;
+            }
+        }
+        Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                substitute(value, vars, path)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn deserialize(table: Table) -> Result<Config, String> {
+    Config::deserialize(Value::Table(table)).map_err(|e| e.to_string())
+}
+
+/// Parse the config file at `path`, applying the overrides from `env` (usually
+/// `std::env::vars()`).
+pub fn load_config(
+    contents: &str,
+    path: &Path,
+    env: impl IntoIterator<Item = (String, String)>,
+) -> Result<Config, ConfigError> {
+    let file_source = ConfigSource::File(path.to_path_buf());
+    let mut base: Table = toml::from_str(contents).map_err(|e| ConfigError {
+        source: file_source.clone(),
+        message: e.to_string(),
+    })?;
+    merge_fragments(&mut base, path)?;
+    resolve_profiles(&mut base, path)?;
+    let env: HashMap<String, String> = env.into_iter().collect();
+    for (_, value) in base.iter_mut() {
+        substitute(value, &env, path)?;
+    }
+
+    // Files come first, then plain environment variables, so that the
+    // environment takes precedence.
+    let mut files = Vec::new();
+    take_secret_files(&mut base, &mut Vec::new(), &mut files);
+    let mut vars = Vec::new();
+    let mut env: Vec<_> = env
+        .into_iter()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
+    env.sort();
+    for (name, value) in env {
+        let mut path: Vec<String> = name[ENV_PREFIX.len()..]
+            .split("__")
+            .map(str::to_lowercase)
+            .collect();
+        let source = ConfigSource::Env(name.clone());
+        let field = path.pop().unwrap_or_default();
+        match secret_field(&field) {
+            Some(secret) => {
+                path.push(secret.to_string());
+                files.push(Override {
+                    path,
+                    value,
+                    source,
+                });
+            }
+            None => {
+                path.push(field);
+                vars.push(Override {
+                    path,
+                    value,
+                    source,
+                });
+            }
+        }
+    }
+
+    let mut overrides = Vec::with_capacity(files.len() + vars.len());
+    for mut over in files {
+        // The value so far is the path of the file.
+        over.value = read_secret_file(Path::new(&over.value))?;
+        overrides.push(over);
+    }
+    overrides.extend(vars);
+
+    let apply = |skip: Option<usize>| -> Result<Table, ConfigError> {
+        let mut table = base.clone();
+        for (i, over) in overrides.iter().enumerate() {
+            if Some(i) != skip {
+                set_path(&mut table, over)?;
+            }
+        }
+        Ok(table)
+    };
+
+    match deserialize(apply(None)?) {
+        Ok(config) => Ok(config),
+        Err(message) => {
+            // Find the override that made the config invalid, so the error can
+            // say where to look.
+            for (i, over) in overrides.iter().enumerate() {
+                if deserialize(apply(Some(i))?).is_ok() {
+                    return Err(ConfigError {
+                        source: over.source.clone(),
+                        message,
+                    });
+                }
+            }
+            Err(ConfigError {
+                source: file_source,
+                message,
+            })
+        }
+    }
+}
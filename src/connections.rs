@@ -0,0 +1,265 @@
+//! Tracking of live client connections, so that the number of connections in
+//! total and from each client address can be limited, and of live sessions, so
+//! that operators can see who is connected and end their sessions.
+
+use hashbrown::HashMap;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionLimit {
+    Total,
+    PerIp,
+}
+
+#[derive(Debug, Default)]
+pub struct ConnectionTracker {
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    total: AtomicUsize,
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// A live connection. The connection is no longer counted once this is dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    tracker: Arc<ConnectionTracker>,
+    ip: IpAddr,
+}
+
+impl ConnectionTracker {
+    pub fn new(max_connections: Option<usize>, max_connections_per_ip: Option<usize>) -> Self {
+        ConnectionTracker {
+            max_connections,
+            max_connections_per_ip,
+            ..Default::default()
+        }
+    }
+
+    /// Count a new connection from this address, unless that would exceed a limit.
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionGuard, ConnectionLimit> {
+        let mut per_ip = self.per_ip.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(max) = self.max_connections {
+            if self.total.load(Ordering::Relaxed) >= max {
+                return Err(ConnectionLimit::Total);
+            }
+        }
+
+        if let Some(max) = self.max_connections_per_ip {
+            if per_ip.get(&ip).copied().unwrap_or_default() >= max {
+                return Err(ConnectionLimit::PerIp);
+            }
+        }
+
+        *per_ip.entry(ip).or_default() += 1;
+        self.total.fetch_add(1, Ordering::Relaxed);
+
+        Ok(ConnectionGuard {
+            tracker: self.clone(),
+            ip,
+        })
+    }
+
+    /// The number of live connections.
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// The number of live connections from this address.
+    pub fn count(&self, ip: &IpAddr) -> usize {
+        self.per_ip
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(ip)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut per_ip = self
+            .tracker
+            .per_ip
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+        self.tracker.total.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A live session, as it is shown to operators.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub client: SocketAddr,
+    /// Seconds since the unix epoch.
+    pub connected: u64,
+    /// The DN that the session is bound as, if it is.
+    pub bind_dn: Option<String>,
+}
+
+#[derive(Debug)]
+struct RegisteredSession {
+    info: SessionInfo,
+    disconnect: Arc<Notify>,
+}
+
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+    next_id: AtomicU64,
+    sessions: Mutex<BTreeMap<u64, RegisteredSession>>,
+    // Set once the proxy is shutting down, and every session has been asked
+    // to end.
+    draining: AtomicBool,
+    // Notified when the last session ends.
+    empty: Notify,
+}
+
+/// The registration of a live session. The session is removed from the
+/// registry once this is dropped.
+#[derive(Debug)]
+pub struct SessionHandle {
+    registry: Arc<SessionRegistry>,
+    id: u64,
+    disconnect: Arc<Notify>,
+}
+
+impl SessionRegistry {
+    pub fn register(self: &Arc<Self>, client: SocketAddr) -> SessionHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let connected = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let disconnect = Arc::new(Notify::new());
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        // A session that arrives as the proxy shuts down ends straight away.
+        if self.is_draining() {
+            disconnect.notify_one();
+        }
+        sessions.insert(
+            id,
+            RegisteredSession {
+                info: SessionInfo {
+                    id,
+                    client,
+                    connected,
+                    bind_dn: None,
+                },
+                disconnect: disconnect.clone(),
+            },
+        );
+        drop(sessions);
+        SessionHandle {
+            registry: self.clone(),
+            id,
+            disconnect,
+        }
+    }
+
+    /// The live sessions, oldest first.
+    pub fn list(&self) -> Vec<SessionInfo> {
+        self.sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .map(|session| session.info.clone())
+            .collect()
+    }
+
+    /// Ask a session to end. False if there is no such session.
+    pub fn disconnect(&self, id: u64) -> bool {
+        match self
+            .sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&id)
+        {
+            Some(session) => {
+                session.disconnect.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Ask every session to end, once the operations that it has in flight
+    /// complete.
+    pub fn drain(&self) {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        self.draining.store(true, Ordering::Relaxed);
+        for session in sessions.values() {
+            session.disconnect.notify_one();
+        }
+    }
+
+    /// If the proxy is shutting down.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Completes once there are no live sessions.
+    pub async fn drained(&self) {
+        loop {
+            let empty = self.empty.notified();
+            if self
+                .sessions
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .is_empty()
+            {
+                return;
+            }
+            empty.await;
+        }
+    }
+}
+
+impl SessionHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn set_bind_dn(&self, bind_dn: Option<String>) {
+        if let Some(session) = self
+            .registry
+            .sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_mut(&self.id)
+        {
+            session.info.bind_dn = bind_dn;
+        }
+    }
+
+    /// Completes once an operator has asked for the session to end.
+    pub async fn disconnected(&self) {
+        self.disconnect.notified().await
+    }
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        let mut sessions = self
+            .registry
+            .sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        sessions.remove(&self.id);
+        if sessions.is_empty() {
+            self.registry.empty.notify_waiters();
+        }
+    }
+}
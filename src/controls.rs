@@ -0,0 +1,236 @@
+//! Which controls are relayed between clients and the backend. Request controls
+//! that are not permitted are removed, or the request is refused with
+//! unavailableCriticalExtension if the control is critical. Response controls
+//! that are not permitted are removed.
+
+use hashbrown::HashSet;
+use ldap3_proto::control::LdapControl;
+use ldap3_proto::proto::SyncRequestMode;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::codec::RawControl;
+
+/// Proxied authorization (RFC 4370). This would let a client act as any user
+/// that the DN we bind as may proxy for.
+pub const OID_PROXIED_AUTHZ: &str = "2.16.840.1.113730.3.4.18";
+/// ManageDsaIT (RFC 3296), which has referral objects treated as ordinary
+/// entries, so that they can be read and changed.
+pub const OID_MANAGE_DSA_IT: &str = "2.16.840.1.113730.3.4.2";
+/// Relax rules, which lets a client bypass schema checks.
+pub const OID_RELAX_RULES: &str = "1.3.6.1.4.1.4203.666.5.12";
+/// Tree delete, which removes a whole subtree at once.
+pub const OID_TREE_DELETE: &str = "1.2.840.113556.1.4.805";
+/// Persistent search (draft-ietf-ldapext-psearch-03). The entries that it
+/// returns carry an entry change notification control.
+pub const OID_PERSISTENT_SEARCH: &str = "2.16.840.1.113730.3.4.3";
+pub const OID_ENTRY_CHANGE_NOTIFICATION: &str = "2.16.840.1.113730.3.4.7";
+/// Active Directory's change notifications.
+pub const OID_AD_NOTIFICATION: &str = "1.2.840.113556.1.4.528";
+
+/// The controls of searches that only end when they are abandoned. They can't
+/// be decoded, so they are relayed to the backend as the client sent them.
+pub const NOTIFICATION_CONTROLS: &[&str] = &[OID_PERSISTENT_SEARCH, OID_AD_NOTIFICATION];
+
+/// The controls that can be decoded, and so relayed. Other controls are removed
+/// from requests by the ClientCodec.
+pub const SUPPORTED_CONTROLS: &[&str] = &[
+    "1.3.6.1.4.1.4203.1.9.1.1",
+    "1.3.6.1.4.1.4203.1.9.1.2",
+    "1.3.6.1.4.1.4203.1.9.1.3",
+    "1.2.840.113556.1.4.841",
+    "1.2.840.113556.1.4.319",
+    OID_MANAGE_DSA_IT,
+    "1.2.840.113556.1.4.473",
+    "1.2.840.113556.1.4.474",
+    "1.3.6.1.4.1.42.2.27.8.5.1",
+];
+
+pub fn default_denied_controls() -> HashSet<String> {
+    [OID_PROXIED_AUTHZ, OID_RELAX_RULES, OID_TREE_DELETE]
+        .into_iter()
+        .map(str::to_string)
+        .collect()
+}
+
+pub fn control_oid(ctrl: &LdapControl) -> &'static str {
+    match ctrl {
+        LdapControl::SyncRequest { .. } => "1.3.6.1.4.1.4203.1.9.1.1",
+        LdapControl::SyncState { .. } => "1.3.6.1.4.1.4203.1.9.1.2",
+        LdapControl::SyncDone { .. } => "1.3.6.1.4.1.4203.1.9.1.3",
+        LdapControl::AdDirsync { .. } => "1.2.840.113556.1.4.841",
+        LdapControl::SimplePagedResults { .. } => "1.2.840.113556.1.4.319",
+        LdapControl::ManageDsaIT { .. } => OID_MANAGE_DSA_IT,
+        LdapControl::ServerSort { .. } => "1.2.840.113556.1.4.473",
+        LdapControl::ServerSortResult { .. } => "1.2.840.113556.1.4.474",
+        LdapControl::PasswordPolicyRequest { .. } => "1.3.6.1.4.1.42.2.27.8.5.1",
+    }
+}
+
+/// If the control is marked critical, as it would be sent.
+pub fn is_critical(ctrl: &LdapControl) -> bool {
+    match ctrl {
+        LdapControl::SyncRequest { criticality, .. }
+        | LdapControl::ManageDsaIT { criticality }
+        | LdapControl::PasswordPolicyRequest { criticality } => *criticality,
+        LdapControl::AdDirsync { .. } => true,
+        _ => false,
+    }
+}
+
+/// If a search with these controls keeps returning changes until it is
+/// abandoned, as a syncrepl refreshAndPersist search (RFC 4533) does.
+pub fn is_persistent(ctrl: &[LdapControl]) -> bool {
+    ctrl.iter().any(|c| {
+        matches!(
+            c,
+            LdapControl::SyncRequest {
+                mode: SyncRequestMode::RefreshAndPersist,
+                ..
+            }
+        )
+    })
+}
+
+/// If a search is a content synchronisation (syncrepl) search, of either mode.
+pub fn is_sync(ctrl: &[LdapControl]) -> bool {
+    ctrl.iter()
+        .any(|c| matches!(c, LdapControl::SyncRequest { .. }))
+}
+
+/// If the results of a search with these controls depend on what the client
+/// has already seen, so they must not be cached.
+pub fn is_stateful(ctrl: &[LdapControl]) -> bool {
+    ctrl.iter().any(|c| {
+        matches!(
+            c,
+            LdapControl::SyncRequest { .. } | LdapControl::AdDirsync { .. }
+        )
+    })
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ControlPolicy {
+    /// If set, only these controls are permitted.
+    pub allowed: Option<HashSet<String>>,
+    /// These controls are never permitted.
+    pub denied: HashSet<String>,
+}
+
+impl ControlPolicy {
+    pub fn new(allowed: Option<HashSet<String>>, denied: HashSet<String>) -> Self {
+        ControlPolicy { allowed, denied }
+    }
+
+    /// This policy, with the lists that are set replacing its own.
+    pub fn with_overrides(
+        &self,
+        allowed: Option<&HashSet<String>>,
+        denied: Option<&HashSet<String>>,
+    ) -> Self {
+        ControlPolicy {
+            allowed: allowed.cloned().or_else(|| self.allowed.clone()),
+            denied: denied.cloned().unwrap_or_else(|| self.denied.clone()),
+        }
+    }
+
+    pub fn permits(&self, oid: &str) -> bool {
+        !self.denied.contains(oid)
+            && self
+                .allowed
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(oid))
+    }
+
+    /// Remove the controls of a request that are not permitted. If one of them
+    /// is critical, the request must be refused, and its oid is returned.
+    pub fn filter_request(&self, ctrl: Vec<LdapControl>) -> Result<Vec<LdapControl>, &'static str> {
+        let mut permitted = Vec::with_capacity(ctrl.len());
+        for c in ctrl {
+            let oid = control_oid(&c);
+            if self.permits(oid) {
+                permitted.push(c);
+            } else if is_critical(&c) {
+                return Err(oid);
+            } else {
+                debug!(%oid, "Removing control from request");
+            }
+        }
+        Ok(permitted)
+    }
+
+    /// Remove the controls of a response that are not permitted.
+    pub fn filter_response(&self, ctrl: &mut Vec<LdapControl>) {
+        ctrl.retain(|c| self.permits(control_oid(c)));
+    }
+}
+
+/// What is done with an administrative control, ManageDsaIT or relax rules,
+/// by the policy of the DN that sends it. These are handled before, and in
+/// place of, the control lists.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AdminControlAction {
+    /// Relay it to the backend. Relax rules is only relayed with writes.
+    Forward,
+    /// Remove it from the request, whether or not it is critical.
+    Strip,
+    /// Refuse the request with unavailableCriticalExtension, whether or not
+    /// it is critical.
+    #[default]
+    Reject,
+}
+
+/// The administrative controls of a request, once they have been handled.
+#[derive(Debug, Default)]
+pub struct AdminControls {
+    /// The decoded controls to relay, which the control lists don't remove.
+    pub forwarded: Vec<LdapControl>,
+    /// The controls to relay as they were sent.
+    pub forwarded_raw: Vec<RawControl>,
+}
+
+/// Handle the administrative controls of a request by their actions. The
+/// request's decoded controls, and the oids of its critical controls which
+/// can't be relayed, are updated. If the request must be refused, the oid of
+/// the control is returned.
+pub fn admin_controls(
+    manage_dsa_it: AdminControlAction,
+    relax_rules: AdminControlAction,
+    write: bool,
+    ctrl: &mut Vec<LdapControl>,
+    unsupported_critical: &mut Vec<String>,
+    raw: &[RawControl],
+) -> Result<AdminControls, &'static str> {
+    let mut handled = AdminControls::default();
+    if let Some(idx) = ctrl
+        .iter()
+        .position(|c| matches!(c, LdapControl::ManageDsaIT { .. }))
+    {
+        match manage_dsa_it {
+            AdminControlAction::Forward => handled.forwarded.push(ctrl.remove(idx)),
+            AdminControlAction::Strip => {
+                debug!(oid = %OID_MANAGE_DSA_IT, "Removing control from request");
+                ctrl.remove(idx);
+            }
+            AdminControlAction::Reject => return Err(OID_MANAGE_DSA_IT),
+        }
+    }
+    if let Some(relax) = raw.iter().find(|c| c.oid == OID_RELAX_RULES) {
+        match relax_rules {
+            AdminControlAction::Forward if write => {
+                unsupported_critical.retain(|oid| oid != OID_RELAX_RULES);
+                handled.forwarded_raw.push(relax.clone());
+            }
+            // It means nothing to other requests, which are refused if it is
+            // critical.
+            AdminControlAction::Forward => {}
+            AdminControlAction::Strip => {
+                debug!(oid = %OID_RELAX_RULES, "Removing control from request");
+                unsupported_critical.retain(|oid| oid != OID_RELAX_RULES);
+            }
+            AdminControlAction::Reject => return Err(OID_RELAX_RULES),
+        }
+    }
+    Ok(handled)
+}
@@ -0,0 +1,174 @@
+//! Normalisation of distinguished names, so that DNs that differ only in case,
+//! insignificant spaces or escaping compare equal.
+//!
+//! The normal form is RFC 4514 syntax where attribute types are lowercased,
+//! values are case folded with runs of spaces collapsed (as caseIgnoreMatch
+//! compares them), and the spaces around separators are removed. The values of
+//! a multi-valued RDN are sorted. Hex encoded values (`cn=#04...`) are kept as
+//! they are, other than being lowercased.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnError {
+    /// An RDN has no `=` between its attribute type and value.
+    MissingEquals,
+    EmptyAttributeType,
+    /// A `\` is not followed by a special character or two hex digits.
+    InvalidEscape,
+    /// The escaped bytes of a value are not utf8.
+    InvalidUtf8,
+}
+
+impl fmt::Display for DnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnError::MissingEquals => write!(f, "rdn is missing '='"),
+            DnError::EmptyAttributeType => write!(f, "rdn has an empty attribute type"),
+            DnError::InvalidEscape => write!(f, "invalid escape sequence"),
+            DnError::InvalidUtf8 => write!(f, "value is not valid utf8"),
+        }
+    }
+}
+
+impl std::error::Error for DnError {}
+
+fn hex_value(c: char) -> Option<u8> {
+    c.to_digit(16).map(|d| d as u8)
+}
+
+fn push_char(value: &mut Vec<u8>, c: char) {
+    let mut buf = [0; 4];
+    value.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+}
+
+fn escape_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' if i == 0 => escaped.push_str("\\#"),
+            '\0' => escaped.push_str("\\00"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Normalise a DN. The empty (anonymous) DN normalises to the empty string.
+pub fn normalize_dn(dn: &str) -> Result<String, DnError> {
+    if dn.trim().is_empty() {
+        return Ok(String::new());
+    }
+
+    let mut rdns = Vec::new();
+    let mut avas = Vec::new();
+    let mut chars = dn.chars();
+
+    loop {
+        let mut attr = String::new();
+        loop {
+            match chars.next() {
+                Some('=') => break,
+                Some(c) => attr.push(c),
+                None => return Err(DnError::MissingEquals),
+            }
+        }
+        let attr = attr.trim().to_ascii_lowercase();
+        let attr = attr.strip_prefix("oid.").unwrap_or(&attr).to_string();
+        if attr.is_empty() {
+            return Err(DnError::EmptyAttributeType);
+        }
+
+        // The value, and how much of it is significant. Unescaped spaces at
+        // the end are not.
+        let mut value = Vec::new();
+        let mut significant = 0;
+        let mut hex_string = false;
+        let separator = loop {
+            match chars.next() {
+                None => break None,
+                Some(c @ (',' | '+')) => break Some(c),
+                Some('\\') => {
+                    match chars.next() {
+                        Some(high) if hex_value(high).is_some() => {
+                            let low = chars
+                                .next()
+                                .and_then(hex_value)
+                                .ok_or(DnError::InvalidEscape)?;
+                            let high = hex_value(high).ok_or(DnError::InvalidEscape)?;
+                            value.push((high << 4) | low);
+                        }
+                        Some(c) => push_char(&mut value, c),
+                        None => return Err(DnError::InvalidEscape),
+                    }
+                    significant = value.len();
+                }
+                Some(' ') if value.is_empty() => {}
+                Some(c) => {
+                    if c == '#' && value.is_empty() {
+                        hex_string = true;
+                    }
+                    push_char(&mut value, c);
+                    if c != ' ' {
+                        significant = value.len();
+                    }
+                }
+            }
+        };
+        value.truncate(significant);
+        let value = String::from_utf8(value).map_err(|_| DnError::InvalidUtf8)?;
+
+        let value = if hex_string {
+            value.to_lowercase()
+        } else {
+            let folded = value
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .to_lowercase();
+            escape_value(&folded)
+        };
+        avas.push(format!("{}={}", attr, value));
+
+        if separator != Some('+') {
+            avas.sort();
+            rdns.push(avas.join("+"));
+            avas.clear();
+        }
+        if separator.is_none() {
+            break;
+        }
+    }
+
+    Ok(rdns.join(","))
+}
+
+/// The parent of a normalised DN, or None if it has a single RDN.
+pub fn parent_dn(dn: &str) -> Option<&str> {
+    let mut escaped = false;
+    for (i, c) in dn.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ',' => return Some(&dn[i + 1..]),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Is the normalised DN at or below `base`? Every DN is below the empty DN.
+pub fn dn_is_within(dn: &str, base: &str) -> bool {
+    let mut current = Some(dn);
+    while let Some(dn) = current {
+        if dn == base {
+            return true;
+        }
+        current = parent_dn(dn);
+    }
+    base.is_empty()
+}
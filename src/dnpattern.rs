@@ -0,0 +1,178 @@
+//! Bind map entries that match many DNs, by glob or regex, for when many
+//! accounts share the same config. They are only checked for DNs without an
+//! exact entry. Globs take precedence over regexes, and of each, the first in
+//! config order that matches is used.
+//!
+//! The groups captured by a pattern can be used in the bases of
+//! `allowed_queries`, and in `allowed_bases`, as `$1` or `${1}`, so that one
+//! pattern can confine each account to its own subtree. Each `*` of a glob captures a group.
+
+use regex::{Regex, RegexSet};
+use serde::{Deserialize, Deserializer};
+
+use crate::dn::normalize_dn;
+use crate::DnConfig;
+
+#[derive(Debug, Deserialize)]
+struct PatternConfig {
+    glob: Option<String>,
+    regex: Option<String>,
+    #[serde(flatten)]
+    config: DnConfig,
+}
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    // As configured, for messages.
+    source: String,
+    regex: Regex,
+    glob: bool,
+    config: DnConfig,
+}
+
+/// The pattern entries of the bind map, compiled when the config is loaded.
+#[derive(Debug, Clone)]
+pub struct BindDnPatterns {
+    set: RegexSet,
+    patterns: Vec<Pattern>,
+}
+
+impl Default for BindDnPatterns {
+    fn default() -> Self {
+        BindDnPatterns {
+            set: RegexSet::empty(),
+            patterns: Vec::new(),
+        }
+    }
+}
+
+// A glob matches normalised DNs, so it is normalised the same way. A `*` matches
+// any part of an attribute value.
+fn glob_regex(glob: &str) -> Result<String, String> {
+    let normalized = normalize_dn(glob).map_err(|e| e.to_string())?;
+    let parts: Vec<_> = normalized.split('*').map(regex::escape).collect();
+    Ok(format!(r"^{}$", parts.join(r"((?:[^,+\\]|\\.)*)")))
+}
+
+// The highest group number referred to by `$n` or `${n}` in a template.
+fn max_group_reference(template: &str) -> usize {
+    let mut max = 0;
+    let mut rest = template;
+    while let Some(idx) = rest.find('$') {
+        rest = &rest[idx + 1..];
+        let digits = rest.trim_start_matches('{');
+        let len = digits.bytes().take_while(u8::is_ascii_digit).count();
+        if let Ok(group) = digits[..len].parse::<usize>() {
+            max = max.max(group);
+        }
+    }
+    max
+}
+
+impl BindDnPatterns {
+    fn compile(configured: Vec<PatternConfig>) -> Result<Self, String> {
+        let mut patterns = Vec::with_capacity(configured.len());
+        for entry in configured {
+            let (source, regex, glob) = match (entry.glob, entry.regex) {
+                (Some(glob), None) => {
+                    let regex = glob_regex(&glob)
+                        .map_err(|e| format!("invalid bind dn glob '{}': {}", glob, e))?;
+                    (glob, regex, true)
+                }
+                // Regexes are always anchored, and DNs are matched once
+                // normalised, so case doesn't matter.
+                (None, Some(regex)) => (regex.clone(), format!("(?i)^(?:{})$", regex), false),
+                _ => {
+                    return Err("a bind dn pattern needs one of glob or regex".to_string());
+                }
+            };
+            let regex = Regex::new(&regex)
+                .map_err(|e| format!("invalid bind dn pattern '{}': {}", source, e))?;
+
+            let groups = regex.captures_len() - 1;
+            let bases = entry
+                .config
+                .allowed_queries
+                .iter()
+                .map(|(base, _, _)| base)
+                .chain(entry.config.allowed_bases.iter());
+            for base in bases {
+                if max_group_reference(base) > groups {
+                    return Err(format!(
+                        "allowed base '{}' refers to a group that bind dn pattern '{}' doesn't have",
+                        base, source
+                    ));
+                }
+            }
+
+            patterns.push(Pattern {
+                source,
+                regex,
+                glob,
+                config: entry.config,
+            });
+        }
+
+        let set = RegexSet::new(patterns.iter().map(|p| p.regex.as_str()))
+            .map_err(|e| format!("invalid bind dn patterns: {}", e))?;
+        Ok(BindDnPatterns { set, patterns })
+    }
+
+    /// The config of the first glob that matches this normalised DN, or else
+    /// of the first regex, with the groups it captured substituted.
+    pub fn lookup(&self, dn: &str) -> Option<DnConfig> {
+        let matches = self.set.matches(dn);
+        let idx = matches
+            .iter()
+            .find(|&idx| self.patterns[idx].glob)
+            .or_else(|| matches.iter().next())?;
+        let pattern = &self.patterns[idx];
+        let captures = pattern.regex.captures(dn)?;
+
+        let expand = |base: &str| {
+            let mut expanded = String::new();
+            captures.expand(base, &mut expanded);
+            expanded
+        };
+
+        let mut config = pattern.config.clone();
+        config.allowed_bases = pattern
+            .config
+            .allowed_bases
+            .iter()
+            .map(|base| expand(base))
+            .collect();
+        config.allowed_queries = pattern
+            .config
+            .allowed_queries
+            .iter()
+            .map(|(base, scope, filter)| (expand(base), scope.clone(), filter.clone()))
+            .collect();
+        Some(config)
+    }
+
+    /// The patterns as configured, and their configs.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &DnConfig)> {
+        self.patterns
+            .iter()
+            .map(|pattern| (pattern.source.as_str(), &pattern.config))
+    }
+
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+}
+
+impl<'de> Deserialize<'de> for BindDnPatterns {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let configured = Vec::<PatternConfig>::deserialize(deserializer)?;
+        BindDnPatterns::compile(configured).map_err(serde::de::Error::custom)
+    }
+}
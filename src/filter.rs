@@ -0,0 +1,202 @@
+//! Templates of the search filters that a DN may use. A template is a filter
+//! in which a value of `{}` stands for any value, so that
+//! `(&(objectClass=person)(uid={}))` permits looking up one person by uid, but
+//! not listing everyone. A wildcard in place of the `{}` makes a presence or
+//! substring filter, which the template doesn't match.
+
+use std::fmt;
+
+use ldap3_proto::{parse_ldap_filter_str, LdapFilter};
+use serde::{Deserialize, Deserializer};
+
+/// The value in a template that matches any value.
+pub const PLACEHOLDER: &str = "{}";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterTemplate {
+    // As configured, for messages.
+    source: String,
+    filter: LdapFilter,
+}
+
+fn value_matches(template: &str, value: &str) -> bool {
+    template == PLACEHOLDER || template.eq_ignore_ascii_case(value)
+}
+
+fn option_matches(template: &Option<String>, value: &Option<String>) -> bool {
+    match (template, value) {
+        (Some(template), Some(value)) => value_matches(template, value),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+// Filters match if they have the same shape, with the same attributes, and
+// the same values where the template doesn't have a placeholder. The terms of
+// an and or an or must be in the same order.
+fn filter_matches(template: &LdapFilter, filter: &LdapFilter) -> bool {
+    match (template, filter) {
+        (LdapFilter::And(templates), LdapFilter::And(filters))
+        | (LdapFilter::Or(templates), LdapFilter::Or(filters)) => {
+            templates.len() == filters.len()
+                && templates
+                    .iter()
+                    .zip(filters)
+                    .all(|(template, filter)| filter_matches(template, filter))
+        }
+        (LdapFilter::Not(template), LdapFilter::Not(filter)) => filter_matches(template, filter),
+        (LdapFilter::Equality(ta, tv), LdapFilter::Equality(fa, fv))
+        | (LdapFilter::GreaterOrEqual(ta, tv), LdapFilter::GreaterOrEqual(fa, fv))
+        | (LdapFilter::LessOrEqual(ta, tv), LdapFilter::LessOrEqual(fa, fv))
+        | (LdapFilter::Approx(ta, tv), LdapFilter::Approx(fa, fv)) => {
+            ta.eq_ignore_ascii_case(fa) && value_matches(tv, fv)
+        }
+        (LdapFilter::Substring(ta, ts), LdapFilter::Substring(fa, fs)) => {
+            ta.eq_ignore_ascii_case(fa)
+                && option_matches(&ts.initial, &fs.initial)
+                && option_matches(&ts.final_, &fs.final_)
+                && ts.any.len() == fs.any.len()
+                && ts
+                    .any
+                    .iter()
+                    .zip(&fs.any)
+                    .all(|(template, value)| value_matches(template, value))
+        }
+        (LdapFilter::Present(ta), LdapFilter::Present(fa)) => ta.eq_ignore_ascii_case(fa),
+        (LdapFilter::Extensible(template), LdapFilter::Extensible(filter)) => template == filter,
+        _ => false,
+    }
+}
+
+/// The attributes that a filter tests.
+pub fn filter_attributes(filter: &LdapFilter) -> Vec<&str> {
+    match filter {
+        LdapFilter::And(filters) | LdapFilter::Or(filters) => {
+            filters.iter().flat_map(filter_attributes).collect()
+        }
+        LdapFilter::Not(filter) => filter_attributes(filter),
+        LdapFilter::Equality(atype, _)
+        | LdapFilter::GreaterOrEqual(atype, _)
+        | LdapFilter::LessOrEqual(atype, _)
+        | LdapFilter::Approx(atype, _)
+        | LdapFilter::Substring(atype, _)
+        | LdapFilter::Present(atype) => vec![atype.as_str()],
+        LdapFilter::Extensible(assertion) => assertion.type_.as_deref().into_iter().collect(),
+    }
+}
+
+/// A filter in a canonical form, in which attribute names are lowercase, and
+/// the terms of `&` and `|` are sorted without duplicates, so that filters
+/// that only differ in these ways are equal. Values are not changed, as how
+/// they compare depends on the attribute's matching rules.
+pub fn canonical_filter(filter: &LdapFilter) -> LdapFilter {
+    let terms = |filters: &[LdapFilter]| {
+        let mut terms: Vec<_> = filters.iter().map(canonical_filter).collect();
+        terms.sort();
+        terms.dedup();
+        terms
+    };
+    match filter {
+        LdapFilter::And(filters) => LdapFilter::And(terms(filters)),
+        LdapFilter::Or(filters) => LdapFilter::Or(terms(filters)),
+        LdapFilter::Not(filter) => LdapFilter::Not(Box::new(canonical_filter(filter))),
+        LdapFilter::Equality(a, v) => LdapFilter::Equality(a.to_lowercase(), v.clone()),
+        LdapFilter::GreaterOrEqual(a, v) => LdapFilter::GreaterOrEqual(a.to_lowercase(), v.clone()),
+        LdapFilter::LessOrEqual(a, v) => LdapFilter::LessOrEqual(a.to_lowercase(), v.clone()),
+        LdapFilter::Approx(a, v) => LdapFilter::Approx(a.to_lowercase(), v.clone()),
+        LdapFilter::Substring(a, sub) => LdapFilter::Substring(a.to_lowercase(), sub.clone()),
+        LdapFilter::Present(a) => LdapFilter::Present(a.to_lowercase()),
+        LdapFilter::Extensible(assertion) => {
+            let mut assertion = assertion.clone();
+            assertion.type_ = assertion.type_.map(|a| a.to_lowercase());
+            LdapFilter::Extensible(assertion)
+        }
+    }
+}
+
+/// Limits on the size of search filters, as deeply nested or enormous filters
+/// can be expensive for the backend to evaluate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FilterLimits {
+    /// The most levels of `&`, `|` and `!`, counting the terms they contain.
+    pub max_depth: Option<usize>,
+    /// The most terms, of any kind.
+    pub max_terms: Option<usize>,
+    /// The most parts of substring terms, such as the 3 of `(cn=a*b*c)`.
+    pub max_substrings: Option<usize>,
+}
+
+#[derive(Default)]
+struct FilterSize {
+    depth: usize,
+    terms: usize,
+    substrings: usize,
+}
+
+fn measure(filter: &LdapFilter, depth: usize, size: &mut FilterSize) {
+    size.depth = size.depth.max(depth);
+    size.terms += 1;
+    match filter {
+        LdapFilter::And(filters) | LdapFilter::Or(filters) => {
+            for filter in filters {
+                measure(filter, depth + 1, size);
+            }
+        }
+        LdapFilter::Not(filter) => measure(filter, depth + 1, size),
+        LdapFilter::Substring(_, sub) => {
+            size.substrings +=
+                sub.initial.iter().count() + sub.any.len() + sub.final_.iter().count();
+        }
+        _ => {}
+    }
+}
+
+impl FilterLimits {
+    /// The name of the limit that a filter exceeds, if it exceeds any.
+    pub fn exceeded_by(&self, filter: &LdapFilter) -> Option<&'static str> {
+        let mut size = FilterSize::default();
+        measure(filter, 1, &mut size);
+        let over = |limit: Option<usize>, value: usize| limit.is_some_and(|limit| value > limit);
+        if over(self.max_depth, size.depth) {
+            Some("max_filter_depth")
+        } else if over(self.max_terms, size.terms) {
+            Some("max_filter_terms")
+        } else if over(self.max_substrings, size.substrings) {
+            Some("max_filter_substrings")
+        } else {
+            None
+        }
+    }
+}
+
+impl FilterTemplate {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let filter = parse_ldap_filter_str(source)
+            .map_err(|e| format!("invalid filter template '{}': {}", source, e))?;
+        Ok(FilterTemplate {
+            source: source.to_string(),
+            filter,
+        })
+    }
+
+    /// Does the filter of a search match this template?
+    pub fn matches(&self, filter: &LdapFilter) -> bool {
+        filter_matches(&self.filter, filter)
+    }
+}
+
+impl fmt::Display for FilterTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for FilterTemplate {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let source = String::deserialize(deserializer)?;
+        FilterTemplate::parse(&source).map_err(serde::de::Error::custom)
+    }
+}
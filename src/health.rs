@@ -0,0 +1,71 @@
+//! Health and readiness probes over HTTP, on an address of their own, for
+//! orchestrators such as Kubernetes. Unlike the admin API these need no token,
+//! and tell nothing more than whether the proxy is working.
+//!
+//! * `GET /healthz` answers 200 for as long as the process can serve requests.
+//! * `GET /readyz` answers 200 while the listeners are accepting connections
+//!   and every backend pool has an address in rotation, and 503 otherwise, so
+//!   that clients are only sent to a proxy that can reach its backends.
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::admin::{path_segments, read_request, write_response};
+use crate::AppState;
+
+/// The time a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// If any address of each pool may be connected to.
+fn readiness(app_state: &AppState) -> (u16, Value) {
+    let listening = app_state.listening.load(Ordering::Relaxed);
+    let backends: serde_json::Map<String, Value> = app_state
+        .backend_pools
+        .iter()
+        .map(|(name, pool)| {
+            let available = pool.backends.iter().any(|backend| {
+                backend
+                    .addrs()
+                    .iter()
+                    .any(|addr| app_state.breakers.allow(addr))
+            });
+            (name.clone(), Value::Bool(available))
+        })
+        .collect();
+    let ready = listening
+        && backends
+            .values()
+            .all(|available| available.as_bool() == Some(true));
+    let status = if ready { 200 } else { 503 };
+    (
+        status,
+        json!({
+            "ready": ready,
+            "listening": listening,
+            "backends": backends,
+        }),
+    )
+}
+
+fn route(app_state: &AppState, method: &str, path: &str) -> (u16, Value) {
+    match (method, path_segments(path).as_slice()) {
+        ("GET", ["healthz"]) => (200, json!({ "status": "ok" })),
+        ("GET", ["readyz"]) => readiness(app_state),
+        (_, ["healthz"] | ["readyz"]) => (405, json!({ "error": "method not allowed" })),
+        _ => (404, json!({ "error": "not found" })),
+    }
+}
+
+/// Answer one request on a connection to the health endpoints.
+pub async fn health_process<S: AsyncRead + AsyncWrite + Unpin>(stream: S, app_state: &AppState) {
+    let (r, mut w) = tokio::io::split(stream);
+    let (status, body) = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(r)).await {
+        Ok(Some(request)) => route(app_state, &request.method, &request.path),
+        Ok(None) => (400, json!({ "error": "bad request" })),
+        Err(_) => return,
+    };
+    write_response(&mut w, status, &body).await;
+}
@@ -0,0 +1,36 @@
+//! A hook for the site-specific mangling of searches that the rewrite rules
+//! of the config can't express, for programs that embed the proxy as a
+//! library. It is given each search once the config and the access policy
+//! have allowed it, and each entry before it is sent.
+//!
+//! This is a library extension point only. No script or WASM engine is built
+//! in, and the hook can't be set from the config, so the ldap-proxy binary
+//! runs without one.
+
+use std::future::ready;
+
+use ldap3_proto::proto::{LdapSearchRequest, LdapSearchResultEntry};
+
+use crate::access::{Decision, DecisionFuture};
+
+pub trait SearchHook: Send + Sync {
+    /// Called with a search of the DN, before its limits are capped and the
+    /// attribute rewrites are applied. The request may be changed, such as to
+    /// rewrite its filter, and is not checked against the config again. A
+    /// search that is denied is answered with unwillingToPerform.
+    fn request<'a>(
+        &'a self,
+        _dn: &'a str,
+        _request: &'a mut LdapSearchRequest,
+    ) -> DecisionFuture<'a> {
+        Box::pin(ready(Decision::Allow))
+    }
+
+    /// Called with each entry for the DN after the attribute rewrites, and
+    /// before the attributes that the DN may not see are removed. This is
+    /// done as entries are sent, including those from the cache. Returns
+    /// false to drop the entry.
+    fn entry(&self, _dn: &str, _entry: &mut LdapSearchResultEntry) -> bool {
+        true
+    }
+}
@@ -0,0 +1,235 @@
+//! How quickly each address of a backend connects, completes its handshake and
+//! answers binds, and how often it fails to. The times are moving averages, so
+//! that a replica that slows down is noticed within a few connections. With
+//! the latency strategy, the backends and addresses that are slow or failing
+//! are chosen less often.
+
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hashbrown::HashMap;
+use rand::Rng;
+use serde::Serialize;
+
+use crate::AppState;
+
+// The weight of each new time in the moving averages.
+const ALPHA: f64 = 0.2;
+
+// How much slower an address that always fails is treated as being. One that
+// fails half of the time is treated as six times slower.
+const ERROR_PENALTY: f64 = 10.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Connect,
+    Handshake,
+    Bind,
+}
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Connect => "connect",
+            Stage::Handshake => "handshake",
+            Stage::Bind => "bind",
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Stage::Connect => 0,
+            Stage::Handshake => 1,
+            Stage::Bind => 2,
+        }
+    }
+}
+
+pub const STAGES: [Stage; 3] = [Stage::Connect, Stage::Handshake, Stage::Bind];
+
+/// The measurements of one address.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct AddrLatency {
+    /// The moving averages of the times of each stage, in microseconds, once
+    /// one has succeeded.
+    pub connect_us: Option<f64>,
+    pub handshake_us: Option<f64>,
+    pub bind_us: Option<f64>,
+    attempts: [u64; 3],
+    errors: [u64; 3],
+    /// The moving average of the failures of every stage, from 0 to 1.
+    pub error_rate: f64,
+}
+
+impl AddrLatency {
+    /// The moving average of the times of the stage, in microseconds.
+    pub fn average_us(&self, stage: Stage) -> Option<f64> {
+        match stage {
+            Stage::Connect => self.connect_us,
+            Stage::Handshake => self.handshake_us,
+            Stage::Bind => self.bind_us,
+        }
+    }
+
+    /// The attempts of the stage, and how many of them failed.
+    pub fn counts(&self, stage: Stage) -> (u64, u64) {
+        (self.attempts[stage.index()], self.errors[stage.index()])
+    }
+
+    fn average(&mut self, stage: Stage) -> &mut Option<f64> {
+        match stage {
+            Stage::Connect => &mut self.connect_us,
+            Stage::Handshake => &mut self.handshake_us,
+            Stage::Bind => &mut self.bind_us,
+        }
+    }
+
+    /// How slow the address is, as the time that a new connection takes to
+    /// connect, complete its handshake and bind, made longer by its failures.
+    /// None until something has been measured.
+    pub fn score(&self) -> Option<f64> {
+        let times = [self.connect_us, self.handshake_us, self.bind_us];
+        if times.iter().all(Option::is_none) {
+            return None;
+        }
+        let total: f64 = times.iter().flatten().sum();
+        Some(total.max(1.0) * (1.0 + ERROR_PENALTY * self.error_rate))
+    }
+}
+
+/// The measurements of the addresses of a backend.
+#[derive(Debug, Default)]
+pub struct BackendLatency {
+    addrs: Mutex<HashMap<SocketAddr, AddrLatency>>,
+}
+
+impl BackendLatency {
+    /// Record an attempt of a stage with the address, with the time that it
+    /// took if it succeeded.
+    pub fn record(&self, addr: SocketAddr, stage: Stage, took: Option<Duration>) {
+        let mut addrs = self.addrs.lock().unwrap_or_else(|e| e.into_inner());
+        let latency = addrs.entry(addr).or_default();
+        latency.attempts[stage.index()] += 1;
+        let failed = match took {
+            Some(took) => {
+                let us = took.as_secs_f64() * 1_000_000.0;
+                let average = latency.average(stage);
+                *average = Some(match *average {
+                    Some(average) => average + ALPHA * (us - average),
+                    None => us,
+                });
+                0.0
+            }
+            None => {
+                latency.errors[stage.index()] += 1;
+                1.0
+            }
+        };
+        latency.error_rate += ALPHA * (failed - latency.error_rate);
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<AddrLatency> {
+        let addrs = self.addrs.lock().unwrap_or_else(|e| e.into_inner());
+        addrs.get(addr).copied()
+    }
+
+    /// The score of the backend's fastest address, if any has been measured.
+    pub fn best_score(&self) -> Option<f64> {
+        let addrs = self.addrs.lock().unwrap_or_else(|e| e.into_inner());
+        addrs
+            .values()
+            .filter_map(AddrLatency::score)
+            .min_by(f64::total_cmp)
+    }
+
+    pub fn all(&self) -> Vec<(SocketAddr, AddrLatency)> {
+        let addrs = self.addrs.lock().unwrap_or_else(|e| e.into_inner());
+        addrs
+            .iter()
+            .map(|(addr, latency)| (*addr, *latency))
+            .collect()
+    }
+}
+
+/// Order the items for a new connection by their scores. The first is chosen
+/// at random, with a chance in proportion to its weight over its score, and
+/// the rest follow from the fastest. Items that haven't been measured are
+/// scored as the fastest of those that have, so that they are tried.
+pub fn order_by_latency<T: Copy>(
+    items: &mut [T],
+    weight: impl Fn(&T) -> u32,
+    score: impl Fn(&T) -> Option<f64>,
+) {
+    if items.is_empty() {
+        return;
+    }
+    let scores: Vec<_> = items.iter().map(&score).collect();
+    let fastest = scores
+        .iter()
+        .flatten()
+        .copied()
+        .min_by(f64::total_cmp)
+        .unwrap_or(1.0);
+    let chances: Vec<f64> = items
+        .iter()
+        .zip(scores.iter())
+        .map(|(item, score)| f64::from(weight(item)) / score.unwrap_or(fastest))
+        .collect();
+    let total: f64 = chances.iter().sum();
+    let chosen = if total > 0.0 {
+        let mut pick = rand::thread_rng().gen_range(0.0..total);
+        chances
+            .iter()
+            .position(|chance| {
+                let within = pick < *chance;
+                pick -= chance;
+                within
+            })
+            .unwrap_or_default()
+    } else {
+        0
+    };
+
+    // The sort is stable, so ties are broken by the configured order.
+    let mut order: Vec<usize> = (0..items.len()).filter(|i| *i != chosen).collect();
+    order.sort_by(|a, b| {
+        let a = scores[*a].unwrap_or(fastest);
+        let b = scores[*b].unwrap_or(fastest);
+        a.total_cmp(&b)
+    });
+    order.insert(0, chosen);
+    let ordered: Vec<T> = order.into_iter().map(|i| items[i]).collect();
+    items.copy_from_slice(&ordered);
+}
+
+/// Set the measurements of every backend address in the metrics, as
+/// `backend_<stage>_latency_us` gauges of the moving averages, and
+/// `backend_address_attempts_total` and `backend_address_errors_total` by
+/// stage.
+pub fn publish_latency(app_state: &AppState) {
+    for pool in app_state.backend_pools.values() {
+        for backend in pool.backends.iter() {
+            for (addr, latency) in backend.latency().all() {
+                let addr = addr.to_string();
+                let labels = [
+                    ("pool", pool.name.as_str()),
+                    ("backend", backend.url.as_str()),
+                    ("address", addr.as_str()),
+                ];
+                for stage in STAGES {
+                    let (attempts, errors) = latency.counts(stage);
+                    let mut stage_labels = labels.to_vec();
+                    stage_labels.push(("stage", stage.name()));
+                    let metrics = &app_state.metrics;
+                    metrics.set("backend_address_attempts_total", &stage_labels, attempts);
+                    metrics.set("backend_address_errors_total", &stage_labels, errors);
+                    if let Some(us) = latency.average_us(stage) {
+                        let name = format!("backend_{}_latency_us", stage.name());
+                        metrics.set(&name, &labels, us as u64);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! The cache written as LDIF (RFC 2849), so that operators can see exactly
+//! what the proxy would answer from it. Each cached search is a block of
+//! comments, saying who made the search and when it expires, followed by the
+//! entries that it found.
+
+use std::fmt::Write;
+use std::time::Instant;
+
+use ldap3_proto::proto::LdapSearchResultEntry;
+use openssl::base64;
+
+use crate::audit::{filter_string, scope_name};
+use crate::proxy::{CachedValue, SearchCacheKey};
+use crate::AppState;
+
+// A value that can be written as it is, rather than in base64. This is the
+// SAFE-STRING of RFC 2849, without a trailing space, which is easily lost.
+fn is_safe(value: &[u8]) -> bool {
+    match value {
+        [] => true,
+        [b' ' | b':' | b'<', ..] => false,
+        [.., b' '] => false,
+        _ => value
+            .iter()
+            .all(|b| b.is_ascii() && !matches!(b, b'\0' | b'\r' | b'\n')),
+    }
+}
+
+pub(crate) fn write_value(out: &mut String, name: &str, value: &[u8]) {
+    if is_safe(value) {
+        let _ = writeln!(out, "{}: {}", name, String::from_utf8_lossy(value));
+    } else {
+        let _ = writeln!(out, "{}:: {}", name, base64::encode_block(value));
+    }
+}
+
+// Comments end at the end of the line, so control characters are escaped.
+fn comment(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if c.is_control() {
+                format!("\\{:02x}", u32::from(c))
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+fn write_entry(out: &mut String, entry: &LdapSearchResultEntry) {
+    write_value(out, "dn", entry.dn.as_bytes());
+    for attr in entry.attributes.iter() {
+        for value in attr.vals.iter() {
+            write_value(out, &attr.atype, value);
+        }
+    }
+    out.push('\n');
+}
+
+fn write_search(out: &mut String, key: &SearchCacheKey, value: &CachedValue, now: Instant) {
+    let search = &key.search;
+    let _ = writeln!(out, "# bind_dn: {}", comment(&key.bind_dn));
+    let _ = writeln!(
+        out,
+        "# search: base=\"{}\" scope={} filter={} attrs={}",
+        comment(&search.base),
+        scope_name(&search.scope),
+        comment(&filter_string(&search.filter, false)),
+        comment(&search.attrs.join(","))
+    );
+    match value.valid_until.checked_duration_since(now) {
+        Some(ttl) => {
+            let _ = writeln!(out, "# ttl: {}s", ttl.as_secs());
+        }
+        None => out.push_str("# ttl: expired\n"),
+    }
+    let _ = writeln!(
+        out,
+        "# result: {:?}, {} entries",
+        value.result.code,
+        value.entries.len()
+    );
+    for (reference, _) in value.references.iter() {
+        for uri in reference.uris.iter() {
+            let _ = writeln!(out, "# reference: {}", comment(uri));
+        }
+    }
+    out.push('\n');
+    for (entry, _) in value.entries.iter() {
+        write_entry(out, entry);
+    }
+}
+
+/// Every cached search, and its entries, as LDIF.
+pub fn cache_ldif(app_state: &AppState) -> String {
+    let now = Instant::now();
+    // Nothing is changed, so this is not committed.
+    let mut searches: Vec<_> = {
+        let cache_write_txn = app_state.cache.write();
+        cache_write_txn
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    };
+    searches.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut out = String::from("version: 1\n\n");
+    for (key, value) in searches.iter() {
+        write_search(&mut out, key, value, now);
+    }
+    out
+}
@@ -1,37 +1,1158 @@
 use std::collections::BTreeMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
+use arc_swap::ArcSwap;
 use concread::arcache::ARCache;
 use hashbrown::HashSet;
+use ipnet::IpNet;
+use ldap3_proto::proto::{LdapBindCred, LdapBindRequest, LdapSearchResultEntry};
 use ldap3_proto::{LdapFilter, LdapSearchScope};
-use openssl::ssl::SslConnector;
-use serde::Deserialize;
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::ssl::{SslConnectorBuilder, SslVersion};
+use openssl::x509::X509Ref;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Deserializer};
+use tracing::{info, warn};
 use url::Url;
 
+pub mod access;
+pub mod adcompat;
+pub mod admin;
+pub mod attrmap;
+pub mod audit;
+pub mod bindcache;
+pub mod breaker;
+pub mod cacheindex;
+pub mod certmap;
+pub mod cldap;
+pub mod coalesce;
+pub mod codec;
+pub mod config;
+pub mod connections;
+pub mod controls;
+pub mod dn;
+pub mod dnpattern;
+pub mod filter;
+pub mod health;
+pub mod hook;
+pub mod latency;
+pub mod ldif;
+pub mod lockout;
+pub mod memberof;
+pub mod metrics;
+pub mod otlp;
+pub mod persist;
+pub mod ppolicy;
 pub mod proxy;
+pub mod proxy_protocol;
+pub mod quota;
+pub mod ratelimit;
+pub mod referral;
+pub mod retry;
+pub mod rewrite;
+pub mod rootdse;
+pub mod schema;
+pub mod secrets;
+pub mod server;
+pub mod sort;
+pub mod systemd;
+pub mod tap;
+pub mod tcp;
+pub mod tls;
+pub mod vlv;
 
-use crate::proxy::{CachedValue, SearchCacheKey};
+use crate::access::AccessPolicy;
+use crate::attrmap::AttrRewrite;
+use crate::audit::{AuditConfig, AuditLog, FilterValues};
+use crate::bindcache::BindCache;
+use crate::breaker::CircuitBreakers;
+use crate::cacheindex::CacheIndex;
+use crate::certmap::{CertMap, UnmappedCertPolicy};
+use crate::cldap::CldapRelay;
+use crate::coalesce::{CacheRefreshes, SearchFlights};
+use crate::config::Secret;
+use crate::connections::{ConnectionTracker, SessionRegistry};
+use crate::controls::{default_denied_controls, AdminControlAction, ControlPolicy};
+use crate::dn::{dn_is_within, normalize_dn, parent_dn};
+use crate::dnpattern::BindDnPatterns;
+use crate::filter::{filter_attributes, FilterLimits, FilterTemplate};
+use crate::hook::SearchHook;
+use crate::latency::{order_by_latency, BackendLatency};
+use crate::lockout::BindFailureTracker;
+use crate::memberof::MemberOfConfig;
+use crate::metrics::Metrics;
+use crate::otlp::OtlpConfig;
+use crate::proxy::{CachedValue, SearchCacheKey, ServiceConnections, UpstreamPool};
+use crate::quota::DnQuotas;
+use crate::ratelimit::BindRateLimiter;
+use crate::referral::ReferralMode;
+use crate::retry::RetryPolicy;
+use crate::rewrite::DnRewrite;
+use crate::rootdse::{RootDse, RootDseConfig};
+use crate::schema::{SchemaCache, SchemaConfig};
+use crate::secrets::{SecretCache, SecretProvider};
+use crate::sort::SortMode;
+use crate::tap::{TapConfig, TapLog};
+use crate::tcp::TcpOptions;
+use crate::tls::TlsConnector;
 
 const MEGABYTES: usize = 1048576;
 
+/// How connections to a backend are secured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// ldaps, tls from the start.
+    Tls,
+    /// ldap, upgraded to tls with the StartTLS extended operation.
+    StartTls,
+    /// ldap, without tls.
+    Plain,
+}
+
+// Decode the %XX escapes of a url component.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// The socket path of an ldapi:// url. This is usually the host, with its
+/// slashes escaped as in `ldapi://%2Fvar%2Frun%2Fslapd%2Fldapi`, but a path
+/// such as `ldapi:///var/run/slapd/ldapi` is accepted as well.
+pub fn ldapi_socket_path(url: &Url) -> Option<PathBuf> {
+    let path = match url.host_str() {
+        Some(host) if !host.is_empty() => percent_decode(host)?,
+        _ => percent_decode(url.path())?,
+    };
+    if path.starts_with('/') {
+        Some(PathBuf::from(path))
+    } else {
+        None
+    }
+}
+
+/// A backend ldap server, and the addresses it last resolved to.
+#[derive(Debug)]
+pub struct Backend {
+    pub url: Url,
+    pub hostname: String,
+    pub port: u16,
+    pub transport: Transport,
+    /// The unix socket of an ldapi:// backend, which is connected to instead
+    /// of any address.
+    pub socket_path: Option<PathBuf>,
+    /// How often this backend is chosen by the weighted strategy, relative to
+    /// the others in its pool.
+    pub weight: u32,
+    addrs: RwLock<Vec<SocketAddr>>,
+    // The strategy of the pool, which also spreads connections across the
+    // addresses of the backend.
+    strategy: BackendStrategy,
+    addr_counter: AtomicUsize,
+    open_connections: Arc<AtomicUsize>,
+    latency: Arc<BackendLatency>,
+}
+
+impl Backend {
+    /// The transport is chosen by the scheme of the url: ldap:// is plain, and
+    /// anything else is tls.
+    pub fn new(url: Url, hostname: String, port: u16, addrs: Vec<SocketAddr>) -> Self {
+        let transport = match url.scheme() {
+            "ldap" => Transport::Plain,
+            _ => Transport::Tls,
+        };
+        Backend {
+            url,
+            hostname,
+            port,
+            transport,
+            socket_path: None,
+            weight: 1,
+            addrs: RwLock::new(addrs),
+            strategy: BackendStrategy::Ordered,
+            addr_counter: AtomicUsize::new(0),
+            open_connections: Arc::new(AtomicUsize::new(0)),
+            latency: Arc::new(BackendLatency::default()),
+        }
+    }
+
+    /// A backend on the unix socket at this path, such as a local slapd.
+    pub fn unix(url: Url, path: PathBuf) -> Self {
+        let mut backend = Backend::new(url, path.display().to_string(), 0, Vec::new());
+        backend.transport = Transport::Plain;
+        backend.socket_path = Some(path);
+        backend
+    }
+
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    /// The count of open connections to this backend, which the connections
+    /// hold while they are open.
+    pub fn open_connections(&self) -> &Arc<AtomicUsize> {
+        &self.open_connections
+    }
+
+    /// How quickly the addresses of this backend have answered, which is
+    /// recorded by the connections to them.
+    pub fn latency(&self) -> &Arc<BackendLatency> {
+        &self.latency
+    }
+
+    /// Use StartTLS for this backend, if it is ldap://.
+    pub fn with_starttls(mut self) -> Self {
+        if self.transport == Transport::Plain {
+            self.transport = Transport::StartTls;
+        }
+        self
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.addrs.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// The addresses in the order they should be tried. Unless the pool is
+    /// ordered, each connection starts from the next address, or a random one,
+    /// so that the first address doesn't take every connection.
+    pub fn addrs_in_order(&self) -> Vec<SocketAddr> {
+        let mut addrs = self.addrs();
+        match self.strategy {
+            BackendStrategy::Ordered => {}
+            BackendStrategy::Random => addrs.shuffle(&mut rand::thread_rng()),
+            BackendStrategy::Latency => order_by_latency(
+                &mut addrs,
+                |_| 1,
+                |addr| self.latency.get(addr).and_then(|latency| latency.score()),
+            ),
+            _ if addrs.is_empty() => {}
+            _ => {
+                let start = self.addr_counter.fetch_add(1, Ordering::Relaxed);
+                let len = addrs.len();
+                addrs.rotate_left(start % len);
+            }
+        }
+        addrs
+    }
+
+    /// If this backend has any addresses to connect to.
+    pub fn is_resolved(&self) -> bool {
+        self.socket_path.is_some()
+            || !self
+                .addrs
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .is_empty()
+    }
+
+    /// Resolve the hostname again, replacing the addresses of this backend. If
+    /// resolution fails the previous addresses are kept.
+    pub async fn resolve(&self) {
+        if self.socket_path.is_some() {
+            return;
+        }
+        // Ipv6 literals are bracketed in urls.
+        let host = self.hostname.trim_start_matches('[').trim_end_matches(']');
+        let addrs: Vec<_> = match tokio::net::lookup_host((host, self.port)).await {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => {
+                warn!(?e, url = %self.url, "Unable to resolve backend, keeping previous addresses");
+                return;
+            }
+        };
+
+        if addrs.is_empty() {
+            warn!(url = %self.url, "Backend resolved to no addresses, keeping previous addresses");
+            return;
+        }
+
+        let mut current = self.addrs.write().unwrap_or_else(|e| e.into_inner());
+        if *current != addrs {
+            info!(url = %self.url, ?addrs, "Backend addresses have changed");
+            *current = addrs;
+        }
+    }
+}
+
+/// How a backend is chosen for a new connection. If the chosen backend can't be
+/// reached the remaining backends are tried in order.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendStrategy {
+    /// Always prefer the first backend.
+    #[default]
+    Ordered,
+    RoundRobin,
+    Random,
+    /// Prefer the backend with the fewest open connections from the proxy.
+    LeastOutstanding,
+    /// Choose at random, in proportion to the weights of the backends.
+    Weighted,
+    /// Choose at random, in proportion to the weights of the backends over
+    /// how long their fastest addresses take to connect, complete the
+    /// handshake and bind, made longer by their failures, so that slow
+    /// replicas are chosen less often.
+    Latency,
+}
+
+/// The name of the backend pool built from the top level ldap_url.
+pub const DEFAULT_BACKEND: &str = "default";
+
+/// A version of TLS.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    fn ssl_version(self) -> SslVersion {
+        match self {
+            TlsVersion::Tls10 => SslVersion::TLS1,
+            TlsVersion::Tls11 => SslVersion::TLS1_1,
+            TlsVersion::Tls12 => SslVersion::TLS1_2,
+            TlsVersion::Tls13 => SslVersion::TLS1_3,
+        }
+    }
+}
+
+/// The TLS that connections to a backend pool may use. Unset versions and
+/// ciphers are left to the openssl defaults.
+#[derive(Debug, Clone)]
+pub struct BackendTls {
+    pub min_version: Option<TlsVersion>,
+    pub max_version: Option<TlsVersion>,
+    /// The cipher list of TLS 1.2 and earlier, in openssl's format.
+    pub cipher_list: Option<String>,
+    /// The ciphersuites of TLS 1.3, in openssl's format.
+    pub ciphersuites: Option<String>,
+    /// If set, backends must present a certificate with one of these public
+    /// keys, whoever signed it.
+    pub spki_pins: Vec<SpkiPin>,
+    /// Check that backends' certificates name them. Their certificates are
+    /// verified against the CA either way.
+    pub verify_hostname: bool,
+    /// The name to send in SNI, and to verify certificates against, instead of
+    /// each backend's hostname.
+    pub sni_name: Option<String>,
+}
+
+impl Default for BackendTls {
+    fn default() -> Self {
+        BackendTls {
+            min_version: None,
+            max_version: None,
+            cipher_list: None,
+            ciphersuites: None,
+            spki_pins: Vec::new(),
+            verify_hostname: true,
+            sni_name: None,
+        }
+    }
+}
+
+impl BackendTls {
+    pub fn apply(&self, builder: &mut SslConnectorBuilder) -> Result<(), ErrorStack> {
+        builder.set_min_proto_version(self.min_version.map(TlsVersion::ssl_version))?;
+        builder.set_max_proto_version(self.max_version.map(TlsVersion::ssl_version))?;
+        if let Some(cipher_list) = self.cipher_list.as_deref() {
+            builder.set_cipher_list(cipher_list)?;
+        }
+        if let Some(ciphersuites) = self.ciphersuites.as_deref() {
+            builder.set_ciphersuites(ciphersuites)?;
+        }
+        Ok(())
+    }
+}
+
+/// The sha256 hash of a certificate's public key, as its DER encoded
+/// SubjectPublicKeyInfo, written `sha256:<hex>`. Unlike a fingerprint of the
+/// certificate, this stays the same when a certificate is renewed with the same
+/// key.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct SpkiPin(String);
+
+impl SpkiPin {
+    /// The pin of this certificate's public key.
+    pub fn of(cert: &X509Ref) -> Result<Self, ErrorStack> {
+        let spki = cert.public_key()?.public_key_to_der()?;
+        let digest = hash(MessageDigest::sha256(), &spki)?;
+        let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        Ok(SpkiPin(format!("sha256:{}", hex)))
+    }
+}
+
+impl TryFrom<String> for SpkiPin {
+    type Error = String;
+
+    fn try_from(pin: String) -> Result<Self, Self::Error> {
+        let pin = pin.to_lowercase();
+        match pin.strip_prefix("sha256:") {
+            Some(hex) if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) => {
+                Ok(SpkiPin(pin))
+            }
+            _ => Err(format!("invalid spki pin '{}', expected sha256:<hex>", pin)),
+        }
+    }
+}
+
+impl std::fmt::Display for SpkiPin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// How long to wait on the connections to a backend pool.
+#[derive(Debug, Clone, Copy)]
+pub struct BackendTimeouts {
+    /// The longest wait to connect to a backend address, including the TLS or
+    /// StartTLS handshake.
+    pub connect: Duration,
+    /// The longest wait for each message of a response. If it passes, the
+    /// connection is treated as lost.
+    pub read: Option<Duration>,
+    /// The longest time an operation may take. Searches that take longer end
+    /// with timeLimitExceeded, and other operations are treated as if their
+    /// connection was lost.
+    pub operation: Option<Duration>,
+}
+
+impl Default for BackendTimeouts {
+    fn default() -> Self {
+        BackendTimeouts {
+            connect: Duration::from_secs(default_connect_timeout_secs()),
+            read: None,
+            operation: None,
+        }
+    }
+}
+
+/// A set of interchangeable backend servers that share a tls configuration.
+pub struct BackendPool {
+    pub name: String,
+    pub tls_params: TlsConnector,
+    pub backends: Vec<Backend>,
+    pub strategy: BackendStrategy,
+    pub counter: AtomicUsize,
+    pub timeouts: BackendTimeouts,
+    /// How backends' certificates are checked after the handshake.
+    pub tls: BackendTls,
+    pub tcp: TcpOptions,
+}
+
+impl BackendPool {
+    pub fn new(
+        name: &str,
+        tls_params: impl Into<TlsConnector>,
+        mut backends: Vec<Backend>,
+        strategy: BackendStrategy,
+    ) -> Self {
+        for backend in backends.iter_mut() {
+            backend.strategy = strategy;
+        }
+        BackendPool {
+            name: name.to_string(),
+            tls_params: tls_params.into(),
+            backends,
+            strategy,
+            counter: AtomicUsize::new(0),
+            timeouts: BackendTimeouts::default(),
+            tls: BackendTls::default(),
+            tcp: TcpOptions::default(),
+        }
+    }
+
+    pub fn with_timeouts(mut self, timeouts: BackendTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn with_tls(mut self, tls: BackendTls) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn with_tcp(mut self, tcp: TcpOptions) -> Self {
+        self.tcp = tcp;
+        self
+    }
+
+    /// The order that backends should be tried in, for a new connection.
+    pub fn order(&self) -> Vec<&Backend> {
+        let mut backends: Vec<_> = self.backends.iter().collect();
+        match self.strategy {
+            BackendStrategy::Ordered => {}
+            BackendStrategy::RoundRobin => {
+                if !backends.is_empty() {
+                    let start = self.counter.fetch_add(1, Ordering::Relaxed);
+                    let len = backends.len();
+                    backends.rotate_left(start % len);
+                }
+            }
+            BackendStrategy::Random => {
+                backends.shuffle(&mut rand::thread_rng());
+            }
+            // The sort is stable, so ties are broken by the configured order.
+            BackendStrategy::LeastOutstanding => {
+                backends.sort_by_key(|backend| backend.open_connections.load(Ordering::Relaxed));
+            }
+            // The chosen backend comes first, and the rest follow in order.
+            BackendStrategy::Weighted => {
+                let total: u32 = backends.iter().map(|backend| backend.weight).sum();
+                if total > 0 {
+                    let mut pick = rand::thread_rng().gen_range(0..total);
+                    let chosen = backends
+                        .iter()
+                        .position(|backend| {
+                            let within = pick < backend.weight;
+                            pick = pick.saturating_sub(backend.weight);
+                            within
+                        })
+                        .unwrap_or_default();
+                    backends[..=chosen].rotate_right(1);
+                }
+            }
+            BackendStrategy::Latency => order_by_latency(
+                &mut backends,
+                |backend| backend.weight,
+                |backend| backend.latency.best_score(),
+            ),
+        }
+        backends
+    }
+}
+
 pub struct AppState {
-    pub tls_params: SslConnector,
-    pub addrs: Vec<SocketAddr>,
-    // Cache later here.
-    pub binddn_map: BTreeMap<String, DnConfig>,
+    /// Backend pools by name. This always contains DEFAULT_BACKEND.
+    pub backend_pools: BTreeMap<String, BackendPool>,
+    /// Normalised bind DN suffixes, and the backend pools that their DNs
+    /// connect to.
+    pub backend_suffixes: Vec<(String, String)>,
+    /// Normalised naming contexts, and the backend pools that hold them.
+    pub naming_contexts: Vec<(String, String)>,
+    /// Normalised forest roots, and the global catalog pools that answer
+    /// subtree searches of them.
+    pub global_catalogs: Vec<(String, String)>,
+    /// The pools that searches no naming context routes are fanned out to.
+    pub fan_out: Vec<String>,
+    pub breakers: CircuitBreakers,
+    pub retry: RetryPolicy,
+    pub connections: Arc<ConnectionTracker>,
+    /// Limits binds per client address, if configured.
+    pub bind_limiter: Option<BindRateLimiter>,
+    /// Failed binds, for reporting and locking out brute force attempts.
+    pub bind_failures: BindFailureTracker,
+    /// Idle backend connections that may be reused by new sessions.
+    pub upstream_pool: UpstreamPool,
+    /// Backend connections bound as service accounts, shared by the sessions
+    /// of DNs with a service bind.
+    pub service_connections: ServiceConnections,
+    /// The passwords fetched from secret providers.
+    pub secrets: SecretCache,
+    /// Recent successful binds, if they are cached.
+    pub bind_cache: Option<BindCache>,
+    pub metrics: Metrics,
+    /// Operations are also counted by the DN of their session.
+    pub per_dn_metrics: bool,
+    /// Read the reasons of Active Directory for refusing binds.
+    pub ad_compat: bool,
+    /// How the values of search filters are written to the log.
+    pub log_filter_values: FilterValues,
+    /// The settings that a reload of the config replaces.
+    pub policy: ArcSwap<Policy>,
     pub cache: ARCache<SearchCacheKey, CachedValue>,
-    pub cache_entry_timeout: Duration,
+    /// Entries are weighed as at least this many bytes, so that the cache holds
+    /// no more than `max_cache_entries` of them.
+    pub cache_min_entry_weight: usize,
+    /// The cached searches by base, for invalidating them after writes.
+    pub cache_index: CacheIndex,
+    /// The backend searches that searches which missed the cache wait for,
+    /// unless coalesce_searches is off.
+    pub search_flights: Option<SearchFlights>,
+    /// The expired cached searches that are being refreshed.
+    pub cache_refreshes: CacheRefreshes,
+    /// The usage of the bind DNs' quotas.
+    pub dn_quotas: DnQuotas,
+    /// Client sessions with no traffic for this long are disconnected.
+    pub idle_timeout: Option<Duration>,
+    /// The most operations that a session may have in flight. Further requests
+    /// are refused with busy until one completes.
+    pub max_session_operations: Option<usize>,
+    /// Searches with the sort control are sorted by the backend, rather than
+    /// by the proxy.
+    pub backend_sorts: bool,
     pub max_incoming_ber_size: Option<usize>,
+    /// The socket options of client connections.
+    pub client_tcp: TcpOptions,
+    /// The port of the backends that CLDAP pings are relayed to, and the
+    /// networks that may send them. Any network may when this is empty.
+    pub cldap_port: u16,
+    pub cldap_allowed_networks: Vec<IpNet>,
+    /// The sockets that CLDAP pings are relayed to backends on.
+    pub cldap_relay: CldapRelay,
     pub max_proxy_ber_size: Option<usize>,
+    pub max_cacheable_result_bytes: Option<usize>,
+    pub max_relayed_entries: Option<usize>,
+    /// Searches are relayed as their entries arrive once they have more than
+    /// this many, or from the first if they aren't cached.
+    pub max_cacheable_entries: Option<usize>,
+    pub referral_mode: ReferralMode,
+    /// The host:port that rewritten referrals point to.
+    pub referral_rewrite_host: String,
+    /// Where rewritten referrals to particular backends point instead.
+    pub referral_rewrite_map: BTreeMap<String, String>,
+    /// Referrals that are chased are followed at most this many times.
+    pub referral_hop_limit: usize,
+    /// The servers that referrals may be chased to, by host:port or host.
+    pub referral_chase_hosts: Vec<String>,
+    /// Clients connect through a load balancer that sends a PROXY protocol header.
+    pub expect_proxy_protocol: bool,
+    /// The load balancers whose PROXY protocol headers are believed.
+    pub proxy_protocol_trusted_networks: Vec<IpNet>,
+    /// Service accounts that clients with these certificates bind as.
+    pub cert_map: CertMap,
+    /// Refuse every bind from clients without a mapped certificate.
+    pub reject_unmapped_cert_binds: bool,
+    /// The bind that anonymous binds are sent to the backend as, if not as
+    /// themselves.
+    pub anonymous_bind: Option<LdapBindRequest>,
+    /// The root DSE that the proxy answers with, if configured.
+    pub root_dse: Option<RootDse>,
+    /// The copy of the subschema subentry, if searches for it are answered
+    /// by the proxy.
+    pub schema: Option<SchemaCache>,
+    /// Decides binds and searches after the config has allowed them.
+    pub access: Arc<dyn AccessPolicy>,
+    /// Passed each search and entry, if set.
+    pub search_hook: Option<Arc<dyn SearchHook>>,
+    /// Maps the DNs that clients use to the backend's, and back.
+    pub dn_rewrite: Option<Arc<DnRewrite>>,
+    /// Where the groups are, for synthesizing memberOf.
+    pub member_of: Option<MemberOfConfig>,
+    /// Where client operations are recorded, if anywhere.
+    pub audit: Option<Arc<AuditLog>>,
+    pub tap: Option<Arc<TapLog>>,
+    /// The live client sessions.
+    pub sessions: Arc<SessionRegistry>,
+    /// The listeners are accepting connections, so the proxy is ready for
+    /// clients if its backends are.
+    pub listening: AtomicBool,
+}
+
+/// The settings that can be changed by reloading the config, without a
+/// restart. Sessions pick up the new settings at their next operation.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// The bind maps, keyed by normalised DN.
+    pub binddn_map: BTreeMap<String, DnConfig>,
+    /// Bind map entries for DNs that match a pattern.
+    pub binddn_patterns: BindDnPatterns,
+    pub allowed_client_networks: Vec<IpNet>,
+    pub denied_client_networks: Vec<IpNet>,
+    /// The controls relayed to the backend, and back to clients, unless a DN
+    /// overrides them.
+    pub request_controls: ControlPolicy,
+    pub response_controls: ControlPolicy,
     pub allow_all_bind_dns: bool,
+    /// If DNs that don't set allow_write may write.
+    pub allow_write: bool,
+    /// Refuse every write and password modify, whatever the DN's config.
+    pub read_only: bool,
+    /// The extended operations that every DN may forward to the backend, by
+    /// oid, as well as those of its own config.
+    pub allowed_extended_oids: HashSet<String>,
+    pub cache_entry_timeout: Duration,
+    /// The cache TTLs of normalised bases.
+    pub cache_ttls: BTreeMap<String, Duration>,
+    /// How long searches that found nothing remain in the cache, if not as
+    /// long as others.
+    pub negative_cache_ttl: Option<Duration>,
+    /// How long after they expire cached searches are still answered with,
+    /// while they are refreshed.
+    pub cache_max_stale: Option<Duration>,
+    /// The oid of a control that clients send on a search to skip the cache.
+    pub cache_bypass_control: Option<String>,
+    /// Searches with larger filters are refused.
+    pub filter_limits: FilterLimits,
+}
+
+impl Policy {
+    pub fn from_config(config: &Config) -> Self {
+        Policy {
+            binddn_map: config.binddn_map.clone(),
+            binddn_patterns: config.binddn_patterns.clone(),
+            allowed_client_networks: config.allowed_client_networks.clone(),
+            denied_client_networks: config.denied_client_networks.clone(),
+            request_controls: ControlPolicy::new(
+                config.allowed_controls.clone(),
+                config.denied_controls.clone(),
+            ),
+            response_controls: ControlPolicy::new(
+                config.allowed_response_controls.clone(),
+                config.denied_response_controls.clone(),
+            ),
+            allow_all_bind_dns: config.allow_all_bind_dns,
+            allow_write: config.allow_write,
+            read_only: config.read_only,
+            allowed_extended_oids: config.allowed_extended_oids.clone(),
+            cache_entry_timeout: Duration::from_secs(config.cache_entry_timeout),
+            cache_ttls: config
+                .cache_ttl
+                .iter()
+                .map(|(base, secs)| (base.clone(), Duration::from_secs(*secs)))
+                .collect(),
+            negative_cache_ttl: config.negative_cache_ttl_secs.map(Duration::from_secs),
+            cache_max_stale: config.cache_max_stale_secs.map(Duration::from_secs),
+            cache_bypass_control: config.cache_bypass_control_oid.clone(),
+            filter_limits: FilterLimits {
+                max_depth: config.max_filter_depth,
+                max_terms: config.max_filter_terms,
+                max_substrings: config.max_filter_substrings,
+            },
+        }
+    }
+
+    /// How long the results of a search by a DN remain in the cache. The TTL of
+    /// the DN and that of the most specific base that the search is within
+    /// replace cache_entry_timeout, and if both are set the shorter is used. A
+    /// TTL of zero means that the search isn't cached.
+    pub fn cache_ttl(&self, config: &DnConfig, base: &str) -> Duration {
+        if config.never_cache {
+            return Duration::ZERO;
+        }
+        let base_ttl = normalize_dn(base).ok().and_then(|base| {
+            self.cache_ttls
+                .iter()
+                .filter(|(ttl_base, _)| dn_is_within(&base, ttl_base))
+                .max_by_key(|(ttl_base, _)| ttl_base.len())
+                .map(|(_, ttl)| *ttl)
+        });
+        let dn_ttl = config.cache_ttl_secs.map(Duration::from_secs);
+        match (dn_ttl, base_ttl) {
+            (Some(dn_ttl), Some(base_ttl)) => dn_ttl.min(base_ttl),
+            (Some(ttl), None) | (None, Some(ttl)) => ttl,
+            (None, None) => self.cache_entry_timeout,
+        }
+    }
+
+    /// The config of a normalised bind DN. Exact entries of the bind map take
+    /// precedence over patterns.
+    pub fn dn_config(&self, dn: &str) -> Option<DnConfig> {
+        self.binddn_map
+            .get(dn)
+            .cloned()
+            .or_else(|| self.binddn_patterns.lookup(dn))
+    }
+
+    /// If a client from this address may connect. Denied networks take precedence
+    /// over allowed networks, and if no networks are allowed then all are.
+    pub fn client_network_permitted(&self, ip: IpAddr) -> bool {
+        if network_contains(&self.denied_client_networks, ip) {
+            return false;
+        }
+        self.allowed_client_networks.is_empty()
+            || network_contains(&self.allowed_client_networks, ip)
+    }
+
+    /// The DN configs of the bind maps and the patterns, with their DNs.
+    pub fn dn_configs(&self) -> impl Iterator<Item = (&str, &DnConfig)> {
+        self.binddn_map
+            .iter()
+            .map(|(dn, dnconfig)| (dn.as_str(), dnconfig))
+            .chain(self.binddn_patterns.iter())
+    }
+}
+
+/// If an address is within any of these networks.
+pub fn network_contains(networks: &[IpNet], ip: IpAddr) -> bool {
+    // Ipv4 clients of an ipv6 listener appear as ipv4 mapped addresses.
+    let ip = ip.to_canonical();
+    networks.iter().any(|net| net.contains(&ip))
+}
+
+impl AppState {
+    /// If a client from this address may connect, by the current policy.
+    pub fn client_network_permitted(&self, ip: IpAddr) -> bool {
+        self.policy.load().client_network_permitted(ip)
+    }
+
+    /// The request and response control policies for sessions of this DN.
+    pub fn control_policies(&self, config: &DnConfig) -> (ControlPolicy, ControlPolicy) {
+        let policy = self.policy.load();
+        (
+            policy.request_controls.with_overrides(
+                config.allowed_controls.as_ref(),
+                config.denied_controls.as_ref(),
+            ),
+            policy.response_controls.with_overrides(
+                config.allowed_response_controls.as_ref(),
+                config.denied_response_controls.as_ref(),
+            ),
+        )
+    }
+
+    /// If every backend pool has at least one backend with addresses to connect to.
+    pub fn is_ready(&self) -> bool {
+        self.backend_pools
+            .values()
+            .all(|pool| pool.backends.iter().any(|backend| backend.is_resolved()))
+    }
+
+    /// The config of a normalised bind DN, from the current policy.
+    pub fn dn_config(&self, dn: &str) -> Option<DnConfig> {
+        self.policy.load().dn_config(dn)
+    }
+
+    /// Change the current policy. Sessions pick up the change at their next
+    /// operation.
+    pub fn update_policy(&self, f: impl FnOnce(&mut Policy)) {
+        let mut policy = Policy::clone(&self.policy.load());
+        f(&mut policy);
+        self.policy.store(Arc::new(policy));
+    }
+
+    /// The backend pool that a normalised DN with this config should connect
+    /// to. The backend of the config is used if it has one, and otherwise the
+    /// backend with the longest bind DN suffix that the DN is under.
+    pub fn backend_pool(&self, dn: &str, config: &DnConfig) -> Option<&BackendPool> {
+        let name = match config.backend.as_deref() {
+            Some(name) => name,
+            None => self
+                .backend_suffixes
+                .iter()
+                .filter(|(suffix, _)| dn_is_within(dn, suffix))
+                .max_by_key(|(suffix, _)| suffix.len())
+                .map_or(DEFAULT_BACKEND, |(_, name)| name.as_str()),
+        };
+        self.backend_pools.get(name)
+    }
+
+    /// The backend pools that a search from a session of `pool` is sent to.
+    /// A subtree search based at a forest root goes to its global catalog. A
+    /// search based within a naming context goes to the pool that holds it,
+    /// the deepest if there are several. If `aggregate`, a search whose scope
+    /// reaches into naming contexts goes to their pools as well as `pool`,
+    /// and one that no naming context routes goes to the fan out pools too.
+    pub fn search_routes<'a>(
+        &'a self,
+        pool: &'a str,
+        base: &str,
+        scope: &LdapSearchScope,
+        aggregate: bool,
+    ) -> Vec<&'a str> {
+        let Ok(base) = normalize_dn(base) else {
+            return vec![pool];
+        };
+        if *scope == LdapSearchScope::Subtree {
+            let catalog = self.global_catalogs.iter().find(|(root, _)| *root == base);
+            if let Some((_, name)) = catalog {
+                return vec![name.as_str()];
+            }
+        }
+        let within = self
+            .naming_contexts
+            .iter()
+            .filter(|(context, _)| dn_is_within(&base, context))
+            .max_by_key(|(context, _)| context.len());
+        if let Some((_, name)) = within {
+            return vec![name.as_str()];
+        }
+
+        let mut routes = vec![pool];
+        if aggregate {
+            for name in self.fan_out.iter() {
+                if !routes.contains(&name.as_str()) {
+                    routes.push(name);
+                }
+            }
+            let reached = self
+                .naming_contexts
+                .iter()
+                .filter(|(context, _)| match scope {
+                    LdapSearchScope::Subtree => dn_is_within(context, &base),
+                    LdapSearchScope::OneLevel => parent_dn(context) == Some(base.as_str()),
+                    _ => false,
+                });
+            for (_, name) in reached {
+                if !routes.contains(&name.as_str()) {
+                    routes.push(name);
+                }
+            }
+        }
+        routes
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct DnConfig {
     #[serde(default)]
     pub allowed_queries: HashSet<(String, LdapSearchScope, LdapFilter)>,
+    /// If set, searches must be based at or below one of these DNs.
+    #[serde(default)]
+    pub allowed_bases: Vec<String>,
+    /// If set, the filters of searches must match one of these templates.
+    #[serde(default)]
+    pub allowed_filters: Vec<FilterTemplate>,
+    /// If set, only these attributes are returned by searches. Attributes are
+    /// matched without regard to case or options.
+    #[serde(default)]
+    pub allowed_attrs: Option<HashSet<String>>,
+    /// Attributes that are never returned by searches, or usable in their
+    /// filters, such as userPassword.
+    #[serde(default)]
+    pub denied_attrs: HashSet<String>,
+    /// Renames and value rewrites of the attributes of search results, applied
+    /// in order.
+    #[serde(default)]
+    pub attribute_rewrites: Vec<AttrRewrite>,
+    /// Allow compare operations to be forwarded to the backend. Compare results
+    /// are never cached.
+    #[serde(default)]
+    pub allow_compare: bool,
+    /// If set, only these attributes may be compared. Attributes are matched
+    /// without regard to case or options.
+    #[serde(default)]
+    pub allowed_compare_attrs: Option<HashSet<String>>,
+    /// Attributes that may never be compared, such as userPassword.
+    #[serde(default)]
+    pub denied_compare_attrs: HashSet<String>,
+    /// Allow add, modify, delete and modify dn operations to be forwarded to
+    /// the backend. Defaults to the top level allow_write.
+    #[serde(default)]
+    pub allow_write: Option<bool>,
+    /// The extended operations that may be forwarded to the backend, by oid,
+    /// as well as the top level allowed_extended_oids.
+    #[serde(default)]
+    pub allowed_extended_oids: HashSet<String>,
+    /// Allow password modify requests (RFC 3062) to be forwarded to the
+    /// backend, so that this DN can change its own password, or others' if the
+    /// backend permits it.
+    #[serde(default)]
+    pub allow_password_modify: bool,
+    /// Forward WhoAmI requests to the backend, rather than answering them with
+    /// the DN of the session.
+    #[serde(default)]
+    pub whoami_from_backend: bool,
+    /// The backend this DN's sessions connect to. This is either the name of a
+    /// `[backends.<name>]` table, or an ldaps (or ldap) url. Defaults to ldap_url.
+    #[serde(default)]
+    pub backend: Option<String>,
+    /// The client networks that this DN may bind from. Defaults to any.
+    #[serde(default)]
+    pub allowed_networks: Vec<IpNet>,
+    /// Replace the global control lists for this DN's sessions.
+    #[serde(default)]
+    pub allowed_controls: Option<HashSet<String>>,
+    #[serde(default)]
+    pub denied_controls: Option<HashSet<String>>,
+    #[serde(default)]
+    pub allowed_response_controls: Option<HashSet<String>>,
+    #[serde(default)]
+    pub denied_response_controls: Option<HashSet<String>>,
+    /// The most entries, and seconds, that this DN's searches may return or take.
+    #[serde(default)]
+    pub size_limit: Option<u32>,
+    #[serde(default)]
+    pub time_limit_secs: Option<u32>,
+    /// Stop this DN's searches once they have returned this many entries, and
+    /// answer them with sizeLimitExceeded, whatever size limit they asked for.
+    /// Unlike size_limit, this isn't sent to the backend, which sees the
+    /// search's own limit. The limit counts across all of the pages of a paged
+    /// search, and applies to results that are already cached.
+    #[serde(default)]
+    pub hard_entry_limit: Option<usize>,
+    /// Write this DN's requests, and the responses to them, to the tap log.
+    #[serde(default)]
+    pub tap: bool,
+    /// Seconds that this DN's search results remain in the cache, in place of
+    /// cache_entry_timeout. Zero means that they aren't cached.
+    #[serde(default)]
+    pub cache_ttl_secs: Option<u64>,
+    /// Never cache this DN's search results, nor answer its searches from the
+    /// cache.
+    #[serde(default)]
+    pub never_cache: bool,
+    /// Once this DN's credentials are verified, run its searches over a
+    /// connection shared by every session of the DN, bound as this service
+    /// account. Writes and forwarded extended operations are refused, as they
+    /// would be made as the service account.
+    #[serde(default)]
+    pub service_bind_dn: Option<String>,
+    #[serde(default)]
+    pub service_bind_password: Option<Secret>,
+    /// Fetch the service account's password from this provider, in place of
+    /// service_bind_password.
+    #[serde(default)]
+    pub service_bind_password_provider: Option<SecretProvider>,
+    /// Check this DN's search restrictions, allowed_queries, allowed_bases,
+    /// allowed_filters and the attribute lists, and log what they would deny,
+    /// but forward its searches and return their results as if they passed.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// The most operations that this DN's sessions may have in flight at
+    /// once, together. Further requests are refused with busy until one
+    /// completes.
+    #[serde(default)]
+    pub max_concurrent_operations: Option<usize>,
+    /// The most searches, and entries returned by searches, that this DN's
+    /// sessions are given each minute, together. Searches beyond either are
+    /// refused with busy.
+    #[serde(default)]
+    pub max_searches_per_minute: Option<u32>,
+    #[serde(default)]
+    pub max_entries_per_minute: Option<u32>,
+    /// What is done with the ManageDsaIT and relax rules controls of this
+    /// DN's requests. Unless they are set, requests with them are refused.
+    #[serde(default)]
+    pub manage_dsa_it: AdminControlAction,
+    #[serde(default)]
+    pub relax_rules: AdminControlAction,
+}
+
+// Is the attribute in the set? Options such as ";binary" don't change the
+// attribute.
+fn contains_attr(attrs: &HashSet<String>, atype: &str) -> bool {
+    let attr = atype.split(';').next().unwrap_or(atype);
+    attrs.iter().any(|a| a.eq_ignore_ascii_case(attr))
+}
+
+// The attribute selectors of RFC 4511 4.5.1.8, for no attributes, all user
+// attributes and all operational attributes.
+const SPECIAL_ATTRS: &[&str] = &["1.1", "*", "+"];
+
+impl DnConfig {
+    /// The bind of the service account that this DN's searches are made as,
+    /// if both its dn and password are set.
+    pub fn service_bind(&self) -> Option<LdapBindRequest> {
+        match (&self.service_bind_dn, &self.service_bind_password) {
+            (Some(dn), Some(password)) => Some(LdapBindRequest {
+                dn: dn.clone(),
+                cred: LdapBindCred::Simple(password.expose().to_string()),
+            }),
+            _ => None,
+        }
+    }
+
+    /// May this attribute be compared, if compares are allowed?
+    pub fn permits_compare(&self, atype: &str) -> bool {
+        let matches = |attrs: &HashSet<String>| contains_attr(attrs, atype);
+        !matches(&self.denied_compare_attrs)
+            && self.allowed_compare_attrs.as_ref().is_none_or(matches)
+    }
+
+    /// May this attribute be returned by a search?
+    pub fn permits_attr(&self, atype: &str) -> bool {
+        let matches = |attrs: &HashSet<String>| contains_attr(attrs, atype);
+        !matches(&self.denied_attrs) && self.allowed_attrs.as_ref().is_none_or(matches)
+    }
+
+    /// The attributes to ask the backend for, in place of those a search asked
+    /// for. Attributes that may not be returned are removed, and if only some
+    /// are allowed they replace the wildcards. Entries are still stripped of
+    /// attributes that may not be returned, as the backend decides what the
+    /// wildcards return.
+    pub fn restrict_attrs(&self, attrs: Vec<String>) -> Vec<String> {
+        if self.allowed_attrs.is_none() && self.denied_attrs.is_empty() {
+            return attrs;
+        }
+        let requested_any = !attrs.is_empty();
+        let all_user = attrs.is_empty() || attrs.iter().any(|a| a == "*");
+
+        let mut restricted: Vec<String> = attrs
+            .into_iter()
+            .filter(|a| SPECIAL_ATTRS.contains(&a.as_str()) || self.permits_attr(a))
+            .collect();
+        if let Some(allowed) = self.allowed_attrs.as_ref() {
+            restricted.retain(|a| a != "*" && a != "+");
+            if all_user {
+                let mut allowed: Vec<_> = allowed
+                    .iter()
+                    .filter(|a| self.permits_attr(a))
+                    .filter(|a| !restricted.iter().any(|r| r.eq_ignore_ascii_case(a)))
+                    .cloned()
+                    .collect();
+                // Sorted, so that the same search has the same cache key.
+                allowed.sort_unstable();
+                restricted.extend(allowed);
+            }
+        }
+        // An empty list would ask for every attribute.
+        if restricted.is_empty() && (requested_any || self.allowed_attrs.is_some()) {
+            restricted.push("1.1".to_string());
+        }
+        restricted
+    }
+
+    /// May a search use this filter? Filters on attributes that may not be
+    /// returned are refused, as their values could be found by testing them.
+    pub fn permits_filter(&self, filter: &LdapFilter) -> bool {
+        let templates = self.allowed_filters.is_empty()
+            || self
+                .allowed_filters
+                .iter()
+                .any(|template| template.matches(filter));
+        // The filter of most searches includes objectClass, so it is always
+        // permitted.
+        templates
+            && filter_attributes(filter)
+                .into_iter()
+                .all(|atype| atype.eq_ignore_ascii_case("objectClass") || self.permits_attr(atype))
+    }
+
+    /// Remove the attributes that may not be returned from a search result
+    /// entry, and return if it had any. In dry run mode they are only found.
+    pub fn strip_attrs(&self, entry: &mut LdapSearchResultEntry) -> bool {
+        if self.allowed_attrs.is_none() && self.denied_attrs.is_empty() {
+            return false;
+        }
+        if self.dry_run {
+            return !entry
+                .attributes
+                .iter()
+                .all(|attr| self.permits_attr(&attr.atype));
+        }
+        let count = entry.attributes.len();
+        entry
+            .attributes
+            .retain(|attr| self.permits_attr(&attr.atype));
+        entry.attributes.len() != count
+    }
+
+    /// May a search be based at this DN?
+    pub fn permits_search_base(&self, base: &str) -> bool {
+        if self.allowed_bases.is_empty() {
+            return true;
+        }
+        let Ok(base) = normalize_dn(base) else {
+            return false;
+        };
+        self.allowed_bases
+            .iter()
+            .any(|allowed| normalize_dn(allowed).is_ok_and(|allowed| dn_is_within(&base, &allowed)))
+    }
 }
 
 fn default_cache_bytes() -> usize {
@@ -42,26 +1163,719 @@ fn default_cache_entry_timeout() -> u64 {
     1800
 }
 
+fn default_upstream_pool_max_idle_secs() -> u64 {
+    60
+}
+
+fn default_upstream_pool_max_lifetime_secs() -> u64 {
+    600
+}
+
+fn default_referral_hop_limit() -> usize {
+    3
+}
+
+fn default_shutdown_grace_secs() -> u64 {
+    30
+}
+
+fn default_starttls_listen_fd_name() -> String {
+    "ldap".to_string()
+}
+
+fn default_listen_fd_name() -> String {
+    "ldaps".to_string()
+}
+
+fn default_cache_sweep_interval_secs() -> u64 {
+    60
+}
+
+fn default_dns_ttl_secs() -> u64 {
+    60
+}
+
+fn default_bind_burst_per_ip() -> u32 {
+    10
+}
+
+fn default_bind_failure_threshold() -> u32 {
+    5
+}
+
+fn default_bind_failure_window_secs() -> u64 {
+    300
+}
+
+fn default_bind_lockout_by_ip() -> bool {
+    true
+}
+
+fn default_ldap_tls_verify_hostname() -> bool {
+    true
+}
+
+fn default_tcp_nodelay() -> bool {
+    true
+}
+
+fn default_coalesce_searches() -> bool {
+    true
+}
+
+fn default_cldap_port() -> u16 {
+    389
+}
+
+fn default_accept_backlog() -> u32 {
+    1024
+}
+
+fn default_retry_attempts() -> u32 {
+    1
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    100
+}
+
+fn default_breaker_failure_threshold() -> u32 {
+    3
+}
+
+fn default_breaker_max_backoff_secs() -> u64 {
+    60
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    5
+}
+
+fn one_or_many_urls<'de, D>(deserializer: D) -> Result<Vec<Url>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Url),
+        Many(Vec<Url>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(url) => Ok(vec![url]),
+        OneOrMany::Many(urls) if urls.is_empty() => Err(serde::de::Error::custom(
+            "at least one ldap_url is required",
+        )),
+        OneOrMany::Many(urls) => Ok(urls),
+    }
+}
+
+fn normalized_binddn_map<'de, D>(deserializer: D) -> Result<BTreeMap<String, DnConfig>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let configured = BTreeMap::<String, DnConfig>::deserialize(deserializer)?;
+    let mut binddn_map = BTreeMap::new();
+    for (dn, dnconfig) in configured {
+        let normalized = normalize_dn(&dn)
+            .map_err(|e| serde::de::Error::custom(format!("invalid bind dn '{}': {}", dn, e)))?;
+        if binddn_map.insert(normalized, dnconfig).is_some() {
+            return Err(serde::de::Error::custom(format!(
+                "bind dn '{}' is the same as another configured dn",
+                dn
+            )));
+        }
+    }
+    Ok(binddn_map)
+}
+
+fn normalized_cache_ttls<'de, D>(deserializer: D) -> Result<BTreeMap<String, u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    BTreeMap::<String, u64>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(base, secs)| {
+            normalize_dn(&base).map(|base| (base, secs)).map_err(|e| {
+                serde::de::Error::custom(format!("invalid cache ttl base '{}': {}", base, e))
+            })
+        })
+        .collect()
+}
+
+fn normalized_dns<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|dn| {
+            normalize_dn(dn)
+                .map_err(|e| serde::de::Error::custom(format!("invalid dn '{}': {}", dn, e)))
+        })
+        .collect()
+}
+
+/// How clients secure their connections to a listener.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenerMode {
+    /// TLS from the start of the connection.
+    #[default]
+    Ldaps,
+    /// Plain ldap, which must be upgraded with StartTLS before any other
+    /// request is accepted.
+    Starttls,
+}
+
+/// Another address to accept client connections on.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListenerConfig {
+    pub bind: SocketAddr,
+    #[serde(default)]
+    pub mode: ListenerMode,
+    /// The connections that may wait to be accepted, in place of the top
+    /// level accept_backlog.
+    pub accept_backlog: Option<u32>,
+    /// The sessions of this listener that may be open at once, in place of
+    /// the top level max_active_sessions.
+    pub max_active_sessions: Option<usize>,
+    /// The certificate chain and key that this listener serves, in place of
+    /// the top level tls_chain and tls_key. Both or neither must be set.
+    pub tls_chain: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+}
+
+/// How the proxy's tasks are run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimeFlavor {
+    /// On a pool of worker threads.
+    #[default]
+    MultiThread,
+    /// On a single thread, which is all that the proxy uses apart from
+    /// blocking work such as writing logs.
+    CurrentThread,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BackendConfig {
+    #[serde(deserialize_with = "one_or_many_urls")]
+    pub ldap_url: Vec<Url>,
+    /// The CA that signs these backends. Defaults to the top level ldap_ca.
+    pub ldap_ca: Option<PathBuf>,
+    #[serde(default)]
+    pub backend_strategy: BackendStrategy,
+    /// The weights of these backends for the weighted strategy, by url.
+    /// Backends that aren't listed have a weight of 1.
+    #[serde(default)]
+    pub backend_weights: BTreeMap<Url, u32>,
+    /// Use StartTLS for the ldap:// urls of these backends. Defaults to the top
+    /// level ldap_starttls.
+    pub ldap_starttls: Option<bool>,
+    /// Sessions bound as DNs under these suffixes connect to these backends,
+    /// unless their bind map entry sets a backend.
+    #[serde(default, deserialize_with = "normalized_dns")]
+    pub bind_dn_suffixes: Vec<String>,
+    /// Searches based at or below these DNs are sent to these backends, and
+    /// searches of subtrees that contain them are sent here as well.
+    #[serde(default, deserialize_with = "normalized_dns")]
+    pub naming_contexts: Vec<String>,
+    /// These backends are the global catalog (ports 3268 and 3269) of Active
+    /// Directory forests with these roots. Subtree searches based at a root
+    /// are sent here, as the global catalog holds every domain of the forest.
+    #[serde(default, deserialize_with = "normalized_dns")]
+    pub global_catalog_roots: Vec<String>,
+    /// Send searches that no naming context routes to these backends as well,
+    /// for directories that hold entries under the same bases as the others.
+    /// Their entries are merged, and of entries with the same DN the first is
+    /// kept, the session's own backend's first.
+    #[serde(default)]
+    pub fan_out: bool,
+    /// Override the top level timeouts for these backends.
+    pub connect_timeout_secs: Option<u64>,
+    pub read_timeout_secs: Option<u64>,
+    pub operation_timeout_secs: Option<u64>,
+    /// Override the top level socket options for these backends.
+    pub tcp_keepalive_secs: Option<u64>,
+    pub tcp_nodelay: Option<bool>,
+    pub tcp_send_buffer_bytes: Option<usize>,
+    pub tcp_recv_buffer_bytes: Option<usize>,
+    /// Override the top level TLS versions and ciphers for these backends.
+    pub ldap_tls_min_version: Option<TlsVersion>,
+    pub ldap_tls_max_version: Option<TlsVersion>,
+    pub ldap_tls_cipher_list: Option<String>,
+    pub ldap_tls_ciphersuites: Option<String>,
+    pub ldap_spki_pins: Option<Vec<SpkiPin>>,
+    pub ldap_tls_verify_hostname: Option<bool>,
+    pub ldap_tls_sni_name: Option<String>,
+    /// The certificate and key that the proxy presents to these backends,
+    /// instead of the top level ones.
+    pub ldap_client_cert: Option<PathBuf>,
+    pub ldap_client_key: Option<PathBuf>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub bind: SocketAddr,
+    /// Also listen for plain ldap connections on this address. Clients must
+    /// upgrade them with StartTLS before any other request is accepted.
+    pub starttls_bind: Option<SocketAddr>,
+    /// Also listen on these addresses, each for ldaps or StartTLS.
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// The connections to bind and starttls_bind that the kernel holds until
+    /// they are accepted. Further connections are refused by the kernel.
+    #[serde(default = "default_accept_backlog")]
+    pub accept_backlog: u32,
+    /// The sessions of bind, and of starttls_bind, that may be open at once.
+    /// Once a listener has this many, it accepts no more until one ends, and
+    /// new connections wait in its accept backlog.
+    pub max_active_sessions: Option<usize>,
+    /// Run on a pool of worker threads, or on one thread.
+    #[serde(default)]
+    pub runtime: RuntimeFlavor,
+    /// The worker threads of the multi_thread runtime. Defaults to the
+    /// number of cpus.
+    pub worker_threads: Option<usize>,
+    /// Also listen for plain ldap connections on this unix socket, for local
+    /// applications. Who can connect is controlled by its permissions.
+    pub ldapi_bind: Option<PathBuf>,
+    /// The permissions of the ldapi socket, such as 0o660.
+    pub ldapi_mode: Option<u32>,
+    /// Serve the admin API on this address.
+    pub admin_bind: Option<SocketAddr>,
+    /// The bearer token that requests to the admin API must carry.
+    pub admin_token: Option<Secret>,
+    /// Serve the /healthz and /readyz probes on this address.
+    pub health_bind: Option<SocketAddr>,
+    /// Answer CLDAP pings on this UDP address, by relaying them to this port
+    /// of the default backends, for the clients in these networks.
+    pub cldap_bind: Option<SocketAddr>,
+    #[serde(default = "default_cldap_port")]
+    pub cldap_backend_port: u16,
+    #[serde(default)]
+    pub cldap_allowed_networks: Vec<IpNet>,
+    /// When socket activated, listen on the passed socket with this
+    /// FileDescriptorName instead of binding to `bind`.
+    #[serde(default = "default_listen_fd_name")]
+    pub listen_fd_name: String,
+    /// When socket activated, a passed socket with this FileDescriptorName is
+    /// the StartTLS listener, instead of binding to `starttls_bind`.
+    #[serde(default = "default_starttls_listen_fd_name")]
+    pub starttls_listen_fd_name: String,
     pub tls_key: PathBuf,
     pub tls_chain: PathBuf,
+    /// How often to check whether tls_key or tls_chain have changed, and load
+    /// them again if they have, without dropping the clients that are
+    /// connected. They are also loaded again when the config is reloaded. If
+    /// unset, the files aren't checked.
+    pub tls_reload_interval_secs: Option<u64>,
 
-    #[serde(default = "default_cache_bytes")]
+    /// The most bytes of entries that the cache holds. Past this the least
+    /// recently and least often used entries are evicted.
+    #[serde(default = "default_cache_bytes", alias = "max_cache_bytes")]
     pub cache_bytes: usize,
+    /// The most entries that the cache holds, however small they are.
+    pub max_cache_entries: Option<usize>,
     #[serde(default = "default_cache_entry_timeout")]
     pub cache_entry_timeout: u64,
+    /// Seconds that the results of searches at or below these bases remain in
+    /// the cache, in place of cache_entry_timeout.
+    #[serde(default, deserialize_with = "normalized_cache_ttls")]
+    pub cache_ttl: BTreeMap<String, u64>,
+    /// Seconds that searches which found no entries, or noSuchObject, remain
+    /// in the cache. Zero means they aren't cached. Defaults to the same TTL as
+    /// other searches.
+    pub negative_cache_ttl_secs: Option<u64>,
+    /// Answer searches from cached results for up to this many seconds after
+    /// they expire, refreshing them from the backend in the background,
+    /// rather than waiting for the backend.
+    pub cache_max_stale_secs: Option<u64>,
+    /// The oid of a control that clients may attach to a search so that it is
+    /// sent to the backend rather than answered from the cache. The fresh
+    /// results still replace those in the cache.
+    pub cache_bypass_control_oid: Option<String>,
+    /// Searches that miss the cache while the same search is being sent to
+    /// the backend wait for its results, rather than sending it again.
+    #[serde(default = "default_coalesce_searches")]
+    pub coalesce_searches: bool,
+    /// Save the cache here on shutdown, and load it on startup.
+    pub cache_persist_path: Option<PathBuf>,
+    /// Also save the cache this often, so that a restart after a crash
+    /// doesn't start cold.
+    pub cache_persist_interval_secs: Option<u64>,
+    /// How often expired entries are removed from the cache.
+    #[serde(default = "default_cache_sweep_interval_secs")]
+    pub cache_sweep_interval_secs: u64,
 
     pub ldap_ca: PathBuf,
-    pub ldap_url: Url,
+    /// One or more backend servers. These must all be signed by the ldap_ca.
+    #[serde(deserialize_with = "one_or_many_urls")]
+    pub ldap_url: Vec<Url>,
+    /// Upgrade connections to ldap:// backends with StartTLS, verifying them
+    /// against ldap_ca, rather than connecting without tls.
+    #[serde(default)]
+    pub ldap_starttls: bool,
+    #[serde(default)]
+    pub backend_strategy: BackendStrategy,
+    /// The weights of the ldap_url backends for the weighted strategy.
+    #[serde(default)]
+    pub backend_weights: BTreeMap<Url, u32>,
+    /// Additional named backends, that DNs can be routed to.
+    #[serde(default)]
+    pub backends: BTreeMap<String, BackendConfig>,
+    /// How often backend hostnames are resolved again.
+    #[serde(default = "default_dns_ttl_secs")]
+    pub dns_ttl_secs: u64,
+    /// Consecutive connection failures before a backend address is skipped.
+    #[serde(default = "default_breaker_failure_threshold")]
+    pub breaker_failure_threshold: u32,
+    /// The longest time that a failed backend address is skipped for.
+    #[serde(default = "default_breaker_max_backoff_secs")]
+    pub breaker_max_backoff_secs: u64,
+    /// How long to wait to connect to a backend.
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    /// How long to wait for each message of a backend response. Unlimited if
+    /// unset.
+    pub read_timeout_secs: Option<u64>,
+    /// How long a backend operation may take. Unlimited if unset.
+    pub operation_timeout_secs: Option<u64>,
+    /// Send TCP keepalives on backend connections that have been quiet this
+    /// long. Unset leaves the system default, which is usually off.
+    pub tcp_keepalive_secs: Option<u64>,
+    /// The same, for client connections.
+    pub client_tcp_keepalive_secs: Option<u64>,
+    /// Disable Nagle's algorithm on client and backend connections.
+    #[serde(default = "default_tcp_nodelay")]
+    pub tcp_nodelay: bool,
+    /// The sizes of the send and receive buffers of client and backend
+    /// connections. Unset leaves the system defaults.
+    pub tcp_send_buffer_bytes: Option<usize>,
+    pub tcp_recv_buffer_bytes: Option<usize>,
+    /// The oldest and newest versions of TLS that backends may use, such as
+    /// "1.2" or "1.3".
+    pub ldap_tls_min_version: Option<TlsVersion>,
+    pub ldap_tls_max_version: Option<TlsVersion>,
+    /// The ciphers that backends may use with TLS 1.2 and earlier, as an
+    /// openssl cipher list.
+    pub ldap_tls_cipher_list: Option<String>,
+    /// The ciphersuites that backends may use with TLS 1.3.
+    pub ldap_tls_ciphersuites: Option<String>,
+    /// Backends must present a certificate with one of these public keys, as
+    /// well as one that ldap_ca signs. This guards against a compromised CA.
+    #[serde(default)]
+    pub ldap_spki_pins: Vec<SpkiPin>,
+    /// Check that backends' certificates name them.
+    #[serde(default = "default_ldap_tls_verify_hostname")]
+    pub ldap_tls_verify_hostname: bool,
+    /// Send this name in SNI, and verify backends' certificates against it,
+    /// instead of their hostnames.
+    pub ldap_tls_sni_name: Option<String>,
+    /// How many times a backend connection, bind or search that fails for a
+    /// transient reason is retried.
+    #[serde(default = "default_retry_attempts")]
+    pub retry_attempts: u32,
+    /// The delay before the first retry, which doubles with each later one.
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub retry_base_delay_ms: u64,
+    /// How often every backend address is checked with a root DSE search. If
+    /// unset, backends are not checked.
+    pub health_check_interval_secs: Option<u64>,
 
     pub max_incoming_ber_size: Option<usize>,
     pub max_proxy_ber_size: Option<usize>,
+    /// Search results larger than this, as measured by the cache, are relayed
+    /// but not cached.
+    pub max_cacheable_result_bytes: Option<usize>,
+    /// Searches returning more entries than this are abandoned, and the client
+    /// receives the entries so far with sizeLimitExceeded.
+    pub max_relayed_entries: Option<usize>,
+    /// Searches returning more entries than this aren't cached. Their entries
+    /// are relayed to the client as they arrive, rather than collected first,
+    /// as are those of every search that isn't cached.
+    pub max_cacheable_entries: Option<usize>,
+    /// Searches with filters nested more deeply than this, with more terms, or
+    /// with more parts of substring terms, are refused with
+    /// adminLimitExceeded.
+    pub max_filter_depth: Option<usize>,
+    pub max_filter_terms: Option<usize>,
+    pub max_filter_substrings: Option<usize>,
+
+    /// What to do with the referrals that backends return. chase_referrals =
+    /// true is the same as "chase", and can't be set with it.
+    #[serde(default, alias = "chase_referrals")]
+    pub referral_mode: ReferralMode,
+    /// The host:port of the proxy, as clients reach it, for rewritten
+    /// referrals. Defaults to `bind`.
+    pub referral_rewrite_host: Option<String>,
+    /// The host:port of the proxy for rewritten referrals to a server, keyed
+    /// by the host:port (or host) of that server. Referrals to servers that
+    /// aren't listed point at referral_rewrite_host.
+    #[serde(default)]
+    pub referral_rewrite_map: BTreeMap<String, String>,
+    /// Keep up to this many idle backend connections for each bind DN, to be
+    /// reused by that DN's next sessions. Unset (0) means connections are
+    /// closed when their session ends.
+    #[serde(default)]
+    pub upstream_pool_size: usize,
+    /// Close pooled connections that have been idle this long.
+    #[serde(default = "default_upstream_pool_max_idle_secs")]
+    pub upstream_pool_max_idle_secs: u64,
+    /// Close pooled connections that are this old.
+    #[serde(default = "default_upstream_pool_max_lifetime_secs")]
+    pub upstream_pool_max_lifetime_secs: u64,
+    /// Check that pooled connections still work this often, with a root DSE
+    /// search, and replace those that don't. Unset disables the checks.
+    pub upstream_pool_probe_interval_secs: Option<u64>,
+    /// Bind connections as this DN before they are pooled, rather than leave
+    /// them bound as the DN of their session. Any DN may then reuse them.
+    pub upstream_pool_neutral_dn: Option<String>,
+    pub upstream_pool_neutral_password: Option<Secret>,
+    /// Remember successful simple binds for this long, so that a pooled
+    /// connection still bound as the DN is reused without binding again.
+    pub bind_cache_ttl_secs: Option<u64>,
+
+    /// How many referrals in a row are chased before giving up.
+    #[serde(default = "default_referral_hop_limit")]
+    pub referral_hop_limit: usize,
+    /// The servers, by host:port (or host), that referrals may be chased to.
+    /// The client's bind is sent to them. Required when chasing referrals.
+    #[serde(default)]
+    pub referral_chase_hosts: Vec<String>,
+
+    /// Disconnect clients that send nothing, and have no operations in
+    /// progress, for this many seconds. Unset by default.
+    pub idle_timeout_secs: Option<u64>,
+    /// The most operations that each session may have in flight at once. Its
+    /// later requests are refused with busy until one completes. Unlimited by
+    /// default.
+    pub max_session_operations: Option<usize>,
+    /// Who sorts the results of searches with the server side sort control.
+    #[serde(default)]
+    pub server_sort: SortMode,
+    /// On shutdown, sessions have this many seconds to finish the operations
+    /// they have in flight before the proxy exits.
+    #[serde(default = "default_shutdown_grace_secs")]
+    pub shutdown_grace_secs: u64,
+
+    /// Read a PROXY protocol (v1 or v2) header from each connection, and use the
+    /// client address that it conveys. Connections without one are dropped.
+    #[serde(default)]
+    pub expect_proxy_protocol: bool,
+    /// The networks of the load balancers that send the PROXY protocol
+    /// header. Connections from other peers are dropped before it is read.
+    #[serde(default)]
+    pub proxy_protocol_trusted_networks: Vec<IpNet>,
+
+    /// Only clients in these networks may connect. Defaults to any.
+    #[serde(default)]
+    pub allowed_client_networks: Vec<IpNet>,
+    /// Clients in these networks may never connect.
+    #[serde(default)]
+    pub denied_client_networks: Vec<IpNet>,
+
+    /// The most client connections that may be open at once.
+    pub max_connections: Option<usize>,
+    /// The most client connections that may be open at once from one address.
+    pub max_connections_per_ip: Option<usize>,
+
+    /// The sustained binds per second allowed from each client address. Unset
+    /// means binds are not limited.
+    pub bind_rate_per_ip: Option<f64>,
+    /// The binds that may be made at once by each client address.
+    #[serde(default = "default_bind_burst_per_ip")]
+    pub bind_burst_per_ip: u32,
+
+    /// Failed binds for a DN, from a client address, or for a DN from an
+    /// address, within the window that are reported.
+    #[serde(default = "default_bind_failure_threshold")]
+    pub bind_failure_threshold: u32,
+    #[serde(default = "default_bind_failure_window_secs")]
+    pub bind_failure_window_secs: u64,
+    /// Once the threshold is reached, refuse binds from the address, or of the
+    /// DN from that address, for this long. Unset means nothing is locked.
+    pub bind_lockout_secs: Option<u64>,
+    /// Lock the whole address, rather than only the DN from that address.
+    #[serde(default = "default_bind_lockout_by_ip")]
+    pub bind_lockout_by_ip: bool,
+    /// Also lock the DN, for binds from every address.
+    #[serde(default)]
+    pub bind_lockout_by_dn: bool,
+
+    /// The certificate and key that the proxy presents to backends that ask
+    /// for one. cert_map entries without a bind_password rely on it.
+    pub ldap_client_cert: Option<PathBuf>,
+    pub ldap_client_key: Option<PathBuf>,
+
+    /// Request client certificates signed by this CA. Clients with a mapped
+    /// certificate may bind with SASL EXTERNAL.
+    pub client_ca: Option<PathBuf>,
+    /// Clients must present a certificate signed by the client_ca.
+    #[serde(default)]
+    pub require_client_cert: bool,
+    /// How unmapped client certificates are refused, when they are required.
+    #[serde(default)]
+    pub unmapped_client_cert: UnmappedCertPolicy,
+    #[serde(default)]
+    pub cert_map: CertMap,
+    /// Anonymous binds, which are permitted by the "" bind map, are sent to the
+    /// backend as this account, for backends that don't allow anonymous access.
+    /// Their sessions keep the "" bind map's restrictions.
+    pub anonymous_bind_dn: Option<String>,
+    pub anonymous_bind_password: Option<Secret>,
+    /// Answer searches for the root DSE locally, even before a bind.
+    pub root_dse: Option<RootDseConfig>,
+    /// Answer searches for the subschema subentry from a copy shared by every
+    /// DN.
+    pub schema: Option<SchemaConfig>,
+    /// Rewrite DNs under these client suffixes to the backend's suffixes, and
+    /// back, including in the values of attributes that hold DNs.
+    pub dn_rewrite: Option<DnRewrite>,
+    /// Synthesize memberOf for searches that ask for it, by searching for the
+    /// groups of each entry.
+    pub member_of: Option<MemberOfConfig>,
+    /// Record each client operation, as a line of JSON.
+    pub audit: Option<AuditConfig>,
+    /// Where the messages of DNs with tap set are written, decoded and with
+    /// their credentials redacted.
+    pub tap: Option<TapConfig>,
+    /// Export the spans of operations, and of their requests to the backend,
+    /// to an OpenTelemetry collector. Needs the otlp feature.
+    pub otlp: Option<OtlpConfig>,
+    /// Also count binds, searches, entries, cache hits and backend search
+    /// durations by the bind DN of each session.
+    #[serde(default)]
+    pub per_dn_metrics: bool,
+    /// The backends are Active Directory. The reasons that it gives for
+    /// refusing binds are written to the audit log and counted, and clients
+    /// are sent plain messages in place of AD's diagnostic ones.
+    #[serde(default)]
+    pub ad_compat: bool,
+    /// How the values of search filters are written to the log. Bind
+    /// credentials are never logged.
+    #[serde(default)]
+    pub log_filter_values: FilterValues,
+
+    /// If set, only these request controls are relayed to the backend.
+    pub allowed_controls: Option<HashSet<String>>,
+    /// Request controls that are never relayed to the backend. By default these
+    /// are proxied authorization, relax rules and tree delete.
+    #[serde(default = "default_denied_controls")]
+    pub denied_controls: HashSet<String>,
+    /// If set, only these response controls are relayed to clients.
+    pub allowed_response_controls: Option<HashSet<String>>,
+    /// Response controls that are never relayed to clients.
+    #[serde(default)]
+    pub denied_response_controls: HashSet<String>,
 
     #[serde(default)]
     pub allow_all_bind_dns: bool,
+    /// Allow writes for the DNs that don't set allow_write.
+    #[serde(default)]
+    pub allow_write: bool,
+    /// Refuse every write and password modify with unwillingToPerform, even
+    /// for DNs that allow them, such as in front of a replica.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Extended operations that every DN may forward to the backend, by oid.
+    #[serde(default)]
+    pub allowed_extended_oids: HashSet<String>,
+
+    /// Bind map entries that match DNs by glob or regex, in the order they
+    /// are checked.
+    #[serde(default)]
+    pub binddn_patterns: BindDnPatterns,
+    /// A directory of `*.toml` files with more bind map entries and
+    /// binddn_patterns, relative to the config file. These are merged when
+    /// the config is loaded.
+    #[serde(default)]
+    pub include_dir: Option<PathBuf>,
 
-    #[serde(flatten)]
+    /// The bind maps, keyed by normalised DN.
+    #[serde(flatten, deserialize_with = "normalized_binddn_map")]
     pub binddn_map: BTreeMap<String, DnConfig>,
 }
+
+impl Config {
+    /// The timeouts of the default backend pool, or of a named one.
+    pub fn backend_timeouts(&self, backend: Option<&BackendConfig>) -> BackendTimeouts {
+        let secs = |secs: Option<u64>| secs.map(Duration::from_secs);
+        BackendTimeouts {
+            connect: Duration::from_secs(
+                backend
+                    .and_then(|b| b.connect_timeout_secs)
+                    .unwrap_or(self.connect_timeout_secs),
+            ),
+            read: secs(
+                backend
+                    .and_then(|b| b.read_timeout_secs)
+                    .or(self.read_timeout_secs),
+            ),
+            operation: secs(
+                backend
+                    .and_then(|b| b.operation_timeout_secs)
+                    .or(self.operation_timeout_secs),
+            ),
+        }
+    }
+
+    /// The socket options of connections to the default backend pool, or to
+    /// a named one.
+    pub fn backend_tcp(&self, backend: Option<&BackendConfig>) -> TcpOptions {
+        TcpOptions {
+            keepalive: backend
+                .and_then(|b| b.tcp_keepalive_secs)
+                .or(self.tcp_keepalive_secs)
+                .map(Duration::from_secs),
+            nodelay: backend
+                .and_then(|b| b.tcp_nodelay)
+                .unwrap_or(self.tcp_nodelay),
+            send_buffer: backend
+                .and_then(|b| b.tcp_send_buffer_bytes)
+                .or(self.tcp_send_buffer_bytes),
+            recv_buffer: backend
+                .and_then(|b| b.tcp_recv_buffer_bytes)
+                .or(self.tcp_recv_buffer_bytes),
+        }
+    }
+
+    /// The socket options of client connections.
+    pub fn client_tcp(&self) -> TcpOptions {
+        TcpOptions {
+            keepalive: self.client_tcp_keepalive_secs.map(Duration::from_secs),
+            nodelay: self.tcp_nodelay,
+            send_buffer: self.tcp_send_buffer_bytes,
+            recv_buffer: self.tcp_recv_buffer_bytes,
+        }
+    }
+
+    /// The TLS of the default backend pool, or of a named one.
+    pub fn backend_tls(&self, backend: Option<&BackendConfig>) -> BackendTls {
+        BackendTls {
+            min_version: backend
+                .and_then(|b| b.ldap_tls_min_version)
+                .or(self.ldap_tls_min_version),
+            max_version: backend
+                .and_then(|b| b.ldap_tls_max_version)
+                .or(self.ldap_tls_max_version),
+            cipher_list: backend
+                .and_then(|b| b.ldap_tls_cipher_list.clone())
+                .or_else(|| self.ldap_tls_cipher_list.clone()),
+            ciphersuites: backend
+                .and_then(|b| b.ldap_tls_ciphersuites.clone())
+                .or_else(|| self.ldap_tls_ciphersuites.clone()),
+            spki_pins: backend
+                .and_then(|b| b.ldap_spki_pins.clone())
+                .unwrap_or_else(|| self.ldap_spki_pins.clone()),
+            verify_hostname: backend
+                .and_then(|b| b.ldap_tls_verify_hostname)
+                .unwrap_or(self.ldap_tls_verify_hostname),
+            sni_name: backend
+                .and_then(|b| b.ldap_tls_sni_name.clone())
+                .or_else(|| self.ldap_tls_sni_name.clone()),
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Tracking of failed binds, to notice brute force attempts against a DN or from
+//! a client address. When a DN or address fails `threshold` binds within the
+//! window it is reported, and if a lockout is configured further binds are
+//! refused without contacting the backend until it expires. This protects the
+//! backend account from being locked by the backend's own password policy.
+//!
+//! Failures are also counted for each DN from each address, and that pair is
+//! always locked, so that a client spraying passwords at one account is stopped
+//! without affecting other accounts or clients. Addresses are locked unless the
+//! operator opts out, for when many users share one address. A DN is only
+//! locked if the operator opts in, as anyone who can reach the proxy could
+//! otherwise lock a DN out for every client.
+
+use hashbrown::HashMap;
+use std::collections::VecDeque;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const SHARDS: usize = 16;
+// Shards are not pruned until they have at least this many keys.
+const MIN_PRUNE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum FailureKey {
+    Ip(IpAddr),
+    Dn(String),
+    Pair(IpAddr, String),
+}
+
+#[derive(Debug, Default)]
+struct Failures {
+    // The times of the most recent failures, at most threshold of them.
+    times: VecDeque<Instant>,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Shard {
+    failures: HashMap<FailureKey, Failures>,
+    prune_at: usize,
+}
+
+/// Which thresholds a failed bind crossed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ThresholdsCrossed {
+    pub ip: bool,
+    pub dn: bool,
+    /// The DN from this address.
+    pub pair: bool,
+}
+
+#[derive(Debug)]
+pub struct BindFailureTracker {
+    threshold: usize,
+    window: Duration,
+    lockout: Option<Duration>,
+    lockout_by_ip: bool,
+    lockout_by_dn: bool,
+    shards: Vec<Mutex<Shard>>,
+    hasher: hashbrown::hash_map::DefaultHashBuilder,
+}
+
+impl BindFailureTracker {
+    /// Report a DN or address once it has `threshold` failed binds within
+    /// `window`, and lock it for `lockout` if that is set.
+    pub fn new(
+        threshold: u32,
+        window: Duration,
+        lockout: Option<Duration>,
+        lockout_by_dn: bool,
+    ) -> Self {
+        let shards = (0..SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    failures: HashMap::new(),
+                    prune_at: MIN_PRUNE_SIZE,
+                })
+            })
+            .collect();
+        BindFailureTracker {
+            threshold: threshold.max(1) as usize,
+            window,
+            lockout,
+            lockout_by_ip: true,
+            lockout_by_dn,
+            shards,
+            hasher: Default::default(),
+        }
+    }
+
+    /// Lock addresses that cross the threshold, for binds as any DN. On by
+    /// default.
+    pub fn with_lockout_by_ip(mut self, lockout_by_ip: bool) -> Self {
+        self.lockout_by_ip = lockout_by_ip;
+        self
+    }
+
+    /// Does crossing the threshold lock the address?
+    pub fn locks_ip(&self) -> bool {
+        self.lockout.is_some() && self.lockout_by_ip
+    }
+
+    /// Does crossing the threshold lock the DN from that address?
+    pub fn locks_pair(&self) -> bool {
+        self.lockout.is_some()
+    }
+
+    /// Does crossing the threshold lock the DN?
+    pub fn locks_dn(&self) -> bool {
+        self.lockout.is_some() && self.lockout_by_dn
+    }
+
+    fn shard(&self, key: &FailureKey) -> std::sync::MutexGuard<'_, Shard> {
+        let idx = (self.hasher.hash_one(key) as usize) % SHARDS;
+        self.shards[idx].lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn key_locked(&self, key: &FailureKey, now: Instant) -> bool {
+        self.shard(key)
+            .failures
+            .get(key)
+            .and_then(|failures| failures.locked_until)
+            .is_some_and(|until| until > now)
+    }
+
+    /// Is a bind for this DN from this address currently locked out?
+    pub fn is_locked(&self, dn: &str, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        (self.lockout_by_ip && self.key_locked(&FailureKey::Ip(ip), now))
+            || (self.lockout_by_dn && self.key_locked(&FailureKey::Dn(dn.to_string()), now))
+            || self.key_locked(&FailureKey::Pair(ip, dn.to_string()), now)
+    }
+
+    // Returns true if this failure crossed the threshold.
+    fn record(&self, key: FailureKey, lock: bool, now: Instant) -> bool {
+        let mut shard = self.shard(&key);
+        let failures = shard.failures.entry(key).or_default();
+
+        if failures.times.len() == self.threshold {
+            failures.times.pop_front();
+        }
+        failures.times.push_back(now);
+
+        let crossed = failures.times.len() == self.threshold
+            && failures
+                .times
+                .front()
+                .is_some_and(|first| now.duration_since(*first) <= self.window);
+        if crossed {
+            // Start counting again, so that this is reported once per threshold
+            // failures rather than on every failure after it.
+            failures.times.clear();
+            if lock {
+                failures.locked_until = self.lockout.map(|lockout| now + lockout);
+            }
+        }
+
+        if shard.failures.len() >= shard.prune_at {
+            self.prune(&mut shard, now);
+        }
+
+        crossed
+    }
+
+    /// Record a failed bind for this DN from this address.
+    pub fn record_failure(&self, dn: &str, ip: IpAddr) -> ThresholdsCrossed {
+        let now = Instant::now();
+        ThresholdsCrossed {
+            ip: self.record(FailureKey::Ip(ip), self.lockout_by_ip, now),
+            dn: self.record(FailureKey::Dn(dn.to_string()), self.lockout_by_dn, now),
+            pair: self.record(FailureKey::Pair(ip, dn.to_string()), true, now),
+        }
+    }
+
+    /// A successful bind forgets the failures of the DN and the address.
+    pub fn record_success(&self, dn: &str, ip: IpAddr) {
+        let keys = [
+            FailureKey::Ip(ip),
+            FailureKey::Dn(dn.to_string()),
+            FailureKey::Pair(ip, dn.to_string()),
+        ];
+        for key in keys {
+            self.shard(&key).failures.remove(&key);
+        }
+    }
+
+    // Keys whose failures have all left the window, and that aren't locked, are
+    // the same as a new key, so they can be removed.
+    fn prune(&self, shard: &mut Shard, now: Instant) {
+        let window = self.window;
+        shard.failures.retain(|_, failures| {
+            failures.locked_until.is_some_and(|until| until > now)
+                || failures
+                    .times
+                    .back()
+                    .is_some_and(|last| now.duration_since(*last) <= window)
+        });
+        shard.prune_at = (shard.failures.len() * 2).max(MIN_PRUNE_SIZE);
+    }
+
+    /// The number of DNs, addresses and pairs of them that currently have state.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .failures
+                    .len()
+            })
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -14,24 +14,24 @@
 static ALLOC: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
 use clap::Parser;
-use ldap3_proto::LdapCodec;
-use ldap_proxy::{AppState, Config};
-use std::fs::File;
-use std::io::Read;
+use ldap3_proto::proto::{
+    LdapBindCred, LdapBindRequest, LdapDerefAliases, LdapSearchRequest, LdapSearchScope,
+};
+use ldap3_proto::{parse_ldap_filter_str, LdapResultCode};
+use ldap_proxy::config::{example_config, read_secret_file};
+use ldap_proxy::dn::normalize_dn;
+#[cfg(feature = "otlp")]
+use ldap_proxy::otlp::OtlpExporter;
+use ldap_proxy::proxy::BasicLdapClient;
+use ldap_proxy::server::{
+    build_app_state, build_runtime, check_listener_tls, read_config, ProxyBuilder,
+};
+use ldap_proxy::DnConfig;
+use std::path::Path;
 use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::broadcast;
+use std::time::Instant;
 use tracing_forest::{traits::*, util::*};
-
-use openssl::ssl::{Ssl, SslAcceptor, SslConnector, SslFiletype, SslMethod, SslVerifyMode};
-use openssl::x509::X509;
-use tokio::net::TcpListener;
-use tokio_openssl::SslStream;
-use tokio_util::codec::{FramedRead, FramedWrite};
-
-use concread::arcache::ARCacheBuilder;
-use ldap_proxy::proxy::client_process;
+use tracing_subscriber::{Layer, Registry};
 
 const DEFAULT_CONFIG_PATH: &str = "/etc/kanidm/ldap-proxy";
 
@@ -42,314 +42,364 @@ struct Opt {
 
     #[clap(value_parser, short, long, default_value_os_t = DEFAULT_CONFIG_PATH.into(), env="LDAP_PROXY_CONFIG_PATH")]
     config: PathBuf,
-}
 
-async fn ldaps_acceptor(
-    listener: TcpListener,
-    tls_parms: SslAcceptor,
-    mut broadcast_rx: broadcast::Receiver<bool>,
-    app_state: Arc<AppState>,
-) {
-    let max_incoming_ber_size = app_state.max_incoming_ber_size;
-    loop {
-        tokio::select! {
-            _ = broadcast_rx.recv() => {
-                break;
-            }
-            accept_result = listener.accept() => {
-                match accept_result {
-                    Ok((tcpstream, client_socket_addr)) => {
-                        let mut tlsstream = match Ssl::new(tls_parms.context())
-                            .and_then(|tls_obj| SslStream::new(tls_obj, tcpstream))
-                        {
-                            Ok(ta) => ta,
-                            Err(e) => {
-                                error!("LDAP TLS setup error, continuing -> {:?}", e);
-                                continue;
-                            }
-                        };
-                        if let Err(e) = SslStream::accept(Pin::new(&mut tlsstream)).await {
-                            error!("LDAP TLS accept error, continuing -> {:?}", e);
-                            continue;
-                        };
-                        let (r, w) = tokio::io::split(tlsstream);
-                        let r = FramedRead::new(r, LdapCodec::new(max_incoming_ber_size));
-                        let w = FramedWrite::new(w, LdapCodec::new(max_incoming_ber_size));
-                        let c_app_state = app_state.clone();
-                        tokio::spawn(client_process(r, w, client_socket_addr, c_app_state));
-                    }
-                    Err(e) => {
-                        error!("LDAP acceptor error, continuing -> {:?}", e);
-                    }
-                }
-            }
-        }
-    }
-    debug!("Stopped ldaps acceptor");
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-async fn setup(opt: &Opt) {
-    info!("Starting ldap-proxy");
-
-    let mut f = match File::open(&opt.config) {
-        Ok(f) => f,
-        Err(e) => {
-            error!(
-                "Unable to open config file '{}' [{:?}] 🥺",
-                &opt.config.display(),
-                e
-            );
-            return;
-        }
-    };
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Check that a config file is valid, and that the certificates and
+    /// backends it names can be used, then exit.
+    CheckConfig {
+        /// The config file, if not the one given by --config.
+        path: Option<PathBuf>,
+    },
+    /// Print an example config, with comments describing every option.
+    GenerateConfig,
+    /// Bind to the backend as a DN would through the proxy, and optionally
+    /// search, printing how long each stage took.
+    Test(TestArgs),
+}
 
-    let mut contents = String::new();
-    if let Err(e) = f.read_to_string(&mut contents) {
-        error!(
-            "unable to read config contents from '{}' {:?}",
-            &opt.config.display(),
-            e
-        );
-        return;
-    };
+#[derive(Debug, clap::Args)]
+struct TestArgs {
+    /// The DN to bind as.
+    #[clap(long)]
+    dn: String,
+    /// A file that holds the password of the DN.
+    #[clap(long)]
+    password_file: PathBuf,
+    /// Search the subtree of the base with this filter after binding.
+    #[clap(long)]
+    filter: Option<String>,
+    /// The base of the search.
+    #[clap(long, default_value = "")]
+    base: String,
+}
 
-    let sync_config: Config = match toml::from_str(contents.as_str()) {
+// Check a config file as far as can be done without starting, logging each
+// problem that is found. Returns if the config is valid.
+async fn check_config(path: &Path) -> bool {
+    let sync_config = match read_config(path) {
         Ok(c) => c,
         Err(e) => {
-            eprintln!(
-                "unable to parse config from '{}' {:?}",
-                &opt.config.display(),
-                e
-            );
-            return;
+            error!("{}", e);
+            return false;
         }
     };
 
-    debug!(?sync_config);
-
-    // Do we need to re-process the config to a different shape?
+    // The state is built as it is when the proxy starts, so what would stop it
+    // from starting is found here.
+    let app_state = build_app_state(&sync_config, None, None);
+    let mut valid = app_state.is_some();
+    if let Err(e) = check_listener_tls(&sync_config) {
+        error!("{}", e);
+        valid = false;
+    }
 
-    // Setup the broadcast system.
-    let (broadcast_tx, broadcast_rx) = broadcast::channel(1);
+    if sync_config.admin_bind.is_some() && sync_config.admin_token.is_none() {
+        error!("admin_bind requires admin_token");
+        valid = false;
+    }
 
-    // Let the listening port ready.
-    let listener = match TcpListener::bind(&sync_config.bind).await {
-        Ok(l) => l,
-        Err(e) => {
-            error!(
-                "Could not bind to LDAP server address {} -> {:?}",
-                sync_config.bind, e
-            );
-            return;
+    // Bind DNs are checked as the config is read, but the bases of each DN are
+    // only checked as they are used.
+    let dnconfigs = sync_config
+        .binddn_map
+        .iter()
+        .map(|(dn, dnconfig)| (dn.as_str(), dnconfig))
+        .chain(sync_config.binddn_patterns.iter());
+    for (dn, dnconfig) in dnconfigs {
+        for base in dnconfig.allowed_bases.iter() {
+            if normalize_dn(base).is_err() {
+                error!(%dn, "allowed_bases entry '{}' is not a valid DN", base);
+                valid = false;
+            }
         }
-    };
+        let password = dnconfig.service_bind_password.is_some();
+        let provider = dnconfig.service_bind_password_provider.is_some();
+        if password && provider {
+            error!(%dn, "service_bind_password and service_bind_password_provider can't both be set");
+            valid = false;
+        } else if dnconfig.service_bind_dn.is_some() != (password || provider) {
+            error!(%dn, "service_bind_dn and service_bind_password must be set together");
+            valid = false;
+        }
+    }
 
-    // Setup the data for the client handles.
+    if let Some(app_state) = app_state {
+        for backend in app_state
+            .backend_pools
+            .values()
+            .flat_map(|pool| pool.backends.iter())
+        {
+            backend.resolve().await;
+            if !backend.is_resolved() {
+                error!(url = %backend.url, "Unable to resolve backend");
+                valid = false;
+            }
+        }
+    }
 
-    let url = sync_config.ldap_url;
+    if valid {
+        info!("{} is valid", path.display());
+    }
+    valid
+}
 
-    match url.scheme() {
-        "ldaps" => {}
-        _ => {
-            error!("Unable to proceed. LDAPS is required in remote ldap_url");
-            return;
-        }
-    };
+// Print how long a stage of the test took.
+fn print_stage(stage: &str, started: Instant, detail: &str) {
+    println!(
+        "{:<8} {:>9.1}ms  {}",
+        stage,
+        started.elapsed().as_secs_f64() * 1000.0,
+        detail
+    );
+}
 
-    let hostname = match url.host_str() {
-        Some(s) => s,
-        None => {
-            error!("Unable to determine hostname from url");
-            return;
+// Take the same path to the backend as a client session of the DN would, and
+// report each stage. Returns if the bind, and any search, succeeded.
+async fn test_backend(config_path: &Path, args: &TestArgs) -> bool {
+    let started = Instant::now();
+    let sync_config = match read_config(config_path) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            return false;
         }
     };
-
-    let addrs = match url.socket_addrs(|| Some(636)) {
-        Ok(a) => a,
+    let password = match read_secret_file(&args.password_file) {
+        Ok(password) => password,
         Err(e) => {
-            error!(?e, "url address resolver error");
-            return;
+            error!("{}", e);
+            return false;
         }
     };
-
-    if addrs.is_empty() {
-        error!("url address resolved to no addresses");
-        return;
-    }
-
-    let mut tls_builder = match SslConnector::builder(SslMethod::tls_client()) {
-        Ok(t) => t,
+    let Some(app_state) = build_app_state(&sync_config, None, None) else {
+        return false;
+    };
+    let dn = match normalize_dn(&args.dn) {
+        Ok(dn) => dn,
         Err(e) => {
-            error!("Unable to create tls client -> {:?}", e);
-            return;
+            error!(%e, "Invalid bind dn {}", args.dn);
+            return false;
         }
     };
-
-    let cert_store = tls_builder.cert_store_mut();
-    let mut file = match File::open(&sync_config.ldap_ca) {
-        Ok(f) => f,
-        Err(e) => {
-            error!(?e, "Unable to open {:?}", &sync_config.ldap_ca);
-            return;
+    let config = match app_state.dn_config(&dn) {
+        Some(config) => config,
+        None if app_state.policy.load().allow_all_bind_dns => DnConfig::default(),
+        None => {
+            error!(
+                "{} is not in the bind maps, so the proxy refuses its binds",
+                dn
+            );
+            return false;
         }
     };
+    let Some(pool) = app_state.backend_pool(&dn, &config) else {
+        error!(%dn, "No backend pool for this DN");
+        return false;
+    };
+    print_stage("config", started, &format!("backend pool {}", pool.name));
 
-    let mut pem = Vec::new();
-    if let Err(e) = file.read_to_end(&mut pem) {
-        error!(?e, "Unable to read {:?}", &sync_config.ldap_ca);
-        return;
+    let started = Instant::now();
+    for backend in pool.backends.iter() {
+        backend.resolve().await;
     }
-
-    let ca_cert = match X509::from_pem(pem.as_slice()) {
-        Ok(c) => c,
+    let addrs: Vec<_> = pool
+        .backends
+        .iter()
+        .flat_map(|backend| backend.addrs())
+        .map(|addr| addr.to_string())
+        .collect();
+    print_stage("resolve", started, &addrs.join(", "));
+
+    let started = Instant::now();
+    let client = match BasicLdapClient::connect(&app_state, pool).await {
+        Ok(client) => client,
         Err(e) => {
-            error!(?e, "openssl");
-            return;
+            error!(?e, "Unable to connect to the backend");
+            return false;
         }
     };
+    print_stage("connect", started, client.backend().as_str());
 
-    if let Err(e) = cert_store.add_cert(ca_cert).map(|()| {
-        debug!("Added {:?} to cert store", &sync_config.ldap_ca);
-    }) {
-        error!(?e, "openssl");
-        return;
+    let started = Instant::now();
+    let lbr = LdapBindRequest {
+        dn: args.dn.clone(),
+        cred: LdapBindCred::Simple(password),
     };
-
-    let verify_param = tls_builder.verify_param_mut();
-    if let Err(e) = verify_param.set_host(hostname) {
-        error!(?e, "openssl");
-        return;
+    let code = match client.bind(lbr, vec![]).await {
+        Ok((resp, _)) => resp.res.code,
+        Err(e) => {
+            error!(?e, "Unable to bind");
+            return false;
+        }
+    };
+    print_stage("bind", started, &format!("{:?}", code));
+    let mut passed = code == LdapResultCode::Success;
+
+    if let Some(filter) = args.filter.as_deref().filter(|_| passed) {
+        let filter = match parse_ldap_filter_str(filter) {
+            Ok(filter) => filter,
+            Err(e) => {
+                error!(?e, "Invalid filter {}", filter);
+                return false;
+            }
+        };
+        if !config.permits_search_base(&args.base) || !config.permits_filter(&filter) {
+            warn!("The proxy would refuse this search for {}", dn);
+        }
+        let sr = LdapSearchRequest {
+            base: args.base.clone(),
+            scope: LdapSearchScope::Subtree,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter,
+            attrs: vec![],
+        };
+        let started = Instant::now();
+        match client.search(sr, vec![], None, None).await {
+            Ok(results) => {
+                print_stage(
+                    "search",
+                    started,
+                    &format!(
+                        "{:?}, {} entries",
+                        results.result.code,
+                        results.entries.len()
+                    ),
+                );
+                passed = results.result.code == LdapResultCode::Success;
+            }
+            Err(e) => {
+                error!(?e, "Unable to search");
+                passed = false;
+            }
+        }
     }
 
-    // None for no cert verification
-    tls_builder.set_verify(SslVerifyMode::PEER);
-
-    let tls_params = tls_builder.build();
-
-    let Some(cache) = ARCacheBuilder::new()
-        .set_size(sync_config.cache_bytes, 0)
-        .build()
-    else {
-        error!("Unable to build query cache");
-        return;
-    };
+    client.unbind().await;
+    passed
+}
 
-    let cache_entry_timeout = Duration::from_secs(sync_config.cache_entry_timeout);
-
-    let max_incoming_ber_size = sync_config.max_incoming_ber_size;
-    let max_proxy_ber_size = sync_config.max_proxy_ber_size;
-    let allow_all_bind_dns = sync_config.allow_all_bind_dns;
-
-    let app_state = Arc::new(AppState {
-        tls_params,
-        addrs,
-        binddn_map: sync_config.binddn_map.clone(),
-        cache,
-        cache_entry_timeout,
-        max_incoming_ber_size,
-        max_proxy_ber_size,
-        allow_all_bind_dns,
-    });
+async fn setup(opt: &Opt) {
+    info!("Starting ldap-proxy");
 
-    // Setup the TLS server parameters
-    let mut tls_builder = match SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()) {
-        Ok(t) => t,
+    let builder = match ProxyBuilder::from_path(&opt.config) {
+        Ok(builder) => builder,
         Err(e) => {
-            error!("Unable to create tls acceptor -> {:?}", e);
+            error!("{}", e);
             return;
         }
     };
 
-    if let Err(e) = tls_builder.set_certificate_chain_file(&sync_config.tls_chain) {
-        error!("Unable to load certificate chain -> {:?}", e);
-        return;
-    }
-
-    if let Err(e) = tls_builder.set_private_key_file(&sync_config.tls_key, SslFiletype::PEM) {
-        error!("Unable to load private key -> {:?}", e);
-        return;
+    debug!(sync_config = ?builder.config());
+    if builder.config().otlp.is_some() && cfg!(not(feature = "otlp")) {
+        warn!("The proxy was built without the otlp feature, so spans are not exported");
     }
 
-    if let Err(e) = tls_builder.check_private_key() {
-        error!("Unable to validate private key -> {:?}", e);
-        return;
+    match builder.start().await {
+        Ok(server) => server.run().await,
+        Err(e) => error!("{}", e),
     }
+}
 
-    // Done!
-    let tls_server_params = tls_builder.build();
+async fn run_command(opt: &Opt) {
+    let level = if opt.debug {
+        LevelFilter::TRACE
+    } else {
+        LevelFilter::INFO
+    };
 
-    // Setup the acceptor.
-    let acceptor = tokio::spawn(async move {
-        ldaps_acceptor(listener, tls_server_params, broadcast_rx, app_state).await
-    });
+    // The proxy's spans are exported whatever the level of the log, if its
+    // config asks for it.
+    #[cfg(feature = "otlp")]
+    let exporter = match opt.command {
+        None => read_config(&opt.config)
+            .ok()
+            .and_then(|config| config.otlp)
+            .map(|otlp| OtlpExporter::new(&otlp)),
+        Some(_) => None,
+    };
 
-    // Finally, block on the signal handler.
-    loop {
-        tokio::select! {
-            Ok(()) = tokio::signal::ctrl_c() => {
-                break
-            }
-            Some(()) = async move {
-                let sigterm = tokio::signal::unix::SignalKind::terminate();
-                #[allow(clippy::unwrap_used)]
-                tokio::signal::unix::signal(sigterm).unwrap().recv().await
-            } => {
-                break
-            }
-            Some(()) = async move {
-                let sigterm = tokio::signal::unix::SignalKind::alarm();
-                #[allow(clippy::unwrap_used)]
-                tokio::signal::unix::signal(sigterm).unwrap().recv().await
-            } => {
-                // Ignore
-            }
-            Some(()) = async move {
-                let sigterm = tokio::signal::unix::SignalKind::hangup();
-                #[allow(clippy::unwrap_used)]
-                tokio::signal::unix::signal(sigterm).unwrap().recv().await
-            } => {
-                // Ignore
-            }
-            Some(()) = async move {
-                let sigterm = tokio::signal::unix::SignalKind::user_defined1();
-                #[allow(clippy::unwrap_used)]
-                tokio::signal::unix::signal(sigterm).unwrap().recv().await
-            } => {
-                // Ignore
+    let runtime = tracing_forest::worker_task()
+        .set_global(true)
+        .map_sender(|sender| sender.or_stderr())
+        .build_with(|layer| {
+            let subscriber = Registry::default().with(layer.with_filter(level));
+            #[cfg(feature = "otlp")]
+            let subscriber = subscriber.with(
+                exporter
+                    .as_ref()
+                    .and_then(|exporter| exporter.as_ref().ok())
+                    .map(OtlpExporter::layer),
+            );
+            subscriber
+        });
+
+    match opt.command.as_ref() {
+        Some(Command::CheckConfig { path }) => {
+            let path = path.as_deref().unwrap_or(&opt.config);
+            if !runtime.on(check_config(path)).await {
+                std::process::exit(1);
             }
-            Some(()) = async move {
-                let sigterm = tokio::signal::unix::SignalKind::user_defined2();
-                #[allow(clippy::unwrap_used)]
-                tokio::signal::unix::signal(sigterm).unwrap().recv().await
-            } => {
-                // Ignore
+        }
+        Some(Command::GenerateConfig) => print!("{}", example_config()),
+        Some(Command::Test(args)) => {
+            if !runtime.on(test_backend(&opt.config, args)).await {
+                std::process::exit(1);
             }
         }
-    }
-    info!("Signal received, sending down signal to tasks");
-    // Send a broadcast that we are done.
-    if let Err(e) = broadcast_tx.send(true) {
-        error!("Unable to shutdown workers {:?}", e);
+        None => {
+            runtime
+                .on(async {
+                    #[cfg(feature = "otlp")]
+                    if let Some(Err(e)) = exporter.as_ref() {
+                        error!("{}", e);
+                    }
+                    setup(opt).await
+                })
+                .await
+        }
     }
 
-    // Wait for tasks to join.
-    let _ = acceptor.await;
+    // Sending the remaining spans blocks until the collector answers, or
+    // the exporter times out, so it is kept off the runtime's threads.
+    #[cfg(feature = "otlp")]
+    if let Some(Ok(exporter)) = exporter {
+        match tokio::task::spawn_blocking(move || exporter.shutdown()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("{}", e),
+            Err(e) => eprintln!("Unable to stop the otlp exporter -> {:?}", e),
+        }
+    }
 }
 
-#[tokio::main(flavor = "multi_thread")]
-async fn main() {
+fn main() {
     let opt = Opt::parse();
 
-    let level = if opt.debug {
-        LevelFilter::TRACE
-    } else {
-        LevelFilter::INFO
-    };
-
-    tracing_forest::worker_task()
-        .set_global(true)
-        .map_sender(|sender| sender.or_stderr())
-        .build_on(|subscriber| subscriber.with(level))
-        .on(setup(&opt))
-        .await;
+    // The proxy runs on the runtime that its config asks for. A config that
+    // can't be read is reported once the default runtime has started.
+    let runtime = match opt.command {
+        None => read_config(&opt.config)
+            .ok()
+            .map(|config| build_runtime(&config)),
+        Some(_) => None,
+    }
+    .unwrap_or_else(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+    });
+    match runtime {
+        Ok(runtime) => runtime.block_on(run_command(&opt)),
+        Err(e) => {
+            eprintln!("Unable to start the runtime -> {:?}", e);
+            std::process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,77 @@
+//! A memberOf attribute computed by the proxy, for backends that don't maintain
+//! one, such as OpenLDAP without the memberof overlay. When a search names
+//! memberOf in its attributes, the groups of each entry that lacks it are found
+//! with a search of the groups that list the entry as a member.
+
+use ldap3_proto::proto::{
+    LdapDerefAliases, LdapPartialAttribute, LdapSearchRequest, LdapSearchResultEntry,
+    LdapSearchScope,
+};
+use ldap3_proto::LdapFilter;
+use serde::Deserialize;
+
+pub const MEMBER_OF: &str = "memberOf";
+
+fn default_member_attribute() -> String {
+    "member".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MemberOfConfig {
+    /// The subtree that holds the groups.
+    pub group_base: String,
+    /// The attribute of groups that lists their members' DNs.
+    #[serde(default = "default_member_attribute")]
+    pub member_attribute: String,
+}
+
+/// If a search names memberOf in its attributes. It isn't synthesized for
+/// searches of all attributes, as servers that maintain it return it only when
+/// it is named.
+pub fn requests_member_of(sr: &LdapSearchRequest) -> bool {
+    sr.attrs.iter().any(|a| a.eq_ignore_ascii_case(MEMBER_OF))
+}
+
+fn has_member_of(entry: &LdapSearchResultEntry) -> bool {
+    entry
+        .attributes
+        .iter()
+        .any(|attr| attr.atype.eq_ignore_ascii_case(MEMBER_OF))
+}
+
+impl MemberOfConfig {
+    /// The search for the groups of an entry, or None if the backend returned
+    /// memberOf itself.
+    pub fn group_search(&self, entry: &LdapSearchResultEntry) -> Option<LdapSearchRequest> {
+        if has_member_of(entry) {
+            return None;
+        }
+        Some(LdapSearchRequest {
+            base: self.group_base.clone(),
+            scope: LdapSearchScope::Subtree,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Equality(self.member_attribute.clone(), entry.dn.clone()),
+            attrs: vec!["1.1".to_string()],
+        })
+    }
+}
+
+/// Add the DNs of an entry's groups to it as memberOf. Entries in no groups
+/// have no memberOf, as the backend would return them.
+pub fn add_member_of(entry: &mut LdapSearchResultEntry, groups: Vec<String>, typesonly: bool) {
+    if groups.is_empty() {
+        return;
+    }
+    let vals = if typesonly {
+        Vec::new()
+    } else {
+        groups.into_iter().map(String::into_bytes).collect()
+    };
+    entry.attributes.push(LdapPartialAttribute {
+        atype: MEMBER_OF.to_string(),
+        vals,
+    });
+}
@@ -0,0 +1,86 @@
+//! Simple in-process metrics. Counters are keyed by their name and labels, in the
+//! same form as the prometheus text format, eg `backend_connections_total{backend="..."}`.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The upper bounds of the buckets of duration histograms, in microseconds.
+pub const DURATION_BUCKETS_US: [u64; 10] = [
+    1_000, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 5_000_000,
+];
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counters: Mutex<BTreeMap<String, u64>>,
+}
+
+fn metric_key(name: &str, labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        name.to_string()
+    } else {
+        let labels: Vec<_> = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+            .collect();
+        format!("{}{{{}}}", name, labels.join(","))
+    }
+}
+
+impl Metrics {
+    pub fn incr(&self, name: &str, labels: &[(&str, &str)]) {
+        self.incr_by(name, labels, 1)
+    }
+
+    pub fn incr_by(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        let key = metric_key(name, labels);
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        *counters.entry(key).or_default() += value;
+    }
+
+    /// Record a duration in a histogram, as prometheus does: `_bucket` counters
+    /// of the durations up to each bound (`le`, in microseconds), and the
+    /// `_sum` of the durations in microseconds and their `_count`.
+    pub fn observe(&self, name: &str, labels: &[(&str, &str)], duration: Duration) {
+        let us = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let bounds: Vec<_> = DURATION_BUCKETS_US
+            .iter()
+            .filter(|bound| us <= **bound)
+            .map(|bound| bound.to_string())
+            .chain(std::iter::once("+Inf".to_string()))
+            .collect();
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        for bound in bounds.iter() {
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", bound));
+            let key = metric_key(&format!("{}_bucket", name), &bucket_labels);
+            *counters.entry(key).or_default() += 1;
+        }
+        *counters
+            .entry(metric_key(&format!("{}_sum", name), labels))
+            .or_default() += us;
+        *counters
+            .entry(metric_key(&format!("{}_count", name), labels))
+            .or_default() += 1;
+    }
+
+    /// Set a metric that measures a current value, rather than counting events.
+    pub fn set(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        let key = metric_key(name, labels);
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        counters.insert(key, value);
+    }
+
+    pub fn get(&self, name: &str, labels: &[(&str, &str)]) -> u64 {
+        let key = metric_key(name, labels);
+        let counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        counters.get(&key).copied().unwrap_or_default()
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.counters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
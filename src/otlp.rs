@@ -0,0 +1,96 @@
+//! Exporting the spans of client operations, and of the backend requests that
+//! they caused, to an OpenTelemetry collector over OTLP/HTTP. An upstream span
+//! is a child of its operation span, so a search that arrived at the proxy can
+//! be followed to the query that it sent to the backend in a tracing UI.
+//!
+//! The exporter is only built with the otlp feature. Without it, a config with
+//! an [otlp] section is accepted, and the section is ignored with a warning.
+
+use serde::Deserialize;
+use url::Url;
+
+/// Where spans are exported to.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OtlpConfig {
+    /// The collector's traces endpoint, such as
+    /// `http://localhost:4318/v1/traces`.
+    pub endpoint: Url,
+    /// The service.name of the exported spans.
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+fn default_service_name() -> String {
+    "ldap-proxy".to_string()
+}
+
+#[cfg(feature = "otlp")]
+pub use exporter::OtlpExporter;
+
+#[cfg(feature = "otlp")]
+mod exporter {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+    use opentelemetry_sdk::Resource;
+    use tracing::level_filters::LevelFilter;
+    use tracing::Subscriber;
+    use tracing_opentelemetry::OpenTelemetryLayer;
+    use tracing_subscriber::filter::Filtered;
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+
+    use super::OtlpConfig;
+
+    /// The spans given to its layer are batched, and sent to the collector
+    /// from a thread of their own. It can be built within the runtime, but
+    /// flush and shutdown block until the collector answers or they time out,
+    /// so they should be called from a blocking task.
+    pub struct OtlpExporter {
+        provider: SdkTracerProvider,
+    }
+
+    impl OtlpExporter {
+        pub fn new(config: &OtlpConfig) -> Result<Self, String> {
+            let exporter = SpanExporter::builder()
+                .with_http()
+                .with_endpoint(config.endpoint.as_str())
+                .build()
+                .map_err(|e| format!("unable to build the otlp exporter: {}", e))?;
+            let provider = SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(
+                    Resource::builder()
+                        .with_service_name(config.service_name.clone())
+                        .build(),
+                )
+                .build();
+            Ok(OtlpExporter { provider })
+        }
+
+        /// A layer that exports spans, whatever the level of the log. Upstream
+        /// spans are at debug, so that they are only logged when debugging.
+        pub fn layer<S>(&self) -> Filtered<OpenTelemetryLayer<S, SdkTracer>, LevelFilter, S>
+        where
+            S: Subscriber + for<'a> LookupSpan<'a>,
+        {
+            tracing_opentelemetry::layer()
+                .with_tracer(self.provider.tracer("ldap-proxy"))
+                .with_filter(LevelFilter::DEBUG)
+        }
+
+        /// Send the spans that have finished, without waiting for the batch.
+        pub fn flush(&self) -> Result<(), String> {
+            self.provider
+                .force_flush()
+                .map_err(|e| format!("unable to export spans: {}", e))
+        }
+
+        /// Send the remaining spans, and stop the exporter's thread.
+        pub fn shutdown(&self) -> Result<(), String> {
+            self.provider
+                .shutdown()
+                .map_err(|e| format!("unable to export spans: {}", e))
+        }
+    }
+}
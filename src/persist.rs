@@ -0,0 +1,210 @@
+//! Saving the search cache to disk on shutdown, and loading it again on startup,
+//! so that a restart doesn't send every client's searches to the backend at once.
+//! The cache can also be saved periodically, for when the process is killed
+//! without the chance to save it.
+//!
+//! The file holds directory data, so it is only readable by its owner. It is
+//! written to a temporary file that is then renamed over the old one, so a crash
+//! part way through leaves the previous file intact.
+//!
+//! The cache lives in memory, and the file is only read once, at startup. An
+//! embedded database such as sled or SQLite would be written on every insert
+//! for no benefit, and would add a dependency, so a snapshot file is used.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+
+use ldap3_proto::control::LdapControl;
+use ldap3_proto::proto::{
+    LdapMsg, LdapOp, LdapResult, LdapSearchResultEntry, LdapSearchResultReference,
+};
+use ldap3_proto::LdapCodec;
+use serde::{Deserialize, Serialize};
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+use tracing::{debug, info, warn};
+
+use crate::proxy::{cache_weight, CachedValue, SearchCacheKey};
+use crate::{AppState, DnConfig};
+
+/// Increased whenever the format of the file changes. Files of other versions
+/// are ignored.
+const CACHE_FILE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct PersistedEntry {
+    bind_dn: String,
+    // The search request and its controls, as a ber encoded message. Filters
+    // can't be deserialised in the form that they are serialised in.
+    request: Vec<u8>,
+    // Instants only have meaning within this process.
+    valid_until: SystemTime,
+    entries: Vec<(LdapSearchResultEntry, Vec<LdapControl>)>,
+    #[serde(default)]
+    references: Vec<(LdapSearchResultReference, Vec<LdapControl>)>,
+    result: LdapResult,
+    ctrl: Vec<LdapControl>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedCache {
+    version: u32,
+    entries: Vec<PersistedEntry>,
+}
+
+fn encode_request(key: &SearchCacheKey) -> io::Result<Vec<u8>> {
+    let mut buf = BytesMut::new();
+    LdapCodec::default().encode(
+        LdapMsg {
+            msgid: 0,
+            op: LdapOp::SearchRequest(key.search.clone()),
+            ctrl: key.ctrl.clone(),
+        },
+        &mut buf,
+    )?;
+    Ok(buf.to_vec())
+}
+
+fn decode_request(bind_dn: String, request: &[u8]) -> Option<SearchCacheKey> {
+    let msg = LdapCodec::default()
+        .decode(&mut BytesMut::from(request))
+        .ok()??;
+    match msg.op {
+        LdapOp::SearchRequest(search) => Some(SearchCacheKey::new(bind_dn, &search, msg.ctrl)),
+        _ => None,
+    }
+}
+
+/// Write the unexpired entries of the cache to path. Returns how many were saved.
+pub fn save_cache(app_state: &AppState, path: &Path) -> io::Result<usize> {
+    let now = Instant::now();
+    let system_now = SystemTime::now();
+
+    let cache_txn = app_state.cache.write();
+    let entries = cache_txn
+        .iter()
+        .filter(|(_, value)| value.valid_until > now)
+        .map(|(key, value)| {
+            Ok(PersistedEntry {
+                bind_dn: key.bind_dn.clone(),
+                request: encode_request(key)?,
+                valid_until: system_now + value.valid_until.duration_since(now),
+                entries: value.entries.clone(),
+                references: value.references.clone(),
+                result: value.result.clone(),
+                ctrl: value.ctrl.clone(),
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    // Nothing was changed, so there is nothing to commit.
+    drop(cache_txn);
+
+    let count = entries.len();
+    let persisted = PersistedCache {
+        version: CACHE_FILE_VERSION,
+        entries,
+    };
+
+    let tmp_path = path.with_extension("tmp");
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, &persisted)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(count)
+}
+
+/// Load the entries saved by save_cache. Entries that have expired, or that
+/// belong to a DN that may no longer bind, are discarded. Any problem with the
+/// file is logged and otherwise ignored. Returns how many entries were loaded.
+pub fn load_cache(app_state: &AppState, path: &Path) -> usize {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!("No saved cache at {}", path.display());
+            return 0;
+        }
+        Err(e) => {
+            warn!(
+                ?e,
+                "Unable to open saved cache {}, ignoring it",
+                path.display()
+            );
+            return 0;
+        }
+    };
+
+    let persisted: PersistedCache = match serde_json::from_reader(BufReader::new(file)) {
+        Ok(persisted) => persisted,
+        Err(e) => {
+            warn!(
+                ?e,
+                "Unable to read saved cache {}, ignoring it",
+                path.display()
+            );
+            return 0;
+        }
+    };
+    if persisted.version != CACHE_FILE_VERSION {
+        warn!(
+            "Saved cache {} is version {}, expected {}, ignoring it",
+            path.display(),
+            persisted.version,
+            CACHE_FILE_VERSION
+        );
+        return 0;
+    }
+
+    let now = Instant::now();
+    let system_now = SystemTime::now();
+    let mut loaded = 0;
+    let mut cache_txn = app_state.cache.write();
+    for entry in persisted.entries {
+        let Ok(remaining) = entry.valid_until.duration_since(system_now) else {
+            continue;
+        };
+        let policy = app_state.policy.load();
+        let dn_config = match app_state.dn_config(&entry.bind_dn) {
+            Some(dn_config) => dn_config,
+            None if policy.allow_all_bind_dns => DnConfig::default(),
+            None => continue,
+        };
+        let Some(key) = decode_request(entry.bind_dn, &entry.request) else {
+            warn!("Unable to decode a saved search, ignoring it");
+            continue;
+        };
+        // Entries never outlive the TTL that the current config gives them.
+        let remaining = remaining.min(policy.cache_ttl(&dn_config, &key.search.base));
+        if remaining.is_zero() {
+            continue;
+        }
+
+        let value = CachedValue {
+            valid_until: now + remaining,
+            entries: entry.entries,
+            references: entry.references,
+            result: entry.result,
+            ctrl: entry.ctrl,
+        };
+        let Some(weight) = cache_weight(app_state, &value) else {
+            continue;
+        };
+        app_state.cache_index.insert(&key, value.size());
+        cache_txn.insert_sized(key, value, weight);
+        loaded += 1;
+    }
+    cache_txn.commit();
+
+    info!("Loaded {} cached searches from {}", loaded, path.display());
+    loaded
+}
@@ -0,0 +1,80 @@
+//! The password policy control (draft-behera-ldap-password-policy-11). Clients
+//! send it without a value on their binds, and the backend answers with one
+//! that warns of a password that is about to expire, or of the grace logins
+//! left once it has, or says why the bind was refused. ldap3_proto decodes the
+//! response as the request and loses its value, so it is relayed to the client
+//! as the backend sent it, and read by the proxy for the audit log.
+
+pub const OID_PASSWORD_POLICY: &str = "1.3.6.1.4.1.42.2.27.8.5.1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyWarning {
+    /// Seconds until the password expires.
+    TimeBeforeExpiration(i32),
+    /// Binds left with the expired password.
+    GraceAuthNsRemaining(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyError {
+    PasswordExpired,
+    AccountLocked,
+    ChangeAfterReset,
+    PasswordModNotAllowed,
+    MustSupplyOldPassword,
+    InsufficientPasswordQuality,
+    PasswordTooShort,
+    PasswordTooYoung,
+    PasswordInHistory,
+}
+
+impl PolicyError {
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            0 => Some(PolicyError::PasswordExpired),
+            1 => Some(PolicyError::AccountLocked),
+            2 => Some(PolicyError::ChangeAfterReset),
+            3 => Some(PolicyError::PasswordModNotAllowed),
+            4 => Some(PolicyError::MustSupplyOldPassword),
+            5 => Some(PolicyError::InsufficientPasswordQuality),
+            6 => Some(PolicyError::PasswordTooShort),
+            7 => Some(PolicyError::PasswordTooYoung),
+            8 => Some(PolicyError::PasswordInHistory),
+            _ => None,
+        }
+    }
+
+    /// The name of the error, for the audit log and metrics.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            PolicyError::PasswordExpired => "password_expired",
+            PolicyError::AccountLocked => "account_locked",
+            PolicyError::ChangeAfterReset => "change_after_reset",
+            PolicyError::PasswordModNotAllowed => "password_mod_not_allowed",
+            PolicyError::MustSupplyOldPassword => "must_supply_old_password",
+            PolicyError::InsufficientPasswordQuality => "insufficient_password_quality",
+            PolicyError::PasswordTooShort => "password_too_short",
+            PolicyError::PasswordTooYoung => "password_too_young",
+            PolicyError::PasswordInHistory => "password_in_history",
+        }
+    }
+}
+
+/// The value of the response control.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PasswordPolicyResponse {
+    pub warning: Option<PolicyWarning>,
+    pub error: Option<PolicyError>,
+}
+
+impl PasswordPolicyResponse {
+    /// The name of the error, or else of the warning, for the audit log.
+    pub fn reason(&self) -> Option<&'static str> {
+        match (self.error, self.warning) {
+            (Some(error), _) => Some(error.reason()),
+            (None, Some(PolicyWarning::TimeBeforeExpiration(_))) => Some("password_expiring"),
+            (None, Some(PolicyWarning::GraceAuthNsRemaining(_))) => Some("grace_login"),
+            (None, None) => None,
+        }
+    }
+}
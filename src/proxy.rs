@@ -1,475 +1,5550 @@
-use futures_util::sink::SinkExt;
+use futures_util::sink::{Sink, SinkExt};
 use futures_util::stream::StreamExt;
+use futures_util::FutureExt;
 use ldap3_proto::control::LdapControl;
+use std::collections::VecDeque;
 use std::net::SocketAddr;
 use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::{debug, error, info, span, trace, warn, Level};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::{AbortHandle, JoinHandle, JoinSet};
+use tokio_util::codec::{Framed, FramedRead, FramedWrite};
+use tracing::{debug, error, field, info, span, trace, warn, Instrument, Level, Span};
+use url::Url;
+use zeroize::Zeroize;
 
-use openssl::ssl::{Ssl, SslConnector};
+use openssl::x509::X509Ref;
+use std::future::Future;
 use std::hash::Hash;
 use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::Duration;
 use tokio::io::{ReadHalf, WriteHalf};
-use tokio::net::TcpStream;
-use tokio_openssl::SslStream;
+use tokio::net::{TcpStream, UnixStream};
 
 use ldap3_proto::proto::*;
-use ldap3_proto::LdapCodec;
 
 use std::time::Instant;
 
-use crate::{AppState, DnConfig};
+use crate::access::{BindAccess, Decision, SearchAccess};
+use crate::adcompat::AdBindFailure;
+use crate::attrmap::{rewrite_entry, rewrite_search};
+use crate::audit::{redacted_filter_string, SessionAudit};
+use crate::breaker::CircuitBreakers;
+use crate::certmap::ClientCertificate;
+use crate::coalesce::{follow, Flight};
+use crate::codec::{
+    password_policy_response, BackendCodec, BackendMsg, ClientCodec, ClientRequest, ClientResponse,
+    RawControl,
+};
+use crate::connections::SessionHandle;
+use crate::controls::{
+    admin_controls, is_persistent, is_stateful, is_sync, AdminControls, ControlPolicy,
+    NOTIFICATION_CONTROLS,
+};
+use crate::dn::{dn_is_within, normalize_dn, parent_dn};
+use crate::filter::canonical_filter;
+use crate::latency::{BackendLatency, Stage};
+use crate::memberof::{add_member_of, requests_member_of};
+use crate::ppolicy::{PasswordPolicyResponse, PolicyWarning, OID_PASSWORD_POLICY};
+use crate::quota::QuotaPermit;
+use crate::referral::{rewrite_target, rewrite_url, LdapUrl, ReferralMode};
+use crate::rewrite::DnRewrite;
+use crate::rootdse::{is_root_dse_search, restrict_root_dse, LEARNED_ATTRIBUTES};
+use crate::schema::SchemaCache;
+use crate::sort::{sort_result, SortRequest, OID_SERVER_SORT};
+use crate::tap::SessionTap;
+use crate::vlv::{
+    VlvRequest, VlvResponse, OID_VLV_REQUEST, OID_VLV_RESPONSE, SORT_CONTROL_MISSING,
+};
+use crate::{
+    network_contains, AppState, Backend, BackendPool, BackendTimeouts, DnConfig, Policy, SpkiPin,
+    Transport, DEFAULT_BACKEND,
+};
+use hashbrown::{HashMap, HashSet};
 
-type CR = ReadHalf<SslStream<TcpStream>>;
-type CW = WriteHalf<SslStream<TcpStream>>;
+const OID_WHOAMI: &str = "1.3.6.1.4.1.4203.1.11.3";
+pub(crate) const OID_PASSWORD_MODIFY: &str = "1.3.6.1.4.1.4203.1.11.1";
+const OID_STARTTLS: &str = "1.3.6.1.4.1.1466.20037";
+const OID_NOTICE_OF_DISCONNECTION: &str = "1.3.6.1.4.1.1466.20036";
+
+// How many responses may be queued for a client before operations have to
+// wait for the client to read them.
+const SESSION_QUEUE_DEPTH: usize = 128;
+// Clients that keep binding after this many rate limited binds in a row are
+// disconnected.
+const MAX_RATE_LIMITED_BINDS: usize = 5;
+// How many paged searches a session may have part way through. Starting more
+// forgets the oldest.
+const MAX_PAGED_SEARCHES: usize = 16;
+
+// The connection to a backend, which is tls unless the backend is ldap://.
+pub(crate) trait BackendStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> BackendStream for T {}
+
+type CR = ReadHalf<Box<dyn BackendStream>>;
+type CW = WriteHalf<Box<dyn BackendStream>>;
 
 #[derive(Debug, Clone, Hash, PartialOrd, Ord, Eq, PartialEq)]
 pub struct SearchCacheKey {
-    bind_dn: String,
-    search: LdapSearchRequest,
-    ctrl: Vec<LdapControl>,
+    pub(crate) bind_dn: String,
+    pub(crate) search: LdapSearchRequest,
+    pub(crate) ctrl: Vec<LdapControl>,
+}
+
+impl SearchCacheKey {
+    /// The key of a search by a normalised bind DN. The search is made
+    /// canonical, so that searches that only differ in the case of the base
+    /// and attribute names, the order of the requested attributes, or the order
+    /// of the terms of their filters, share an entry.
+    pub fn new(bind_dn: String, search: &LdapSearchRequest, ctrl: Vec<LdapControl>) -> Self {
+        let mut search = search.clone();
+        if let Ok(base) = normalize_dn(&search.base) {
+            search.base = base;
+        }
+        for attr in search.attrs.iter_mut() {
+            attr.make_ascii_lowercase();
+        }
+        search.attrs.sort();
+        search.attrs.dedup();
+        search.filter = canonical_filter(&search.filter);
+        SearchCacheKey {
+            bind_dn,
+            search,
+            ctrl,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct CachedValue {
     pub valid_until: Instant,
     pub entries: Vec<(LdapSearchResultEntry, Vec<LdapControl>)>,
+    pub references: Vec<(LdapSearchResultReference, Vec<LdapControl>)>,
     pub result: LdapResult,
     pub ctrl: Vec<LdapControl>,
 }
 
+// The memory of the buffer of a vector, not counting what its items hold.
+fn buffer_size<T>(v: &Vec<T>) -> usize {
+    v.capacity() * size_of::<T>()
+}
+
+// The memory that an entry holds beyond itself: its DN, and the names and
+// values of its attributes. The size of ldap3_proto counts each byte of a
+// value as if it were a vector.
+fn entry_heap_size(entry: &LdapSearchResultEntry) -> usize {
+    entry.dn.capacity()
+        + buffer_size(&entry.attributes)
+        + entry
+            .attributes
+            .iter()
+            .map(|attr| {
+                attr.atype.capacity()
+                    + buffer_size(&attr.vals)
+                    + attr.vals.iter().map(|v| v.capacity()).sum::<usize>()
+            })
+            .sum::<usize>()
+}
+
+fn controls_heap_size(ctrl: &Vec<LdapControl>) -> usize {
+    let cookie = |cookie: &Option<Vec<u8>>| cookie.as_ref().map_or(0, |c| c.capacity());
+    buffer_size(ctrl)
+        + ctrl
+            .iter()
+            .map(|c| match c {
+                LdapControl::SyncRequest { cookie: c, .. }
+                | LdapControl::SyncState { cookie: c, .. }
+                | LdapControl::SyncDone { cookie: c, .. }
+                | LdapControl::AdDirsync { cookie: c, .. } => cookie(c),
+                LdapControl::SimplePagedResults { cookie, .. } => cookie.capacity(),
+                LdapControl::ServerSort { sort_requests } => {
+                    buffer_size(sort_requests)
+                        + sort_requests
+                            .iter()
+                            .map(|key| {
+                                key.attribute_name.capacity()
+                                    + key.ordering_rule.as_ref().map_or(0, |r| r.capacity())
+                            })
+                            .sum::<usize>()
+                }
+                LdapControl::ServerSortResult { sort_result } => sort_result
+                    .attribute_type
+                    .as_ref()
+                    .map_or(0, |a| a.capacity()),
+                LdapControl::ManageDsaIT { .. } | LdapControl::PasswordPolicyRequest { .. } => 0,
+            })
+            .sum::<usize>()
+}
+
+fn strings_heap_size(strings: &Vec<String>) -> usize {
+    buffer_size(strings) + strings.iter().map(|s| s.capacity()).sum::<usize>()
+}
+
 impl CachedValue {
+    /// The memory that the value holds, including the DNs, attribute names and
+    /// values of its entries, its references and its controls.
     pub fn size(&self) -> usize {
-        std::mem::size_of::<Self>() + self.entries.iter().map(|(e, _)| e.size()).sum::<usize>()
+        size_of::<Self>()
+            + buffer_size(&self.entries)
+            + self
+                .entries
+                .iter()
+                .map(|(e, ctrl)| entry_heap_size(e) + controls_heap_size(ctrl))
+                .sum::<usize>()
+            + buffer_size(&self.references)
+            + self
+                .references
+                .iter()
+                .map(|(r, ctrl)| strings_heap_size(&r.uris) + controls_heap_size(ctrl))
+                .sum::<usize>()
+            + self.result.matcheddn.capacity()
+            + self.result.message.capacity()
+            + strings_heap_size(&self.result.referral)
+            + controls_heap_size(&self.ctrl)
+    }
+
+    /// Found nothing, such as the lookup of a user that doesn't exist.
+    pub fn is_negative(&self) -> bool {
+        match self.result.code {
+            LdapResultCode::NoSuchObject => true,
+            LdapResultCode::Success => self.entries.is_empty() && self.references.is_empty(),
+            _ => false,
+        }
     }
 }
 
-enum ClientState {
-    Unbound,
-    Authenticated {
-        dn: String,
-        config: DnConfig,
-        client: BasicLdapClient,
-    },
+/// Intermediate responses, with their controls.
+pub type Intermediates = Vec<(LdapIntermediateResponse, Vec<LdapControl>)>;
+
+/// The responses of the backend to a search.
+#[derive(Debug, Clone)]
+pub struct SearchResults {
+    pub entries: Vec<(LdapSearchResultEntry, Vec<LdapControl>)>,
+    pub references: Vec<(LdapSearchResultReference, Vec<LdapControl>)>,
+    /// Intermediate responses, such as the sync info messages of a syncrepl
+    /// refresh, in the order that they arrived.
+    pub intermediates: Intermediates,
+    pub result: LdapResult,
+    pub ctrl: Vec<LdapControl>,
 }
 
-fn bind_operror(msgid: i32, msg: &str) -> LdapMsg {
-    LdapMsg {
-        msgid,
-        op: LdapOp::BindResponse(LdapBindResponse {
-            res: LdapResult {
-                code: LdapResultCode::OperationsError,
-                matcheddn: "".to_string(),
-                message: msg.to_string(),
-                referral: vec![],
-            },
-            saslcreds: None,
-        }),
-        ctrl: vec![],
+// The state of an authenticated session. This is shared between all the
+// operations that the client has in flight.
+struct Session {
+    dn: String,
+    // The name of the backend pool that client is connected to.
+    pool: String,
+    policy: std::sync::RwLock<Arc<SessionPolicy>>,
+    client: std::sync::RwLock<Arc<BasicLdapClient>>,
+    // Held while the backend connection is being replaced, so that concurrent
+    // operations that all see the same failure only reconnect once.
+    reconnect_lock: Mutex<()>,
+    bind: std::sync::Mutex<RetainedBind>,
+    // Paged searches that can be continued, by the cookie that was handed to
+    // the client for the next page.
+    paged_searches: Mutex<HashMap<Vec<u8>, PagedSearch>>,
+    // Connections to the other backend pools that searches have been routed
+    // to by their base, bound as the session.
+    routed: Mutex<HashMap<String, Arc<BasicLdapClient>>>,
+    // The client is a connection of the service connections, bound as the
+    // service account of the DN rather than as the session.
+    shared: bool,
+}
+
+// A session that has been detached from its client, with a backend connection
+// yet to be made. A session of a service bind keeps the shared connection.
+struct DetachedSession {
+    dn: String,
+    pool: String,
+    policy: Arc<SessionPolicy>,
+    bind: RetainedBind,
+    shared: Option<Arc<BasicLdapClient>>,
+}
+
+impl DetachedSession {
+    // The session, on an idle pooled connection bound again as its DN, or a
+    // new one. It should be released with release_session once done.
+    async fn connect(self, app_state: &AppState) -> Result<Arc<Session>, LdapError> {
+        let shared = self.shared.is_some();
+        let client = match self.shared {
+            Some(client) => client,
+            None => {
+                let pool = app_state
+                    .backend_pools
+                    .get(&self.pool)
+                    .ok_or(LdapError::ConnectError)?;
+                let pooled = app_state
+                    .upstream_pool
+                    .checkout(&self.pool, &self.dn)
+                    .or_else(|| app_state.upstream_pool.checkout_neutral(&self.pool));
+                let client = match pooled {
+                    Some(client) => client,
+                    None => BasicLdapClient::connect(app_state, pool).await?,
+                };
+                let (bind_resp, _) = client
+                    .bind(self.bind.lbr.clone(), self.bind.ctrl.clone())
+                    .await?;
+                if bind_resp.res.code != LdapResultCode::Success {
+                    error!(code = ?bind_resp.res.code, "Unable to bind {} to a new backend connection", self.dn);
+                    return Err(LdapError::RebindFailed);
+                }
+                Arc::new(client)
+            }
+        };
+        Ok(Arc::new(Session {
+            dn: self.dn,
+            pool: self.pool,
+            policy: std::sync::RwLock::new(self.policy),
+            client: std::sync::RwLock::new(client),
+            reconnect_lock: Mutex::new(()),
+            bind: std::sync::Mutex::new(self.bind),
+            paged_searches: Mutex::new(HashMap::new()),
+            routed: Mutex::new(HashMap::new()),
+            shared,
+        }))
     }
 }
 
-pub async fn client_process<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
-    mut r: FramedRead<R, LdapCodec>,
-    mut w: FramedWrite<W, LdapCodec>,
-    client_address: SocketAddr,
-    app_state: Arc<AppState>,
-) {
-    info!("Accept from {}", client_address);
+// The config of a session's DN, and the request controls that it may relay to
+// the backend. These are replaced when the config is reloaded.
+struct SessionPolicy {
+    config: DnConfig,
+    request_controls: ControlPolicy,
+}
 
-    // We always start unbound.
-    let mut state = ClientState::Unbound;
+// The bind request of a session, retained so that the session can be re-bound
+// to a new backend connection. The credentials are zeroed when it is dropped.
+struct RetainedBind {
+    lbr: LdapBindRequest,
+    ctrl: Vec<LdapControl>,
+}
 
-    // Start to wait for incoming packets
-    while let Some(Ok(protomsg)) = r.next().await {
-        let next_state = match (&mut state, protomsg) {
-            // Doesn't matter what state we are in, any bind will trigger this process.
-            (
-                _,
-                LdapMsg {
-                    msgid,
-                    op: LdapOp::BindRequest(lbr),
-                    ctrl,
-                },
-            ) => {
-                let span = span!(Level::INFO, "bind");
-                let _enter = span.enter();
+impl Drop for RetainedBind {
+    fn drop(&mut self) {
+        match &mut self.lbr.cred {
+            LdapBindCred::Simple(password) => password.zeroize(),
+            LdapBindCred::SASL(sasl) => sasl.credentials.zeroize(),
+        }
+    }
+}
 
-                trace!(?lbr);
-                // Is the requested bind dn valid per our map?
-                let config = match app_state.binddn_map.get(&lbr.dn) {
-                    Some(dnconfig) => {
-                        // They have a config! They can proceed.
-                        dnconfig.clone()
-                    }
-                    None => {
-                        if app_state.allow_all_bind_dns {
-                            // All bind dns are allow, return a default config.
-                            DnConfig::default()
-                        } else {
-                            // Bind dns are filtered, sad trombone time.
-                            let resp_msg = bind_operror(msgid, "unable to bind");
-                            if w.send(resp_msg).await.is_err() {
-                                error!("Unable to send response");
-                                break;
-                            }
-                            continue;
-                        }
-                    }
-                };
+impl Session {
+    fn policy(&self) -> Arc<SessionPolicy> {
+        self.policy
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
 
-                // Okay, we have a dnconfig, so they are allowed to proceed. Lets
-                // now setup the client for their session, and anything else we
-                // need to configure.
+    fn set_policy(&self, config: DnConfig, request_controls: ControlPolicy) {
+        *self.policy.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(SessionPolicy {
+            config,
+            request_controls,
+        });
+    }
 
-                let dn = lbr.dn.clone();
+    // The bind to send to a new backend connection.
+    fn retained_bind(&self) -> (LdapBindRequest, Vec<LdapControl>) {
+        let bind = self.bind.lock().unwrap_or_else(|e| e.into_inner());
+        (bind.lbr.clone(), bind.ctrl.clone())
+    }
 
-                // We need the client to connect *and* bind to proceed here!
-                let mut client = match BasicLdapClient::build(
-                    &app_state.addrs,
-                    &app_state.tls_params,
-                    app_state.max_proxy_ber_size,
-                )
-                .await
-                {
-                    Ok(c) => c,
-                    Err(e) => {
-                        error!(?e, "A client build error has occurred.");
-                        let resp_msg = bind_operror(msgid, "unable to bind");
-                        if w.send(resp_msg).await.is_err() {
-                            error!("Unable to send response");
-                        }
-                        // Always bail.
-                        break;
-                    }
-                };
+    // After the session has changed its own password, later re-binds must use
+    // the new one.
+    fn update_retained_password(&self, new_password: &str) {
+        let mut bind = self.bind.lock().unwrap_or_else(|e| e.into_inner());
+        if let LdapBindCred::Simple(password) = &mut bind.lbr.cred {
+            password.zeroize();
+            *password = new_password.to_string();
+        }
+    }
 
-                let valid = match client.bind(lbr, ctrl).await {
-                    Ok((bind_resp, ctrl)) => {
-                        // Almost there, lets check the bind result.
-                        let valid = bind_resp.res.code == LdapResultCode::Success;
+    fn client(&self) -> Arc<BasicLdapClient> {
+        self.client
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
 
-                        let resp_msg = LdapMsg {
-                            msgid,
-                            op: LdapOp::BindResponse(bind_resp),
-                            ctrl,
-                        };
-                        if w.send(resp_msg).await.is_err() {
-                            error!("Unable to send response");
-                            break;
-                        }
-                        valid
-                    }
-                    Err(e) => {
-                        error!(?e, "A client bind error has occurred");
-                        let resp_msg = bind_operror(msgid, "unable to bind");
-                        if w.send(resp_msg).await.is_err() {
-                            error!("Unable to send response");
-                        }
-                        // Always bail.
-                        break;
-                    }
-                };
+    // What a copy of the session needs, to make operations of its own once
+    // the client's operation has completed.
+    fn detach(&self) -> DetachedSession {
+        let (lbr, ctrl) = self.retained_bind();
+        DetachedSession {
+            dn: self.dn.clone(),
+            pool: self.pool.clone(),
+            policy: self.policy(),
+            bind: RetainedBind { lbr, ctrl },
+            shared: self.shared.then(|| self.client()),
+        }
+    }
 
-                if valid {
-                    info!("Successful bind for {}", dn);
-                    Some(ClientState::Authenticated { dn, config, client })
-                } else {
-                    None
-                }
-            }
-            // Unbinds are always actioned.
-            (
-                _,
-                LdapMsg {
-                    msgid: _,
-                    op: LdapOp::UnbindRequest,
-                    ctrl: _,
-                },
-            ) => {
-                trace!("unbind");
-                break;
-            }
+    // Replace a failed backend connection with a new one, bound with the
+    // session's original credentials.
+    async fn reconnect(
+        &self,
+        app_state: &AppState,
+        failed: &Arc<BasicLdapClient>,
+    ) -> Result<Arc<BasicLdapClient>, LdapError> {
+        let _guard = self.reconnect_lock.lock().await;
 
-            // Authenticated message handler.
-            //  - Search
-            (
-                ClientState::Authenticated {
-                    dn,
-                    config,
-                    ref mut client,
-                },
-                LdapMsg {
-                    msgid,
-                    op: LdapOp::SearchRequest(sr),
-                    ctrl,
-                },
-            ) => {
-                let span = span!(Level::INFO, "search");
-                let _enter = span.enter();
+        let current = self.client();
+        if !Arc::ptr_eq(&current, failed) {
+            // Another operation has already reconnected.
+            return Ok(current);
+        }
 
-                // Pre check if the search is allowed for this dn / scope / filter
-                if config.allowed_queries.is_empty() {
-                    // All queries are allowed.
-                    debug!("All queries are allowed");
-                } else {
-                    // Let's check the query details.
-                    let allow_key = (sr.base.clone(), sr.scope.clone(), sr.filter.clone());
-
-                    if config.allowed_queries.contains(&allow_key) {
-                        // Good to proceed.
-                        debug!("Query is granted");
-                    } else {
-                        warn!(?allow_key, "Requested query is not allowed for {}", dn);
-                        // If not, send an empty result.
-                        if w.send(LdapMsg {
-                            msgid,
-                            op: LdapOp::SearchResultDone(LdapResult {
-                                code: LdapResultCode::Success,
-                                matcheddn: "".to_string(),
-                                message: "".to_string(),
-                                referral: vec![],
-                            }),
-                            ctrl,
-                        })
-                        .await
-                        .is_err()
-                        {
-                            error!("Unable to send response");
-                        }
-                        // Always bail.
-                        break;
-                    }
-                };
+        let pool = app_state
+            .backend_pools
+            .get(&self.pool)
+            .ok_or(LdapError::ConnectError)?;
 
-                // This is done like this to facilitate a cache mechanism in future.
-                //
-                // Cache will need to key on:
-                //    bind_dn
-                //    base
-                //    scope
-                //    deref aliases
-                //    types only
-                //    filter
-                //    attrs
-                //   search controls
-                //
-                // Which is a lot, but it's everything that controls to results to
-                // ensure we don't introduce corruption.
-
-                let now = Instant::now();
-
-                // get the read txn.
-                let mut cache_read_txn = app_state.cache.read();
-
-                let cache_key = SearchCacheKey {
-                    bind_dn: dn.clone(),
-                    search: sr.clone(),
-                    ctrl: ctrl.clone(),
-                };
-                debug!(?cache_key);
+        warn!(pool = %self.pool, backend = %failed.backend(), "Backend connection lost, reconnecting");
+        let (lbr, ctrl) = self.retained_bind();
+        let client = if self.shared {
+            let config = self.policy().config.clone();
+            let (client, service_lbr) =
+                service_connection(app_state, pool, &self.dn, &config, Some(failed))
+                    .await?
+                    .ok_or(LdapError::RebindFailed)?;
+            // The password may have been fetched again.
+            self.bind.lock().unwrap_or_else(|e| e.into_inner()).lbr = service_lbr;
+            client
+        } else {
+            let client = BasicLdapClient::connect(app_state, pool).await?;
+            let (bind_resp, _) = client.bind(lbr, ctrl).await?;
+            if bind_resp.res.code != LdapResultCode::Success {
+                error!(code = ?bind_resp.res.code, "Unable to re-bind {}", self.dn);
+                return Err(LdapError::RebindFailed);
+            }
+            Arc::new(client)
+        };
 
-                let maybe_results = cache_read_txn.get(&cache_key).and_then(|cache_value| {
-                    if cache_value.valid_until > now {
-                        Some(cache_value.clone())
-                    } else {
-                        debug!("Cache item expired");
-                        None
-                    }
-                });
+        // Paged search state lived on the old connection.
+        self.paged_searches
+            .lock()
+            .await
+            .retain(|_, paged| matches!(paged.state, PagedState::Cached { .. }));
 
-                let was_cache_miss = maybe_results.is_none();
+        app_state
+            .metrics
+            .incr("backend_reconnects_total", &[("pool", &self.pool)]);
+        info!(backend = %client.backend(), "Re-bound {} to a new backend connection", self.dn);
 
-                debug!("cache hit {}", !was_cache_miss);
+        *self.client.write().unwrap_or_else(|e| e.into_inner()) = client.clone();
+        Ok(client)
+    }
 
-                let (entries, result, ctrl) = match maybe_results {
-                    Some(CachedValue {
-                        valid_until: _,
-                        entries,
-                        result,
-                        ctrl,
-                    }) => (entries, result, ctrl),
-                    None => {
-                        match client.search(sr, ctrl).await {
-                            Ok(data) => data,
-                            Err(e) => {
-                                error!(?e, "A client search error has occurred");
-                                let resp_msg = bind_operror(msgid, "unable to search");
-                                if w.send(resp_msg).await.is_err() {
-                                    error!("Unable to send response");
-                                }
-                                // Always bail.
-                                break;
-                            }
+    // Run an operation against the backend. If the backend connection has been
+    // lost, reconnect and run it again, as many times as the retry policy
+    // allows.
+    async fn retry<T, F, Fut>(&self, app_state: &AppState, f: F) -> Result<T, LdapError>
+    where
+        F: Fn(Arc<BasicLdapClient>) -> Fut,
+        Fut: Future<Output = Result<T, LdapError>>,
+    {
+        let mut client = self.client();
+        let mut retry = 0;
+        loop {
+            match f(client.clone()).await {
+                Err(LdapError::Transport) if retry < app_state.retry.attempts => {
+                    tokio::time::sleep(app_state.retry.delay(retry)).await;
+                    retry += 1;
+                    client = match self.reconnect(app_state, &client).await {
+                        Ok(client) => client,
+                        // The backend is still unreachable.
+                        Err(LdapError::ConnectError | LdapError::Unavailable) => {
+                            return Err(LdapError::Transport)
                         }
-                    }
-                };
-
-                // Update cache if needed.
-                if was_cache_miss {
-                    let cache_value = CachedValue {
-                        valid_until: now + app_state.cache_entry_timeout,
-                        entries: entries.clone(),
-                        result: result.clone(),
-                        ctrl: ctrl.clone(),
+                        Err(e) => return Err(e),
                     };
-                    if let Some(cache_value_size) = NonZeroUsize::new(cache_value.size()) {
-                        debug!("Adding entry of size {} to cache", cache_value_size);
-                        cache_read_txn.insert_sized(cache_key, cache_value, cache_value_size);
-                    } else {
-                        error!("Invalid entry size, unable to add to cache");
-                    }
                 }
+                res => return res,
+            }
+        }
+    }
 
-                for (entry, ctrl) in entries {
-                    if w.send(LdapMsg {
-                        msgid,
-                        op: LdapOp::SearchResultEntry(entry),
-                        ctrl,
-                    })
-                    .await
-                    .is_err()
+    // Reconnect if the backend connection has been lost, without retrying the
+    // operation. This is for operations that may have been applied before the
+    // connection failed, so that the next operation has a working connection.
+    async fn recover(&self, app_state: &AppState, failed: &Arc<BasicLdapClient>) {
+        if let Err(e) = self.reconnect(app_state, failed).await {
+            error!(?e, "Unable to reconnect to backend");
+        }
+    }
+
+    // The connection to another backend pool that a search has been routed to,
+    // connecting and binding as the session if there isn't an open one.
+    async fn routed_client(
+        &self,
+        app_state: &AppState,
+        pool: &str,
+    ) -> Result<Arc<BasicLdapClient>, LdapError> {
+        let mut routed = self.routed.lock().await;
+        if let Some(client) = routed.get(pool).filter(|client| client.is_open()) {
+            return Ok(client.clone());
+        }
+
+        let backend_pool = app_state
+            .backend_pools
+            .get(pool)
+            .ok_or(LdapError::ConnectError)?;
+        let client = BasicLdapClient::connect(app_state, backend_pool).await?;
+        let (lbr, ctrl) = self.retained_bind();
+        let (bind_resp, _) = client.bind(lbr, ctrl).await?;
+        if bind_resp.res.code != LdapResultCode::Success {
+            error!(code = ?bind_resp.res.code, %pool, "Unable to bind {} to a routed backend", self.dn);
+            return Err(LdapError::RebindFailed);
+        }
+        debug!(%pool, backend = %client.backend(), "Bound {} to a routed backend", self.dn);
+
+        let client = Arc::new(client);
+        routed.insert(pool.to_string(), client.clone());
+        Ok(client)
+    }
+
+    // Run an operation against the connection to another backend pool that a
+    // search has been routed to. If that connection has been lost, connect
+    // and bind a new one and run it again, as many times as the retry policy
+    // allows.
+    async fn retry_routed<T, F, Fut>(
+        &self,
+        app_state: &AppState,
+        pool: &str,
+        f: F,
+    ) -> Result<T, LdapError>
+    where
+        F: Fn(Arc<BasicLdapClient>) -> Fut,
+        Fut: Future<Output = Result<T, LdapError>>,
+    {
+        let mut retry = 0;
+        loop {
+            let client = self
+                .routed_client(app_state, pool)
+                .await
+                .map_err(unreachable_route)?;
+            match f(client.clone()).await {
+                Err(LdapError::Transport) if retry < app_state.retry.attempts => {
+                    warn!(%pool, backend = %client.backend(), "Routed backend connection lost, reconnecting");
+                    let mut routed = self.routed.lock().await;
+                    if routed
+                        .get(pool)
+                        .is_some_and(|current| Arc::ptr_eq(current, &client))
                     {
-                        error!("Unable to send response");
-                        break;
+                        routed.remove(pool);
                     }
+                    drop(routed);
+                    app_state
+                        .metrics
+                        .incr("backend_reconnects_total", &[("pool", pool)]);
+                    tokio::time::sleep(app_state.retry.delay(retry)).await;
+                    retry += 1;
                 }
+                res => return res,
+            }
+        }
+    }
 
-                if w.send(LdapMsg {
-                    msgid,
-                    op: LdapOp::SearchResultDone(result),
-                    ctrl,
-                })
-                .await
-                .is_err()
-                {
-                    error!("Unable to send response");
-                    break;
-                }
+    // Record an operation against the backend that will serve it, returning the
+    // span that the operation should run in.
+    fn span(&self, op: &'static str, msgid: i32, app_state: &AppState) -> Span {
+        let client = self.client();
+        let backend = client.backend().as_str();
+        app_state.metrics.incr(
+            "operations_total",
+            &[("op", op), ("pool", &self.pool), ("backend", backend)],
+        );
+        span!(Level::INFO, "operation", op, msgid, pool = %self.pool, backend)
+    }
+}
 
-                // Try and quiesce now.
-                app_state.cache.try_quiesce();
+enum ClientState {
+    Unbound,
+    Authenticated(Arc<Session>),
+}
 
-                // No state change
-                None
-            }
-            // Extended Requests - Generally has whoami.
-            (
-                ClientState::Authenticated {
-                    dn,
-                    config: _,
-                    client: _,
-                },
-                LdapMsg {
-                    msgid,
-                    op: LdapOp::ExtendedRequest(ler),
-                    ctrl: _,
-                },
-            ) => {
-                let op = match ler.name.as_str() {
-                    "1.3.6.1.4.1.4203.1.11.3" => LdapOp::ExtendedResponse(LdapExtendedResponse {
-                        res: LdapResult {
-                            code: LdapResultCode::Success,
-                            matcheddn: "".to_string(),
-                            message: "".to_string(),
-                            referral: vec![],
-                        },
-                        name: None,
-                        value: Some(Vec::from(dn.as_str())),
-                    }),
-                    _ => LdapOp::ExtendedResponse(LdapExtendedResponse {
+// Operations run as their own tasks, and send their responses back to the
+// client session through a queue of these. Nearly all of these are responses,
+// so the size difference between the variants doesn't matter.
+#[allow(clippy::large_enum_variant)]
+enum SessionEvent {
+    Response(ClientResponse),
+    // The results of this search were read from the cache.
+    CacheHit(i32),
+    // Something went wrong badly enough that the session must be ended.
+    Disconnect,
+}
+
+type Responder = mpsc::Sender<SessionEvent>;
+
+// Returns the paged results (RFC 2696) cookie of the request, if the paged
+// results control is present.
+fn paged_results_cookie(ctrl: &[LdapControl]) -> Option<&[u8]> {
+    paged_results(ctrl).map(|(_, cookie)| cookie)
+}
+
+// The page size and cookie of the paged results control.
+fn paged_results(ctrl: &[LdapControl]) -> Option<(i64, &[u8])> {
+    ctrl.iter().find_map(|c| match c {
+        LdapControl::SimplePagedResults { size, cookie } => Some((*size, cookie.as_slice())),
+        _ => None,
+    })
+}
+
+fn without_paged_results(ctrl: &[LdapControl]) -> Vec<LdapControl> {
+    ctrl.iter()
+        .filter(|c| !matches!(c, LdapControl::SimplePagedResults { .. }))
+        .cloned()
+        .collect()
+}
+
+// A paged search that the client can ask for the next page of.
+struct PagedSearch {
+    // The search without its paging, which the pages must all be for.
+    key: SearchCacheKey,
+    started: Instant,
+    state: PagedState,
+}
+
+enum PagedState {
+    // The backend holds the state of the search, on the session's connection.
+    // The pages so far are collected, so that the whole result set can be
+    // cached once the last page arrives. They are dropped if the results
+    // can't be cached.
+    Backend {
+        collected: Option<CachedValue>,
+        // The entries of the pages so far, which count towards the DN's
+        // entry limits.
+        sent: usize,
+    },
+    // The whole result set was in the cache, and the proxy serves the pages.
+    Cached {
+        results: SearchResults,
+        offset: usize,
+    },
+}
+
+// The authzId of a WhoAmI response, which is empty for anonymous sessions.
+fn whoami_authzid(dn: &str) -> Vec<u8> {
+    if dn.is_empty() {
+        Vec::new()
+    } else {
+        format!("dn:{}", dn).into_bytes()
+    }
+}
+
+fn extended_error(code: LdapResultCode, message: String) -> LdapOp {
+    LdapOp::ExtendedResponse(LdapExtendedResponse {
+        res: LdapResult {
+            code,
+            matcheddn: "".to_string(),
+            message,
+            referral: vec![],
+        },
+        name: None,
+        value: None,
+    })
+}
+
+// The response to a request that is refused before it reaches the backend. None
+// if the request has no response.
+fn refusal(msgid: i32, op: &LdapOp, code: LdapResultCode, message: &str) -> Option<LdapMsg> {
+    let res = LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message: message.to_string(),
+        referral: vec![],
+    };
+    let op = match op {
+        LdapOp::BindRequest(_) => LdapOp::BindResponse(LdapBindResponse {
+            res,
+            saslcreds: None,
+        }),
+        LdapOp::SearchRequest(_) => LdapOp::SearchResultDone(res),
+        LdapOp::ModifyRequest(_) => LdapOp::ModifyResponse(res),
+        LdapOp::AddRequest(_) => LdapOp::AddResponse(res),
+        LdapOp::DelRequest(_) => LdapOp::DelResponse(res),
+        LdapOp::ModifyDNRequest(_) => LdapOp::ModifyDNResponse(res),
+        LdapOp::CompareRequest(_) => LdapOp::CompareResult(res),
+        LdapOp::ExtendedRequest(_) => LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res,
+            name: None,
+            value: None,
+        }),
+        _ => return None,
+    };
+    Some(LdapMsg {
+        msgid,
+        op,
+        ctrl: vec![],
+    })
+}
+
+/// An unsolicited notice, telling the client that the server is about to close
+/// the connection (RFC 4511 4.4.1).
+pub fn notice_of_disconnection(code: LdapResultCode, message: &str) -> LdapMsg {
+    LdapMsg {
+        msgid: 0,
+        op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResult {
+                code,
+                matcheddn: "".to_string(),
+                message: message.to_string(),
+                referral: vec![],
+            },
+            name: Some(OID_NOTICE_OF_DISCONNECTION.to_string()),
+            value: None,
+        }),
+        ctrl: vec![],
+    }
+}
+
+// The result when the backend can't be reached, even after reconnecting.
+fn unavailable() -> LdapResult {
+    LdapResult {
+        code: LdapResultCode::Unavailable,
+        matcheddn: "".to_string(),
+        message: "backend unavailable".to_string(),
+        referral: vec![],
+    }
+}
+
+// The identity of a SASL PLAIN bind (RFC 4616), whose credentials are authzid
+// NUL authcid NUL password. The authcid is a DN, optionally prefixed with "dn:".
+// An authzid that asks to act as anyone else is refused, as the bind maps
+// couldn't apply to the session.
+fn sasl_plain_authcid(credentials: &[u8]) -> Option<String> {
+    let mut parts = credentials.split(|b| *b == 0);
+    let authzid = std::str::from_utf8(parts.next()?).ok()?;
+    let authcid = std::str::from_utf8(parts.next()?).ok()?;
+    parts.next()?;
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let authcid = authcid.strip_prefix("dn:").unwrap_or(authcid);
+    if authcid.is_empty() {
+        return None;
+    }
+    if !authzid.is_empty() {
+        let authzid = authzid.strip_prefix("dn:")?;
+        if normalize_dn(authzid).ok()? != normalize_dn(authcid).ok()? {
+            return None;
+        }
+    }
+    Some(authcid.to_string())
+}
+
+fn bind_operror(msgid: i32, msg: &str) -> LdapMsg {
+    bind_result(msgid, LdapResultCode::OperationsError, msg)
+}
+
+fn bind_result(msgid: i32, code: LdapResultCode, msg: &str) -> LdapMsg {
+    LdapMsg {
+        msgid,
+        op: LdapOp::BindResponse(LdapBindResponse {
+            res: LdapResult {
+                code,
+                matcheddn: "".to_string(),
+                message: msg.to_string(),
+                referral: vec![],
+            },
+            saslcreds: None,
+        }),
+        ctrl: vec![],
+    }
+}
+
+// Count towards a metric of a bind DN, if metrics are kept by DN.
+fn incr_dn(app_state: &AppState, name: &str, dn: &str, value: u64) {
+    if app_state.per_dn_metrics {
+        app_state.metrics.incr_by(name, &[("dn", dn)], value);
+    }
+}
+
+// Record a failed bind, and report the DN or address if it has failed too often.
+fn record_bind_failure(app_state: &AppState, dn: &str, client_address: SocketAddr) {
+    app_state.metrics.incr("bind_failures_total", &[]);
+    let crossed = app_state
+        .bind_failures
+        .record_failure(dn, client_address.ip());
+    if crossed.ip {
+        let locked = app_state.bind_failures.locks_ip();
+        app_state
+            .metrics
+            .incr("bind_failure_threshold_total", &[("key", "ip")]);
+        if locked {
+            app_state
+                .metrics
+                .incr("bind_lockouts_total", &[("key", "ip")]);
+        }
+        warn!(
+            event = "bind_failure_threshold",
+            key = "ip",
+            %client_address,
+            bind_dn = %dn,
+            locked,
+            "Too many failed binds from this address"
+        );
+    }
+    if crossed.dn {
+        let locked = app_state.bind_failures.locks_dn();
+        app_state
+            .metrics
+            .incr("bind_failure_threshold_total", &[("key", "dn")]);
+        if locked {
+            app_state
+                .metrics
+                .incr("bind_lockouts_total", &[("key", "dn")]);
+        }
+        warn!(
+            event = "bind_failure_threshold",
+            key = "dn",
+            %client_address,
+            bind_dn = %dn,
+            locked,
+            "Too many failed binds for this dn"
+        );
+    }
+    // When the address itself is locked, the pair adds nothing to report.
+    if crossed.pair && !(crossed.ip && app_state.bind_failures.locks_ip()) {
+        let locked = app_state.bind_failures.locks_pair();
+        app_state
+            .metrics
+            .incr("bind_failure_threshold_total", &[("key", "ip_dn")]);
+        if locked {
+            app_state
+                .metrics
+                .incr("bind_lockouts_total", &[("key", "ip_dn")]);
+        }
+        warn!(
+            event = "bind_failure_threshold",
+            key = "ip_dn",
+            %client_address,
+            bind_dn = %dn,
+            locked,
+            "Too many failed binds for this dn from this address"
+        );
+    }
+}
+
+// Could a write to the entry `dn` change the results of this search? That is if
+// the entry is in the scope of the search, or the base is at or below the entry
+// (which a modify dn moves). Both DNs are normalised.
+fn write_affects_search(dn: &str, base: &str, scope: &LdapSearchScope) -> bool {
+    let in_scope = match scope {
+        LdapSearchScope::Base => dn == base,
+        // One level searches are treated as subtree searches, which at worst
+        // flushes a few more entries.
+        LdapSearchScope::OneLevel | LdapSearchScope::Children => {
+            dn != base && dn_is_within(dn, base)
+        }
+        LdapSearchScope::Subtree => dn_is_within(dn, base),
+    };
+    in_scope || dn_is_within(base, dn)
+}
+
+// Remove the cached searches of every bind dn that a write to these entries may
+// have changed.
+fn cache_invalidate_written(app_state: &AppState, written: &[String]) {
+    // The password of a written entry may have changed.
+    if let Some(bind_cache) = app_state.bind_cache.as_ref() {
+        for dn in written {
+            bind_cache.remove(dn);
+        }
+    }
+    let stale_keys: HashSet<_> = written
+        .iter()
+        .flat_map(|dn| {
+            app_state
+                .cache_index
+                .candidates(dn)
+                .into_iter()
+                .filter(move |k| {
+                    let base = normalize_dn(&k.search.base)
+                        .unwrap_or_else(|_| k.search.base.to_lowercase());
+                    write_affects_search(dn, &base, &k.search.scope)
+                })
+        })
+        .collect();
+    let mut cache_write_txn = app_state.cache.write();
+    debug!(
+        "Invalidating {} cached searches for writes to {:?}",
+        stale_keys.len(),
+        written
+    );
+    for k in stale_keys {
+        app_state.cache_index.remove(&k);
+        cache_write_txn.remove(k);
+    }
+    cache_write_txn.commit();
+}
+
+// Expired entries are removed this many at a time, so that other writers to the
+// cache are never held up for long.
+const CACHE_SWEEP_BATCH: usize = 1024;
+
+/// Remove the expired entries of the cache. Returns the entries and bytes that
+/// were reclaimed.
+pub async fn sweep_expired_cache(app_state: &AppState) -> (usize, usize) {
+    let now = Instant::now();
+    // Expired entries may still be answered with for a while.
+    let max_stale = policy_cache_max_stale(app_state).unwrap_or_default();
+    // The live and expired keys are collected in one pass. Nothing is changed
+    // meanwhile, but this is committed so that searches which were added
+    // before now are in the cache, and the index can be pruned of those that
+    // aren't.
+    let (cached_entries, expired) = {
+        let cache_write_txn = app_state.cache.write();
+        let mut live = HashSet::new();
+        let mut expired = Vec::new();
+        for (k, v) in cache_write_txn.iter() {
+            live.insert(k);
+            if v.valid_until + max_stale <= now {
+                expired.push(k.clone());
+            }
+        }
+        app_state.cache_index.prune(&live, now);
+        let cached_entries = live.len();
+        drop(live);
+        cache_write_txn.commit();
+        (cached_entries, expired)
+    };
+
+    // The index knows the size of each search, so the cache is never walked
+    // to add them up.
+    let entries = expired.len();
+    let mut bytes = 0;
+    for batch in expired.chunks(CACHE_SWEEP_BATCH) {
+        {
+            let mut cache_write_txn = app_state.cache.write();
+            for k in batch {
+                bytes += app_state.cache_index.remove(k).unwrap_or_default();
+                cache_write_txn.remove(k.clone());
+            }
+            cache_write_txn.commit();
+        }
+        tokio::task::yield_now().await;
+    }
+
+    // What is left in the cache once the expired entries are gone.
+    app_state.metrics.set(
+        "cache_entries",
+        &[],
+        cached_entries.saturating_sub(entries) as u64,
+    );
+    app_state
+        .metrics
+        .set("cache_bytes", &[], app_state.cache_index.bytes() as u64);
+    if entries > 0 {
+        app_state
+            .metrics
+            .incr_by("cache_swept_entries_total", &[], entries as u64);
+        app_state
+            .metrics
+            .incr_by("cache_swept_bytes_total", &[], bytes as u64);
+    }
+    debug!("Swept {} expired cache entries, {} bytes", entries, bytes);
+    (entries, bytes)
+}
+
+// Send a response to the client. If this fails the session is already gone.
+async fn respond(tx: &Responder, msg: impl Into<ClientResponse>) -> bool {
+    if tx.send(SessionEvent::Response(msg.into())).await.is_err() {
+        error!("Unable to send response");
+        false
+    } else {
+        true
+    }
+}
+
+// Send a final response to the client, and then end the session.
+async fn respond_and_disconnect(tx: &Responder, msg: LdapMsg) {
+    if respond(tx, msg).await {
+        let _ = tx.send(SessionEvent::Disconnect).await;
+    }
+}
+
+// Writes responses to the client, and records the operations that they
+// complete in the audit log, and the responses of tapped DNs in the tap log.
+struct ClientWriter<W> {
+    inner: FramedWrite<W, ClientCodec>,
+    audit: Option<SessionAudit>,
+    tap: Option<SessionTap>,
+}
+
+impl<W: AsyncWrite + Unpin, T: Into<ClientResponse>> Sink<T> for ClientWriter<W> {
+    type Error = std::io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<ClientResponse>::poll_ready(Pin::new(&mut self.get_mut().inner), cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: T) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let response = msg.into();
+        if let Some(audit) = this.audit.as_mut() {
+            audit.response(&response.msg);
+        }
+        if let Some(tap) = this.tap.as_mut() {
+            tap.response(&response.msg);
+        }
+        Pin::new(&mut this.inner).start_send(response)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<ClientResponse>::poll_flush(Pin::new(&mut self.get_mut().inner), cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<ClientResponse>::poll_close(Pin::new(&mut self.get_mut().inner), cx)
+    }
+}
+
+// Write a response to the client. Returns false if the session should end.
+async fn client_write<W: AsyncWrite + Unpin>(
+    w: &mut ClientWriter<W>,
+    response_controls: &ControlPolicy,
+    event: SessionEvent,
+) -> bool {
+    match event {
+        SessionEvent::Response(mut response) => {
+            response_controls.filter_response(&mut response.msg.ctrl);
+            response
+                .raw_controls
+                .retain(|c| response_controls.permits(&c.oid));
+            if !response_controls.permits(OID_VLV_RESPONSE) {
+                response.vlv = None;
+            }
+            if w.send(response).await.is_err() {
+                error!("Unable to send response");
+                false
+            } else {
+                true
+            }
+        }
+        SessionEvent::CacheHit(msgid) => {
+            if let Some(audit) = w.audit.as_mut() {
+                audit.cache_hit(msgid);
+            }
+            true
+        }
+        SessionEvent::Disconnect => false,
+    }
+}
+
+// Abandon the persistent searches of a session, which never complete by
+// themselves.
+fn end_streams(searches: &mut HashMap<i32, AbortHandle>, streams: &mut HashSet<i32>) {
+    for msgid in streams.drain() {
+        if let Some(search) = searches.remove(&msgid) {
+            debug!(%msgid, "Abandoning persistent search");
+            search.abort();
+        }
+    }
+}
+
+// Wait for all in flight operations to complete, relaying their responses to
+// the client. Returns false if the session should end.
+async fn complete_operations<W: AsyncWrite + Unpin>(
+    ops: &mut JoinSet<()>,
+    rx: &mut mpsc::Receiver<SessionEvent>,
+    w: &mut ClientWriter<W>,
+    response_controls: &ControlPolicy,
+) -> bool {
+    while !ops.is_empty() {
+        tokio::select! {
+            _ = ops.join_next() => {}
+            Some(event) = rx.recv() => {
+                if !client_write(w, response_controls, event).await {
+                    return false;
+                }
+            }
+        }
+    }
+    // Flush anything that is still queued.
+    while let Ok(event) = rx.try_recv() {
+        if !client_write(w, response_controls, event).await {
+            return false;
+        }
+    }
+    true
+}
+
+/// Serve a plain ldap connection until the client upgrades it with StartTLS
+/// (RFC 4511 4.14). Until then every other request is refused with
+/// confidentialityRequired, so that nothing is sent in the clear. Returns the
+/// connection once the client has been told to begin the tls handshake, or None
+/// if the client went away.
+pub async fn client_starttls<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    client_address: SocketAddr,
+    app_state: &AppState,
+) -> Option<S> {
+    let (r, w) = tokio::io::split(stream);
+    let mut r = FramedRead::new(r, ClientCodec::new(app_state.max_incoming_ber_size));
+    let mut w = FramedWrite::new(w, ClientCodec::new(app_state.max_incoming_ber_size));
+    let idle_timeout = app_state
+        .idle_timeout
+        .unwrap_or(Duration::from_secs(86400 * 365));
+
+    loop {
+        let msg = match tokio::time::timeout(idle_timeout, r.next()).await {
+            Ok(Some(Ok(ClientRequest { msg, .. }))) => msg,
+            Ok(Some(Err(e))) => {
+                warn!(?e, %client_address, "Invalid request before StartTLS");
+                return None;
+            }
+            Ok(None) => return None,
+            Err(_) => {
+                debug!(%client_address, "Idle timeout before StartTLS");
+                return None;
+            }
+        };
+
+        match msg.op {
+            LdapOp::ExtendedRequest(ler) if ler.name == OID_STARTTLS => {
+                // Nothing may be sent after the request until the handshake is
+                // done (RFC 4511 4.14.1).
+                if !r.read_buffer().is_empty() {
+                    warn!(%client_address, "Request sent before the StartTLS response");
+                    return None;
+                }
+                let reply = LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::ExtendedResponse(LdapExtendedResponse {
                         res: LdapResult {
-                            code: LdapResultCode::OperationsError,
+                            code: LdapResultCode::Success,
                             matcheddn: "".to_string(),
                             message: "".to_string(),
                             referral: vec![],
                         },
-                        name: None,
+                        name: Some(OID_STARTTLS.to_string()),
                         value: None,
                     }),
+                    ctrl: vec![],
                 };
+                w.send(reply).await.ok()?;
+                app_state.metrics.incr("client_starttls_total", &[]);
+                return Some(r.into_inner().unsplit(w.into_inner()));
+            }
+            LdapOp::UnbindRequest => return None,
+            op => {
+                app_state
+                    .metrics
+                    .incr("client_starttls_required_total", &[]);
+                let reply = refusal(
+                    msg.msgid,
+                    &op,
+                    LdapResultCode::ConfidentialityRequired,
+                    "StartTLS is required",
+                );
+                if let Some(reply) = reply {
+                    w.send(reply).await.ok()?;
+                }
+            }
+        }
+    }
+}
 
-                if w.send(LdapMsg {
-                    msgid,
-                    op,
-                    ctrl: vec![],
-                })
-                .await
-                .is_err()
-                {
+pub async fn client_process<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
+    r: FramedRead<R, ClientCodec>,
+    w: FramedWrite<W, ClientCodec>,
+    client_address: SocketAddr,
+    client_cert: Option<ClientCertificate>,
+    app_state: Arc<AppState>,
+) {
+    let registration = app_state.sessions.register(client_address);
+    // Every event of the session, and of its operations against the backend,
+    // is in its span, so that it can be followed through interleaved logs.
+    let span = span!(
+        Level::INFO,
+        "session",
+        id = registration.id(),
+        bind_dn = field::Empty
+    );
+    client_session(
+        r,
+        w,
+        client_address,
+        client_cert,
+        app_state,
+        registration,
+        span.clone(),
+    )
+    .instrument(span)
+    .await
+}
+
+async fn client_session<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
+    mut r: FramedRead<R, ClientCodec>,
+    w: FramedWrite<W, ClientCodec>,
+    client_address: SocketAddr,
+    client_cert: Option<ClientCertificate>,
+    app_state: Arc<AppState>,
+    registration: SessionHandle,
+    span: Span,
+) {
+    info!("Accept from {}", client_address);
+
+    let mut w = ClientWriter {
+        inner: w,
+        audit: app_state
+            .audit
+            .clone()
+            .map(|log| SessionAudit::new(log, client_address)),
+        tap: app_state
+            .tap
+            .clone()
+            .map(|log| SessionTap::new(log, client_address)),
+    };
+
+    // The cert_map entry of the client certificate, if one was presented.
+    let cert_entry = client_cert
+        .as_ref()
+        .and_then(|cert| cert.lookup(&app_state.cert_map));
+    if let Some(cert) = client_cert.as_ref() {
+        info!(subject = %cert.subject, mapped = cert_entry.is_some(), "Client certificate presented");
+    }
+
+    // We always start unbound.
+    let mut state = ClientState::Unbound;
+
+    // Operations of an authenticated session run concurrently, so that a client
+    // may have many requests in flight. Their responses are written to the
+    // client from here, in the order that they are produced.
+    let mut ops = JoinSet::new();
+    let (tx, mut rx) = mpsc::channel(SESSION_QUEUE_DEPTH);
+    // The searches in flight, by msgid, so that the client can abandon them.
+    let mut searches: HashMap<i32, AbortHandle> = HashMap::new();
+    // The searches in flight that only end when they are abandoned.
+    let mut streams: HashSet<i32> = HashSet::new();
+
+    // Binds in a row that have been refused by the rate limiter.
+    let mut limited_binds = 0;
+
+    // The response controls that may be relayed to the client. This changes with
+    // each successful bind.
+    let mut policy = app_state.policy.load_full();
+    let mut response_controls = policy.response_controls.clone();
+
+    // Reset whenever there is traffic in either direction. Without a timeout
+    // this never fires, it just needs to be something that can be added to now.
+    let idle_timeout = app_state
+        .idle_timeout
+        .unwrap_or(Duration::from_secs(86400 * 365));
+    let idle = tokio::time::sleep(idle_timeout);
+    tokio::pin!(idle);
+
+    // Start to wait for incoming packets
+    loop {
+        let protomsg = tokio::select! {
+            maybe_msg = r.next() => {
+                match maybe_msg {
+                    Some(Ok(request)) => request,
+                    Some(Err(e)) => {
+                        warn!(?e, "Unable to decode request from {}", client_address);
+                        let _ = w
+                            .send(notice_of_disconnection(
+                                LdapResultCode::ProtocolError,
+                                &e.to_string(),
+                            ))
+                            .await;
+                        break;
+                    }
+                    None => break,
+                }
+            }
+            Some(event) = rx.recv() => {
+                idle.as_mut().reset(tokio::time::Instant::now() + idle_timeout);
+                if !client_write(&mut w, &response_controls, event).await {
+                    break;
+                }
+                continue;
+            }
+            // Reap completed operations.
+            Some(_) = ops.join_next(), if !ops.is_empty() => {
+                searches.retain(|_, search| !search.is_finished());
+                continue;
+            }
+            _ = registration.disconnected() => {
+                let message = if app_state.sessions.is_draining() {
+                    info!("Shutting down, closing the session of {}", client_address);
+                    // The operations in flight are allowed to finish first,
+                    // other than persistent searches, which never do.
+                    end_streams(&mut searches, &mut streams);
+                    if !complete_operations(&mut ops, &mut rx, &mut w, &response_controls).await {
+                        break;
+                    }
+                    "the server is shutting down"
+                } else {
+                    info!("Disconnecting {} by request", client_address);
+                    "disconnected by an administrator"
+                };
+                let _ = w
+                    .send(notice_of_disconnection(LdapResultCode::Unavailable, message))
+                    .await;
+                break;
+            }
+            _ = &mut idle, if app_state.idle_timeout.is_some() => {
+                if !ops.is_empty() {
+                    // Operations that are still running are not idle.
+                    idle.as_mut().reset(tokio::time::Instant::now() + idle_timeout);
+                    continue;
+                }
+                info!("Idle timeout for {}", client_address);
+                app_state.metrics.incr("sessions_idle_closed_total", &[]);
+                let _ = w
+                    .send(notice_of_disconnection(
+                        LdapResultCode::Unavailable,
+                        "idle timeout",
+                    ))
+                    .await;
+                break;
+            }
+        };
+
+        idle.as_mut()
+            .reset(tokio::time::Instant::now() + idle_timeout);
+
+        let ClientRequest {
+            msg: mut protomsg,
+            mut unsupported_critical_controls,
+            removed_controls,
+            server_sort,
+            vlv,
+            raw_controls,
+        } = protomsg;
+
+        // Pick up a reloaded config. A session whose DN may no longer bind from
+        // this client is ended.
+        if !Arc::ptr_eq(&policy, &app_state.policy.load()) {
+            policy = app_state.policy.load_full();
+            response_controls = policy.response_controls.clone();
+            if let ClientState::Authenticated(session) = &state {
+                let Some(config) = reloaded_config(&policy, &session.dn, client_address) else {
+                    info!(
+                        "Bind for {} is no longer permitted, disconnecting",
+                        session.dn
+                    );
+                    let _ = w
+                        .send(notice_of_disconnection(
+                            LdapResultCode::InsufficentAccessRights,
+                            "the bind is no longer permitted",
+                        ))
+                        .await;
+                    break;
+                };
+                let (request_controls, session_response_controls) =
+                    app_state.control_policies(&config);
+                session.set_policy(config, request_controls);
+                response_controls = session_response_controls;
+            }
+        }
+
+        if let Some(audit) = w.audit.as_mut() {
+            let bind_dn = match &state {
+                ClientState::Authenticated(session) => session.dn.as_str(),
+                ClientState::Unbound => "",
+            };
+            audit.request(&protomsg, bind_dn);
+        }
+        if let Some(tap) = w.tap.as_mut() {
+            // A bind is tapped if the DN that it binds as is.
+            let tapped = match (&protomsg.op, &state) {
+                (LdapOp::BindRequest(lbr), _) => normalize_dn(&lbr.dn)
+                    .ok()
+                    .filter(|dn| policy.dn_config(dn).is_some_and(|config| config.tap)),
+                (_, ClientState::Authenticated(session)) if session.policy().config.tap => {
+                    Some(session.dn.clone())
+                }
+                _ => None,
+            };
+            if let Some(bind_dn) = tapped {
+                tap.request(&protomsg, &bind_dn);
+            }
+        }
+        // The cache bypass control is handled by the proxy, so it is never sent
+        // to the backend.
+        let cache_bypass = match policy.cache_bypass_control.as_ref() {
+            Some(bypass) if matches!(protomsg.op, LdapOp::SearchRequest(_)) => {
+                unsupported_critical_controls.retain(|oid| oid != bypass);
+                removed_controls.contains(bypass)
+            }
+            _ => false,
+        };
+        // The administrative controls of a bound session's requests are
+        // handled by the policy of its DN, before the control lists.
+        let mut admin = AdminControls::default();
+        let handled = !matches!(
+            protomsg.op,
+            LdapOp::BindRequest(_) | LdapOp::UnbindRequest | LdapOp::AbandonRequest(_)
+        );
+        if let (ClientState::Authenticated(session), true) = (&state, handled) {
+            let write = matches!(
+                protomsg.op,
+                LdapOp::AddRequest(_)
+                    | LdapOp::ModifyRequest(_)
+                    | LdapOp::DelRequest(_)
+                    | LdapOp::ModifyDNRequest(_)
+            );
+            let config = &session.policy().config;
+            match admin_controls(
+                config.manage_dsa_it,
+                config.relax_rules,
+                write,
+                &mut protomsg.ctrl,
+                &mut unsupported_critical_controls,
+                &raw_controls,
+            ) {
+                Ok(handled) => admin = handled,
+                Err(oid) => {
+                    warn!(%oid, "Refusing request with an administrative control");
+                    app_state
+                        .metrics
+                        .incr("admin_controls_refused_total", &[("oid", oid)]);
+                    if let Some(resp_msg) = refusal(
+                        protomsg.msgid,
+                        &protomsg.op,
+                        LdapResultCode::UnavailableCriticalExtension,
+                        "administrative control is not permitted",
+                    ) {
+                        if w.send(resp_msg).await.is_err() {
+                            error!("Unable to send response");
+                            break;
+                        }
+                    }
+                    continue;
+                }
+            }
+        }
+        // The controls of searches that only end when they are abandoned are
+        // relayed as they were sent.
+        let notification = match protomsg.op {
+            LdapOp::SearchRequest(_) => raw_controls
+                .into_iter()
+                .find(|c| NOTIFICATION_CONTROLS.contains(&c.oid.as_str())),
+            _ => None,
+        };
+        if let Some(notification) = notification.as_ref() {
+            unsupported_critical_controls.retain(|oid| *oid != notification.oid);
+        }
+        // Only searches can be sorted (RFC 2891 1.1).
+        if server_sort.as_ref().is_some_and(|sort| sort.critical)
+            && !matches!(protomsg.op, LdapOp::SearchRequest(_))
+        {
+            unsupported_critical_controls.push(OID_SERVER_SORT.to_string());
+        }
+        if vlv.as_ref().is_some_and(|vlv| vlv.critical)
+            && !matches!(protomsg.op, LdapOp::SearchRequest(_))
+        {
+            unsupported_critical_controls.push(OID_VLV_REQUEST.to_string());
+        }
+        if let Some(oid) = unsupported_critical_controls.first() {
+            warn!(%oid, "Refusing request with an unsupported critical control");
+            if let Some(resp_msg) = refusal(
+                protomsg.msgid,
+                &protomsg.op,
+                LdapResultCode::UnavailableCriticalExtension,
+                "unsupported critical control",
+            ) {
+                if w.send(resp_msg).await.is_err() {
                     error!("Unable to send response");
                     break;
                 }
+            }
+            continue;
+        }
+
+        // A session that has as many operations in flight as it may is told to
+        // try again later, rather than have its requests queue up.
+        if let (Some(max), ClientState::Authenticated(_)) =
+            (app_state.max_session_operations, &state)
+        {
+            // Reap the operations that have completed, so that they aren't
+            // counted.
+            while let Some(Some(_)) = ops.join_next().now_or_never() {}
+            searches.retain(|_, search| !search.is_finished());
+            if ops.len() >= max && !matches!(protomsg.op, LdapOp::BindRequest(_)) {
+                if let Some(resp_msg) = refusal(
+                    protomsg.msgid,
+                    &protomsg.op,
+                    LdapResultCode::Busy,
+                    "too many operations in progress",
+                ) {
+                    debug!(
+                        max,
+                        "Refusing request, the session has too many operations in flight"
+                    );
+                    app_state
+                        .metrics
+                        .incr("session_operations_refused_total", &[]);
+                    if w.send(resp_msg).await.is_err() {
+                        error!("Unable to send response");
+                        break;
+                    }
+                    continue;
+                }
+            }
+        }
+
+        // The quotas of the bind DN are shared by all of its sessions. The
+        // permit is held by the operation until it completes.
+        let mut permit = None;
+        let counted = !matches!(
+            protomsg.op,
+            LdapOp::BindRequest(_) | LdapOp::UnbindRequest | LdapOp::AbandonRequest(_)
+        );
+        if let (ClientState::Authenticated(session), true) = (&state, counted) {
+            let search = match &protomsg.op {
+                LdapOp::SearchRequest(sr) => {
+                    !(app_state.root_dse.is_some() && is_root_dse_search(sr))
+                }
+                _ => false,
+            };
+            let config = &session.policy().config;
+            match app_state.dn_quotas.acquire(&session.dn, config, search) {
+                Ok(acquired) => permit = acquired,
+                Err(quota) => {
+                    if let Some(resp_msg) = refusal(
+                        protomsg.msgid,
+                        &protomsg.op,
+                        LdapResultCode::Busy,
+                        "the quota of the bind DN is exhausted",
+                    ) {
+                        debug!(dn = %session.dn, quota = quota.name(), "Refusing request over quota");
+                        app_state
+                            .metrics
+                            .incr("dn_quota_refused_total", &[("quota", quota.name())]);
+                        if w.send(resp_msg).await.is_err() {
+                            error!("Unable to send response");
+                            break;
+                        }
+                        continue;
+                    }
+                }
+            }
+        }
+
+        // Remove the controls that this session may not relay. Binds are
+        // checked against the policy of the DN that is binding.
+        let protomsg = match (&state, protomsg) {
+            (ClientState::Authenticated(session), LdapMsg { msgid, op, ctrl })
+                if !matches!(op, LdapOp::BindRequest(_)) =>
+            {
+                match session.policy().request_controls.filter_request(ctrl) {
+                    Ok(mut ctrl) => {
+                        ctrl.append(&mut admin.forwarded);
+                        LdapMsg { msgid, op, ctrl }
+                    }
+                    Err(oid) => {
+                        warn!(%oid, "Refusing request with a denied critical control");
+                        if let Some(resp_msg) = refusal(
+                            msgid,
+                            &op,
+                            LdapResultCode::UnavailableCriticalExtension,
+                            "critical control is not permitted",
+                        ) {
+                            if w.send(resp_msg).await.is_err() {
+                                error!("Unable to send response");
+                                break;
+                            }
+                        }
+                        continue;
+                    }
+                }
+            }
+            (_, msg) => msg,
+        };
+
+        let next_state = match (&mut state, protomsg) {
+            // Doesn't matter what state we are in, any bind will trigger this process.
+            (
+                _,
+                LdapMsg {
+                    msgid,
+                    op: LdapOp::BindRequest(lbr),
+                    ctrl,
+                },
+            ) => {
+                let span = span!(Level::INFO, "bind");
+                let _enter = span.enter();
+
+                // All outstanding operations must complete before a bind is
+                // processed, so persistent searches are abandoned.
+                end_streams(&mut searches, &mut streams);
+                if !complete_operations(&mut ops, &mut rx, &mut w, &response_controls).await {
+                    break;
+                }
+
+                if let Some(limiter) = app_state.bind_limiter.as_ref() {
+                    if !limiter.check(client_address.ip()) {
+                        limited_binds += 1;
+                        app_state.metrics.incr("bind_rate_limited_total", &[]);
+                        warn!(%client_address, "Bind rate limit exceeded");
+                        if limited_binds >= MAX_RATE_LIMITED_BINDS {
+                            // This client isn't backing off, so stop talking to it.
+                            break;
+                        }
+                        let resp_msg =
+                            bind_result(msgid, LdapResultCode::Busy, "too many bind attempts");
+                        if w.send(resp_msg).await.is_err() {
+                            error!("Unable to send response");
+                            break;
+                        }
+                        continue;
+                    }
+                    limited_binds = 0;
+                }
+
+                trace!(dn = %lbr.dn, "Bind request");
+                if cert_entry.is_none() && app_state.reject_unmapped_cert_binds {
+                    warn!(%client_address, "Bind refused, the client certificate is not mapped");
+                    let resp_msg = bind_result(msgid, LdapResultCode::InvalidCredentials, "");
+                    if w.send(resp_msg).await.is_err() {
+                        error!("Unable to send response");
+                        break;
+                    }
+                    continue;
+                }
+
+                let (lbr, dn, config) = match &lbr.cred {
+                    LdapBindCred::SASL(creds) if creds.mechanism == "EXTERNAL" => {
+                        // The client is authenticated by its certificate, so we
+                        // bind to the backend as the account it maps to.
+                        let Some(entry) = cert_entry.filter(|entry| {
+                            creds.credentials.is_empty()
+                                || creds.credentials == format!("dn:{}", entry.bind_dn).as_bytes()
+                        }) else {
+                            warn!(%client_address, "EXTERNAL bind without a mapped client certificate");
+                            let resp_msg =
+                                bind_result(msgid, LdapResultCode::InvalidCredentials, "");
+                            if w.send(resp_msg).await.is_err() {
+                                error!("Unable to send response");
+                                break;
+                            }
+                            continue;
+                        };
+                        (
+                            entry.backend_bind(),
+                            normalize_dn(&entry.bind_dn).unwrap_or_else(|_| entry.bind_dn.clone()),
+                            entry.config.clone(),
+                        )
+                    }
+                    _ => {
+                        // SASL PLAIN binds are checked against the bind maps by
+                        // their authcid, and are forwarded as they are.
+                        let name = match &lbr.cred {
+                            LdapBindCred::SASL(creds) if creds.mechanism == "PLAIN" => {
+                                match sasl_plain_authcid(&creds.credentials) {
+                                    Some(authcid) => authcid,
+                                    None => {
+                                        warn!(%client_address, "Invalid SASL PLAIN credentials");
+                                        let resp_msg = bind_result(
+                                            msgid,
+                                            LdapResultCode::InvalidCredentials,
+                                            "",
+                                        );
+                                        if w.send(resp_msg).await.is_err() {
+                                            error!("Unable to send response");
+                                            break;
+                                        }
+                                        continue;
+                                    }
+                                }
+                            }
+                            _ => lbr.dn.clone(),
+                        };
+                        let dn = match normalize_dn(&name) {
+                            Ok(dn) => dn,
+                            Err(e) => {
+                                warn!(%e, "Invalid bind dn {}", name);
+                                let resp_msg = bind_result(
+                                    msgid,
+                                    LdapResultCode::InvalidDNSyntax,
+                                    &e.to_string(),
+                                );
+                                if w.send(resp_msg).await.is_err() {
+                                    error!("Unable to send response");
+                                    break;
+                                }
+                                continue;
+                            }
+                        };
+                        if app_state.bind_failures.is_locked(&dn, client_address.ip()) {
+                            app_state.metrics.incr("bind_locked_out_total", &[]);
+                            warn!(%client_address, "Bind for {} refused, it is locked out", dn);
+                            let resp_msg =
+                                bind_result(msgid, LdapResultCode::InvalidCredentials, "");
+                            if w.send(resp_msg).await.is_err() {
+                                error!("Unable to send response");
+                                break;
+                            }
+                            continue;
+                        }
+                        // Is the requested bind dn valid per our map?
+                        match app_state.dn_config(&dn) {
+                            Some(config) => {
+                                // They have a config! They can proceed.
+                                (lbr, dn, config)
+                            }
+                            None => {
+                                if app_state.policy.load().allow_all_bind_dns {
+                                    // All bind dns are allow, return a default config.
+                                    (lbr, dn, DnConfig::default())
+                                } else {
+                                    // Bind dns are filtered, sad trombone time.
+                                    record_bind_failure(&app_state, &dn, client_address);
+                                    let resp_msg = bind_operror(msgid, "unable to bind");
+                                    if w.send(resp_msg).await.is_err() {
+                                        error!("Unable to send response");
+                                        break;
+                                    }
+                                    continue;
+                                }
+                            }
+                        }
+                    }
+                };
+
+                if !config.allowed_networks.is_empty()
+                    && !network_contains(&config.allowed_networks, client_address.ip())
+                {
+                    warn!(%client_address, "Bind for {} is not permitted from this network", dn);
+                    let resp_msg = bind_result(msgid, LdapResultCode::InsufficentAccessRights, "");
+                    if w.send(resp_msg).await.is_err() {
+                        error!("Unable to send response");
+                        break;
+                    }
+                    continue;
+                }
+
+                let access = BindAccess {
+                    dn: &dn,
+                    client_address,
+                    config: &config,
+                };
+                if let Decision::Deny(message) = app_state.access.bind(access).await {
+                    warn!(%client_address, %message, "Bind for {} is denied by the access policy", dn);
+                    app_state
+                        .metrics
+                        .incr("access_policy_denied_total", &[("operation", "bind")]);
+                    let resp_msg =
+                        bind_result(msgid, LdapResultCode::InsufficentAccessRights, &message);
+                    if w.send(resp_msg).await.is_err() {
+                        error!("Unable to send response");
+                        break;
+                    }
+                    continue;
+                }
+
+                // Anonymous binds may be made as a service account instead.
+                let lbr = match app_state.anonymous_bind.as_ref() {
+                    Some(service)
+                        if dn.is_empty() && lbr.cred == LdapBindCred::Simple(String::new()) =>
+                    {
+                        debug!(service_dn = %service.dn, "Binding anonymously as the service account");
+                        service.clone()
+                    }
+                    _ => lbr,
+                };
+
+                let (request_controls, bind_response_controls) =
+                    app_state.control_policies(&config);
+                let ctrl = match request_controls.filter_request(ctrl) {
+                    Ok(ctrl) => ctrl,
+                    Err(oid) => {
+                        warn!(%oid, "Refusing bind with a denied critical control");
+                        let resp_msg = bind_result(
+                            msgid,
+                            LdapResultCode::UnavailableCriticalExtension,
+                            "critical control is not permitted",
+                        );
+                        if w.send(resp_msg).await.is_err() {
+                            error!("Unable to send response");
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                // Okay, we have a dnconfig, so they are allowed to proceed. Lets
+                // now setup the client for their session, and anything else we
+                // need to configure.
+
+                // We need the client to connect *and* bind to proceed here!
+                let Some(pool) = app_state.backend_pool(&dn, &config) else {
+                    error!(backend = ?config.backend, "No backend is configured for this dn");
+                    let resp_msg = bind_operror(msgid, "unable to bind");
+                    if w.send(resp_msg).await.is_err() {
+                        error!("Unable to send response");
+                    }
+                    break;
+                };
+
+                // Reuse an idle connection to the backend if there is one,
+                // preferring one that is still bound as this DN.
+                let pooled = app_state.upstream_pool.checkout(&pool.name, &dn);
+                let bound_as_dn = pooled.is_some();
+                let pooled =
+                    pooled.or_else(|| app_state.upstream_pool.checkout_neutral(&pool.name));
+                if pooled.is_some() {
+                    app_state.metrics.incr("upstream_pool_hits_total", &[]);
+                }
+                // A pooled connection that is still bound as this DN needn't be
+                // bound again if the same password bound recently.
+                let password = match &lbr.cred {
+                    LdapBindCred::Simple(password) if !password.is_empty() => Some(password),
+                    _ => None,
+                };
+                let bind_cached = bound_as_dn
+                    && ctrl.is_empty()
+                    && app_state.bind_cache.as_ref().is_some_and(|cache| {
+                        password.is_some_and(|password| cache.verify(&dn, password))
+                    });
+                let connected = match pooled {
+                    Some(client) => Ok(client),
+                    None => BasicLdapClient::connect(&app_state, pool).await,
+                };
+                let mut client = match connected {
+                    Ok(c) => c,
+                    Err(LdapError::Unavailable) => {
+                        // Fail fast, the client may try again later.
+                        warn!("All backends are unavailable");
+                        let resp_msg = LdapMsg {
+                            msgid,
+                            op: LdapOp::BindResponse(LdapBindResponse {
+                                res: unavailable(),
+                                saslcreds: None,
+                            }),
+                            ctrl: vec![],
+                        };
+                        if w.send(resp_msg).await.is_err() {
+                            error!("Unable to send response");
+                            break;
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        error!(?e, "A client build error has occurred.");
+                        let resp_msg = bind_operror(msgid, "unable to bind");
+                        if w.send(resp_msg).await.is_err() {
+                            error!("Unable to send response");
+                        }
+                        // Always bail.
+                        break;
+                    }
+                };
+
+                let bind = RetainedBind {
+                    lbr: lbr.clone(),
+                    ctrl: ctrl.clone(),
+                };
+
+                // A pooled connection may have been closed by the backend, and
+                // a new one dropped, so the bind is retried on a new
+                // connection.
+                let bound = if bind_cached {
+                    debug!("Bind for {} answered from the bind cache", dn);
+                    app_state.metrics.incr("bind_cache_hits_total", &[]);
+                    let bind_resp = LdapBindResponse {
+                        res: LdapResult {
+                            code: LdapResultCode::Success,
+                            matcheddn: "".to_string(),
+                            message: "".to_string(),
+                            referral: vec![],
+                        },
+                        saslcreds: None,
+                    };
+                    Ok((bind_resp, vec![], vec![]))
+                } else {
+                    let mut retry = 0;
+                    loop {
+                        match client.bind_raw(lbr.clone(), ctrl.clone()).await {
+                            Err(LdapError::Transport) if retry < app_state.retry.attempts => {
+                                tokio::time::sleep(app_state.retry.delay(retry)).await;
+                                retry += 1;
+                                match BasicLdapClient::connect(&app_state, pool).await {
+                                    Ok(c) => client = c,
+                                    Err(e) => break Err(e),
+                                }
+                            }
+                            res => break res,
+                        }
+                    }
+                };
+
+                let valid = match bound {
+                    Ok((mut bind_resp, mut ctrl, mut raw_controls)) => {
+                        // Almost there, lets check the bind result.
+                        let valid = bind_resp.res.code == LdapResultCode::Success;
+                        let failure = (app_state.ad_compat && !valid)
+                            .then(|| AdBindFailure::parse(&bind_resp.res.message))
+                            .flatten();
+                        if let Some(failure) = failure {
+                            info!(
+                                reason = failure.reason(),
+                                "Active Directory refused the bind for {}", dn
+                            );
+                            app_state
+                                .metrics
+                                .incr("ad_bind_failures_total", &[("reason", failure.reason())]);
+                            if let Some(audit) = w.audit.as_mut() {
+                                audit.reason(msgid, failure.reason());
+                            }
+                            bind_resp.res.message = failure.message().to_string();
+                        }
+                        let policy = raw_controls
+                            .iter()
+                            .filter(|c| c.oid == OID_PASSWORD_POLICY)
+                            .find_map(|c| c.value.as_deref().and_then(password_policy_response));
+                        if let Some(policy) = policy {
+                            password_policy_audit(&app_state, &dn, &policy);
+                            let reason = policy.reason().filter(|_| failure.is_none());
+                            if let (Some(reason), Some(audit)) = (reason, w.audit.as_mut()) {
+                                audit.reason(msgid, reason);
+                            }
+                        }
+                        incr_dn(&app_state, "dn_binds_total", &dn, 1);
+                        if !valid {
+                            incr_dn(&app_state, "dn_bind_failures_total", &dn, 1);
+                        }
+                        if bind_resp.res.code == LdapResultCode::InvalidCredentials {
+                            record_bind_failure(&app_state, &dn, client_address);
+                        }
+                        if let Some(cache) = app_state.bind_cache.as_ref() {
+                            match password {
+                                Some(password) if valid && !bind_cached => {
+                                    cache.insert(&dn, password)
+                                }
+                                _ if !valid => cache.remove(&dn),
+                                _ => {}
+                            }
+                        }
+
+                        bind_response_controls.filter_response(&mut ctrl);
+                        raw_controls.retain(|c| bind_response_controls.permits(&c.oid));
+                        let resp_msg = ClientResponse {
+                            msg: LdapMsg {
+                                msgid,
+                                op: LdapOp::BindResponse(bind_resp),
+                                ctrl,
+                            },
+                            vlv: None,
+                            raw_controls,
+                        };
+                        if w.send(resp_msg).await.is_err() {
+                            error!("Unable to send response");
+                            break;
+                        }
+                        valid
+                    }
+                    Err(e) => {
+                        error!(?e, "A client bind error has occurred");
+                        // The backend was lost, or didn't answer in time.
+                        let resp_msg = match e {
+                            LdapError::Transport => LdapMsg {
+                                msgid,
+                                op: LdapOp::BindResponse(LdapBindResponse {
+                                    res: unavailable(),
+                                    saslcreds: None,
+                                }),
+                                ctrl: vec![],
+                            },
+                            _ => bind_operror(msgid, "unable to bind"),
+                        };
+                        if w.send(resp_msg).await.is_err() {
+                            error!("Unable to send response");
+                        }
+                        // Always bail.
+                        break;
+                    }
+                };
+
+                if valid {
+                    info!(backend = %client.backend(), "Successful bind for {}", dn);
+                    app_state
+                        .bind_failures
+                        .record_success(&dn, client_address.ip());
+                    response_controls = bind_response_controls;
+                    // With a service bind, the connection that verified the
+                    // credentials is released, and the session uses the
+                    // connection shared by the DN's sessions.
+                    let service = match service_connection(&app_state, pool, &dn, &config, None)
+                        .await
+                    {
+                        Ok(service) => service,
+                        Err(e) => {
+                            warn!(?e, "Unable to connect as the service account of {}, using the session's own connection", dn);
+                            None
+                        }
+                    };
+                    let (client, bind, shared) = match service {
+                        Some((shared_client, service_lbr)) => {
+                            debug!(service_dn = %service_lbr.dn, "Searches of {} are made as the service account", dn);
+                            release_client(&app_state, &pool.name, dn.clone(), Arc::new(client))
+                                .await;
+                            let bind = RetainedBind {
+                                lbr: service_lbr,
+                                ctrl: vec![],
+                            };
+                            (shared_client, bind, true)
+                        }
+                        None => (Arc::new(client), bind, false),
+                    };
+                    Some(ClientState::Authenticated(Arc::new(Session {
+                        dn,
+                        pool: pool.name.clone(),
+                        policy: std::sync::RwLock::new(Arc::new(SessionPolicy {
+                            config,
+                            request_controls,
+                        })),
+                        client: std::sync::RwLock::new(client),
+                        reconnect_lock: Mutex::new(()),
+                        bind: std::sync::Mutex::new(bind),
+                        paged_searches: Mutex::new(HashMap::new()),
+                        routed: Mutex::new(HashMap::new()),
+                        shared,
+                    })))
+                } else {
+                    None
+                }
+            }
+            // Unbinds are always actioned.
+            (
+                _,
+                LdapMsg {
+                    msgid: _,
+                    op: LdapOp::UnbindRequest,
+                    ctrl: _,
+                },
+            ) => {
+                trace!("unbind");
+                break;
+            }
+
+            // The root DSE is answered by the proxy, before or after a bind.
+            (
+                _,
+                LdapMsg {
+                    msgid,
+                    op: LdapOp::SearchRequest(sr),
+                    ctrl: _,
+                },
+            ) if app_state.root_dse.is_some() && is_root_dse_search(&sr) => {
+                if let Some(root_dse) = app_state.root_dse.as_ref() {
+                    app_state.metrics.incr("root_dse_searches_total", &[]);
+                    let [entry, done] = root_dse.responses(msgid, &sr);
+                    if w.send(entry).await.is_err() || w.send(done).await.is_err() {
+                        error!("Unable to send response");
+                        break;
+                    }
+                }
+                None
+            }
+            // Authenticated message handler.
+            //  - Search
+            (
+                ClientState::Authenticated(session),
+                LdapMsg {
+                    msgid,
+                    op: LdapOp::SearchRequest(sr),
+                    ctrl,
+                },
+            ) => {
+                if notification.is_some() || is_persistent(&ctrl) {
+                    streams.insert(msgid);
+                }
+                let search = ops.spawn(
+                    with_permit(
+                        permit.take(),
+                        search_operation(
+                            session.clone(),
+                            app_state.clone(),
+                            tx.clone(),
+                            msgid,
+                            sr,
+                            ctrl,
+                            cache_bypass,
+                            server_sort,
+                            vlv,
+                            notification,
+                        ),
+                    )
+                    .instrument(session.span("search", msgid, &app_state)),
+                );
+                searches.insert(msgid, search);
+                // No state change
+                None
+            }
+            // Abandons have no response (RFC 4511 4.11). Only searches are
+            // abandoned, as other operations are short, or have side effects
+            // that must be seen through.
+            (
+                _,
+                LdapMsg {
+                    msgid: _,
+                    op: LdapOp::AbandonRequest(abandoned),
+                    ctrl: _,
+                },
+            ) => {
+                streams.remove(&abandoned);
+                if let Some(search) = searches.remove(&abandoned) {
+                    debug!(msgid = %abandoned, "Abandoning search");
+                    app_state.metrics.incr("searches_abandoned_total", &[]);
+                    search.abort();
+                }
+                None
+            }
+            //  - Compare
+            (
+                ClientState::Authenticated(session),
+                LdapMsg {
+                    msgid,
+                    op: LdapOp::CompareRequest(lcr),
+                    ctrl,
+                },
+            ) => {
+                ops.spawn(
+                    with_permit(
+                        permit.take(),
+                        compare_operation(
+                            session.clone(),
+                            app_state.clone(),
+                            tx.clone(),
+                            msgid,
+                            lcr,
+                            ctrl,
+                        ),
+                    )
+                    .instrument(session.span("compare", msgid, &app_state)),
+                );
+                None
+            }
+            //  - Add / Modify / Delete / ModifyDN
+            (
+                ClientState::Authenticated(session),
+                LdapMsg {
+                    msgid,
+                    op:
+                        op @ (LdapOp::AddRequest(_)
+                        | LdapOp::ModifyRequest(_)
+                        | LdapOp::DelRequest(_)
+                        | LdapOp::ModifyDNRequest(_)),
+                    ctrl,
+                },
+            ) => {
+                ops.spawn(
+                    with_permit(
+                        permit.take(),
+                        write_operation(
+                            session.clone(),
+                            app_state.clone(),
+                            tx.clone(),
+                            msgid,
+                            op,
+                            ctrl,
+                            std::mem::take(&mut admin.forwarded_raw),
+                        ),
+                    )
+                    .instrument(session.span("write", msgid, &app_state)),
+                );
+                None
+            }
+            // Extended Requests - Generally has whoami.
+            (
+                ClientState::Authenticated(session),
+                LdapMsg {
+                    msgid,
+                    op: LdapOp::ExtendedRequest(ler),
+                    ctrl,
+                },
+            ) => {
+                ops.spawn(
+                    with_permit(
+                        permit.take(),
+                        extended_operation(
+                            session.clone(),
+                            app_state.clone(),
+                            tx.clone(),
+                            msgid,
+                            ler,
+                            ctrl,
+                        ),
+                    )
+                    .instrument(session.span("extended", msgid, &app_state)),
+                );
+                None
+            }
+            // An unbound client is anonymous.
+            (
+                ClientState::Unbound,
+                LdapMsg {
+                    msgid,
+                    op: LdapOp::ExtendedRequest(ler),
+                    ctrl: _,
+                },
+            ) if ler.name == OID_WHOAMI => {
+                let resp_msg = LdapMsg {
+                    msgid,
+                    op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                        res: LdapResult {
+                            code: LdapResultCode::Success,
+                            matcheddn: "".to_string(),
+                            message: "".to_string(),
+                            referral: vec![],
+                        },
+                        name: None,
+                        value: Some(whoami_authzid("")),
+                    }),
+                    ctrl: vec![],
+                };
+                if w.send(resp_msg).await.is_err() {
+                    error!("Unable to send response");
+                    break;
+                }
+                None
+            }
+            // The client is already on a tls session, as it is once bound.
+            (
+                ClientState::Unbound,
+                LdapMsg {
+                    msgid,
+                    op: LdapOp::ExtendedRequest(ler),
+                    ctrl: _,
+                },
+            ) if ler.name == OID_STARTTLS => {
+                warn!("StartTLS requested on an existing tls session");
+                let resp_msg = LdapMsg {
+                    msgid,
+                    op: extended_error(
+                        LdapResultCode::OperationsError,
+                        "tls is already established".to_string(),
+                    ),
+                    ctrl: vec![],
+                };
+                if w.send(resp_msg).await.is_err() {
+                    error!("Unable to send response");
+                    break;
+                }
+                None
+            }
+            // Every request of a bound session is handled above, so a request
+            // that gets here needs a bind first. Anything else isn't a request,
+            // and ends the session.
+            (_, msg) => {
+                // Only the msgid, as the request may hold passwords.
+                match refusal(
+                    msg.msgid,
+                    &msg.op,
+                    LdapResultCode::OperationsError,
+                    "a bind is required",
+                ) {
+                    Some(resp_msg) => {
+                        debug!(msgid = msg.msgid, "Refusing a request before a bind");
+                        if w.send(resp_msg).await.is_err() {
+                            error!("Unable to send response");
+                            break;
+                        }
+                        None
+                    }
+                    None => {
+                        warn!(
+                            msgid = msg.msgid,
+                            "Unexpected message from {}", client_address
+                        );
+                        let _ = w
+                            .send(notice_of_disconnection(
+                                LdapResultCode::ProtocolError,
+                                "unexpected message",
+                            ))
+                            .await;
+                        break;
+                    }
+                }
+            }
+        };
+
+        if let Some(next_state) = next_state {
+            // Update the client state, releasing any former session.
+            if let ClientState::Authenticated(session) = std::mem::replace(&mut state, next_state) {
+                release_session(&app_state, session).await;
+            }
+            let bind_dn = match &state {
+                ClientState::Authenticated(session) => Some(session.dn.clone()),
+                ClientState::Unbound => None,
+            };
+            span.record("bind_dn", bind_dn.as_deref().unwrap_or(""));
+            registration.set_bind_dn(bind_dn);
+        }
+    }
+    // Let the backend know we are done with its connection.
+    ops.shutdown().await;
+    if let ClientState::Authenticated(session) = state {
+        release_session(&app_state, session).await;
+    }
+    info!("Disconnect for {}", client_address);
+}
+
+// Hold the permit of an operation until it completes.
+async fn with_permit<T>(permit: Option<QuotaPermit>, op: impl Future<Output = T>) -> T {
+    let _permit = permit;
+    op.await
+}
+
+// The config of a session's DN from a reloaded policy, or None if the DN may no
+// longer be bound from this client.
+fn reloaded_config(policy: &Policy, dn: &str, client_address: SocketAddr) -> Option<DnConfig> {
+    if !policy.client_network_permitted(client_address.ip()) {
+        return None;
+    }
+    let config = policy
+        .dn_config(dn)
+        .or_else(|| policy.allow_all_bind_dns.then(DnConfig::default))?;
+    if !config.allowed_networks.is_empty()
+        && !network_contains(&config.allowed_networks, client_address.ip())
+    {
+        return None;
+    }
+    Some(config)
+}
+
+/// Read the root DSE of the default backend, without binding.
+pub async fn read_root_dse(app_state: &AppState) -> Result<LdapSearchResultEntry, LdapError> {
+    let sr = LdapSearchRequest {
+        base: "".to_string(),
+        scope: LdapSearchScope::Base,
+        aliases: LdapDerefAliases::Never,
+        sizelimit: 1,
+        timelimit: 0,
+        typesonly: false,
+        filter: LdapFilter::Present("objectClass".to_string()),
+        attrs: LEARNED_ATTRIBUTES.iter().map(|a| a.to_string()).collect(),
+    };
+    read_entry(app_state, sr).await
+}
+
+/// Read the subschema subentry from the default backend, without binding, for
+/// the proxy to answer searches for it with.
+pub async fn read_schema(app_state: &AppState) -> Result<(), LdapError> {
+    let Some(schema) = app_state.schema.as_ref() else {
+        return Ok(());
+    };
+    let entry = read_entry(app_state, schema.request()).await?;
+    schema.store(entry);
+    Ok(())
+}
+
+// Read one entry from the default backend, without binding.
+async fn read_entry(
+    app_state: &AppState,
+    sr: LdapSearchRequest,
+) -> Result<LdapSearchResultEntry, LdapError> {
+    let pool = app_state
+        .backend_pools
+        .get(DEFAULT_BACKEND)
+        .ok_or(LdapError::ConnectError)?;
+    let client = BasicLdapClient::connect(app_state, pool).await?;
+    let base = sr.base.clone();
+    let results = client
+        .search(sr, vec![], Some(1), Some(Duration::from_secs(5)))
+        .await;
+    client.unbind().await;
+
+    let mut results = results?;
+    if results.result.code != LdapResultCode::Success {
+        warn!(code = ?results.result.code, %base, "Backend refused the search");
+        return Err(LdapError::InvalidProtocolState);
+    }
+    results
+        .entries
+        .pop()
+        .map(|(entry, _)| entry)
+        .ok_or(LdapError::InvalidProtocolState)
+}
+
+/// Check each address of every backend with an anonymous root DSE search. The
+/// addresses that don't answer are taken out of rotation, and those that
+/// answer again are returned to it. Returns the number out of rotation.
+pub async fn check_backend_health(app_state: &AppState) -> usize {
+    for pool in app_state.backend_pools.values() {
+        for backend in pool.backends.iter() {
+            for addr in backend.addrs() {
+                let healthy = probe_backend(app_state, pool, backend, addr).await;
+                if healthy {
+                    if app_state.breakers.mark_healthy(&addr) {
+                        info!(pool = %pool.name, backend = %backend.url, %addr, "backend address passed its health check, returning it to rotation");
+                    }
+                } else if app_state.breakers.mark_unhealthy(&addr) {
+                    warn!(pool = %pool.name, backend = %backend.url, %addr, "backend address failed its health check, taking it out of rotation");
+                    app_state.metrics.incr(
+                        "backend_health_check_failures_total",
+                        &[("backend", backend.url.as_str())],
+                    );
+                }
+            }
+        }
+    }
+    let unhealthy = app_state.breakers.unhealthy_count();
+    app_state
+        .metrics
+        .set("backend_unhealthy_addresses", &[], unhealthy as u64);
+    unhealthy
+}
+
+// Any response to the search shows the backend is serving, even a refusal.
+async fn probe_backend(
+    app_state: &AppState,
+    pool: &BackendPool,
+    backend: &Backend,
+    addr: SocketAddr,
+) -> bool {
+    let client = match BasicLdapClient::build_to(
+        backend,
+        &[addr],
+        pool,
+        app_state.max_proxy_ber_size,
+        &app_state.breakers,
+    )
+    .await
+    {
+        Ok(client) => client,
+        Err(e) => {
+            debug!(?e, backend = %backend.url, %addr, "health check could not connect");
+            return false;
+        }
+    };
+    let sr = LdapSearchRequest {
+        base: "".to_string(),
+        scope: LdapSearchScope::Base,
+        aliases: LdapDerefAliases::Never,
+        sizelimit: 1,
+        timelimit: 0,
+        typesonly: false,
+        filter: LdapFilter::Present("objectClass".to_string()),
+        attrs: vec!["1.1".to_string()],
+    };
+    let results = client
+        .search(sr, vec![], Some(1), Some(Duration::from_secs(5)))
+        .await;
+    client.unbind().await;
+    match results {
+        Ok(results) => results.result.code != LdapResultCode::TimeLimitExceeded,
+        Err(e) => {
+            debug!(?e, backend = %backend.url, %addr, "health check search failed");
+            false
+        }
+    }
+}
+
+// Return the backend connection of a session that has ended to the pool, or
+// unbind it if it can't be reused.
+async fn release_session(app_state: &AppState, session: Arc<Session>) {
+    let client = session.client();
+    let (pool, dn, shared) = (session.pool.clone(), session.dn.clone(), session.shared);
+    let routed: Vec<_> = session.routed.lock().await.drain().collect();
+    drop(session);
+    for (_, routed_client) in routed {
+        routed_client.unbind().await;
+    }
+    // Shared connections stay open for the DN's other sessions.
+    if !shared {
+        release_client(app_state, &pool, dn, client).await;
+    }
+}
+
+// Return a backend connection that is bound as `dn` to the pool, or close it.
+async fn release_client(
+    app_state: &AppState,
+    pool: &str,
+    dn: String,
+    client: Arc<BasicLdapClient>,
+) {
+    // Operations that are still running have their own reference to it, and
+    // it can't be reused until they finish.
+    let client = match Arc::try_unwrap(client) {
+        Ok(client) => match neutral_rebind(app_state, client, dn).await {
+            Ok((client, dn)) => match app_state.upstream_pool.checkin(pool, &dn, client) {
+                None => {
+                    app_state.metrics.set(
+                        "upstream_pool_idle_connections",
+                        &[],
+                        app_state.upstream_pool.len() as u64,
+                    );
+                    return;
+                }
+                Some(client) => Arc::new(client),
+            },
+            Err(client) => Arc::new(client),
+        },
+        Err(client) => client,
+    };
+    client.unbind().await;
+}
+
+/// Check each idle pooled connection with a root DSE search, as connections
+/// that a firewall has silently dropped look open until they are used. Those
+/// that fail are closed, and those that were bound as the neutral DN are
+/// replaced with a new connection. Returns the number that were closed.
+pub async fn probe_upstream_pool(app_state: &AppState) -> usize {
+    let upstream_pool = &app_state.upstream_pool;
+    let mut closed = 0;
+    for (pool, dn, idle_since) in upstream_pool.idle_members() {
+        let Some(client) = upstream_pool.take(&pool, &dn, idle_since) else {
+            continue;
+        };
+        if probe_connection(&client).await {
+            if let Some(client) = upstream_pool.restore(&pool, &dn, client, idle_since) {
+                client.unbind().await;
+            }
+            continue;
+        }
+        warn!(%pool, backend = %client.backend(), "Closing a pooled connection that failed its probe");
+        app_state
+            .metrics
+            .incr("upstream_pool_probe_failures_total", &[]);
+        closed += 1;
+        drop(client);
+        // Connections bound as a session's DN can't be replaced, as the
+        // proxy doesn't keep the credentials once the session has ended.
+        if upstream_pool.neutral_bind().is_some_and(|lbr| lbr.dn == dn) {
+            replace_neutral_connection(app_state, &pool).await;
+        }
+    }
+    app_state.metrics.set(
+        "upstream_pool_idle_connections",
+        &[],
+        upstream_pool.len() as u64,
+    );
+    closed
+}
+
+// Any answer to the search shows the connection still works, even a refusal.
+async fn probe_connection(client: &BasicLdapClient) -> bool {
+    if !client.is_open() {
+        return false;
+    }
+    let sr = LdapSearchRequest {
+        base: "".to_string(),
+        scope: LdapSearchScope::Base,
+        aliases: LdapDerefAliases::Never,
+        sizelimit: 1,
+        timelimit: 0,
+        typesonly: false,
+        filter: LdapFilter::Present("objectClass".to_string()),
+        attrs: vec!["1.1".to_string()],
+    };
+    match client
+        .search(sr, vec![], Some(1), Some(Duration::from_secs(5)))
+        .await
+    {
+        Ok(results) => results.result.code != LdapResultCode::TimeLimitExceeded,
+        Err(e) => {
+            debug!(?e, backend = %client.backend(), "Pooled connection probe failed");
+            false
+        }
+    }
+}
+
+// Connect to a backend of the pool and pool the connection, bound as the
+// neutral DN.
+async fn replace_neutral_connection(app_state: &AppState, pool: &str) {
+    let (Some(backend_pool), Some(lbr)) = (
+        app_state.backend_pools.get(pool),
+        app_state.upstream_pool.neutral_bind(),
+    ) else {
+        return;
+    };
+    let client = match BasicLdapClient::connect(app_state, backend_pool).await {
+        Ok(client) => client,
+        Err(e) => {
+            debug!(?e, %pool, "Unable to connect to replace a pooled connection");
+            return;
+        }
+    };
+    match client.bind(lbr.clone(), vec![]).await {
+        Ok((resp, _)) if resp.res.code == LdapResultCode::Success => {
+            if let Some(client) = app_state.upstream_pool.checkin(pool, &lbr.dn, client) {
+                client.unbind().await;
+            }
+        }
+        Ok((resp, _)) => {
+            warn!(code = ?resp.res.code, neutral_dn = %lbr.dn, "Backend refused the neutral bind of a pooled connection");
+            client.unbind().await;
+        }
+        Err(e) => debug!(?e, "Unable to bind a pooled connection as the neutral dn"),
+    }
+}
+
+// Bind a connection that is about to be pooled as the neutral DN, if there is
+// one, returning the DN that it is bound as. It is given back if the bind
+// fails, so that it can be closed.
+async fn neutral_rebind(
+    app_state: &AppState,
+    client: BasicLdapClient,
+    dn: String,
+) -> Result<(BasicLdapClient, String), BasicLdapClient> {
+    let Some(lbr) = app_state.upstream_pool.neutral_bind() else {
+        return Ok((client, dn));
+    };
+    if !client.is_open() {
+        return Err(client);
+    }
+    match client.bind(lbr.clone(), vec![]).await {
+        Ok((resp, _)) if resp.res.code == LdapResultCode::Success => Ok((client, lbr.dn.clone())),
+        Ok((resp, _)) => {
+            warn!(code = ?resp.res.code, neutral_dn = %lbr.dn, "Backend refused the neutral bind of a pooled connection");
+            Err(client)
+        }
+        Err(e) => {
+            debug!(?e, "Unable to bind a pooled connection as the neutral dn");
+            Err(client)
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn search_operation(
+    session: Arc<Session>,
+    app_state: Arc<AppState>,
+    tx: Responder,
+    msgid: i32,
+    mut sr: LdapSearchRequest,
+    mut ctrl: Vec<LdapControl>,
+    cache_bypass: bool,
+    server_sort: Option<SortRequest>,
+    vlv: Option<VlvRequest>,
+    notification: Option<RawControl>,
+) {
+    let dn = &session.dn;
+    let policy = session.policy();
+    let config = &policy.config;
+    // In dry run mode the restrictions are checked, and what they would deny
+    // is logged, but the search goes ahead as if they passed.
+    let would_deny = |sr: &LdapSearchRequest, check: &str, reason: &str| {
+        let filter = redacted_filter_string(&sr.filter, app_state.log_filter_values);
+        warn!(base = %sr.base, %filter, "Dry run: {} for {}", reason, dn);
+        app_state
+            .metrics
+            .incr("searches_dry_run_denied_total", &[("check", check)]);
+    };
+
+    // Pre check if the search is allowed for this dn / scope / filter
+    if config.allowed_queries.is_empty() {
+        // All queries are allowed.
+        debug!("All queries are allowed");
+    } else {
+        // Let's check the query details.
+        let allow_key = (sr.base.clone(), sr.scope.clone(), sr.filter.clone());
+
+        if config.allowed_queries.contains(&allow_key) {
+            // Good to proceed.
+            debug!("Query is granted");
+        } else if config.dry_run {
+            would_deny(&sr, "query", "requested query is not allowed");
+        } else {
+            warn!(?allow_key, "Requested query is not allowed for {}", dn);
+            // If not, send an empty result.
+            respond_and_disconnect(
+                &tx,
+                LdapMsg {
+                    msgid,
+                    op: LdapOp::SearchResultDone(LdapResult {
+                        code: LdapResultCode::Success,
+                        matcheddn: "".to_string(),
+                        message: "".to_string(),
+                        referral: vec![],
+                    }),
+                    ctrl,
+                },
+            )
+            .await;
+            // Always bail.
+            return;
+        }
+    };
+
+    if !config.permits_search_base(&sr.base) && config.dry_run {
+        would_deny(&sr, "base", "search base is not allowed");
+    } else if !config.permits_search_base(&sr.base) {
+        warn!(base = %sr.base, "Search base is not allowed for {}", dn);
+        respond(
+            &tx,
+            LdapMsg {
+                msgid,
+                op: LdapOp::SearchResultDone(LdapResult {
+                    code: LdapResultCode::InsufficentAccessRights,
+                    matcheddn: "".to_string(),
+                    message: "search base is not permitted".to_string(),
+                    referral: vec![],
+                }),
+                ctrl: vec![],
+            },
+        )
+        .await;
+        return;
+    }
+
+    if let Some(limit) = app_state
+        .policy
+        .load()
+        .filter_limits
+        .exceeded_by(&sr.filter)
+    {
+        warn!(%limit, "Refusing search with a filter over the limit for {}", dn);
+        app_state.metrics.incr("searches_filter_limited_total", &[]);
+        respond(
+            &tx,
+            LdapMsg {
+                msgid,
+                op: LdapOp::SearchResultDone(LdapResult {
+                    code: LdapResultCode::AdminLimitExceeded,
+                    matcheddn: "".to_string(),
+                    message: format!("the filter exceeds {}", limit),
+                    referral: vec![],
+                }),
+                ctrl: vec![],
+            },
+        )
+        .await;
+        return;
+    }
+
+    if !config.permits_filter(&sr.filter) && config.dry_run {
+        would_deny(&sr, "filter", "search filter is not allowed");
+    } else if !config.permits_filter(&sr.filter) {
+        let filter = redacted_filter_string(&sr.filter, app_state.log_filter_values);
+        warn!(%filter, "Search filter is not allowed for {}", dn);
+        app_state.metrics.incr("searches_filter_denied_total", &[]);
+        respond(
+            &tx,
+            LdapMsg {
+                msgid,
+                op: LdapOp::SearchResultDone(LdapResult {
+                    code: LdapResultCode::Success,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec![],
+                }),
+                ctrl: vec![],
+            },
+        )
+        .await;
+        return;
+    }
+
+    let access = SearchAccess {
+        dn,
+        config,
+        request: &sr,
+    };
+    if let Decision::Deny(message) = app_state.access.search(access).await {
+        if config.dry_run {
+            would_deny(&sr, "policy", "the access policy denies the search");
+        } else {
+            warn!(base = %sr.base, %message, "Search is denied by the access policy for {}", dn);
+            app_state
+                .metrics
+                .incr("access_policy_denied_total", &[("operation", "search")]);
+            respond(
+                &tx,
+                LdapMsg {
+                    msgid,
+                    op: LdapOp::SearchResultDone(LdapResult {
+                        code: LdapResultCode::InsufficentAccessRights,
+                        matcheddn: "".to_string(),
+                        message,
+                        referral: vec![],
+                    }),
+                    ctrl: vec![],
+                },
+            )
+            .await;
+            return;
+        }
+    }
+
+    if let Some(hook) = app_state.search_hook.as_ref() {
+        if let Decision::Deny(message) = hook.request(dn, &mut sr).await {
+            warn!(base = %sr.base, %message, "Search is vetoed by the search hook for {}", dn);
+            app_state.metrics.incr("search_hook_vetoed_total", &[]);
+            respond(
+                &tx,
+                LdapMsg {
+                    msgid,
+                    op: LdapOp::SearchResultDone(LdapResult {
+                        code: LdapResultCode::UnwillingToPerform,
+                        matcheddn: "".to_string(),
+                        message,
+                        referral: vec![],
+                    }),
+                    ctrl: vec![],
+                },
+            )
+            .await;
+            return;
+        }
+    }
+
+    cap_search_limits(&mut sr, config);
+    let attrs = config.restrict_attrs(sr.attrs.clone());
+    if !config.dry_run {
+        sr.attrs = attrs;
+    } else if attrs != sr.attrs {
+        would_deny(&sr, "attrs", "requested attributes would be restricted");
+    }
+    rewrite_search(&config.attribute_rewrites, &mut sr);
+
+    let notification = match notification {
+        Some(control) if !policy.request_controls.permits(&control.oid) => {
+            if control.critical {
+                warn!("Refusing search with a denied critical control");
+                respond(
+                    &tx,
+                    LdapMsg {
+                        msgid,
+                        op: LdapOp::SearchResultDone(LdapResult {
+                            code: LdapResultCode::UnavailableCriticalExtension,
+                            matcheddn: "".to_string(),
+                            message: "critical control is not permitted".to_string(),
+                            referral: vec![],
+                        }),
+                        ctrl: vec![],
+                    },
+                )
+                .await;
+                return;
+            }
+            None
+        }
+        control => control,
+    };
+    // The results of change notification and content synchronisation
+    // searches are streamed to the client as they arrive, without being
+    // cached. Some of them only end when they are abandoned, and syncrepl
+    // interleaves intermediate responses with the entries.
+    if notification.is_some() || is_sync(&ctrl) {
+        streamed_search_operation(&session, &app_state, &tx, msgid, sr, ctrl, notification).await;
+        return;
+    }
+    let cache_bypass = cache_bypass || is_stateful(&ctrl);
+
+    // The sort control is forwarded to a backend that sorts. Otherwise the
+    // results are sorted here, which can't be done a page at a time. A sort
+    // that is denied by the control policy is treated like any other denied
+    // control.
+    let mut sort_response = None;
+    let mut local_sort = None;
+    let server_sort = match server_sort {
+        Some(sort) if !policy.request_controls.permits(OID_SERVER_SORT) => {
+            if sort.critical {
+                warn!("Refusing search with a denied critical control");
+                respond(
+                    &tx,
+                    LdapMsg {
+                        msgid,
+                        op: LdapOp::SearchResultDone(LdapResult {
+                            code: LdapResultCode::UnavailableCriticalExtension,
+                            matcheddn: "".to_string(),
+                            message: "critical control is not permitted".to_string(),
+                            referral: vec![],
+                        }),
+                        ctrl: vec![],
+                    },
+                )
+                .await;
+                return;
+            }
+            None
+        }
+        sort => sort,
+    };
+
+    // The virtual list view control is never forwarded. The window is taken
+    // from the whole sorted result set, which can't be paged.
+    let vlv = match vlv {
+        Some(vlv) if !policy.request_controls.permits(OID_VLV_REQUEST) => {
+            if vlv.critical {
+                warn!("Refusing search with a denied critical control");
+                respond(
+                    &tx,
+                    LdapMsg {
+                        msgid,
+                        op: LdapOp::SearchResultDone(LdapResult {
+                            code: LdapResultCode::UnavailableCriticalExtension,
+                            matcheddn: "".to_string(),
+                            message: "critical control is not permitted".to_string(),
+                            referral: vec![],
+                        }),
+                        ctrl: vec![],
+                    },
+                )
+                .await;
+                return;
+            }
+            None
+        }
+        vlv => vlv,
+    };
+    if vlv.is_some() {
+        let refused = if server_sort.is_none() {
+            Some((
+                SORT_CONTROL_MISSING,
+                "a virtual list view needs a sort control",
+            ))
+        } else if paged_results(&ctrl).is_some() {
+            Some((
+                LdapResultCode::UnwillingToPerform as i64,
+                "a virtual list view can't be paged",
+            ))
+        } else {
+            None
+        };
+        if let Some((result, message)) = refused {
+            warn!(%message, "Refusing virtual list view search for {}", dn);
+            respond(
+                &tx,
+                ClientResponse {
+                    msg: LdapMsg {
+                        msgid,
+                        op: LdapOp::SearchResultDone(LdapResult {
+                            code: LdapResultCode::UnwillingToPerform,
+                            matcheddn: "".to_string(),
+                            message: message.to_string(),
+                            referral: vec![],
+                        }),
+                        ctrl: vec![],
+                    },
+                    vlv: Some(VlvResponse::error(result)),
+                    raw_controls: Vec::new(),
+                },
+            )
+            .await;
+            return;
+        }
+    }
+    let window_sort = vlv.as_ref().and(server_sort.clone());
+    if let Some(sort) = server_sort {
+        let locally = !app_state.backend_sorts;
+        let unsortable = if locally && paged_results(&ctrl).is_some() {
+            Some((LdapResultCode::UnwillingToPerform, "paged"))
+        } else {
+            sort.unsortable(config, locally)
+        };
+        match unsortable {
+            // The client would rather have no results than unsorted ones
+            // (RFC 2891 1.1).
+            Some((code, key)) if sort.critical => {
+                warn!(?code, %key, "Refusing search that can't be sorted for {}", dn);
+                respond(
+                    &tx,
+                    LdapMsg {
+                        msgid,
+                        op: LdapOp::SearchResultDone(LdapResult {
+                            code: LdapResultCode::UnavailableCriticalExtension,
+                            matcheddn: "".to_string(),
+                            message: "the results can't be sorted".to_string(),
+                            referral: vec![],
+                        }),
+                        ctrl: vec![sort_result(code)],
+                    },
+                )
+                .await;
+                return;
+            }
+            Some((code, key)) => {
+                debug!(?code, %key, "Search can't be sorted for {}", dn);
+                sort_response = Some(sort_result(code));
+            }
+            None if locally => local_sort = Some(sort),
+            None => ctrl.push(sort.control()),
+        }
+    }
+
+    // This is done like this to facilitate a cache mechanism in future.
+    //
+    // Cache will need to key on:
+    //    bind_dn
+    //    base
+    //    scope
+    //    deref aliases
+    //    types only
+    //    filter
+    //    attrs
+    //   search controls
+    //
+    // Which is a lot, but it's everything that controls to results to
+    // ensure we don't introduce corruption.
+
+    if let Some((size, cookie)) = paged_results(&ctrl) {
+        let page = (size, cookie.to_vec());
+        paged_search_operation(
+            &session,
+            &app_state,
+            &tx,
+            msgid,
+            sr,
+            ctrl,
+            page,
+            cache_bypass,
+        )
+        .await;
+        return;
+    }
+
+    if let Some(schema) = app_state.schema.as_ref() {
+        if local_sort.is_none() && vlv.is_none() && schema.answers(&sr, &ctrl) {
+            schema_search_operation(&session, &app_state, &tx, msgid, sr, schema).await;
+            return;
+        }
+    }
+
+    let now = Instant::now();
+    let root_dse = is_root_dse_search(&sr);
+
+    let cache_key = SearchCacheKey::new(dn.clone(), &sr, ctrl.clone());
+    debug!(
+        base = %sr.base,
+        filter = %redacted_filter_string(&sr.filter, app_state.log_filter_values),
+        "Search by {}",
+        dn
+    );
+
+    let ttl = session_cache_ttl(&session, &app_state, &sr.base);
+    let maybe_results = if ttl.is_zero() {
+        None
+    } else if cache_bypass {
+        debug!("cache bypassed by control");
+        app_state.metrics.incr("cache_bypassed_total", &[]);
+        None
+    } else {
+        cache_lookup(&app_state, &cache_key, now).or_else(|| {
+            let max_stale = policy_cache_max_stale(&app_state)?;
+            let cached = stale_cache_lookup(&app_state, &cache_key, now, max_stale)?;
+            // The expired results are answered with now, and replaced once
+            // the search has been sent to the backend again.
+            app_state.metrics.incr("cache_stale_hits_total", &[]);
+            if app_state.cache_refreshes.start(&cache_key) {
+                tokio::spawn(
+                    refresh_cached_search(
+                        session.detach(),
+                        app_state.clone(),
+                        cache_key.clone(),
+                        sr.clone(),
+                        ctrl.clone(),
+                        ttl,
+                    )
+                    .in_current_span(),
+                );
+            }
+            Some(cached)
+        })
+    };
+
+    let was_cache_miss = maybe_results.is_none();
+
+    debug!("cache hit {}", !was_cache_miss);
+    incr_dn(&app_state, "dn_searches_total", dn, 1);
+    if was_cache_miss {
+        app_state.metrics.incr("cache_misses_total", &[]);
+        incr_dn(&app_state, "dn_cache_misses_total", dn, 1);
+    } else {
+        app_state.metrics.incr("cache_hits_total", &[]);
+        incr_dn(&app_state, "dn_cache_hits_total", dn, 1);
+        let _ = tx.send(SessionEvent::CacheHit(msgid)).await;
+    }
+
+    // A miss of a search that would be cached waits for the same search if it
+    // is already in flight, and otherwise leads it for those that follow.
+    let mut leader = None;
+    let mut coalesced = false;
+    let maybe_results = match (maybe_results, app_state.search_flights.as_ref()) {
+        (None, Some(flights)) if !ttl.is_zero() && !cache_bypass => {
+            match flights.join(&cache_key) {
+                Flight::Leader(flight) => {
+                    leader = Some(flight);
+                    None
+                }
+                Flight::Follower(rx) => {
+                    let results = follow(rx).await;
+                    coalesced = results.is_some();
+                    let outcome = if coalesced { "shared" } else { "fallback" };
+                    app_state
+                        .metrics
+                        .incr("search_coalesced_total", &[("outcome", outcome)]);
+                    results
+                }
+            }
+        }
+        (maybe_results, _) => maybe_results,
+    };
+
+    let results = match maybe_results {
+        Some(CachedValue {
+            valid_until: _,
+            entries,
+            references,
+            result,
+            ctrl,
+        }) => SearchResults {
+            entries,
+            references,
+            intermediates: Vec::new(),
+            result,
+            ctrl,
+        },
+        None => {
+            let relay_after = match (&local_sort, &vlv) {
+                (None, None) => {
+                    relay_after(&session, &app_state, &sr, !ttl.is_zero() && !cache_bypass)
+                }
+                _ => None,
+            };
+            let mut relayed = None;
+            if let Some(after) = relay_after {
+                let (sr, ctrl) = (sr.clone(), ctrl.clone());
+                match relayed_search(&session, &app_state, &tx, msgid, sr, ctrl, after).await {
+                    Relayed::Sent => return,
+                    Relayed::Complete(results) => relayed = Some(results),
+                    Relayed::Failed => {}
+                }
+            }
+            match relayed {
+                Some(results) => results,
+                None => match timed_backend_search(&session, &app_state, sr, ctrl).await {
+                    Ok(results) => results,
+                    Err(LdapError::Transport) => {
+                        respond(&tx, search_unavailable(msgid)).await;
+                        return;
+                    }
+                    Err(e) => {
+                        error!(?e, "A client search error has occurred");
+                        respond_and_disconnect(&tx, bind_operror(msgid, "unable to search")).await;
+                        // Always bail.
+                        return;
+                    }
+                },
+            }
+        }
+    };
+
+    // Update cache if needed.
+    if was_cache_miss && !coalesced && is_cacheable(&results) && !ttl.is_zero() {
+        let cache_value = CachedValue {
+            valid_until: now + ttl,
+            entries: results.entries.clone(),
+            references: results.references.clone(),
+            result: results.result.clone(),
+            ctrl: results.ctrl.clone(),
+        };
+        if let Some(leader) = leader.take() {
+            leader.complete(cache_value.clone());
+        }
+        cache_insert(&app_state, cache_key, cache_value);
+    }
+    drop(leader);
+
+    let mut results = results;
+    if root_dse {
+        for (entry, _) in results.entries.iter_mut().filter(|(e, _)| e.dn.is_empty()) {
+            restrict_root_dse(entry, &policy.request_controls, &[OID_WHOAMI], |oid| {
+                permits_extension(&session, &app_state, oid)
+            });
+        }
+    }
+    if let Some(sort) = local_sort {
+        sort.sort(&mut results.entries);
+        sort_response = Some(sort_result(LdapResultCode::Success));
+    }
+    results.ctrl.extend(sort_response);
+
+    let mut vlv_response = None;
+    if let Some((vlv, sort)) = vlv.zip(window_sort) {
+        match vlv.window(&sort, &results.entries) {
+            Ok((window, mut response)) => {
+                if results.result.code != LdapResultCode::Success {
+                    response.result = results.result.code.clone() as i64;
+                }
+                results.entries = results.entries.drain(window).collect();
+                vlv_response = Some(response);
+            }
+            Err(response) => {
+                debug!("Virtual list view offset is out of range for {}", dn);
+                results.entries.clear();
+                results.references.clear();
+                results.result = LdapResult {
+                    code: LdapResultCode::UnwillingToPerform,
+                    matcheddn: "".to_string(),
+                    message: "the offset is out of range".to_string(),
+                    referral: vec![],
+                };
+                vlv_response = Some(response);
+            }
+        }
+    }
+
+    // The entries that are sent, after the DN's entry limits.
+    let sent = entry_cap(&app_state, &policy.config)
+        .map_or(results.entries.len(), |cap| cap.min(results.entries.len()));
+    incr_dn(&app_state, "dn_search_entries_total", dn, sent as u64);
+    send_search_results(&tx, msgid, results, &session, &app_state, vlv_response).await;
+
+    // Try and quiesce now.
+    app_state.cache.try_quiesce();
+}
+
+// Answer a search for the subschema subentry from the proxy's copy, reading it
+// from the backend first if it has expired. If that fails, the copy is used
+// anyway, so schema searches work while the backend is unavailable.
+async fn schema_search_operation(
+    session: &Session,
+    app_state: &AppState,
+    tx: &Responder,
+    msgid: i32,
+    sr: LdapSearchRequest,
+    schema: &SchemaCache,
+) {
+    let entry = match schema.get(false) {
+        Some(entry) => {
+            app_state.metrics.incr("schema_cache_hits_total", &[]);
+            Some(entry)
+        }
+        None => {
+            app_state.metrics.incr("schema_cache_misses_total", &[]);
+            let searched = session
+                .retry(app_state, |client| {
+                    let sr = schema.request();
+                    async move { client.search(sr, vec![], Some(1), None).await }
+                })
+                .await;
+            let read = match searched {
+                Ok(mut results)
+                    if results.result.code == LdapResultCode::Success
+                        && !results.entries.is_empty() =>
+                {
+                    Ok(results.entries.swap_remove(0).0)
+                }
+                Ok(results) => Err(Some(results)),
+                Err(e) => {
+                    warn!(?e, "Unable to read the subschema subentry");
+                    Err(None)
+                }
+            };
+            match read {
+                Ok(entry) => {
+                    schema.store(entry.clone());
+                    Some(entry)
+                }
+                Err(results) => match (schema.get(true), results) {
+                    (Some(entry), _) => {
+                        debug!("Answering with an expired copy of the subschema subentry");
+                        app_state.metrics.incr("schema_cache_stale_total", &[]);
+                        Some(entry)
+                    }
+                    // The backend's answer is relayed, as there's no copy.
+                    (None, Some(results)) => {
+                        send_search_results(tx, msgid, results, session, app_state, None).await;
+                        return;
+                    }
+                    (None, None) => None,
+                },
+            }
+        }
+    };
+    let Some(entry) = entry else {
+        respond(tx, search_unavailable(msgid)).await;
+        return;
+    };
+    let results = SearchResults {
+        entries: vec![(SchemaCache::select(&entry, &sr), vec![])],
+        references: vec![],
+        intermediates: vec![],
+        result: LdapResult {
+            code: LdapResultCode::Success,
+            matcheddn: "".to_string(),
+            message: "".to_string(),
+            referral: vec![],
+        },
+        ctrl: vec![],
+    };
+    send_search_results(tx, msgid, results, session, app_state, None).await;
+}
+
+// What became of a search whose entries were to be relayed as they arrived.
+enum Relayed {
+    // Its entries were relayed, and it has been answered.
+    Sent,
+    // It ended before there were too many entries to collect.
+    Complete(SearchResults),
+    // It failed before any entry was relayed, so it can be made again as
+    // usual.
+    Failed,
+}
+
+// How many entries of a search to collect before relaying them, and the rest
+// as they arrive, if max_cacheable_entries is set. Searches that aren't cached
+// are relayed from the first entry. Searches that are merged from several
+// backends, whose referrals are chased, or that are given memberOf are only
+// complete once every entry has arrived, so they are never relayed, and nor is
+// the root DSE, which is restricted before it is sent.
+fn relay_after(
+    session: &Session,
+    app_state: &AppState,
+    sr: &LdapSearchRequest,
+    cached: bool,
+) -> Option<usize> {
+    let max = app_state.max_cacheable_entries?;
+    let routes = app_state.search_routes(&session.pool, &sr.base, &sr.scope, true);
+    let whole = routes != [session.pool.as_str()]
+        || is_root_dse_search(sr)
+        || app_state.referral_mode == ReferralMode::Chase
+        || (app_state.member_of.is_some() && requests_member_of(sr));
+    match (whole, cached) {
+        (true, _) => None,
+        (false, true) => Some(max),
+        (false, false) => Some(0),
+    }
+}
+
+// Search on the session's own connection, relaying the entries to the client
+// once there are more than `after` of them. The search is then not cached,
+// and is never retried, as the client has some of its entries.
+#[allow(clippy::too_many_arguments)]
+async fn relayed_search(
+    session: &Session,
+    app_state: &AppState,
+    tx: &Responder,
+    msgid: i32,
+    sr: LdapSearchRequest,
+    ctrl: Vec<LdapControl>,
+    after: usize,
+) -> Relayed {
+    let policy = session.policy();
+    let config = &policy.config;
+    let relay = |mut entry, ctrl| {
+        rewrite_entry(&config.attribute_rewrites, &mut entry);
+        let keep = hook_entry(app_state, &session.dn, &mut entry);
+        config.strip_attrs(&mut entry);
+        async move {
+            // A dropped entry is not a failure to relay.
+            !keep
+                || respond(
+                    tx,
+                    LdapMsg {
+                        msgid,
+                        op: LdapOp::SearchResultEntry(entry),
+                        ctrl,
+                    },
+                )
+                .await
+        }
+    };
+    let limits = search_limits(app_state, config, &sr);
+    let started = Instant::now();
+    let client = session.client();
+    let (results, relayed) = client.search_relayed(sr, ctrl, limits, after, relay).await;
+    if results.is_err() && relayed == 0 {
+        return Relayed::Failed;
+    }
+    if app_state.per_dn_metrics {
+        app_state.metrics.observe(
+            "dn_backend_search_duration_us",
+            &[("dn", &session.dn)],
+            started.elapsed(),
+        );
+    }
+    let results = results.map(|mut results| {
+        match app_state.referral_mode {
+            ReferralMode::Rewrite => rewrite_referrals(&mut results, app_state),
+            ReferralMode::Strip => strip_referrals(&mut results),
+            ReferralMode::Passthrough | ReferralMode::Chase => {}
+        }
+        results
+    });
+    if relayed == 0 {
+        return match results {
+            Ok(results) => Relayed::Complete(results),
+            Err(_) => Relayed::Failed,
+        };
+    }
+
+    debug!(entries = %relayed, "Relayed the entries of a search as they arrived");
+    app_state.metrics.incr("searches_relayed_total", &[]);
+    match results {
+        Ok(results) => {
+            let count = relayed + results.entries.len();
+            incr_dn(
+                app_state,
+                "dn_search_entries_total",
+                &session.dn,
+                count as u64,
+            );
+            send_search_results(tx, msgid, results, session, app_state, None).await;
+        }
+        Err(LdapError::Transport) => {
+            error!("Connection to the backend closed during a relayed search");
+            respond(tx, search_unavailable(msgid)).await;
+        }
+        Err(e) => {
+            error!(?e, "A client search error has occurred");
+            respond_and_disconnect(tx, bind_operror(msgid, "unable to search")).await;
+        }
+    }
+    Relayed::Sent
+}
+
+// Relay a search whose responses are streamed to the client as they arrive,
+// until the backend ends it or the client abandons it. It is sent on the
+// session's own connection, whatever the search routes say.
+async fn streamed_search_operation(
+    session: &Session,
+    app_state: &AppState,
+    tx: &Responder,
+    msgid: i32,
+    sr: LdapSearchRequest,
+    ctrl: Vec<LdapControl>,
+    notification: Option<RawControl>,
+) {
+    app_state.metrics.incr("searches_streamed_total", &[]);
+    let client = session.client();
+    let mut stream = match client
+        .search_stream(sr, ctrl, notification.into_iter().collect())
+        .await
+    {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!(?e, "Unable to start a streamed search");
+            respond(tx, search_unavailable(msgid)).await;
+            return;
+        }
+    };
+    let policy = session.policy();
+    let mut stripped = false;
+    while let Some(mut response) = stream.next().await {
+        let is_done = matches!(response.msg.op, LdapOp::SearchResultDone(_));
+        if let LdapOp::SearchResultEntry(entry) = &mut response.msg.op {
+            rewrite_entry(&policy.config.attribute_rewrites, entry);
+            if !hook_entry(app_state, &session.dn, entry) {
+                continue;
+            }
+            if policy.config.strip_attrs(entry) && policy.config.dry_run && !stripped {
+                warn!(
+                    "Dry run: returning attributes that are not allowed for {}",
+                    session.dn
+                );
+                stripped = true;
+            }
+        }
+        response.msg.msgid = msgid;
+        if !respond(tx, response).await || is_done {
+            return;
+        }
+    }
+    error!("Connection to the backend closed during a streamed search");
+    respond(tx, search_unavailable(msgid)).await;
+}
+
+// How long the results of a search by this session remain in the cache.
+fn session_cache_ttl(session: &Session, app_state: &AppState, base: &str) -> Duration {
+    app_state
+        .policy
+        .load()
+        .cache_ttl(&session.policy().config, base)
+}
+
+// Results that were cut short by a limit are incomplete, so they are never
+// cached, and nor are those with intermediate responses, which the cache doesn't
+// hold.
+fn is_cacheable(results: &SearchResults) -> bool {
+    let truncated = matches!(
+        results.result.code,
+        LdapResultCode::SizeLimitExceeded | LdapResultCode::TimeLimitExceeded
+    );
+    !truncated && results.intermediates.is_empty()
+}
+
+fn policy_cache_max_stale(app_state: &AppState) -> Option<Duration> {
+    app_state.policy.load().cache_max_stale
+}
+
+// A cached search that has expired, but not more than max_stale ago.
+fn stale_cache_lookup(
+    app_state: &AppState,
+    cache_key: &SearchCacheKey,
+    now: Instant,
+    max_stale: Duration,
+) -> Option<CachedValue> {
+    let mut cache_read_txn = app_state.cache.read();
+    cache_read_txn
+        .get(cache_key)
+        .filter(|cache_value| cache_value.valid_until + max_stale > now)
+        .cloned()
+}
+
+// Ends a refresh, even if its task is dropped.
+struct RefreshGuard<'a> {
+    app_state: &'a AppState,
+    cache_key: &'a SearchCacheKey,
+}
+
+impl Drop for RefreshGuard<'_> {
+    fn drop(&mut self) {
+        self.app_state.cache_refreshes.finish(self.cache_key);
+    }
+}
+
+// Send a search that was answered with expired results to the backend again,
+// and cache its results. It is sent over a connection of its own, so that the
+// client's session can end, and its connection be pooled, meanwhile.
+async fn refresh_cached_search(
+    detached: DetachedSession,
+    app_state: Arc<AppState>,
+    cache_key: SearchCacheKey,
+    sr: LdapSearchRequest,
+    ctrl: Vec<LdapControl>,
+    ttl: Duration,
+) {
+    let _guard = RefreshGuard {
+        app_state: &app_state,
+        cache_key: &cache_key,
+    };
+    let session = match detached.connect(&app_state).await {
+        Ok(session) => session,
+        Err(e) => {
+            debug!(?e, "Unable to connect to refresh a cached search");
+            app_state
+                .metrics
+                .incr("cache_refreshes_total", &[("result", "failed")]);
+            return;
+        }
+    };
+    let now = Instant::now();
+    let searched = timed_backend_search(&session, &app_state, sr, ctrl).await;
+    release_session(&app_state, session).await;
+    let result = match searched {
+        Ok(results) if is_cacheable(&results) => {
+            let cache_value = CachedValue {
+                valid_until: now + ttl,
+                entries: results.entries,
+                references: results.references,
+                result: results.result,
+                ctrl: results.ctrl,
+            };
+            cache_insert(&app_state, cache_key.clone(), cache_value);
+            "refreshed"
+        }
+        Ok(_) => "uncacheable",
+        Err(e) => {
+            debug!(?e, "Unable to refresh a cached search");
+            "failed"
+        }
+    };
+    app_state
+        .metrics
+        .incr("cache_refreshes_total", &[("result", result)]);
+}
+
+fn cache_lookup(
+    app_state: &AppState,
+    cache_key: &SearchCacheKey,
+    now: Instant,
+) -> Option<CachedValue> {
+    let mut cache_read_txn = app_state.cache.read();
+    cache_read_txn.get(cache_key).and_then(|cache_value| {
+        if cache_value.valid_until > now {
+            Some(cache_value.clone())
+        } else {
+            debug!("Cache item expired");
+            None
+        }
+    })
+}
+
+/// The weight of a cached value, which is its size unless that is less than the
+/// least weight of an entry.
+pub fn cache_weight(app_state: &AppState, cache_value: &CachedValue) -> Option<NonZeroUsize> {
+    NonZeroUsize::new(cache_value.size().max(app_state.cache_min_entry_weight))
+}
+
+fn cache_insert(app_state: &AppState, cache_key: SearchCacheKey, mut cache_value: CachedValue) {
+    if cache_value.is_negative() {
+        if let Some(ttl) = app_state.policy.load().negative_cache_ttl {
+            if ttl.is_zero() {
+                debug!("Not caching a result that found nothing");
+                return;
+            }
+            cache_value.valid_until = Instant::now() + ttl;
+        }
+    }
+    let mut cache_read_txn = app_state.cache.read();
+    match NonZeroUsize::new(cache_value.size()) {
+        Some(cache_value_size)
+            if app_state
+                .max_cacheable_result_bytes
+                .is_some_and(|max| cache_value_size.get() > max) =>
+        {
+            debug!("Result of size {} is too large to cache", cache_value_size);
+        }
+        Some(cache_value_size) => {
+            debug!("Adding entry of size {} to cache", cache_value_size);
+            let weight = cache_weight(app_state, &cache_value).unwrap_or(cache_value_size);
+            cache_read_txn.insert_sized(cache_key.clone(), cache_value, weight);
+            drop(cache_read_txn);
+            // After the insert is queued, so that a sweep never prunes it
+            // before it reaches the cache.
+            app_state
+                .cache_index
+                .insert(&cache_key, cache_value_size.get());
+        }
+        None => {
+            error!("Invalid entry size, unable to add to cache");
+        }
+    }
+}
+
+// Continue, or start, a paged search (RFC 2696). The first page is served from
+// the cache if the whole result set is there. Otherwise the pages come from the
+// backend, which holds the state of the search on this session's connection,
+// and are collected so that the whole result set is cached with the last page.
+#[allow(clippy::too_many_arguments)]
+async fn paged_search_operation(
+    session: &Session,
+    app_state: &AppState,
+    tx: &Responder,
+    msgid: i32,
+    sr: LdapSearchRequest,
+    ctrl: Vec<LdapControl>,
+    (size, cookie): (i64, Vec<u8>),
+    cache_bypass: bool,
+) {
+    let dn = &session.dn;
+    let now = Instant::now();
+    let key = SearchCacheKey::new(dn.clone(), &sr, without_paged_results(&ctrl));
+
+    let paged = if cookie.is_empty() {
+        let ttl = session_cache_ttl(session, app_state, &key.search.base);
+        let cached = if ttl.is_zero() {
+            None
+        } else if cache_bypass {
+            app_state.metrics.incr("cache_bypassed_total", &[]);
+            None
+        } else {
+            cache_lookup(app_state, &key, now)
+        };
+        match cached {
+            Some(cached) => {
+                debug!("cache hit for paged search");
+                let _ = tx.send(SessionEvent::CacheHit(msgid)).await;
+                PagedSearch {
+                    key,
+                    started: now,
+                    state: PagedState::Cached {
+                        results: SearchResults {
+                            entries: cached.entries,
+                            references: cached.references,
+                            intermediates: Vec::new(),
+                            result: cached.result,
+                            ctrl: cached.ctrl,
+                        },
+                        offset: 0,
+                    },
+                }
+            }
+            None => PagedSearch {
+                started: now,
+                state: PagedState::Backend {
+                    collected: (!ttl.is_zero()).then(|| CachedValue {
+                        valid_until: now + ttl,
+                        entries: Vec::new(),
+                        references: Vec::new(),
+                        result: LdapResult {
+                            code: LdapResultCode::Success,
+                            matcheddn: "".to_string(),
+                            message: "".to_string(),
+                            referral: vec![],
+                        },
+                        ctrl: Vec::new(),
+                    }),
+                    sent: 0,
+                },
+                key,
+            },
+        }
+    } else {
+        let paged = session.paged_searches.lock().await.remove(&cookie);
+        match paged {
+            Some(paged) if paged.key == key => paged,
+            _ => {
+                warn!("Invalid or expired paged results cookie for {}", dn);
+                respond(
+                    tx,
+                    LdapMsg {
+                        msgid,
+                        op: LdapOp::SearchResultDone(LdapResult {
+                            code: LdapResultCode::UnwillingToPerform,
+                            matcheddn: "".to_string(),
+                            message: "invalid paged results cookie".to_string(),
+                            referral: vec![],
+                        }),
+                        ctrl: vec![],
+                    },
+                )
+                .await;
+                return;
+            }
+        }
+    };
+
+    let PagedSearch {
+        key,
+        started,
+        state,
+    } = paged;
+    match state {
+        PagedState::Cached {
+            mut results,
+            offset,
+        } => {
+            // A size of zero abandons the search.
+            let page_size = if size > 0 {
+                usize::try_from(size).unwrap_or(usize::MAX)
+            } else {
+                0
+            };
+            // The DN's entry limits count across all of the pages.
+            let cap = entry_cap(app_state, &session.policy().config);
+            let available = cap.map_or(results.entries.len(), |cap| cap.min(results.entries.len()));
+            let offset = offset.min(available);
+            let end = offset.saturating_add(page_size).min(available);
+            let total = available as i64;
+            let is_last = size <= 0 || end >= available;
+
+            let mut page = SearchResults {
+                entries: results.entries[offset..end].to_vec(),
+                references: Vec::new(),
+                intermediates: Vec::new(),
+                result: LdapResult {
+                    code: LdapResultCode::Success,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec![],
+                },
+                ctrl: Vec::new(),
+            };
+            let next_cookie = if is_last {
+                // References are returned with the last page.
+                if size > 0 {
+                    page.references = std::mem::take(&mut results.references);
+                }
+                page.result = results.result;
+                page.ctrl = results.ctrl;
+                if available < results.entries.len() {
+                    page.result = LdapResult {
+                        code: LdapResultCode::SizeLimitExceeded,
+                        matcheddn: "".to_string(),
+                        message: "".to_string(),
+                        referral: vec![],
+                    };
+                }
+                Vec::new()
+            } else {
+                let next_cookie = rand::random::<[u8; 16]>().to_vec();
+                remember_paged_search(
+                    session,
+                    next_cookie.clone(),
+                    PagedSearch {
+                        key,
+                        started,
+                        state: PagedState::Cached {
+                            results,
+                            offset: end,
+                        },
+                    },
+                )
+                .await;
+                next_cookie
+            };
+            page.ctrl.push(LdapControl::SimplePagedResults {
+                size: total,
+                cookie: next_cookie,
+            });
+            send_search_results(tx, msgid, page, session, app_state, None).await;
+        }
+        PagedState::Backend {
+            mut collected,
+            sent,
+        } => {
+            let mut results = match backend_search(session, app_state, sr, ctrl).await {
+                Ok(results) => results,
+                Err(LdapError::Transport) => {
+                    respond(tx, search_unavailable(msgid)).await;
+                    return;
+                }
+                Err(e) => {
+                    error!(?e, "A client search error has occurred");
+                    respond_and_disconnect(tx, bind_operror(msgid, "unable to search")).await;
+                    // Always bail.
+                    return;
+                }
+            };
+
+            // The DN's entry limits count across all of the pages, so a page
+            // that goes over them is the last.
+            let cap = entry_cap(app_state, &session.policy().config);
+            if truncate_entries(&mut results, cap.map(|cap| cap.saturating_sub(sent))) {
+                debug!(
+                    "Paged search was cut short by the entry limit of {}",
+                    session.dn
+                );
+                results.ctrl = without_paged_results(&results.ctrl);
+                results.ctrl.push(LdapControl::SimplePagedResults {
+                    size: 0,
+                    cookie: Vec::new(),
+                });
+            }
+            let sent = sent + results.entries.len();
+
+            // Anything but success means the result set is not whole, and the
+            // cache doesn't hold intermediate responses.
+            if results.result.code != LdapResultCode::Success || !results.intermediates.is_empty() {
+                collected = None;
+            }
+            if let Some(value) = collected.as_mut() {
+                value.entries.extend(results.entries.iter().cloned());
+                value.references.extend(results.references.iter().cloned());
+            }
+            let too_large = |value: &CachedValue| {
+                app_state
+                    .max_cacheable_result_bytes
+                    .is_some_and(|max| value.size() > max)
+            };
+            if collected.as_ref().is_some_and(too_large) {
+                debug!("Paged search result set is too large to cache");
+                collected = None;
+            }
+
+            match paged_results_cookie(&results.ctrl) {
+                // Remember the cookie for the next page.
+                Some(next_cookie) if !next_cookie.is_empty() => {
+                    remember_paged_search(
+                        session,
+                        next_cookie.to_vec(),
+                        PagedSearch {
+                            key,
+                            started,
+                            state: PagedState::Backend { collected, sent },
+                        },
+                    )
+                    .await;
+                }
+                // This was the last page, so the result set is complete.
+                _ => {
+                    if let Some(mut value) = collected {
+                        value.result = results.result.clone();
+                        value.ctrl = without_paged_results(&results.ctrl);
+                        cache_insert(app_state, key, value);
+                    }
+                }
+            }
+
+            send_search_results(tx, msgid, results, session, app_state, None).await;
+        }
+    }
+}
+
+async fn remember_paged_search(session: &Session, cookie: Vec<u8>, paged: PagedSearch) {
+    let mut paged_searches = session.paged_searches.lock().await;
+    if paged_searches.len() >= MAX_PAGED_SEARCHES {
+        let oldest = paged_searches
+            .iter()
+            .min_by_key(|(_, paged)| paged.started)
+            .map(|(cookie, _)| cookie.clone());
+        if let Some(oldest) = oldest {
+            debug!("Forgetting the oldest paged search of {}", session.dn);
+            paged_searches.remove(&oldest);
+        }
+    }
+    paged_searches.insert(cookie, paged);
+}
+
+// Send a search to the backend, timing it for the metrics of the session's DN.
+async fn timed_backend_search(
+    session: &Session,
+    app_state: &AppState,
+    sr: LdapSearchRequest,
+    ctrl: Vec<LdapControl>,
+) -> Result<SearchResults, LdapError> {
+    let started = Instant::now();
+    let results = backend_search(session, app_state, sr, ctrl).await;
+    if app_state.per_dn_metrics {
+        app_state.metrics.observe(
+            "dn_backend_search_duration_us",
+            &[("dn", &session.dn)],
+            started.elapsed(),
+        );
+    }
+    results
+}
+
+// The most entries that any search of the DN may return, whatever size limit
+// it asks for.
+fn entry_cap(app_state: &AppState, config: &DnConfig) -> Option<usize> {
+    [app_state.max_relayed_entries, config.hard_entry_limit]
+        .into_iter()
+        .flatten()
+        .min()
+}
+
+// Cut the entries of results down to `cap`, ending them with sizeLimitExceeded
+// as a search that the backend stopped is. Returns whether any were cut.
+fn truncate_entries(results: &mut SearchResults, cap: Option<usize>) -> bool {
+    match cap {
+        Some(cap) if results.entries.len() > cap => {
+            results.entries.truncate(cap);
+            results.result = LdapResult {
+                code: LdapResultCode::SizeLimitExceeded,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            };
+            true
+        }
+        _ => false,
+    }
+}
+
+// The most entries that a search of the DN may return, and how long it may
+// take.
+fn search_limits(
+    app_state: &AppState,
+    config: &DnConfig,
+    sr: &LdapSearchRequest,
+) -> (Option<usize>, Option<Duration>) {
+    let size_limit = usize::try_from(sr.sizelimit)
+        .ok()
+        .filter(|limit| *limit > 0);
+    let max_entries = [size_limit, entry_cap(app_state, config)]
+        .into_iter()
+        .flatten()
+        .min();
+    let time_limit = u64::try_from(sr.timelimit)
+        .ok()
+        .filter(|limit| *limit > 0)
+        .map(Duration::from_secs);
+    (max_entries, time_limit)
+}
+
+// Send a search to the backend. Searches that return more entries, or take
+// longer, than their (capped) limits are stopped by the proxy as well.
+async fn backend_search(
+    session: &Session,
+    app_state: &AppState,
+    sr: LdapSearchRequest,
+    ctrl: Vec<LdapControl>,
+) -> Result<SearchResults, LdapError> {
+    let (max_entries, time_limit) = search_limits(app_state, &session.policy().config, &sr);
+
+    // The results of a paged search can't be merged, as each backend has its
+    // own cookie.
+    let paged = paged_results_cookie(&ctrl).is_some();
+    let routes = app_state.search_routes(&session.pool, &sr.base, &sr.scope, !paged);
+    let mut results = match routes.as_slice() {
+        [pool] if *pool == session.pool => {
+            session
+                .retry(app_state, |client| {
+                    let sr = sr.clone();
+                    let ctrl = ctrl.clone();
+                    async move { client.search(sr, ctrl, max_entries, time_limit).await }
+                })
+                .await?
+        }
+        [pool] => {
+            debug!(%pool, base = %sr.base, "Routing search by its base");
+            session
+                .retry_routed(app_state, pool, |client| {
+                    let sr = sr.clone();
+                    let ctrl = ctrl.clone();
+                    async move { client.search(sr, ctrl, max_entries, time_limit).await }
+                })
+                .await?
+        }
+        _ => {
+            let limits = (max_entries, time_limit);
+            aggregate_search(session, app_state, &routes, &sr, &ctrl, limits).await?
+        }
+    };
+
+    match app_state.referral_mode {
+        ReferralMode::Passthrough => {}
+        ReferralMode::Rewrite => rewrite_referrals(&mut results, app_state),
+        ReferralMode::Strip => strip_referrals(&mut results),
+        // The state of a paged search is held by the backend that started it,
+        // so it can't be continued elsewhere.
+        ReferralMode::Chase if paged_results_cookie(&ctrl).is_some() => {}
+        ReferralMode::Chase => {
+            let limits = (max_entries, time_limit);
+            chase_referrals(session, app_state, &sr, &ctrl, &mut results, limits).await
+        }
+    }
+
+    let member_of = app_state
+        .member_of
+        .as_ref()
+        .filter(|_| requests_member_of(&sr));
+    if let Some(member_of) = member_of {
+        for (entry, _) in results.entries.iter_mut() {
+            if let Some(group_sr) = member_of.group_search(entry) {
+                let groups = member_groups(session, app_state, group_sr).await?;
+                add_member_of(entry, groups, sr.typesonly);
+            }
+        }
+    }
+    Ok(results)
+}
+
+// Log and count the warnings and errors of a password policy response to a
+// bind, so that expiring passwords can be followed up by DN.
+fn password_policy_audit(app_state: &AppState, dn: &str, policy: &PasswordPolicyResponse) {
+    match policy.warning {
+        Some(PolicyWarning::TimeBeforeExpiration(seconds)) => {
+            info!(seconds, "The password of {} is about to expire", dn);
+            app_state.metrics.incr(
+                "password_policy_warnings_total",
+                &[("warning", "password_expiring")],
+            );
+        }
+        Some(PolicyWarning::GraceAuthNsRemaining(remaining)) => {
+            info!(
+                remaining,
+                "The password of {} has expired, and a grace login was used", dn
+            );
+            app_state.metrics.incr(
+                "password_policy_warnings_total",
+                &[("warning", "grace_login")],
+            );
+        }
+        None => {}
+    }
+    if let Some(error) = policy.error {
+        info!(
+            error = error.reason(),
+            "The password policy refused the bind for {}", dn
+        );
+        app_state
+            .metrics
+            .incr("password_policy_errors_total", &[("error", error.reason())]);
+    }
+}
+
+// A routed backend that can't be connected to, or bound to, is unavailable
+// like the session's own backend when it can't be reached.
+fn unreachable_route(e: LdapError) -> LdapError {
+    warn!(?e, "Unable to reach the backend that a search is routed to");
+    LdapError::Transport
+}
+
+// Send a search whose scope spans the naming contexts of several backend
+// pools, or that is fanned out, to each of them at once, and merge their
+// results. Of entries with the same DN, that of the first pool is kept. A pool
+// that doesn't hold the base answers noSuchObject, which is ignored if another
+// pool succeeds. Any other failure is returned, with the entries found.
+async fn aggregate_search(
+    session: &Session,
+    app_state: &AppState,
+    routes: &[&str],
+    sr: &LdapSearchRequest,
+    ctrl: &[LdapControl],
+    (max_entries, time_limit): (Option<usize>, Option<Duration>),
+) -> Result<SearchResults, LdapError> {
+    debug!(?routes, base = %sr.base, "Aggregating search across backends");
+    let searches = routes.iter().map(|pool| async move {
+        if *pool == session.pool {
+            session
+                .retry(app_state, |client| {
+                    let (sr, ctrl) = (sr.clone(), ctrl.to_vec());
+                    async move { client.search(sr, ctrl, max_entries, time_limit).await }
+                })
+                .await
+        } else {
+            session
+                .retry_routed(app_state, pool, |client| {
+                    let (sr, ctrl) = (sr.clone(), ctrl.to_vec());
+                    async move { client.search(sr, ctrl, max_entries, time_limit).await }
+                })
+                .await
+        }
+    });
+    let all = futures_util::future::join_all(searches).await;
+
+    let mut merged: Option<SearchResults> = None;
+    let mut seen = HashSet::new();
+    for results in all {
+        let mut results = results?;
+        results.entries.retain(|(entry, _)| {
+            seen.insert(normalize_dn(&entry.dn).unwrap_or_else(|_| entry.dn.to_lowercase()))
+        });
+        let Some(merged) = merged.as_mut() else {
+            merged = Some(results);
+            continue;
+        };
+        merged.entries.extend(results.entries);
+        merged.references.extend(results.references);
+        merged.intermediates.extend(results.intermediates);
+        let code = (&merged.result.code, &results.result.code);
+        if matches!(code, (LdapResultCode::NoSuchObject, _))
+            || matches!(code, (LdapResultCode::Success, c) if *c != LdapResultCode::NoSuchObject)
+        {
+            merged.result = results.result;
+            merged.ctrl = results.ctrl;
+        }
+    }
+
+    let mut merged = merged.ok_or(LdapError::InvalidProtocolState)?;
+    if let Some(max) = max_entries.filter(|max| merged.entries.len() > *max) {
+        merged.entries.truncate(max);
+        merged.result = LdapResult {
+            code: LdapResultCode::SizeLimitExceeded,
+            matcheddn: "".to_string(),
+            message: "".to_string(),
+            referral: vec![],
+        };
+    }
+    Ok(merged)
+}
+
+// The DNs of the groups found by a search for an entry's groups. These
+// searches are cached like any other, so that the groups of an entry aren't
+// searched for every time it is.
+async fn member_groups(
+    session: &Session,
+    app_state: &AppState,
+    sr: LdapSearchRequest,
+) -> Result<Vec<String>, LdapError> {
+    let now = Instant::now();
+    let cache_key = SearchCacheKey::new(session.dn.clone(), &sr, vec![]);
+    let ttl = session_cache_ttl(session, app_state, &sr.base);
+    let cached = if ttl.is_zero() {
+        None
+    } else {
+        cache_lookup(app_state, &cache_key, now)
+    };
+    if let Some(cached) = cached {
+        return Ok(cached
+            .entries
+            .into_iter()
+            .map(|(entry, _)| entry.dn)
+            .collect());
+    }
+
+    app_state.metrics.incr("member_of_searches_total", &[]);
+    let results = session
+        .retry(app_state, |client| {
+            let sr = sr.clone();
+            async move {
+                client
+                    .search(sr, vec![], app_state.max_relayed_entries, None)
+                    .await
+            }
+        })
+        .await?;
+    if results.result.code != LdapResultCode::Success {
+        let filter = redacted_filter_string(&sr.filter, app_state.log_filter_values);
+        warn!(code = ?results.result.code, %filter, "Unable to search for the groups of an entry");
+        return Ok(Vec::new());
+    }
+
+    let groups = results
+        .entries
+        .iter()
+        .map(|(entry, _)| entry.dn.clone())
+        .collect();
+    if !ttl.is_zero() {
+        let cache_value = CachedValue {
+            valid_until: now + ttl,
+            entries: results.entries,
+            references: results.references,
+            result: results.result,
+            ctrl: results.ctrl,
+        };
+        cache_insert(app_state, cache_key, cache_value);
+    }
+    Ok(groups)
+}
+
+// Point the referrals of a search at the proxy.
+fn rewrite_referrals(results: &mut SearchResults, app_state: &AppState) {
+    let uris = results
+        .references
+        .iter_mut()
+        .flat_map(|(reference, _)| reference.uris.iter_mut())
+        .chain(results.result.referral.iter_mut());
+    for uri in uris {
+        rewrite_uri(uri, app_state);
+    }
+}
+
+fn rewrite_uri(uri: &mut String, app_state: &AppState) {
+    let proxy = rewrite_target(
+        uri,
+        &app_state.referral_rewrite_map,
+        &app_state.referral_rewrite_host,
+    );
+    *uri = rewrite_url(uri, proxy);
+}
+
+fn strip_referrals(results: &mut SearchResults) {
+    results.references.clear();
+    strip_result_referrals(&mut results.result);
+}
+
+fn strip_result_referrals(result: &mut LdapResult) {
+    result.referral.clear();
+    // A referral result must have referrals, so this becomes the closest
+    // answer we can give.
+    if result.code == LdapResultCode::Referral {
+        result.code = LdapResultCode::NoSuchObject;
+    }
+}
+
+// Apply the referral mode to the result of a compare or a write. These
+// referrals are never chased, as writes can't safely be replayed elsewhere.
+fn handle_result_referrals(result: &mut LdapResult, app_state: &AppState) {
+    match app_state.referral_mode {
+        ReferralMode::Rewrite => {
+            for uri in result.referral.iter_mut() {
+                rewrite_uri(uri, app_state);
+            }
+        }
+        ReferralMode::Strip => strip_result_referrals(result),
+        ReferralMode::Passthrough | ReferralMode::Chase => {}
+    }
+}
+
+// A referral to chase, and how many referrals were followed to reach it.
+struct PendingReferral {
+    uris: Vec<String>,
+    hops: usize,
+    // This is the referral of the search result, rather than a reference.
+    is_result: bool,
+}
+
+// Follow the referrals of a search, merging what the referred servers return
+// into the results. Referrals that can't be followed, because of a loop, the
+// hop limit or an error, are left in the results as they are.
+async fn chase_referrals(
+    session: &Session,
+    app_state: &AppState,
+    sr: &LdapSearchRequest,
+    ctrl: &[LdapControl],
+    results: &mut SearchResults,
+    (max_entries, time_limit): (Option<usize>, Option<Duration>),
+) {
+    let mut visited = HashSet::new();
+    let backend = session.client().backend().clone();
+    if let (Some(host), Some(port)) = (backend.host_str(), backend.port_or_known_default()) {
+        visited.insert(referral_key(host, port, &sr.base));
+    }
+
+    let mut pending: VecDeque<_> = std::mem::take(&mut results.references)
+        .into_iter()
+        .map(|(reference, _)| PendingReferral {
+            uris: reference.uris,
+            hops: 1,
+            is_result: false,
+        })
+        .collect();
+    if results.result.code == LdapResultCode::Referral {
+        pending.push_back(PendingReferral {
+            uris: std::mem::take(&mut results.result.referral),
+            hops: 1,
+            is_result: true,
+        });
+    }
+
+    while let Some(referral) = pending.pop_front() {
+        let remaining = max_entries.map(|max| max.saturating_sub(results.entries.len()));
+        let chased = if referral.hops > app_state.referral_hop_limit {
+            warn!(uris = ?referral.uris, "Referral hop limit reached");
+            None
+        } else {
+            chase_referral(
+                session,
+                app_state,
+                sr,
+                ctrl,
+                &referral.uris,
+                &mut visited,
+                remaining,
+                time_limit,
+            )
+            .await
+        };
+
+        let Some(chased) = chased else {
+            app_state.metrics.incr("referral_chase_failures_total", &[]);
+            if referral.is_result {
+                results.result.referral = referral.uris;
+            } else {
+                results.references.push((
+                    LdapSearchResultReference {
+                        uris: referral.uris,
+                    },
+                    vec![],
+                ));
+            }
+            continue;
+        };
+        app_state.metrics.incr("referrals_chased_total", &[]);
+
+        results.entries.extend(chased.entries);
+        pending.extend(
+            chased
+                .references
+                .into_iter()
+                .map(|(reference, _)| PendingReferral {
+                    uris: reference.uris,
+                    hops: referral.hops + 1,
+                    is_result: false,
+                }),
+        );
+        let truncated = matches!(
+            chased.result.code,
+            LdapResultCode::SizeLimitExceeded | LdapResultCode::TimeLimitExceeded
+        );
+        if truncated {
+            // The results are incomplete, so stop here.
+            results.result = chased.result;
+            results.ctrl = chased.ctrl;
+            break;
+        }
+        if referral.is_result {
+            if chased.result.code == LdapResultCode::Referral {
+                pending.push_back(PendingReferral {
+                    uris: chased.result.referral,
+                    hops: referral.hops + 1,
+                    is_result: true,
+                });
+            } else {
+                results.result = chased.result;
+                results.ctrl = chased.ctrl;
+            }
+        }
+    }
+}
+
+// Referrals to the same search of the same server are a loop.
+fn referral_key(host: &str, port: u16, base: &str) -> String {
+    let base = normalize_dn(base).unwrap_or_else(|_| base.to_lowercase());
+    format!("{}:{}/{}", host.to_lowercase(), port, base)
+}
+
+// Follow one referral, trying each of its urls until one answers. The search is
+// made with the credentials of the session.
+#[allow(clippy::too_many_arguments)]
+async fn chase_referral(
+    session: &Session,
+    app_state: &AppState,
+    sr: &LdapSearchRequest,
+    ctrl: &[LdapControl],
+    uris: &[String],
+    visited: &mut HashSet<String>,
+    max_entries: Option<usize>,
+    time_limit: Option<Duration>,
+) -> Option<SearchResults> {
+    let pool = app_state.backend_pools.get(&session.pool)?;
+    for uri in uris {
+        let Some(url) = LdapUrl::parse(uri) else {
+            warn!(%uri, "Unable to parse referral");
+            continue;
+        };
+        if !url.host_in(&app_state.referral_chase_hosts) {
+            warn!(%uri, "Referral to a server that may not be chased");
+            continue;
+        }
+
+        let mut sr = sr.clone();
+        if let Some(base) = url.base {
+            sr.base = base;
+        }
+        if let Some(scope) = url.scope {
+            sr.scope = scope;
+        }
+        if let Some(filter) = url.filter {
+            sr.filter = filter;
+        }
+        if !visited.insert(referral_key(&url.host, url.port, &sr.base)) {
+            warn!(%uri, "Referral loop detected");
+            continue;
+        }
+
+        // The session's bind is sent to the server, so it is only ever sent
+        // over tls: plain ldap referrals are upgraded with StartTLS, and are
+        // not followed if the server refuses it.
+        let scheme = if url.tls { "ldaps" } else { "ldap" };
+        let Ok(backend_url) = Url::parse(&format!("{}://{}:{}", scheme, url.host, url.port)) else {
+            continue;
+        };
+        let backend = Backend::new(backend_url, url.host.clone(), url.port, Vec::new());
+        let backend = if url.tls {
+            backend
+        } else {
+            backend.with_starttls()
+        };
+        let client = match BasicLdapClient::build(
+            &backend,
+            pool,
+            app_state.max_proxy_ber_size,
+            &app_state.breakers,
+        )
+        .await
+        {
+            // The server referred to holds the backend's DNs too.
+            Ok(client) => client.with_dn_rewrite(app_state.dn_rewrite.clone()),
+            Err(e) => {
+                warn!(?e, %uri, "Unable to connect to referral");
+                continue;
+            }
+        };
+        let (lbr, bind_ctrl) = session.retained_bind();
+        match client.bind(lbr, bind_ctrl).await {
+            Ok((bind_resp, _)) if bind_resp.res.code == LdapResultCode::Success => {}
+            Ok((bind_resp, _)) => {
+                warn!(code = ?bind_resp.res.code, %uri, "Unable to bind to referral");
+                continue;
+            }
+            Err(e) => {
+                warn!(?e, %uri, "Unable to bind to referral");
+                continue;
+            }
+        }
+        debug!(%uri, "Chasing referral");
+        let chased = client
+            .search(sr, ctrl.to_vec(), max_entries, time_limit)
+            .await;
+        client.unbind().await;
+        match chased {
+            Ok(chased) => return Some(chased),
+            Err(e) => warn!(?e, %uri, "Unable to search referral"),
+        }
+    }
+    None
+}
+
+// Reduce the limits of a search to this DN's caps. A limit of 0 requested by
+// the client means no limit, so it is replaced by the cap.
+fn cap_search_limits(sr: &mut LdapSearchRequest, config: &DnConfig) {
+    fn cap(requested: i32, cap: Option<u32>) -> i32 {
+        match cap.map(|cap| i32::try_from(cap).unwrap_or(i32::MAX)) {
+            Some(cap) if requested <= 0 => cap,
+            Some(cap) => requested.min(cap),
+            None => requested,
+        }
+    }
+    sr.sizelimit = cap(sr.sizelimit, config.size_limit);
+    sr.timelimit = cap(sr.timelimit, config.time_limit_secs);
+}
+
+fn search_unavailable(msgid: i32) -> LdapMsg {
+    LdapMsg {
+        msgid,
+        op: LdapOp::SearchResultDone(unavailable()),
+        ctrl: vec![],
+    }
+}
+
+// Pass an entry for the DN through the search hook, if there is one. Returns
+// false if the entry is dropped.
+fn hook_entry(app_state: &AppState, dn: &str, entry: &mut LdapSearchResultEntry) -> bool {
+    match app_state.search_hook.as_ref() {
+        Some(hook) => hook.entry(dn, entry),
+        None => true,
+    }
+}
+
+// Send the results of a search of the session, with the DN's attribute
+// rewrites and search hook, and without the attributes that it may not see.
+// This is done as they are sent, so that cached results follow the config of
+// the DN as it is now, and are cut short by its entry limits.
+async fn send_search_results(
+    tx: &Responder,
+    msgid: i32,
+    mut results: SearchResults,
+    session: &Session,
+    app_state: &AppState,
+    vlv: Option<VlvResponse>,
+) {
+    let policy = session.policy();
+    let config = &policy.config;
+    if truncate_entries(&mut results, entry_cap(app_state, config)) {
+        debug!(
+            "Search results were cut short by the entry limit of {}",
+            session.dn
+        );
+    }
+    app_state
+        .dn_quotas
+        .take_entries(&session.dn, config, results.entries.len());
+    let mut stripped = false;
+    let entries = results.entries.into_iter().filter_map(|(mut entry, ctrl)| {
+        rewrite_entry(&config.attribute_rewrites, &mut entry);
+        if !hook_entry(app_state, &session.dn, &mut entry) {
+            return None;
+        }
+        stripped |= config.strip_attrs(&mut entry);
+        Some((LdapOp::SearchResultEntry(entry), ctrl))
+    });
+    let references = results
+        .references
+        .into_iter()
+        .map(|(reference, ctrl)| (LdapOp::SearchResultReference(reference), ctrl));
+    let intermediates = results
+        .intermediates
+        .into_iter()
+        .map(|(intermediate, ctrl)| (LdapOp::IntermediateResponse(intermediate), ctrl));
+    for (op, ctrl) in entries.chain(references).chain(intermediates) {
+        if !respond(tx, LdapMsg { msgid, op, ctrl }).await {
+            return;
+        }
+    }
+    if stripped && config.dry_run {
+        warn!("Dry run: returning attributes that are not allowed");
+    }
+
+    respond(
+        tx,
+        ClientResponse {
+            msg: LdapMsg {
+                msgid,
+                op: LdapOp::SearchResultDone(results.result),
+                ctrl: results.ctrl,
+            },
+            vlv,
+            raw_controls: Vec::new(),
+        },
+    )
+    .await;
+}
+
+async fn compare_operation(
+    session: Arc<Session>,
+    app_state: Arc<AppState>,
+    tx: Responder,
+    msgid: i32,
+    lcr: LdapCompareRequest,
+    ctrl: Vec<LdapControl>,
+) {
+    if !session.policy().config.allow_compare {
+        warn!("Compare is not allowed for {}", session.dn);
+        respond(
+            &tx,
+            LdapMsg {
+                msgid,
+                op: LdapOp::CompareResult(LdapResult {
+                    code: LdapResultCode::InsufficentAccessRights,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec![],
+                }),
+                ctrl: vec![],
+            },
+        )
+        .await;
+        return;
+    }
+
+    if !session.policy().config.permits_compare(&lcr.atype) {
+        warn!("Compare of {} is not allowed for {}", lcr.atype, session.dn);
+        respond(
+            &tx,
+            LdapMsg {
+                msgid,
+                op: LdapOp::CompareResult(LdapResult {
+                    code: LdapResultCode::InsufficentAccessRights,
+                    matcheddn: "".to_string(),
+                    message: format!("compare of {} is not permitted", lcr.atype),
+                    referral: vec![],
+                }),
+                ctrl: vec![],
+            },
+        )
+        .await;
+        return;
+    }
+
+    // Compares are commonly used to check credentials, so these are
+    // never cached.
+    let compare_result = session
+        .retry(&app_state, |client| {
+            let lcr = lcr.clone();
+            let ctrl = ctrl.clone();
+            async move { client.compare(lcr, ctrl).await }
+        })
+        .await;
+
+    let (mut result, ctrl) = match compare_result {
+        Ok(data) => data,
+        Err(LdapError::Transport) => {
+            respond(
+                &tx,
+                LdapMsg {
+                    msgid,
+                    op: LdapOp::CompareResult(unavailable()),
+                    ctrl: vec![],
+                },
+            )
+            .await;
+            return;
+        }
+        Err(e) => {
+            error!(?e, "A client compare error has occurred");
+            respond_and_disconnect(
+                &tx,
+                LdapMsg {
+                    msgid,
+                    op: LdapOp::CompareResult(LdapResult {
+                        code: LdapResultCode::OperationsError,
+                        matcheddn: "".to_string(),
+                        message: "unable to compare".to_string(),
+                        referral: vec![],
+                    }),
+                    ctrl: vec![],
+                },
+            )
+            .await;
+            // Always bail.
+            return;
+        }
+    };
+
+    handle_result_referrals(&mut result, &app_state);
+    respond(
+        &tx,
+        LdapMsg {
+            msgid,
+            op: LdapOp::CompareResult(result),
+            ctrl,
+        },
+    )
+    .await;
+}
+
+async fn write_operation(
+    session: Arc<Session>,
+    app_state: Arc<AppState>,
+    tx: Responder,
+    msgid: i32,
+    op: LdapOp,
+    ctrl: Vec<LdapControl>,
+    raw_controls: Vec<RawControl>,
+) {
+    let dn = &session.dn;
+
+    // Only the target dn is logged here, never the attribute values.
+    let (kind, target_dn, respond_op): (_, _, fn(LdapResult) -> LdapOp) = match &op {
+        LdapOp::AddRequest(lar) => ("add", lar.dn.clone(), LdapOp::AddResponse),
+        LdapOp::ModifyRequest(lmr) => ("modify", lmr.dn.clone(), LdapOp::ModifyResponse),
+        LdapOp::DelRequest(del_dn) => ("delete", del_dn.clone(), LdapOp::DelResponse),
+        LdapOp::ModifyDNRequest(lmdr) => ("modify dn", lmdr.dn.clone(), LdapOp::ModifyDNResponse),
+        _ => {
+            error!("Invalid write operation");
+            let _ = tx.send(SessionEvent::Disconnect).await;
+            return;
+        }
+    };
+
+    info!(%target_dn, "{} requested by {}", kind, dn);
+
+    // Where a modify dn moves the entry to.
+    let moved_to = match &op {
+        LdapOp::ModifyDNRequest(lmdr) => {
+            let superior = lmdr
+                .new_superior
+                .clone()
+                .or_else(|| parent_dn(&lmdr.dn).map(str::to_string));
+            Some(match superior {
+                Some(superior) => format!("{},{}", lmdr.newrdn, superior),
+                None => lmdr.newrdn.clone(),
+            })
+        }
+        _ => None,
+    };
+
+    if app_state.policy.load().read_only {
+        warn!(%target_dn, "Refusing {} by {} as the proxy is read only", kind, dn);
+        respond(
+            &tx,
+            LdapMsg {
+                msgid,
+                op: respond_op(LdapResult {
+                    code: LdapResultCode::UnwillingToPerform,
+                    matcheddn: "".to_string(),
+                    message: "the proxy is read only".to_string(),
+                    referral: vec![],
+                }),
+                ctrl: vec![],
+            },
+        )
+        .await;
+        return;
+    }
+
+    if !session
+        .policy()
+        .config
+        .allow_write
+        .unwrap_or(app_state.policy.load().allow_write)
+    {
+        warn!(%target_dn, "Writes are not allowed for {}", dn);
+        respond(
+            &tx,
+            LdapMsg {
+                msgid,
+                op: respond_op(LdapResult {
+                    code: LdapResultCode::InsufficentAccessRights,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec![],
+                }),
+                ctrl: vec![],
+            },
+        )
+        .await;
+        return;
+    }
+
+    // The backend would see the write as made by the service account.
+    if session.shared {
+        warn!(%target_dn, "Writes are not allowed on the shared connection of {}", dn);
+        respond(
+            &tx,
+            LdapMsg {
+                msgid,
+                op: respond_op(LdapResult {
+                    code: LdapResultCode::UnwillingToPerform,
+                    matcheddn: "".to_string(),
+                    message: "writes are not permitted on a shared connection".to_string(),
+                    referral: vec![],
+                }),
+                ctrl: vec![],
+            },
+        )
+        .await;
+        return;
+    }
+
+    // Writes are not retried after a lost connection, as the backend may have
+    // applied the change before the connection failed.
+    let client = session.client();
+    let write_result = match op {
+        LdapOp::AddRequest(lar) => client.add(lar, ctrl, raw_controls).await,
+        LdapOp::ModifyRequest(lmr) => client.modify(lmr, ctrl, raw_controls).await,
+        LdapOp::DelRequest(del_dn) => client.delete(del_dn, ctrl, raw_controls).await,
+        LdapOp::ModifyDNRequest(lmdr) => client.modify_dn(lmdr, ctrl, raw_controls).await,
+        _ => Err(LdapError::InvalidProtocolState),
+    };
+
+    let (mut result, ctrl) = match write_result {
+        Ok(data) => data,
+        Err(LdapError::Transport) => {
+            session.recover(&app_state, &client).await;
+            respond(
+                &tx,
+                LdapMsg {
+                    msgid,
+                    op: respond_op(unavailable()),
+                    ctrl: vec![],
+                },
+            )
+            .await;
+            return;
+        }
+        Err(e) => {
+            error!(?e, "A client {} error has occurred", kind);
+            respond_and_disconnect(
+                &tx,
+                LdapMsg {
+                    msgid,
+                    op: respond_op(LdapResult {
+                        code: LdapResultCode::OperationsError,
+                        matcheddn: "".to_string(),
+                        message: format!("unable to {}", kind),
+                        referral: vec![],
+                    }),
+                    ctrl: vec![],
+                },
+            )
+            .await;
+            // Always bail.
+            return;
+        }
+    };
+
+    info!(%target_dn, code = ?result.code, "{} completed for {}", kind, dn);
+
+    if result.code == LdapResultCode::Success {
+        // The cached searches of every bind dn may contain the target, and
+        // the new dn of a moved entry.
+        let target = normalize_dn(&target_dn).unwrap_or_else(|_| target_dn.to_lowercase());
+        let mut written = vec![target];
+        if let Some(new_dn) = moved_to {
+            written.extend(normalize_dn(&new_dn).ok());
+        }
+        cache_invalidate_written(&app_state, &written);
+    }
+
+    handle_result_referrals(&mut result, &app_state);
+    respond(
+        &tx,
+        LdapMsg {
+            msgid,
+            op: respond_op(result),
+            ctrl,
+        },
+    )
+    .await;
+}
+
+// A password modify of the session's own entry (RFC 3062) changes the password
+// that the session must re-bind with, and may change what its searches return.
+fn password_modified(
+    session: &Session,
+    app_state: &AppState,
+    request: &LdapPasswordModifyRequest,
+    resp: &LdapExtendedResponse,
+) {
+    let target = match request.user_identity.as_deref() {
+        None => session.dn.clone(),
+        Some(identity) => {
+            let identity = identity.strip_prefix("dn:").unwrap_or(identity);
+            match normalize_dn(identity) {
+                Ok(dn) => dn,
+                // Not a dn, so we can't tell which entry was changed.
+                Err(_) => return,
+            }
+        }
+    };
+    cache_invalidate_written(app_state, std::slice::from_ref(&target));
+
+    if target != session.dn {
+        return;
+    }
+    // Without a new password the backend generates one, and returns it.
+    let generated = LdapPasswordModifyResponse::try_from(resp)
+        .ok()
+        .and_then(|resp| resp.gen_password);
+    if let Some(new_password) = request.new_password.as_deref().or(generated.as_deref()) {
+        session.update_retained_password(new_password);
+    }
+}
+
+// If this session may use an extended operation, as extended_operation
+// decides. StartTLS is always refused, as the session already has tls.
+fn permits_extension(session: &Session, app_state: &AppState, oid: &str) -> bool {
+    let policy = session.policy();
+    let global = app_state.policy.load();
+    match oid {
+        OID_WHOAMI => true,
+        OID_STARTTLS => false,
+        _ if session.shared => false,
+        OID_PASSWORD_MODIFY if global.read_only => false,
+        OID_PASSWORD_MODIFY if policy.config.allow_password_modify => true,
+        oid => {
+            policy.config.allowed_extended_oids.contains(oid)
+                || global.allowed_extended_oids.contains(oid)
+        }
+    }
+}
+
+async fn extended_operation(
+    session: Arc<Session>,
+    app_state: Arc<AppState>,
+    tx: Responder,
+    msgid: i32,
+    ler: LdapExtendedRequest,
+    ctrl: Vec<LdapControl>,
+) {
+    let dn = &session.dn;
+
+    let (op, ctrl) = match ler.name.as_str() {
+        // Answered from the session, as the backend would only repeat the dn
+        // that we bound as (RFC 4532).
+        OID_WHOAMI if !session.policy().config.whoami_from_backend || session.shared => (
+            LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: LdapResult {
+                    code: LdapResultCode::Success,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec![],
+                },
+                name: None,
+                value: Some(whoami_authzid(dn)),
+            }),
+            vec![],
+        ),
+        // The client is already on a tls session.
+        OID_STARTTLS => {
+            warn!("StartTLS requested on an existing tls session");
+            (
+                extended_error(
+                    LdapResultCode::OperationsError,
+                    "tls is already established".to_string(),
+                ),
+                vec![],
+            )
+        }
+        // These would be made as the service account.
+        oid if session.shared => {
+            warn!(%oid, "Extended operations are not allowed on the shared connection of {}", dn);
+            (
+                extended_error(
+                    LdapResultCode::UnwillingToPerform,
+                    format!(
+                        "extended operation {} is not permitted on a shared connection",
+                        oid
+                    ),
+                ),
+                vec![],
+            )
+        }
+        OID_PASSWORD_MODIFY if app_state.policy.load().read_only => {
+            warn!(
+                "Refusing password modify by {} as the proxy is read only",
+                dn
+            );
+            (
+                extended_error(
+                    LdapResultCode::UnwillingToPerform,
+                    "the proxy is read only".to_string(),
+                ),
+                vec![],
+            )
+        }
+        oid if permits_extension(&session, &app_state, oid) => {
+            debug!(%oid, "Forwarding extended operation");
+            let password_modify = if oid == OID_PASSWORD_MODIFY {
+                LdapPasswordModifyRequest::try_from(&ler).ok()
+            } else {
+                None
+            };
+            // As with writes, extended operations may have side effects and so
+            // are not retried.
+            let client = session.client();
+            match client.extended(ler, ctrl).await {
+                Ok((ext_resp, ctrl, intermediates)) => {
+                    for (intermediate, ctrl) in intermediates {
+                        let op = LdapOp::IntermediateResponse(intermediate);
+                        if !respond(&tx, LdapMsg { msgid, op, ctrl }).await {
+                            return;
+                        }
+                    }
+                    if let Some(request) = password_modify {
+                        if ext_resp.res.code == LdapResultCode::Success {
+                            password_modified(&session, &app_state, &request, &ext_resp);
+                        }
+                    }
+                    (LdapOp::ExtendedResponse(ext_resp), ctrl)
+                }
+                Err(LdapError::Transport) => {
+                    session.recover(&app_state, &client).await;
+                    let res = unavailable();
+                    (extended_error(res.code, res.message), vec![])
+                }
+                Err(e) => {
+                    error!(?e, "A client extended operation error has occurred");
+                    let op = extended_error(
+                        LdapResultCode::OperationsError,
+                        "unable to perform extended operation".to_string(),
+                    );
+                    respond_and_disconnect(
+                        &tx,
+                        LdapMsg {
+                            msgid,
+                            op,
+                            ctrl: vec![],
+                        },
+                    )
+                    .await;
+                    // Always bail.
+                    return;
+                }
+            }
+        }
+        // As the backend would answer an operation it doesn't recognise (RFC
+        // 4511 4.12).
+        oid => {
+            warn!(%oid, "Extended operation is not allowed for {}", dn);
+            (
+                extended_error(
+                    LdapResultCode::ProtocolError,
+                    format!("extended operation {} is not permitted", oid),
+                ),
+                vec![],
+            )
+        }
+    };
+
+    respond(&tx, LdapMsg { msgid, op, ctrl }).await;
+}
+
+#[derive(Debug, Clone)]
+pub enum LdapError {
+    TlsError,
+    ConnectError,
+    Transport,
+    InvalidProtocolState,
+    RebindFailed,
+    // No connection was attempted, as the backend is known to be down.
+    Unavailable,
+}
+
+// Idle connections by backend pool and bind dn, most recently checked in last.
+type IdleConnections = HashMap<(String, String), Vec<(BasicLdapClient, Instant)>>;
+
+/// Idle connections to the backends, kept so that clients that bind often
+/// don't each need a new tls connection. Connections are pooled by the DN
+/// that they were last bound as, and are always bound again with the client's
+/// own credentials when they are checked out.
+///
+/// With a neutral bind, connections are bound as a service account when their
+/// session ends, so that they aren't left idle with a client's identity, and
+/// may be checked out by any DN.
+pub struct UpstreamPool {
+    max_idle_per_dn: usize,
+    max_idle: Duration,
+    max_lifetime: Duration,
+    neutral_bind: Option<LdapBindRequest>,
+    idle: std::sync::Mutex<IdleConnections>,
+}
+
+impl UpstreamPool {
+    /// Keep up to `max_idle_per_dn` connections for each bind DN. Connections
+    /// are closed once idle for `max_idle`, or once `max_lifetime` old.
+    pub fn new(max_idle_per_dn: usize, max_idle: Duration, max_lifetime: Duration) -> Self {
+        UpstreamPool {
+            max_idle_per_dn,
+            max_idle,
+            max_lifetime,
+            neutral_bind: None,
+            idle: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Bind connections as this DN with this password before they are pooled.
+    pub fn with_neutral_bind(mut self, dn: &str, password: &str) -> Self {
+        self.neutral_bind = Some(LdapBindRequest {
+            dn: dn.to_string(),
+            cred: LdapBindCred::Simple(password.to_string()),
+        });
+        self
+    }
+
+    /// The bind that connections are pooled with, if they aren't pooled as the
+    /// DN of their session.
+    pub fn neutral_bind(&self) -> Option<&LdapBindRequest> {
+        self.neutral_bind.as_ref()
+    }
+
+    /// Take an idle connection of this backend pool that was bound with the
+    /// neutral bind.
+    pub fn checkout_neutral(&self, pool: &str) -> Option<BasicLdapClient> {
+        let dn = self.neutral_bind.as_ref()?.dn.clone();
+        self.checkout(pool, &dn)
+    }
+
+    fn reusable(&self, client: &BasicLdapClient, idle_since: Instant, now: Instant) -> bool {
+        client.is_open()
+            && now.duration_since(idle_since) < self.max_idle
+            && now.duration_since(client.connected_at) < self.max_lifetime
+    }
+
+    /// Take an idle connection of this backend pool that was bound as `dn`.
+    pub fn checkout(&self, pool: &str, dn: &str) -> Option<BasicLdapClient> {
+        let now = Instant::now();
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        let clients = idle.get_mut(&(pool.to_string(), dn.to_string()))?;
+        let found = loop {
+            match clients.pop() {
+                Some((client, idle_since)) if self.reusable(&client, idle_since, now) => {
+                    break Some(client)
+                }
+                // Too old, or closed by the backend.
+                Some(_) => continue,
+                None => break None,
+            }
+        };
+        if clients.is_empty() {
+            idle.remove(&(pool.to_string(), dn.to_string()));
+        }
+        found
+    }
+
+    /// Return a connection that is bound as `dn` to the pool. If the pool
+    /// can't take it, it is given back so that it can be closed.
+    pub fn checkin(
+        &self,
+        pool: &str,
+        dn: &str,
+        client: BasicLdapClient,
+    ) -> Option<BasicLdapClient> {
+        let now = Instant::now();
+        if self.max_idle_per_dn == 0 || !self.reusable(&client, now, now) {
+            return Some(client);
+        }
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        // Drop the connections that can no longer be used while we are here.
+        for clients in idle.values_mut() {
+            clients.retain(|(client, idle_since)| self.reusable(client, *idle_since, now));
+        }
+        idle.retain(|_, clients| !clients.is_empty());
+
+        let clients = idle.entry((pool.to_string(), dn.to_string())).or_default();
+        if clients.len() >= self.max_idle_per_dn {
+            return Some(client);
+        }
+        clients.push((client, now));
+        None
+    }
+
+    // The backend pool, bind dn and idle time of each idle connection.
+    fn idle_members(&self) -> Vec<(String, String, Instant)> {
+        let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        idle.iter()
+            .flat_map(|((pool, dn), clients)| {
+                clients
+                    .iter()
+                    .map(move |(_, idle_since)| (pool.clone(), dn.clone(), *idle_since))
+            })
+            .collect()
+    }
+
+    // Take the idle connection that has been idle since `idle_since`, unless a
+    // session has taken it already.
+    fn take(&self, pool: &str, dn: &str, idle_since: Instant) -> Option<BasicLdapClient> {
+        let key = (pool.to_string(), dn.to_string());
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        let clients = idle.get_mut(&key)?;
+        let index = clients.iter().position(|(_, since)| *since == idle_since)?;
+        let (client, _) = clients.remove(index);
+        if clients.is_empty() {
+            idle.remove(&key);
+        }
+        Some(client)
+    }
+
+    // Put a connection that was taken back, as if it had never left. If the
+    // pool has filled up in the meantime, it is given back.
+    fn restore(
+        &self,
+        pool: &str,
+        dn: &str,
+        client: BasicLdapClient,
+        idle_since: Instant,
+    ) -> Option<BasicLdapClient> {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        let clients = idle.entry((pool.to_string(), dn.to_string())).or_default();
+        if clients.len() >= self.max_idle_per_dn {
+            return Some(client);
+        }
+        let index = clients.partition_point(|(_, since)| *since <= idle_since);
+        clients.insert(index, (client, idle_since));
+        None
+    }
+
+    /// The number of idle connections.
+    pub fn len(&self) -> usize {
+        self.idle
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .map(Vec::len)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// The connection shared by the sessions of a DN with a service bind, and the
+// bind of its service account, if it has one. A password from a provider that
+// the backend refuses is forgotten, so that a rotated one is fetched next time.
+async fn service_connection(
+    app_state: &AppState,
+    pool: &BackendPool,
+    dn: &str,
+    config: &DnConfig,
+    failed: Option<&Arc<BasicLdapClient>>,
+) -> Result<Option<(Arc<BasicLdapClient>, LdapBindRequest)>, LdapError> {
+    let provider = config.service_bind_password_provider.as_ref();
+    let lbr = match (&config.service_bind_dn, provider) {
+        (Some(service_dn), Some(provider)) => match app_state.secrets.get(provider).await {
+            Ok(password) => LdapBindRequest {
+                dn: service_dn.clone(),
+                cred: LdapBindCred::Simple(password.expose().to_string()),
+            },
+            Err(e) => {
+                error!(%e, "Unable to fetch the service account password of {}", dn);
+                app_state
+                    .metrics
+                    .incr("secret_fetch_errors_total", &[("kind", "service_bind")]);
+                return Err(LdapError::RebindFailed);
+            }
+        },
+        _ => match config.service_bind() {
+            Some(lbr) => lbr,
+            None => return Ok(None),
+        },
+    };
+    match app_state
+        .service_connections
+        .get(app_state, pool, &lbr, failed)
+        .await
+    {
+        Ok(client) => Ok(Some((client, lbr))),
+        Err(e) => {
+            if let (Some(provider), LdapError::RebindFailed) = (provider, &e) {
+                app_state.secrets.invalidate(provider);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Backend connections bound as service accounts, by backend pool and service
+/// DN. Each is shared by every session whose DN has that service bind, as
+/// operations on a connection are multiplexed by their msgid.
+#[derive(Default)]
+pub struct ServiceConnections {
+    clients: Mutex<HashMap<(String, String), Arc<BasicLdapClient>>>,
+}
+
+impl ServiceConnections {
+    /// The open connection of this pool that is bound with `lbr`, connecting
+    /// and binding a new one if there isn't one, or if it is the one that
+    /// failed.
+    async fn get(
+        &self,
+        app_state: &AppState,
+        pool: &BackendPool,
+        lbr: &LdapBindRequest,
+        failed: Option<&Arc<BasicLdapClient>>,
+    ) -> Result<Arc<BasicLdapClient>, LdapError> {
+        let key = (pool.name.clone(), lbr.dn.clone());
+        let mut clients = self.clients.lock().await;
+        if let Some(client) = clients.get(&key).filter(|client| {
+            client.is_open() && !failed.is_some_and(|failed| Arc::ptr_eq(client, failed))
+        }) {
+            return Ok(client.clone());
+        }
+
+        let client = BasicLdapClient::connect(app_state, pool).await?;
+        let (bind_resp, _) = client.bind(lbr.clone(), vec![]).await?;
+        if bind_resp.res.code != LdapResultCode::Success {
+            error!(code = ?bind_resp.res.code, pool = %pool.name, "Unable to bind the service account {}", lbr.dn);
+            return Err(LdapError::RebindFailed);
+        }
+        debug!(backend = %client.backend(), "Bound a shared connection as {}", lbr.dn);
+        let client = Arc::new(client);
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
+}
+
+// Operations that are waiting on responses from the ldap server, by msgid. This
+// is None once the connection has closed.
+type PendingOperations = Arc<Mutex<Option<HashMap<i32, mpsc::UnboundedSender<BackendMsg>>>>>;
+
+// Ask the backend to start tls on this connection (RFC 4511 4.14), returning it
+// once the backend is ready for the handshake.
+async fn start_tls(
+    tcpstream: TcpStream,
+    max_ber_size: Option<usize>,
+    timeout: Duration,
+) -> Result<TcpStream, LdapError> {
+    let mut framed = Framed::new(tcpstream, BackendCodec::new(max_ber_size));
+    let request = LdapMsg {
+        msgid: 1,
+        op: LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: OID_STARTTLS.to_string(),
+            value: None,
+        }),
+        ctrl: vec![],
+    };
+    if let Err(e) = framed.send(request).await {
+        error!(?e, "unable to send starttls");
+        return Err(LdapError::Transport);
+    }
+
+    let response = framed
+        .next()
+        .map(|next| next.map(|res| res.map(|backend| backend.msg)));
+    match tokio::time::timeout(timeout, response).await {
+        Ok(Some(Ok(LdapMsg {
+            msgid: 1,
+            op: LdapOp::ExtendedResponse(resp),
+            ..
+        }))) if resp.res.code == LdapResultCode::Success => {}
+        Ok(Some(Ok(LdapMsg {
+            op: LdapOp::ExtendedResponse(resp),
+            ..
+        }))) => {
+            error!(code = ?resp.res.code, message = %resp.res.message, "backend refused starttls");
+            return Err(LdapError::TlsError);
+        }
+        Err(_) => {
+            warn!("timeout waiting for starttls response");
+            return Err(LdapError::TlsError);
+        }
+        other => {
+            error!(?other, "unexpected starttls response");
+            return Err(LdapError::InvalidProtocolState);
+        }
+    }
+
+    let parts = framed.into_parts();
+    // Nothing may be sent by the backend before the handshake.
+    if !parts.read_buf.is_empty() {
+        error!("backend sent data after accepting starttls");
+        return Err(LdapError::InvalidProtocolState);
+    }
+    Ok(parts.io)
+}
+
+// Check that the backend's certificate has one of the pinned public keys.
+fn verify_spki_pin(
+    cert: Option<&X509Ref>,
+    pins: &[SpkiPin],
+    backend: &Backend,
+) -> Result<(), LdapError> {
+    let pin = cert.and_then(|cert| SpkiPin::of(cert).ok());
+    match pin {
+        Some(pin) if pins.contains(&pin) => Ok(()),
+        Some(pin) => {
+            error!(backend = %backend.url, %pin, "backend certificate does not match any ldap_spki_pins");
+            Err(LdapError::TlsError)
+        }
+        None => {
+            error!(backend = %backend.url, "unable to read the public key of the backend certificate");
+            Err(LdapError::TlsError)
+        }
+    }
+}
+
+/// A connection to the backend ldap server. Many operations may be in flight
+/// at once, and responses are routed back to the operation by msgid.
+pub struct BasicLdapClient {
+    w: Arc<Mutex<FramedWrite<CW, BackendCodec>>>,
+    pending: PendingOperations,
+    reader: JoinHandle<()>,
+    msg_counter: AtomicI32,
+    backend: Url,
+    connected_at: Instant,
+    rewrite: Option<Arc<DnRewrite>>,
+    // Counts the open connections to the backend.
+    open_connections: Arc<AtomicUsize>,
+    timeouts: BackendTimeouts,
+    // Where the times of binds are recorded, for connections to an address.
+    latency: Option<(SocketAddr, Arc<BackendLatency>)>,
+}
 
-                None
-            }
-            // Unknown message handler.
-            (_, msg) => {
-                debug!(?msg);
-                // Return a disconnect.
+impl Drop for BasicLdapClient {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Read responses from the ldap server, and route them to the operation that is
+// waiting for them.
+async fn client_demux(mut r: FramedRead<CR, BackendCodec>, pending: PendingOperations) {
+    while let Some(frame) = r.next().await {
+        let response = match frame {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!(?e, "unable to receive from ldap server");
                 break;
             }
         };
 
-        if let Some(next_state) = next_state {
-            // Update the client state, dropping any former state.
-            state = next_state;
+        // These are followed by more responses to the same operation.
+        let is_final = !matches!(
+            response.msg.op,
+            LdapOp::SearchResultEntry(_)
+                | LdapOp::SearchResultReference(_)
+                | LdapOp::IntermediateResponse(_)
+        );
+
+        let mut pending_guard = pending.lock().await;
+        let Some(pending_ops) = pending_guard.as_mut() else {
+            break;
+        };
+        let msgid = response.msg.msgid;
+        match pending_ops.get(&msgid) {
+            Some(op_tx) => {
+                if op_tx.send(response).is_err() || is_final {
+                    // Either the operation is complete, or it is no longer interested.
+                    pending_ops.remove(&msgid);
+                }
+            }
+            None => {
+                warn!(%msgid, "unsolicited message from ldap server");
+                trace!(?response);
+            }
         }
     }
-    info!("Disconnect for {}", client_address);
+    // Dropping the senders lets all the waiting operations know the connection
+    // is gone, and no new operations may start.
+    *pending.lock().await = None;
+    debug!("connection closed");
 }
 
-#[derive(Debug, Clone)]
-pub enum LdapError {
-    TlsError,
-    ConnectError,
-    Transport,
-    InvalidProtocolState,
-}
+impl BasicLdapClient {
+    /// The backend server this client is connected to.
+    pub fn backend(&self) -> &Url {
+        &self.backend
+    }
 
-pub struct BasicLdapClient {
-    r: FramedRead<CR, LdapCodec>,
-    w: FramedWrite<CW, LdapCodec>,
-    msg_counter: i32,
-}
+    /// If the connection to the backend is still open.
+    pub fn is_open(&self) -> bool {
+        !self.reader.is_finished()
+    }
 
-impl BasicLdapClient {
-    fn next_msgid(&mut self) -> i32 {
-        self.msg_counter += 1;
-        self.msg_counter
+    fn next_msgid(&self) -> i32 {
+        self.msg_counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Connect to a backend of the pool, chosen by the pool's strategy. Backends
+    /// that can't be reached are skipped in favour of the next one.
+    ///
+    /// If the circuit breakers of every address in the pool are open, this fails
+    /// immediately with `LdapError::Unavailable`. Otherwise if no backend can be
+    /// reached, this is retried as the retry policy allows.
+    pub async fn connect(app_state: &AppState, pool: &BackendPool) -> Result<Self, LdapError> {
+        let mut retry = 0;
+        loop {
+            match Self::connect_once(app_state, pool).await {
+                Err(LdapError::ConnectError) if retry < app_state.retry.attempts => {
+                    tokio::time::sleep(app_state.retry.delay(retry)).await;
+                    retry += 1;
+                }
+                // The failures of our earlier attempts opened the breakers.
+                Err(LdapError::Unavailable) if retry > 0 => return Err(LdapError::ConnectError),
+                res => return res,
+            }
+        }
+    }
+
+    async fn connect_once(app_state: &AppState, pool: &BackendPool) -> Result<Self, LdapError> {
+        let mut all_unavailable = true;
+        for backend in pool.order() {
+            let labels = [
+                ("pool", pool.name.as_str()),
+                ("backend", backend.url.as_str()),
+            ];
+            match Self::build(
+                backend,
+                pool,
+                app_state.max_proxy_ber_size,
+                &app_state.breakers,
+            )
+            .await
+            {
+                Ok(client) => {
+                    app_state.metrics.incr("backend_connections_total", &labels);
+                    return Ok(client.with_dn_rewrite(app_state.dn_rewrite.clone()));
+                }
+                Err(LdapError::Unavailable) => {
+                    debug!(pool = %pool.name, backend = %backend.url, "backend circuit breakers are open");
+                }
+                Err(e) => {
+                    all_unavailable = false;
+                    warn!(?e, pool = %pool.name, backend = %backend.url, "unable to connect to backend");
+                    app_state
+                        .metrics
+                        .incr("backend_connection_errors_total", &labels);
+                }
+            }
+        }
+        if all_unavailable {
+            Err(LdapError::Unavailable)
+        } else {
+            Err(LdapError::ConnectError)
+        }
     }
 
     pub async fn build(
-        addrs: &[SocketAddr],
-        tls_connector: &SslConnector,
+        backend: &Backend,
+        pool: &BackendPool,
         max_ber_size: Option<usize>,
+        breakers: &CircuitBreakers,
     ) -> Result<Self, LdapError> {
-        let timeout = Duration::from_secs(5);
+        if let Some(path) = backend.socket_path.as_ref() {
+            return Self::build_unix(backend, path, pool, max_ber_size).await;
+        }
+
+        if !backend.is_resolved() {
+            // We haven't been able to resolve this backend yet, so try now.
+            backend.resolve().await;
+        }
+
+        let addrs = backend.addrs_in_order();
+        if addrs.is_empty() {
+            return Err(LdapError::ConnectError);
+        }
+
+        // Skip the addresses that have recently failed.
+        let addrs: Vec<_> = addrs
+            .into_iter()
+            .filter(|addr| breakers.allow(addr))
+            .collect();
+        if addrs.is_empty() {
+            return Err(LdapError::Unavailable);
+        }
+
+        Self::build_to(backend, &addrs, pool, max_ber_size, breakers).await
+    }
 
+    /// Connect to the first of these addresses of the backend that can be
+    /// reached, whether or not their circuit breakers are open.
+    pub async fn build_to(
+        backend: &Backend,
+        addrs: &[SocketAddr],
+        pool: &BackendPool,
+        max_ber_size: Option<usize>,
+        breakers: &CircuitBreakers,
+    ) -> Result<Self, LdapError> {
+        let timeout = pool.timeouts.connect;
         let mut aiter = addrs.iter();
 
-        let tcpstream = loop {
+        let (addr, tcpstream) = loop {
             if let Some(addr) = aiter.next() {
                 let sleep = tokio::time::sleep(timeout);
                 tokio::pin!(sleep);
+                let started = Instant::now();
                 tokio::select! {
                     maybe_stream = TcpStream::connect(addr) => {
                         match maybe_stream {
                             Ok(t) => {
                                 trace!(?addr, "connection established");
-                                break t;
+                                backend.latency().record(*addr, Stage::Connect, Some(started.elapsed()));
+                                break (addr, t);
                             }
                             Err(e) => {
                                 trace!(?addr, ?e, "error");
+                                breakers.record_failure(addr);
+                                backend.latency().record(*addr, Stage::Connect, None);
                                 continue;
                             }
                         }
                     }
                     _ = &mut sleep => {
                         warn!(?addr, "timeout");
+                        breakers.record_failure(addr);
+                        backend.latency().record(*addr, Stage::Connect, None);
                         continue;
                     }
                 }
@@ -477,146 +5552,699 @@ impl BasicLdapClient {
                 return Err(LdapError::ConnectError);
             }
         };
+        pool.tcp.apply(&tcpstream);
 
-        let mut tlsstream = Ssl::new(tls_connector.context())
-            .and_then(|tls_obj| SslStream::new(tls_obj, tcpstream))
-            .map_err(|e| {
-                error!(?e, "openssl");
-                LdapError::TlsError
-            })?;
-
-        SslStream::connect(Pin::new(&mut tlsstream))
+        // A backend that accepts connections but fails the handshake is just as
+        // unusable, so this counts towards tripping its breaker too.
+        let started = Instant::now();
+        let handshake = Self::handshake(backend, tcpstream, pool, max_ber_size, timeout);
+        let handshake = tokio::time::timeout(timeout, handshake)
             .await
-            .map_err(|e| {
-                error!(?e, "openssl");
-                LdapError::TlsError
-            })?;
+            .unwrap_or_else(|_| {
+                warn!(?addr, "timeout during the handshake");
+                Err(LdapError::TlsError)
+            });
+        let stream = match handshake {
+            Ok(stream) => {
+                breakers.record_success(addr);
+                backend
+                    .latency()
+                    .record(*addr, Stage::Handshake, Some(started.elapsed()));
+                stream
+            }
+            Err(e) => {
+                breakers.record_failure(addr);
+                backend.latency().record(*addr, Stage::Handshake, None);
+                return Err(e);
+            }
+        };
+
+        let mut client = Self::from_stream(backend, stream, pool, max_ber_size);
+        client.latency = Some((*addr, backend.latency().clone()));
+        Ok(client)
+    }
 
-        let (r, w) = tokio::io::split(tlsstream);
+    // Connect to the unix socket of an ldapi backend. There are no addresses,
+    // so there are no circuit breakers either.
+    async fn build_unix(
+        backend: &Backend,
+        path: &Path,
+        pool: &BackendPool,
+        max_ber_size: Option<usize>,
+    ) -> Result<Self, LdapError> {
+        let stream =
+            match tokio::time::timeout(pool.timeouts.connect, UnixStream::connect(path)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    warn!(?e, path = %path.display(), "unable to connect to ldapi socket");
+                    return Err(LdapError::ConnectError);
+                }
+                Err(_) => {
+                    warn!(path = %path.display(), "timeout");
+                    return Err(LdapError::ConnectError);
+                }
+            };
+        Ok(Self::from_stream(
+            backend,
+            Box::new(stream),
+            pool,
+            max_ber_size,
+        ))
+    }
+
+    fn from_stream(
+        backend: &Backend,
+        stream: Box<dyn BackendStream>,
+        pool: &BackendPool,
+        max_ber_size: Option<usize>,
+    ) -> Self {
+        let (r, w) = tokio::io::split(stream);
 
-        let w = FramedWrite::new(w, LdapCodec::new(max_ber_size));
-        let r = FramedRead::new(r, LdapCodec::new(max_ber_size));
+        let w = FramedWrite::new(w, BackendCodec::new(max_ber_size));
+        let r = FramedRead::new(r, BackendCodec::new(max_ber_size));
 
-        info!("Connected to remote ldap server");
-        Ok(BasicLdapClient {
-            r,
-            w,
-            msg_counter: 0,
-        })
+        let pending: PendingOperations = Arc::new(Mutex::new(Some(HashMap::new())));
+        // The reader logs in the span of the session that connected.
+        let reader = tokio::spawn(client_demux(r, pending.clone()).in_current_span());
+
+        info!(backend = %backend.url, "Connected to remote ldap server");
+        let open_connections = backend.open_connections().clone();
+        open_connections.fetch_add(1, Ordering::Relaxed);
+        BasicLdapClient {
+            w: Arc::new(Mutex::new(w)),
+            pending,
+            reader,
+            msg_counter: AtomicI32::new(0),
+            backend: backend.url.clone(),
+            connected_at: Instant::now(),
+            rewrite: None,
+            open_connections,
+            timeouts: pool.timeouts,
+            latency: None,
+        }
     }
 
-    pub async fn bind(
-        &mut self,
-        lbr: LdapBindRequest,
+    // Start TLS on a new connection to a backend, if it uses it.
+    async fn handshake(
+        backend: &Backend,
+        tcpstream: TcpStream,
+        pool: &BackendPool,
+        max_ber_size: Option<usize>,
+        timeout: Duration,
+    ) -> Result<Box<dyn BackendStream>, LdapError> {
+        let tcpstream = match backend.transport {
+            Transport::StartTls => start_tls(tcpstream, max_ber_size, timeout).await?,
+            Transport::Tls | Transport::Plain => tcpstream,
+        };
+
+        if backend.transport != Transport::Plain {
+            // Ipv6 literals are bracketed in urls.
+            let hostname = backend
+                .hostname
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+            let name = pool.tls.sni_name.as_deref().unwrap_or(hostname);
+            let (tlsstream, peer) = pool
+                .tls_params
+                .connect(tcpstream, name, pool.tls.verify_hostname)
+                .await?;
+            if !pool.tls.spki_pins.is_empty() {
+                verify_spki_pin(peer.as_deref(), &pool.tls.spki_pins, backend)?;
+            }
+            Ok(tlsstream)
+        } else {
+            Ok(Box::new(tcpstream))
+        }
+    }
+
+    /// Rewrite the DNs of requests to this client's backend, and of its
+    /// responses.
+    pub fn with_dn_rewrite(mut self, rewrite: Option<Arc<DnRewrite>>) -> Self {
+        self.rewrite = rewrite;
+        self
+    }
+
+    // Send a request, returning the stream of responses to it.
+    async fn start(
+        &self,
+        mut op: LdapOp,
         ctrl: Vec<LdapControl>,
-    ) -> Result<(LdapBindResponse, Vec<LdapControl>), LdapError> {
+        raw_controls: Vec<RawControl>,
+    ) -> Result<(i32, mpsc::UnboundedReceiver<BackendMsg>), LdapError> {
+        if let Some(rewrite) = self.rewrite.as_ref() {
+            rewrite.request(&mut op);
+        }
         let ck_msgid = self.next_msgid();
+        // The backend's msgid, which ties the span of the request to the
+        // backend's own logs. Callers are in an upstream span, so that this
+        // doesn't replace the client's msgid on the operation's span.
+        Span::current().record("msgid", ck_msgid);
+        let (op_tx, op_rx) = mpsc::unbounded_channel();
 
-        let msg = LdapMsg {
-            msgid: ck_msgid,
-            op: LdapOp::BindRequest(lbr),
-            ctrl,
+        // Register before sending so that we can't miss the response.
+        match self.pending.lock().await.as_mut() {
+            Some(pending_ops) => {
+                pending_ops.insert(ck_msgid, op_tx);
+            }
+            None => {
+                error!("connection to ldap server is closed");
+                return Err(LdapError::Transport);
+            }
+        }
+
+        let msg = BackendMsg {
+            msg: LdapMsg {
+                msgid: ck_msgid,
+                op,
+                ctrl,
+            },
+            raw_controls,
         };
 
-        self.w.send(msg).await.map_err(|e| {
+        if let Err(e) = self.w.lock().await.send(msg).await {
             error!(?e, "unable to transmit to ldap server");
-            LdapError::Transport
-        })?;
+            if let Some(pending_ops) = self.pending.lock().await.as_mut() {
+                pending_ops.remove(&ck_msgid);
+            }
+            return Err(LdapError::Transport);
+        }
 
-        match self.r.next().await {
-            Some(Ok(LdapMsg {
-                msgid,
-                op: LdapOp::BindResponse(bind_resp),
-                ctrl,
-            })) => {
-                if msgid == ck_msgid {
-                    Ok((bind_resp, ctrl))
-                } else {
-                    error!("invalid msgid, sequence error.");
-                    Err(LdapError::InvalidProtocolState)
+        Ok((ck_msgid, op_rx))
+    }
+
+    // A response from the backend, as the client sees it.
+    fn received(&self, mut msg: LdapMsg) -> LdapMsg {
+        if let Some(rewrite) = self.rewrite.as_ref() {
+            rewrite.response(&mut msg.op);
+        }
+        msg
+    }
+
+    // Wait for the next message of a response, for no longer than the read
+    // timeout. None once the connection has closed.
+    async fn recv(
+        &self,
+        op_rx: &mut mpsc::UnboundedReceiver<BackendMsg>,
+    ) -> Result<Option<LdapMsg>, LdapError> {
+        // The controls that can't be decoded are only relayed by streamed
+        // searches and binds.
+        Ok(self.recv_raw(op_rx).await?.map(|response| response.msg))
+    }
+
+    async fn recv_raw(
+        &self,
+        op_rx: &mut mpsc::UnboundedReceiver<BackendMsg>,
+    ) -> Result<Option<BackendMsg>, LdapError> {
+        match self.timeouts.read {
+            Some(limit) => tokio::time::timeout(limit, op_rx.recv())
+                .await
+                .map_err(|_| {
+                    warn!(backend = %self.backend, "backend exceeded the read timeout");
+                    LdapError::Transport
+                }),
+            None => Ok(op_rx.recv().await),
+        }
+    }
+
+    async fn request(&self, op: LdapOp, ctrl: Vec<LdapControl>) -> Result<LdapMsg, LdapError> {
+        Ok(self.request_raw(op, ctrl, Vec::new()).await?.0.msg)
+    }
+
+    // As request, with controls that ldap3_proto can't encode, and the
+    // controls of the response that it can't decode, and the intermediate
+    // responses that came before it.
+    #[tracing::instrument(name = "upstream", level = "debug", skip_all, fields(backend = %self.backend, msgid))]
+    async fn request_raw(
+        &self,
+        op: LdapOp,
+        ctrl: Vec<LdapControl>,
+        raw_controls: Vec<RawControl>,
+    ) -> Result<(BackendMsg, Intermediates), LdapError> {
+        let (_, mut op_rx) = self.start(op, ctrl, raw_controls).await?;
+
+        let responses = async {
+            let mut intermediates = Vec::new();
+            loop {
+                match self.recv_raw(&mut op_rx).await? {
+                    Some(BackendMsg {
+                        msg:
+                            LdapMsg {
+                                op: LdapOp::IntermediateResponse(intermediate),
+                                ctrl,
+                                ..
+                            },
+                        ..
+                    }) => intermediates.push((intermediate, ctrl)),
+                    Some(response) => break Ok((response, intermediates)),
+                    None => {
+                        error!("connection closed");
+                        break Err(LdapError::Transport);
+                    }
                 }
             }
-            Some(Ok(msg)) => {
+        };
+        let (response, intermediates) = match self.timeouts.operation {
+            Some(limit) => tokio::time::timeout(limit, responses)
+                .await
+                .unwrap_or_else(|_| {
+                    warn!(backend = %self.backend, "backend exceeded the operation timeout");
+                    Err(LdapError::Transport)
+                })?,
+            None => responses.await?,
+        };
+        let response = BackendMsg {
+            msg: self.received(response.msg),
+            raw_controls: response.raw_controls,
+        };
+        Ok((response, intermediates))
+    }
+
+    // This has no response, the backend simply closes the connection.
+    pub async fn unbind(&self) {
+        let msg = LdapMsg {
+            msgid: self.next_msgid(),
+            op: LdapOp::UnbindRequest,
+            ctrl: vec![],
+        };
+        if let Err(e) = self.w.lock().await.send(msg).await {
+            debug!(?e, "unable to send unbind to ldap server");
+        }
+    }
+
+    pub async fn bind(
+        &self,
+        lbr: LdapBindRequest,
+        ctrl: Vec<LdapControl>,
+    ) -> Result<(LdapBindResponse, Vec<LdapControl>), LdapError> {
+        let (bind_resp, ctrl, _) = self.bind_raw(lbr, ctrl).await?;
+        Ok((bind_resp, ctrl))
+    }
+
+    /// As bind, with the controls of the response that can't be decoded, such
+    /// as the password policy response.
+    pub async fn bind_raw(
+        &self,
+        lbr: LdapBindRequest,
+        ctrl: Vec<LdapControl>,
+    ) -> Result<(LdapBindResponse, Vec<LdapControl>, Vec<RawControl>), LdapError> {
+        let started = Instant::now();
+        let response = self
+            .request_raw(LdapOp::BindRequest(lbr), ctrl, Vec::new())
+            .await;
+        if let Some((addr, latency)) = &self.latency {
+            // Refused credentials are the client's failure, not the backend's.
+            let answered = match &response {
+                Ok((BackendMsg { msg, .. }, _)) => !matches!(
+                    &msg.op,
+                    LdapOp::BindResponse(LdapBindResponse { res, .. })
+                        if matches!(res.code, LdapResultCode::Busy | LdapResultCode::Unavailable | LdapResultCode::Other)
+                ),
+                Err(_) => false,
+            };
+            latency.record(*addr, Stage::Bind, answered.then(|| started.elapsed()));
+        }
+        match response?.0 {
+            BackendMsg {
+                msg:
+                    LdapMsg {
+                        msgid: _,
+                        op: LdapOp::BindResponse(bind_resp),
+                        ctrl,
+                    },
+                raw_controls,
+            } => Ok((bind_resp, ctrl, raw_controls)),
+            msg => {
+                trace!(?msg);
+                Err(LdapError::InvalidProtocolState)
+            }
+        }
+    }
+
+    pub async fn compare(
+        &self,
+        lcr: LdapCompareRequest,
+        ctrl: Vec<LdapControl>,
+    ) -> Result<(LdapResult, Vec<LdapControl>), LdapError> {
+        match self.request(LdapOp::CompareRequest(lcr), ctrl).await? {
+            LdapMsg {
+                msgid: _,
+                op: LdapOp::CompareResult(compare_res),
+                ctrl,
+            } => Ok((compare_res, ctrl)),
+            msg => {
                 trace!(?msg);
                 Err(LdapError::InvalidProtocolState)
             }
-            Some(Err(e)) => {
-                error!(?e, "unable to receive from ldap server");
-                Err(LdapError::Transport)
+        }
+    }
+
+    pub async fn add(
+        &self,
+        lar: LdapAddRequest,
+        ctrl: Vec<LdapControl>,
+        raw_controls: Vec<RawControl>,
+    ) -> Result<(LdapResult, Vec<LdapControl>), LdapError> {
+        let request = self.request_raw(LdapOp::AddRequest(lar), ctrl, raw_controls);
+        match request.await?.0.msg {
+            LdapMsg {
+                msgid: _,
+                op: LdapOp::AddResponse(add_res),
+                ctrl,
+            } => Ok((add_res, ctrl)),
+            msg => {
+                trace!(?msg);
+                Err(LdapError::InvalidProtocolState)
             }
-            None => {
-                error!("connection closed");
-                Err(LdapError::Transport)
+        }
+    }
+
+    pub async fn modify(
+        &self,
+        lmr: LdapModifyRequest,
+        ctrl: Vec<LdapControl>,
+        raw_controls: Vec<RawControl>,
+    ) -> Result<(LdapResult, Vec<LdapControl>), LdapError> {
+        let request = self.request_raw(LdapOp::ModifyRequest(lmr), ctrl, raw_controls);
+        match request.await?.0.msg {
+            LdapMsg {
+                msgid: _,
+                op: LdapOp::ModifyResponse(modify_res),
+                ctrl,
+            } => Ok((modify_res, ctrl)),
+            msg => {
+                trace!(?msg);
+                Err(LdapError::InvalidProtocolState)
             }
         }
     }
 
-    pub async fn search(
-        &mut self,
-        sr: LdapSearchRequest,
+    pub async fn delete(
+        &self,
+        dn: String,
         ctrl: Vec<LdapControl>,
-    ) -> Result<
-        (
-            Vec<(LdapSearchResultEntry, Vec<LdapControl>)>,
-            LdapResult,
-            Vec<LdapControl>,
-        ),
-        LdapError,
-    > {
-        let ck_msgid = self.next_msgid();
+        raw_controls: Vec<RawControl>,
+    ) -> Result<(LdapResult, Vec<LdapControl>), LdapError> {
+        let request = self.request_raw(LdapOp::DelRequest(dn), ctrl, raw_controls);
+        match request.await?.0.msg {
+            LdapMsg {
+                msgid: _,
+                op: LdapOp::DelResponse(del_res),
+                ctrl,
+            } => Ok((del_res, ctrl)),
+            msg => {
+                trace!(?msg);
+                Err(LdapError::InvalidProtocolState)
+            }
+        }
+    }
+
+    pub async fn modify_dn(
+        &self,
+        lmdr: LdapModifyDNRequest,
+        ctrl: Vec<LdapControl>,
+        raw_controls: Vec<RawControl>,
+    ) -> Result<(LdapResult, Vec<LdapControl>), LdapError> {
+        let request = self.request_raw(LdapOp::ModifyDNRequest(lmdr), ctrl, raw_controls);
+        match request.await?.0.msg {
+            LdapMsg {
+                msgid: _,
+                op: LdapOp::ModifyDNResponse(modify_dn_res),
+                ctrl,
+            } => Ok((modify_dn_res, ctrl)),
+            msg => {
+                trace!(?msg);
+                Err(LdapError::InvalidProtocolState)
+            }
+        }
+    }
+
+    // The request and response values are opaque to us, and are relayed as is.
+    /// An extended operation, with the intermediate responses that the
+    /// backend sent before its result (RFC 4511 4.13).
+    pub async fn extended(
+        &self,
+        ler: LdapExtendedRequest,
+        ctrl: Vec<LdapControl>,
+    ) -> Result<(LdapExtendedResponse, Vec<LdapControl>, Intermediates), LdapError> {
+        let (response, intermediates) = self
+            .request_raw(LdapOp::ExtendedRequest(ler), ctrl, Vec::new())
+            .await?;
+        match response.msg {
+            LdapMsg {
+                msgid: _,
+                op: LdapOp::ExtendedResponse(ext_resp),
+                ctrl,
+            } => Ok((ext_resp, ctrl, intermediates)),
+            msg => {
+                trace!(?msg);
+                Err(LdapError::InvalidProtocolState)
+            }
+        }
+    }
 
+    // Stop waiting for the responses to an operation, and ask the backend to
+    // stop processing it. This doesn't borrow the client, so that it can be
+    // spawned.
+    fn abandon(&self, msgid: i32) -> impl Future<Output = ()> + Send + 'static {
+        let (w, pending) = (self.w.clone(), self.pending.clone());
         let msg = LdapMsg {
-            msgid: ck_msgid,
-            op: LdapOp::SearchRequest(sr),
-            ctrl,
+            msgid: self.next_msgid(),
+            op: LdapOp::AbandonRequest(msgid),
+            ctrl: vec![],
         };
+        async move {
+            if let Some(pending_ops) = pending.lock().await.as_mut() {
+                pending_ops.remove(&msgid);
+            }
+            if let Err(e) = w.lock().await.send(msg).await {
+                debug!(?e, "unable to send abandon to ldap server");
+            }
+        }
+    }
 
-        self.w.send(msg).await.map_err(|e| {
-            error!(?e, "unable to transmit to ldap server");
-            LdapError::Transport
-        })?;
+    /// Search, buffering the resulting entries. If there are more than
+    /// `max_entries` entries the search is abandoned, and the entries so far are
+    /// returned with sizeLimitExceeded. Likewise if it takes longer than
+    /// `time_limit`, or the operation timeout, with timeLimitExceeded.
+    pub async fn search(
+        &self,
+        sr: LdapSearchRequest,
+        ctrl: Vec<LdapControl>,
+        max_entries: Option<usize>,
+        time_limit: Option<Duration>,
+    ) -> Result<SearchResults, LdapError> {
+        let limits = (max_entries, time_limit);
+        let relay = |_, _| std::future::ready(true);
+        self.search_relayed(sr, ctrl, limits, usize::MAX, relay)
+            .await
+            .0
+    }
+
+    /// Search like `search`, but once more than `buffer` entries have
+    /// arrived, pass them and each later entry to `relay` as they arrive,
+    /// rather than collecting them. If `relay` fails the search is abandoned.
+    /// Returns the results, without the relayed entries, and how many of them
+    /// there were.
+    #[tracing::instrument(name = "upstream", level = "debug", skip_all, fields(backend = %self.backend, msgid))]
+    pub async fn search_relayed<F, Fut>(
+        &self,
+        sr: LdapSearchRequest,
+        ctrl: Vec<LdapControl>,
+        (max_entries, time_limit): (Option<usize>, Option<Duration>),
+        buffer: usize,
+        mut relay: F,
+    ) -> (Result<SearchResults, LdapError>, usize)
+    where
+        F: FnMut(LdapSearchResultEntry, Vec<LdapControl>) -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        let deadline = time_limit
+            .into_iter()
+            .chain(self.timeouts.operation)
+            .min()
+            .map(|limit| tokio::time::Instant::now() + limit);
+        let (search_msgid, mut op_rx) = match self
+            .start(LdapOp::SearchRequest(sr), ctrl, Vec::new())
+            .await
+        {
+            Ok(started) => started,
+            Err(e) => return (Err(e), 0),
+        };
+        let mut in_flight = InFlight {
+            client: self,
+            msgid: search_msgid,
+            complete: false,
+        };
 
         let mut entries = Vec::new();
-        loop {
-            match self.r.next().await {
+        let mut references = Vec::new();
+        let mut intermediates = Vec::new();
+        let mut relayed = 0;
+        let results = loop {
+            let next = match deadline {
+                Some(deadline) => {
+                    match tokio::time::timeout_at(deadline, self.recv(&mut op_rx)).await {
+                        Ok(next) => next,
+                        Err(_) => {
+                            let count = entries.len() + relayed;
+                            warn!(entries = %count, "search exceeded the time limit");
+                            self.abandon(search_msgid).await;
+                            let result = LdapResult {
+                                code: LdapResultCode::TimeLimitExceeded,
+                                matcheddn: "".to_string(),
+                                message: "".to_string(),
+                                referral: vec![],
+                            };
+                            break Ok(SearchResults {
+                                entries,
+                                references,
+                                intermediates,
+                                result,
+                                ctrl: vec![],
+                            });
+                        }
+                    }
+                }
+                None => self.recv(&mut op_rx).await,
+            };
+            let next = match next {
+                Ok(next) => next,
+                Err(e) => break Err(e),
+            };
+            match next.map(|msg| self.received(msg)) {
                 // This terminates the iteration of entries.
-                Some(Ok(LdapMsg {
-                    msgid,
-                    op: LdapOp::SearchResultDone(search_res),
+                Some(LdapMsg {
+                    msgid: _,
+                    op: LdapOp::SearchResultDone(result),
                     ctrl,
-                })) => {
-                    if msgid == ck_msgid {
-                        break Ok((entries, search_res, ctrl));
-                    } else {
-                        error!("invalid msgid, sequence error.");
-                        break Err(LdapError::InvalidProtocolState);
-                    }
+                }) => {
+                    break Ok(SearchResults {
+                        entries,
+                        references,
+                        intermediates,
+                        result,
+                        ctrl,
+                    });
                 }
-                Some(Ok(LdapMsg {
-                    msgid,
+                Some(LdapMsg {
+                    msgid: _,
+                    op: LdapOp::SearchResultEntry(_),
+                    ctrl: _,
+                }) if max_entries.is_some_and(|max| entries.len() + relayed >= max) => {
+                    let count = entries.len() + relayed;
+                    warn!(entries = %count, "search exceeded the entry limit");
+                    self.abandon(search_msgid).await;
+                    let result = LdapResult {
+                        code: LdapResultCode::SizeLimitExceeded,
+                        matcheddn: "".to_string(),
+                        message: "".to_string(),
+                        referral: vec![],
+                    };
+                    break Ok(SearchResults {
+                        entries,
+                        references,
+                        intermediates,
+                        result,
+                        ctrl: vec![],
+                    });
+                }
+                Some(LdapMsg {
+                    msgid: _,
                     op: LdapOp::SearchResultEntry(search_entry),
                     ctrl,
-                })) => {
-                    if msgid == ck_msgid {
-                        entries.push((search_entry, ctrl))
-                    } else {
-                        error!("invalid msgid, sequence error.");
-                        break Err(LdapError::InvalidProtocolState);
+                }) => {
+                    entries.push((search_entry, ctrl));
+                    if entries.len() + relayed > buffer {
+                        relayed += entries.len();
+                        for (entry, ctrl) in entries.drain(..) {
+                            if !relay(entry, ctrl).await {
+                                self.abandon(search_msgid).await;
+                                in_flight.complete = true;
+                                return (Err(LdapError::Transport), relayed);
+                            }
+                        }
                     }
                 }
-                Some(Ok(msg)) => {
+                Some(LdapMsg {
+                    msgid: _,
+                    op: LdapOp::SearchResultReference(reference),
+                    ctrl,
+                }) => references.push((reference, ctrl)),
+                Some(LdapMsg {
+                    msgid: _,
+                    op: LdapOp::IntermediateResponse(intermediate),
+                    ctrl,
+                }) => intermediates.push((intermediate, ctrl)),
+                Some(msg) => {
                     trace!(?msg);
                     break Err(LdapError::InvalidProtocolState);
                 }
-                Some(Err(e)) => {
-                    error!(?e, "unable to receive from ldap server");
-                    break Err(LdapError::Transport);
-                }
                 None => {
                     error!("connection closed");
                     break Err(LdapError::Transport);
                 }
             }
+        };
+        in_flight.complete = true;
+        (results, relayed)
+    }
+
+    // Start a search whose responses are relayed as they arrive, rather than
+    // collected. This is for searches that only end when they are abandoned.
+    #[tracing::instrument(name = "upstream", level = "debug", skip_all, fields(backend = %self.backend, msgid))]
+    async fn search_stream(
+        &self,
+        sr: LdapSearchRequest,
+        ctrl: Vec<LdapControl>,
+        raw_controls: Vec<RawControl>,
+    ) -> Result<SearchStream<'_>, LdapError> {
+        let (msgid, op_rx) = self
+            .start(LdapOp::SearchRequest(sr), ctrl, raw_controls)
+            .await?;
+        Ok(SearchStream {
+            in_flight: InFlight {
+                client: self,
+                msgid,
+                complete: false,
+            },
+            op_rx,
+        })
+    }
+}
+
+// The responses of a search, as they arrive. If it is dropped before the search
+// is done, the search is abandoned.
+struct SearchStream<'a> {
+    in_flight: InFlight<'a>,
+    op_rx: mpsc::UnboundedReceiver<BackendMsg>,
+}
+
+impl SearchStream<'_> {
+    // The next response, as the client sees it. None once the connection has
+    // closed. There is no timeout, as changes may be a long time coming.
+    async fn next(&mut self) -> Option<BackendMsg> {
+        let mut response = self.op_rx.recv().await?;
+        if matches!(response.msg.op, LdapOp::SearchResultDone(_)) {
+            self.in_flight.complete = true;
+        }
+        response.msg = self.in_flight.client.received(response.msg);
+        Some(response)
+    }
+}
+
+// An operation that the backend is still processing. If it is dropped before it
+// completes, as happens when the client abandons the search, the operation is
+// abandoned on the backend too.
+struct InFlight<'a> {
+    client: &'a BasicLdapClient,
+    msgid: i32,
+    complete: bool,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if !self.complete {
+            tokio::spawn(self.client.abandon(self.msgid).in_current_span());
         }
     }
 }
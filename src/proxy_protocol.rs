@@ -0,0 +1,147 @@
+//! Parsing of the PROXY protocol header, that load balancers send ahead of the
+//! client's own traffic to convey the client's address. Version 2 (binary) and
+//! version 1 (text) headers are supported. Only the header is read from the
+//! stream, so what follows can be handed on as is.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// The longest possible v1 header, including the trailing CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+#[derive(Debug)]
+pub enum ProxyHeaderError {
+    Io(std::io::Error),
+    InvalidSignature,
+    InvalidHeader(&'static str),
+}
+
+impl std::fmt::Display for ProxyHeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyHeaderError::Io(e) => write!(f, "io error reading proxy header: {}", e),
+            ProxyHeaderError::InvalidSignature => write!(f, "no proxy protocol header"),
+            ProxyHeaderError::InvalidHeader(reason) => {
+                write!(f, "invalid proxy protocol header: {}", reason)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for ProxyHeaderError {
+    fn from(e: std::io::Error) -> Self {
+        ProxyHeaderError::Io(e)
+    }
+}
+
+/// Read a PROXY protocol header from the stream. This returns the source address
+/// that it conveys, or None if the header doesn't convey one (such as a health
+/// check from the load balancer itself).
+pub async fn read_proxy_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    // Both versions can be told apart by their first five bytes, and no header
+    // is shorter than that.
+    let mut prefix = [0u8; 5];
+    stream.read_exact(&mut prefix).await?;
+
+    if &prefix == b"PROXY" {
+        read_v1(stream).await
+    } else if prefix[..] == V2_SIGNATURE[..5] {
+        read_v2(stream, prefix).await
+    } else {
+        Err(ProxyHeaderError::InvalidSignature)
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    prefix: [u8; 5],
+) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    let mut header = [0u8; 16];
+    header[..5].copy_from_slice(&prefix);
+    stream.read_exact(&mut header[5..]).await?;
+
+    if header[..12] != V2_SIGNATURE {
+        return Err(ProxyHeaderError::InvalidSignature);
+    }
+
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0f;
+    let family = header[13];
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+
+    if version != 2 {
+        return Err(ProxyHeaderError::InvalidHeader("unsupported version"));
+    }
+
+    let mut addresses = vec![0u8; length];
+    stream.read_exact(&mut addresses).await?;
+
+    match command {
+        // LOCAL, the connection was made by the proxy itself.
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => return Err(ProxyHeaderError::InvalidHeader("unsupported command")),
+    }
+
+    match family {
+        // TCP over ipv4
+        0x11 => {
+            let a = addresses
+                .get(..12)
+                .ok_or(ProxyHeaderError::InvalidHeader("short ipv4 address block"))?;
+            let ip = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+            let port = u16::from_be_bytes([a[8], a[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // TCP over ipv6
+        0x21 => {
+            let a = addresses
+                .get(..36)
+                .ok_or(ProxyHeaderError::InvalidHeader("short ipv6 address block"))?;
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&a[..16]);
+            let port = u16::from_be_bytes([a[32], a[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        // UNSPEC, or an address family that doesn't have a useful source address.
+        _ => Ok(None),
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    // Read a byte at a time, so that nothing past the end of the line is consumed.
+    let mut line = b"PROXY".to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(ProxyHeaderError::InvalidHeader("v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| ProxyHeaderError::InvalidHeader("v1 header is not text"))?;
+    let fields: Vec<_> = line.split(' ').collect();
+
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, sport, _dport] => {
+            let ip: IpAddr = src
+                .parse()
+                .map_err(|_| ProxyHeaderError::InvalidHeader("invalid v1 source address"))?;
+            let port: u16 = sport
+                .parse()
+                .map_err(|_| ProxyHeaderError::InvalidHeader("invalid v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(ProxyHeaderError::InvalidHeader("malformed v1 header")),
+    }
+}
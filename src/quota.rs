@@ -0,0 +1,194 @@
+//! Per bind DN quotas, shared by every session of the DN, so that one
+//! application's credential can't take all of the proxy's or the backend's
+//! time. A DN may be limited in the operations that its sessions have in
+//! flight at once, and in the searches and entries that it is given each
+//! minute, which are token buckets that refill over the minute. Searches are
+//! refused once a DN's entries run out, and the entries of a search are
+//! taken once it has returned them, so a large search can leave its DN
+//! waiting for up to a minute.
+
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use hashbrown::HashMap;
+
+use crate::DnConfig;
+
+// The DNs' usage is not pruned until there are at least this many.
+const MIN_PRUNE_SIZE: usize = 1024;
+
+/// The quota that an operation was refused by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quota {
+    Operations,
+    Searches,
+    Entries,
+}
+
+impl Quota {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Quota::Operations => "operations",
+            Quota::Searches => "searches",
+            Quota::Entries => "entries",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, per_minute: u32, now: Instant) {
+        let per_minute = f64::from(per_minute);
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_minute / 60.0).min(per_minute);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    in_flight: usize,
+    searches: Option<Bucket>,
+    entries: Option<Bucket>,
+}
+
+impl Usage {
+    // The bucket for the limit, full when it is first used.
+    fn bucket(bucket: &mut Option<Bucket>, per_minute: u32, now: Instant) -> &mut Bucket {
+        let bucket = bucket.get_or_insert(Bucket {
+            tokens: f64::from(per_minute),
+            updated: now,
+        });
+        bucket.refill(per_minute, now);
+        bucket
+    }
+
+    // Usage that is the same as none can be removed.
+    fn is_idle(&self, now: Instant) -> bool {
+        let full = |bucket: &Option<Bucket>| {
+            bucket.as_ref().is_none_or(|bucket| {
+                // A bucket refills within two minutes, even from owing a
+                // minute of entries.
+                now.duration_since(bucket.updated).as_secs() >= 120
+            })
+        };
+        self.in_flight == 0 && full(&self.searches) && full(&self.entries)
+    }
+}
+
+#[derive(Debug)]
+struct Usages {
+    dns: HashMap<String, Usage>,
+    prune_at: usize,
+}
+
+/// The usage of the DNs that have quotas, by normalised DN.
+#[derive(Debug)]
+pub struct DnQuotas {
+    usages: Arc<Mutex<Usages>>,
+}
+
+impl Default for DnQuotas {
+    fn default() -> Self {
+        DnQuotas {
+            usages: Arc::new(Mutex::new(Usages {
+                dns: HashMap::new(),
+                prune_at: MIN_PRUNE_SIZE,
+            })),
+        }
+    }
+}
+
+/// An operation of a DN in flight, which is counted until this is dropped.
+#[derive(Debug)]
+pub struct QuotaPermit {
+    usages: Arc<Mutex<Usages>>,
+    dn: String,
+}
+
+impl Drop for QuotaPermit {
+    fn drop(&mut self) {
+        let mut usages = self.usages.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(usage) = usages.dns.get_mut(&self.dn) {
+            usage.in_flight = usage.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+fn has_quotas(config: &DnConfig) -> bool {
+    config.max_concurrent_operations.is_some()
+        || config.max_searches_per_minute.is_some()
+        || config.max_entries_per_minute.is_some()
+}
+
+impl DnQuotas {
+    /// Start an operation of the DN, or a search if `search` is set, within
+    /// the quotas of its config. The permit is None if the DN has no quotas.
+    pub fn acquire(
+        &self,
+        dn: &str,
+        config: &DnConfig,
+        search: bool,
+    ) -> Result<Option<QuotaPermit>, Quota> {
+        if !has_quotas(config) {
+            return Ok(None);
+        }
+        let now = Instant::now();
+        let mut usages = self.usages.lock().unwrap_or_else(|e| e.into_inner());
+        if usages.dns.len() >= usages.prune_at {
+            usages.dns.retain(|_, usage| !usage.is_idle(now));
+            usages.prune_at = (usages.dns.len() * 2).max(MIN_PRUNE_SIZE);
+        }
+        let usage = usages.dns.entry_ref(dn).or_default();
+
+        if let Some(max) = config.max_concurrent_operations {
+            if usage.in_flight >= max {
+                return Err(Quota::Operations);
+            }
+        }
+        if search {
+            // Every bucket is checked before any is taken from.
+            if let Some(per_minute) = config.max_entries_per_minute {
+                if Usage::bucket(&mut usage.entries, per_minute, now).tokens < 1.0 {
+                    return Err(Quota::Entries);
+                }
+            }
+            if let Some(per_minute) = config.max_searches_per_minute {
+                let searches = Usage::bucket(&mut usage.searches, per_minute, now);
+                if searches.tokens < 1.0 {
+                    return Err(Quota::Searches);
+                }
+                searches.tokens -= 1.0;
+            }
+        }
+        usage.in_flight += 1;
+        Ok(Some(QuotaPermit {
+            usages: self.usages.clone(),
+            dn: dn.to_string(),
+        }))
+    }
+
+    /// Take the entries that a search of the DN returned from its quota. A
+    /// DN can be left owing up to a minute of entries.
+    pub fn take_entries(&self, dn: &str, config: &DnConfig, entries: usize) {
+        let Some(per_minute) = config.max_entries_per_minute else {
+            return;
+        };
+        let now = Instant::now();
+        let mut usages = self.usages.lock().unwrap_or_else(|e| e.into_inner());
+        let usage = usages.dns.entry_ref(dn).or_default();
+        let bucket = Usage::bucket(&mut usage.entries, per_minute, now);
+        bucket.tokens = (bucket.tokens - entries as f64).max(-f64::from(per_minute));
+    }
+
+    /// The operations of the DN in flight.
+    pub fn in_flight(&self, dn: &str) -> usize {
+        let usages = self.usages.lock().unwrap_or_else(|e| e.into_inner());
+        usages.dns.get(dn).map_or(0, |usage| usage.in_flight)
+    }
+}
@@ -0,0 +1,111 @@
+//! Per source address rate limiting of binds. Each address has a token bucket
+//! that refills at the configured rate, up to the burst size. The buckets are
+//! split into shards so that concurrent binds rarely contend on the same lock.
+
+use hashbrown::HashMap;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+const SHARDS: usize = 16;
+// Shards are not pruned until they have at least this many buckets.
+const MIN_PRUNE_SIZE: usize = 1024;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Shard {
+    buckets: HashMap<IpAddr, Bucket>,
+    prune_at: usize,
+}
+
+#[derive(Debug)]
+pub struct BindRateLimiter {
+    rate: f64,
+    burst: f64,
+    shards: Vec<Mutex<Shard>>,
+    hasher: hashbrown::hash_map::DefaultHashBuilder,
+}
+
+impl BindRateLimiter {
+    /// Allow `rate` binds per second from each address, with up to `burst` binds
+    /// at once.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        let shards = (0..SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    buckets: HashMap::new(),
+                    prune_at: MIN_PRUNE_SIZE,
+                })
+            })
+            .collect();
+        BindRateLimiter {
+            rate: rate.max(f64::MIN_POSITIVE),
+            burst: f64::from(burst.max(1)),
+            shards,
+            hasher: Default::default(),
+        }
+    }
+
+    /// Take a token for a bind from this address. Returns false if the address
+    /// is over its limit.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let idx = (self.hasher.hash_one(ip) as usize) % SHARDS;
+
+        let mut shard = self.shards[idx].lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+
+        let bucket = shard.buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        if shard.buckets.len() >= shard.prune_at {
+            self.prune(&mut shard, now);
+        }
+
+        allowed
+    }
+
+    // Buckets that have refilled are the same as a new bucket, so they can be
+    // removed.
+    fn prune(&self, shard: &mut Shard, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        shard.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * rate < burst
+        });
+        shard.prune_at = (shard.buckets.len() * 2).max(MIN_PRUNE_SIZE);
+    }
+
+    /// The number of addresses that currently have state.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .buckets
+                    .len()
+            })
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,198 @@
+//! Handling of the referrals that backends return, either in the result of a
+//! search or as search result references. Clients of the proxy often can't
+//! reach the servers that are referred to, and the urls leak internal names.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use ldap3_proto::proto::LdapSearchScope;
+use ldap3_proto::{parse_ldap_filter_str, LdapFilter};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer};
+
+/// What the proxy does with referrals. As well as by name, it can be given as
+/// a bool, as chase_referrals is, with true for chase and false for
+/// passthrough.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReferralMode {
+    /// Referrals are relayed as the backend sent them.
+    #[default]
+    Passthrough,
+    /// The host and port of the referral urls are replaced by the proxy's.
+    Rewrite,
+    /// The proxy follows the referrals, and returns what it finds.
+    Chase,
+    /// Referrals are removed.
+    Strip,
+}
+
+impl<'de> Deserialize<'de> for ReferralMode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ModeVisitor;
+
+        impl Visitor<'_> for ModeVisitor {
+            type Value = ReferralMode;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("passthrough, rewrite, chase, strip, or a bool")
+            }
+
+            fn visit_bool<E: de::Error>(self, chase: bool) -> Result<ReferralMode, E> {
+                Ok(if chase {
+                    ReferralMode::Chase
+                } else {
+                    ReferralMode::Passthrough
+                })
+            }
+
+            fn visit_str<E: de::Error>(self, mode: &str) -> Result<ReferralMode, E> {
+                match mode {
+                    "passthrough" => Ok(ReferralMode::Passthrough),
+                    "rewrite" => Ok(ReferralMode::Rewrite),
+                    "chase" => Ok(ReferralMode::Chase),
+                    "strip" => Ok(ReferralMode::Strip),
+                    _ => Err(E::unknown_variant(
+                        mode,
+                        &["passthrough", "rewrite", "chase", "strip"],
+                    )),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(ModeVisitor)
+    }
+}
+
+/// The parts of an ldap url (RFC 4516) that are needed to follow a referral.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LdapUrl {
+    /// An ldaps url, rather than ldap.
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    pub base: Option<String>,
+    pub scope: Option<LdapSearchScope>,
+    pub filter: Option<LdapFilter>,
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let high = (iter.next()? as char).to_digit(16)?;
+            let low = (iter.next()? as char).to_digit(16)?;
+            bytes.push(((high << 4) | low) as u8);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// Split a url into its scheme, authority, and the rest (which starts with / or ?).
+fn split_url(uri: &str) -> Option<(&str, &str, &str)> {
+    let (scheme, rest) = uri.split_once("://")?;
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    Some((scheme, &rest[..end], &rest[end..]))
+}
+
+impl LdapUrl {
+    /// Parse an ldap or ldaps url.
+    pub fn parse(uri: &str) -> Option<Self> {
+        let (scheme, authority, rest) = split_url(uri)?;
+        let (tls, default_port) = match scheme.to_ascii_lowercase().as_str() {
+            "ldaps" => (true, 636),
+            "ldap" => (false, 389),
+            _ => return None,
+        };
+
+        let (host, port) = match authority.rsplit_once(':') {
+            // A bracketed ipv6 address without a port.
+            Some((_, port)) if port.ends_with(']') => (authority, default_port),
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (authority, default_port),
+        };
+        if host.is_empty() {
+            return None;
+        }
+        let rest = rest.strip_prefix('/').unwrap_or(rest);
+        let (dn, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let base = Some(percent_decode(dn)?).filter(|dn| !dn.is_empty());
+
+        // The query is attributes?scope?filter?extensions.
+        let mut parts = query.split('?').skip(1);
+        let scope = match parts.next().unwrap_or("") {
+            "" => None,
+            "base" => Some(LdapSearchScope::Base),
+            "one" => Some(LdapSearchScope::OneLevel),
+            "sub" => Some(LdapSearchScope::Subtree),
+            _ => return None,
+        };
+        let filter = match parts.next().map(percent_decode) {
+            Some(Some(filter)) if !filter.is_empty() => Some(parse_ldap_filter_str(&filter).ok()?),
+            Some(None) => return None,
+            _ => None,
+        };
+
+        Some(LdapUrl {
+            tls,
+            host: host.to_string(),
+            port,
+            base,
+            scope,
+            filter,
+        })
+    }
+}
+
+impl LdapUrl {
+    /// Whether the server that the url names is in `hosts`, which holds
+    /// host:port pairs, or only hosts, without regard to case.
+    pub fn host_in(&self, hosts: &[String]) -> bool {
+        let authority = format!("{}:{}", self.host, self.port);
+        hosts.iter().any(|allowed| {
+            allowed.eq_ignore_ascii_case(&authority) || allowed.eq_ignore_ascii_case(&self.host)
+        })
+    }
+}
+
+/// The host:port of the proxy that a referral url is rewritten to. `map` is
+/// keyed by the host:port, or only the host, of the server that the referral
+/// names, without regard to case. Referrals to any other server are rewritten
+/// to `default`.
+pub fn rewrite_target<'a>(
+    uri: &str,
+    map: &'a BTreeMap<String, String>,
+    default: &'a str,
+) -> &'a str {
+    let Some((_, authority, _)) = split_url(uri) else {
+        return default;
+    };
+    let host = match authority.rsplit_once(':') {
+        Some((_, port)) if port.ends_with(']') => authority,
+        Some((host, _)) => host,
+        None => authority,
+    };
+    [authority, host]
+        .into_iter()
+        .find_map(|key| {
+            map.iter()
+                .find(|(server, _)| server.eq_ignore_ascii_case(key))
+                .map(|(_, proxy)| proxy.as_str())
+        })
+        .unwrap_or(default)
+}
+
+/// Point a referral url at the proxy, replacing its host and port with
+/// `proxy`. The proxy only serves ldaps, so the scheme is changed to match.
+/// Urls that can't be parsed are returned unchanged.
+pub fn rewrite_url(uri: &str, proxy: &str) -> String {
+    match split_url(uri) {
+        Some((_, _, rest)) => format!("ldaps://{}{}", proxy, rest),
+        None => uri.to_string(),
+    }
+}
@@ -0,0 +1,30 @@
+//! Retries of backend operations that fail for transient reasons, such as a
+//! dropped connection or a failed TLS handshake, so that these don't reach the
+//! client as errors. Each retry waits for a delay that doubles with each
+//! attempt, with jitter so that many sessions don't all retry at once.
+
+use rand::Rng;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts after the first, before the error is returned.
+    pub attempts: u32,
+    base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(attempts: u32, base_delay: Duration) -> Self {
+        RetryPolicy {
+            attempts,
+            base_delay,
+        }
+    }
+
+    /// The delay before a retry, counting from zero.
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(1 << retry.min(16))
+            .mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
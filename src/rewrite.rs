@@ -0,0 +1,316 @@
+//! Rewriting of DNs between the namespace that clients see and the backend's,
+//! for when clients use `dc=corp,dc=example` but the backend holds the same
+//! entries under `o=example`. Requests are rewritten as they are sent to the
+//! backend and responses as they are received, so that everything else in the
+//! proxy, such as the bind map, the cache and allowed bases, deals only in the
+//! client's DNs.
+//!
+//! The DNs of operations, entries and results are rewritten, as are the values
+//! of attributes that hold DNs, such as `member`, wherever they appear in
+//! entries, filters and writes. The part of a DN below the suffix is kept as it
+//! was written.
+
+use ldap3_proto::proto::{
+    LdapExtendedRequest, LdapOp, LdapPartialAttribute, LdapPasswordModifyRequest, LdapResult,
+};
+use ldap3_proto::LdapFilter;
+use serde::{Deserialize, Deserializer};
+
+use crate::dn::normalize_dn;
+use crate::proxy::OID_PASSWORD_MODIFY;
+
+/// The attributes whose values are DNs, unless others are configured.
+pub const DEFAULT_DN_ATTRIBUTES: &[&str] = &[
+    "member",
+    "uniqueMember",
+    "memberOf",
+    "owner",
+    "manager",
+    "secretary",
+    "seeAlso",
+    "roleOccupant",
+    "namingContexts",
+    "creatorsName",
+    "modifiersName",
+];
+
+fn default_dn_attributes() -> Vec<String> {
+    DEFAULT_DN_ATTRIBUTES
+        .iter()
+        .map(|a| a.to_string())
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+struct SuffixConfig {
+    client: String,
+    backend: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DnRewriteConfig {
+    suffixes: Vec<SuffixConfig>,
+    #[serde(default = "default_dn_attributes")]
+    attributes: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct Suffix {
+    // As configured, which is how it is written into rewritten DNs.
+    dn: String,
+    normalized: String,
+    rdns: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    ToBackend,
+    ToClient,
+}
+
+/// The suffix mappings, compiled when the config is loaded.
+#[derive(Debug, Clone)]
+pub struct DnRewrite {
+    // Pairs of client and backend suffixes.
+    rules: Vec<(Suffix, Suffix)>,
+    attributes: Vec<String>,
+}
+
+// The byte offsets at which the RDNs of a DN start.
+fn rdn_starts(dn: &str) -> Vec<usize> {
+    let mut starts = vec![0];
+    let mut escaped = false;
+    for (i, c) in dn.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ',' => starts.push(i + 1),
+            _ => {}
+        }
+    }
+    starts
+}
+
+impl Suffix {
+    fn parse(dn: &str) -> Result<Self, String> {
+        let normalized =
+            normalize_dn(dn).map_err(|e| format!("invalid dn rewrite suffix '{}': {}", dn, e))?;
+        if normalized.is_empty() {
+            return Err("dn rewrite suffixes can't be empty".to_string());
+        }
+        Ok(Suffix {
+            dn: dn.trim().to_string(),
+            rdns: rdn_starts(&normalized).len(),
+            normalized,
+        })
+    }
+}
+
+// Replace the suffix `from` of a DN with `to`, if the DN is at or below it.
+fn replace_suffix(dn: &str, from: &Suffix, to: &Suffix) -> Option<String> {
+    let starts = rdn_starts(dn);
+    let start = *starts.get(starts.len().checked_sub(from.rdns)?)?;
+    if normalize_dn(&dn[start..]).ok()? != from.normalized {
+        return None;
+    }
+    Some(format!("{}{}", &dn[..start], to.dn))
+}
+
+impl DnRewrite {
+    fn compile(config: DnRewriteConfig) -> Result<Self, String> {
+        let rules = config
+            .suffixes
+            .iter()
+            .map(|suffix| {
+                Ok((
+                    Suffix::parse(&suffix.client)?,
+                    Suffix::parse(&suffix.backend)?,
+                ))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(DnRewrite {
+            rules,
+            attributes: config.attributes,
+        })
+    }
+
+    // The DN rewritten by the rule with the longest matching suffix, if any.
+    fn rewritten(&self, dn: &str, direction: Direction) -> Option<String> {
+        self.rules
+            .iter()
+            .filter_map(|(client, backend)| {
+                let (from, to) = match direction {
+                    Direction::ToBackend => (client, backend),
+                    Direction::ToClient => (backend, client),
+                };
+                Some((from.rdns, replace_suffix(dn, from, to)?))
+            })
+            .max_by_key(|(rdns, _)| *rdns)
+            .map(|(_, dn)| dn)
+    }
+
+    /// The backend's DN for a DN that clients use. DNs outside of every client
+    /// suffix are unchanged.
+    pub fn to_backend(&self, dn: &str) -> String {
+        self.rewritten(dn, Direction::ToBackend)
+            .unwrap_or_else(|| dn.to_string())
+    }
+
+    /// The DN that clients use for a backend DN.
+    pub fn to_client(&self, dn: &str) -> String {
+        self.rewritten(dn, Direction::ToClient)
+            .unwrap_or_else(|| dn.to_string())
+    }
+
+    fn dn(&self, dn: &mut String, direction: Direction) {
+        if let Some(rewritten) = self.rewritten(dn, direction) {
+            *dn = rewritten;
+        }
+    }
+
+    // Options such as ";binary" don't change the attribute.
+    fn is_dn_attribute(&self, atype: &str) -> bool {
+        let attr = atype.split(';').next().unwrap_or(atype);
+        self.attributes.iter().any(|a| a.eq_ignore_ascii_case(attr))
+    }
+
+    fn value(&self, value: &mut Vec<u8>, direction: Direction) {
+        let rewritten = std::str::from_utf8(value)
+            .ok()
+            .and_then(|dn| self.rewritten(dn, direction));
+        if let Some(rewritten) = rewritten {
+            *value = rewritten.into_bytes();
+        }
+    }
+
+    fn attribute(&self, attr: &mut LdapPartialAttribute, direction: Direction) {
+        if self.is_dn_attribute(&attr.atype) {
+            for value in attr.vals.iter_mut() {
+                self.value(value, direction);
+            }
+        }
+    }
+
+    fn filter(&self, filter: &mut LdapFilter, direction: Direction) {
+        match filter {
+            LdapFilter::And(filters) | LdapFilter::Or(filters) => {
+                for filter in filters.iter_mut() {
+                    self.filter(filter, direction);
+                }
+            }
+            LdapFilter::Not(filter) => self.filter(filter, direction),
+            LdapFilter::Equality(atype, value)
+            | LdapFilter::GreaterOrEqual(atype, value)
+            | LdapFilter::LessOrEqual(atype, value)
+            | LdapFilter::Approx(atype, value)
+                if self.is_dn_attribute(atype) =>
+            {
+                self.dn(value, direction)
+            }
+            LdapFilter::Extensible(assertion)
+                if assertion
+                    .type_
+                    .as_deref()
+                    .is_some_and(|atype| self.is_dn_attribute(atype)) =>
+            {
+                self.dn(&mut assertion.match_value, direction)
+            }
+            _ => {}
+        }
+    }
+
+    fn result(&self, result: &mut LdapResult) {
+        self.dn(&mut result.matcheddn, Direction::ToClient);
+    }
+
+    // The identity of a password modify request is usually "dn:" and a DN,
+    // though some servers take a bare DN.
+    fn password_modify(&self, ler: &mut LdapExtendedRequest) {
+        let Ok(mut request) = LdapPasswordModifyRequest::try_from(&*ler) else {
+            return;
+        };
+        let Some(identity) = request.user_identity.as_mut() else {
+            return;
+        };
+        let (prefix, dn) = match identity.strip_prefix("dn:") {
+            Some(dn) => ("dn:", dn),
+            None => ("", identity.as_str()),
+        };
+        if let Some(rewritten) = self.rewritten(dn, Direction::ToBackend) {
+            *identity = format!("{}{}", prefix, rewritten);
+            *ler = request.into();
+        }
+    }
+
+    /// Rewrite a request from a client for the backend.
+    pub fn request(&self, op: &mut LdapOp) {
+        let direction = Direction::ToBackend;
+        match op {
+            LdapOp::BindRequest(lbr) => self.dn(&mut lbr.dn, direction),
+            LdapOp::SearchRequest(sr) => {
+                self.dn(&mut sr.base, direction);
+                self.filter(&mut sr.filter, direction);
+            }
+            LdapOp::CompareRequest(lcr) => {
+                self.dn(&mut lcr.dn, direction);
+                if self.is_dn_attribute(&lcr.atype) {
+                    self.value(&mut lcr.val, direction);
+                }
+            }
+            LdapOp::AddRequest(lar) => {
+                self.dn(&mut lar.dn, direction);
+                for attr in lar.attributes.iter_mut() {
+                    self.attribute(attr, direction);
+                }
+            }
+            LdapOp::ModifyRequest(lmr) => {
+                self.dn(&mut lmr.dn, direction);
+                for change in lmr.changes.iter_mut() {
+                    self.attribute(&mut change.modification, direction);
+                }
+            }
+            LdapOp::DelRequest(dn) => self.dn(dn, direction),
+            LdapOp::ModifyDNRequest(lmdr) => {
+                self.dn(&mut lmdr.dn, direction);
+                if let Some(new_superior) = lmdr.new_superior.as_mut() {
+                    self.dn(new_superior, direction);
+                }
+            }
+            LdapOp::ExtendedRequest(ler) if ler.name == OID_PASSWORD_MODIFY => {
+                self.password_modify(ler)
+            }
+            _ => {}
+        }
+    }
+
+    /// Rewrite a response from the backend for the client.
+    pub fn response(&self, op: &mut LdapOp) {
+        match op {
+            LdapOp::SearchResultEntry(entry) => {
+                self.dn(&mut entry.dn, Direction::ToClient);
+                for attr in entry.attributes.iter_mut() {
+                    self.attribute(attr, Direction::ToClient);
+                }
+            }
+            LdapOp::BindResponse(resp) => self.result(&mut resp.res),
+            LdapOp::ExtendedResponse(resp) => self.result(&mut resp.res),
+            LdapOp::SearchResultDone(result)
+            | LdapOp::ModifyResponse(result)
+            | LdapOp::AddResponse(result)
+            | LdapOp::DelResponse(result)
+            | LdapOp::ModifyDNResponse(result)
+            | LdapOp::CompareResult(result) => self.result(result),
+            _ => {}
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for DnRewrite {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let config = DnRewriteConfig::deserialize(deserializer)?;
+        DnRewrite::compile(config).map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,227 @@
+//! A root DSE (RFC 4512 5.1) answered by the proxy, so that clients can
+//! discover what it supports before they bind. The values are configured, or
+//! learned from the root DSE of the default backend when the proxy starts, and
+//! the controls are only those that clients may use through the proxy.
+//!
+//! Root DSEs that the backend answers are restricted in the same way, so
+//! that clients don't discover controls and extended operations that would
+//! fail through the proxy.
+
+use ldap3_proto::proto::{
+    LdapMsg, LdapOp, LdapPartialAttribute, LdapResult, LdapResultCode, LdapSearchRequest,
+    LdapSearchResultEntry, LdapSearchScope,
+};
+use serde::Deserialize;
+
+use crate::controls::{ControlPolicy, NOTIFICATION_CONTROLS, SUPPORTED_CONTROLS};
+use crate::sort::OID_SERVER_SORT;
+use crate::vlv::OID_VLV_REQUEST;
+
+const DEFAULT_VENDOR_NAME: &str = "ldap-proxy";
+
+/// The attributes of the backend's root DSE that are learned.
+pub const LEARNED_ATTRIBUTES: &[&str] = &[
+    "namingContexts",
+    "subschemaSubentry",
+    "supportedControl",
+    "vendorName",
+];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RootDseConfig {
+    /// Read the backend's root DSE at startup, for the values that aren't
+    /// configured here. Controls that the backend doesn't list are left out.
+    #[serde(default)]
+    pub from_backend: bool,
+    #[serde(default)]
+    pub naming_contexts: Vec<String>,
+    pub vendor_name: Option<String>,
+}
+
+/// The root DSE entry that the proxy answers with.
+#[derive(Debug, Clone)]
+pub struct RootDse {
+    attributes: Vec<LdapPartialAttribute>,
+}
+
+// The values of an attribute of an entry, matched without regard to case.
+fn values<'a>(entry: &'a LdapSearchResultEntry, atype: &str) -> Vec<&'a [u8]> {
+    entry
+        .attributes
+        .iter()
+        .filter(|attr| attr.atype.eq_ignore_ascii_case(atype))
+        .flat_map(|attr| attr.vals.iter().map(Vec::as_slice))
+        .collect()
+}
+
+/// If a root DSE lists this control in its supportedControl.
+pub fn supports_control(entry: &LdapSearchResultEntry, oid: &str) -> bool {
+    values(entry, "supportedControl").contains(&oid.as_bytes())
+}
+
+/// Restrict the supportedControl and supportedExtension of a root DSE from
+/// the backend to what a client can use through the proxy. Controls are listed
+/// if they can be relayed and `controls` permits them, and the sort and
+/// virtual list view controls, which the proxy handles itself, are added.
+/// Extended operations are listed if `permits_extension` allows them, and
+/// those in `answered`, which the proxy answers itself, are added.
+pub fn restrict_root_dse(
+    entry: &mut LdapSearchResultEntry,
+    controls: &ControlPolicy,
+    answered: &[&str],
+    permits_extension: impl Fn(&str) -> bool,
+) {
+    let relayed = |oid: &str| {
+        (SUPPORTED_CONTROLS.contains(&oid)
+            || NOTIFICATION_CONTROLS.contains(&oid)
+            || oid == OID_VLV_REQUEST)
+            && controls.permits(oid)
+    };
+    let restrict = |vals: &mut Vec<Vec<u8>>, keep: &dyn Fn(&str) -> bool, add: &[&str]| {
+        vals.retain(|val| std::str::from_utf8(val).is_ok_and(keep));
+        for oid in add.iter().filter(|oid| keep(oid)) {
+            if !vals.iter().any(|val| val == oid.as_bytes()) {
+                vals.push(oid.as_bytes().to_vec());
+            }
+        }
+    };
+    // Attributes without values were asked for with typesonly.
+    for attr in entry
+        .attributes
+        .iter_mut()
+        .filter(|attr| !attr.vals.is_empty())
+    {
+        if attr.atype.eq_ignore_ascii_case("supportedControl") {
+            restrict(
+                &mut attr.vals,
+                &relayed,
+                &[OID_SERVER_SORT, OID_VLV_REQUEST],
+            );
+        } else if attr.atype.eq_ignore_ascii_case("supportedExtension") {
+            restrict(&mut attr.vals, &permits_extension, answered);
+        }
+    }
+}
+
+/// The DN of the subschema subentry that a root DSE names, if any.
+pub fn subschema_subentry(entry: &LdapSearchResultEntry) -> Option<String> {
+    values(entry, "subschemaSubentry")
+        .first()
+        .map(|dn| String::from_utf8_lossy(dn).into_owned())
+}
+
+/// If this search is for the root DSE.
+pub fn is_root_dse_search(sr: &LdapSearchRequest) -> bool {
+    sr.base.is_empty() && sr.scope == LdapSearchScope::Base
+}
+
+impl RootDse {
+    /// Build the root DSE from the config, and from the backend's root DSE if
+    /// it was read. Only the controls that `controls` permits are listed.
+    pub fn new(
+        config: &RootDseConfig,
+        backend: Option<&LdapSearchResultEntry>,
+        controls: &ControlPolicy,
+    ) -> Self {
+        let naming_contexts = if config.naming_contexts.is_empty() {
+            backend
+                .map(|entry| values(entry, "namingContexts"))
+                .unwrap_or_default()
+                .into_iter()
+                .map(<[u8]>::to_vec)
+                .collect()
+        } else {
+            config
+                .naming_contexts
+                .iter()
+                .map(|context| context.as_bytes().to_vec())
+                .collect()
+        };
+
+        let backend_controls = backend.map(|entry| values(entry, "supportedControl"));
+        // The sort and virtual list view controls are handled by the proxy
+        // when the backend can't.
+        let supported_controls = SUPPORTED_CONTROLS
+            .iter()
+            .chain([OID_VLV_REQUEST].iter())
+            .filter(|oid| controls.permits(oid))
+            .filter(|oid| {
+                [OID_SERVER_SORT, OID_VLV_REQUEST].contains(oid)
+                    || backend_controls
+                        .as_ref()
+                        .is_none_or(|listed| listed.contains(&oid.as_bytes()))
+            })
+            .map(|oid| oid.as_bytes().to_vec())
+            .collect();
+
+        let vendor_name = match (&config.vendor_name, backend) {
+            (Some(name), _) => name.as_bytes().to_vec(),
+            (None, Some(entry)) if !values(entry, "vendorName").is_empty() => {
+                values(entry, "vendorName")[0].to_vec()
+            }
+            _ => DEFAULT_VENDOR_NAME.as_bytes().to_vec(),
+        };
+
+        let attributes = [
+            ("namingContexts", naming_contexts),
+            ("supportedLDAPVersion", vec![b"3".to_vec()]),
+            ("supportedControl", supported_controls),
+            ("vendorName", vec![vendor_name]),
+        ]
+        .into_iter()
+        .filter(|(_, vals)| !vals.is_empty())
+        .map(|(atype, vals)| LdapPartialAttribute {
+            atype: atype.to_string(),
+            vals,
+        })
+        .collect();
+
+        RootDse { attributes }
+    }
+
+    /// The entry, with the attributes that the search asks for. These are all
+    /// operational, but as clients rarely ask for "+" they are also returned
+    /// when no attributes are named. The filter isn't checked, as it is almost
+    /// always (objectClass=*).
+    pub fn entry(&self, sr: &LdapSearchRequest) -> LdapSearchResultEntry {
+        let all = sr.attrs.is_empty() || sr.attrs.iter().any(|a| a == "*" || a == "+");
+        let attributes = self
+            .attributes
+            .iter()
+            .filter(|attr| all || sr.attrs.iter().any(|a| a.eq_ignore_ascii_case(&attr.atype)))
+            .map(|attr| LdapPartialAttribute {
+                atype: attr.atype.clone(),
+                vals: if sr.typesonly {
+                    Vec::new()
+                } else {
+                    attr.vals.clone()
+                },
+            })
+            .collect();
+        LdapSearchResultEntry {
+            dn: String::new(),
+            attributes,
+        }
+    }
+
+    /// The responses to a root DSE search.
+    pub fn responses(&self, msgid: i32, sr: &LdapSearchRequest) -> [LdapMsg; 2] {
+        [
+            LdapMsg {
+                msgid,
+                op: LdapOp::SearchResultEntry(self.entry(sr)),
+                ctrl: vec![],
+            },
+            LdapMsg {
+                msgid,
+                op: LdapOp::SearchResultDone(LdapResult {
+                    code: LdapResultCode::Success,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec![],
+                }),
+                ctrl: vec![],
+            },
+        ]
+    }
+}
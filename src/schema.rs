@@ -0,0 +1,136 @@
+//! The subschema subentry (RFC 4512 4.2), which clients such as Apache
+//! Directory Studio read again and again. It is read from the backend once and
+//! shared by every DN, and searches for it are answered by the proxy until it
+//! expires. While the backend can't be reached, the last copy is used however
+//! old it is.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ldap3_proto::control::LdapControl;
+use ldap3_proto::proto::{
+    LdapDerefAliases, LdapFilter, LdapPartialAttribute, LdapSearchRequest, LdapSearchResultEntry,
+    LdapSearchScope,
+};
+use serde::Deserialize;
+
+use crate::dn::normalize_dn;
+
+/// The DN of the subentry if neither the config nor the backend's root DSE
+/// name it.
+pub const DEFAULT_SCHEMA_DN: &str = "cn=subschema";
+
+// The attributes that are read. Not every backend returns the operational
+// attributes for "+", so those of the schema are also named.
+const SCHEMA_ATTRIBUTES: &[&str] = &[
+    "*",
+    "+",
+    "attributeTypes",
+    "objectClasses",
+    "ldapSyntaxes",
+    "matchingRules",
+    "matchingRuleUse",
+    "dITContentRules",
+    "dITStructureRules",
+    "nameForms",
+    "createTimestamp",
+    "modifyTimestamp",
+];
+
+fn default_ttl_secs() -> u64 {
+    86400
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SchemaConfig {
+    /// The DN of the subentry. Defaults to the subschemaSubentry of the
+    /// backend's root DSE, or cn=subschema.
+    #[serde(default)]
+    pub dn: Option<String>,
+    /// Seconds before the subentry is read again.
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+/// The copy of the subentry, once it has been read.
+#[derive(Debug)]
+pub struct SchemaCache {
+    /// The normalised DN of the subentry.
+    dn: String,
+    ttl: Duration,
+    entry: Mutex<Option<(Instant, LdapSearchResultEntry)>>,
+}
+
+impl SchemaCache {
+    pub fn new(dn: &str, ttl: Duration) -> Self {
+        SchemaCache {
+            dn: normalize_dn(dn).unwrap_or_else(|_| dn.to_lowercase()),
+            ttl,
+            entry: Mutex::new(None),
+        }
+    }
+
+    pub fn dn(&self) -> &str {
+        &self.dn
+    }
+
+    /// If a search is for the subentry. Searches with controls are sent to
+    /// the backend, as the copy can't honour them.
+    pub fn answers(&self, sr: &LdapSearchRequest, ctrl: &[LdapControl]) -> bool {
+        ctrl.is_empty()
+            && sr.scope == LdapSearchScope::Base
+            && normalize_dn(&sr.base).is_ok_and(|base| base == self.dn)
+    }
+
+    /// The search that reads the subentry.
+    pub fn request(&self) -> LdapSearchRequest {
+        LdapSearchRequest {
+            base: self.dn.clone(),
+            scope: LdapSearchScope::Base,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 1,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: SCHEMA_ATTRIBUTES.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    pub fn store(&self, entry: LdapSearchResultEntry) {
+        *self.entry.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), entry));
+    }
+
+    /// The copy of the subentry, if it hasn't expired. With `stale`, it is
+    /// returned whatever its age.
+    pub fn get(&self, stale: bool) -> Option<LdapSearchResultEntry> {
+        let entry = self.entry.lock().unwrap_or_else(|e| e.into_inner());
+        entry
+            .as_ref()
+            .filter(|(read, _)| stale || read.elapsed() < self.ttl)
+            .map(|(_, entry)| entry.clone())
+    }
+
+    /// The subentry with the attributes that the search asks for. As with the
+    /// root DSE, the schema attributes are operational but are returned when
+    /// no attributes are named, and the filter isn't checked.
+    pub fn select(entry: &LdapSearchResultEntry, sr: &LdapSearchRequest) -> LdapSearchResultEntry {
+        let all = sr.attrs.is_empty() || sr.attrs.iter().any(|a| a == "*" || a == "+");
+        let attributes = entry
+            .attributes
+            .iter()
+            .filter(|attr| all || sr.attrs.iter().any(|a| a.eq_ignore_ascii_case(&attr.atype)))
+            .map(|attr| LdapPartialAttribute {
+                atype: attr.atype.clone(),
+                vals: if sr.typesonly {
+                    Vec::new()
+                } else {
+                    attr.vals.clone()
+                },
+            })
+            .collect();
+        LdapSearchResultEntry {
+            dn: entry.dn.clone(),
+            attributes,
+        }
+    }
+}
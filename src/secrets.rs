@@ -0,0 +1,297 @@
+//! Passwords of service accounts that are fetched when they are needed, rather
+//! than written in the config, so that they can be rotated without a reload. A
+//! provider runs a command and takes the password from its output, reads a
+//! file, or gets a URL, such as that of a Vault or KMS secret, and takes the
+//! password from the JSON it answers with.
+//!
+//! Fetched passwords are kept for `cache_secs`, and forgotten early when the
+//! backend refuses them, so that a rotated password is fetched again.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+use openssl::ssl::{SslConnector, SslMethod};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio_openssl::SslStream;
+use url::{Host, Position, Url};
+
+use crate::config::{read_secret_file, Secret};
+
+// How long a provider may take to answer.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The largest HTTP response that is read, headers included.
+const MAX_RESPONSE_BYTES: u64 = 64 * 1024;
+
+fn default_cache_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SecretSource {
+    /// Run the command, which is the program and its arguments, and use what
+    /// it writes to stdout, without the newline that ends it.
+    Command { command: Vec<String> },
+    /// Read the file, which is read again each time the password is fetched.
+    File { path: PathBuf },
+    /// GET the URL, sending these headers, such as `X-Vault-Token`. The
+    /// password is the body of the response, or with `pointer`, the string at
+    /// that JSON pointer of it, as in `/data/data/password`. https URLs are
+    /// verified against `ca`, or else the system's trusted certificates. http
+    /// URLs are refused unless `allow_http` is set, as the headers and the
+    /// password would be sent in the clear.
+    Http {
+        url: Url,
+        #[serde(default)]
+        headers: BTreeMap<String, Secret>,
+        pointer: Option<String>,
+        ca: Option<PathBuf>,
+        #[serde(default)]
+        allow_http: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+pub struct SecretProvider {
+    #[serde(flatten)]
+    pub source: SecretSource,
+    /// Keep a fetched password for this long before fetching it again.
+    #[serde(default = "default_cache_secs")]
+    pub cache_secs: u64,
+}
+
+impl SecretProvider {
+    /// Fetch the password, without a cache.
+    pub async fn fetch(&self) -> Result<Secret, String> {
+        match tokio::time::timeout(FETCH_TIMEOUT, self.source.fetch()).await {
+            Ok(res) => res,
+            Err(_) => Err("timed out".to_string()),
+        }
+    }
+}
+
+impl SecretSource {
+    async fn fetch(&self) -> Result<Secret, String> {
+        match self {
+            SecretSource::Command { command } => run_command(command).await,
+            SecretSource::File { path } => {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || read_secret_file(&path))
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .map(Secret::from)
+                    .map_err(|e| e.to_string())
+            }
+            SecretSource::Http {
+                url,
+                headers,
+                pointer,
+                ca,
+                allow_http,
+            } => {
+                if url.scheme() == "http" && !allow_http {
+                    return Err("http urls are refused unless allow_http is set".to_string());
+                }
+                let body = http_get(url, headers, ca.as_ref()).await?;
+                match pointer {
+                    Some(pointer) => {
+                        let json: serde_json::Value =
+                            serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+                        match json.pointer(pointer) {
+                            Some(serde_json::Value::String(password)) => {
+                                Ok(Secret::from(password.as_str()))
+                            }
+                            Some(_) => Err(format!("{} is not a string", pointer)),
+                            None => Err(format!("the response has no {}", pointer)),
+                        }
+                    }
+                    None => String::from_utf8(body)
+                        .map(|body| Secret::from(body.trim_end_matches(['\r', '\n'])))
+                        .map_err(|_| "the response is not UTF-8".to_string()),
+                }
+            }
+        }
+    }
+}
+
+// Run the command, which is killed if the fetch times out.
+async fn run_command(command: &[String]) -> Result<Secret, String> {
+    let Some((program, args)) = command.split_first() else {
+        return Err("the command is empty".to_string());
+    };
+    let output = Command::new(program)
+        .args(args)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| format!("unable to run {}: {}", program, e))?;
+    if !output.status.success() {
+        return Err(format!("{} failed with {}", program, output.status));
+    }
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|_| format!("the output of {} is not UTF-8", program))?;
+    Ok(Secret::from(stdout.trim_end_matches(['\r', '\n'])))
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+// A GET of the URL over HTTP/1.1, returning the body if the status is 200.
+async fn http_get(
+    url: &Url,
+    headers: &BTreeMap<String, Secret>,
+    ca: Option<&PathBuf>,
+) -> Result<Vec<u8>, String> {
+    let port = url.port_or_known_default().ok_or("the url has no port")?;
+    // The name that the certificate is checked against. ipv6 addresses are
+    // without the brackets of the url.
+    let host = match url.host().ok_or("the url has no host")? {
+        Host::Domain(domain) => domain.to_string(),
+        Host::Ipv4(ip) => ip.to_string(),
+        Host::Ipv6(ip) => ip.to_string(),
+    };
+    let tcp = TcpStream::connect((host.as_str(), port))
+        .await
+        .map_err(|e| format!("unable to connect to {}: {}", host, e))?;
+    let mut stream: Box<dyn Stream> = match url.scheme() {
+        "http" => Box::new(tcp),
+        "https" => {
+            let mut builder =
+                SslConnector::builder(SslMethod::tls_client()).map_err(|e| e.to_string())?;
+            if let Some(ca) = ca {
+                builder.set_ca_file(ca).map_err(|e| e.to_string())?;
+            }
+            let ssl = builder
+                .build()
+                .configure()
+                .and_then(|config| config.into_ssl(&host))
+                .map_err(|e| e.to_string())?;
+            let mut tls = SslStream::new(ssl, tcp).map_err(|e| e.to_string())?;
+            Pin::new(&mut tls)
+                .connect()
+                .await
+                .map_err(|e| format!("tls with {} failed: {}", host, e))?;
+            Box::new(tls)
+        }
+        scheme => return Err(format!("unsupported url scheme {}", scheme)),
+    };
+
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+        path,
+        &url[Position::BeforeHost..Position::AfterPort]
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value.expose()));
+    }
+    request.push_str("\r\n");
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut response = Vec::new();
+    stream
+        .take(MAX_RESPONSE_BYTES + 1)
+        .read_to_end(&mut response)
+        .await
+        .map_err(|e| e.to_string())?;
+    if response.len() as u64 > MAX_RESPONSE_BYTES {
+        return Err(format!(
+            "the response is larger than {} bytes",
+            MAX_RESPONSE_BYTES
+        ));
+    }
+
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("the response has no headers")?;
+    let head = String::from_utf8_lossy(&response[..split]).to_string();
+    let body = &response[split + 4..];
+    let mut lines = head.split("\r\n");
+    let status = lines.next().and_then(|line| line.split(' ').nth(1));
+    if status != Some("200") {
+        return Err(format!(
+            "the response status is {}",
+            status.unwrap_or("missing")
+        ));
+    }
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.eq_ignore_ascii_case("transfer-encoding")
+                && value.trim().eq_ignore_ascii_case("chunked")
+        })
+    });
+    if chunked {
+        dechunk(body)
+    } else {
+        Ok(body.to_vec())
+    }
+}
+
+// The body of a response in the chunked transfer coding.
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    loop {
+        let end = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or("a chunk has no size")?;
+        let size = String::from_utf8_lossy(&body[..end]);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| "a chunk size is invalid")?;
+        body = &body[end + 2..];
+        if size == 0 {
+            return Ok(out);
+        }
+        let chunk = body.get(..size).ok_or("a chunk is truncated")?;
+        out.extend_from_slice(chunk);
+        body = body.get(size + 2..).unwrap_or_default();
+    }
+}
+
+/// The passwords that have been fetched, by their provider.
+#[derive(Default)]
+pub struct SecretCache {
+    fetched: std::sync::Mutex<HashMap<SecretProvider, (Instant, Secret)>>,
+}
+
+impl SecretCache {
+    /// The password of the provider, fetched if it isn't cached, or was
+    /// fetched more than cache_secs ago.
+    pub async fn get(&self, provider: &SecretProvider) -> Result<Secret, String> {
+        let ttl = Duration::from_secs(provider.cache_secs);
+        if let Ok(fetched) = self.fetched.lock() {
+            if let Some((at, secret)) = fetched.get(provider) {
+                if at.elapsed() < ttl {
+                    return Ok(secret.clone());
+                }
+            }
+        }
+        let secret = provider.fetch().await?;
+        if let Ok(mut fetched) = self.fetched.lock() {
+            fetched.insert(provider.clone(), (Instant::now(), secret.clone()));
+        }
+        Ok(secret)
+    }
+
+    /// Forget the password of the provider, such as when it has been refused,
+    /// so that it is fetched again.
+    pub fn invalidate(&self, provider: &SecretProvider) {
+        if let Ok(mut fetched) = self.fetched.lock() {
+            fetched.remove(provider);
+        }
+    }
+}
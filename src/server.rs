@@ -0,0 +1,2162 @@
+//! The proxy as a server: its listeners, the tasks that accept connections on
+//! them and maintain the backends and the cache, and their shutdown. The
+//! ldap-proxy binary runs a ProxyServer made from its config file, and other
+//! programs can embed one, built from a Config with ProxyBuilder, as the
+//! tests do.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use arc_swap::ArcSwap;
+use concread::arcache::ARCacheBuilder;
+use futures_util::sink::SinkExt;
+use ldap3_proto::proto::{LdapBindCred, LdapBindRequest};
+use ldap3_proto::{LdapCodec, LdapResultCode};
+#[cfg(not(feature = "rustls"))]
+use openssl::ssl::SslConnector;
+use openssl::ssl::{Ssl, SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::X509Name;
+#[cfg(not(feature = "rustls"))]
+use openssl::x509::X509;
+use tokio::net::{TcpListener, TcpStream, UdpSocket, UnixListener, UnixStream};
+use tokio::runtime::Runtime;
+use tokio::sync::{broadcast, Semaphore};
+use tokio::task::JoinHandle;
+use tokio_openssl::SslStream;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::{debug, error, info, warn};
+use url::Url;
+
+use crate::access::{AccessPolicy, ConfigAccessPolicy};
+use crate::admin::admin_process;
+use crate::audit::AuditLog;
+use crate::bindcache::BindCache;
+use crate::breaker::CircuitBreakers;
+use crate::cacheindex::CacheIndex;
+use crate::certmap::{ClientCertificate, UnmappedCertPolicy};
+use crate::cldap::{cldap_answer, CldapRelay, MAX_PENDING_PINGS};
+use crate::coalesce::{CacheRefreshes, SearchFlights};
+use crate::codec::ClientCodec;
+use crate::config::{load_config, Secret};
+use crate::connections::{ConnectionLimit, ConnectionTracker};
+use crate::dn::{normalize_dn, DnError};
+use crate::health::health_process;
+use crate::hook::SearchHook;
+use crate::latency::publish_latency;
+use crate::lockout::BindFailureTracker;
+use crate::metrics::Metrics;
+use crate::persist::{load_cache, save_cache};
+use crate::proxy::{
+    check_backend_health, client_process, client_starttls, notice_of_disconnection,
+    probe_upstream_pool, read_root_dse, read_schema, sweep_expired_cache, ServiceConnections,
+    UpstreamPool,
+};
+use crate::proxy_protocol::read_proxy_header;
+use crate::quota::DnQuotas;
+use crate::ratelimit::BindRateLimiter;
+use crate::retry::RetryPolicy;
+use crate::rootdse::{subschema_subentry, supports_control, RootDse};
+use crate::schema::{SchemaCache, DEFAULT_SCHEMA_DN};
+use crate::secrets::SecretCache;
+use crate::sort::{SortMode, OID_SERVER_SORT};
+use crate::systemd;
+use crate::tap::TapLog;
+use crate::{
+    ldapi_socket_path, network_contains, AppState, Backend, BackendConfig, BackendPool,
+    BackendStrategy, BackendTls, Config, DnConfig, ListenerConfig, ListenerMode, Policy,
+    ReferralMode, RuntimeFlavor, DEFAULT_BACKEND,
+};
+
+// Warnings about refused connections are logged at most once a second, so a
+// client that hammers us can't also flood the logs.
+#[derive(Default)]
+struct LimitWarnings {
+    last: Option<Instant>,
+    suppressed: usize,
+}
+
+impl LimitWarnings {
+    // If a warning should be logged now, returns how many were suppressed
+    // since the last warning.
+    fn check(&mut self) -> Option<usize> {
+        let now = Instant::now();
+        if self
+            .last
+            .map(|last| now.duration_since(last) >= Duration::from_secs(1))
+            .unwrap_or(true)
+        {
+            self.last = Some(now);
+            Some(std::mem::take(&mut self.suppressed))
+        } else {
+            self.suppressed += 1;
+            None
+        }
+    }
+}
+
+// Warnings about refused connections, shared by all connection tasks.
+#[derive(Default)]
+struct RefusalWarnings {
+    limits: Mutex<LimitWarnings>,
+    networks: Mutex<LimitWarnings>,
+}
+
+impl RefusalWarnings {
+    fn check(warnings: &Mutex<LimitWarnings>) -> Option<usize> {
+        warnings.lock().unwrap_or_else(|e| e.into_inner()).check()
+    }
+}
+
+// The config option of a connection limit.
+fn limit_reason(limit: ConnectionLimit) -> &'static str {
+    match limit {
+        ConnectionLimit::Total => "max_connections",
+        ConnectionLimit::PerIp => "max_connections_per_ip",
+    }
+}
+
+async fn refuse_connection(
+    app_state: &AppState,
+    warnings: &RefusalWarnings,
+    tcpstream: TcpStream,
+    client_socket_addr: SocketAddr,
+    limit: ConnectionLimit,
+    tls_parms: &SslAcceptor,
+    starttls: bool,
+) {
+    let reason = limit_reason(limit);
+    app_state
+        .metrics
+        .incr("connections_refused_total", &[("limit", reason)]);
+
+    if let Some(suppressed) = RefusalWarnings::check(&warnings.limits) {
+        warn!(
+            client = %client_socket_addr.ip(),
+            %suppressed,
+            "Refusing connection, {} reached", reason
+        );
+    }
+
+    let notice = notice_of_disconnection(LdapResultCode::Busy, "too many connections");
+    if starttls {
+        // The client hasn't started tls yet, and the notice isn't secret.
+        let _ = FramedWrite::new(tcpstream, LdapCodec::new(None))
+            .send(notice)
+            .await;
+        return;
+    }
+
+    // Complete the handshake so that the client can be told why it is being
+    // disconnected.
+    let Ok(mut tlsstream) =
+        Ssl::new(tls_parms.context()).and_then(|tls_obj| SslStream::new(tls_obj, tcpstream))
+    else {
+        return;
+    };
+    let handshake = SslStream::accept(Pin::new(&mut tlsstream));
+    if !matches!(
+        tokio::time::timeout(Duration::from_secs(5), handshake).await,
+        Ok(Ok(()))
+    ) {
+        return;
+    }
+    let mut w = FramedWrite::new(tlsstream, LdapCodec::new(None));
+    let _ = w.send(notice).await;
+}
+
+/// Request client certificates from the listener, if a client_ca is configured.
+fn configure_client_certs(tls_builder: &mut SslAcceptorBuilder, config: &Config) -> Option<()> {
+    let Some(client_ca) = config.client_ca.as_ref() else {
+        if config.require_client_cert {
+            error!("require_client_cert is set, but there is no client_ca");
+            return None;
+        }
+        if !config.cert_map.is_empty() {
+            warn!(
+                "cert_map is set, but there is no client_ca so no certificates will be requested"
+            );
+        }
+        return Some(());
+    };
+
+    if let Err(e) = tls_builder.set_ca_file(client_ca) {
+        error!("Unable to load client ca -> {:?}", e);
+        return None;
+    }
+    match X509Name::load_client_ca_file(client_ca) {
+        Ok(names) => tls_builder.set_client_ca_list(names),
+        Err(e) => {
+            error!("Unable to load client ca names -> {:?}", e);
+            return None;
+        }
+    }
+
+    let mut mode = SslVerifyMode::PEER;
+    if config.require_client_cert {
+        mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+    }
+
+    if config.require_client_cert
+        && config.unmapped_client_cert == UnmappedCertPolicy::RejectHandshake
+    {
+        let cert_map = Arc::new(config.cert_map.clone());
+        tls_builder.set_verify_callback(mode, move |preverify_ok, ctx| {
+            // Only the client's own certificate is mapped, not its issuers.
+            if !preverify_ok || ctx.error_depth() != 0 {
+                return preverify_ok;
+            }
+            let Some(cert) = ctx.current_cert() else {
+                return false;
+            };
+            let cert = ClientCertificate::from_x509(cert);
+            let mapped = cert.lookup(&cert_map).is_some();
+            if !mapped {
+                warn!(subject = %cert.subject, "Refusing unmapped client certificate");
+            }
+            mapped
+        });
+    } else {
+        tls_builder.set_verify(mode);
+    }
+
+    Some(())
+}
+
+async fn handle_connection(
+    mut tcpstream: TcpStream,
+    mut client_socket_addr: SocketAddr,
+    tls_parms: SslAcceptor,
+    starttls: bool,
+    app_state: Arc<AppState>,
+    warnings: Arc<RefusalWarnings>,
+) {
+    app_state.client_tcp.apply(&tcpstream);
+    if app_state.expect_proxy_protocol {
+        // Anyone else could claim any address in a header of their own.
+        if !network_contains(
+            &app_state.proxy_protocol_trusted_networks,
+            client_socket_addr.ip(),
+        ) {
+            app_state
+                .metrics
+                .incr("connections_refused_total", &[("limit", "proxy_protocol")]);
+            if let Some(suppressed) = RefusalWarnings::check(&warnings.networks) {
+                warn!(peer = %client_socket_addr, %suppressed, "Dropping connection from a peer that is not trusted to send the proxy protocol header");
+            }
+            return;
+        }
+        // The load balancer sends this as soon as it connects, so it shouldn't
+        // take long to arrive.
+        let header =
+            tokio::time::timeout(Duration::from_secs(5), read_proxy_header(&mut tcpstream)).await;
+        match header {
+            Ok(Ok(Some(source))) => {
+                debug!(proxy = %client_socket_addr, client = %source, "proxy protocol header");
+                client_socket_addr = source;
+            }
+            Ok(Ok(None)) => {
+                debug!(proxy = %client_socket_addr, "proxy protocol header without a source address");
+            }
+            Ok(Err(e)) => {
+                warn!(peer = %client_socket_addr, "Dropping connection, {}", e);
+                return;
+            }
+            Err(_) => {
+                warn!(peer = %client_socket_addr, "Dropping connection, timed out waiting for the proxy protocol header");
+                return;
+            }
+        }
+    }
+
+    if !app_state.client_network_permitted(client_socket_addr.ip()) {
+        app_state
+            .metrics
+            .incr("connections_refused_total", &[("limit", "client_networks")]);
+        if let Some(suppressed) = RefusalWarnings::check(&warnings.networks) {
+            warn!(client = %client_socket_addr.ip(), %suppressed, "Refusing connection from a network that is not permitted");
+        }
+        return;
+    }
+
+    let guard = match app_state.connections.try_acquire(client_socket_addr.ip()) {
+        Ok(guard) => guard,
+        Err(limit) => {
+            refuse_connection(
+                &app_state,
+                &warnings,
+                tcpstream,
+                client_socket_addr,
+                limit,
+                &tls_parms,
+                starttls,
+            )
+            .await;
+            return;
+        }
+    };
+    app_state.metrics.set(
+        "connections_active",
+        &[],
+        app_state.connections.total() as u64,
+    );
+
+    if starttls {
+        tcpstream = match client_starttls(tcpstream, client_socket_addr, &app_state).await {
+            Some(tcpstream) => tcpstream,
+            None => return,
+        };
+    }
+
+    let mut tlsstream = match Ssl::new(tls_parms.context())
+        .and_then(|tls_obj| SslStream::new(tls_obj, tcpstream))
+    {
+        Ok(ta) => ta,
+        Err(e) => {
+            error!("LDAP TLS setup error, continuing -> {:?}", e);
+            return;
+        }
+    };
+    if let Err(e) = SslStream::accept(Pin::new(&mut tlsstream)).await {
+        error!("LDAP TLS accept error, continuing -> {:?}", e);
+        return;
+    };
+    let client_cert = tlsstream
+        .ssl()
+        .peer_certificate()
+        .map(|cert| ClientCertificate::from_x509(&cert));
+    let max_incoming_ber_size = app_state.max_incoming_ber_size;
+    let (r, w) = tokio::io::split(tlsstream);
+    let r = FramedRead::new(r, ClientCodec::new(max_incoming_ber_size));
+    let w = FramedWrite::new(w, ClientCodec::new(max_incoming_ber_size));
+    client_process(r, w, client_socket_addr, client_cert, app_state.clone()).await;
+
+    drop(guard);
+    app_state.metrics.set(
+        "connections_active",
+        &[],
+        app_state.connections.total() as u64,
+    );
+}
+
+// Accept connections on the listener. On a StartTLS listener the connections are
+// plain until the client upgrades them, otherwise they are ldaps.
+async fn ldaps_acceptor(
+    listener: TcpListener,
+    tls_parms: Arc<ArcSwap<SslAcceptor>>,
+    starttls: bool,
+    budget: Option<Arc<Semaphore>>,
+    mut broadcast_rx: broadcast::Receiver<bool>,
+    app_state: Arc<AppState>,
+) {
+    let warnings = Arc::new(RefusalWarnings::default());
+    let local_addr = listener
+        .local_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    loop {
+        // Once the listener's sessions use its whole budget, new connections
+        // wait in its accept backlog until one of them ends.
+        let permit = match budget.as_ref() {
+            Some(budget) => {
+                if budget.available_permits() == 0 {
+                    app_state.metrics.incr(
+                        "listener_budget_exhausted_total",
+                        &[("listener", local_addr.as_str())],
+                    );
+                }
+                tokio::select! {
+                    _ = broadcast_rx.recv() => {
+                        break;
+                    }
+                    permit = budget.clone().acquire_owned() => permit.ok(),
+                }
+            }
+            None => None,
+        };
+        tokio::select! {
+            _ = broadcast_rx.recv() => {
+                break;
+            }
+            accept_result = listener.accept() => {
+                match accept_result {
+                    Ok((tcpstream, client_socket_addr)) => {
+                        // The certificate of the listener as it is now, so
+                        // that a new one is used from the next connection.
+                        let connection = handle_connection(
+                            tcpstream,
+                            client_socket_addr,
+                            SslAcceptor::clone(&tls_parms.load()),
+                            starttls,
+                            app_state.clone(),
+                            warnings.clone(),
+                        );
+                        tokio::spawn(async move {
+                            connection.await;
+                            drop(permit);
+                        });
+                    }
+                    Err(e) => {
+                        error!("LDAP acceptor error, continuing -> {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+    debug!(starttls, "Stopped ldaps acceptor");
+}
+
+// Connections on the ldapi socket come from this host, so they are treated as
+// coming from the loopback address. The client networks don't apply, as the
+// socket's permissions decide who can connect.
+async fn handle_ldapi_connection(stream: UnixStream, app_state: Arc<AppState>) {
+    let client_socket_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    match stream.peer_cred() {
+        Ok(cred) => {
+            info!(uid = %cred.uid(), gid = %cred.gid(), pid = ?cred.pid(), "ldapi connection");
+        }
+        Err(e) => debug!(?e, "Unable to read the credentials of an ldapi peer"),
+    }
+
+    let guard = match app_state.connections.try_acquire(client_socket_addr.ip()) {
+        Ok(guard) => guard,
+        Err(limit) => {
+            let reason = limit_reason(limit);
+            app_state
+                .metrics
+                .incr("connections_refused_total", &[("limit", reason)]);
+            warn!("Refusing ldapi connection, {} reached", reason);
+            let notice = notice_of_disconnection(LdapResultCode::Busy, "too many connections");
+            let _ = FramedWrite::new(stream, LdapCodec::new(None))
+                .send(notice)
+                .await;
+            return;
+        }
+    };
+    app_state.metrics.set(
+        "connections_active",
+        &[],
+        app_state.connections.total() as u64,
+    );
+
+    let max_incoming_ber_size = app_state.max_incoming_ber_size;
+    let (r, w) = tokio::io::split(stream);
+    let r = FramedRead::new(r, ClientCodec::new(max_incoming_ber_size));
+    let w = FramedWrite::new(w, ClientCodec::new(max_incoming_ber_size));
+    client_process(r, w, client_socket_addr, None, app_state.clone()).await;
+
+    drop(guard);
+    app_state.metrics.set(
+        "connections_active",
+        &[],
+        app_state.connections.total() as u64,
+    );
+}
+
+async fn ldapi_acceptor(
+    listener: UnixListener,
+    mut broadcast_rx: broadcast::Receiver<bool>,
+    app_state: Arc<AppState>,
+) {
+    loop {
+        tokio::select! {
+            _ = broadcast_rx.recv() => {
+                break;
+            }
+            accept_result = listener.accept() => {
+                match accept_result {
+                    Ok((stream, _)) => {
+                        tokio::spawn(handle_ldapi_connection(stream, app_state.clone()));
+                    }
+                    Err(e) => {
+                        error!("LDAPI acceptor error, continuing -> {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+    debug!("Stopped ldapi acceptor");
+}
+
+async fn admin_acceptor(
+    listener: TcpListener,
+    token: Secret,
+    mut broadcast_rx: broadcast::Receiver<bool>,
+    app_state: Arc<AppState>,
+) {
+    let token = Arc::new(token);
+    loop {
+        tokio::select! {
+            _ = broadcast_rx.recv() => {
+                break;
+            }
+            accept_result = listener.accept() => {
+                match accept_result {
+                    Ok((stream, addr)) => {
+                        debug!(%addr, "Admin connection");
+                        let app_state = app_state.clone();
+                        let token = token.clone();
+                        tokio::spawn(async move {
+                            admin_process(stream, &app_state, &token).await
+                        });
+                    }
+                    Err(e) => {
+                        error!("Admin acceptor error, continuing -> {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+    debug!("Stopped admin acceptor");
+}
+
+async fn health_acceptor(
+    listener: TcpListener,
+    mut broadcast_rx: broadcast::Receiver<bool>,
+    app_state: Arc<AppState>,
+) {
+    loop {
+        tokio::select! {
+            _ = broadcast_rx.recv() => {
+                break;
+            }
+            accept_result = listener.accept() => {
+                match accept_result {
+                    Ok((stream, _addr)) => {
+                        let app_state = app_state.clone();
+                        tokio::spawn(async move { health_process(stream, &app_state).await });
+                    }
+                    Err(e) => {
+                        error!("Health acceptor error, continuing -> {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+    debug!("Stopped health acceptor");
+}
+
+async fn cldap_responder(
+    socket: UdpSocket,
+    mut broadcast_rx: broadcast::Receiver<bool>,
+    app_state: Arc<AppState>,
+) {
+    let socket = Arc::new(socket);
+    let permits = Arc::new(Semaphore::new(MAX_PENDING_PINGS));
+    let mut datagram = vec![0; 65_535];
+    loop {
+        tokio::select! {
+            _ = broadcast_rx.recv() => {
+                break;
+            }
+            recv_result = socket.recv_from(&mut datagram) => {
+                match recv_result {
+                    Ok((len, addr)) => {
+                        let Ok(permit) = permits.clone().try_acquire_owned() else {
+                            app_state
+                                .metrics
+                                .incr("cldap_pings_total", &[("result", "dropped")]);
+                            continue;
+                        };
+                        let ping = datagram[..len].to_vec();
+                        let app_state = app_state.clone();
+                        let socket = socket.clone();
+                        tokio::spawn(async move {
+                            if let Some(answer) = cldap_answer(&app_state, &ping, addr.ip()).await {
+                                if let Err(e) = socket.send_to(&answer, addr).await {
+                                    debug!(?e, %addr, "Unable to send a cldap answer");
+                                }
+                            }
+                            drop(permit);
+                        });
+                    }
+                    Err(e) => {
+                        error!("CLDAP responder error, continuing -> {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+    debug!("Stopped cldap responder");
+}
+
+// Tell systemd that we are alive, for as long as the runtime is able to run
+// this. If it stops, systemd restarts the service.
+async fn watchdog(interval: Duration, mut broadcast_rx: broadcast::Receiver<bool>) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = broadcast_rx.recv() => {
+                break;
+            }
+            _ = interval.tick() => {
+                sd_notify("WATCHDOG=1");
+            }
+        }
+    }
+}
+
+// Periodically resolve the backend hostnames, so that changes to their addresses
+// are picked up without a restart.
+async fn backend_resolver(
+    app_state: Arc<AppState>,
+    dns_ttl: Duration,
+    mut broadcast_rx: broadcast::Receiver<bool>,
+) {
+    let mut interval = tokio::time::interval(dns_ttl);
+    // The first tick completes immediately, and we resolved during setup.
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = broadcast_rx.recv() => {
+                break;
+            }
+            _ = interval.tick() => {
+                for backend in app_state
+                    .backend_pools
+                    .values()
+                    .flat_map(|pool| pool.backends.iter())
+                {
+                    backend.resolve().await;
+                }
+            }
+        }
+    }
+    debug!("Stopped backend resolver");
+}
+
+// Periodically check the backend addresses, taking those that fail out of
+// rotation until they recover.
+async fn backend_health_checker(
+    app_state: Arc<AppState>,
+    check_interval: Duration,
+    mut broadcast_rx: broadcast::Receiver<bool>,
+) {
+    let mut interval = tokio::time::interval(check_interval);
+    loop {
+        tokio::select! {
+            _ = broadcast_rx.recv() => {
+                break;
+            }
+            _ = interval.tick() => {
+                check_backend_health(&app_state).await;
+            }
+        }
+    }
+    debug!("Stopped backend health checker");
+}
+
+// Periodically probe the idle pooled backend connections, replacing those that
+// have stopped working before a session checks them out.
+async fn upstream_pool_prober(
+    app_state: Arc<AppState>,
+    probe_interval: Duration,
+    mut broadcast_rx: broadcast::Receiver<bool>,
+) {
+    let mut interval = tokio::time::interval(probe_interval);
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = broadcast_rx.recv() => {
+                break;
+            }
+            _ = interval.tick() => {
+                let closed = probe_upstream_pool(&app_state).await;
+                if closed > 0 {
+                    info!("Closed {} pooled backend connections that failed their probe", closed);
+                }
+            }
+        }
+    }
+    debug!("Stopped upstream pool prober");
+}
+
+// Periodically remove expired entries from the cache, which would otherwise stay
+// until they are looked up again.
+async fn cache_sweeper(
+    app_state: Arc<AppState>,
+    sweep_interval: Duration,
+    mut broadcast_rx: broadcast::Receiver<bool>,
+) {
+    let mut interval = tokio::time::interval(sweep_interval);
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = broadcast_rx.recv() => {
+                break;
+            }
+            _ = interval.tick() => {
+                sweep_expired_cache(&app_state).await;
+            }
+        }
+    }
+    debug!("Stopped cache sweeper");
+}
+
+// Periodically save the cache, so that it survives the process being killed.
+async fn cache_saver(
+    app_state: Arc<AppState>,
+    path: PathBuf,
+    save_interval: Duration,
+    mut broadcast_rx: broadcast::Receiver<bool>,
+) {
+    let mut interval = tokio::time::interval(save_interval);
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = broadcast_rx.recv() => {
+                break;
+            }
+            _ = interval.tick() => {
+                let app_state = app_state.clone();
+                let path = path.clone();
+                let saved = tokio::task::spawn_blocking(move || save_cache(&app_state, &path)).await;
+                match saved {
+                    Ok(Ok(count)) => debug!("Saved {} cached searches", count),
+                    Ok(Err(e)) => error!(?e, "Unable to save cache"),
+                    Err(e) => error!(?e, "Cache save task failed"),
+                }
+            }
+        }
+    }
+    debug!("Stopped cache saver");
+}
+
+// Bind a listener for clients, whose kernel holds up to backlog connections
+// until they are accepted. Ipv6 listeners that are only_v6 only accept ipv6
+// connections, so that the same port can be listened on for ipv4 as well.
+fn bind_listener(addr: SocketAddr, backlog: u32, only_v6: bool) -> std::io::Result<TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(i32::try_from(backlog).unwrap_or(i32::MAX))?;
+    TcpListener::from_std(socket.into())
+}
+
+// Listen on the socket passed by systemd if we were socket activated, otherwise
+// bind the configured address.
+async fn open_listener(config: &Config) -> Option<TcpListener> {
+    let fds: Vec<_> = systemd::listen_fds()
+        .into_iter()
+        .filter(|fd| fd.name != config.starttls_listen_fd_name)
+        .collect();
+    if fds.is_empty() {
+        return match bind_listener(config.bind, config.accept_backlog, false) {
+            Ok(l) => Some(l),
+            Err(e) => {
+                error!(
+                    "Could not bind to LDAP server address {} -> {:?}",
+                    config.bind, e
+                );
+                None
+            }
+        };
+    }
+
+    let Some(fd) = systemd::select_listen_fd(&fds, &config.listen_fd_name) else {
+        error!(
+            "Socket activated, but no socket is named {} -> {:?}",
+            config.listen_fd_name, fds
+        );
+        return None;
+    };
+    // Safety: systemd passed this descriptor to us, and nothing else has
+    // taken it.
+    match unsafe { systemd::tcp_listener(fd) } {
+        Ok(l) => {
+            info!("Listening on socket activated descriptor {}", fd.name);
+            Some(l)
+        }
+        Err(e) => {
+            error!(
+                "Could not listen on socket activated descriptor {} -> {:?}",
+                fd.name, e
+            );
+            None
+        }
+    }
+}
+
+// Bind the ldapi socket, replacing the socket of an earlier run if it was
+// left behind.
+fn open_ldapi_listener(path: &Path, mode: Option<u32>) -> Option<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            error!("ldapi_bind {} exists and is not a socket", path.display());
+            return None;
+        }
+        if let Err(e) = std::fs::remove_file(path) {
+            error!(
+                "Could not remove old ldapi socket {} -> {:?}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+    }
+    let listener = match UnixListener::bind(path) {
+        Ok(l) => l,
+        Err(e) => {
+            error!(
+                "Could not bind to ldapi socket {} -> {:?}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+    };
+    if let Some(mode) = mode {
+        let permissions = std::fs::Permissions::from_mode(mode);
+        if let Err(e) = std::fs::set_permissions(path, permissions) {
+            error!(
+                "Could not set the permissions of {} -> {:?}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+    }
+    Some(listener)
+}
+
+// Tell systemd about our state. This does nothing if we aren't a notify service.
+fn sd_notify(state: &str) {
+    if let Err(e) = systemd::notify(state) {
+        warn!(?e, "Unable to notify systemd of {}", state);
+    }
+}
+
+fn parse_backends(
+    urls: &[Url],
+    starttls: bool,
+    weights: &BTreeMap<Url, u32>,
+) -> Option<Vec<Backend>> {
+    let mut backends = Vec::with_capacity(urls.len());
+
+    for url in urls.iter() {
+        let weight = weights.get(url).copied().unwrap_or(1);
+        if url.scheme() == "ldapi" {
+            let Some(path) = ldapi_socket_path(url) else {
+                error!(%url, "Unable to determine the socket path from url");
+                return None;
+            };
+            backends.push(Backend::unix(url.clone(), path).with_weight(weight));
+            continue;
+        }
+
+        let default_port = match url.scheme() {
+            "ldaps" => 636,
+            "ldap" if starttls => 389,
+            "ldap" => {
+                warn!(%url, "Connections to this backend are not encrypted");
+                389
+            }
+            _ => {
+                error!(%url, "Unable to proceed. ldap_url must be ldaps://, ldap:// or ldapi://");
+                return None;
+            }
+        };
+
+        let hostname = match url.host_str() {
+            Some(s) => s.to_string(),
+            None => {
+                error!(%url, "Unable to determine hostname from url");
+                return None;
+            }
+        };
+
+        // Addresses are resolved once the proxy has started.
+        let port = url.port().unwrap_or(default_port);
+        let backend = Backend::new(url.clone(), hostname, port, Vec::new()).with_weight(weight);
+        backends.push(if starttls {
+            backend.with_starttls()
+        } else {
+            backend
+        });
+    }
+
+    Some(backends)
+}
+
+// The certificate and key that are presented to backends.
+type ClientIdentity<'a> = Option<(&'a Path, &'a Path)>;
+
+// The identity that is configured, or the default if neither of the
+// certificate and key are.
+fn client_identity<'a>(
+    cert: Option<&'a Path>,
+    key: Option<&'a Path>,
+    default: ClientIdentity<'a>,
+) -> Option<ClientIdentity<'a>> {
+    match (cert, key) {
+        (Some(cert), Some(key)) => Some(Some((cert, key))),
+        (None, None) => Some(default),
+        _ => {
+            error!("ldap_client_cert and ldap_client_key must be set together");
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "rustls"))]
+fn build_tls_connector(
+    ldap_ca: &Path,
+    tls: &BackendTls,
+    identity: ClientIdentity,
+) -> Option<SslConnector> {
+    let mut tls_builder = match SslConnector::builder(SslMethod::tls_client()) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Unable to create tls client -> {:?}", e);
+            return None;
+        }
+    };
+
+    if matches!((tls.min_version, tls.max_version), (Some(min), Some(max)) if min > max) {
+        error!(
+            ?tls,
+            "ldap_tls_min_version is newer than ldap_tls_max_version"
+        );
+        return None;
+    }
+    if let Err(e) = tls.apply(&mut tls_builder) {
+        error!(
+            ?e,
+            ?tls,
+            "Unable to set the TLS versions or ciphers of backends"
+        );
+        return None;
+    }
+
+    let cert_store = tls_builder.cert_store_mut();
+    let mut file = match File::open(ldap_ca) {
+        Ok(f) => f,
+        Err(e) => {
+            error!(?e, "Unable to open {:?}", ldap_ca);
+            return None;
+        }
+    };
+
+    let mut pem = Vec::new();
+    if let Err(e) = file.read_to_end(&mut pem) {
+        error!(?e, "Unable to read {:?}", ldap_ca);
+        return None;
+    }
+
+    let ca_cert = match X509::from_pem(pem.as_slice()) {
+        Ok(c) => c,
+        Err(e) => {
+            error!(?e, "openssl");
+            return None;
+        }
+    };
+
+    if let Err(e) = cert_store.add_cert(ca_cert).map(|()| {
+        debug!("Added {:?} to cert store", ldap_ca);
+    }) {
+        error!(?e, "openssl");
+        return None;
+    };
+
+    if let Some((cert, key)) = identity {
+        if let Err(e) = tls_builder.set_certificate_chain_file(cert) {
+            error!(?e, "Unable to load ldap_client_cert {:?}", cert);
+            return None;
+        }
+        if let Err(e) = tls_builder.set_private_key_file(key, SslFiletype::PEM) {
+            error!(?e, "Unable to load ldap_client_key {:?}", key);
+            return None;
+        }
+        if let Err(e) = tls_builder.check_private_key() {
+            error!(?e, "ldap_client_key does not match ldap_client_cert");
+            return None;
+        }
+    }
+
+    // None for no cert verification
+    tls_builder.set_verify(SslVerifyMode::PEER);
+
+    Some(tls_builder.build())
+}
+
+fn build_backend_pool(
+    name: &str,
+    urls: &[Url],
+    (ldap_ca, tls): (&Path, &BackendTls),
+    (strategy, weights): (BackendStrategy, &BTreeMap<Url, u32>),
+    starttls: bool,
+    identity: ClientIdentity,
+) -> Option<BackendPool> {
+    let backends = parse_backends(urls, starttls, weights)?;
+    #[cfg(not(feature = "rustls"))]
+    let tls_params = build_tls_connector(ldap_ca, tls, identity)?;
+    #[cfg(feature = "rustls")]
+    let tls_params = crate::tls::build_rustls_config(ldap_ca, tls, identity)?;
+    if !tls.verify_hostname {
+        warn!(
+            backend = %name,
+            "The hostnames of backend certificates are not verified"
+        );
+    }
+    Some(BackendPool::new(name, tls_params, backends, strategy).with_tls(tls.clone()))
+}
+
+/// Build the default backend pool, the named pools, and a pool for each url that
+/// a DN references directly.
+pub fn build_backend_pools(sync_config: &Config) -> Option<BTreeMap<String, BackendPool>> {
+    let mut pools = BTreeMap::new();
+
+    let identity = client_identity(
+        sync_config.ldap_client_cert.as_deref(),
+        sync_config.ldap_client_key.as_deref(),
+        None,
+    )?;
+    for (key, entry) in sync_config.cert_map.iter() {
+        if entry.bind_password.is_none() && identity.is_none() {
+            warn!(
+                "cert_map entry {} has no bind_password, and binds with SASL EXTERNAL without an ldap_client_cert",
+                key
+            );
+        }
+    }
+
+    let default_pool = build_backend_pool(
+        DEFAULT_BACKEND,
+        &sync_config.ldap_url,
+        (&sync_config.ldap_ca, &sync_config.backend_tls(None)),
+        (sync_config.backend_strategy, &sync_config.backend_weights),
+        sync_config.ldap_starttls,
+        identity,
+    )?
+    .with_timeouts(sync_config.backend_timeouts(None))
+    .with_tcp(sync_config.backend_tcp(None));
+    pools.insert(DEFAULT_BACKEND.to_string(), default_pool);
+
+    for (name, backend_config) in sync_config.backends.iter() {
+        if name == DEFAULT_BACKEND {
+            error!("The backend name '{}' is reserved", DEFAULT_BACKEND);
+            return None;
+        }
+        let ldap_ca = backend_config
+            .ldap_ca
+            .as_deref()
+            .unwrap_or(&sync_config.ldap_ca);
+        let pool = build_backend_pool(
+            name,
+            &backend_config.ldap_url,
+            (ldap_ca, &sync_config.backend_tls(Some(backend_config))),
+            (
+                backend_config.backend_strategy,
+                &backend_config.backend_weights,
+            ),
+            backend_config
+                .ldap_starttls
+                .unwrap_or(sync_config.ldap_starttls),
+            client_identity(
+                backend_config.ldap_client_cert.as_deref(),
+                backend_config.ldap_client_key.as_deref(),
+                identity,
+            )?,
+        )?
+        .with_timeouts(sync_config.backend_timeouts(Some(backend_config)))
+        .with_tcp(sync_config.backend_tcp(Some(backend_config)));
+        pools.insert(name.clone(), pool);
+    }
+
+    let dnconfigs = sync_config
+        .binddn_map
+        .iter()
+        .map(|(dn, dnconfig)| (dn.as_str(), dnconfig))
+        .chain(sync_config.binddn_patterns.iter());
+    for (dn, dnconfig) in dnconfigs {
+        let Some(backend) = dnconfig.backend.as_ref() else {
+            continue;
+        };
+        if pools.contains_key(backend) {
+            continue;
+        }
+        let Ok(url) = Url::parse(backend) else {
+            error!(%dn, "backend '{}' is not a named backend or a url", backend);
+            return None;
+        };
+        let pool = build_backend_pool(
+            backend,
+            &[url],
+            (&sync_config.ldap_ca, &sync_config.backend_tls(None)),
+            (BackendStrategy::Ordered, &BTreeMap::new()),
+            sync_config.ldap_starttls,
+            identity,
+        )?
+        .with_timeouts(sync_config.backend_timeouts(None))
+        .with_tcp(sync_config.backend_tcp(None));
+        pools.insert(backend.clone(), pool);
+    }
+
+    Some(pools)
+}
+
+/// The TLS parameters that clients are served with.
+pub fn build_tls_acceptor(sync_config: &Config) -> Option<SslAcceptor> {
+    tls_acceptor(sync_config, &sync_config.tls_chain, &sync_config.tls_key)
+}
+
+// The TLS parameters of a listener that serves the certificate chain and key,
+// which accepts the same client certificates as the others.
+fn tls_acceptor(sync_config: &Config, chain: &Path, key: &Path) -> Option<SslAcceptor> {
+    let mut tls_builder = match SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Unable to create tls acceptor -> {:?}", e);
+            return None;
+        }
+    };
+
+    if let Err(e) = tls_builder.set_certificate_chain_file(chain) {
+        error!(chain = %chain.display(), "Unable to load certificate chain -> {:?}", e);
+        return None;
+    }
+
+    if let Err(e) = tls_builder.set_private_key_file(key, SslFiletype::PEM) {
+        error!(key = %key.display(), "Unable to load private key -> {:?}", e);
+        return None;
+    }
+
+    if let Err(e) = tls_builder.check_private_key() {
+        error!("Unable to validate private key -> {:?}", e);
+        return None;
+    }
+
+    configure_client_certs(&mut tls_builder, sync_config)?;
+
+    Some(tls_builder.build())
+}
+
+// The certificate chain and key of a listener that has its own.
+fn listener_certificate(listener: &ListenerConfig) -> Result<Option<(&Path, &Path)>, String> {
+    match (listener.tls_chain.as_deref(), listener.tls_key.as_deref()) {
+        (Some(chain), Some(key)) => Ok(Some((chain, key))),
+        (None, None) => Ok(None),
+        _ => Err(format!(
+            "The listener on {} needs both tls_chain and tls_key",
+            listener.bind
+        )),
+    }
+}
+
+/// Load the certificates that the listeners serve, as the proxy does when it
+/// starts.
+pub fn check_listener_tls(sync_config: &Config) -> Result<(), String> {
+    ListenerTls::new(sync_config).map(|_| ())
+}
+
+// The certificates that the listeners serve: that of tls_chain and tls_key,
+// and those of the listeners that have their own, by their bind address.
+struct ListenerTls {
+    default: Arc<ArcSwap<SslAcceptor>>,
+    listeners: Vec<(SocketAddr, Arc<ArcSwap<SslAcceptor>>)>,
+}
+
+impl ListenerTls {
+    fn new(sync_config: &Config) -> Result<Self, String> {
+        let default =
+            build_tls_acceptor(sync_config).ok_or("unable to load the listener certificate")?;
+        let mut listeners = Vec::new();
+        for listener in sync_config.listeners.iter() {
+            if let Some((chain, key)) = listener_certificate(listener)? {
+                let acceptor = tls_acceptor(sync_config, chain, key).ok_or_else(|| {
+                    format!("unable to load the certificate of {}", listener.bind)
+                })?;
+                listeners.push((listener.bind, Arc::new(ArcSwap::from_pointee(acceptor))));
+            }
+        }
+        Ok(ListenerTls {
+            default: Arc::new(ArcSwap::from_pointee(default)),
+            listeners,
+        })
+    }
+
+    // The certificate that the listener on this address serves.
+    fn acceptor(&self, bind: Option<SocketAddr>) -> Arc<ArcSwap<SslAcceptor>> {
+        bind.and_then(|bind| {
+            self.listeners
+                .iter()
+                .find(|(addr, _)| *addr == bind)
+                .map(|(_, tls)| tls.clone())
+        })
+        .unwrap_or_else(|| self.default.clone())
+    }
+}
+
+/// Read and load the config file.
+pub fn read_config(path: &Path) -> Result<Config, String> {
+    let mut f = File::open(path).map_err(|e| {
+        format!(
+            "Unable to open config file '{}' [{:?}] 🥺",
+            path.display(),
+            e
+        )
+    })?;
+
+    let mut contents = String::new();
+    f.read_to_string(&mut contents).map_err(|e| {
+        format!(
+            "unable to read config contents from '{}' {:?}",
+            path.display(),
+            e
+        )
+    })?;
+
+    load_config(&contents, path, std::env::vars())
+        .map_err(|e| format!("unable to load config: {}", e))
+}
+
+// Replace the bind maps, access rules and cache settings with those of the
+// config, and load the listener's certificate again. Everything else needs a
+// restart to change. The current settings are kept if the new config is
+// invalid.
+fn reload_config(config: &Config, app_state: &AppState, tls: &ListenerTls) -> bool {
+    reload_tls(config, app_state, tls);
+    let policy = Policy::from_config(config);
+    let unknown_backend = policy.dn_configs().find_map(|(dn, dnconfig)| {
+        dnconfig
+            .backend
+            .as_ref()
+            .filter(|backend| !app_state.backend_pools.contains_key(*backend))
+            .map(|backend| (dn, backend))
+    });
+    if let Some((dn, backend)) = unknown_backend {
+        error!(%dn, "Not reloading, backend '{}' needs a restart to add", backend);
+        return false;
+    }
+    app_state.policy.store(Arc::new(policy));
+    app_state.metrics.incr("config_reloads_total", &[]);
+    true
+}
+
+// Swap the certificates and keys that new client connections are served with
+// for those of the config. Connections that are open keep the ones they were
+// accepted with. A certificate that can't be loaded is kept as it is. The
+// listeners that a restart would add are left to it.
+fn reload_tls(config: &Config, app_state: &AppState, tls: &ListenerTls) {
+    match build_tls_acceptor(config) {
+        Some(acceptor) => {
+            tls.default.store(Arc::new(acceptor));
+            app_state.metrics.incr("tls_reloads_total", &[]);
+            info!(chain = %config.tls_chain.display(), "Loaded the listener certificate");
+        }
+        None => error!("Keeping the current listener certificate"),
+    }
+    for (bind, listener_tls) in tls.listeners.iter() {
+        let certificate = config
+            .listeners
+            .iter()
+            .find(|listener| listener.bind == *bind)
+            .and_then(|listener| listener_certificate(listener).ok().flatten());
+        let Some((chain, key)) = certificate else {
+            warn!(%bind, "Keeping the current listener certificate until a restart");
+            continue;
+        };
+        match tls_acceptor(config, chain, key) {
+            Some(acceptor) => {
+                listener_tls.store(Arc::new(acceptor));
+                app_state.metrics.incr("tls_reloads_total", &[]);
+                info!(%bind, chain = %chain.display(), "Loaded the listener certificate");
+            }
+            None => error!(%bind, "Keeping the current listener certificate"),
+        }
+    }
+}
+
+// The modification times of the listeners' certificates and keys, or None for
+// a file that can't be read.
+fn tls_file_times(config: &Config) -> Vec<Option<SystemTime>> {
+    let listener_files = config
+        .listeners
+        .iter()
+        .flat_map(|listener| [&listener.tls_chain, &listener.tls_key])
+        .flatten();
+    [&config.tls_chain, &config.tls_key]
+        .into_iter()
+        .chain(listener_files)
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+// Load the listener's certificate again whenever its files change, such as when
+// they are renewed. The config is read again to find them, so that the files
+// of a reloaded config are watched.
+async fn tls_watcher(
+    path: PathBuf,
+    app_state: Arc<AppState>,
+    tls: Arc<ListenerTls>,
+    mut times: Vec<Option<SystemTime>>,
+    interval: Duration,
+    mut broadcast_rx: broadcast::Receiver<bool>,
+) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = broadcast_rx.recv() => {
+                break;
+            }
+            _ = interval.tick() => {
+                let config = match read_config(&path) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        warn!("Unable to check the listener certificate, {}", e);
+                        continue;
+                    }
+                };
+                let now = tls_file_times(&config);
+                if now != times {
+                    debug!("The listener certificate or key has changed");
+                    times = now;
+                    reload_tls(&config, &app_state, &tls);
+                }
+            }
+        }
+    }
+    debug!("Stopped tls watcher");
+}
+
+/// The state shared by client sessions, without the root DSE, which is read
+/// from the backend once it is reachable.
+pub fn build_app_state(
+    sync_config: &Config,
+    audit: Option<Arc<AuditLog>>,
+    tap: Option<Arc<TapLog>>,
+) -> Option<AppState> {
+    let backend_pools = build_backend_pools(sync_config)?;
+
+    let Some(cache) = ARCacheBuilder::new()
+        .set_size(sync_config.cache_bytes, 0)
+        .build()
+    else {
+        error!("Unable to build query cache");
+        return None;
+    };
+
+    let upstream_pool = UpstreamPool::new(
+        sync_config.upstream_pool_size,
+        Duration::from_secs(sync_config.upstream_pool_max_idle_secs),
+        Duration::from_secs(sync_config.upstream_pool_max_lifetime_secs),
+    );
+    let upstream_pool = match (
+        &sync_config.upstream_pool_neutral_dn,
+        &sync_config.upstream_pool_neutral_password,
+    ) {
+        (Some(dn), Some(password)) => upstream_pool.with_neutral_bind(dn, password.expose()),
+        (None, None) => upstream_pool,
+        _ => {
+            error!(
+                "upstream_pool_neutral_dn and upstream_pool_neutral_password must be set together"
+            );
+            return None;
+        }
+    };
+
+    let anonymous_bind = match (
+        &sync_config.anonymous_bind_dn,
+        &sync_config.anonymous_bind_password,
+    ) {
+        (Some(dn), Some(password)) => Some(LdapBindRequest {
+            dn: dn.clone(),
+            cred: LdapBindCred::Simple(password.expose().to_string()),
+        }),
+        (None, None) => None,
+        _ => {
+            error!("anonymous_bind_dn and anonymous_bind_password must be set together");
+            return None;
+        }
+    };
+
+    if sync_config.expect_proxy_protocol && sync_config.proxy_protocol_trusted_networks.is_empty() {
+        error!("expect_proxy_protocol requires proxy_protocol_trusted_networks");
+        return None;
+    }
+
+    if sync_config.referral_mode == ReferralMode::Chase
+        && sync_config.referral_chase_hosts.is_empty()
+    {
+        error!("Chasing referrals requires referral_chase_hosts");
+        return None;
+    }
+
+    let max_incoming_ber_size = sync_config.max_incoming_ber_size;
+    let max_proxy_ber_size = sync_config.max_proxy_ber_size;
+
+    Some(AppState {
+        backend_pools,
+        backend_suffixes: sync_config
+            .backends
+            .iter()
+            .flat_map(|(name, backend)| {
+                backend
+                    .bind_dn_suffixes
+                    .iter()
+                    .map(move |suffix| (suffix.clone(), name.clone()))
+            })
+            .collect(),
+        naming_contexts: sync_config
+            .backends
+            .iter()
+            .flat_map(|(name, backend)| {
+                backend
+                    .naming_contexts
+                    .iter()
+                    .map(move |context| (context.clone(), name.clone()))
+            })
+            .collect(),
+        global_catalogs: sync_config
+            .backends
+            .iter()
+            .flat_map(|(name, backend)| {
+                backend
+                    .global_catalog_roots
+                    .iter()
+                    .map(move |root| (root.clone(), name.clone()))
+            })
+            .collect(),
+        fan_out: sync_config
+            .backends
+            .iter()
+            .filter(|(_, backend)| backend.fan_out)
+            .map(|(name, _)| name.clone())
+            .collect(),
+        breakers: CircuitBreakers::new(
+            sync_config.breaker_failure_threshold,
+            Duration::from_secs(sync_config.breaker_max_backoff_secs),
+        ),
+        retry: RetryPolicy::new(
+            sync_config.retry_attempts,
+            Duration::from_millis(sync_config.retry_base_delay_ms),
+        ),
+        connections: Arc::new(ConnectionTracker::new(
+            sync_config.max_connections,
+            sync_config.max_connections_per_ip,
+        )),
+        bind_limiter: sync_config
+            .bind_rate_per_ip
+            .map(|rate| BindRateLimiter::new(rate, sync_config.bind_burst_per_ip)),
+        upstream_pool,
+        service_connections: ServiceConnections::default(),
+        secrets: SecretCache::default(),
+        bind_cache: sync_config
+            .bind_cache_ttl_secs
+            .map(|secs| BindCache::new(Duration::from_secs(secs))),
+        bind_failures: BindFailureTracker::new(
+            sync_config.bind_failure_threshold,
+            Duration::from_secs(sync_config.bind_failure_window_secs),
+            sync_config.bind_lockout_secs.map(Duration::from_secs),
+            sync_config.bind_lockout_by_dn,
+        )
+        .with_lockout_by_ip(sync_config.bind_lockout_by_ip),
+        metrics: Metrics::default(),
+        per_dn_metrics: sync_config.per_dn_metrics,
+        ad_compat: sync_config.ad_compat,
+        log_filter_values: sync_config.log_filter_values,
+        policy: ArcSwap::from_pointee(Policy::from_config(sync_config)),
+        cache,
+        idle_timeout: sync_config.idle_timeout_secs.map(Duration::from_secs),
+        max_session_operations: sync_config.max_session_operations,
+        backend_sorts: sync_config.server_sort == SortMode::Backend,
+        max_incoming_ber_size,
+        client_tcp: sync_config.client_tcp(),
+        cldap_port: sync_config.cldap_backend_port,
+        cldap_allowed_networks: sync_config.cldap_allowed_networks.clone(),
+        cldap_relay: CldapRelay::default(),
+        max_proxy_ber_size,
+        expect_proxy_protocol: sync_config.expect_proxy_protocol,
+        proxy_protocol_trusted_networks: sync_config.proxy_protocol_trusted_networks.clone(),
+        max_cacheable_result_bytes: sync_config.max_cacheable_result_bytes,
+        cache_index: CacheIndex::default(),
+        search_flights: sync_config.coalesce_searches.then(SearchFlights::default),
+        cache_refreshes: CacheRefreshes::default(),
+        dn_quotas: DnQuotas::default(),
+        cache_min_entry_weight: sync_config
+            .max_cache_entries
+            .map(|entries| sync_config.cache_bytes.div_ceil(entries.max(1)))
+            .unwrap_or(1),
+        max_relayed_entries: sync_config.max_relayed_entries,
+        max_cacheable_entries: sync_config.max_cacheable_entries,
+        referral_mode: sync_config.referral_mode,
+        referral_rewrite_host: sync_config
+            .referral_rewrite_host
+            .clone()
+            .unwrap_or_else(|| sync_config.bind.to_string()),
+        referral_rewrite_map: sync_config.referral_rewrite_map.clone(),
+        referral_hop_limit: sync_config.referral_hop_limit,
+        referral_chase_hosts: sync_config.referral_chase_hosts.clone(),
+        cert_map: sync_config.cert_map.clone(),
+        reject_unmapped_cert_binds: sync_config.require_client_cert
+            && sync_config.unmapped_client_cert == UnmappedCertPolicy::RejectBind,
+        anonymous_bind,
+        root_dse: None,
+        schema: None,
+        access: Arc::new(ConfigAccessPolicy),
+        search_hook: None,
+        dn_rewrite: sync_config.dn_rewrite.clone().map(Arc::new),
+        member_of: sync_config.member_of.clone(),
+        audit,
+        tap,
+        sessions: Arc::default(),
+        listening: AtomicBool::new(false),
+    })
+}
+
+/// The runtime that the config asks for, which the proxy is run on by the
+/// ldap-proxy binary. An embedded proxy runs on the runtime of its program.
+pub fn build_runtime(config: &Config) -> std::io::Result<Runtime> {
+    let mut builder = match config.runtime {
+        RuntimeFlavor::MultiThread => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            if let Some(threads) = config.worker_threads {
+                builder.worker_threads(threads.max(1));
+            }
+            builder
+        }
+        RuntimeFlavor::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+    };
+    builder.enable_all().build()
+}
+
+/// A proxy that is yet to start, made from a config and the parts of one that
+/// can't be written in a config file, such as an access policy or a listener
+/// that is already bound.
+pub struct ProxyBuilder {
+    config: Config,
+    config_path: Option<PathBuf>,
+    listener: Option<TcpListener>,
+    access: Option<Arc<dyn AccessPolicy>>,
+    search_hook: Option<Arc<dyn SearchHook>>,
+}
+
+impl ProxyBuilder {
+    pub fn new(config: Config) -> Self {
+        ProxyBuilder {
+            config,
+            config_path: None,
+            listener: None,
+            access: None,
+            search_hook: None,
+        }
+    }
+
+    /// A proxy of the config file, which is read again when the proxy is
+    /// reloaded, and to find the listener certificate that is watched with
+    /// tls_reload_interval_secs.
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let mut builder = ProxyBuilder::new(read_config(path)?);
+        builder.config_path = Some(path.to_path_buf());
+        Ok(builder)
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Listen for ldaps connections on this address, in place of the config's
+    /// bind.
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.config.bind = addr;
+        self
+    }
+
+    /// Accept ldaps connections from a listener that is already bound, such
+    /// as one on port 0, in place of binding the config's address.
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Connect the default backend pool to these urls, in place of the
+    /// config's ldap_url.
+    pub fn ldap_url(mut self, urls: Vec<Url>) -> Self {
+        self.config.ldap_url = urls;
+        self
+    }
+
+    /// Add a named backend pool, replacing any of the same name.
+    pub fn backend(mut self, name: &str, backend: BackendConfig) -> Self {
+        self.config.backends.insert(name.to_string(), backend);
+        self
+    }
+
+    /// Add the bind map entry of a DN, replacing any that it has.
+    pub fn bind_dn(mut self, dn: &str, dnconfig: DnConfig) -> Result<Self, DnError> {
+        self.config.binddn_map.insert(normalize_dn(dn)?, dnconfig);
+        Ok(self)
+    }
+
+    /// Decide binds and searches with this policy, in place of the bind maps
+    /// alone.
+    pub fn access_policy(mut self, access: Arc<dyn AccessPolicy>) -> Self {
+        self.access = Some(access);
+        self
+    }
+
+    pub fn search_hook(mut self, search_hook: Arc<dyn SearchHook>) -> Self {
+        self.search_hook = Some(search_hook);
+        self
+    }
+
+    /// Open the listeners and logs, resolve the backends, and start accepting
+    /// connections. The problems that stop the proxy from starting are also
+    /// logged.
+    pub async fn start(self) -> Result<ProxyServer, String> {
+        let ProxyBuilder {
+            config: sync_config,
+            config_path,
+            listener,
+            access,
+            search_hook,
+        } = self;
+
+        // Setup the broadcast system.
+        let (broadcast_tx, broadcast_rx) = broadcast::channel(1);
+
+        // Let the listening port ready.
+        let listener = match listener {
+            Some(listener) => listener,
+            None => open_listener(&sync_config)
+                .await
+                .ok_or("unable to open the listener")?,
+        };
+        let local_addr = listener
+            .local_addr()
+            .map_err(|e| format!("unable to find the listener's address {:?}", e))?;
+        let listen_fds = systemd::listen_fds();
+        let starttls_fd = listen_fds
+            .iter()
+            .find(|fd| fd.name == sync_config.starttls_listen_fd_name);
+        let starttls_listener = match (starttls_fd, sync_config.starttls_bind) {
+            // Safety: systemd passed this descriptor to us, and open_listener
+            // leaves it alone.
+            (Some(fd), _) => match unsafe { systemd::tcp_listener(fd) } {
+                Ok(l) => {
+                    info!(
+                        "Listening for StartTLS on socket activated descriptor {}",
+                        fd.name
+                    );
+                    Some(l)
+                }
+                Err(e) => {
+                    return Err(format!(
+                        "Could not listen on socket activated descriptor {} -> {:?}",
+                        fd.name, e
+                    ));
+                }
+            },
+            (None, Some(addr)) => match bind_listener(addr, sync_config.accept_backlog, false) {
+                Ok(l) => Some(l),
+                Err(e) => {
+                    return Err(format!(
+                        "Could not bind to StartTLS address {} -> {:?}",
+                        addr, e
+                    ));
+                }
+            },
+            (None, None) => None,
+        };
+
+        let mut extra_listeners = Vec::with_capacity(sync_config.listeners.len());
+        for listener in sync_config.listeners.iter() {
+            let backlog = listener
+                .accept_backlog
+                .unwrap_or(sync_config.accept_backlog);
+            match bind_listener(listener.bind, backlog, true) {
+                Ok(l) => {
+                    info!(mode = ?listener.mode, "Listening on {}", listener.bind);
+                    let max_sessions = listener
+                        .max_active_sessions
+                        .or(sync_config.max_active_sessions);
+                    extra_listeners.push((
+                        l,
+                        listener.mode == ListenerMode::Starttls,
+                        max_sessions,
+                        Some(listener.bind),
+                    ));
+                }
+                Err(e) => {
+                    return Err(format!(
+                        "Could not bind to listener address {} -> {:?}",
+                        listener.bind, e
+                    ));
+                }
+            }
+        }
+
+        let ldapi_listener = match sync_config.ldapi_bind.as_deref() {
+            Some(path) => Some(
+                open_ldapi_listener(path, sync_config.ldapi_mode)
+                    .ok_or("unable to open the ldapi socket")?,
+            ),
+            None => None,
+        };
+
+        let admin_listener = match (sync_config.admin_bind, sync_config.admin_token.clone()) {
+            (Some(addr), Some(token)) => match TcpListener::bind(addr).await {
+                Ok(l) => Some((l, token)),
+                Err(e) => {
+                    return Err(format!(
+                        "Could not bind to admin address {} -> {:?}",
+                        addr, e
+                    ));
+                }
+            },
+            (Some(_), None) => return Err("admin_bind requires admin_token".to_string()),
+            (None, _) => None,
+        };
+
+        let health_listener = match sync_config.health_bind {
+            Some(addr) => match TcpListener::bind(addr).await {
+                Ok(l) => Some(l),
+                Err(e) => {
+                    return Err(format!(
+                        "Could not bind to health address {} -> {:?}",
+                        addr, e
+                    ));
+                }
+            },
+            None => None,
+        };
+
+        let cldap_socket = match sync_config.cldap_bind {
+            Some(addr) => match UdpSocket::bind(addr).await {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    return Err(format!(
+                        "Could not bind to cldap address {} -> {:?}",
+                        addr, e
+                    ));
+                }
+            },
+            None => None,
+        };
+
+        // Setup the data for the client handles.
+
+        let audit = match sync_config.audit.as_ref().map(AuditLog::open).transpose() {
+            Ok(audit) => audit.map(Arc::new),
+            Err(e) => return Err(format!("Unable to open the audit log {:?}", e)),
+        };
+
+        let tap = match sync_config.tap.as_ref().map(TapLog::open).transpose() {
+            Ok(tap) => tap.map(Arc::new),
+            Err(e) => return Err(format!("Unable to open the tap log {:?}", e)),
+        };
+
+        let mut app_state =
+            build_app_state(&sync_config, audit, tap).ok_or("unable to set up the proxy")?;
+        if let Some(access) = access {
+            app_state.access = access;
+        }
+        app_state.search_hook = search_hook;
+
+        // Resolve the backends now. If this fails we still start, and the
+        // resolver task will keep trying.
+        for backend in app_state
+            .backend_pools
+            .values()
+            .flat_map(|pool| pool.backends.iter())
+        {
+            backend.resolve().await;
+        }
+
+        // The backend's root DSE says which of the controls that the proxy can
+        // handle itself it supports, and can be answered with, and where its
+        // schema is.
+        let from_backend = sync_config
+            .root_dse
+            .as_ref()
+            .is_some_and(|root_dse_config| root_dse_config.from_backend);
+        let learn_schema_dn = sync_config
+            .schema
+            .as_ref()
+            .is_some_and(|schema| schema.dn.is_none());
+        let learned =
+            if from_backend || sync_config.server_sort == SortMode::Auto || learn_schema_dn {
+                match read_root_dse(&app_state).await {
+                    Ok(entry) => Some(entry),
+                    Err(e) => {
+                        warn!(?e, "Unable to read the root DSE of the backend");
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+        if sync_config.server_sort == SortMode::Auto {
+            app_state.backend_sorts = learned
+                .as_ref()
+                .is_some_and(|entry| supports_control(entry, OID_SERVER_SORT));
+            info!(
+                "Searches with the sort control are sorted by the {}",
+                if app_state.backend_sorts {
+                    "backend"
+                } else {
+                    "proxy"
+                }
+            );
+        }
+        if let Some(schema_config) = sync_config.schema.as_ref() {
+            let dn = schema_config
+                .dn
+                .clone()
+                .or_else(|| learned.as_ref().and_then(subschema_subentry))
+                .unwrap_or_else(|| DEFAULT_SCHEMA_DN.to_string());
+            info!(%dn, "Answering searches for the subschema subentry");
+            app_state.schema = Some(SchemaCache::new(
+                &dn,
+                Duration::from_secs(schema_config.ttl_secs),
+            ));
+            if let Err(e) = read_schema(&app_state).await {
+                warn!(?e, "Unable to read the subschema subentry, it will be read when it is first searched for");
+            }
+        }
+        if let Some(root_dse_config) = sync_config.root_dse.as_ref() {
+            let learned = learned.filter(|_| root_dse_config.from_backend);
+            app_state.root_dse = Some(RootDse::new(
+                root_dse_config,
+                learned.as_ref(),
+                &app_state.policy.load().request_controls,
+            ));
+        }
+        let app_state = Arc::new(app_state);
+
+        if let Some(path) = sync_config.cache_persist_path.as_ref() {
+            load_cache(&app_state, path);
+        }
+
+        // Setup the TLS server parameters
+        let tls_server_params = Arc::new(ListenerTls::new(&sync_config)?);
+
+        let mut workers = Vec::new();
+
+        // A config without a file has nothing to read the certificate's paths
+        // from again, so it isn't watched.
+        let tls_watched = sync_config
+            .tls_reload_interval_secs
+            .zip(config_path.clone());
+        if let Some((secs, path)) = tls_watched {
+            let watcher_app_state = app_state.clone();
+            let tls = tls_server_params.clone();
+            let times = tls_file_times(&sync_config);
+            let broadcast_rx = broadcast_tx.subscribe();
+            let interval = Duration::from_secs(secs.max(1));
+            workers.push(tokio::spawn(async move {
+                tls_watcher(path, watcher_app_state, tls, times, interval, broadcast_rx).await
+            }));
+        }
+
+        if !app_state.is_ready() {
+            warn!("Some backends could not be resolved, they will be retried");
+        }
+
+        let resolver_app_state = app_state.clone();
+        let resolver_broadcast_rx = broadcast_tx.subscribe();
+        let dns_ttl = Duration::from_secs(sync_config.dns_ttl_secs.max(1));
+        workers.push(tokio::spawn(async move {
+            backend_resolver(resolver_app_state, dns_ttl, resolver_broadcast_rx).await
+        }));
+
+        if let Some(secs) = sync_config.health_check_interval_secs {
+            let checker_app_state = app_state.clone();
+            let checker_broadcast_rx = broadcast_tx.subscribe();
+            let check_interval = Duration::from_secs(secs.max(1));
+            workers.push(tokio::spawn(async move {
+                backend_health_checker(checker_app_state, check_interval, checker_broadcast_rx)
+                    .await
+            }));
+        }
+
+        let probe_interval = sync_config
+            .upstream_pool_probe_interval_secs
+            .filter(|_| sync_config.upstream_pool_size > 0);
+        if let Some(secs) = probe_interval {
+            let prober_app_state = app_state.clone();
+            let prober_broadcast_rx = broadcast_tx.subscribe();
+            let probe_interval = Duration::from_secs(secs.max(1));
+            workers.push(tokio::spawn(async move {
+                upstream_pool_prober(prober_app_state, probe_interval, prober_broadcast_rx).await
+            }));
+        }
+
+        let sweeper_app_state = app_state.clone();
+        let sweeper_broadcast_rx = broadcast_tx.subscribe();
+        let sweep_interval = Duration::from_secs(sync_config.cache_sweep_interval_secs.max(1));
+        workers.push(tokio::spawn(async move {
+            cache_sweeper(sweeper_app_state, sweep_interval, sweeper_broadcast_rx).await
+        }));
+
+        let persist = sync_config
+            .cache_persist_path
+            .clone()
+            .zip(sync_config.cache_persist_interval_secs);
+        if let Some((path, secs)) = persist {
+            let saver_app_state = app_state.clone();
+            let broadcast_rx = broadcast_tx.subscribe();
+            let save_interval = Duration::from_secs(secs.max(1));
+            workers.push(tokio::spawn(async move {
+                cache_saver(saver_app_state, path, save_interval, broadcast_rx).await
+            }));
+        }
+
+        // Setup the acceptors.
+        let mut acceptors = Vec::new();
+        let max_sessions = sync_config.max_active_sessions;
+        let tcp_listeners = [(listener, false, max_sessions, None)]
+            .into_iter()
+            .chain(starttls_listener.map(|listener| (listener, true, max_sessions, None)))
+            .chain(extra_listeners);
+        for (listener, starttls, max_sessions, bind) in tcp_listeners {
+            let acceptor_app_state = app_state.clone();
+            let tls_server_params = tls_server_params.acceptor(bind);
+            let budget = max_sessions.map(|max| Arc::new(Semaphore::new(max.max(1))));
+            let broadcast_rx = broadcast_tx.subscribe();
+            acceptors.push(tokio::spawn(async move {
+                ldaps_acceptor(
+                    listener,
+                    tls_server_params,
+                    starttls,
+                    budget,
+                    broadcast_rx,
+                    acceptor_app_state,
+                )
+                .await
+            }));
+        }
+        if let Some(listener) = ldapi_listener {
+            let acceptor_app_state = app_state.clone();
+            let broadcast_rx = broadcast_tx.subscribe();
+            acceptors.push(tokio::spawn(async move {
+                ldapi_acceptor(listener, broadcast_rx, acceptor_app_state).await
+            }));
+        }
+        if let Some((listener, token)) = admin_listener {
+            let acceptor_app_state = app_state.clone();
+            let broadcast_rx = broadcast_tx.subscribe();
+            acceptors.push(tokio::spawn(async move {
+                admin_acceptor(listener, token, broadcast_rx, acceptor_app_state).await
+            }));
+        }
+        if let Some(listener) = health_listener {
+            let acceptor_app_state = app_state.clone();
+            let broadcast_rx = broadcast_tx.subscribe();
+            acceptors.push(tokio::spawn(async move {
+                health_acceptor(listener, broadcast_rx, acceptor_app_state).await
+            }));
+        }
+        if let Some(socket) = cldap_socket {
+            let responder_app_state = app_state.clone();
+            let broadcast_rx = broadcast_tx.subscribe();
+            acceptors.push(tokio::spawn(async move {
+                cldap_responder(socket, broadcast_rx, responder_app_state).await
+            }));
+        }
+        drop(broadcast_rx);
+
+        app_state.listening.store(true, Ordering::Relaxed);
+
+        Ok(ProxyServer {
+            config: sync_config,
+            config_path,
+            app_state,
+            tls: tls_server_params,
+            local_addr,
+            broadcast_tx,
+            acceptors,
+            workers,
+        })
+    }
+}
+
+/// A proxy that is accepting connections, until it is shut down.
+pub struct ProxyServer {
+    config: Config,
+    config_path: Option<PathBuf>,
+    app_state: Arc<AppState>,
+    tls: Arc<ListenerTls>,
+    local_addr: SocketAddr,
+    broadcast_tx: broadcast::Sender<bool>,
+    // The tasks that accept connections, which are stopped before the sessions
+    // are drained, and those that maintain the backends and the cache, which
+    // are stopped after.
+    acceptors: Vec<JoinHandle<()>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ProxyServer {
+    /// The address that ldaps connections are accepted on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn app_state(&self) -> &Arc<AppState> {
+        &self.app_state
+    }
+
+    /// Replace the bind maps, access rules and cache settings with those of
+    /// the config, and load the listener's certificate again. Everything else
+    /// needs a restart to change. Returns if the config was applied, which it
+    /// isn't if it adds a backend pool.
+    pub fn reload(&self, config: &Config) -> bool {
+        reload_config(config, &self.app_state, &self.tls)
+    }
+
+    /// Read the config file that the proxy was built from again, and reload
+    /// it. Returns if it was reloaded.
+    pub fn reload_from_file(&self) -> bool {
+        let Some(path) = self.config_path.as_ref() else {
+            error!("Not reloading, the proxy has no config file");
+            return false;
+        };
+        let config = match read_config(path) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Not reloading, {}", e);
+                return false;
+            }
+        };
+        let reloaded = self.reload(&config);
+        if reloaded {
+            info!("Reloaded config from {}", path.display());
+        }
+        reloaded
+    }
+
+    /// Serve as the ldap-proxy service does: tell systemd that the proxy is
+    /// ready, reload the config file on SIGHUP, log the metrics on SIGUSR1,
+    /// and shut down on ctrl-c or SIGTERM.
+    pub async fn run(self) {
+        let watchdog = systemd::watchdog_interval().map(|interval| {
+            let broadcast_rx = self.broadcast_tx.subscribe();
+            tokio::spawn(async move { watchdog(interval, broadcast_rx).await })
+        });
+
+        sd_notify("READY=1");
+
+        // Finally, block on the signal handler.
+        loop {
+            tokio::select! {
+                Ok(()) = tokio::signal::ctrl_c() => {
+                    break
+                }
+                Some(()) = async move {
+                    let sigterm = tokio::signal::unix::SignalKind::terminate();
+                    #[allow(clippy::unwrap_used)]
+                    tokio::signal::unix::signal(sigterm).unwrap().recv().await
+                } => {
+                    break
+                }
+                Some(()) = async move {
+                    let sigterm = tokio::signal::unix::SignalKind::alarm();
+                    #[allow(clippy::unwrap_used)]
+                    tokio::signal::unix::signal(sigterm).unwrap().recv().await
+                } => {
+                    // Ignore
+                }
+                Some(()) = async move {
+                    let sigterm = tokio::signal::unix::SignalKind::hangup();
+                    #[allow(clippy::unwrap_used)]
+                    tokio::signal::unix::signal(sigterm).unwrap().recv().await
+                } => {
+                    self.reload_from_file();
+                }
+                Some(()) = async move {
+                    let sigterm = tokio::signal::unix::SignalKind::user_defined1();
+                    #[allow(clippy::unwrap_used)]
+                    tokio::signal::unix::signal(sigterm).unwrap().recv().await
+                } => {
+                    // Dump the current metrics to the log.
+                    publish_latency(&self.app_state);
+                    for (metric, value) in self.app_state.metrics.snapshot() {
+                        info!(%metric, %value, "metrics");
+                    }
+                }
+                Some(()) = async move {
+                    let sigterm = tokio::signal::unix::SignalKind::user_defined2();
+                    #[allow(clippy::unwrap_used)]
+                    tokio::signal::unix::signal(sigterm).unwrap().recv().await
+                } => {
+                    // Ignore
+                }
+            }
+        }
+        info!("Signal received, sending down signal to tasks");
+        sd_notify("STOPPING=1");
+        self.shutdown().await;
+        if let Some(watchdog) = watchdog {
+            let _ = watchdog.await;
+        }
+    }
+
+    /// Stop accepting connections, let the sessions finish the operations
+    /// they have in flight for up to shutdown_grace_secs, and then stop the
+    /// background tasks and save the cache.
+    pub async fn shutdown(self) {
+        let ProxyServer {
+            config,
+            app_state,
+            broadcast_tx,
+            acceptors,
+            workers,
+            ..
+        } = self;
+
+        app_state.listening.store(false, Ordering::Relaxed);
+        // Send a broadcast that we are done.
+        if let Err(e) = broadcast_tx.send(true) {
+            error!("Unable to shutdown workers {:?}", e);
+        }
+
+        // Wait for tasks to join.
+        for acceptor in acceptors {
+            let _ = acceptor.await;
+        }
+        if let Some(path) = config.ldapi_bind.as_ref() {
+            let _ = std::fs::remove_file(path);
+        }
+
+        // Let the sessions finish the operations they have in flight, and then
+        // tell them that we are going.
+        app_state.sessions.drain();
+        let grace = Duration::from_secs(config.shutdown_grace_secs);
+        if tokio::time::timeout(grace, app_state.sessions.drained())
+            .await
+            .is_err()
+        {
+            warn!(
+                "{} sessions were still open at the end of the shutdown grace period",
+                app_state.sessions.list().len()
+            );
+        }
+        for worker in workers {
+            let _ = worker.await;
+        }
+
+        if let Some(path) = config.cache_persist_path.as_ref() {
+            match save_cache(&app_state, path) {
+                Ok(count) => info!("Saved {} cached searches to {}", count, path.display()),
+                Err(e) => error!(?e, "Unable to save cache to {}", path.display()),
+            }
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! The server side sort control (RFC 2891). Backends that support it sort
+//! the results themselves, and for those that don't the proxy sorts them, as
+//! it has the whole result set before it answers the client.
+//!
+//! The proxy knows the case ignore, case exact and integer ordering rules.
+//! Entries without a value for a key sort after those with one, and of the
+//! values of a multi-valued attribute the lowest is used.
+
+use std::cmp::Ordering;
+
+use ldap3_proto::control::{LdapControl, ServerSortRequet, ServerSortResult};
+use ldap3_proto::proto::{LdapResultCode, LdapSearchResultEntry};
+use serde::Deserialize;
+
+use crate::DnConfig;
+
+pub const OID_SERVER_SORT: &str = "1.2.840.113556.1.4.473";
+pub const OID_SORT_RESULT: &str = "1.2.840.113556.1.4.474";
+
+/// Who sorts the results of searches with the sort control.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortMode {
+    /// The backend, if the root DSE of the default backend lists the control
+    /// in its supportedControl, and otherwise the proxy.
+    #[default]
+    Auto,
+    Backend,
+    Proxy,
+}
+
+/// The sort control of a search, as the client sent it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortRequest {
+    pub keys: Vec<ServerSortRequet>,
+    pub critical: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rule {
+    CaseIgnore,
+    CaseExact,
+    Integer,
+}
+
+fn rule(key: &ServerSortRequet) -> Option<Rule> {
+    match key.ordering_rule.as_deref() {
+        None => Some(Rule::CaseIgnore),
+        Some(rule) if rule.eq_ignore_ascii_case("caseIgnoreOrderingMatch") => {
+            Some(Rule::CaseIgnore)
+        }
+        Some("2.5.13.3") => Some(Rule::CaseIgnore),
+        Some(rule) if rule.eq_ignore_ascii_case("caseExactOrderingMatch") => Some(Rule::CaseExact),
+        Some("2.5.13.6") => Some(Rule::CaseExact),
+        Some(rule) if rule.eq_ignore_ascii_case("integerOrderingMatch") => Some(Rule::Integer),
+        Some("2.5.13.15") => Some(Rule::Integer),
+        Some(_) => None,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum SortValue {
+    Integer(i128),
+    Text(String),
+}
+
+fn sort_value(value: &[u8], rule: Rule) -> Option<SortValue> {
+    let value = String::from_utf8_lossy(value);
+    match rule {
+        Rule::CaseIgnore => Some(SortValue::Text(
+            value
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .to_lowercase(),
+        )),
+        Rule::CaseExact => Some(SortValue::Text(value.into_owned())),
+        Rule::Integer => value.trim().parse().ok().map(SortValue::Integer),
+    }
+}
+
+// The value that an entry sorts by for a key, if it has one.
+fn entry_value(
+    entry: &LdapSearchResultEntry,
+    key: &ServerSortRequet,
+    rule: Rule,
+) -> Option<SortValue> {
+    entry
+        .attributes
+        .iter()
+        .filter(|attr| {
+            let atype = attr.atype.split(';').next().unwrap_or(&attr.atype);
+            atype.eq_ignore_ascii_case(&key.attribute_name)
+        })
+        .flat_map(|attr| attr.vals.iter())
+        .filter_map(|value| sort_value(value, rule))
+        .min()
+}
+
+/// The sort result control of a response. The attribute at fault is never
+/// included, as ldap3_proto clients can't decode it.
+pub fn sort_result(code: LdapResultCode) -> LdapControl {
+    LdapControl::ServerSortResult {
+        sort_result: ServerSortResult {
+            result_code: code,
+            attribute_type: None,
+        },
+    }
+}
+
+impl SortRequest {
+    /// The control to forward to a backend that sorts.
+    pub fn control(&self) -> LdapControl {
+        LdapControl::ServerSort {
+            sort_requests: self.keys.clone(),
+        }
+    }
+
+    /// Why the results of this DN's search can't be sorted by these keys, and
+    /// the key at fault. Attributes that the DN may not read are never sorted
+    /// on, as the order would reveal their values. The ordering rules are only
+    /// checked if the proxy is to sort.
+    pub fn unsortable(&self, config: &DnConfig, locally: bool) -> Option<(LdapResultCode, &str)> {
+        self.keys.iter().find_map(|key| {
+            if !config.permits_attr(&key.attribute_name) {
+                Some((
+                    LdapResultCode::InsufficentAccessRights,
+                    key.attribute_name.as_str(),
+                ))
+            } else if locally && rule(key).is_none() {
+                Some((
+                    LdapResultCode::InappropriateMatching,
+                    key.attribute_name.as_str(),
+                ))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The index of the first of the sorted entries that sorts at or after
+    /// this value by the first key.
+    pub fn position<T>(&self, entries: &[(LdapSearchResultEntry, T)], value: &[u8]) -> usize {
+        let Some(key) = self.keys.first() else {
+            return 0;
+        };
+        let rule = rule(key).unwrap_or(Rule::CaseIgnore);
+        let Some(value) = sort_value(value, rule) else {
+            return entries.len();
+        };
+        // Entries without a value are last, so never before the value.
+        entries.partition_point(|(entry, _)| match entry_value(entry, key, rule) {
+            Some(v) if key.reverse_order => v > value,
+            Some(v) => v < value,
+            None => false,
+        })
+    }
+
+    /// Sort entries by these keys. The sort is stable, so that entries that
+    /// compare equal stay in the order that the backend returned them.
+    pub fn sort<T>(&self, entries: &mut Vec<(LdapSearchResultEntry, T)>) {
+        let rules: Vec<_> = self
+            .keys
+            .iter()
+            .map(|key| rule(key).unwrap_or(Rule::CaseIgnore))
+            .collect();
+        let mut keyed: Vec<_> = std::mem::take(entries)
+            .into_iter()
+            .map(|(entry, extra)| {
+                let values: Vec<_> = self
+                    .keys
+                    .iter()
+                    .zip(rules.iter())
+                    .map(|(key, rule)| entry_value(&entry, key, *rule))
+                    .collect();
+                (values, entry, extra)
+            })
+            .collect();
+        keyed.sort_by(|(a, _, _), (b, _, _)| {
+            self.keys
+                .iter()
+                .zip(a.iter().zip(b.iter()))
+                .map(|(key, (a, b))| {
+                    // Entries without a value sort as larger than any value.
+                    let order = match (a, b) {
+                        (Some(a), Some(b)) => a.cmp(b),
+                        (Some(_), None) => Ordering::Less,
+                        (None, Some(_)) => Ordering::Greater,
+                        (None, None) => Ordering::Equal,
+                    };
+                    if key.reverse_order {
+                        order.reverse()
+                    } else {
+                        order
+                    }
+                })
+                .find(|order| order.is_ne())
+                .unwrap_or(Ordering::Equal)
+        });
+        *entries = keyed
+            .into_iter()
+            .map(|(_, entry, extra)| (entry, extra))
+            .collect();
+    }
+}
@@ -0,0 +1,126 @@
+//! Support for running as a systemd service: listening sockets passed in by
+//! socket activation (`LISTEN_FDS`), and readiness and watchdog notifications
+//! (`sd_notify`) for `Type=notify` units. These are implemented directly rather
+//! than through libsystemd, and do nothing when the process isn't started by
+//! systemd.
+
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+// The first descriptor passed by systemd, after stdin, stdout and stderr.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A descriptor passed by socket activation, and its `FileDescriptorName`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenFd {
+    pub name: String,
+    pub fd: RawFd,
+}
+
+/// Parse the socket activation variables. The descriptors are only for us if
+/// `LISTEN_PID` is our pid, otherwise they were inherited from a parent.
+pub fn parse_listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+) -> Vec<ListenFd> {
+    if listen_pid.and_then(|p| p.trim().parse::<u32>().ok()) != Some(pid) {
+        return Vec::new();
+    }
+    let Some(count) = listen_fds.and_then(|n| n.trim().parse::<RawFd>().ok()) else {
+        return Vec::new();
+    };
+    let mut names = listen_fdnames.unwrap_or_default().split(':');
+
+    (0..count.max(0))
+        .map(|i| ListenFd {
+            name: names.next().unwrap_or("unknown").to_string(),
+            fd: SD_LISTEN_FDS_START + i,
+        })
+        .collect()
+}
+
+/// The descriptors that systemd passed to this process.
+pub fn listen_fds() -> Vec<ListenFd> {
+    parse_listen_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::env::var("LISTEN_FDNAMES").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+/// Choose the descriptor for the listener `name`. If no descriptor has that
+/// name, but only one was passed, that one is used.
+pub fn select_listen_fd<'a>(fds: &'a [ListenFd], name: &str) -> Option<&'a ListenFd> {
+    fds.iter().find(|fd| fd.name == name).or(match fds {
+        [only] => Some(only),
+        _ => None,
+    })
+}
+
+/// Take ownership of a passed descriptor as a tcp listener.
+///
+/// # Safety
+///
+/// The descriptor must be open, and not owned by anything else in the process.
+pub unsafe fn tcp_listener(fd: &ListenFd) -> io::Result<tokio::net::TcpListener> {
+    let listener = TcpListener::from_raw_fd(fd.fd);
+    // This fails if the descriptor isn't a socket.
+    listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+    tokio::net::TcpListener::from_std(listener)
+}
+
+/// Send a notification such as `READY=1` to the socket at `path`. Paths that
+/// start with `@` are in the abstract namespace.
+pub fn notify_to(path: &str, state: &str) -> io::Result<()> {
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// Send a notification to systemd, if we were started with a `NOTIFY_SOCKET`.
+/// Returns whether it was sent.
+pub fn notify(state: &str) -> io::Result<bool> {
+    match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) => notify_to(&path, state).map(|()| true),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Parse the watchdog variables, returning how often the watchdog should be
+/// notified: half of `WATCHDOG_USEC`, so that one late notification doesn't
+/// get the service restarted. As with socket activation the variables are
+/// only for us if `WATCHDOG_PID` is unset or is our pid.
+pub fn parse_watchdog(
+    watchdog_usec: Option<&str>,
+    watchdog_pid: Option<&str>,
+    pid: u32,
+) -> Option<Duration> {
+    if let Some(watchdog_pid) = watchdog_pid {
+        if watchdog_pid.trim().parse::<u32>().ok() != Some(pid) {
+            return None;
+        }
+    }
+    let usec = watchdog_usec?.trim().parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec) / 2)
+}
+
+/// How often to send `WATCHDOG=1`, if systemd is watching this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
@@ -0,0 +1,392 @@
+//! A log of the messages of the DNs that are tapped, for debugging what an
+//! application sends and receives without capturing and decrypting its
+//! traffic. Each request from the client and each response sent to it is
+//! written as a block of LDIF-like lines, headed by comments saying when and
+//! from whom, with the credentials of binds, password changes and password
+//! attributes replaced by `<redacted>`.
+//!
+//! Like the audit log, blocks are written by a thread of their own. The file
+//! is rotated once it reaches max_bytes, keeping `keep` earlier files with
+//! `.1`, `.2` and so on appended to the path.
+
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hashbrown::HashMap;
+use ldap3_proto::proto::{
+    LdapBindCred, LdapIntermediateResponse, LdapModifyType, LdapMsg, LdapOp, LdapResult,
+};
+use serde::Deserialize;
+use tracing::error;
+
+use crate::audit::{filter_string, scope_name};
+use crate::ldif::write_value;
+use crate::proxy::OID_PASSWORD_MODIFY;
+
+const REDACTED: &str = "<redacted>";
+
+// The attributes whose values are passwords.
+const PASSWORD_ATTRIBUTES: &[&str] = &["userPassword", "unicodePwd", "authPassword"];
+
+fn default_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_keep() -> usize {
+    3
+}
+
+/// Where the messages of tapped DNs are written.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TapConfig {
+    pub path: PathBuf,
+    /// Rotate the file once it is this large.
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+    /// The rotated files to keep.
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+// A file that is rotated once it is too large.
+struct RotatingFile {
+    config: TapConfig,
+    file: File,
+    written: u64,
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    PathBuf::from(rotated)
+}
+
+impl RotatingFile {
+    fn open(config: TapConfig) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let written = file.metadata()?.len();
+        Ok(RotatingFile {
+            config,
+            file,
+            written,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.config.path;
+        if self.config.keep > 0 {
+            for n in (1..self.config.keep).rev() {
+                let from = rotated_path(path, n);
+                if from.exists() {
+                    fs::rename(from, rotated_path(path, n + 1))?;
+                }
+            }
+            fs::rename(path, rotated_path(path, 1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        self.written = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, block: &str) -> io::Result<()> {
+        let len = block.len() as u64;
+        if self.written > 0 && self.written + len > self.config.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(block.as_bytes())?;
+        self.written += len;
+        Ok(())
+    }
+}
+
+pub struct TapLog {
+    tx: mpsc::Sender<String>,
+}
+
+impl TapLog {
+    pub fn open(config: &TapConfig) -> io::Result<Self> {
+        let mut file = RotatingFile::open(config.clone())?;
+        let (tx, rx) = mpsc::channel::<String>();
+        std::thread::spawn(move || {
+            for block in rx {
+                if let Err(e) = file.write(&block) {
+                    error!(?e, "Unable to write to the tap log");
+                }
+            }
+        });
+        Ok(TapLog { tx })
+    }
+
+    fn write(&self, block: String) {
+        // This only fails once the writer can't write any more.
+        let _ = self.tx.send(block);
+    }
+}
+
+fn is_password(atype: &str) -> bool {
+    let atype = atype.split(';').next().unwrap_or(atype);
+    PASSWORD_ATTRIBUTES
+        .iter()
+        .any(|attr| attr.eq_ignore_ascii_case(atype))
+}
+
+fn write_values(out: &mut String, atype: &str, vals: &[Vec<u8>]) {
+    for val in vals {
+        if is_password(atype) {
+            let _ = writeln!(out, "{}: {}", atype, REDACTED);
+        } else {
+            write_value(out, atype, val);
+        }
+    }
+}
+
+fn write_result(out: &mut String, res: &LdapResult) {
+    let _ = writeln!(out, "result: {:?}", res.code);
+    if !res.matcheddn.is_empty() {
+        write_value(out, "matchedDN", res.matcheddn.as_bytes());
+    }
+    if !res.message.is_empty() {
+        write_value(out, "message", res.message.as_bytes());
+    }
+    for referral in res.referral.iter() {
+        write_value(out, "referral", referral.as_bytes());
+    }
+}
+
+// The lines of an operation, after its msgid.
+fn write_op(out: &mut String, op: &LdapOp) {
+    match op {
+        LdapOp::BindRequest(lbr) => {
+            out.push_str("op: bindRequest\n");
+            write_value(out, "dn", lbr.dn.as_bytes());
+            match &lbr.cred {
+                LdapBindCred::Simple(_) => {
+                    let _ = writeln!(out, "simple: {}", REDACTED);
+                }
+                LdapBindCred::SASL(sasl) => {
+                    write_value(out, "mechanism", sasl.mechanism.as_bytes());
+                    let _ = writeln!(out, "credentials: {}", REDACTED);
+                }
+            }
+        }
+        LdapOp::BindResponse(resp) => {
+            out.push_str("op: bindResponse\n");
+            write_result(out, &resp.res);
+            if resp.saslcreds.is_some() {
+                let _ = writeln!(out, "serverSaslCreds: {}", REDACTED);
+            }
+        }
+        LdapOp::UnbindRequest => out.push_str("op: unbindRequest\n"),
+        LdapOp::SearchRequest(sr) => {
+            out.push_str("op: searchRequest\n");
+            write_value(out, "base", sr.base.as_bytes());
+            let _ = writeln!(out, "scope: {}", scope_name(&sr.scope));
+            let _ = writeln!(out, "derefAliases: {:?}", sr.aliases);
+            let _ = writeln!(out, "sizeLimit: {}", sr.sizelimit);
+            let _ = writeln!(out, "timeLimit: {}", sr.timelimit);
+            let _ = writeln!(out, "typesOnly: {}", sr.typesonly);
+            write_value(out, "filter", filter_string(&sr.filter, false).as_bytes());
+            for attr in sr.attrs.iter() {
+                write_value(out, "attribute", attr.as_bytes());
+            }
+        }
+        LdapOp::SearchResultEntry(entry) => {
+            out.push_str("op: searchResultEntry\n");
+            write_value(out, "dn", entry.dn.as_bytes());
+            for attr in entry.attributes.iter() {
+                write_values(out, &attr.atype, &attr.vals);
+            }
+        }
+        LdapOp::SearchResultReference(reference) => {
+            out.push_str("op: searchResultReference\n");
+            for uri in reference.uris.iter() {
+                write_value(out, "uri", uri.as_bytes());
+            }
+        }
+        LdapOp::SearchResultDone(res) => {
+            out.push_str("op: searchResultDone\n");
+            write_result(out, res);
+        }
+        LdapOp::ModifyRequest(lmr) => {
+            out.push_str("op: modifyRequest\n");
+            write_value(out, "dn", lmr.dn.as_bytes());
+            for change in lmr.changes.iter() {
+                let operation = match change.operation {
+                    LdapModifyType::Add => "add",
+                    LdapModifyType::Delete => "delete",
+                    LdapModifyType::Replace => "replace",
+                };
+                let atype = &change.modification.atype;
+                write_value(out, operation, atype.as_bytes());
+                write_values(out, atype, &change.modification.vals);
+                out.push_str("-\n");
+            }
+        }
+        LdapOp::AddRequest(lar) => {
+            out.push_str("op: addRequest\n");
+            write_value(out, "dn", lar.dn.as_bytes());
+            for attr in lar.attributes.iter() {
+                write_values(out, &attr.atype, &attr.vals);
+            }
+        }
+        LdapOp::DelRequest(dn) => {
+            out.push_str("op: delRequest\n");
+            write_value(out, "dn", dn.as_bytes());
+        }
+        LdapOp::ModifyDNRequest(lmdr) => {
+            out.push_str("op: modDNRequest\n");
+            write_value(out, "dn", lmdr.dn.as_bytes());
+            write_value(out, "newrdn", lmdr.newrdn.as_bytes());
+            let _ = writeln!(out, "deleteoldrdn: {}", lmdr.deleteoldrdn);
+            if let Some(superior) = lmdr.new_superior.as_ref() {
+                write_value(out, "newSuperior", superior.as_bytes());
+            }
+        }
+        LdapOp::CompareRequest(lcr) => {
+            out.push_str("op: compareRequest\n");
+            write_value(out, "dn", lcr.dn.as_bytes());
+            write_values(out, &lcr.atype, std::slice::from_ref(&lcr.val));
+        }
+        LdapOp::AbandonRequest(msgid) => {
+            out.push_str("op: abandonRequest\n");
+            let _ = writeln!(out, "abandon: {}", msgid);
+        }
+        LdapOp::ExtendedRequest(ler) => {
+            out.push_str("op: extendedRequest\n");
+            write_value(out, "name", ler.name.as_bytes());
+            match &ler.value {
+                Some(_) if ler.name == OID_PASSWORD_MODIFY => {
+                    let _ = writeln!(out, "value: {}", REDACTED);
+                }
+                Some(value) => write_value(out, "value", value),
+                None => {}
+            }
+        }
+        LdapOp::ExtendedResponse(resp) => {
+            out.push_str("op: extendedResponse\n");
+            write_result(out, &resp.res);
+            if let Some(name) = resp.name.as_ref() {
+                write_value(out, "name", name.as_bytes());
+            }
+            // The value of a password modify response is a generated password.
+            match &resp.value {
+                Some(_) if resp.name.as_deref() == Some(OID_PASSWORD_MODIFY) => {
+                    let _ = writeln!(out, "value: {}", REDACTED);
+                }
+                Some(value) => write_value(out, "value", value),
+                None => {}
+            }
+        }
+        LdapOp::IntermediateResponse(resp) => {
+            out.push_str("op: intermediateResponse\n");
+            match resp {
+                LdapIntermediateResponse::Raw { name, value } => {
+                    if let Some(name) = name.as_ref() {
+                        write_value(out, "name", name.as_bytes());
+                    }
+                    if let Some(value) = value.as_ref() {
+                        write_value(out, "value", value);
+                    }
+                }
+                other => {
+                    write_value(out, "value", format!("{:?}", other).as_bytes());
+                }
+            }
+        }
+        LdapOp::ModifyResponse(res)
+        | LdapOp::AddResponse(res)
+        | LdapOp::DelResponse(res)
+        | LdapOp::ModifyDNResponse(res)
+        | LdapOp::CompareResult(res) => {
+            let name = match op {
+                LdapOp::ModifyResponse(_) => "modifyResponse",
+                LdapOp::AddResponse(_) => "addResponse",
+                LdapOp::DelResponse(_) => "delResponse",
+                LdapOp::ModifyDNResponse(_) => "modDNResponse",
+                _ => "compareResponse",
+            };
+            let _ = writeln!(out, "op: {}", name);
+            write_result(out, res);
+        }
+    }
+}
+
+/// A message as it is written to the tap log.
+pub fn tap_block(direction: &str, client: SocketAddr, bind_dn: &str, msg: &LdapMsg) -> String {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default();
+    let mut out = String::new();
+    let _ = writeln!(out, "# {} {:.3} {} {}", direction, time, client, bind_dn);
+    let _ = writeln!(out, "msgid: {}", msg.msgid);
+    write_op(&mut out, &msg.op);
+    for ctrl in msg.ctrl.iter() {
+        write_value(&mut out, "control", format!("{:?}", ctrl).as_bytes());
+    }
+    out.push('\n');
+    out
+}
+
+// The responses that end an operation.
+fn is_final(op: &LdapOp) -> bool {
+    !matches!(
+        op,
+        LdapOp::SearchResultEntry(_)
+            | LdapOp::SearchResultReference(_)
+            | LdapOp::IntermediateResponse(_)
+    )
+}
+
+/// The tap of one client session, which writes the requests of tapped DNs and
+/// the responses to them.
+pub struct SessionTap {
+    log: Arc<TapLog>,
+    client: SocketAddr,
+    // The bind DNs of the tapped operations that haven't completed, by msgid.
+    tapped: HashMap<i32, String>,
+}
+
+impl SessionTap {
+    pub fn new(log: Arc<TapLog>, client: SocketAddr) -> Self {
+        SessionTap {
+            log,
+            client,
+            tapped: HashMap::new(),
+        }
+    }
+
+    /// A request from the client, by a DN that is tapped.
+    pub fn request(&mut self, msg: &LdapMsg, bind_dn: &str) {
+        self.log
+            .write(tap_block("request", self.client, bind_dn, msg));
+        if !matches!(msg.op, LdapOp::AbandonRequest(_) | LdapOp::UnbindRequest) {
+            self.tapped.insert(msg.msgid, bind_dn.to_string());
+        }
+    }
+
+    /// A response sent to the client, which is written if its request was.
+    pub fn response(&mut self, msg: &LdapMsg) {
+        let Some(bind_dn) = self.tapped.get(&msg.msgid) else {
+            return;
+        };
+        self.log
+            .write(tap_block("response", self.client, bind_dn, msg));
+        if is_final(&msg.op) {
+            self.tapped.remove(&msg.msgid);
+        }
+    }
+}
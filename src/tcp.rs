@@ -0,0 +1,59 @@
+//! The socket options of TCP connections, to clients and to backends. LDAP
+//! sessions send small requests and wait for each answer, so Nagle's algorithm
+//! only delays them, and they are often idle for long enough that firewalls
+//! between the two ends forget them unless keepalives are sent.
+
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpOptions {
+    /// Send keepalives on connections that have been quiet this long. Unset
+    /// leaves the system default, which is usually off.
+    pub keepalive: Option<Duration>,
+    /// Send small messages at once, rather than waiting to fill a segment.
+    pub nodelay: bool,
+    /// The sizes of the socket's buffers. Unset leaves the system defaults.
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        TcpOptions {
+            keepalive: None,
+            nodelay: true,
+            send_buffer: None,
+            recv_buffer: None,
+        }
+    }
+}
+
+impl TcpOptions {
+    /// Set the options on a connection. Those that can't be set are warned
+    /// about, and the connection is used as it is.
+    pub fn apply(&self, stream: &TcpStream) {
+        let socket = socket2::SockRef::from(stream);
+        if let Some(time) = self.keepalive {
+            let keepalive = socket2::TcpKeepalive::new().with_time(time);
+            if let Err(e) = socket.set_tcp_keepalive(&keepalive) {
+                warn!(?e, "Unable to enable tcp keepalives");
+            }
+        }
+        if let Err(e) = socket.set_nodelay(self.nodelay) {
+            warn!(?e, "Unable to set tcp nodelay");
+        }
+        if let Some(size) = self.send_buffer {
+            if let Err(e) = socket.set_send_buffer_size(size) {
+                warn!(?e, size, "Unable to set the tcp send buffer size");
+            }
+        }
+        if let Some(size) = self.recv_buffer {
+            if let Err(e) = socket.set_recv_buffer_size(size) {
+                warn!(?e, size, "Unable to set the tcp receive buffer size");
+            }
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! The TLS of connections to backends. The pools of the config connect with
+//! openssl, unless the proxy is built with the rustls feature, in which case
+//! they connect with rustls. The listener, client certificates and the spki
+//! pins of backends use openssl either way.
+//!
+//! rustls has no TLS 1.0 or 1.1, and no openssl cipher strings, so a pool
+//! that configures either can't be built with it.
+
+use std::net::IpAddr;
+use std::pin::Pin;
+
+use openssl::ssl::{Ssl, SslConnector};
+use openssl::x509::X509;
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+use tracing::error;
+
+use crate::proxy::{BackendStream, LdapError};
+
+#[cfg(feature = "rustls")]
+pub use rustls_connector::build_rustls_config;
+
+/// How the connections to a backend pool are made secure.
+pub enum TlsConnector {
+    OpenSsl(SslConnector),
+    #[cfg(feature = "rustls")]
+    Rustls(std::sync::Arc<rustls::ClientConfig>),
+}
+
+impl From<SslConnector> for TlsConnector {
+    fn from(connector: SslConnector) -> Self {
+        TlsConnector::OpenSsl(connector)
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl From<std::sync::Arc<rustls::ClientConfig>> for TlsConnector {
+    fn from(config: std::sync::Arc<rustls::ClientConfig>) -> Self {
+        TlsConnector::Rustls(config)
+    }
+}
+
+impl TlsConnector {
+    /// Handshake with the backend called `name`, which is sent in SNI unless
+    /// it is an address. Returns the stream, and the backend's certificate.
+    pub(crate) async fn connect(
+        &self,
+        tcpstream: TcpStream,
+        name: &str,
+        verify_hostname: bool,
+    ) -> Result<(Box<dyn BackendStream>, Option<X509>), LdapError> {
+        let ip = name.parse::<IpAddr>().ok();
+        match self {
+            TlsConnector::OpenSsl(connector) => {
+                let mut tlsstream = Ssl::new(connector.context())
+                    .and_then(|mut tls_obj| {
+                        // SNI can't name an address (RFC 6066 3).
+                        if ip.is_none() {
+                            tls_obj.set_hostname(name)?;
+                        }
+                        // Each backend presents its own certificate, so verify against its name.
+                        if verify_hostname {
+                            match ip {
+                                Some(ip) => tls_obj.param_mut().set_ip(ip)?,
+                                None => tls_obj.param_mut().set_host(name)?,
+                            }
+                        }
+                        SslStream::new(tls_obj, tcpstream)
+                    })
+                    .map_err(|e| {
+                        error!(?e, "openssl");
+                        LdapError::TlsError
+                    })?;
+
+                SslStream::connect(Pin::new(&mut tlsstream))
+                    .await
+                    .map_err(|e| {
+                        error!(?e, "openssl");
+                        LdapError::TlsError
+                    })?;
+                let peer = tlsstream.ssl().peer_certificate();
+                Ok((Box::new(tlsstream), peer))
+            }
+            #[cfg(feature = "rustls")]
+            TlsConnector::Rustls(config) => {
+                use rustls_pki_types::ServerName;
+
+                // The name is only checked if the config's verifier does.
+                let server_name = match ip {
+                    Some(ip) => ServerName::IpAddress(ip.into()),
+                    None => ServerName::try_from(name.to_string()).map_err(|e| {
+                        error!(?e, name, "invalid backend name");
+                        LdapError::TlsError
+                    })?,
+                };
+                let tlsstream = tokio_rustls::TlsConnector::from(config.clone())
+                    .connect(server_name, tcpstream)
+                    .await
+                    .map_err(|e| {
+                        error!(?e, "rustls");
+                        LdapError::TlsError
+                    })?;
+                let peer = tlsstream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|certs| certs.first())
+                    .and_then(|cert| X509::from_der(cert).ok());
+                Ok((Box::new(tlsstream), peer))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "rustls")]
+mod rustls_connector {
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::client::WebPkiServerVerifier;
+    use rustls::crypto::ring;
+    use rustls::{
+        CertificateError, ClientConfig, DigitallySignedStruct, Error, RootCertStore,
+        SignatureScheme, SupportedProtocolVersion,
+    };
+    use rustls_pki_types::pem::PemObject;
+    use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+    use tracing::{debug, error};
+
+    use crate::{BackendTls, TlsVersion};
+
+    // Verifies certificates against the CA, whatever they name.
+    #[derive(Debug)]
+    struct IgnoreHostname(Arc<WebPkiServerVerifier>);
+
+    impl ServerCertVerifier for IgnoreHostname {
+        fn verify_server_cert(
+            &self,
+            end_entity: &CertificateDer<'_>,
+            intermediates: &[CertificateDer<'_>],
+            server_name: &ServerName<'_>,
+            ocsp_response: &[u8],
+            now: UnixTime,
+        ) -> Result<ServerCertVerified, Error> {
+            match self.0.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            ) {
+                Err(Error::InvalidCertificate(
+                    CertificateError::NotValidForName
+                    | CertificateError::NotValidForNameContext { .. },
+                )) => Ok(ServerCertVerified::assertion()),
+                other => other,
+            }
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, Error> {
+            self.0.verify_tls12_signature(message, cert, dss)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, Error> {
+            self.0.verify_tls13_signature(message, cert, dss)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.supported_verify_schemes()
+        }
+    }
+
+    // The versions of rustls between the pool's min and max.
+    fn protocol_versions(tls: &BackendTls) -> Vec<&'static SupportedProtocolVersion> {
+        [
+            (TlsVersion::Tls12, &rustls::version::TLS12),
+            (TlsVersion::Tls13, &rustls::version::TLS13),
+        ]
+        .into_iter()
+        .filter(|(version, _)| tls.min_version.is_none_or(|min| *version >= min))
+        .filter(|(version, _)| tls.max_version.is_none_or(|max| *version <= max))
+        .map(|(_, supported)| supported)
+        .collect()
+    }
+
+    /// The rustls equivalent of the openssl connector of a pool, which trusts
+    /// the certificates in `ldap_ca`, and presents the identity if it is set.
+    pub fn build_rustls_config(
+        ldap_ca: &Path,
+        tls: &BackendTls,
+        identity: Option<(&Path, &Path)>,
+    ) -> Option<Arc<ClientConfig>> {
+        if tls.cipher_list.is_some() || tls.ciphersuites.is_some() {
+            error!(
+                ?tls,
+                "ldap_tls_cipher_list and ldap_tls_ciphersuites are not supported with rustls"
+            );
+            return None;
+        }
+        let versions = protocol_versions(tls);
+        if versions.is_empty() {
+            error!(
+                ?tls,
+                "rustls supports only TLS 1.2 and 1.3, which ldap_tls_min_version and ldap_tls_max_version exclude"
+            );
+            return None;
+        }
+
+        let mut roots = RootCertStore::empty();
+        let certs = match CertificateDer::pem_file_iter(ldap_ca) {
+            Ok(certs) => certs,
+            Err(e) => {
+                error!(?e, "Unable to read {:?}", ldap_ca);
+                return None;
+            }
+        };
+        for cert in certs {
+            if let Err(e) = cert
+                .map_err(|e| e.to_string())
+                .and_then(|cert| roots.add(cert).map_err(|e| e.to_string()))
+            {
+                error!(?e, "Unable to load {:?}", ldap_ca);
+                return None;
+            }
+        }
+        if roots.is_empty() {
+            error!("{:?} contains no certificates", ldap_ca);
+            return None;
+        }
+        debug!("Added {:?} to cert store", ldap_ca);
+
+        let provider = Arc::new(ring::default_provider());
+        let verifier =
+            match WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+            {
+                Ok(v) => v,
+                Err(e) => {
+                    error!(?e, "rustls");
+                    return None;
+                }
+            };
+        let builder =
+            match ClientConfig::builder_with_provider(provider).with_protocol_versions(&versions) {
+                Ok(b) => b,
+                Err(e) => {
+                    error!(?e, "rustls");
+                    return None;
+                }
+            };
+        let builder = if tls.verify_hostname {
+            builder.with_webpki_verifier(verifier)
+        } else {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(IgnoreHostname(verifier)))
+        };
+
+        let config = match identity {
+            Some((cert, key)) => {
+                let chain = match CertificateDer::pem_file_iter(cert)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                {
+                    Ok(chain) => chain,
+                    Err(e) => {
+                        error!(?e, "Unable to load ldap_client_cert {:?}", cert);
+                        return None;
+                    }
+                };
+                let key_der = match PrivateKeyDer::from_pem_file(key) {
+                    Ok(k) => k,
+                    Err(e) => {
+                        error!(?e, "Unable to load ldap_client_key {:?}", key);
+                        return None;
+                    }
+                };
+                match builder.with_client_auth_cert(chain, key_der) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        error!(?e, "ldap_client_key does not match ldap_client_cert");
+                        return None;
+                    }
+                }
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Some(Arc::new(config))
+    }
+}
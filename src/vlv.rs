@@ -0,0 +1,110 @@
+//! The virtual list view control (draft-ietf-ldapext-ldapv3-vlv-09), which
+//! address book clients use to scroll through a sorted result set a window at
+//! a time. The control is never forwarded. The proxy searches for the whole
+//! result set, which is sorted and cached as if the control had not been sent,
+//! and returns the window of it that the client asked for.
+//!
+//! Each window is searched for afresh, so no context id is issued, and a
+//! window that follows another one is served from the cache.
+
+use std::ops::Range;
+
+use ldap3_proto::proto::LdapSearchResultEntry;
+
+use crate::sort::SortRequest;
+
+pub const OID_VLV_REQUEST: &str = "2.16.840.1.113730.3.4.9";
+pub const OID_VLV_RESPONSE: &str = "2.16.840.1.113730.3.4.10";
+
+// The results of the response control that are not ldap result codes.
+pub const SORT_CONTROL_MISSING: i64 = 60;
+pub const OFFSET_RANGE_ERROR: i64 = 61;
+
+/// Where the window is in the result set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VlvTarget {
+    /// The position of the target, out of the number of entries that the
+    /// client believes there are. A content count of zero means the offset is
+    /// the position.
+    ByOffset { offset: i32, content_count: i32 },
+    /// The first entry that sorts at or after this value by the first sort key.
+    GreaterOrEqual(Vec<u8>),
+}
+
+/// The virtual list view control of a search, as the client sent it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VlvRequest {
+    pub before_count: usize,
+    pub after_count: usize,
+    pub target: VlvTarget,
+    pub critical: bool,
+}
+
+/// The virtual list view response control of a search.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VlvResponse {
+    /// The position of the target in the result set, from one.
+    pub target_position: i32,
+    pub content_count: i32,
+    /// An ldap result code, or one of the codes of the control.
+    pub result: i64,
+}
+
+impl VlvResponse {
+    pub fn error(result: i64) -> Self {
+        VlvResponse {
+            target_position: 0,
+            content_count: 0,
+            result,
+        }
+    }
+}
+
+impl VlvRequest {
+    /// The range of the sorted entries that make up the window, and the
+    /// response to send with it. An offset of zero is out of range.
+    pub fn window<T>(
+        &self,
+        sort: &SortRequest,
+        entries: &[(LdapSearchResultEntry, T)],
+    ) -> Result<(Range<usize>, VlvResponse), VlvResponse> {
+        let count = entries.len();
+        let target = match &self.target {
+            VlvTarget::ByOffset { offset, .. } if *offset <= 0 => {
+                return Err(VlvResponse::error(OFFSET_RANGE_ERROR))
+            }
+            VlvTarget::ByOffset {
+                offset,
+                content_count,
+            } => {
+                // The offset is scaled from the client's idea of the number of
+                // entries to ours, and the last entry is used if it's beyond it.
+                let offset = *offset as usize;
+                let position = match usize::try_from(*content_count) {
+                    Ok(content_count) if content_count > 0 => {
+                        (offset.saturating_mul(count) + content_count / 2) / content_count
+                    }
+                    _ => offset,
+                };
+                position.clamp(1, count.max(1)) - 1
+            }
+            // This may be one past the last entry, if none sort after the value.
+            VlvTarget::GreaterOrEqual(value) => sort.position(entries, value),
+        };
+        let start = target.saturating_sub(self.before_count).min(count);
+        let end = target
+            .saturating_add(self.after_count)
+            .saturating_add(1)
+            .min(count);
+        let response = VlvResponse {
+            target_position: if count == 0 {
+                0
+            } else {
+                i32::try_from(target + 1).unwrap_or(i32::MAX)
+            },
+            content_count: i32::try_from(count).unwrap_or(i32::MAX),
+            result: 0,
+        };
+        Ok((start..end, response))
+    }
+}
@@ -0,0 +1,797 @@
+// Shared helpers for the integration tests. This provides a scripted ldaps
+// backend, and the plumbing to drive client_process over in-memory streams.
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use concread::arcache::ARCacheBuilder;
+use futures_util::sink::SinkExt;
+use futures_util::stream::StreamExt;
+use ldap3_proto::control::LdapControl;
+use ldap3_proto::proto::*;
+use ldap3_proto::LdapCodec;
+use ldap_proxy::access::ConfigAccessPolicy;
+use ldap_proxy::audit::FilterValues;
+use ldap_proxy::breaker::CircuitBreakers;
+use ldap_proxy::cacheindex::CacheIndex;
+use ldap_proxy::certmap::ClientCertificate;
+use ldap_proxy::coalesce::{CacheRefreshes, SearchFlights};
+use ldap_proxy::codec::{ClientCodec, ClientRequest, ClientResponse};
+use ldap_proxy::connections::ConnectionTracker;
+use ldap_proxy::controls::{default_denied_controls, ControlPolicy};
+use ldap_proxy::dnpattern::BindDnPatterns;
+use ldap_proxy::filter::FilterLimits;
+use ldap_proxy::lockout::BindFailureTracker;
+use ldap_proxy::metrics::Metrics;
+use ldap_proxy::proxy::{client_process, ServiceConnections, UpstreamPool};
+use ldap_proxy::quota::DnQuotas;
+use ldap_proxy::referral::ReferralMode;
+use ldap_proxy::retry::RetryPolicy;
+use ldap_proxy::secrets::SecretCache;
+use ldap_proxy::tcp::TcpOptions;
+use ldap_proxy::{
+    AppState, Backend, BackendPool, BackendStrategy, DnConfig, Policy, DEFAULT_BACKEND,
+};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{Ssl, SslAcceptor, SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf,
+};
+use tokio::net::TcpListener;
+use tokio_openssl::SslStream;
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedRead, FramedWrite};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+use url::Url;
+
+/// What the mock server should do in response to a message.
+pub enum MockAction {
+    Reply(Vec<LdapMsg>),
+    /// Reply with controls that ldap3_proto can't encode.
+    ReplyRaw(Vec<ClientResponse>),
+    Disconnect,
+}
+
+/// Build a self signed certificate, and the acceptor / connector pair that
+/// uses and trusts it.
+pub fn tls_pair() -> (SslAcceptor, SslConnector) {
+    let (cert, pkey) = certificate("localhost", "localhost");
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).expect("acceptor");
+    acceptor.set_certificate(&cert).expect("cert");
+    acceptor.set_private_key(&pkey).expect("key");
+    let acceptor = acceptor.build();
+
+    let mut connector = SslConnector::builder(SslMethod::tls_client()).expect("connector");
+    connector.cert_store_mut().add_cert(cert).expect("store");
+    connector.set_verify(SslVerifyMode::PEER);
+    let connector = connector.build();
+
+    (acceptor, connector)
+}
+
+/// Build a self signed certificate for O=Example,CN=`cn`, with a dns name. It
+/// isn't a CA's, so that rustls accepts it from a server.
+pub fn certificate(cn: &str, dns: &str) -> (X509, PKey<Private>) {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).expect("group");
+    let pkey = PKey::from_ec_key(EcKey::generate(&group).expect("ec")).expect("pkey");
+
+    let mut name = X509NameBuilder::new().expect("name");
+    name.append_entry_by_text("O", "Example").expect("o");
+    name.append_entry_by_text("CN", cn).expect("cn");
+    let name = name.build();
+
+    let mut serial = BigNum::new().expect("bn");
+    serial
+        .rand(128, MsbOption::MAYBE_ZERO, false)
+        .expect("rand");
+
+    let mut builder = X509::builder().expect("x509");
+    builder.set_version(2).expect("version");
+    builder
+        .set_serial_number(&serial.to_asn1_integer().expect("serial"))
+        .expect("serial");
+    builder.set_subject_name(&name).expect("subject");
+    builder.set_issuer_name(&name).expect("issuer");
+    builder.set_pubkey(&pkey).expect("pubkey");
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).expect("time"))
+        .expect("not before");
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).expect("time"))
+        .expect("not after");
+    let san = SubjectAlternativeName::new()
+        .dns(dns)
+        .ip("127.0.0.1")
+        .build(&builder.x509v3_context(None, None))
+        .expect("san");
+    builder.append_extension(san).expect("san");
+    builder.sign(&pkey, MessageDigest::sha256()).expect("sign");
+
+    (builder.build(), pkey)
+}
+
+/// Start a scripted ldaps server. Every connection is handled by the same
+/// handler, which is called once per received message.
+pub async fn mock_server<F>(acceptor: SslAcceptor, handler: F) -> SocketAddr
+where
+    F: Fn(LdapMsg) -> MockAction + Send + Sync + 'static,
+{
+    mock_server_requests(acceptor, move |request| handler(request_msg(request))).await
+}
+
+/// Start a scripted ldaps server whose handler is given each request as it was
+/// decoded, with the controls that ldap3_proto can't decode.
+pub async fn mock_server_requests<F>(acceptor: SslAcceptor, handler: F) -> SocketAddr
+where
+    F: Fn(ClientRequest) -> MockAction + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let addr = listener.local_addr().expect("addr");
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        while let Ok((tcpstream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            let handler = handler.clone();
+            // Responses are several writes, which Nagle would hold back.
+            let _ = tcpstream.set_nodelay(true);
+            tokio::spawn(async move {
+                let Ok(mut tlsstream) = Ssl::new(acceptor.context())
+                    .and_then(|tls_obj| SslStream::new(tls_obj, tcpstream))
+                else {
+                    return;
+                };
+                if SslStream::accept(Pin::new(&mut tlsstream)).await.is_err() {
+                    return;
+                }
+                serve_requests(tlsstream, handler.as_ref()).await;
+            });
+        }
+    });
+
+    addr
+}
+
+/// Start a scripted ldap server on a unix socket at this path.
+pub fn mock_unix_server<F>(path: &std::path::Path, handler: F)
+where
+    F: Fn(LdapMsg) -> MockAction + Send + Sync + 'static,
+{
+    let listener = tokio::net::UnixListener::bind(path).expect("bind");
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let handler = handler.clone();
+            tokio::spawn(async move { serve_mock(stream, handler.as_ref()).await });
+        }
+    });
+}
+
+/// Start a scripted plain ldap server, without tls.
+pub async fn mock_plain_server<F>(handler: F) -> SocketAddr
+where
+    F: Fn(LdapMsg) -> MockAction + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let addr = listener.local_addr().expect("addr");
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        while let Ok((tcpstream, _)) = listener.accept().await {
+            let handler = handler.clone();
+            tokio::spawn(async move { serve_mock(tcpstream, handler.as_ref()).await });
+        }
+    });
+
+    addr
+}
+
+/// Start a scripted plain ldap server, that upgrades to tls when it receives
+/// a StartTLS request. Everything after the upgrade is handled by `handler`.
+pub async fn mock_starttls_server<F>(acceptor: SslAcceptor, handler: F) -> SocketAddr
+where
+    F: Fn(LdapMsg) -> MockAction + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let addr = listener.local_addr().expect("addr");
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        while let Ok((tcpstream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                let mut framed = Framed::new(tcpstream, LdapCodec::new(None));
+                let Some(Ok(msg)) = framed.next().await else {
+                    return;
+                };
+                let LdapOp::ExtendedRequest(req) = msg.op else {
+                    return;
+                };
+                assert_eq!(req.name, "1.3.6.1.4.1.1466.20037");
+                let resp = LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                        res: success(),
+                        name: None,
+                        value: None,
+                    }),
+                    ctrl: vec![],
+                };
+                if framed.send(resp).await.is_err() {
+                    return;
+                }
+
+                let tcpstream = framed.into_inner();
+                let Ok(mut tlsstream) = Ssl::new(acceptor.context())
+                    .and_then(|tls_obj| SslStream::new(tls_obj, tcpstream))
+                else {
+                    return;
+                };
+                if SslStream::accept(Pin::new(&mut tlsstream)).await.is_err() {
+                    return;
+                }
+                serve_mock(tlsstream, handler.as_ref()).await;
+            });
+        }
+    });
+
+    addr
+}
+
+// The message of a request. The codec takes the sort control out of it.
+fn request_msg(request: ClientRequest) -> LdapMsg {
+    let mut msg = request.msg;
+    msg.ctrl
+        .extend(request.server_sort.map(|sort| sort.control()));
+    msg
+}
+
+async fn serve_mock<S, F>(stream: S, handler: &F)
+where
+    S: AsyncRead + AsyncWrite,
+    F: Fn(LdapMsg) -> MockAction,
+{
+    serve_requests(stream, &|request| handler(request_msg(request))).await
+}
+
+async fn serve_requests<S, F>(stream: S, handler: &F)
+where
+    S: AsyncRead + AsyncWrite,
+    F: Fn(ClientRequest) -> MockAction,
+{
+    let (r, w) = tokio::io::split(stream);
+    // This codec also decodes SASL binds.
+    let mut r = FramedRead::new(r, ClientCodec::new(None));
+    let mut w = FramedWrite::new(w, ClientCodec::new(None));
+
+    while let Some(Ok(request)) = r.next().await {
+        match handler(request) {
+            MockAction::Reply(msgs) => {
+                for msg in msgs {
+                    if w.send(msg).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            MockAction::ReplyRaw(responses) => {
+                for response in responses {
+                    if w.send(response).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            MockAction::Disconnect => return,
+        }
+    }
+}
+
+/// A handler that accepts any bind, and defers everything else to `f`.
+pub fn accept_binds<F>(f: F) -> impl Fn(LdapMsg) -> MockAction + Send + Sync + 'static
+where
+    F: Fn(LdapMsg) -> MockAction + Send + Sync + 'static,
+{
+    move |msg: LdapMsg| match msg.op {
+        LdapOp::BindRequest(_) => MockAction::Reply(vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::BindResponse(LdapBindResponse {
+                res: success(),
+                saslcreds: None,
+            }),
+            ctrl: vec![],
+        }]),
+        _ => f(msg),
+    }
+}
+
+pub fn success() -> LdapResult {
+    result(LdapResultCode::Success)
+}
+
+pub fn result(code: LdapResultCode) -> LdapResult {
+    LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message: "".to_string(),
+        referral: vec![],
+    }
+}
+
+pub fn backend(addr: SocketAddr) -> Backend {
+    Backend::new(
+        Url::parse(&format!("ldaps://localhost:{}", addr.port())).expect("url"),
+        "localhost".to_string(),
+        addr.port(),
+        vec![addr],
+    )
+}
+
+pub fn backend_pool(name: &str, addr: SocketAddr, tls_params: SslConnector) -> BackendPool {
+    BackendPool::new(
+        name,
+        tls_params,
+        vec![backend(addr)],
+        BackendStrategy::Ordered,
+    )
+}
+
+pub fn app_state(
+    addr: SocketAddr,
+    tls_params: SslConnector,
+    binddn_map: BTreeMap<String, DnConfig>,
+) -> AppState {
+    AppState {
+        backend_pools: BTreeMap::from([(
+            DEFAULT_BACKEND.to_string(),
+            backend_pool(DEFAULT_BACKEND, addr, tls_params),
+        )]),
+        backend_suffixes: Vec::new(),
+        naming_contexts: Vec::new(),
+        global_catalogs: Vec::new(),
+        fan_out: Vec::new(),
+        breakers: CircuitBreakers::new(3, Duration::from_secs(60)),
+        retry: RetryPolicy::new(1, Duration::from_millis(10)),
+        connections: Arc::new(ConnectionTracker::new(None, None)),
+        bind_limiter: None,
+        upstream_pool: UpstreamPool::new(0, Duration::from_secs(60), Duration::from_secs(600)),
+        service_connections: ServiceConnections::default(),
+        secrets: SecretCache::default(),
+        bind_cache: None,
+        bind_failures: BindFailureTracker::new(5, Duration::from_secs(300), None, false),
+        metrics: Metrics::default(),
+        per_dn_metrics: false,
+        ad_compat: false,
+        log_filter_values: FilterValues::Plain,
+        policy: ArcSwap::from_pointee(Policy {
+            binddn_map,
+            binddn_patterns: BindDnPatterns::default(),
+            allowed_client_networks: vec![],
+            denied_client_networks: vec![],
+            request_controls: ControlPolicy::new(None, default_denied_controls()),
+            response_controls: ControlPolicy::default(),
+            allow_all_bind_dns: false,
+            allow_write: false,
+            read_only: false,
+            allowed_extended_oids: Default::default(),
+            cache_entry_timeout: Duration::from_secs(60),
+            cache_ttls: BTreeMap::new(),
+            negative_cache_ttl: None,
+            cache_max_stale: None,
+            cache_bypass_control: None,
+            filter_limits: FilterLimits::default(),
+        }),
+        cache: ARCacheBuilder::new()
+            .set_size(1024 * 1024, 0)
+            .build()
+            .expect("cache"),
+        idle_timeout: None,
+        max_session_operations: None,
+        backend_sorts: false,
+        max_incoming_ber_size: None,
+        client_tcp: TcpOptions::default(),
+        cldap_port: 389,
+        cldap_allowed_networks: Vec::new(),
+        cldap_relay: Default::default(),
+        max_proxy_ber_size: None,
+        max_cacheable_result_bytes: None,
+        cache_min_entry_weight: 1,
+        cache_index: CacheIndex::default(),
+        search_flights: Some(SearchFlights::default()),
+        cache_refreshes: CacheRefreshes::default(),
+        dn_quotas: DnQuotas::default(),
+        expect_proxy_protocol: false,
+        proxy_protocol_trusted_networks: Vec::new(),
+        max_relayed_entries: None,
+        max_cacheable_entries: None,
+        referral_mode: ReferralMode::Passthrough,
+        referral_rewrite_host: "proxy.example.com:636".to_string(),
+        referral_rewrite_map: BTreeMap::new(),
+        referral_hop_limit: 3,
+        referral_chase_hosts: Vec::new(),
+        cert_map: BTreeMap::new(),
+        reject_unmapped_cert_binds: false,
+        anonymous_bind: None,
+        root_dse: None,
+        schema: None,
+        access: Arc::new(ConfigAccessPolicy),
+        search_hook: None,
+        dn_rewrite: None,
+        member_of: None,
+        audit: None,
+        tap: None,
+        sessions: Arc::default(),
+        listening: AtomicBool::new(true),
+    }
+}
+
+/// The client side of a proxy session.
+pub struct TestClient {
+    pub r: FramedRead<ReadHalf<DuplexStream>, LdapCodec>,
+    pub w: FramedWrite<WriteHalf<DuplexStream>, LdapCodec>,
+}
+
+impl TestClient {
+    pub async fn send(&mut self, msgid: i32, op: LdapOp) {
+        self.send_with_controls(msgid, op, vec![]).await
+    }
+
+    pub async fn send_with_controls(&mut self, msgid: i32, op: LdapOp, ctrl: Vec<LdapControl>) {
+        self.w
+            .send(LdapMsg { msgid, op, ctrl })
+            .await
+            .expect("send");
+    }
+
+    /// Send a request with a control that ldap3_proto can't encode, with no
+    /// value.
+    pub async fn send_with_raw_control(
+        &mut self,
+        msgid: i32,
+        op: LdapOp,
+        oid: &str,
+        criticality: bool,
+    ) {
+        self.send_with_raw_controls(msgid, op, &[(oid, criticality, None)])
+            .await
+    }
+
+    /// Send a request with controls that ldap3_proto can't encode, each as its
+    /// oid, criticality and encoded value.
+    pub async fn send_with_raw_controls(
+        &mut self,
+        msgid: i32,
+        op: LdapOp,
+        controls: &[(&str, bool, Option<&[u8]>)],
+    ) {
+        let mut encoded = BytesMut::new();
+        LdapCodec::new(None)
+            .encode(
+                LdapMsg {
+                    msgid,
+                    op,
+                    ctrl: vec![],
+                },
+                &mut encoded,
+            )
+            .expect("encode");
+        let header = match encoded[1] {
+            short if short & 0x80 == 0 => 2,
+            long => 2 + usize::from(long & 0x7f),
+        };
+        let mut encoded_controls = Vec::new();
+        for (oid, criticality, value) in controls {
+            let mut control = ber(0x04, oid.as_bytes());
+            if *criticality {
+                control.extend([0x01, 0x01, 0xff]);
+            }
+            if let Some(value) = value {
+                control.extend(ber(0x04, value));
+            }
+            encoded_controls.extend(ber(0x30, &control));
+        }
+        let mut body = encoded[header..].to_vec();
+        body.extend(ber(0xa0, &encoded_controls));
+        let frame = ber(0x30, &body);
+
+        self.w.flush().await.expect("flush");
+        let stream = self.w.get_mut();
+        stream.write_all(&frame).await.expect("send");
+        stream.flush().await.expect("flush");
+    }
+
+    pub async fn recv(&mut self) -> Option<LdapMsg> {
+        tokio::time::timeout(Duration::from_secs(10), self.r.next())
+            .await
+            .expect("timeout")
+            .and_then(|r| r.ok())
+    }
+
+    /// Receive a response that may have controls that ldap3_proto can't
+    /// decode. The message is returned without its controls, and with them
+    /// encoded.
+    pub async fn recv_with_raw_controls(&mut self) -> (LdapMsg, Vec<u8>) {
+        let mut buf = self.r.read_buffer_mut().split().to_vec();
+        let stream = self.r.get_mut();
+        let frame = loop {
+            if let Some(length) = element_length(&buf) {
+                if buf.len() >= length {
+                    break buf.drain(..length).collect::<Vec<_>>();
+                }
+            }
+            let mut more = [0; 4096];
+            let read = tokio::time::timeout(Duration::from_secs(10), stream.read(&mut more))
+                .await
+                .expect("timeout")
+                .expect("read");
+            assert!(read > 0, "no response");
+            buf.extend_from_slice(&more[..read]);
+        };
+        self.r.read_buffer_mut().extend_from_slice(&buf);
+
+        let body = &frame[header_length(&frame)..];
+        let msgid_length = element_length(body).expect("msgid");
+        let op_length = element_length(&body[msgid_length..]).expect("op");
+        let (msgid_and_op, controls) = body.split_at(msgid_length + op_length);
+        let controls = match controls {
+            [] => Vec::new(),
+            controls => controls[header_length(controls)..].to_vec(),
+        };
+        let msg = LdapCodec::new(None)
+            .decode(&mut BytesMut::from(ber(0x30, msgid_and_op).as_slice()))
+            .expect("decode")
+            .expect("message");
+        (msg, controls)
+    }
+
+    pub async fn bind(&mut self, msgid: i32, dn: &str) -> LdapResultCode {
+        self.send(
+            msgid,
+            LdapOp::BindRequest(LdapBindRequest {
+                dn: dn.to_string(),
+                cred: LdapBindCred::Simple("password".to_string()),
+            }),
+        )
+        .await;
+        match self.recv().await {
+            Some(LdapMsg {
+                op: LdapOp::BindResponse(resp),
+                ..
+            }) => resp.res.code,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
+
+// A ber element with this tag and contents.
+fn header_length(buf: &[u8]) -> usize {
+    match buf[1] {
+        short if short & 0x80 == 0 => 2,
+        long => 2 + usize::from(long & 0x7f),
+    }
+}
+
+// The length of the ber element at the start of buf, if its header is there.
+fn element_length(buf: &[u8]) -> Option<usize> {
+    let first = *buf.get(1)?;
+    if first & 0x80 == 0 {
+        return Some(2 + usize::from(first));
+    }
+    let octets = usize::from(first & 0x7f);
+    let length = buf
+        .get(2..2 + octets)?
+        .iter()
+        .fold(0, |acc, octet| (acc << 8) | usize::from(*octet));
+    Some(2 + octets + length)
+}
+
+pub fn ber(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut element = vec![tag];
+    if contents.len() < 0x80 {
+        element.push(contents.len() as u8);
+    } else {
+        let octets: Vec<u8> = contents
+            .len()
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|octet| *octet == 0)
+            .collect();
+        element.push(0x80 | octets.len() as u8);
+        element.extend(octets);
+    }
+    element.extend_from_slice(contents);
+    element
+}
+
+/// Connect to an ldaps listener, such as that of a ProxyServer. The tls
+/// stream is relayed over in-memory streams, like those of connect.
+pub async fn connect_ldaps(addr: SocketAddr, connector: &SslConnector) -> TestClient {
+    let tcpstream = tokio::net::TcpStream::connect(addr).await.expect("connect");
+    let ssl = connector
+        .configure()
+        .and_then(|config| config.into_ssl("localhost"))
+        .expect("ssl");
+    let mut tlsstream = SslStream::new(ssl, tcpstream).expect("tls");
+    SslStream::connect(Pin::new(&mut tlsstream))
+        .await
+        .expect("handshake");
+
+    let (client, mut server) = tokio::io::duplex(65536);
+    tokio::spawn(async move {
+        let _ = tokio::io::copy_bidirectional(&mut server, &mut tlsstream).await;
+    });
+    let (cr, cw) = tokio::io::split(client);
+    TestClient {
+        r: FramedRead::new(cr, LdapCodec::new(None)),
+        w: FramedWrite::new(cw, LdapCodec::new(None)),
+    }
+}
+
+/// Start a proxy session for a single client, connected over in-memory streams.
+pub fn connect(app_state: Arc<AppState>) -> TestClient {
+    connect_with_cert(app_state, None)
+}
+
+/// Start a proxy session for a client that presented this certificate.
+pub fn connect_with_cert(
+    app_state: Arc<AppState>,
+    client_cert: Option<ClientCertificate>,
+) -> TestClient {
+    let (client, server) = tokio::io::duplex(65536);
+    let (sr, sw) = tokio::io::split(server);
+    let (cr, cw) = tokio::io::split(client);
+
+    let client_address: SocketAddr = "127.0.0.1:12345".parse().expect("addr");
+    tokio::spawn(client_process(
+        FramedRead::new(sr, ClientCodec::new(None)),
+        FramedWrite::new(sw, ClientCodec::new(None)),
+        client_address,
+        client_cert,
+        app_state,
+    ));
+
+    TestClient {
+        r: FramedRead::new(cr, LdapCodec::new(None)),
+        w: FramedWrite::new(cw, LdapCodec::new(None)),
+    }
+}
+
+/// A span that SpanRecorder saw, with its msgid, id and bind_dn fields once
+/// they were recorded.
+#[derive(Debug, Clone, Default)]
+pub struct RecordedSpan {
+    pub name: &'static str,
+    /// The index of the parent span in the recording.
+    pub parent: Option<usize>,
+    pub msgid: Option<i64>,
+    pub id: Option<u64>,
+    pub bind_dn: Option<String>,
+}
+
+/// An event that SpanRecorder saw, with the span that it was in.
+#[derive(Debug, Clone)]
+pub struct RecordedEvent {
+    pub message: String,
+    /// The index of its span in the recording.
+    pub span: Option<usize>,
+}
+
+/// A tracing layer that records the spans that are created, and the events,
+/// in order.
+#[derive(Clone, Default)]
+pub struct SpanRecorder {
+    spans: Arc<Mutex<Vec<(u64, RecordedSpan)>>>,
+    events: Arc<Mutex<Vec<RecordedEvent>>>,
+}
+
+impl SpanRecorder {
+    pub fn spans(&self) -> Vec<RecordedSpan> {
+        let spans = self.spans.lock().unwrap();
+        spans.iter().map(|(_, span)| span.clone()).collect()
+    }
+
+    pub fn events(&self) -> Vec<RecordedEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+struct MessageVisitor<'a>(&'a mut String);
+
+impl Visit for MessageVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            *self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+struct SpanVisitor<'a>(&'a mut RecordedSpan);
+
+impl Visit for SpanVisitor<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        match field.name() {
+            "msgid" => self.0.msgid = Some(value),
+            "id" => self.0.id = u64::try_from(value).ok(),
+            _ => {}
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "msgid" => self.0.msgid = i64::try_from(value).ok(),
+            "id" => self.0.id = Some(value),
+            _ => {}
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "bind_dn" {
+            self.0.bind_dn = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, _: &Field, _: &dyn std::fmt::Debug) {}
+}
+
+// Ids are reused once spans close, so a span is the latest that has its id.
+fn latest(spans: &[(u64, RecordedSpan)], id: u64) -> Option<usize> {
+    spans.iter().rposition(|(span_id, _)| *span_id == id)
+}
+
+impl<S> Layer<S> for SpanRecorder
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut spans = self.spans.lock().unwrap();
+        let parent = ctx
+            .span(id)
+            .and_then(|span| span.parent())
+            .and_then(|parent| latest(&spans, parent.id().into_u64()));
+        let mut span = RecordedSpan {
+            name: attrs.metadata().name(),
+            parent,
+            ..Default::default()
+        };
+        attrs.record(&mut SpanVisitor(&mut span));
+        spans.push((id.into_u64(), span));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, _: Context<'_, S>) {
+        let mut spans = self.spans.lock().unwrap();
+        if let Some(idx) = latest(&spans, id.into_u64()) {
+            values.record(&mut SpanVisitor(&mut spans[idx].1));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut message = String::new();
+        event.record(&mut MessageVisitor(&mut message));
+        let span = ctx.event_span(event).and_then(|span| {
+            let spans = self.spans.lock().unwrap();
+            latest(&spans, span.id().into_u64())
+        });
+        self.events
+            .lock()
+            .unwrap()
+            .push(RecordedEvent { message, span });
+    }
+}
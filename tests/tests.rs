@@ -1,9 +1,71 @@
 // use ldap_proxy::proxy::BasicLdapClient;
 
-use ldap3_proto::proto::LdapResult;
-use ldap_proxy::proxy::CachedValue;
-use ldap_proxy::Config;
+mod common;
+
+use common::MockAction;
+use ldap3_proto::control::LdapControl;
+use ldap3_proto::parse_ldap_filter_str;
+use ldap3_proto::proto::*;
+use ldap_proxy::access::{AccessPolicy, BindAccess, Decision, DecisionFuture, SearchAccess};
+use ldap_proxy::adcompat::AdBindFailure;
+use ldap_proxy::admin::admin_process;
+use ldap_proxy::attrmap::{rewrite_entry, rewrite_search};
+use ldap_proxy::audit::{
+    filter_string, redacted_filter_string, AuditConfig, AuditLog, FilterValues,
+};
+use ldap_proxy::bindcache::BindCache;
+use ldap_proxy::breaker::CircuitBreakers;
+use ldap_proxy::certmap::{CertMap, CertMapEntry, ClientCertificate, UnmappedCertPolicy};
+use ldap_proxy::cldap::cldap_answer;
+use ldap_proxy::codec::{
+    password_policy_response, BackendCodec, ClientCodec, ClientResponse, RawControl,
+};
+use ldap_proxy::config::{example_config, load_config, ConfigSource, Secret};
+use ldap_proxy::connections::{ConnectionLimit, ConnectionTracker};
+use ldap_proxy::controls::{
+    default_denied_controls, AdminControlAction, ControlPolicy, OID_ENTRY_CHANGE_NOTIFICATION,
+    OID_MANAGE_DSA_IT, OID_PERSISTENT_SEARCH, OID_PROXIED_AUTHZ, OID_RELAX_RULES,
+};
+use ldap_proxy::dn::{normalize_dn, DnError};
+use ldap_proxy::filter::FilterLimits;
+use ldap_proxy::health::health_process;
+use ldap_proxy::hook::SearchHook;
+use ldap_proxy::latency::Stage;
+use ldap_proxy::lockout::{BindFailureTracker, ThresholdsCrossed};
+use ldap_proxy::memberof::MemberOfConfig;
+use ldap_proxy::persist::{load_cache, save_cache};
+use ldap_proxy::ppolicy::{
+    PasswordPolicyResponse, PolicyError, PolicyWarning, OID_PASSWORD_POLICY,
+};
+use ldap_proxy::proxy::{
+    check_backend_health, client_process, client_starttls, probe_upstream_pool, read_root_dse,
+    sweep_expired_cache, BasicLdapClient, CachedValue, UpstreamPool,
+};
+use ldap_proxy::proxy_protocol::read_proxy_header;
+use ldap_proxy::ratelimit::BindRateLimiter;
+use ldap_proxy::referral::{rewrite_target, rewrite_url, LdapUrl, ReferralMode};
+use ldap_proxy::retry::RetryPolicy;
+use ldap_proxy::rootdse::{RootDse, RootDseConfig};
+use ldap_proxy::schema::SchemaCache;
+use ldap_proxy::secrets::{SecretProvider, SecretSource};
+use ldap_proxy::server::{build_runtime, ProxyBuilder};
+use ldap_proxy::sort::OID_SERVER_SORT;
+use ldap_proxy::systemd::{self, ListenFd};
+use ldap_proxy::tap::{TapConfig, TapLog};
+use ldap_proxy::tcp::TcpOptions;
+use ldap_proxy::vlv::OID_VLV_REQUEST;
+use ldap_proxy::{
+    ldapi_socket_path, AppState, Backend, BackendPool, BackendStrategy, BackendTls, Config,
+    DnConfig, ListenerMode, Policy, RuntimeFlavor, SpkiPin, TlsVersion, Transport, DEFAULT_BACKEND,
+};
+use std::collections::BTreeMap;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::Decoder;
 
 #[test]
 fn hello_world() {
@@ -25,6 +87,7 @@ fn test_cachedvalue() {
     let cv = CachedValue {
         valid_until: Instant::now() + Duration::from_secs(60),
         entries: Vec::with_capacity(5),
+        references: Vec::with_capacity(5),
         result: LdapResult {
             code: ldap3_proto::LdapResultCode::Busy,
             matcheddn: "dn=doo".to_string(),
@@ -33,5 +96,10278 @@ fn test_cachedvalue() {
         },
         ctrl: Vec::with_capacity(5),
     };
-    assert_eq!(cv.size(), 144);
+    // The struct, and the buffers of its vectors and strings.
+    let buffers = 5 * size_of::<(LdapSearchResultEntry, Vec<LdapControl>)>()
+        + 5 * size_of::<(LdapSearchResultReference, Vec<LdapControl>)>()
+        + "dn=doo".len()
+        + "ohno".len()
+        + 5 * size_of::<String>()
+        + 5 * size_of::<LdapControl>();
+    assert_eq!(cv.size(), 168 + buffers);
+
+    // The bytes of attribute values are counted once each.
+    let empty = CachedValue {
+        entries: vec![],
+        references: vec![],
+        result: common::success(),
+        ctrl: vec![],
+        ..cv
+    };
+    let photo = CachedValue {
+        entries: vec![(
+            LdapSearchResultEntry {
+                dn: "uid=demo,o=example".to_string(),
+                attributes: vec![LdapPartialAttribute {
+                    atype: "jpegPhoto".to_string(),
+                    vals: vec![vec![0; 10000]],
+                }],
+            },
+            vec![],
+        )],
+        ..empty.clone()
+    };
+    let grown = photo.size() - empty.size();
+    assert!((10000..11000).contains(&grown), "{}", grown);
+}
+
+fn compare_request() -> LdapOp {
+    LdapOp::CompareRequest(LdapCompareRequest {
+        dn: "uid=demo,o=example".to_string(),
+        atype: "userPassword".to_string(),
+        val: b"password".to_vec(),
+    })
+}
+
+async fn compare_app_state(allow_compare: bool) -> Arc<ldap_proxy::AppState> {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            // Only the demo user will compare as true.
+            LdapOp::CompareRequest(lcr) if lcr.dn == "uid=demo,o=example" => {
+                MockAction::Reply(vec![LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::CompareResult(common::result(LdapResultCode::CompareTrue)),
+                    ctrl: vec![],
+                }])
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert(
+        "cn=radius".to_string(),
+        DnConfig {
+            allow_compare,
+            ..Default::default()
+        },
+    );
+    Arc::new(common::app_state(addr, connector, binddn_map))
+}
+
+#[tokio::test]
+async fn test_compare_permitted() {
+    let app_state = compare_app_state(true).await;
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=radius").await, LdapResultCode::Success);
+
+    client.send(2, compare_request()).await;
+    let msg = client.recv().await.expect("no response");
+    assert_eq!(msg.msgid, 2);
+    assert!(matches!(
+        msg.op,
+        LdapOp::CompareResult(LdapResult {
+            code: LdapResultCode::CompareTrue,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_compare_denied() {
+    let app_state = compare_app_state(false).await;
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=radius").await, LdapResultCode::Success);
+
+    client.send(2, compare_request()).await;
+    let msg = client.recv().await.expect("no response");
+    assert_eq!(msg.msgid, 2);
+    assert!(matches!(
+        msg.op,
+        LdapOp::CompareResult(LdapResult {
+            code: LdapResultCode::InsufficentAccessRights,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_compare_attrs() {
+    let config = DnConfig {
+        allow_compare: true,
+        denied_compare_attrs: ["userPassword".to_string()].into(),
+        ..Default::default()
+    };
+    assert!(!config.permits_compare("userpassword"));
+    assert!(!config.permits_compare("userPassword;binary"));
+    assert!(config.permits_compare("mail"));
+    let config = DnConfig {
+        allowed_compare_attrs: Some(["memberOf".to_string()].into()),
+        ..config
+    };
+    assert!(config.permits_compare("MEMBEROF"));
+    assert!(!config.permits_compare("mail"));
+
+    let app_state = compare_app_state(true).await;
+    app_state.update_policy(|policy| {
+        let dnconfig = policy.binddn_map.get_mut("cn=radius").unwrap();
+        dnconfig.denied_compare_attrs = ["userPassword".to_string()].into();
+    });
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=radius").await, LdapResultCode::Success);
+
+    client.send(2, compare_request()).await;
+    let msg = client.recv().await.expect("no response");
+    assert!(matches!(
+        msg.op,
+        LdapOp::CompareResult(LdapResult {
+            code: LdapResultCode::InsufficentAccessRights,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_compare_backend_error() {
+    let app_state = compare_app_state(true).await;
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=radius").await, LdapResultCode::Success);
+
+    // The backend drops the connection on this compare.
+    client
+        .send(
+            2,
+            LdapOp::CompareRequest(LdapCompareRequest {
+                dn: "uid=other,o=example".to_string(),
+                atype: "userPassword".to_string(),
+                val: b"password".to_vec(),
+            }),
+        )
+        .await;
+    let msg = client.recv().await.expect("no response");
+    assert_eq!(msg.msgid, 2);
+    assert!(matches!(
+        msg.op,
+        LdapOp::CompareResult(LdapResult {
+            code: LdapResultCode::Unavailable,
+            ..
+        })
+    ));
+
+    // The session reconnects, and remains usable.
+    client.send(3, compare_request()).await;
+    let msg = client.recv().await.expect("no response");
+    assert_eq!(msg.msgid, 3);
+    assert!(matches!(
+        msg.op,
+        LdapOp::CompareResult(LdapResult {
+            code: LdapResultCode::CompareTrue,
+            ..
+        })
+    ));
+}
+
+fn search_request() -> LdapOp {
+    LdapOp::SearchRequest(LdapSearchRequest {
+        base: "o=example".to_string(),
+        scope: LdapSearchScope::Subtree,
+        aliases: LdapDerefAliases::Never,
+        sizelimit: 0,
+        timelimit: 0,
+        typesonly: false,
+        filter: LdapFilter::Present("objectClass".to_string()),
+        attrs: vec![],
+    })
+}
+
+// Read a search result stream, returning the number of entries and the result code.
+async fn recv_search(client: &mut common::TestClient) -> (usize, LdapResultCode) {
+    let mut entries = 0;
+    loop {
+        match client.recv().await.expect("no response").op {
+            LdapOp::SearchResultEntry(_) => entries += 1,
+            LdapOp::SearchResultDone(res) => break (entries, res.code),
+            op => panic!("unexpected {:?}", op),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_allowed_bases() {
+    let config = DnConfig {
+        allowed_bases: vec!["OU=People, o=example".to_string()],
+        ..Default::default()
+    };
+    assert!(config.permits_search_base("ou=people,o=example"));
+    assert!(config.permits_search_base("uid=a, ou=People,o=example"));
+    assert!(!config.permits_search_base("o=example"));
+    assert!(!config.permits_search_base("ou=groups,o=example"));
+    assert!(DnConfig::default().permits_search_base("o=example"));
+
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            }]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+    let binddn_map = BTreeMap::from([("cn=sssd".to_string(), config)]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    let search = |base: &str| {
+        let mut sr = search_request();
+        if let LdapOp::SearchRequest(sr) = &mut sr {
+            sr.base = base.to_string();
+        }
+        sr
+    };
+    client.send(2, search("o=example")).await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (0, LdapResultCode::InsufficentAccessRights)
+    );
+    // The session remains usable.
+    client.send(3, search("ou=people,o=example")).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+}
+
+#[tokio::test]
+async fn test_attribute_restrictions() {
+    let attrs = |names: &[&str]| names.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+    let denied = DnConfig {
+        denied_attrs: ["userPassword".to_string()].into(),
+        ..Default::default()
+    };
+    assert_eq!(denied.restrict_attrs(attrs(&[])), attrs(&[]));
+    assert_eq!(
+        denied.restrict_attrs(attrs(&["cn", "USERPASSWORD;binary"])),
+        attrs(&["cn"])
+    );
+    assert_eq!(
+        denied.restrict_attrs(attrs(&["userPassword"])),
+        attrs(&["1.1"])
+    );
+    let allowed = DnConfig {
+        allowed_attrs: Some(["uid".to_string(), "cn".to_string()].into()),
+        ..Default::default()
+    };
+    assert_eq!(allowed.restrict_attrs(attrs(&[])), attrs(&["cn", "uid"]));
+    assert_eq!(
+        allowed.restrict_attrs(attrs(&["*", "+", "mail"])),
+        attrs(&["cn", "uid"])
+    );
+    assert_eq!(allowed.restrict_attrs(attrs(&["mail"])), attrs(&["1.1"]));
+    assert!(!denied.permits_filter(&parse_ldap_filter_str("(userPassword=secret*)").unwrap()));
+    assert!(
+        allowed.permits_filter(&parse_ldap_filter_str("(&(objectClass=person)(uid=a))").unwrap())
+    );
+
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: "uid=demo,o=example".to_string(),
+                        attributes: ["cn", "userPassword", "krbPrincipalKey"]
+                            .into_iter()
+                            .map(|atype| LdapPartialAttribute {
+                                atype: atype.to_string(),
+                                vals: vec![b"value".to_vec()],
+                            })
+                            .collect(),
+                    }),
+                    ctrl: vec![],
+                },
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                },
+            ]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+    let config = DnConfig {
+        denied_attrs: ["userpassword".to_string(), "krbPrincipalKey".to_string()].into(),
+        ..Default::default()
+    };
+    let binddn_map = BTreeMap::from([("cn=sssd".to_string(), config)]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    let msg = client.recv().await.expect("no response");
+    let LdapOp::SearchResultEntry(entry) = msg.op else {
+        panic!("unexpected {:?}", msg.op);
+    };
+    let atypes: Vec<_> = entry.attributes.iter().map(|a| a.atype.as_str()).collect();
+    assert_eq!(atypes, ["cn"]);
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+}
+
+#[tokio::test]
+async fn test_member_of() {
+    let group_searches = Arc::new(AtomicUsize::new(0));
+    let c_group_searches = group_searches.clone();
+
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| {
+            let LdapOp::SearchRequest(sr) = &msg.op else {
+                return MockAction::Disconnect;
+            };
+            let entry = |dn: &str| LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: dn.to_string(),
+                    attributes: vec![],
+                }),
+                ctrl: vec![],
+            };
+            let done = LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            };
+            if sr.base == "ou=groups,o=example" {
+                c_group_searches.fetch_add(1, Ordering::SeqCst);
+                assert_eq!(
+                    sr.filter,
+                    LdapFilter::Equality("member".to_string(), "uid=demo,o=example".to_string())
+                );
+                MockAction::Reply(vec![entry("cn=admins,ou=groups,o=example"), done])
+            } else {
+                MockAction::Reply(vec![entry("uid=demo,o=example"), done])
+            }
+        }),
+    )
+    .await;
+    let binddn_map = BTreeMap::from([("cn=app".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.member_of = Some(MemberOfConfig {
+        group_base: "ou=groups,o=example".to_string(),
+        member_attribute: "member".to_string(),
+    });
+    let mut client = common::connect(Arc::new(app_state));
+    assert_eq!(client.bind(1, "cn=app").await, LdapResultCode::Success);
+
+    for (msgid, attrs) in [(2, vec!["memberOf"]), (3, vec!["cn", "memberof"])] {
+        let mut sr = search_request();
+        if let LdapOp::SearchRequest(sr) = &mut sr {
+            sr.attrs = attrs.into_iter().map(str::to_string).collect();
+        }
+        client.send(msgid, sr).await;
+        let msg = client.recv().await.expect("no response");
+        let LdapOp::SearchResultEntry(entry) = msg.op else {
+            panic!("unexpected {:?}", msg.op);
+        };
+        assert_eq!(entry.attributes[0].atype, "memberOf");
+        assert_eq!(
+            entry.attributes[0].vals,
+            [b"cn=admins,ou=groups,o=example".to_vec()]
+        );
+        assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    }
+    // The second search found the groups in the cache.
+    assert_eq!(group_searches.load(Ordering::SeqCst), 1);
+
+    // Searches that don't name memberOf don't get it.
+    client.send(4, search_request()).await;
+    let msg = client.recv().await.expect("no response");
+    let LdapOp::SearchResultEntry(entry) = msg.op else {
+        panic!("unexpected {:?}", msg.op);
+    };
+    assert!(entry.attributes.is_empty());
+}
+
+#[tokio::test]
+async fn test_write_denied() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr =
+        common::mock_server(acceptor, common::accept_binds(|_| MockAction::Disconnect)).await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert("cn=reader".to_string(), DnConfig::default());
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=reader").await, LdapResultCode::Success);
+
+    client
+        .send(2, LdapOp::DelRequest("uid=demo,o=example".to_string()))
+        .await;
+    let msg = client.recv().await.expect("no response");
+    assert_eq!(msg.msgid, 2);
+    assert!(matches!(
+        msg.op,
+        LdapOp::DelResponse(LdapResult {
+            code: LdapResultCode::InsufficentAccessRights,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_write_invalidates_cache() {
+    let searches = Arc::new(AtomicUsize::new(0));
+    let c_searches = searches.clone();
+
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => {
+                c_searches.fetch_add(1, Ordering::SeqCst);
+                MockAction::Reply(vec![
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: "uid=demo,o=example".to_string(),
+                            attributes: vec![],
+                        }),
+                        ctrl: vec![],
+                    },
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultDone(common::success()),
+                        ctrl: vec![],
+                    },
+                ])
+            }
+            LdapOp::ModifyRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::ModifyResponse(common::success()),
+                ctrl: vec![],
+            }]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert(
+        "cn=provisioner".to_string(),
+        DnConfig {
+            allow_write: Some(true),
+            ..Default::default()
+        },
+    );
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state);
+    assert_eq!(
+        client.bind(1, "cn=provisioner").await,
+        LdapResultCode::Success
+    );
+
+    // The second search is served from the cache.
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    client.send(3, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    assert_eq!(searches.load(Ordering::SeqCst), 1);
+
+    client
+        .send(
+            4,
+            LdapOp::ModifyRequest(LdapModifyRequest {
+                dn: "uid=demo,o=example".to_string(),
+                changes: vec![],
+            }),
+        )
+        .await;
+    let msg = client.recv().await.expect("no response");
+    assert!(matches!(
+        msg.op,
+        LdapOp::ModifyResponse(LdapResult {
+            code: LdapResultCode::Success,
+            ..
+        })
+    ));
+
+    // The write flushed the cache, so this goes to the backend again.
+    client.send(5, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_paged_search_cached() {
+    let (acceptor, connector) = common::tls_pair();
+    let searches = Arc::new(AtomicUsize::new(0));
+    let seen = searches.clone();
+    // Three entries, in pages of two.
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => {
+                seen.fetch_add(1, Ordering::SeqCst);
+                let (names, next_cookie) = match paged_results_cookie(&msg.ctrl) {
+                    Some(b"") => (vec!["cn=a", "cn=b"], b"next".to_vec()),
+                    Some(b"next") => (vec!["cn=c"], vec![]),
+                    _ => return MockAction::Disconnect,
+                };
+                let mut msgs: Vec<_> = names
+                    .into_iter()
+                    .map(|name| LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: format!("{},o=example", name),
+                            attributes: vec![],
+                        }),
+                        ctrl: vec![],
+                    })
+                    .collect();
+                msgs.push(LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![LdapControl::SimplePagedResults {
+                        size: 3,
+                        cookie: next_cookie,
+                    }],
+                });
+                MockAction::Reply(msgs)
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert("cn=sssd".to_string(), DnConfig::default());
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+
+    // Read every page of the search, returning the number of entries on each.
+    let mut msgid = 1;
+    let mut paged_search = async |client: &mut common::TestClient| {
+        let mut pages = Vec::new();
+        let mut cookie = Vec::new();
+        loop {
+            msgid += 1;
+            let paged = LdapControl::SimplePagedResults { size: 2, cookie };
+            client
+                .send_with_controls(msgid, search_request(), vec![paged])
+                .await;
+            let mut entries = 0;
+            let done = loop {
+                let msg = client.recv().await.expect("no response");
+                match msg.op {
+                    LdapOp::SearchResultEntry(_) => entries += 1,
+                    LdapOp::SearchResultDone(res) => {
+                        assert_eq!(res.code, LdapResultCode::Success);
+                        break msg.ctrl;
+                    }
+                    op => panic!("unexpected {:?}", op),
+                }
+            };
+            pages.push(entries);
+            cookie = paged_results_cookie(&done)
+                .expect("no paged control")
+                .to_vec();
+            if cookie.is_empty() {
+                break pages;
+            }
+        }
+    };
+
+    assert_eq!(paged_search(&mut client).await, vec![2, 1]);
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+
+    // The whole result set was cached with the last page, so the proxy serves
+    // the pages, as well as the unpaged search.
+    assert_eq!(paged_search(&mut client).await, vec![2, 1]);
+    client.send(10, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (3, LdapResultCode::Success));
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+
+    // Cookies that the session wasn't given are refused.
+    let paged = LdapControl::SimplePagedResults {
+        size: 2,
+        cookie: b"forged".to_vec(),
+    };
+    client
+        .send_with_controls(11, search_request(), vec![paged])
+        .await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (0, LdapResultCode::UnwillingToPerform)
+    );
+}
+
+fn paged_results_cookie(ctrl: &[LdapControl]) -> Option<&[u8]> {
+    ctrl.iter().find_map(|c| match c {
+        LdapControl::SimplePagedResults { cookie, .. } => Some(cookie.as_slice()),
+        _ => None,
+    })
+}
+
+#[tokio::test]
+async fn test_extended_passthrough() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            // Echo the request value back.
+            LdapOp::ExtendedRequest(ler) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                    res: common::success(),
+                    name: Some(ler.name),
+                    value: ler.value,
+                }),
+                ctrl: vec![],
+            }]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert(
+        "cn=selfservice".to_string(),
+        DnConfig {
+            allowed_extended_oids: ["1.3.6.1.4.1.4203.1.11.1".to_string()]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        },
+    );
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state);
+    assert_eq!(
+        client.bind(1, "cn=selfservice").await,
+        LdapResultCode::Success
+    );
+
+    let value = vec![0x30, 0x00, 0xff, 0x01];
+    client
+        .send(
+            2,
+            LdapOp::ExtendedRequest(LdapExtendedRequest {
+                name: "1.3.6.1.4.1.4203.1.11.1".to_string(),
+                value: Some(value.clone()),
+            }),
+        )
+        .await;
+    match client.recv().await.expect("no response").op {
+        LdapOp::ExtendedResponse(resp) => {
+            assert_eq!(resp.res.code, LdapResultCode::Success);
+            assert_eq!(resp.value, Some(value));
+        }
+        op => panic!("unexpected {:?}", op),
+    }
+
+    client
+        .send(
+            3,
+            LdapOp::ExtendedRequest(LdapExtendedRequest {
+                name: "1.2.3.4".to_string(),
+                value: None,
+            }),
+        )
+        .await;
+    match client.recv().await.expect("no response").op {
+        LdapOp::ExtendedResponse(resp) => {
+            assert_eq!(resp.res.code, LdapResultCode::ProtocolError);
+            assert!(resp.res.message.contains("1.2.3.4"));
+        }
+        op => panic!("unexpected {:?}", op),
+    }
+}
+
+#[tokio::test]
+async fn test_password_modify() {
+    let (acceptor, connector) = common::tls_pair();
+    let passwords = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = passwords.clone();
+    let dropped = AtomicUsize::new(0);
+    let addr = common::mock_server(acceptor, move |msg| match msg.op {
+        LdapOp::BindRequest(lbr) => {
+            if let LdapBindCred::Simple(password) = lbr.cred {
+                seen.lock().unwrap().push(password);
+            }
+            MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res: common::success(),
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            }])
+        }
+        LdapOp::ExtendedRequest(_) => MockAction::Reply(vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: common::success(),
+                name: None,
+                value: None,
+            }),
+            ctrl: vec![],
+        }]),
+        // Drop the connection on the first search.
+        LdapOp::SearchRequest(_) if dropped.fetch_add(1, Ordering::SeqCst) == 0 => {
+            MockAction::Disconnect
+        }
+        _ => MockAction::Reply(vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::SearchResultDone(common::success()),
+            ctrl: vec![],
+        }]),
+    })
+    .await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert(
+        "cn=selfservice".to_string(),
+        DnConfig {
+            allow_password_modify: true,
+            allowed_queries: [(
+                "o=example".to_string(),
+                LdapSearchScope::Subtree,
+                LdapFilter::Present("objectClass".to_string()),
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        },
+    );
+    binddn_map.insert("cn=other".to_string(), DnConfig::default());
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let password_modify = |new_password: &str| {
+        LdapOp::ExtendedRequest(
+            LdapPasswordModifyRequest {
+                user_identity: None,
+                old_password: Some("password".to_string()),
+                new_password: Some(new_password.to_string()),
+            }
+            .into(),
+        )
+    };
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=other").await, LdapResultCode::Success);
+    client.send(2, password_modify("changed")).await;
+    match client.recv().await.expect("no response").op {
+        LdapOp::ExtendedResponse(resp) => {
+            assert_eq!(resp.res.code, LdapResultCode::ProtocolError)
+        }
+        op => panic!("unexpected {:?}", op),
+    }
+
+    let mut client = common::connect(app_state);
+    assert_eq!(
+        client.bind(1, "cn=selfservice").await,
+        LdapResultCode::Success
+    );
+    client.send(2, password_modify("changed")).await;
+    match client.recv().await.expect("no response").op {
+        LdapOp::ExtendedResponse(resp) => assert_eq!(resp.res.code, LdapResultCode::Success),
+        op => panic!("unexpected {:?}", op),
+    }
+
+    // The backend drops the connection, and the session binds again with the
+    // new password before the search is retried.
+    client.send(3, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    assert_eq!(
+        passwords.lock().unwrap().as_slice(),
+        ["password", "password", "changed"]
+    );
+}
+
+#[tokio::test]
+async fn test_pipelined_searches() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            // Return one entry per rdn in the base.
+            LdapOp::SearchRequest(sr) => {
+                let mut msgs: Vec<_> = sr
+                    .base
+                    .split(',')
+                    .map(|rdn| LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: rdn.to_string(),
+                            attributes: vec![],
+                        }),
+                        ctrl: vec![],
+                    })
+                    .collect();
+                msgs.push(LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                });
+                MockAction::Reply(msgs)
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert("cn=sssd".to_string(), DnConfig::default());
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+
+    let search = |base: &str| {
+        LdapOp::SearchRequest(LdapSearchRequest {
+            base: base.to_string(),
+            scope: LdapSearchScope::Subtree,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: vec![],
+        })
+    };
+
+    // Both searches are sent before any results are read.
+    client.send(2, search("ou=a,o=example")).await;
+    client.send(3, search("ou=b,ou=c,o=example")).await;
+
+    let mut entries: BTreeMap<i32, usize> = BTreeMap::new();
+    let mut done = 0;
+    while done < 2 {
+        let msg = client.recv().await.expect("no response");
+        match msg.op {
+            LdapOp::SearchResultEntry(_) => *entries.entry(msg.msgid).or_default() += 1,
+            LdapOp::SearchResultDone(res) => {
+                assert_eq!(res.code, LdapResultCode::Success);
+                done += 1;
+            }
+            op => panic!("unexpected {:?}", op),
+        }
+    }
+    assert_eq!(entries.get(&2), Some(&2));
+    assert_eq!(entries.get(&3), Some(&3));
+}
+
+#[test]
+fn test_config_multiple_backends() {
+    let config = toml::from_str::<Config>(
+        r#"
+bind = "127.0.0.1:3636"
+tls_key = "/tmp/key.pem"
+tls_chain = "/tmp/chain.pem"
+ldap_ca = "/tmp/ca.pem"
+ldap_url = ["ldaps://a.example.com", "ldaps://b.example.com"]
+backend_strategy = "round-robin"
+read_timeout_secs = 10
+
+[backends.master]
+ldap_url = "ldaps://master.example.com"
+bind_dn_suffixes = ["DC=ipa, dc=example"]
+connect_timeout_secs = 2
+
+["cn=provisioner"]
+backend = "master"
+"#,
+    )
+    .unwrap();
+    assert_eq!(config.ldap_url.len(), 2);
+    assert_eq!(config.backend_strategy, BackendStrategy::RoundRobin);
+    assert_eq!(config.backends["master"].ldap_url.len(), 1);
+    assert!(config.backends["master"].ldap_ca.is_none());
+    assert_eq!(
+        config.backends["master"].bind_dn_suffixes,
+        ["dc=ipa,dc=example"]
+    );
+    assert_eq!(
+        config.binddn_map["cn=provisioner"].backend.as_deref(),
+        Some("master")
+    );
+    assert!(!config.binddn_map.contains_key("backends"));
+
+    // Backends inherit the timeouts they don't set.
+    let timeouts = config.backend_timeouts(Some(&config.backends["master"]));
+    assert_eq!(timeouts.connect, Duration::from_secs(2));
+    assert_eq!(timeouts.read, Some(Duration::from_secs(10)));
+    assert_eq!(timeouts.operation, None);
+    assert_eq!(
+        config.backend_timeouts(None).connect,
+        Duration::from_secs(5)
+    );
+}
+
+#[tokio::test]
+async fn test_abandon_search() {
+    let (acceptor, connector) = common::tls_pair();
+    // The backend msgid of the slow search, and of the search it was asked to
+    // abandon.
+    let slow = Arc::new(AtomicUsize::new(0));
+    let abandoned = Arc::new(AtomicUsize::new(0));
+    let (seen_slow, seen_abandoned) = (slow.clone(), abandoned.clone());
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            // Never answered.
+            LdapOp::SearchRequest(sr) if sr.base == "ou=slow,o=example" => {
+                seen_slow.store(msg.msgid as usize, Ordering::SeqCst);
+                MockAction::Reply(vec![])
+            }
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            }]),
+            LdapOp::AbandonRequest(msgid) => {
+                seen_abandoned.store(msgid as usize, Ordering::SeqCst);
+                MockAction::Reply(vec![])
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert("cn=sssd".to_string(), DnConfig::default());
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+
+    let mut slow_search = search_request();
+    if let LdapOp::SearchRequest(sr) = &mut slow_search {
+        sr.base = "ou=slow,o=example".to_string();
+    }
+    client.send(2, slow_search).await;
+    let wait_for = async |value: &AtomicUsize| {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while value.load(Ordering::SeqCst) == 0 {
+            assert!(Instant::now() < deadline, "backend never saw the request");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    wait_for(&slow).await;
+
+    // The search is abandoned on the backend, and has no response.
+    client.send(3, LdapOp::AbandonRequest(2)).await;
+    client.send(4, search_request()).await;
+    let msg = client.recv().await.expect("no response");
+    assert_eq!(msg.msgid, 4);
+    assert!(matches!(msg.op, LdapOp::SearchResultDone(_)));
+    wait_for(&abandoned).await;
+    assert_eq!(
+        abandoned.load(Ordering::SeqCst),
+        slow.load(Ordering::SeqCst)
+    );
+    assert_eq!(app_state.metrics.get("searches_abandoned_total", &[]), 1);
+
+    // Abandoning an unknown operation is ignored.
+    client.send(5, LdapOp::AbandonRequest(42)).await;
+    client.send(6, search_request()).await;
+    assert_eq!(client.recv().await.expect("no response").msgid, 6);
+}
+
+#[tokio::test]
+async fn test_backend_failover() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr =
+        common::mock_server(acceptor, common::accept_binds(|_| MockAction::Disconnect)).await;
+
+    // Nothing is listening on this address, so the proxy must move on to the next backend.
+    let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let dead_addr = dead.local_addr().unwrap();
+    drop(dead);
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert("cn=radius".to_string(), DnConfig::default());
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    let pool = app_state
+        .backend_pools
+        .get_mut(DEFAULT_BACKEND)
+        .expect("default pool");
+    pool.backends.insert(0, common::backend(dead_addr));
+    let dead_url = pool.backends[0].url.to_string();
+    let url = pool.backends[1].url.to_string();
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=radius").await, LdapResultCode::Success);
+
+    assert_eq!(
+        app_state.metrics.get(
+            "backend_connection_errors_total",
+            &[("pool", DEFAULT_BACKEND), ("backend", dead_url.as_str())]
+        ),
+        1
+    );
+    assert_eq!(
+        app_state.metrics.get(
+            "backend_connections_total",
+            &[("pool", DEFAULT_BACKEND), ("backend", url.as_str())]
+        ),
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_per_dn_backend_routing() {
+    let (acceptor, connector) = common::tls_pair();
+    let replica = common::mock_server(
+        acceptor.clone(),
+        common::accept_binds(|_| MockAction::Disconnect),
+    )
+    .await;
+    let master =
+        common::mock_server(acceptor, common::accept_binds(|_| MockAction::Disconnect)).await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert("cn=radius".to_string(), DnConfig::default());
+    binddn_map.insert(
+        "cn=provisioner".to_string(),
+        DnConfig {
+            backend: Some("master".to_string()),
+            ..Default::default()
+        },
+    );
+    let mut app_state = common::app_state(replica, connector.clone(), binddn_map);
+    app_state.backend_pools.insert(
+        "master".to_string(),
+        common::backend_pool("master", master, connector),
+    );
+    app_state.backend_suffixes = vec![("dc=ipa,dc=example".to_string(), "master".to_string())];
+    let app_state = Arc::new(app_state);
+
+    // DNs are routed by suffix, unless their config names a backend.
+    let pool =
+        |dn: &str, config: &DnConfig| app_state.backend_pool(dn, config).unwrap().name.clone();
+    assert_eq!(
+        pool("uid=a,dc=ipa,dc=example", &DnConfig::default()),
+        "master"
+    );
+    assert_eq!(
+        pool("uid=a,dc=ad,dc=example", &DnConfig::default()),
+        DEFAULT_BACKEND
+    );
+    let replica_config = DnConfig {
+        backend: Some(DEFAULT_BACKEND.to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        pool("uid=a,dc=ipa,dc=example", &replica_config),
+        DEFAULT_BACKEND
+    );
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=provisioner").await,
+        LdapResultCode::Success
+    );
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=radius").await, LdapResultCode::Success);
+
+    let master_url = common::backend(master).url.to_string();
+    let replica_url = common::backend(replica).url.to_string();
+    assert_eq!(
+        app_state.metrics.get(
+            "backend_connections_total",
+            &[("pool", "master"), ("backend", master_url.as_str())]
+        ),
+        1
+    );
+    assert_eq!(
+        app_state.metrics.get(
+            "backend_connections_total",
+            &[("pool", DEFAULT_BACKEND), ("backend", replica_url.as_str())]
+        ),
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_search_base_routing() {
+    // Each backend returns one entry from its own naming context, and doesn't
+    // hold the other's.
+    let backend = |context: &'static str| {
+        common::accept_binds(move |msg| {
+            let LdapOp::SearchRequest(sr) = &msg.op else {
+                return MockAction::Disconnect;
+            };
+            let done = |code| LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::result(code)),
+                ctrl: vec![],
+            };
+            if !normalize_dn(&sr.base).unwrap().ends_with(context) && sr.base != "o=example" {
+                return MockAction::Reply(vec![done(LdapResultCode::NoSuchObject)]);
+            }
+            MockAction::Reply(vec![
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: format!("uid=demo,{}", context),
+                        attributes: vec![],
+                    }),
+                    ctrl: vec![],
+                },
+                done(LdapResultCode::Success),
+            ])
+        })
+    };
+    let (acceptor, connector) = common::tls_pair();
+    let default = common::mock_server(acceptor.clone(), backend("ou=a,o=example")).await;
+    let tenant = common::mock_server(acceptor, backend("ou=b,o=example")).await;
+
+    let binddn_map = BTreeMap::from([("cn=app".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(default, connector.clone(), binddn_map);
+    app_state.backend_pools.insert(
+        "tenant".to_string(),
+        common::backend_pool("tenant", tenant, connector),
+    );
+    app_state.naming_contexts = vec![("ou=b,o=example".to_string(), "tenant".to_string())];
+    assert_eq!(
+        app_state.search_routes(DEFAULT_BACKEND, "o=example", &LdapSearchScope::Base, true),
+        [DEFAULT_BACKEND]
+    );
+    let mut client = common::connect(Arc::new(app_state));
+    assert_eq!(client.bind(1, "cn=app").await, LdapResultCode::Success);
+
+    let search = |base: &str| {
+        let mut sr = search_request();
+        if let LdapOp::SearchRequest(sr) = &mut sr {
+            sr.base = base.to_string();
+        }
+        sr
+    };
+    for (msgid, base, expected) in [
+        (2, "OU=b,o=example", vec!["uid=demo,ou=b,o=example"]),
+        (3, "ou=a,o=example", vec!["uid=demo,ou=a,o=example"]),
+        (
+            4,
+            "o=example",
+            vec!["uid=demo,ou=a,o=example", "uid=demo,ou=b,o=example"],
+        ),
+    ] {
+        client.send(msgid, search(base)).await;
+        assert_eq!(
+            recv_search_refs(&mut client).await,
+            (expected.iter().map(|dn| dn.to_string()).collect(), vec![])
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_reconnect_after_backend_restart() {
+    let (acceptor, connector) = common::tls_pair();
+    let binds = Arc::new(AtomicUsize::new(0));
+    let searches = Arc::new(AtomicUsize::new(0));
+    let c_binds = binds.clone();
+    let c_searches = searches.clone();
+    let addr = common::mock_server(acceptor, move |msg| match msg.op {
+        LdapOp::BindRequest(lbr) => {
+            // The retained credentials are used to re-bind.
+            assert_eq!(lbr.cred, LdapBindCred::Simple("password".to_string()));
+            c_binds.fetch_add(1, Ordering::SeqCst);
+            MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res: common::success(),
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            }])
+        }
+        // The first search finds the backend has gone away.
+        LdapOp::SearchRequest(_) if c_searches.fetch_add(1, Ordering::SeqCst) == 0 => {
+            MockAction::Disconnect
+        }
+        LdapOp::SearchRequest(_) => MockAction::Reply(vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::SearchResultDone(common::success()),
+            ctrl: vec![],
+        }]),
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert("cn=radius".to_string(), DnConfig::default());
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=radius").await, LdapResultCode::Success);
+
+    client.send(2, search_request()).await;
+    let (entries, code) = recv_search(&mut client).await;
+    assert_eq!(entries, 0);
+    assert_eq!(code, LdapResultCode::Success);
+
+    assert_eq!(binds.load(Ordering::SeqCst), 2);
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+    assert_eq!(
+        app_state
+            .metrics
+            .get("backend_reconnects_total", &[("pool", DEFAULT_BACKEND)]),
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_retry_transient_failures() {
+    let (acceptor, connector) = common::tls_pair();
+    let binds = Arc::new(AtomicUsize::new(0));
+    let searches = Arc::new(AtomicUsize::new(0));
+    let c_binds = binds.clone();
+    let c_searches = searches.clone();
+    let addr = common::mock_server(acceptor, move |msg| match msg.op {
+        // The first bind finds its connection dropped.
+        LdapOp::BindRequest(_) if c_binds.fetch_add(1, Ordering::SeqCst) == 0 => {
+            MockAction::Disconnect
+        }
+        LdapOp::BindRequest(_) => MockAction::Reply(vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::BindResponse(LdapBindResponse {
+                res: common::success(),
+                saslcreds: None,
+            }),
+            ctrl: vec![],
+        }]),
+        // As do the first two searches.
+        LdapOp::SearchRequest(_) if c_searches.fetch_add(1, Ordering::SeqCst) < 2 => {
+            MockAction::Disconnect
+        }
+        LdapOp::SearchRequest(_) => MockAction::Reply(vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::SearchResultDone(common::success()),
+            ctrl: vec![],
+        }]),
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert("cn=radius".to_string(), DnConfig::default());
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.retry = RetryPolicy::new(2, Duration::from_millis(10));
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=radius").await, LdapResultCode::Success);
+    assert_eq!(binds.load(Ordering::SeqCst), 2);
+
+    client.send(2, search_request()).await;
+    let (entries, code) = recv_search(&mut client).await;
+    assert_eq!(entries, 0);
+    assert_eq!(code, LdapResultCode::Success);
+    assert_eq!(searches.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_backend_timeouts() {
+    let (acceptor, connector) = common::tls_pair();
+    // Searches are never answered.
+    let addr = common::mock_server(acceptor, |msg| match msg.op {
+        LdapOp::BindRequest(_) => MockAction::Reply(vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::BindResponse(LdapBindResponse {
+                res: common::success(),
+                saslcreds: None,
+            }),
+            ctrl: vec![],
+        }]),
+        LdapOp::SearchRequest(_) | LdapOp::AbandonRequest(_) => MockAction::Reply(vec![]),
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert("cn=radius".to_string(), DnConfig::default());
+    let mut app_state = common::app_state(addr, connector.clone(), binddn_map.clone());
+    app_state
+        .backend_pools
+        .get_mut(DEFAULT_BACKEND)
+        .unwrap()
+        .timeouts
+        .operation = Some(Duration::from_millis(200));
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=radius").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    let (entries, code) = recv_search(&mut client).await;
+    assert_eq!(entries, 0);
+    assert_eq!(code, LdapResultCode::TimeLimitExceeded);
+
+    // A backend that accepts connections, but never completes the handshake.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stalled = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            held.push(stream);
+        }
+    });
+    let mut app_state = common::app_state(stalled, connector, binddn_map);
+    app_state
+        .backend_pools
+        .get_mut(DEFAULT_BACKEND)
+        .unwrap()
+        .timeouts
+        .connect = Duration::from_millis(200);
+    let app_state = Arc::new(app_state);
+
+    let started = Instant::now();
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=radius").await,
+        LdapResultCode::OperationsError
+    );
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn test_circuit_breaker_fails_fast() {
+    let (_, connector) = common::tls_pair();
+    let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let dead_addr = dead.local_addr().unwrap();
+    drop(dead);
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert("cn=radius".to_string(), DnConfig::default());
+    let mut app_state = common::app_state(dead_addr, connector, binddn_map);
+    app_state.breakers = CircuitBreakers::new(1, Duration::from_secs(60));
+    let app_state = Arc::new(app_state);
+
+    // The first attempt fails to connect, which opens the breaker.
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=radius").await,
+        LdapResultCode::OperationsError
+    );
+    assert!(!app_state.breakers.allow(&dead_addr));
+
+    // Now binds fail without trying to connect.
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=radius").await,
+        LdapResultCode::Unavailable
+    );
+    assert_eq!(
+        client.bind(2, "cn=radius").await,
+        LdapResultCode::Unavailable
+    );
+}
+
+#[tokio::test]
+async fn test_circuit_breaker_counts_handshake_failures() {
+    let (_, connector) = common::tls_pair();
+    // Accepts connections, but closes them before the TLS handshake.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            drop(stream);
+        }
+    });
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert("cn=radius".to_string(), DnConfig::default());
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.breakers = CircuitBreakers::new(1, Duration::from_secs(60));
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=radius").await,
+        LdapResultCode::OperationsError
+    );
+    assert!(!app_state.breakers.allow(&addr));
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=radius").await,
+        LdapResultCode::Unavailable
+    );
+}
+
+#[tokio::test]
+async fn test_backend_health_checks() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(acceptor, |msg| match msg.op {
+        LdapOp::SearchRequest(sr) if sr.base.is_empty() => MockAction::Reply(vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::SearchResultDone(common::success()),
+            ctrl: vec![],
+        }]),
+        _ => MockAction::Disconnect,
+    })
+    .await;
+    let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let dead_addr = dead.local_addr().unwrap();
+    drop(dead);
+
+    // An address that fails its check is out of rotation.
+    let app_state = common::app_state(dead_addr, connector.clone(), BTreeMap::new());
+    assert_eq!(check_backend_health(&app_state).await, 1);
+    assert!(!app_state.breakers.allow(&dead_addr));
+    assert_eq!(app_state.metrics.get("backend_unhealthy_addresses", &[]), 1);
+
+    // And returns to rotation once it passes.
+    let app_state = common::app_state(addr, connector, BTreeMap::new());
+    app_state.breakers.mark_unhealthy(&addr);
+    assert!(!app_state.breakers.allow(&addr));
+    assert_eq!(check_backend_health(&app_state).await, 0);
+    assert!(app_state.breakers.allow(&addr));
+}
+
+#[tokio::test]
+async fn test_backend_resolve() {
+    let url = url::Url::parse("ldaps://localhost:3636").unwrap();
+    let backend = Backend::new(url, "localhost".to_string(), 3636, Vec::new());
+    assert!(!backend.is_resolved());
+
+    backend.resolve().await;
+    assert!(backend.is_resolved());
+    assert!(backend
+        .addrs()
+        .iter()
+        .all(|addr| addr.port() == 3636 && addr.ip().is_loopback()));
+
+    // A failed resolution keeps the addresses we already have.
+    let url = url::Url::parse("ldaps://unresolvable.invalid").unwrap();
+    let addr = "127.0.0.1:636".parse().unwrap();
+    let backend = Backend::new(url, "unresolvable.invalid".to_string(), 636, vec![addr]);
+    backend.resolve().await;
+    assert_eq!(backend.addrs(), vec![addr]);
+}
+
+#[test]
+fn test_bind_rate_limiter() {
+    let limiter = BindRateLimiter::new(0.001, 2);
+    let a: std::net::IpAddr = "192.0.2.1".parse().unwrap();
+    let b: std::net::IpAddr = "192.0.2.2".parse().unwrap();
+
+    assert!(limiter.check(a));
+    assert!(limiter.check(a));
+    assert!(!limiter.check(a));
+    // Each address has its own bucket.
+    assert!(limiter.check(b));
+    assert_eq!(limiter.len(), 2);
+}
+
+#[tokio::test]
+async fn test_bind_rate_limited() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr =
+        common::mock_server(acceptor, common::accept_binds(|_| MockAction::Disconnect)).await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert("cn=radius".to_string(), DnConfig::default());
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.bind_limiter = Some(BindRateLimiter::new(0.001, 1));
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=radius").await, LdapResultCode::Success);
+    for msgid in 2..6 {
+        assert_eq!(client.bind(msgid, "cn=radius").await, LdapResultCode::Busy);
+    }
+    // The client is disconnected once it has been refused enough times.
+    client
+        .send(
+            6,
+            LdapOp::BindRequest(LdapBindRequest {
+                dn: "cn=radius".to_string(),
+                cred: LdapBindCred::Simple("password".to_string()),
+            }),
+        )
+        .await;
+    assert!(client.recv().await.is_none());
+    assert_eq!(app_state.metrics.get("bind_rate_limited_total", &[]), 5);
+}
+
+#[test]
+fn test_bind_failure_tracker() {
+    let a: std::net::IpAddr = "192.0.2.1".parse().unwrap();
+    let b: std::net::IpAddr = "192.0.2.2".parse().unwrap();
+
+    let tracker = BindFailureTracker::new(3, Duration::from_secs(60), None, false);
+    assert_eq!(
+        tracker.record_failure("cn=a", a),
+        ThresholdsCrossed::default()
+    );
+    tracker.record_failure("cn=a", a);
+    assert_eq!(
+        tracker.record_failure("cn=a", a),
+        ThresholdsCrossed {
+            ip: true,
+            dn: true,
+            pair: true
+        }
+    );
+    // Without a lockout the threshold is only reported.
+    assert!(!tracker.is_locked("cn=a", a));
+
+    let tracker = BindFailureTracker::new(
+        2,
+        Duration::from_secs(60),
+        Some(Duration::from_secs(60)),
+        false,
+    );
+    tracker.record_failure("cn=a", a);
+    tracker.record_failure("cn=a", b);
+    assert!(tracker.record_failure("cn=a", a).ip);
+    assert!(tracker.is_locked("cn=a", a));
+    assert!(tracker.is_locked("cn=b", a));
+    // The dn crossed its threshold, but isn't locked for other addresses.
+    assert!(!tracker.is_locked("cn=a", b));
+
+    let tracker = BindFailureTracker::new(
+        2,
+        Duration::from_secs(60),
+        Some(Duration::from_secs(60)),
+        true,
+    );
+    tracker.record_failure("cn=a", a);
+    tracker.record_failure("cn=a", b);
+    assert!(tracker.is_locked("cn=a", "192.0.2.3".parse().unwrap()));
+
+    // Without locking addresses, only the DN from that address is locked.
+    let tracker = BindFailureTracker::new(
+        2,
+        Duration::from_secs(60),
+        Some(Duration::from_secs(60)),
+        false,
+    )
+    .with_lockout_by_ip(false);
+    tracker.record_failure("cn=a", a);
+    let crossed = tracker.record_failure("cn=a", a);
+    assert!(crossed.ip && crossed.pair);
+    assert!(tracker.is_locked("cn=a", a));
+    assert!(!tracker.is_locked("cn=b", a));
+    assert!(!tracker.is_locked("cn=a", b));
+
+    // Failures outside the window don't count towards the threshold.
+    let tracker = BindFailureTracker::new(
+        2,
+        Duration::from_millis(10),
+        Some(Duration::from_secs(60)),
+        false,
+    );
+    tracker.record_failure("cn=a", a);
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(!tracker.record_failure("cn=a", a).ip);
+
+    // Lockouts expire.
+    let tracker = BindFailureTracker::new(
+        1,
+        Duration::from_secs(60),
+        Some(Duration::from_millis(10)),
+        false,
+    );
+    tracker.record_failure("cn=a", a);
+    assert!(tracker.is_locked("cn=a", a));
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(!tracker.is_locked("cn=a", a));
+
+    // A successful bind resets the count.
+    let tracker = BindFailureTracker::new(2, Duration::from_secs(60), None, false);
+    tracker.record_failure("cn=a", a);
+    tracker.record_success("cn=a", a);
+    assert!(tracker.is_empty());
+    assert!(!tracker.record_failure("cn=a", a).ip);
+}
+
+#[tokio::test]
+async fn test_bind_lockout() {
+    let (acceptor, connector) = common::tls_pair();
+    let binds = Arc::new(AtomicUsize::new(0));
+    let backend_binds = binds.clone();
+    let addr = common::mock_server(acceptor, move |msg| match msg.op {
+        LdapOp::BindRequest(lbr) => {
+            backend_binds.fetch_add(1, Ordering::SeqCst);
+            let code = match lbr.cred {
+                LdapBindCred::Simple(pw) if pw == "password" => LdapResultCode::Success,
+                _ => LdapResultCode::InvalidCredentials,
+            };
+            MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res: common::result(code),
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            }])
+        }
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=user".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.bind_failures = BindFailureTracker::new(
+        3,
+        Duration::from_secs(60),
+        Some(Duration::from_secs(60)),
+        false,
+    );
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    let mut bind_code = async |msgid, dn: &str, pw: &str| {
+        let op = LdapOp::BindRequest(LdapBindRequest {
+            dn: dn.to_string(),
+            cred: LdapBindCred::Simple(pw.to_string()),
+        });
+        client.send(msgid, op).await;
+        match client.recv().await {
+            Some(LdapMsg {
+                op: LdapOp::BindResponse(resp),
+                ..
+            }) => resp.res.code,
+            other => panic!("unexpected {:?}", other),
+        }
+    };
+
+    // Unknown dns and bad passwords both count as failures.
+    assert_eq!(
+        bind_code(1, "cn=unknown", "password").await,
+        LdapResultCode::OperationsError
+    );
+    assert_eq!(
+        bind_code(2, "cn=user", "wrong").await,
+        LdapResultCode::InvalidCredentials
+    );
+    assert_eq!(
+        bind_code(3, "cn=user", "wrong").await,
+        LdapResultCode::InvalidCredentials
+    );
+    assert_eq!(binds.load(Ordering::SeqCst), 2);
+
+    // The address is now locked, and the backend isn't asked even if the
+    // password is right.
+    assert_eq!(
+        bind_code(4, "cn=user", "password").await,
+        LdapResultCode::InvalidCredentials
+    );
+    assert_eq!(binds.load(Ordering::SeqCst), 2);
+
+    assert_eq!(app_state.metrics.get("bind_failures_total", &[]), 3);
+    assert_eq!(
+        app_state
+            .metrics
+            .get("bind_lockouts_total", &[("key", "ip")]),
+        1
+    );
+    assert_eq!(app_state.metrics.get("bind_locked_out_total", &[]), 1);
+}
+
+#[test]
+fn test_connection_limits() {
+    let tracker = Arc::new(ConnectionTracker::new(Some(3), Some(2)));
+    let a: std::net::IpAddr = "192.0.2.1".parse().unwrap();
+    let b: std::net::IpAddr = "192.0.2.2".parse().unwrap();
+
+    let a1 = tracker.try_acquire(a).unwrap();
+    let _a2 = tracker.try_acquire(a).unwrap();
+    assert_eq!(tracker.try_acquire(a).unwrap_err(), ConnectionLimit::PerIp);
+
+    let _b1 = tracker.try_acquire(b).unwrap();
+    assert_eq!(tracker.try_acquire(b).unwrap_err(), ConnectionLimit::Total);
+    assert_eq!(tracker.total(), 3);
+
+    // Closing a connection frees its slot.
+    drop(a1);
+    assert_eq!(tracker.count(&a), 1);
+    let _b2 = tracker.try_acquire(b).unwrap();
+    assert_eq!(tracker.total(), 3);
+}
+
+#[tokio::test]
+async fn test_idle_timeout() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr =
+        common::mock_server(acceptor, common::accept_binds(|_| MockAction::Disconnect)).await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert("cn=radius".to_string(), DnConfig::default());
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.idle_timeout = Some(Duration::from_millis(200));
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=radius").await, LdapResultCode::Success);
+
+    // Say nothing, and we should be told that the session is over.
+    let msg = client.recv().await.expect("no notice of disconnection");
+    assert_eq!(msg.msgid, 0);
+    assert!(matches!(
+        msg.op,
+        LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResult {
+                code: LdapResultCode::Unavailable,
+                ..
+            },
+            name: Some(ref oid),
+            ..
+        }) if oid == "1.3.6.1.4.1.1466.20036"
+    ));
+    assert!(client.recv().await.is_none());
+    assert_eq!(app_state.metrics.get("sessions_idle_closed_total", &[]), 1);
+}
+
+#[tokio::test]
+async fn test_max_relayed_entries() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::SearchRequest(_) => {
+                let mut msgs: Vec<_> = (0..3)
+                    .map(|i| LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: format!("uid=user{},o=example", i),
+                            attributes: vec![],
+                        }),
+                        ctrl: vec![],
+                    })
+                    .collect();
+                msgs.push(LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                });
+                MockAction::Reply(msgs)
+            }
+            LdapOp::AbandonRequest(_) => MockAction::Reply(vec![]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert("cn=sssd".to_string(), DnConfig::default());
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.max_relayed_entries = Some(2);
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+
+    client.send(2, search_request()).await;
+    let (entries, code) = recv_search(&mut client).await;
+    assert_eq!(entries, 2);
+    assert_eq!(code, LdapResultCode::SizeLimitExceeded);
+}
+
+#[tokio::test]
+async fn test_oversized_request() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr =
+        common::mock_server(acceptor, common::accept_binds(|_| MockAction::Disconnect)).await;
+    let app_state = Arc::new(common::app_state(addr, connector, BTreeMap::new()));
+    let mut client = common::connect(app_state);
+
+    // The header claims a 16MB message, more than the default limit. We are
+    // disconnected without having to send the rest of it.
+    client
+        .w
+        .get_mut()
+        .write_all(&[0x30, 0x84, 0x01, 0x00, 0x00, 0x00])
+        .await
+        .unwrap();
+    let msg = client.recv().await.expect("no notice of disconnection");
+    assert!(matches!(
+        msg.op,
+        LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResult {
+                code: LdapResultCode::ProtocolError,
+                ..
+            },
+            ..
+        })
+    ));
+    assert!(client.recv().await.is_none());
+}
+
+const MINIMAL_CONFIG: &str = r#"
+bind = "127.0.0.1:3636"
+tls_key = "/tmp/key.pem"
+tls_chain = "/tmp/chain.pem"
+ldap_ca = "/tmp/ca.pem"
+ldap_url = "ldaps://idm.example.com"
+"#;
+
+#[tokio::test]
+async fn test_ldapi_backend() {
+    let url = url::Url::parse("ldapi://%2Fvar%2Frun%2Fslapd%2Fldapi").unwrap();
+    assert_eq!(
+        ldapi_socket_path(&url).as_deref(),
+        Some(std::path::Path::new("/var/run/slapd/ldapi"))
+    );
+    let url = url::Url::parse("ldapi:///var/run/slapd/ldapi").unwrap();
+    assert_eq!(
+        ldapi_socket_path(&url).as_deref(),
+        Some(std::path::Path::new("/var/run/slapd/ldapi"))
+    );
+
+    let path = std::env::temp_dir().join(format!("ldap-proxy-test-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    common::mock_unix_server(
+        &path,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            }]),
+            _ => MockAction::Disconnect,
+        }),
+    );
+
+    let (_, connector) = common::tls_pair();
+    let binddn_map = BTreeMap::from([("cn=radius".to_string(), DnConfig::default())]);
+    let dead: std::net::SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let mut app_state = common::app_state(dead, connector.clone(), binddn_map);
+    let url = url::Url::parse("ldapi:///var/run/slapd/ldapi").unwrap();
+    app_state.backend_pools.insert(
+        DEFAULT_BACKEND.to_string(),
+        BackendPool::new(
+            DEFAULT_BACKEND,
+            connector,
+            vec![Backend::unix(url, path.clone())],
+            BackendStrategy::Ordered,
+        ),
+    );
+    let app_state = Arc::new(app_state);
+    assert!(app_state.is_ready());
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=radius").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    let (entries, code) = recv_search(&mut client).await;
+    assert_eq!(entries, 0);
+    assert_eq!(code, LdapResultCode::Success);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_config_ldapi() {
+    let config = toml::from_str::<Config>(&format!(
+        r#"ldapi_bind = "/run/ldap-proxy/ldapi"
+ldapi_mode = 0o660
+{}"#,
+        MINIMAL_CONFIG
+    ))
+    .unwrap();
+    assert_eq!(
+        config.ldapi_bind.as_deref(),
+        Some(std::path::Path::new("/run/ldap-proxy/ldapi"))
+    );
+    assert_eq!(config.ldapi_mode, Some(0o660));
+}
+
+#[tokio::test]
+async fn test_dn_rewrite() {
+    let config = toml::from_str::<Config>(&format!(
+        r#"{}
+[dn_rewrite]
+suffixes = [
+    {{ client = "dc=corp,dc=example", backend = "o=example" }},
+    {{ client = "ou=legacy,dc=corp,dc=example", backend = "o=legacy" }},
+]
+"#,
+        MINIMAL_CONFIG
+    ))
+    .unwrap();
+    let rewrite = config.dn_rewrite.unwrap();
+    assert_eq!(
+        rewrite.to_backend("uid=A\\,B, ou=People,DC=corp,dc=example"),
+        "uid=A\\,B, ou=People,o=example"
+    );
+    assert_eq!(
+        rewrite.to_backend("uid=a,ou=legacy,dc=corp,dc=example"),
+        "uid=a,o=legacy"
+    );
+    assert_eq!(rewrite.to_backend("dc=corp,dc=example"), "o=example");
+    assert_eq!(rewrite.to_backend("uid=a,o=other"), "uid=a,o=other");
+    assert_eq!(
+        rewrite.to_client("cn=admins,o=example"),
+        "cn=admins,dc=corp,dc=example"
+    );
+
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(acceptor, |msg| {
+        let ok = |op| {
+            MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op,
+                ctrl: vec![],
+            }])
+        };
+        match &msg.op {
+            LdapOp::BindRequest(lbr) if lbr.dn == "uid=demo,ou=people,o=example" => {
+                ok(LdapOp::BindResponse(LdapBindResponse {
+                    res: common::success(),
+                    saslcreds: None,
+                }))
+            }
+            LdapOp::SearchRequest(sr)
+                if sr.base == "ou=people,o=example"
+                    && sr.filter
+                        == LdapFilter::Equality(
+                            "member".to_string(),
+                            "cn=admins,ou=groups,o=example".to_string(),
+                        ) =>
+            {
+                MockAction::Reply(vec![
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: "uid=demo,ou=people,o=example".to_string(),
+                            attributes: vec![LdapPartialAttribute {
+                                atype: "memberOf".to_string(),
+                                vals: vec![b"cn=admins,ou=groups,o=example".to_vec()],
+                            }],
+                        }),
+                        ctrl: vec![],
+                    },
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultDone(common::success()),
+                        ctrl: vec![],
+                    },
+                ])
+            }
+            LdapOp::DelRequest(_) => ok(LdapOp::DelResponse(LdapResult {
+                code: LdapResultCode::NoSuchObject,
+                matcheddn: "ou=people,o=example".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            })),
+            _ => MockAction::Disconnect,
+        }
+    })
+    .await;
+    let dn = "uid=demo,ou=people,dc=corp,dc=example";
+    let binddn_map = BTreeMap::from([(
+        dn.to_string(),
+        DnConfig {
+            allow_write: Some(true),
+            ..Default::default()
+        },
+    )]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.dn_rewrite = Some(Arc::new(rewrite));
+    let mut client = common::connect(Arc::new(app_state));
+    assert_eq!(client.bind(1, dn).await, LdapResultCode::Success);
+
+    let mut sr = search_request();
+    if let LdapOp::SearchRequest(sr) = &mut sr {
+        sr.base = "ou=people,dc=corp,dc=example".to_string();
+        sr.filter = LdapFilter::Equality(
+            "member".to_string(),
+            "cn=admins,ou=groups,dc=corp,dc=example".to_string(),
+        );
+    }
+    client.send(2, sr).await;
+    let msg = client.recv().await.expect("no response");
+    let LdapOp::SearchResultEntry(entry) = msg.op else {
+        panic!("unexpected {:?}", msg.op);
+    };
+    assert_eq!(entry.dn, dn);
+    assert_eq!(
+        entry.attributes[0].vals,
+        [b"cn=admins,ou=groups,dc=corp,dc=example".to_vec()]
+    );
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+
+    client
+        .send(
+            3,
+            LdapOp::DelRequest("uid=gone,ou=people,dc=corp,dc=example".to_string()),
+        )
+        .await;
+    let msg = client.recv().await.expect("no response");
+    let LdapOp::DelResponse(result) = msg.op else {
+        panic!("unexpected {:?}", msg.op);
+    };
+    assert_eq!(result.matcheddn, "ou=people,dc=corp,dc=example");
+}
+
+#[test]
+fn test_attribute_rewrites() {
+    let config = toml::from_str::<Config>(&format!(
+        r#"{}
+["cn=app"]
+attribute_rewrites = [
+    {{ attribute = "sAMAccountName", rename = "uid" }},
+    {{ attribute = "mail", regex = "@corp\\.example\\.com$", replace = "@example.com" }},
+]
+"#,
+        MINIMAL_CONFIG
+    ))
+    .unwrap();
+    let rewrites = &config.binddn_map["cn=app"].attribute_rewrites;
+
+    let mut entry = LdapSearchResultEntry {
+        dn: "cn=alice,o=example".to_string(),
+        attributes: [
+            ("samaccountname", "alice"),
+            ("mail", "alice@corp.example.com"),
+        ]
+        .into_iter()
+        .map(|(atype, val)| LdapPartialAttribute {
+            atype: atype.to_string(),
+            vals: vec![val.as_bytes().to_vec()],
+        })
+        .collect(),
+    };
+    rewrite_entry(rewrites, &mut entry);
+    assert_eq!(entry.attributes[0].atype, "uid");
+    assert_eq!(entry.attributes[0].vals, [b"alice".to_vec()]);
+    assert_eq!(entry.attributes[1].vals, [b"alice@example.com".to_vec()]);
+
+    let LdapOp::SearchRequest(mut sr) = search_request() else {
+        unreachable!();
+    };
+    sr.attrs = vec!["UID".to_string(), "mail".to_string()];
+    sr.filter = parse_ldap_filter_str("(&(objectClass=person)(uid=alice))").unwrap();
+    rewrite_search(rewrites, &mut sr);
+    assert_eq!(sr.attrs, ["sAMAccountName", "mail"]);
+    assert_eq!(
+        sr.filter,
+        parse_ldap_filter_str("(&(objectClass=person)(sAMAccountName=alice))").unwrap()
+    );
+
+    let err = toml::from_str::<Config>(&format!(
+        r#"{}
+["cn=app"]
+attribute_rewrites = [{{ attribute = "mail", regex = "@corp" }}]
+"#,
+        MINIMAL_CONFIG
+    ))
+    .unwrap_err();
+    assert!(err.to_string().contains("needs both regex and replace"));
+}
+
+#[test]
+fn test_backend_strategies() {
+    let (_, connector) = common::tls_pair();
+    let addrs: Vec<std::net::SocketAddr> = (1..=3)
+        .map(|port| std::net::SocketAddr::from(([127, 0, 0, 1], port)))
+        .collect();
+    let backend = |port: u16| {
+        Backend::new(
+            url::Url::parse(&format!("ldaps://localhost:{}", port)).unwrap(),
+            "localhost".to_string(),
+            port,
+            addrs.clone(),
+        )
+    };
+    let pool = |strategy, backends| BackendPool::new("test", connector.clone(), backends, strategy);
+
+    // Connections start from each address of a backend in turn.
+    let round_robin = pool(BackendStrategy::RoundRobin, vec![backend(1)]);
+    let firsts: Vec<_> = (0..3)
+        .map(|_| round_robin.backends[0].addrs_in_order()[0])
+        .collect();
+    assert_eq!(firsts, addrs);
+    let ordered = pool(BackendStrategy::Ordered, vec![backend(1)]);
+    assert_eq!(ordered.backends[0].addrs_in_order(), addrs);
+
+    let least = pool(
+        BackendStrategy::LeastOutstanding,
+        vec![backend(1), backend(2)],
+    );
+    assert_eq!(least.order()[0].port, 1);
+    least.backends[0]
+        .open_connections()
+        .fetch_add(2, Ordering::Relaxed);
+    assert_eq!(least.order()[0].port, 2);
+
+    let weighted = pool(
+        BackendStrategy::Weighted,
+        vec![
+            backend(1).with_weight(0),
+            backend(2),
+            backend(3).with_weight(0),
+        ],
+    );
+    for _ in 0..10 {
+        let order: Vec<_> = weighted.order().iter().map(|b| b.port).collect();
+        assert_eq!(order, [2, 1, 3]);
+    }
+
+    let config = toml::from_str::<Config>(&format!(
+        r#"{}
+backend_strategy = "weighted"
+backend_weights = {{ "ldaps://idm.example.com" = 3 }}
+"#,
+        MINIMAL_CONFIG
+    ))
+    .unwrap();
+    assert_eq!(config.backend_strategy, BackendStrategy::Weighted);
+    assert_eq!(config.backend_weights[&config.ldap_url[0]], 3);
+}
+
+#[test]
+fn test_filter_templates() {
+    let config = toml::from_str::<Config>(&format!(
+        r#"{}
+["cn=app"]
+allowed_filters = ["(&(objectClass=person)(uid={{}}))", "(cn={{}}*)"]
+"#,
+        MINIMAL_CONFIG
+    ))
+    .unwrap();
+    let dnconfig = &config.binddn_map["cn=app"];
+    let permits = |filter: &str| dnconfig.permits_filter(&parse_ldap_filter_str(filter).unwrap());
+
+    assert!(permits("(&(objectclass=Person)(uid=alice))"));
+    assert!(permits("(cn=Al*)"));
+    // A wildcard in place of the value changes the kind of filter.
+    assert!(!permits("(&(objectClass=person)(uid=*))"));
+    assert!(!permits("(&(objectClass=person)(uid=a*))"));
+    assert!(!permits("(&(uid=alice)(objectClass=person))"));
+    assert!(!permits("(&(objectClass=person)(uid=alice)(mail=x))"));
+    assert!(!permits("(cn=*)"));
+    assert!(!permits("(objectClass=*)"));
+    assert!(DnConfig::default().permits_filter(&LdapFilter::Present("objectClass".to_string())));
+
+    let err = toml::from_str::<Config>(&format!(
+        r#"{}
+["cn=app"]
+allowed_filters = ["(uid={{}}"]
+"#,
+        MINIMAL_CONFIG
+    ))
+    .unwrap_err();
+    assert!(err.to_string().contains("invalid filter template"));
+}
+
+#[test]
+fn test_client_networks() {
+    let config = format!(
+        "{}\nallowed_client_networks = [\"10.0.0.0/8\", \"2001:db8::/32\"]\ndenied_client_networks = [\"10.1.0.0/16\"]\n",
+        MINIMAL_CONFIG
+    );
+    let config = toml::from_str::<Config>(&config).unwrap();
+
+    let (_, connector) = common::tls_pair();
+    let addr = "127.0.0.1:636".parse().unwrap();
+    let app_state = common::app_state(addr, connector, BTreeMap::new());
+    app_state.update_policy(|policy| {
+        policy.allowed_client_networks = config.allowed_client_networks;
+        policy.denied_client_networks = config.denied_client_networks;
+    });
+
+    assert!(app_state.client_network_permitted("10.2.3.4".parse().unwrap()));
+    assert!(app_state.client_network_permitted("::ffff:10.2.3.4".parse().unwrap()));
+    assert!(app_state.client_network_permitted("2001:db8::1".parse().unwrap()));
+    assert!(!app_state.client_network_permitted("10.1.2.3".parse().unwrap()));
+    assert!(!app_state.client_network_permitted("192.0.2.1".parse().unwrap()));
+
+    // Invalid networks are found when the config is loaded.
+    let config = format!(
+        "{}\nallowed_client_networks = [\"10.0.0.0/33\"]\n",
+        MINIMAL_CONFIG
+    );
+    assert!(toml::from_str::<Config>(&config).is_err());
+}
+
+#[tokio::test]
+async fn test_dn_allowed_networks() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr =
+        common::mock_server(acceptor, common::accept_binds(|_| MockAction::Disconnect)).await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert(
+        "cn=radius".to_string(),
+        DnConfig {
+            allowed_networks: vec!["192.0.2.0/24".parse().unwrap()],
+            ..Default::default()
+        },
+    );
+    binddn_map.insert(
+        "cn=sssd".to_string(),
+        DnConfig {
+            allowed_networks: vec!["127.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        },
+    );
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    // The test client connects from 127.0.0.1.
+    let mut client = common::connect(app_state);
+    assert_eq!(
+        client.bind(1, "cn=radius").await,
+        LdapResultCode::InsufficentAccessRights
+    );
+    assert_eq!(client.bind(2, "cn=sssd").await, LdapResultCode::Success);
+}
+
+#[tokio::test]
+async fn test_proxy_protocol_header() {
+    // v2, TCP over ipv4, from 192.0.2.1:4242 to 192.0.2.2:636, followed by
+    // the start of the client's traffic.
+    let mut v2 = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+    v2.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+    v2.extend_from_slice(&[192, 0, 2, 1, 192, 0, 2, 2]);
+    v2.extend_from_slice(&4242u16.to_be_bytes());
+    v2.extend_from_slice(&636u16.to_be_bytes());
+    v2.extend_from_slice(b"rest");
+    let mut stream = v2.as_slice();
+    let source = read_proxy_header(&mut stream).await.unwrap();
+    assert_eq!(source, Some("192.0.2.1:4242".parse().unwrap()));
+    assert_eq!(stream, b"rest");
+
+    let mut stream: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4242 636\r\nrest";
+    let source = read_proxy_header(&mut stream).await.unwrap();
+    assert_eq!(source, Some("[2001:db8::1]:4242".parse().unwrap()));
+    assert_eq!(stream, b"rest");
+
+    let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+    assert_eq!(read_proxy_header(&mut stream).await.unwrap(), None);
+
+    // A client speaking ldap straight away.
+    let mut stream: &[u8] = &[0x30, 0x0c, 0x02, 0x01, 0x01, 0x60, 0x07];
+    assert!(read_proxy_header(&mut stream).await.is_err());
+}
+
+#[tokio::test]
+async fn test_proxy_protocol_trusted_networks() {
+    use tokio::io::AsyncReadExt;
+
+    let dir = std::env::temp_dir().join(format!("ldap-proxy-proxy-header-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = common::certificate("localhost", "localhost");
+    std::fs::write(dir.join("chain.pem"), cert.to_pem().unwrap()).unwrap();
+    std::fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    let path = dir.join("config.toml");
+    let config = |trusted: &str| {
+        let contents = format!(
+            r#"bind = "127.0.0.1:0"
+tls_key = "{dir}/key.pem"
+tls_chain = "{dir}/chain.pem"
+ldap_ca = "{dir}/chain.pem"
+ldap_url = "ldaps://127.0.0.1:636"
+shutdown_grace_secs = 1
+expect_proxy_protocol = true
+allowed_client_networks = ["10.0.0.0/8"]
+{trusted}
+"#,
+            dir = dir.display()
+        );
+        load_config(&contents, &path, Vec::new()).unwrap()
+    };
+
+    // The load balancers must be named.
+    assert!(ProxyBuilder::new(config("")).start().await.is_err());
+
+    let server = ProxyBuilder::new(config(
+        r#"proxy_protocol_trusted_networks = ["127.0.0.1/32"]"#,
+    ))
+    .start()
+    .await
+    .unwrap();
+    let header = b"PROXY TCP4 10.0.0.1 127.0.0.1 4242 636\r\n";
+    let connect_from = async |ip: [u8; 4]| {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind((ip, 0).into()).unwrap();
+        let mut stream = socket.connect(server.local_addr()).await.unwrap();
+        stream.write_all(header).await.unwrap();
+        stream
+    };
+
+    // The load balancer's header is believed, so its client may connect.
+    let stream = connect_from([127, 0, 0, 1]).await;
+    let mut connector =
+        openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls_client()).unwrap();
+    connector.set_verify(openssl::ssl::SslVerifyMode::NONE);
+    let ssl = connector
+        .build()
+        .configure()
+        .and_then(|config| config.into_ssl("localhost"))
+        .unwrap();
+    let mut tlsstream = tokio_openssl::SslStream::new(ssl, stream).unwrap();
+    std::pin::Pin::new(&mut tlsstream).connect().await.unwrap();
+
+    // Another peer sending the same header is dropped.
+    let mut stream = connect_from([127, 0, 0, 2]).await;
+    let mut buf = [0; 1];
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)));
+    assert_eq!(
+        server
+            .app_state()
+            .metrics
+            .get("connections_refused_total", &[("limit", "proxy_protocol")]),
+        1
+    );
+
+    drop(tlsstream);
+    tokio::time::timeout(Duration::from_secs(10), server.shutdown())
+        .await
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_client_certificate_lookup() {
+    let (cert, _) = common::certificate("client1", "client1.example.com");
+    let cert = ClientCertificate::from_x509(&cert);
+    assert_eq!(cert.subject, "CN=client1,O=Example");
+    assert_eq!(cert.sans, vec!["dns:client1.example.com".to_string()]);
+    assert!(cert.fingerprint.starts_with("sha256:"));
+    assert_eq!(cert.fingerprint.len(), "sha256:".len() + 64);
+
+    let entry = |bind_dn: &str| CertMapEntry {
+        bind_dn: bind_dn.to_string(),
+        bind_password: Some("password".into()),
+        config: DnConfig::default(),
+    };
+
+    let mut cert_map = CertMap::new();
+    assert!(cert.lookup(&cert_map).is_none());
+    cert_map.insert("dns:client1.example.com".to_string(), entry("cn=san"));
+    assert_eq!(cert.lookup(&cert_map).unwrap().bind_dn, "cn=san");
+    cert_map.insert("CN=client1,O=Example".to_string(), entry("cn=subject"));
+    assert_eq!(cert.lookup(&cert_map).unwrap().bind_dn, "cn=subject");
+    cert_map.insert(cert.fingerprint.clone(), entry("cn=fingerprint"));
+    assert_eq!(cert.lookup(&cert_map).unwrap().bind_dn, "cn=fingerprint");
+}
+
+#[test]
+fn test_config_cert_map() {
+    let config = toml::from_str::<Config>(&format!(
+        r#"{}
+client_ca = "/etc/ldap-proxy/client-ca.pem"
+require_client_cert = true
+unmapped_client_cert = "reject-bind"
+
+[cert_map."CN=client1,O=Example"]
+bind_dn = "cn=svc"
+bind_password = "password"
+allowed_networks = ["192.0.2.0/24"]
+"#,
+        MINIMAL_CONFIG
+    ))
+    .unwrap();
+    assert!(config.require_client_cert);
+    assert_eq!(config.unmapped_client_cert, UnmappedCertPolicy::RejectBind);
+    let entry = config.cert_map.get("CN=client1,O=Example").unwrap();
+    assert_eq!(entry.bind_dn, "cn=svc");
+    assert_eq!(entry.config.allowed_networks.len(), 1);
+    // The cert_map is not a bind dn.
+    assert!(!config.binddn_map.contains_key("cert_map"));
+}
+
+#[test]
+fn test_config_overrides() {
+    let dir = std::env::temp_dir().join(format!("ldap-proxy-secrets-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let secret = dir.join("svc");
+    std::fs::write(&secret, "from-file\n").unwrap();
+    let env_secret = dir.join("env");
+    std::fs::write(&env_secret, "from-env-file\n").unwrap();
+
+    let contents = format!(
+        r#"{}
+[cert_map.host1]
+bind_dn = "cn=svc"
+bind_password_file = "{}"
+
+[cert_map.host2]
+bind_dn = "cn=svc2"
+bind_password = "from-toml"
+"#,
+        MINIMAL_CONFIG,
+        secret.display()
+    );
+    let path = std::path::Path::new("/etc/ldap-proxy/config.toml");
+    let env = |vars: &[(&str, &str)]| {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>()
+    };
+
+    let config = load_config(
+        &contents,
+        path,
+        env(&[
+            ("LDAP_PROXY__TLS_KEY", "/run/secrets/key.pem"),
+            (
+                "LDAP_PROXY__CERT_MAP__HOST2__BIND_PASSWORD_FILE",
+                env_secret.to_str().unwrap(),
+            ),
+            ("UNRELATED", "value"),
+        ]),
+    )
+    .unwrap();
+    assert_eq!(
+        config.tls_key,
+        std::path::PathBuf::from("/run/secrets/key.pem")
+    );
+    let password = |config: &Config, key: &str| {
+        config.cert_map[key]
+            .bind_password
+            .as_ref()
+            .map(|p| p.expose().to_string())
+    };
+    assert_eq!(password(&config, "host1").as_deref(), Some("from-file"));
+    assert_eq!(password(&config, "host2").as_deref(), Some("from-env-file"));
+
+    // Secrets never appear in the Debug output.
+    let debug = format!("{:?}", config);
+    assert!(!debug.contains("from-file"));
+    assert!(!debug.contains("from-env-file"));
+
+    // The environment takes precedence over files.
+    let config = load_config(
+        &contents,
+        path,
+        env(&[("LDAP_PROXY__CERT_MAP__HOST1__BIND_PASSWORD", "from-env")]),
+    )
+    .unwrap();
+    assert_eq!(password(&config, "host1").as_deref(), Some("from-env"));
+
+    // Errors name where the bad value came from.
+    let err = load_config(
+        &contents,
+        path,
+        env(&[("LDAP_PROXY__BIND", "not an address")]),
+    )
+    .unwrap_err();
+    assert_eq!(
+        err.source,
+        ConfigSource::Env("LDAP_PROXY__BIND".to_string())
+    );
+    let missing = dir.join("missing");
+    let err = load_config(
+        &contents,
+        path,
+        env(&[(
+            "LDAP_PROXY__CERT_MAP__HOST1__BIND_PASSWORD_FILE",
+            missing.to_str().unwrap(),
+        )]),
+    )
+    .unwrap_err();
+    assert_eq!(err.source, ConfigSource::SecretFile(missing));
+    let err = load_config("bind = 1", path, env(&[])).unwrap_err();
+    assert_eq!(err.source, ConfigSource::File(path.to_path_buf()));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+async fn sasl_external(client: &mut common::TestClient, msgid: i32) -> LdapResultCode {
+    client
+        .send(
+            msgid,
+            LdapOp::BindRequest(LdapBindRequest {
+                dn: "".to_string(),
+                cred: LdapBindCred::SASL(SaslCredentials {
+                    mechanism: "EXTERNAL".to_string(),
+                    credentials: vec![],
+                }),
+            }),
+        )
+        .await;
+    match client.recv().await {
+        Some(LdapMsg {
+            op: LdapOp::BindResponse(resp),
+            ..
+        }) => resp.res.code,
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_external_bind() {
+    let (acceptor, connector) = common::tls_pair();
+    // The backend only accepts the service account, and cn=user.
+    let addr = common::mock_server(acceptor, |msg: LdapMsg| match msg.op {
+        LdapOp::BindRequest(lbr) => {
+            let code = match (lbr.dn.as_str(), &lbr.cred) {
+                ("cn=svc", LdapBindCred::Simple(pw)) if pw == "svcpass" => LdapResultCode::Success,
+                // The proxy's own certificate, acting as cn=certsvc.
+                ("", LdapBindCred::SASL(creds))
+                    if creds.mechanism == "EXTERNAL" && creds.credentials == b"dn:cn=certsvc" =>
+                {
+                    LdapResultCode::Success
+                }
+                ("cn=user", LdapBindCred::Simple(pw)) if pw == "password" => {
+                    LdapResultCode::Success
+                }
+                _ => LdapResultCode::InvalidCredentials,
+            };
+            MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res: common::result(code),
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            }])
+        }
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let (cert, _) = common::certificate("client1", "client1.example.com");
+    let mapped = ClientCertificate::from_x509(&cert);
+    let (cert, _) = common::certificate("client2", "client2.example.com");
+    let unmapped = ClientCertificate::from_x509(&cert);
+    let (cert, _) = common::certificate("client3", "client3.example.com");
+    let passwordless = ClientCertificate::from_x509(&cert);
+
+    let app_state = |reject_unmapped_cert_binds| {
+        let mut app_state = common::app_state(addr, connector.clone(), BTreeMap::new());
+        app_state.cert_map.insert(
+            "dns:client1.example.com".to_string(),
+            CertMapEntry {
+                bind_dn: "cn=svc".to_string(),
+                bind_password: Some("svcpass".into()),
+                config: DnConfig::default(),
+            },
+        );
+        app_state.cert_map.insert(
+            "CN=client3,O=Example".to_string(),
+            CertMapEntry {
+                bind_dn: "cn=certsvc".to_string(),
+                bind_password: None,
+                config: DnConfig::default(),
+            },
+        );
+        app_state.update_policy(|policy| policy.allow_all_bind_dns = true);
+        app_state.reject_unmapped_cert_binds = reject_unmapped_cert_binds;
+        Arc::new(app_state)
+    };
+
+    let mut client = common::connect_with_cert(app_state(false), Some(mapped));
+    assert_eq!(sasl_external(&mut client, 1).await, LdapResultCode::Success);
+
+    // Entries without a password bind to the backend with SASL EXTERNAL too.
+    let mut client = common::connect_with_cert(app_state(false), Some(passwordless));
+    assert_eq!(sasl_external(&mut client, 1).await, LdapResultCode::Success);
+
+    let mut client = common::connect_with_cert(app_state(false), Some(unmapped.clone()));
+    assert_eq!(
+        sasl_external(&mut client, 1).await,
+        LdapResultCode::InvalidCredentials
+    );
+    // Simple binds are still allowed.
+    assert_eq!(
+        client.bind(2, "cn=other").await,
+        LdapResultCode::InvalidCredentials
+    );
+
+    let mut client = common::connect(app_state(false));
+    assert_eq!(
+        sasl_external(&mut client, 1).await,
+        LdapResultCode::InvalidCredentials
+    );
+
+    // When unmapped certificates are refused at bind, they can't bind at all.
+    let mut client = common::connect_with_cert(app_state(true), Some(unmapped));
+    assert_eq!(
+        client.bind(1, "cn=user").await,
+        LdapResultCode::InvalidCredentials
+    );
+}
+
+#[test]
+fn test_control_policy() {
+    let policy = ControlPolicy::new(None, default_denied_controls());
+    assert!(!policy.permits(OID_PROXIED_AUTHZ));
+    assert!(policy.permits("2.16.840.1.113730.3.4.2"));
+
+    let policy = ControlPolicy::new(None, ["2.16.840.1.113730.3.4.2".to_string()].into());
+    let paged = LdapControl::SimplePagedResults {
+        size: 10,
+        cookie: vec![],
+    };
+    // Denied controls that are not critical are removed.
+    let ctrl = policy
+        .filter_request(vec![
+            LdapControl::ManageDsaIT { criticality: false },
+            paged.clone(),
+        ])
+        .unwrap();
+    assert_eq!(ctrl, vec![paged.clone()]);
+    assert_eq!(
+        policy.filter_request(vec![LdapControl::ManageDsaIT { criticality: true }]),
+        Err("2.16.840.1.113730.3.4.2")
+    );
+
+    // A DN may replace the lists.
+    let allow_paged = ["1.2.840.113556.1.4.319".to_string()].into();
+    let overridden = policy.with_overrides(Some(&allow_paged), Some(&Default::default()));
+    assert!(overridden.permits("1.2.840.113556.1.4.319"));
+    assert!(!overridden.permits("1.2.840.113556.1.4.473"));
+    let inherited = policy.with_overrides(None, None);
+    assert!(!inherited.permits("2.16.840.1.113730.3.4.2"));
+
+    let mut ctrl = vec![LdapControl::ManageDsaIT { criticality: true }];
+    policy.filter_response(&mut ctrl);
+    assert!(ctrl.is_empty());
+}
+
+#[test]
+fn test_unsupported_controls_removed() {
+    // An abandon request for msgid 3, with a proxied authorization control.
+    let message = |critical: bool| {
+        let mut control = vec![0x04, OID_PROXIED_AUTHZ.len() as u8];
+        control.extend_from_slice(OID_PROXIED_AUTHZ.as_bytes());
+        if critical {
+            control.extend_from_slice(&[0x01, 0x01, 0xff]);
+        }
+        let mut controls = vec![0x30, control.len() as u8];
+        controls.extend(control);
+        let mut body = vec![
+            0x02,
+            0x01,
+            0x05,
+            0x50,
+            0x01,
+            0x03,
+            0xa0,
+            controls.len() as u8,
+        ];
+        body.extend(controls);
+        let mut msg = vec![0x30, body.len() as u8];
+        msg.extend(body);
+        BytesMut::from(msg.as_slice())
+    };
+
+    let mut codec = ClientCodec::new(None);
+    let request = codec.decode(&mut message(false)).unwrap().unwrap();
+    assert_eq!(request.msg.msgid, 5);
+    assert_eq!(request.msg.op, LdapOp::AbandonRequest(3));
+    assert!(request.msg.ctrl.is_empty());
+    assert!(request.unsupported_critical_controls.is_empty());
+
+    let request = codec.decode(&mut message(true)).unwrap().unwrap();
+    assert_eq!(request.msg.op, LdapOp::AbandonRequest(3));
+    assert_eq!(
+        request.unsupported_critical_controls,
+        vec![OID_PROXIED_AUTHZ.to_string()]
+    );
+}
+
+#[tokio::test]
+async fn test_control_filtering() {
+    let (acceptor, connector) = common::tls_pair();
+    let relayed = Arc::new(AtomicUsize::new(0));
+    let backend_relayed = relayed.clone();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| {
+            backend_relayed.fetch_add(msg.ctrl.len(), Ordering::SeqCst);
+            MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![LdapControl::PasswordPolicyRequest { criticality: false }],
+            }])
+        }),
+    )
+    .await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert(
+        "cn=reader".to_string(),
+        DnConfig {
+            denied_controls: Some(["1.3.6.1.4.1.42.2.27.8.5.1".to_string()].into()),
+            denied_response_controls: Some(["1.3.6.1.4.1.42.2.27.8.5.1".to_string()].into()),
+            ..Default::default()
+        },
+    );
+    binddn_map.insert("cn=other".to_string(), DnConfig::default());
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=reader").await, LdapResultCode::Success);
+
+    // A denied control that is not critical is removed.
+    client
+        .send_with_controls(
+            2,
+            search_request(),
+            vec![LdapControl::PasswordPolicyRequest { criticality: false }],
+        )
+        .await;
+    let msg = client.recv().await.unwrap();
+    assert!(matches!(
+        msg.op,
+        LdapOp::SearchResultDone(LdapResult {
+            code: LdapResultCode::Success,
+            ..
+        })
+    ));
+    // The response control is removed too.
+    assert!(msg.ctrl.is_empty());
+    assert_eq!(relayed.load(Ordering::SeqCst), 0);
+
+    // A critical one refuses the request.
+    client
+        .send_with_controls(
+            3,
+            search_request(),
+            vec![LdapControl::PasswordPolicyRequest { criticality: true }],
+        )
+        .await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (0, LdapResultCode::UnavailableCriticalExtension)
+    );
+
+    // Other DNs relay both.
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=other").await, LdapResultCode::Success);
+    client
+        .send_with_controls(
+            2,
+            search_request(),
+            vec![LdapControl::PasswordPolicyRequest { criticality: true }],
+        )
+        .await;
+    let msg = client.recv().await.unwrap();
+    assert_eq!(msg.ctrl.len(), 1);
+    assert_eq!(relayed.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_search_limits() {
+    let (acceptor, connector) = common::tls_pair();
+    let searches = Arc::new(AtomicUsize::new(0));
+    let backend_searches = searches.clone();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            // Searches under ou=slow never complete.
+            LdapOp::SearchRequest(sr) if sr.base == "ou=slow,o=example" => {
+                MockAction::Reply(vec![])
+            }
+            LdapOp::SearchRequest(sr) => {
+                backend_searches.fetch_add(1, Ordering::SeqCst);
+                // The limits that the backend was asked for.
+                assert_eq!((sr.sizelimit, sr.timelimit), (2, 30));
+                let mut msgs: Vec<_> = (0..3)
+                    .map(|i| LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: format!("uid=user{},o=example", i),
+                            attributes: vec![],
+                        }),
+                        ctrl: vec![],
+                    })
+                    .collect();
+                msgs.push(LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                });
+                MockAction::Reply(msgs)
+            }
+            LdapOp::AbandonRequest(_) => MockAction::Reply(vec![]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert(
+        "cn=sssd".to_string(),
+        DnConfig {
+            size_limit: Some(2),
+            time_limit_secs: Some(30),
+            ..Default::default()
+        },
+    );
+    binddn_map.insert(
+        "cn=slow".to_string(),
+        DnConfig {
+            time_limit_secs: Some(1),
+            ..Default::default()
+        },
+    );
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+
+    // The client asks for no limit, or a larger one, and gets the cap.
+    client.send(2, search_request()).await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (2, LdapResultCode::SizeLimitExceeded)
+    );
+    let LdapOp::SearchRequest(mut sr) = search_request() else {
+        unreachable!()
+    };
+    sr.sizelimit = 100;
+    sr.timelimit = 60;
+    client.send(3, LdapOp::SearchRequest(sr)).await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (2, LdapResultCode::SizeLimitExceeded)
+    );
+
+    // Truncated results are not cached.
+    client.send(4, search_request()).await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (2, LdapResultCode::SizeLimitExceeded)
+    );
+    assert_eq!(searches.load(Ordering::SeqCst), 3);
+
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=slow").await, LdapResultCode::Success);
+    let LdapOp::SearchRequest(mut sr) = search_request() else {
+        unreachable!()
+    };
+    sr.base = "ou=slow,o=example".to_string();
+    client.send(2, LdapOp::SearchRequest(sr)).await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (0, LdapResultCode::TimeLimitExceeded)
+    );
+}
+
+#[test]
+fn test_normalize_dn() {
+    let dn = "cn=svc-app,ou=services,dc=example,dc=com";
+    assert_eq!(normalize_dn(dn).unwrap(), dn);
+    assert_eq!(
+        normalize_dn("CN=svc-app,OU=Services,DC=example,DC=com").unwrap(),
+        dn
+    );
+    assert_eq!(
+        normalize_dn(" cn = svc-app , ou=Services,  dc=example ,dc=com ").unwrap(),
+        dn
+    );
+    assert_eq!(normalize_dn("").unwrap(), "");
+    assert_eq!(normalize_dn("   ").unwrap(), "");
+
+    // Insignificant spaces within a value are collapsed.
+    assert_eq!(
+        normalize_dn("cn=John   Smith,o=Example").unwrap(),
+        "cn=john smith,o=example"
+    );
+    // Escaped separators are part of the value, and are escaped the same way
+    // however they were written.
+    assert_eq!(
+        normalize_dn("cn=Smith\\, John,o=Example").unwrap(),
+        "cn=smith\\, john,o=example"
+    );
+    assert_eq!(
+        normalize_dn("cn=Smith\\2C John,o=Example").unwrap(),
+        "cn=smith\\, john,o=example"
+    );
+    assert_eq!(normalize_dn("cn=\\C3\\A9cole").unwrap(), "cn=école");
+    assert_eq!(normalize_dn("cn=\\#1").unwrap(), "cn=\\#1");
+    assert_eq!(normalize_dn("cn=a\\+b").unwrap(), "cn=a\\+b");
+    // Multi-valued rdns are sorted.
+    assert_eq!(
+        normalize_dn("UID=jsmith+CN=John,o=example").unwrap(),
+        "cn=john+uid=jsmith,o=example"
+    );
+    assert_eq!(normalize_dn("OID.2.5.4.3=Foo").unwrap(), "2.5.4.3=foo");
+    assert_eq!(normalize_dn("cn=#04024869").unwrap(), "cn=#04024869");
+
+    assert_eq!(normalize_dn("cn"), Err(DnError::MissingEquals));
+    assert_eq!(normalize_dn("=foo"), Err(DnError::EmptyAttributeType));
+    assert_eq!(normalize_dn("cn=foo\\"), Err(DnError::InvalidEscape));
+    assert_eq!(normalize_dn("cn=foo\\4"), Err(DnError::InvalidEscape));
+    assert_eq!(normalize_dn("cn=\\ff"), Err(DnError::InvalidUtf8));
+}
+
+#[test]
+fn test_config_normalizes_bind_dns() {
+    let config = toml::from_str::<Config>(&format!(
+        r#"{}
+["CN=Administrator, DC=Example"]
+allow_compare = true
+"#,
+        MINIMAL_CONFIG
+    ))
+    .unwrap();
+    assert!(config.binddn_map["cn=administrator,dc=example"].allow_compare);
+
+    assert!(toml::from_str::<Config>(&format!(
+        r#"{}
+["cn=Administrator,dc=example"]
+["CN=administrator, DC=Example"]
+"#,
+        MINIMAL_CONFIG
+    ))
+    .is_err());
+}
+
+#[tokio::test]
+async fn test_bind_dn_normalized() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr =
+        common::mock_server(acceptor, common::accept_binds(|_| MockAction::Disconnect)).await;
+
+    let mut binddn_map = BTreeMap::new();
+    binddn_map.insert(
+        "cn=svc-app,ou=services,dc=example,dc=com".to_string(),
+        DnConfig::default(),
+    );
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(
+        client
+            .bind(1, "CN=svc-app, OU=Services,DC=example,DC=com")
+            .await,
+        LdapResultCode::Success
+    );
+    assert_eq!(
+        client.bind(2, "cn=svc-app").await,
+        LdapResultCode::OperationsError
+    );
+    assert_eq!(
+        client.bind(3, "not a dn").await,
+        LdapResultCode::InvalidDNSyntax
+    );
+}
+
+#[tokio::test]
+async fn test_cache_persistence() {
+    let (acceptor, connector) = common::tls_pair();
+    let searches = Arc::new(AtomicUsize::new(0));
+    let backend_searches = searches.clone();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => {
+                backend_searches.fetch_add(1, Ordering::SeqCst);
+                MockAction::Reply(vec![
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: "uid=user,o=example".to_string(),
+                            attributes: vec![],
+                        }),
+                        ctrl: vec![],
+                    },
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultDone(common::success()),
+                        ctrl: vec![],
+                    },
+                ])
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = || BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+    let path = std::env::temp_dir().join(format!("ldap-proxy-cache-{}.json", std::process::id()));
+
+    let app_state = Arc::new(common::app_state(addr, connector.clone(), binddn_map()));
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    assert_eq!(searches.load(Ordering::SeqCst), 1);
+
+    assert_eq!(save_cache(&app_state, &path).unwrap(), 1);
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // After a restart the search is answered from the loaded cache.
+    let app_state = Arc::new(common::app_state(addr, connector.clone(), binddn_map()));
+    assert_eq!(load_cache(&app_state, &path), 1);
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    assert_eq!(searches.load(Ordering::SeqCst), 1);
+
+    // Entries of DNs that are no longer configured are discarded.
+    let app_state = common::app_state(addr, connector.clone(), BTreeMap::new());
+    assert_eq!(load_cache(&app_state, &path), 0);
+
+    // As are corrupt files, and files of other versions.
+    let app_state = common::app_state(addr, connector, binddn_map());
+    std::fs::write(&path, b"{\"version\": 1, \"entr").unwrap();
+    assert_eq!(load_cache(&app_state, &path), 0);
+    std::fs::write(&path, b"{\"version\": 999, \"entries\": []}").unwrap();
+    assert_eq!(load_cache(&app_state, &path), 0);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(load_cache(&app_state, &path), 0);
+}
+
+#[tokio::test]
+async fn test_cache_sweep() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            }]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+    let app_state = common::app_state(addr, connector, binddn_map);
+    app_state.update_policy(|policy| policy.cache_entry_timeout = Duration::from_millis(100));
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+
+    // Nothing has expired yet.
+    assert_eq!(sweep_expired_cache(&app_state).await, (0, 0));
+    assert_eq!(app_state.cache.write().iter().count(), 1);
+    assert_eq!(app_state.metrics.get("cache_entries", &[]), 1);
+    assert!(app_state.metrics.get("cache_bytes", &[]) > 0);
+
+    // The expired entry is reclaimed without it being looked up again.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let (entries, bytes) = sweep_expired_cache(&app_state).await;
+    assert_eq!(entries, 1);
+    assert!(bytes > 0);
+    assert_eq!(app_state.cache.write().iter().count(), 0);
+    assert_eq!(app_state.metrics.get("cache_swept_entries_total", &[]), 1);
+    assert_eq!(
+        app_state.metrics.get("cache_swept_bytes_total", &[]),
+        bytes as u64
+    );
+    assert_eq!(app_state.metrics.get("cache_entries", &[]), 0);
+    assert_eq!(app_state.metrics.get("cache_bytes", &[]), 0);
+}
+
+#[test]
+fn test_listen_fds() {
+    let fds = systemd::parse_listen_fds(Some("42"), Some("2"), Some("ldaps:ldapi"), 42);
+    assert_eq!(
+        fds,
+        vec![
+            ListenFd {
+                name: "ldaps".to_string(),
+                fd: 3
+            },
+            ListenFd {
+                name: "ldapi".to_string(),
+                fd: 4
+            },
+        ]
+    );
+    assert_eq!(
+        systemd::select_listen_fd(&fds, "ldapi").map(|fd| fd.fd),
+        Some(4)
+    );
+    assert_eq!(systemd::select_listen_fd(&fds, "other"), None);
+
+    // A single socket is used whatever it is named.
+    let fds = systemd::parse_listen_fds(Some("42"), Some("1"), None, 42);
+    assert_eq!(
+        systemd::select_listen_fd(&fds, "ldaps").map(|fd| fd.fd),
+        Some(3)
+    );
+
+    // Sockets passed to another process aren't ours.
+    assert!(systemd::parse_listen_fds(Some("41"), Some("1"), None, 42).is_empty());
+    assert!(systemd::parse_listen_fds(None, None, None, 42).is_empty());
+}
+
+#[tokio::test]
+async fn test_socket_activated_listener() {
+    use std::os::fd::IntoRawFd;
+
+    let std_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = std_listener.local_addr().unwrap();
+    let fd = ListenFd {
+        name: "ldaps".to_string(),
+        fd: std_listener.into_raw_fd(),
+    };
+    let listener = unsafe { systemd::tcp_listener(&fd) }.unwrap();
+    assert_eq!(listener.local_addr().unwrap(), addr);
+
+    let (_client, accepted) = tokio::join!(tokio::net::TcpStream::connect(addr), listener.accept());
+    assert!(accepted.is_ok());
+}
+
+#[test]
+fn test_sd_notify() {
+    let path = std::env::temp_dir().join(format!("ldap-proxy-notify-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+
+    systemd::notify_to(path.to_str().unwrap(), "READY=1").unwrap();
+    let mut buf = [0; 64];
+    let len = socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"READY=1");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_ldap_url() {
+    let url =
+        LdapUrl::parse("ldaps://dc2.example.com:3269/ou=sub,%20o=example??one?(uid=*)").unwrap();
+    assert_eq!(url.host, "dc2.example.com");
+    assert_eq!(url.port, 3269);
+    assert_eq!(url.base.as_deref(), Some("ou=sub, o=example"));
+    assert_eq!(url.scope, Some(LdapSearchScope::OneLevel));
+    assert_eq!(url.filter, Some(LdapFilter::Present("uid".to_string())));
+
+    assert!(url.tls);
+
+    let url = LdapUrl::parse("ldap://dc3.example.com/").unwrap();
+    assert!(!url.tls);
+    assert_eq!((url.host.as_str(), url.port), ("dc3.example.com", 389));
+    assert_eq!((url.base, url.scope, url.filter), (None, None, None));
+
+    assert!(LdapUrl::parse("http://example.com/").is_none());
+    assert!(LdapUrl::parse("ldap://dc.example.com/o=example??nope").is_none());
+
+    assert_eq!(
+        rewrite_url(
+            "ldap://dc2.example.com:389/ou=sub,o=example??sub",
+            "proxy:636"
+        ),
+        "ldaps://proxy:636/ou=sub,o=example??sub"
+    );
+
+    // Mapped servers are matched by host:port, then by host.
+    let map = BTreeMap::from([
+        (
+            "dc2.example.com:3269".to_string(),
+            "gc-proxy:636".to_string(),
+        ),
+        ("dc2.example.com".to_string(), "dc2-proxy:636".to_string()),
+    ]);
+    let target = |uri| rewrite_target(uri, &map, "proxy:636");
+    assert_eq!(target("ldap://DC2.example.com:3269/"), "gc-proxy:636");
+    assert_eq!(target("ldap://dc2.example.com:389/"), "dc2-proxy:636");
+    assert_eq!(target("ldap://dc2.example.com/"), "dc2-proxy:636");
+    assert_eq!(target("ldap://dc3.example.com/"), "proxy:636");
+}
+
+fn search_entry(msgid: i32, dn: &str) -> LdapMsg {
+    LdapMsg {
+        msgid,
+        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+            dn: dn.to_string(),
+            attributes: vec![],
+        }),
+        ctrl: vec![],
+    }
+}
+
+// Read a search result stream, returning the dns of the entries and the urls of
+// the references.
+async fn recv_search_refs(client: &mut common::TestClient) -> (Vec<String>, Vec<String>) {
+    let mut entries = Vec::new();
+    let mut references = Vec::new();
+    loop {
+        match client.recv().await.expect("no response").op {
+            LdapOp::SearchResultEntry(entry) => entries.push(entry.dn),
+            LdapOp::SearchResultReference(reference) => references.extend(reference.uris),
+            LdapOp::SearchResultDone(res) => {
+                assert_eq!(res.code, LdapResultCode::Success);
+                break (entries, references);
+            }
+            op => panic!("unexpected {:?}", op),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_root_dse() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(acceptor, |msg| match msg.op {
+        LdapOp::SearchRequest(sr) if sr.base.is_empty() => {
+            let attr = |atype: &str, vals: &[&str]| LdapPartialAttribute {
+                atype: atype.to_string(),
+                vals: vals.iter().map(|v| v.as_bytes().to_vec()).collect(),
+            };
+            MockAction::Reply(vec![
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: "".to_string(),
+                        attributes: vec![
+                            attr("namingContexts", &["o=example"]),
+                            attr("vendorName", &["Mock"]),
+                            attr(
+                                "supportedControl",
+                                &["1.2.840.113556.1.4.319", "2.16.840.1.113730.3.4.2"],
+                            ),
+                        ],
+                    }),
+                    ctrl: vec![],
+                },
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                },
+            ])
+        }
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let mut app_state = common::app_state(addr, connector, BTreeMap::new());
+    let learned = read_root_dse(&app_state).await.expect("root dse");
+    // The backend's naming contexts are replaced, and paging is not permitted.
+    let config = RootDseConfig {
+        from_backend: true,
+        naming_contexts: vec!["o=proxied".to_string()],
+        vendor_name: None,
+    };
+    let controls = ControlPolicy::new(None, ["1.2.840.113556.1.4.319".to_string()].into());
+    app_state.root_dse = Some(RootDse::new(&config, Some(&learned), &controls));
+    let app_state = Arc::new(app_state);
+
+    // Answered before a bind.
+    let mut client = common::connect(app_state.clone());
+    let mut root_search = search_request();
+    if let LdapOp::SearchRequest(sr) = &mut root_search {
+        sr.base = "".to_string();
+        sr.scope = LdapSearchScope::Base;
+    }
+    client.send(1, root_search.clone()).await;
+    let entry = match client.recv().await.expect("no response").op {
+        LdapOp::SearchResultEntry(entry) => entry,
+        op => panic!("unexpected {:?}", op),
+    };
+    let values = |atype: &str| -> Vec<String> {
+        entry
+            .attributes
+            .iter()
+            .filter(|attr| attr.atype == atype)
+            .flat_map(|attr| attr.vals.iter())
+            .map(|v| String::from_utf8_lossy(v).to_string())
+            .collect()
+    };
+    assert_eq!(values("namingContexts"), vec!["o=proxied"]);
+    assert_eq!(values("supportedLDAPVersion"), vec!["3"]);
+    // The sort and virtual list view controls are handled by the proxy.
+    assert_eq!(
+        values("supportedControl"),
+        vec![
+            "2.16.840.1.113730.3.4.2",
+            "1.2.840.113556.1.4.473",
+            "2.16.840.1.113730.3.4.9"
+        ]
+    );
+    assert_eq!(values("vendorName"), vec!["Mock"]);
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+
+    // Only the attributes that are asked for are returned.
+    if let LdapOp::SearchRequest(sr) = &mut root_search {
+        sr.attrs = vec!["namingcontexts".to_string()];
+    }
+    client.send(2, root_search).await;
+    match client.recv().await.expect("no response").op {
+        LdapOp::SearchResultEntry(entry) => {
+            assert_eq!(entry.attributes.len(), 1);
+            assert_eq!(entry.attributes[0].atype, "namingContexts");
+        }
+        op => panic!("unexpected {:?}", op),
+    }
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+}
+
+#[tokio::test]
+async fn test_referrals() {
+    let (acceptor, connector) = common::tls_pair();
+    let referred_searches = Arc::new(AtomicUsize::new(0));
+    let backend_searches = referred_searches.clone();
+    let referred = common::mock_server(
+        acceptor.clone(),
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(sr) => {
+                assert_eq!(sr.base, "ou=sub,o=example");
+                backend_searches.fetch_add(1, Ordering::SeqCst);
+                MockAction::Reply(vec![
+                    search_entry(msg.msgid, "uid=b,ou=sub,o=example"),
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultDone(common::success()),
+                        ctrl: vec![],
+                    },
+                ])
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    // The main backend refers to the other server, and to itself.
+    let own_port = Arc::new(AtomicUsize::new(0));
+    let backend_port = own_port.clone();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![
+                search_entry(msg.msgid, "uid=a,o=example"),
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultReference(LdapSearchResultReference {
+                        uris: vec![format!(
+                            "ldaps://localhost:{}/ou=sub,o=example??sub",
+                            referred.port()
+                        )],
+                    }),
+                    ctrl: vec![],
+                },
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultReference(LdapSearchResultReference {
+                        uris: vec![format!(
+                            "ldaps://localhost:{}/o=example",
+                            backend_port.load(Ordering::SeqCst)
+                        )],
+                    }),
+                    ctrl: vec![],
+                },
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                },
+            ]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+    own_port.store(addr.port() as usize, Ordering::SeqCst);
+    let referred_url = format!(
+        "ldaps://localhost:{}/ou=sub,o=example??sub",
+        referred.port()
+    );
+    let loop_url = format!("ldaps://localhost:{}/o=example", addr.port());
+
+    let session = |mode| {
+        let binddn_map = BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+        let mut app_state = common::app_state(addr, connector.clone(), binddn_map);
+        app_state.referral_mode = mode;
+        app_state.referral_chase_hosts = vec!["localhost".to_string()];
+        app_state.referral_rewrite_map = BTreeMap::from([(
+            format!("LOCALHOST:{}", referred.port()),
+            "proxy-sub.example.com:636".to_string(),
+        )]);
+        let app_state = Arc::new(app_state);
+        (common::connect(app_state.clone()), app_state)
+    };
+
+    let (mut client, _) = session(ReferralMode::Passthrough);
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    let (entries, references) = recv_search_refs(&mut client).await;
+    assert_eq!(entries, vec!["uid=a,o=example"]);
+    assert_eq!(references, vec![referred_url.clone(), loop_url.clone()]);
+
+    let (mut client, _) = session(ReferralMode::Rewrite);
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    let (_, references) = recv_search_refs(&mut client).await;
+    assert_eq!(
+        references,
+        vec![
+            "ldaps://proxy-sub.example.com:636/ou=sub,o=example??sub",
+            "ldaps://proxy.example.com:636/o=example",
+        ]
+    );
+
+    let (mut client, _) = session(ReferralMode::Strip);
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search_refs(&mut client).await.1, Vec::<String>::new());
+
+    // The other server's entries are merged in, and the reference to the
+    // backend itself is a loop that is left alone.
+    let (mut client, app_state) = session(ReferralMode::Chase);
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    let (entries, references) = recv_search_refs(&mut client).await;
+    assert_eq!(entries, vec!["uid=a,o=example", "uid=b,ou=sub,o=example"]);
+    assert_eq!(references, vec![loop_url]);
+    assert_eq!(referred_searches.load(Ordering::SeqCst), 1);
+    assert_eq!(app_state.metrics.get("referrals_chased_total", &[]), 1);
+
+    // The chased results are cached under the original search.
+    client.send(3, search_request()).await;
+    assert_eq!(recv_search_refs(&mut client).await.0.len(), 2);
+    assert_eq!(referred_searches.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_binddn_patterns() {
+    let config = toml::from_str::<Config>(&format!(
+        r#"{}
+[[binddn_patterns]]
+glob = "CN=svc-*, ou=proxy,dc=example,dc=com"
+allowed_queries = [
+    ["ou=${{1}},dc=example,dc=com", "subtree", "(objectclass=*)"],
+]
+allowed_bases = ["ou=$1,dc=example,dc=com"]
+
+[[binddn_patterns]]
+regex = "cn=([a-z]+)-([a-z]+),ou=proxy,dc=example,dc=com"
+allow_compare = true
+allowed_queries = [
+    ["ou=$2,ou=$1,dc=example,dc=com", "subtree", "(objectclass=*)"],
+]
+
+["cn=svc-admin,ou=proxy,dc=example,dc=com"]
+allow_write = true
+"#,
+        MINIMAL_CONFIG
+    ))
+    .unwrap();
+    assert_eq!(config.binddn_patterns.len(), 2);
+
+    let bases = |dnconfig: &DnConfig| {
+        dnconfig
+            .allowed_queries
+            .iter()
+            .map(|(base, _, _)| base.clone())
+            .collect::<Vec<_>>()
+    };
+
+    // Each tenant gets its own subtree, and the first matching pattern wins.
+    let tenant = config
+        .binddn_patterns
+        .lookup("cn=svc-tenant1,ou=proxy,dc=example,dc=com")
+        .unwrap();
+    assert_eq!(bases(&tenant), vec!["ou=tenant1,dc=example,dc=com"]);
+    assert_eq!(tenant.allowed_bases, vec!["ou=tenant1,dc=example,dc=com"]);
+    assert!(!tenant.allow_compare);
+
+    let other = config
+        .binddn_patterns
+        .lookup("cn=app-reports,ou=proxy,dc=example,dc=com")
+        .unwrap();
+    assert_eq!(bases(&other), vec!["ou=reports,ou=app,dc=example,dc=com"]);
+    assert!(other.allow_compare);
+
+    // Patterns are anchored.
+    assert!(config
+        .binddn_patterns
+        .lookup("cn=svc-tenant1,ou=proxy,dc=example,dc=com,o=other")
+        .is_none());
+    assert!(config
+        .binddn_patterns
+        .lookup("cn=svc-a,ou=b,ou=proxy,dc=example,dc=com")
+        .is_none());
+
+    // Invalid patterns fail when the config is loaded.
+    for pattern in [
+        r#"regex = "cn=(unclosed""#,
+        r#"glob = "cn=a,b""#,
+        r#"glob = "cn=*""
+regex = "cn=.*""#,
+        r#"glob = "cn=svc-*"
+allowed_queries = [["ou=$2", "subtree", "(objectclass=*)"]]"#,
+    ] {
+        let config = format!("{}\n[[binddn_patterns]]\n{}\n", MINIMAL_CONFIG, pattern);
+        assert!(toml::from_str::<Config>(&config).is_err(), "{}", pattern);
+    }
+}
+
+#[tokio::test]
+async fn test_binddn_pattern_bind() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            }]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let config = toml::from_str::<Config>(&format!(
+        r#"{}
+[[binddn_patterns]]
+glob = "cn=svc-*,o=example"
+allowed_queries = [["ou=$1,o=example", "subtree", "(objectClass=*)"]]
+
+["cn=svc-exact,o=example"]
+"#,
+        MINIMAL_CONFIG
+    ))
+    .unwrap();
+    let app_state = common::app_state(addr, connector, config.binddn_map);
+    app_state.update_policy(|policy| policy.binddn_patterns = config.binddn_patterns);
+    let app_state = Arc::new(app_state);
+
+    let search = |base: &str| {
+        LdapOp::SearchRequest(LdapSearchRequest {
+            base: base.to_string(),
+            scope: LdapSearchScope::Subtree,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: vec![],
+        })
+    };
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(
+        client.bind(1, "CN=svc-Tenant1,o=example").await,
+        LdapResultCode::Success
+    );
+    client.send(2, search("ou=tenant1,o=example")).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    // Other tenants' subtrees are refused, which disconnects the client.
+    client.send(3, search("ou=tenant2,o=example")).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    assert!(client.recv().await.is_none());
+
+    // The exact entry takes precedence, and allows any query.
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=svc-exact,o=example").await,
+        LdapResultCode::Success
+    );
+    client.send(2, search("ou=tenant2,o=example")).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+
+    let mut client = common::connect(app_state);
+    assert_eq!(
+        client.bind(1, "cn=other,o=example").await,
+        LdapResultCode::OperationsError
+    );
+}
+
+#[tokio::test]
+async fn test_upstream_pool() {
+    let (acceptor, connector) = common::tls_pair();
+    let binds = Arc::new(AtomicUsize::new(0));
+    let backend_binds = binds.clone();
+    let addr = common::mock_server(acceptor, move |msg| match msg.op {
+        LdapOp::BindRequest(_) => {
+            backend_binds.fetch_add(1, Ordering::SeqCst);
+            MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res: common::success(),
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            }])
+        }
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let binddn_map = BTreeMap::from([
+        ("cn=user".to_string(), DnConfig::default()),
+        ("cn=other".to_string(), DnConfig::default()),
+    ]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.upstream_pool =
+        UpstreamPool::new(1, Duration::from_secs(60), Duration::from_secs(600));
+    let app_state = Arc::new(app_state);
+
+    let wait_for_idle = async |count: usize| {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while app_state.upstream_pool.len() != count {
+            assert!(Instant::now() < deadline, "pool never had {} idle", count);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+
+    // The first session's connection is kept when it unbinds.
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+    client.send(2, LdapOp::UnbindRequest).await;
+    wait_for_idle(1).await;
+    assert_eq!(app_state.metrics.get("upstream_pool_hits_total", &[]), 0);
+
+    // Another dn doesn't get it.
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=other").await, LdapResultCode::Success);
+    assert_eq!(app_state.metrics.get("upstream_pool_hits_total", &[]), 0);
+    assert_eq!(app_state.upstream_pool.len(), 1);
+    drop(client);
+    wait_for_idle(2).await;
+
+    // The same dn reuses it, but still binds with its own credentials.
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+    assert_eq!(app_state.metrics.get("upstream_pool_hits_total", &[]), 1);
+    assert_eq!(app_state.upstream_pool.len(), 1);
+    assert_eq!(binds.load(Ordering::SeqCst), 3);
+    client.send(2, LdapOp::UnbindRequest).await;
+    wait_for_idle(2).await;
+}
+
+#[tokio::test]
+async fn test_plaintext_backend() {
+    let (_, connector) = common::tls_pair();
+    let addr = common::mock_plain_server(common::accept_binds(|msg| match msg.op {
+        LdapOp::SearchRequest(_) => MockAction::Reply(vec![
+            LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=user,o=example".to_string(),
+                    attributes: vec![],
+                }),
+                ctrl: vec![],
+            },
+            LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            },
+        ]),
+        _ => MockAction::Disconnect,
+    }))
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=user".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(addr, connector.clone(), binddn_map);
+    let url = url::Url::parse(&format!("ldap://localhost:{}", addr.port())).unwrap();
+    let backend = Backend::new(url, "localhost".to_string(), addr.port(), vec![addr]);
+    assert_eq!(backend.transport, Transport::Plain);
+    assert_eq!(common::backend(addr).transport, Transport::Tls);
+    app_state.backend_pools.insert(
+        DEFAULT_BACKEND.to_string(),
+        BackendPool::new(
+            DEFAULT_BACKEND,
+            connector,
+            vec![backend],
+            BackendStrategy::Ordered,
+        ),
+    );
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+}
+
+#[tokio::test]
+async fn test_starttls_backend() {
+    let (acceptor, connector) = common::tls_pair();
+    let handler = |msg: LdapMsg| match msg.op {
+        LdapOp::SearchRequest(_) => MockAction::Reply(vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::SearchResultDone(common::success()),
+            ctrl: vec![],
+        }]),
+        _ => MockAction::Disconnect,
+    };
+    let addr = common::mock_starttls_server(acceptor, common::accept_binds(handler)).await;
+    // A backend that doesn't support StartTLS.
+    let refusing = common::mock_plain_server(|msg| match msg.op {
+        LdapOp::ExtendedRequest(_) => MockAction::Reply(vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: common::result(LdapResultCode::ProtocolError),
+                name: None,
+                value: None,
+            }),
+            ctrl: vec![],
+        }]),
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let starttls_pool = |name: &str, addr: std::net::SocketAddr, connector| {
+        let url = url::Url::parse(&format!("ldap://localhost:{}", addr.port())).unwrap();
+        let backend = Backend::new(url, "localhost".to_string(), addr.port(), vec![addr]);
+        let backend = backend.with_starttls();
+        assert_eq!(backend.transport, Transport::StartTls);
+        BackendPool::new(name, connector, vec![backend], BackendStrategy::Ordered)
+    };
+
+    let binddn_map = BTreeMap::from([
+        ("cn=user".to_string(), DnConfig::default()),
+        (
+            "cn=refused".to_string(),
+            DnConfig {
+                backend: Some("refusing".to_string()),
+                ..Default::default()
+            },
+        ),
+    ]);
+    let mut app_state = common::app_state(addr, connector.clone(), binddn_map);
+    app_state.backend_pools.insert(
+        DEFAULT_BACKEND.to_string(),
+        starttls_pool(DEFAULT_BACKEND, addr, connector.clone()),
+    );
+    app_state.backend_pools.insert(
+        "refusing".to_string(),
+        starttls_pool("refusing", refusing, connector),
+    );
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+
+    // The proxy never binds over a connection that tls was refused on.
+    let mut client = common::connect(app_state);
+    assert_ne!(client.bind(1, "cn=refused").await, LdapResultCode::Success);
+}
+
+#[tokio::test]
+async fn test_client_starttls() {
+    use futures_util::{SinkExt, StreamExt};
+    use ldap3_proto::LdapCodec;
+    use tokio_util::codec::{Framed, FramedRead, FramedWrite};
+
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor.clone(),
+        common::accept_binds(|_| MockAction::Disconnect),
+    )
+    .await;
+    let binddn_map = BTreeMap::from([("cn=user".to_string(), DnConfig::default())]);
+    let app_state = Arc::new(common::app_state(addr, connector.clone(), binddn_map));
+
+    let (client, server) = tokio::io::duplex(65536);
+    let server_state = app_state.clone();
+    tokio::spawn(async move {
+        let client_address = "127.0.0.1:12345".parse().unwrap();
+        let Some(stream) = client_starttls(server, client_address, &server_state).await else {
+            return;
+        };
+        let ssl = openssl::ssl::Ssl::new(acceptor.context()).unwrap();
+        let mut tlsstream = tokio_openssl::SslStream::new(ssl, stream).unwrap();
+        std::pin::Pin::new(&mut tlsstream).accept().await.unwrap();
+        let (r, w) = tokio::io::split(tlsstream);
+        client_process(
+            FramedRead::new(r, ClientCodec::new(None)),
+            FramedWrite::new(w, ClientCodec::new(None)),
+            client_address,
+            None,
+            server_state,
+        )
+        .await;
+    });
+
+    let mut plain = Framed::new(client, LdapCodec::new(None));
+    let bind = LdapOp::BindRequest(LdapBindRequest {
+        dn: "cn=user".to_string(),
+        cred: LdapBindCred::Simple("password".to_string()),
+    });
+    let send = |msgid, op| LdapMsg {
+        msgid,
+        op,
+        ctrl: vec![],
+    };
+
+    // Nothing is accepted in the clear.
+    plain.send(send(1, bind.clone())).await.unwrap();
+    match plain.next().await {
+        Some(Ok(LdapMsg {
+            op: LdapOp::BindResponse(resp),
+            ..
+        })) => assert_eq!(resp.res.code, LdapResultCode::ConfidentialityRequired),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let starttls = LdapOp::ExtendedRequest(LdapExtendedRequest {
+        name: "1.3.6.1.4.1.1466.20037".to_string(),
+        value: None,
+    });
+    plain.send(send(2, starttls)).await.unwrap();
+    match plain.next().await {
+        Some(Ok(LdapMsg {
+            op: LdapOp::ExtendedResponse(resp),
+            ..
+        })) => assert_eq!(resp.res.code, LdapResultCode::Success),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let ssl = connector
+        .configure()
+        .unwrap()
+        .into_ssl("localhost")
+        .unwrap();
+    let mut tlsstream = tokio_openssl::SslStream::new(ssl, plain.into_inner()).unwrap();
+    std::pin::Pin::new(&mut tlsstream).connect().await.unwrap();
+    let mut secure = Framed::new(tlsstream, LdapCodec::new(None));
+
+    // StartTLS is refused once tls is established, before a bind and after.
+    let starttls = LdapOp::ExtendedRequest(LdapExtendedRequest {
+        name: "1.3.6.1.4.1.1466.20037".to_string(),
+        value: None,
+    });
+    let refused = |msg: Option<Result<LdapMsg, _>>| match msg {
+        Some(Ok(LdapMsg {
+            op: LdapOp::ExtendedResponse(resp),
+            ..
+        })) => {
+            assert_eq!(resp.res.code, LdapResultCode::OperationsError);
+            assert_eq!(resp.res.message, "tls is already established");
+        }
+        other => panic!("unexpected {:?}", other),
+    };
+    secure.send(send(3, starttls.clone())).await.unwrap();
+    refused(secure.next().await);
+
+    secure.send(send(4, bind)).await.unwrap();
+    match secure.next().await {
+        Some(Ok(LdapMsg {
+            op: LdapOp::BindResponse(resp),
+            ..
+        })) => assert_eq!(resp.res.code, LdapResultCode::Success),
+        other => panic!("unexpected {:?}", other),
+    }
+    secure.send(send(5, starttls)).await.unwrap();
+    refused(secure.next().await);
+    assert_eq!(app_state.metrics.get("client_starttls_total", &[]), 1);
+    assert_eq!(
+        app_state.metrics.get("client_starttls_required_total", &[]),
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_sasl_plain_bind() {
+    let (acceptor, connector) = common::tls_pair();
+    // The backend sees the bind as the client sent it.
+    let addr = common::mock_server(acceptor, move |msg| match msg.op {
+        LdapOp::BindRequest(lbr) => {
+            let code = match &lbr.cred {
+                LdapBindCred::SASL(creds)
+                    if creds.mechanism == "PLAIN" && creds.credentials.ends_with(b"\0password") =>
+                {
+                    LdapResultCode::Success
+                }
+                _ => LdapResultCode::InvalidCredentials,
+            };
+            MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res: common::result(code),
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            }])
+        }
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=user,o=example".to_string(), DnConfig::default())]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state);
+    let mut plain = async |msgid, credentials: &[u8]| {
+        let op = LdapOp::BindRequest(LdapBindRequest {
+            dn: "".to_string(),
+            cred: LdapBindCred::SASL(SaslCredentials {
+                mechanism: "PLAIN".to_string(),
+                credentials: credentials.to_vec(),
+            }),
+        });
+        client.send(msgid, op).await;
+        match client.recv().await {
+            Some(LdapMsg {
+                op: LdapOp::BindResponse(resp),
+                ..
+            }) => resp.res.code,
+            other => panic!("unexpected {:?}", other),
+        }
+    };
+
+    assert_eq!(
+        plain(1, b"\0CN=User, O=Example\0password").await,
+        LdapResultCode::Success
+    );
+    assert_eq!(
+        plain(2, b"dn:cn=user,o=example\0dn:cn=user,o=example\0password").await,
+        LdapResultCode::Success
+    );
+    assert_eq!(
+        plain(3, b"\0cn=user,o=example\0wrong").await,
+        LdapResultCode::InvalidCredentials
+    );
+    // The authcid must be in the bind maps.
+    assert_eq!(
+        plain(4, b"\0cn=other,o=example\0password").await,
+        LdapResultCode::OperationsError
+    );
+    // Acting as another identity, or malformed credentials, are refused.
+    assert_eq!(
+        plain(5, b"dn:cn=admin\0cn=user,o=example\0password").await,
+        LdapResultCode::InvalidCredentials
+    );
+    assert_eq!(
+        plain(6, b"cn=user,o=example").await,
+        LdapResultCode::InvalidCredentials
+    );
+}
+
+#[test]
+fn test_dn_is_within() {
+    use ldap_proxy::dn::{dn_is_within, parent_dn};
+    assert_eq!(
+        parent_dn("uid=demo,ou=people,o=example"),
+        Some("ou=people,o=example")
+    );
+    assert_eq!(parent_dn(r"cn=a\,b,o=example"), Some("o=example"));
+    assert_eq!(parent_dn("o=example"), None);
+    assert!(dn_is_within("uid=demo,o=example", "o=example"));
+    assert!(dn_is_within("o=example", "o=example"));
+    assert!(dn_is_within("o=example", ""));
+    assert!(!dn_is_within(r"cn=a\,o=example", "o=example"));
+    assert!(!dn_is_within("o=example", "uid=demo,o=example"));
+}
+
+#[tokio::test]
+async fn test_write_invalidates_other_dns() {
+    let (acceptor, connector) = common::tls_pair();
+    let searches = Arc::new(AtomicUsize::new(0));
+    let backend_searches = searches.clone();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => {
+                backend_searches.fetch_add(1, Ordering::SeqCst);
+                MockAction::Reply(vec![LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                }])
+            }
+            LdapOp::ModifyRequest(_) | LdapOp::ModifyDNRequest(_) => {
+                let op = match msg.op {
+                    LdapOp::ModifyRequest(_) => LdapOp::ModifyResponse(common::success()),
+                    _ => LdapOp::ModifyDNResponse(common::success()),
+                };
+                MockAction::Reply(vec![LdapMsg {
+                    msgid: msg.msgid,
+                    op,
+                    ctrl: vec![],
+                }])
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([
+        ("cn=reader".to_string(), DnConfig::default()),
+        ("cn=writer".to_string(), DnConfig::default()),
+        (
+            "cn=readonly".to_string(),
+            DnConfig {
+                allow_write: Some(false),
+                ..Default::default()
+            },
+        ),
+    ]);
+    let app_state = common::app_state(addr, connector, binddn_map);
+    app_state.update_policy(|policy| policy.allow_write = true);
+    let app_state = Arc::new(app_state);
+
+    let search = |base: &str, scope| {
+        LdapOp::SearchRequest(LdapSearchRequest {
+            base: base.to_string(),
+            scope,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: vec![],
+        })
+    };
+    let searches_of = [
+        search("o=example", LdapSearchScope::Subtree),
+        search("ou=groups,o=example", LdapSearchScope::Subtree),
+        search("uid=demo,ou=people,o=example", LdapSearchScope::Base),
+        search("uid=moved,ou=people,o=example", LdapSearchScope::Base),
+    ];
+
+    let mut reader = common::connect(app_state.clone());
+    assert_eq!(reader.bind(1, "cn=reader").await, LdapResultCode::Success);
+    let mut msgid = 2;
+    let mut search_all = async || {
+        let before = searches.load(Ordering::SeqCst);
+        for op in searches_of.iter() {
+            reader.send(msgid, op.clone()).await;
+            msgid += 1;
+            recv_search(&mut reader).await;
+        }
+        searches.load(Ordering::SeqCst) - before
+    };
+    assert_eq!(search_all().await, 4);
+    assert_eq!(search_all().await, 0);
+
+    let mut writer = common::connect(app_state.clone());
+    assert_eq!(writer.bind(1, "cn=writer").await, LdapResultCode::Success);
+    writer
+        .send(
+            2,
+            LdapOp::ModifyRequest(LdapModifyRequest {
+                dn: "UID=Demo, OU=People, O=Example".to_string(),
+                changes: vec![],
+            }),
+        )
+        .await;
+    writer.recv().await.expect("no response");
+    // The subtree search, and the base search of the entry, were flushed.
+    assert_eq!(search_all().await, 2);
+
+    writer
+        .send(
+            3,
+            LdapOp::ModifyDNRequest(LdapModifyDNRequest {
+                dn: "uid=demo,ou=people,o=example".to_string(),
+                newrdn: "uid=moved".to_string(),
+                deleteoldrdn: true,
+                new_superior: None,
+            }),
+        )
+        .await;
+    writer.recv().await.expect("no response");
+    // The old and new dn of the entry are both flushed.
+    assert_eq!(search_all().await, 3);
+
+    // The global allow_write doesn't apply to DNs that deny writes.
+    let mut readonly = common::connect(app_state.clone());
+    assert_eq!(
+        readonly.bind(1, "cn=readonly").await,
+        LdapResultCode::Success
+    );
+    readonly
+        .send(
+            2,
+            LdapOp::ModifyRequest(LdapModifyRequest {
+                dn: "uid=demo,o=example".to_string(),
+                changes: vec![],
+            }),
+        )
+        .await;
+    match readonly.recv().await.expect("no response").op {
+        LdapOp::ModifyResponse(res) => {
+            assert_eq!(res.code, LdapResultCode::InsufficentAccessRights)
+        }
+        op => panic!("unexpected {:?}", op),
+    }
+}
+
+#[tokio::test]
+async fn test_whoami() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::ExtendedRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                    res: common::success(),
+                    name: None,
+                    value: Some(b"dn:uid=backend,o=example".to_vec()),
+                }),
+                ctrl: vec![],
+            }]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([
+        ("cn=user".to_string(), DnConfig::default()),
+        (
+            "cn=forwarded".to_string(),
+            DnConfig {
+                whoami_from_backend: true,
+                ..Default::default()
+            },
+        ),
+    ]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let whoami = async |client: &mut common::TestClient, msgid| {
+        let op = LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: "1.3.6.1.4.1.4203.1.11.3".to_string(),
+            value: None,
+        });
+        client.send(msgid, op).await;
+        match client.recv().await.expect("no response").op {
+            LdapOp::ExtendedResponse(resp) => {
+                assert_eq!(resp.res.code, LdapResultCode::Success);
+                String::from_utf8(resp.value.unwrap_or_default()).unwrap()
+            }
+            op => panic!("unexpected {:?}", op),
+        }
+    };
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(whoami(&mut client, 1).await, "");
+    assert_eq!(client.bind(2, "CN=User").await, LdapResultCode::Success);
+    assert_eq!(whoami(&mut client, 3).await, "dn:cn=user");
+
+    let mut client = common::connect(app_state);
+    assert_eq!(
+        client.bind(1, "cn=forwarded").await,
+        LdapResultCode::Success
+    );
+    assert_eq!(whoami(&mut client, 2).await, "dn:uid=backend,o=example");
+}
+
+#[tokio::test]
+async fn test_audit_log() {
+    let filter = parse_ldap_filter_str("(&(uid=alice)(cn=a*b))").unwrap();
+    assert_eq!(filter_string(&filter, false), "(&(uid=alice)(cn=a*b))");
+    assert_eq!(filter_string(&filter, true), "(&(uid=*)(cn=***))");
+
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: "uid=alice,o=example".to_string(),
+                        attributes: vec![],
+                    }),
+                    ctrl: vec![],
+                },
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                },
+            ]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let path = std::env::temp_dir().join(format!("ldap-proxy-audit-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let audit = AuditLog::open(&AuditConfig {
+        path: Some(path.clone()),
+        syslog: false,
+        redact_filter_values: true,
+        filter_values: None,
+    })
+    .unwrap();
+    let binddn_map = BTreeMap::from([("cn=user".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.audit = Some(Arc::new(audit));
+    let mut client = common::connect(Arc::new(app_state));
+
+    assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+    let search = || {
+        let mut sr = search_request();
+        if let LdapOp::SearchRequest(sr) = &mut sr {
+            sr.filter = filter.clone();
+        }
+        sr
+    };
+    client.send(2, search()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    client.send(3, search()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+
+    // The records are written by a thread of their own.
+    let mut records = Vec::new();
+    for _ in 0..50 {
+        let log = std::fs::read_to_string(&path).unwrap_or_default();
+        records = log
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect();
+        if records.len() == 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(records.len(), 3);
+
+    assert_eq!(records[0]["op"], "bind");
+    assert_eq!(records[0]["dn"], "cn=user");
+    assert_eq!(records[0]["bind_dn"], "");
+    assert_eq!(records[0]["result"], "Success");
+    assert_eq!(records[0]["client"], "127.0.0.1");
+
+    for (record, cache_hit) in records[1..].iter().zip([false, true]) {
+        assert_eq!(record["op"], "search");
+        assert_eq!(record["bind_dn"], "cn=user");
+        assert_eq!(record["dn"], "o=example");
+        assert_eq!(record["scope"], "sub");
+        assert_eq!(record["filter"], "(&(uid=*)(cn=***))");
+        assert_eq!(record["entries"], 1);
+        assert_eq!(record["cache_hit"], cache_hit);
+    }
+}
+
+// Make one request of the admin API, returning the status and the body.
+async fn admin_request(
+    app_state: &AppState,
+    method: &str,
+    path: &str,
+    token: &str,
+) -> (u16, serde_json::Value) {
+    let (status, body) = admin_request_text(app_state, method, path, token).await;
+    (status, serde_json::from_str(&body).unwrap())
+}
+
+async fn admin_request_text(
+    app_state: &AppState,
+    method: &str,
+    path: &str,
+    token: &str,
+) -> (u16, String) {
+    use tokio::io::AsyncReadExt;
+
+    let (mut client, server) = tokio::io::duplex(65536);
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\n\r\n",
+        method, path, token
+    );
+    client.write_all(request.as_bytes()).await.unwrap();
+    let token = Secret::from("secret");
+    admin_process(server, app_state, &token).await;
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
+
+#[tokio::test]
+async fn test_admin_api() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            }]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+    let binddn_map = BTreeMap::from([("cn=user".to_string(), DnConfig::default())]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let (status, _) = admin_request(&app_state, "GET", "/sessions", "wrong").await;
+    assert_eq!(status, 401);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+
+    let (status, sessions) = admin_request(&app_state, "GET", "/sessions", "secret").await;
+    assert_eq!(status, 200);
+    assert_eq!(sessions.as_array().unwrap().len(), 1);
+    assert_eq!(sessions[0]["id"], 1);
+    assert_eq!(sessions[0]["bind_dn"], "cn=user");
+
+    let (status, cache) = admin_request(&app_state, "GET", "/cache", "secret").await;
+    assert_eq!(status, 200);
+    assert_eq!(cache["entries"], 1);
+    assert_eq!(cache["misses"], 1);
+    let (status, flushed) = admin_request(&app_state, "POST", "/cache/flush", "secret").await;
+    assert_eq!((status, &flushed["flushed"]), (200, &1.into()));
+    let (_, cache) = admin_request(&app_state, "GET", "/cache", "secret").await;
+    assert_eq!(cache["entries"], 0);
+
+    let (status, backends) = admin_request(&app_state, "GET", "/backends", "secret").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        backends[DEFAULT_BACKEND][0]["addresses"][0]["healthy"],
+        true
+    );
+
+    let (status, _) = admin_request(&app_state, "GET", "/cache/flush", "secret").await;
+    assert_eq!(status, 405);
+    let (status, _) = admin_request(&app_state, "POST", "/sessions/9/disconnect", "secret").await;
+    assert_eq!(status, 404);
+
+    let (status, _) = admin_request(&app_state, "POST", "/sessions/1/disconnect", "secret").await;
+    assert_eq!(status, 200);
+    let msg = client.recv().await.expect("no notice of disconnection");
+    assert_eq!(msg.msgid, 0);
+    assert!(client.recv().await.is_none());
+    let (_, sessions) = admin_request(&app_state, "GET", "/sessions", "secret").await;
+    assert_eq!(sessions, serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn test_policy_reload() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            }]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+    let binddn_map = BTreeMap::from([("cn=user".to_string(), DnConfig::default())]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+
+    // The established session follows the new config of its DN.
+    app_state.update_policy(|policy| {
+        policy.binddn_map.insert(
+            "cn=user".to_string(),
+            DnConfig {
+                allowed_bases: vec!["ou=people,o=example".to_string()],
+                ..Default::default()
+            },
+        );
+    });
+    client.send(3, search_request()).await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (0, LdapResultCode::InsufficentAccessRights)
+    );
+
+    // And is ended once its DN may no longer bind.
+    app_state.update_policy(|policy| policy.binddn_map.clear());
+    client.send(4, search_request()).await;
+    let msg = client.recv().await.expect("no notice of disconnection");
+    assert_eq!(msg.msgid, 0);
+    assert!(client.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn test_shutdown_drains_sessions() {
+    let (acceptor, connector) = common::tls_pair();
+    // Searches are never answered, so they run until the operation timeout.
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::SearchRequest(_) | LdapOp::AbandonRequest(_) => MockAction::Reply(vec![]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+    let binddn_map = BTreeMap::from([("cn=user".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state
+        .backend_pools
+        .get_mut(DEFAULT_BACKEND)
+        .unwrap()
+        .timeouts
+        .operation = Some(Duration::from_millis(300));
+    let app_state = Arc::new(app_state);
+
+    let mut idle = common::connect(app_state.clone());
+    assert_eq!(idle.bind(1, "cn=user").await, LdapResultCode::Success);
+    let mut busy = common::connect(app_state.clone());
+    assert_eq!(busy.bind(1, "cn=user").await, LdapResultCode::Success);
+    busy.send(2, search_request()).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let started = Instant::now();
+    app_state.sessions.drain();
+
+    // The idle session is told straight away.
+    let msg = idle.recv().await.expect("no notice of disconnection");
+    assert_eq!(msg.msgid, 0);
+    assert!(idle.recv().await.is_none());
+    assert!(started.elapsed() < Duration::from_millis(200));
+
+    // The busy session finishes its search first.
+    assert_eq!(
+        recv_search(&mut busy).await,
+        (0, LdapResultCode::TimeLimitExceeded)
+    );
+    let msg = busy.recv().await.expect("no notice of disconnection");
+    assert_eq!(msg.msgid, 0);
+    assert!(busy.recv().await.is_none());
+
+    tokio::time::timeout(Duration::from_secs(1), app_state.sessions.drained())
+        .await
+        .expect("sessions were not drained");
+
+    // Sessions that arrive during the shutdown are ended straight away.
+    let mut late = common::connect(app_state.clone());
+    let msg = late.recv().await.expect("no notice of disconnection");
+    assert_eq!(msg.msgid, 0);
+}
+
+#[test]
+fn test_sd_watchdog() {
+    let pid = std::process::id();
+    assert_eq!(
+        systemd::parse_watchdog(Some("30000000"), None, pid),
+        Some(Duration::from_secs(15))
+    );
+    assert_eq!(
+        systemd::parse_watchdog(Some("30000000"), Some(&pid.to_string()), pid),
+        Some(Duration::from_secs(15))
+    );
+    // The watchdog is for another process, or is disabled.
+    assert_eq!(
+        systemd::parse_watchdog(Some("30000000"), Some("1"), pid),
+        None
+    );
+    assert_eq!(systemd::parse_watchdog(Some("0"), None, pid), None);
+    assert_eq!(systemd::parse_watchdog(Some("nope"), None, pid), None);
+    assert_eq!(systemd::parse_watchdog(None, None, pid), None);
+}
+
+async fn health_request(
+    app_state: &AppState,
+    method: &str,
+    path: &str,
+) -> (u16, serde_json::Value) {
+    use tokio::io::AsyncReadExt;
+
+    let (mut client, server) = tokio::io::duplex(65536);
+    let request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path);
+    client.write_all(request.as_bytes()).await.unwrap();
+    health_process(server, app_state).await;
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[tokio::test]
+async fn test_health_endpoints() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr =
+        common::mock_server(acceptor, common::accept_binds(|_| MockAction::Disconnect)).await;
+    let app_state = common::app_state(addr, connector, BTreeMap::new());
+
+    assert_eq!(health_request(&app_state, "GET", "/healthz").await.0, 200);
+    let (status, ready) = health_request(&app_state, "GET", "/readyz").await;
+    assert_eq!(status, 200);
+    assert_eq!(ready["ready"], true);
+    assert_eq!(ready["backends"][DEFAULT_BACKEND], true);
+
+    // The only backend address failed its health check.
+    app_state.breakers.mark_unhealthy(&addr);
+    let (status, ready) = health_request(&app_state, "GET", "/readyz").await;
+    assert_eq!(status, 503);
+    assert_eq!(ready["backends"][DEFAULT_BACKEND], false);
+    // The process is still alive, though.
+    assert_eq!(health_request(&app_state, "GET", "/healthz").await.0, 200);
+
+    app_state.breakers.mark_healthy(&addr);
+    app_state.listening.store(false, Ordering::Relaxed);
+    let (status, ready) = health_request(&app_state, "GET", "/readyz").await;
+    assert_eq!(status, 503);
+    assert_eq!(ready["listening"], false);
+
+    assert_eq!(health_request(&app_state, "POST", "/readyz").await.0, 405);
+    assert_eq!(health_request(&app_state, "GET", "/sessions").await.0, 404);
+}
+
+#[tokio::test]
+async fn test_cache_max_entries() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            }]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    // The test cache holds 1MiB, so at most two entries.
+    app_state.cache_min_entry_weight = 512 * 1024;
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    for (msgid, uid) in (2..).zip(["a", "b", "c", "d"]) {
+        let mut search = search_request();
+        if let LdapOp::SearchRequest(sr) = &mut search {
+            sr.filter = LdapFilter::Equality("uid".to_string(), uid.to_string());
+        }
+        client.send(msgid, search).await;
+        assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    }
+
+    // The queued inserts are applied, and the older entries evicted, as the
+    // cache is committed.
+    app_state.cache.write().commit();
+    let entries = app_state.cache.write().iter().count();
+    assert!((1..=2).contains(&entries), "{} entries cached", entries);
+}
+
+#[tokio::test]
+async fn test_cache_ttl_overrides() {
+    let (acceptor, connector) = common::tls_pair();
+    let searches = Arc::new(AtomicUsize::new(0));
+    let c_searches = searches.clone();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => {
+                c_searches.fetch_add(1, Ordering::SeqCst);
+                MockAction::Reply(vec![LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                }])
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([
+        ("cn=sssd".to_string(), DnConfig::default()),
+        (
+            "cn=fresh".to_string(),
+            DnConfig {
+                cache_ttl_secs: Some(0),
+                ..Default::default()
+            },
+        ),
+    ]);
+    let app_state = common::app_state(addr, connector, binddn_map);
+    app_state.update_policy(|policy| {
+        policy.cache_ttls = BTreeMap::from([
+            ("ou=groups,o=example".to_string(), Duration::from_secs(60)),
+            ("cn=schema".to_string(), Duration::from_secs(86400)),
+        ])
+    });
+    let app_state = Arc::new(app_state);
+
+    // The most specific base is used, and the shorter of it and a DN's TTL.
+    let policy = app_state.policy.load();
+    let dn_config = DnConfig::default();
+    assert_eq!(
+        policy.cache_ttl(&dn_config, "cn=admins,OU=Groups,o=example"),
+        Duration::from_secs(60)
+    );
+    assert_eq!(
+        policy.cache_ttl(&dn_config, "cn=schema"),
+        Duration::from_secs(86400)
+    );
+    assert_eq!(
+        policy.cache_ttl(&dn_config, "o=example"),
+        policy.cache_entry_timeout
+    );
+    let dn_config = DnConfig {
+        cache_ttl_secs: Some(5),
+        ..Default::default()
+    };
+    assert_eq!(
+        policy.cache_ttl(&dn_config, "cn=schema"),
+        Duration::from_secs(5)
+    );
+
+    // The second search of a DN with a TTL of zero goes to the backend again.
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=fresh").await, LdapResultCode::Success);
+    for msgid in [2, 3] {
+        client.send(msgid, search_request()).await;
+        assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    }
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+    assert_eq!(app_state.cache.write().iter().count(), 0);
+
+    // Where others are answered from the cache.
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    for msgid in [2, 3] {
+        client.send(msgid, search_request()).await;
+        assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    }
+    assert_eq!(searches.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_negative_cache_ttl() {
+    let (acceptor, connector) = common::tls_pair();
+    let searches = Arc::new(AtomicUsize::new(0));
+    let c_searches = searches.clone();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(sr) => {
+                c_searches.fetch_add(1, Ordering::SeqCst);
+                let mut msgs = vec![];
+                if sr.filter == LdapFilter::Equality("uid".to_string(), "demo".to_string()) {
+                    msgs.push(LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: "uid=demo,o=example".to_string(),
+                            attributes: vec![],
+                        }),
+                        ctrl: vec![],
+                    });
+                }
+                msgs.push(LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                });
+                MockAction::Reply(msgs)
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+    let app_state = common::app_state(addr, connector, binddn_map);
+    app_state.update_policy(|policy| policy.negative_cache_ttl = Some(Duration::from_millis(100)));
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    let lookup = |uid: &str| {
+        let mut search = search_request();
+        if let LdapOp::SearchRequest(sr) = &mut search {
+            sr.filter = LdapFilter::Equality("uid".to_string(), uid.to_string());
+        }
+        search
+    };
+
+    // Both are cached, but the miss only for a short time.
+    client.send(2, lookup("demo")).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    client.send(3, lookup("dmeo")).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    client.send(4, lookup("dmeo")).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    client.send(5, lookup("demo")).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    client.send(6, lookup("dmeo")).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    assert_eq!(searches.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_bind_cache() {
+    let (acceptor, connector) = common::tls_pair();
+    let binds = Arc::new(AtomicUsize::new(0));
+    let backend_binds = binds.clone();
+    let addr = common::mock_server(acceptor, move |msg| match msg.op {
+        LdapOp::BindRequest(lbr) => {
+            backend_binds.fetch_add(1, Ordering::SeqCst);
+            let mut res = common::success();
+            if lbr.cred != LdapBindCred::Simple("password".to_string()) {
+                res.code = LdapResultCode::InvalidCredentials;
+            }
+            MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res,
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            }])
+        }
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=user".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.upstream_pool =
+        UpstreamPool::new(1, Duration::from_secs(60), Duration::from_secs(600));
+    app_state.bind_cache = Some(BindCache::new(Duration::from_secs(60)));
+    let app_state = Arc::new(app_state);
+
+    let bind_again = async |password: &str| {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while app_state.upstream_pool.len() != 1 {
+            assert!(Instant::now() < deadline, "the connection was never pooled");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut client = common::connect(app_state.clone());
+        client
+            .send(
+                1,
+                LdapOp::BindRequest(LdapBindRequest {
+                    dn: "cn=user".to_string(),
+                    cred: LdapBindCred::Simple(password.to_string()),
+                }),
+            )
+            .await;
+        let code = match client.recv().await {
+            Some(LdapMsg {
+                op: LdapOp::BindResponse(resp),
+                ..
+            }) => resp.res.code,
+            other => panic!("unexpected {:?}", other),
+        };
+        client.send(2, LdapOp::UnbindRequest).await;
+        code
+    };
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+    client.send(2, LdapOp::UnbindRequest).await;
+    assert_eq!(binds.load(Ordering::SeqCst), 1);
+
+    // The same password on the pooled connection isn't sent to the backend.
+    assert_eq!(bind_again("password").await, LdapResultCode::Success);
+    assert_eq!(binds.load(Ordering::SeqCst), 1);
+    assert_eq!(app_state.metrics.get("bind_cache_hits_total", &[]), 1);
+
+    // Another password is, and when it is refused the cached bind is forgotten.
+    assert_eq!(bind_again("nope").await, LdapResultCode::InvalidCredentials);
+    assert_eq!(binds.load(Ordering::SeqCst), 2);
+    let cache = app_state.bind_cache.as_ref().unwrap();
+    assert!(cache.is_empty());
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+    assert_eq!(binds.load(Ordering::SeqCst), 3);
+    assert!(cache.verify("cn=user", "password"));
+    assert!(!cache.verify("cn=user", "Password"));
+    assert!(!cache.verify("cn=other", "password"));
+}
+
+#[tokio::test]
+async fn test_cache_index_invalidation() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            }]),
+            LdapOp::ModifyRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::ModifyResponse(common::success()),
+                ctrl: vec![],
+            }]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([(
+        "cn=provisioner".to_string(),
+        DnConfig {
+            allow_write: Some(true),
+            ..Default::default()
+        },
+    )]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=provisioner").await,
+        LdapResultCode::Success
+    );
+    let searches = [
+        ("o=example", LdapSearchScope::Subtree),
+        ("ou=people,o=example", LdapSearchScope::Subtree),
+        ("ou=people,o=example", LdapSearchScope::OneLevel),
+        ("uid=demo,ou=people,o=example", LdapSearchScope::Base),
+        ("ou=groups,o=example", LdapSearchScope::Subtree),
+        ("uid=other,ou=people,o=example", LdapSearchScope::Base),
+    ];
+    for (msgid, (base, scope)) in (2..).zip(searches) {
+        let mut search = search_request();
+        if let LdapOp::SearchRequest(sr) = &mut search {
+            sr.base = base.to_string();
+            sr.scope = scope;
+        }
+        client.send(msgid, search).await;
+        assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    }
+    assert_eq!(app_state.cache_index.len(), 6);
+
+    client
+        .send(
+            10,
+            LdapOp::ModifyRequest(LdapModifyRequest {
+                dn: "UID=demo, ou=people,o=example".to_string(),
+                changes: vec![],
+            }),
+        )
+        .await;
+    let msg = client.recv().await.expect("no response");
+    assert!(matches!(msg.op, LdapOp::ModifyResponse(_)));
+
+    // Only the searches of the groups, and of the other entry, are left.
+    assert_eq!(app_state.cache_index.len(), 2);
+    assert_eq!(app_state.cache.write().iter().count(), 2);
+
+    // A sweep keeps the searches that are still cached.
+    sweep_expired_cache(&app_state).await;
+    assert_eq!(app_state.cache_index.len(), 2);
+}
+
+#[tokio::test]
+async fn test_cache_bypass() {
+    let (acceptor, connector) = common::tls_pair();
+    let searches = Arc::new(AtomicUsize::new(0));
+    let c_searches = searches.clone();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => {
+                c_searches.fetch_add(1, Ordering::SeqCst);
+                MockAction::Reply(vec![LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                }])
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let bypass = "1.3.6.1.4.1.99999.1";
+    let binddn_map = BTreeMap::from([
+        ("cn=sssd".to_string(), DnConfig::default()),
+        (
+            "cn=fresh".to_string(),
+            DnConfig {
+                never_cache: true,
+                ..Default::default()
+            },
+        ),
+    ]);
+    let app_state = common::app_state(addr, connector, binddn_map);
+    app_state.update_policy(|policy| policy.cache_bypass_control = Some(bypass.to_string()));
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    client.send(3, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    assert_eq!(searches.load(Ordering::SeqCst), 1);
+
+    // The control is accepted even when critical, and goes to the backend.
+    client
+        .send_with_raw_control(4, search_request(), bypass, true)
+        .await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+    client.send(5, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+
+    // Other unknown critical controls are still refused.
+    client
+        .send_with_raw_control(6, search_request(), "1.3.6.1.4.1.99999.2", true)
+        .await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (0, LdapResultCode::UnavailableCriticalExtension)
+    );
+
+    // A DN that is never cached always searches the backend.
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=fresh").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    client.send(3, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    assert_eq!(searches.load(Ordering::SeqCst), 4);
+    assert_eq!(app_state.metrics.get("cache_bypassed_total", &[]), 1);
+}
+
+#[tokio::test]
+async fn test_cache_key_canonical() {
+    let (acceptor, connector) = common::tls_pair();
+    let searches = Arc::new(AtomicUsize::new(0));
+    let c_searches = searches.clone();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => {
+                c_searches.fetch_add(1, Ordering::SeqCst);
+                MockAction::Reply(vec![
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: "uid=demo,o=example".to_string(),
+                            attributes: vec![],
+                        }),
+                        ctrl: vec![],
+                    },
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultDone(common::success()),
+                        ctrl: vec![],
+                    },
+                ])
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+
+    let search = |base: &str, filter: &str, attrs: &[&str]| {
+        LdapOp::SearchRequest(LdapSearchRequest {
+            base: base.to_string(),
+            scope: LdapSearchScope::Subtree,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: ldap3_proto::parse_ldap_filter_str(filter).unwrap(),
+            attrs: attrs.iter().map(|a| a.to_string()).collect(),
+        })
+    };
+    client
+        .send(
+            2,
+            search(
+                "o=example",
+                "(&(objectClass=person)(uid=demo))",
+                &["cn", "uid"],
+            ),
+        )
+        .await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    client
+        .send(
+            3,
+            search(
+                "O=Example",
+                "(&(UID=demo)(objectclass=person)(uid=demo))",
+                &["UID", "cn"],
+            ),
+        )
+        .await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    assert_eq!(searches.load(Ordering::SeqCst), 1);
+
+    // Values are not made canonical.
+    client
+        .send(
+            4,
+            search(
+                "o=example",
+                "(&(objectClass=person)(uid=DEMO))",
+                &["cn", "uid"],
+            ),
+        )
+        .await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_check_config() {
+    let dir = std::env::temp_dir().join(format!("ldap-proxy-check-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = common::certificate("localhost", "localhost");
+    std::fs::write(dir.join("chain.pem"), cert.to_pem().unwrap()).unwrap();
+    std::fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+    let check = |name: &str, contents: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        std::process::Command::new(env!("CARGO_BIN_EXE_ldap-proxy"))
+            .arg("check-config")
+            .arg(&path)
+            .output()
+            .unwrap()
+    };
+    let config = |ldap_url: &str, base: &str| {
+        format!(
+            r#"bind = "127.0.0.1:3636"
+tls_key = "{dir}/key.pem"
+tls_chain = "{dir}/chain.pem"
+ldap_ca = "{dir}/chain.pem"
+ldap_url = "{ldap_url}"
+
+["cn=Directory Manager"]
+allowed_bases = ["{base}"]
+"#,
+            dir = dir.display()
+        )
+    };
+
+    let output = check("valid.toml", &config("ldaps://127.0.0.1:636", "o=example"));
+    assert!(output.status.success());
+
+    let output = check("base.toml", &config("ldaps://127.0.0.1:636", "o=example,"));
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("is not a valid DN"));
+
+    let output = check("invalid.toml", "bind = ");
+    assert!(!output.status.success());
+
+    let missing = config("ldaps://127.0.0.1:636", "o=example").replace("key.pem", "nokey.pem");
+    let output = check("missing.toml", &missing);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Unable to load private key"));
+
+    // What would stop the proxy from starting is found, such as half of a
+    // pair of options.
+    for (name, option) in [
+        ("neutral.toml", "upstream_pool_neutral_dn = \"cn=neutral\""),
+        ("anonymous.toml", "anonymous_bind_password = \"password\""),
+    ] {
+        let valid = config("ldaps://127.0.0.1:636", "o=example");
+        let output = check(name, &format!("{}\n{}", option, valid));
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("must be set together"));
+    }
+
+    // As are the certificates of listeners that have their own.
+    let listener = |tls: &str| {
+        format!(
+            "listeners = [{{ bind = \"127.0.0.1:3637\"{} }}]\n{}",
+            tls,
+            config("ldaps://127.0.0.1:636", "o=example")
+        )
+    };
+    let chain = format!(", tls_chain = \"{}/chain.pem\"", dir.display());
+    let key = |name: &str| format!("{}, tls_key = \"{}/{}\"", chain, dir.display(), name);
+    let output = check("listener.toml", &listener(&key("key.pem")));
+    assert!(output.status.success());
+    let output = check("listener.toml", &listener(&chain));
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("needs both tls_chain and tls_key"));
+    let output = check("listener.toml", &listener(&key("nokey.pem")));
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Unable to load private key"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_test_subcommand() {
+    let dir = std::env::temp_dir().join(format!("ldap-proxy-test-cmd-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = common::certificate("localhost", "localhost");
+    std::fs::write(dir.join("chain.pem"), cert.to_pem().unwrap()).unwrap();
+    std::fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    std::fs::write(dir.join("password"), "secret\n").unwrap();
+    std::fs::write(dir.join("wrong"), "wrong\n").unwrap();
+
+    let mut acceptor =
+        openssl::ssl::SslAcceptor::mozilla_intermediate_v5(openssl::ssl::SslMethod::tls()).unwrap();
+    acceptor.set_certificate(&cert).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    let addr = common::mock_server(acceptor.build(), |msg| match msg.op {
+        LdapOp::BindRequest(lbr) => {
+            let code = if lbr.cred == LdapBindCred::Simple("secret".to_string()) {
+                LdapResultCode::Success
+            } else {
+                LdapResultCode::InvalidCredentials
+            };
+            MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res: common::result(code),
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            }])
+        }
+        LdapOp::SearchRequest(_) => MockAction::Reply(vec![
+            LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=demo,o=example".to_string(),
+                    attributes: vec![],
+                }),
+                ctrl: vec![],
+            },
+            LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            },
+        ]),
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let config = dir.join("config.toml");
+    std::fs::write(
+        &config,
+        format!(
+            r#"bind = "127.0.0.1:3636"
+tls_key = "{dir}/key.pem"
+tls_chain = "{dir}/chain.pem"
+ldap_ca = "{dir}/chain.pem"
+ldap_url = "ldaps://localhost:{port}"
+
+["cn=sssd"]
+"#,
+            dir = dir.display(),
+            port = addr.port()
+        ),
+    )
+    .unwrap();
+    let run = |dn: &'static str, password: &'static str| {
+        let dir = dir.clone();
+        let config = config.clone();
+        tokio::task::spawn_blocking(move || {
+            std::process::Command::new(env!("CARGO_BIN_EXE_ldap-proxy"))
+                .arg("--config")
+                .arg(&config)
+                .args(["test", "--dn", dn, "--filter", "(uid=demo)"])
+                .arg("--password-file")
+                .arg(dir.join(password))
+                .output()
+                .unwrap()
+        })
+    };
+
+    let output = run("cn=sssd", "password").await.unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    for stage in ["config", "resolve", "connect", "bind", "search"] {
+        assert!(
+            stdout.lines().any(|line| line.starts_with(stage)),
+            "{}",
+            stdout
+        );
+    }
+    assert!(stdout.contains("Success, 1 entries"), "{}", stdout);
+
+    let output = run("cn=sssd", "wrong").await.unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("InvalidCredentials"));
+
+    // DNs that the proxy refuses are not sent to the backend.
+    let output = run("cn=other", "password").await.unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("not in the bind maps"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_admin_cache_ldif() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: "uid=demo,o=example".to_string(),
+                        attributes: vec![
+                            LdapPartialAttribute {
+                                atype: "cn".to_string(),
+                                vals: vec![b"Demo User".to_vec()],
+                            },
+                            LdapPartialAttribute {
+                                atype: "description".to_string(),
+                                vals: vec![b" lead\nline".to_vec()],
+                            },
+                        ],
+                    }),
+                    ctrl: vec![],
+                },
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                },
+            ]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    app_state.cache.try_quiesce();
+
+    let (status, ldif) = admin_request_text(&app_state, "GET", "/cache/ldif", "secret").await;
+    assert_eq!(status, 200);
+    let lines: Vec<_> = ldif.lines().collect();
+    assert_eq!(lines[0], "version: 1");
+    assert!(lines.contains(&"# bind_dn: cn=sssd"));
+    assert!(lines.contains(&"# search: base=\"o=example\" scope=sub filter=(objectclass=*) attrs="));
+    assert!(lines.iter().any(|line| line.starts_with("# ttl: ")
+        && line
+            .trim_start_matches("# ttl: ")
+            .trim_end_matches('s')
+            .parse::<u64>()
+            .is_ok()));
+    assert!(lines.contains(&"dn: uid=demo,o=example"));
+    assert!(lines.contains(&"cn: Demo User"));
+    // Values that can't be written as they are are in base64.
+    assert!(lines.contains(&"description:: IGxlYWQKbGluZQ=="));
+
+    let (status, _) = admin_request(&app_state, "POST", "/cache/ldif", "secret").await;
+    assert_eq!(status, 405);
+    let (status, _) = admin_request(&app_state, "GET", "/cache/ldif", "wrong").await;
+    assert_eq!(status, 401);
+}
+
+#[tokio::test]
+async fn test_backend_tls_versions() {
+    let config: Config = toml::from_str(&format!(
+        r#"{}
+ldap_tls_min_version = "1.2"
+ldap_tls_ciphersuites = "TLS_AES_256_GCM_SHA384"
+
+[backends.legacy]
+ldap_url = "ldaps://legacy.example.com"
+ldap_tls_min_version = "1.0"
+ldap_tls_max_version = "1.2"
+ldap_tls_cipher_list = "AES256-SHA"
+"#,
+        MINIMAL_CONFIG
+    ))
+    .unwrap();
+    let tls = config.backend_tls(None);
+    assert_eq!(tls.min_version, Some(TlsVersion::Tls12));
+    assert_eq!(tls.max_version, None);
+    assert_eq!(tls.ciphersuites.as_deref(), Some("TLS_AES_256_GCM_SHA384"));
+    let legacy = config.backend_tls(config.backends.get("legacy"));
+    assert_eq!(legacy.min_version, Some(TlsVersion::Tls10));
+    assert_eq!(legacy.max_version, Some(TlsVersion::Tls12));
+    assert_eq!(legacy.cipher_list.as_deref(), Some("AES256-SHA"));
+    assert_eq!(
+        legacy.ciphersuites.as_deref(),
+        Some("TLS_AES_256_GCM_SHA384")
+    );
+    assert!(toml::from_str::<Config>(&format!(
+        "{}\nldap_tls_min_version = \"1.4\"\n",
+        MINIMAL_CONFIG
+    ))
+    .is_err());
+
+    // A backend that only speaks TLS 1.2.
+    let (cert, key) = common::certificate("localhost", "localhost");
+    let mut acceptor =
+        openssl::ssl::SslAcceptor::mozilla_intermediate_v5(openssl::ssl::SslMethod::tls()).unwrap();
+    acceptor.set_certificate(&cert).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    acceptor
+        .set_max_proto_version(Some(openssl::ssl::SslVersion::TLS1_2))
+        .unwrap();
+    let addr = common::mock_server(
+        acceptor.build(),
+        common::accept_binds(|_| MockAction::Disconnect),
+    )
+    .await;
+
+    let connector = |tls: BackendTls| {
+        let mut connector =
+            openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls_client()).unwrap();
+        connector.cert_store_mut().add_cert(cert.clone()).unwrap();
+        tls.apply(&mut connector).unwrap();
+        connector.build()
+    };
+    let binddn_map = BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+
+    let tls13 = BackendTls {
+        min_version: Some(TlsVersion::Tls13),
+        ..Default::default()
+    };
+    let app_state = common::app_state(addr, connector(tls13), binddn_map.clone());
+    let mut client = common::connect(Arc::new(app_state));
+    assert_ne!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+
+    let tls12 = BackendTls {
+        max_version: Some(TlsVersion::Tls12),
+        ..Default::default()
+    };
+    let app_state = common::app_state(addr, connector(tls12), binddn_map);
+    let mut client = common::connect(Arc::new(app_state));
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+}
+
+#[tokio::test]
+async fn test_backend_spki_pins() {
+    let pin = "sha256:".to_string() + &"AB".repeat(32);
+    let config: Config = toml::from_str(&format!(
+        r#"{}
+ldap_spki_pins = ["{}"]
+
+[backends.other]
+ldap_url = "ldaps://other.example.com"
+ldap_spki_pins = []
+"#,
+        MINIMAL_CONFIG, pin
+    ))
+    .unwrap();
+    let pins = config.backend_tls(None).spki_pins;
+    assert_eq!(pins.len(), 1);
+    assert_eq!(pins[0].to_string(), pin.to_lowercase());
+    assert!(config
+        .backend_tls(config.backends.get("other"))
+        .spki_pins
+        .is_empty());
+    for invalid in ["AB".repeat(32), "sha256:abcd".to_string()] {
+        assert!(toml::from_str::<Config>(&format!(
+            "{}\nldap_spki_pins = [\"{}\"]\n",
+            MINIMAL_CONFIG, invalid
+        ))
+        .is_err());
+    }
+
+    let (acceptor, connector) = common::tls_pair();
+    let backend_pin = SpkiPin::of(acceptor.context().certificate().unwrap()).unwrap();
+    let addr =
+        common::mock_server(acceptor, common::accept_binds(|_| MockAction::Disconnect)).await;
+    let binddn_map = BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+    let pinned = |pins: Vec<SpkiPin>| {
+        let tls = BackendTls {
+            spki_pins: pins,
+            ..Default::default()
+        };
+        let mut app_state = common::app_state(addr, connector.clone(), binddn_map.clone());
+        let pool = app_state.backend_pools.remove(DEFAULT_BACKEND).unwrap();
+        app_state
+            .backend_pools
+            .insert(DEFAULT_BACKEND.to_string(), pool.with_tls(tls));
+        common::connect(Arc::new(app_state))
+    };
+
+    // The certificate is signed by a trusted CA, but its key isn't pinned.
+    let other_pin = SpkiPin::try_from(pin).unwrap();
+    let mut client = pinned(vec![other_pin.clone()]);
+    assert_ne!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+
+    let mut client = pinned(vec![other_pin, backend_pin]);
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+}
+
+#[tokio::test]
+async fn test_backend_sni_and_hostname_verification() {
+    let config: Config = toml::from_str(&format!(
+        r#"{}
+ldap_tls_sni_name = "ldap.example.com"
+
+[backends.lab]
+ldap_url = "ldaps://10.0.0.1"
+ldap_tls_verify_hostname = false
+"#,
+        MINIMAL_CONFIG
+    ))
+    .unwrap();
+    let tls = config.backend_tls(None);
+    assert!(tls.verify_hostname);
+    assert_eq!(tls.sni_name.as_deref(), Some("ldap.example.com"));
+    let lab = config.backend_tls(config.backends.get("lab"));
+    assert!(!lab.verify_hostname);
+    assert_eq!(lab.sni_name.as_deref(), Some("ldap.example.com"));
+
+    // A backend with a certificate for localhost, that records the SNI it was
+    // sent.
+    let (cert, key) = common::certificate("localhost", "localhost");
+    let mut acceptor =
+        openssl::ssl::SslAcceptor::mozilla_intermediate_v5(openssl::ssl::SslMethod::tls()).unwrap();
+    acceptor.set_certificate(&cert).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    let sni = Arc::new(std::sync::Mutex::new(None));
+    let seen = sni.clone();
+    acceptor.set_servername_callback(move |ssl, _| {
+        *seen.lock().unwrap() = ssl
+            .servername(openssl::ssl::NameType::HOST_NAME)
+            .map(str::to_string);
+        Ok(())
+    });
+    let addr = common::mock_server(
+        acceptor.build(),
+        common::accept_binds(|_| MockAction::Disconnect),
+    )
+    .await;
+
+    let mut connector =
+        openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls_client()).unwrap();
+    connector.cert_store_mut().add_cert(cert).unwrap();
+    let connector = connector.build();
+    let binddn_map = BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+    let bind = |tls: BackendTls| {
+        let mut app_state = common::app_state(addr, connector.clone(), binddn_map.clone());
+        let pool = app_state.backend_pools.remove(DEFAULT_BACKEND).unwrap();
+        app_state
+            .backend_pools
+            .insert(DEFAULT_BACKEND.to_string(), pool.with_tls(tls));
+        async move {
+            let mut client = common::connect(Arc::new(app_state));
+            client.bind(1, "cn=sssd").await
+        }
+    };
+
+    assert_eq!(bind(BackendTls::default()).await, LdapResultCode::Success);
+    assert_eq!(sni.lock().unwrap().as_deref(), Some("localhost"));
+
+    // The certificate doesn't name the overridden SNI name.
+    let renamed = BackendTls {
+        sni_name: Some("ldap.example.com".to_string()),
+        ..Default::default()
+    };
+    assert_ne!(bind(renamed.clone()).await, LdapResultCode::Success);
+    assert_eq!(sni.lock().unwrap().as_deref(), Some("ldap.example.com"));
+
+    let unverified = BackendTls {
+        verify_hostname: false,
+        ..renamed
+    };
+    assert_eq!(bind(unverified).await, LdapResultCode::Success);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_backend_client_certificate() {
+    let dir = std::env::temp_dir().join(format!("ldap-proxy-test-mtls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = common::certificate("localhost", "localhost");
+    std::fs::write(dir.join("chain.pem"), cert.to_pem().unwrap()).unwrap();
+    std::fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    let (client_cert, client_key) = common::certificate("proxy", "proxy.example.com");
+    std::fs::write(dir.join("client.pem"), client_cert.to_pem().unwrap()).unwrap();
+    std::fs::write(
+        dir.join("client-key.pem"),
+        client_key.private_key_to_pem_pkcs8().unwrap(),
+    )
+    .unwrap();
+    std::fs::write(dir.join("password"), "secret\n").unwrap();
+
+    // A backend that requires the proxy's certificate.
+    let mut acceptor =
+        openssl::ssl::SslAcceptor::mozilla_intermediate_v5(openssl::ssl::SslMethod::tls()).unwrap();
+    acceptor.set_certificate(&cert).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    acceptor.cert_store_mut().add_cert(client_cert).unwrap();
+    acceptor.set_verify(
+        openssl::ssl::SslVerifyMode::PEER | openssl::ssl::SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+    );
+    let addr = common::mock_server(
+        acceptor.build(),
+        common::accept_binds(|_| MockAction::Disconnect),
+    )
+    .await;
+
+    let run = |backend: &str| {
+        let config = dir.join("config.toml");
+        std::fs::write(
+            &config,
+            format!(
+                r#"bind = "127.0.0.1:3636"
+tls_key = "{dir}/key.pem"
+tls_chain = "{dir}/chain.pem"
+ldap_ca = "{dir}/chain.pem"
+ldap_url = "ldaps://localhost:{port}"
+
+[backends.mtls]
+ldap_url = "ldaps://localhost:{port}"
+{backend}
+
+["cn=sssd"]
+backend = "mtls"
+"#,
+                dir = dir.display(),
+                port = addr.port(),
+                backend = backend
+            ),
+        )
+        .unwrap();
+        let password = dir.join("password");
+        tokio::task::spawn_blocking(move || {
+            std::process::Command::new(env!("CARGO_BIN_EXE_ldap-proxy"))
+                .arg("--config")
+                .arg(&config)
+                .args(["test", "--dn", "cn=sssd", "--password-file"])
+                .arg(password)
+                .output()
+                .unwrap()
+        })
+    };
+
+    let output = run("").await.unwrap();
+    assert!(!output.status.success());
+
+    let output = run(&format!(
+        "ldap_client_cert = \"{dir}/client.pem\"\nldap_client_key = \"{dir}/client-key.pem\"",
+        dir = dir.display()
+    ))
+    .await
+    .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+
+    let output = run(&format!(
+        "ldap_client_cert = \"{dir}/client.pem\"",
+        dir = dir.display()
+    ))
+    .await
+    .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success());
+    assert!(stdout.contains("must be set together"), "{}", stdout);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_upstream_pool_neutral_bind() {
+    let (acceptor, connector) = common::tls_pair();
+    let binds = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let backend_binds = binds.clone();
+    let addr = common::mock_server(acceptor, move |msg| match msg.op {
+        LdapOp::BindRequest(lbr) => {
+            let code = if lbr.dn == "cn=pool"
+                && lbr.cred != LdapBindCred::Simple("pool-secret".to_string())
+            {
+                LdapResultCode::InvalidCredentials
+            } else {
+                LdapResultCode::Success
+            };
+            backend_binds.lock().unwrap().push(lbr.dn);
+            MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res: common::result(code),
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            }])
+        }
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let binddn_map = BTreeMap::from([
+        ("cn=user".to_string(), DnConfig::default()),
+        ("cn=other".to_string(), DnConfig::default()),
+    ]);
+    let pooled_app_state = |password: &str| {
+        let mut app_state = common::app_state(addr, connector.clone(), binddn_map.clone());
+        app_state.upstream_pool =
+            UpstreamPool::new(1, Duration::from_secs(60), Duration::from_secs(600))
+                .with_neutral_bind("cn=pool", password);
+        Arc::new(app_state)
+    };
+    let wait_for_binds = async |count: usize| {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while binds.lock().unwrap().len() != count {
+            assert!(
+                Instant::now() < deadline,
+                "backend never had {} binds",
+                count
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+
+    // The connection is bound as the neutral dn when its session unbinds, and
+    // another dn reuses it.
+    let app_state = pooled_app_state("pool-secret");
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+    client.send(2, LdapOp::UnbindRequest).await;
+    wait_for_binds(2).await;
+    while app_state.upstream_pool.len() != 1 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=other").await, LdapResultCode::Success);
+    assert_eq!(app_state.metrics.get("upstream_pool_hits_total", &[]), 1);
+    assert!(app_state.upstream_pool.is_empty());
+    assert_eq!(*binds.lock().unwrap(), ["cn=user", "cn=pool", "cn=other"]);
+    drop(client);
+    wait_for_binds(4).await;
+
+    // A connection that can't be bound as the neutral dn isn't pooled.
+    binds.lock().unwrap().clear();
+    let app_state = pooled_app_state("wrong");
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+    client.send(2, LdapOp::UnbindRequest).await;
+    wait_for_binds(2).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(app_state.upstream_pool.is_empty());
+}
+
+#[tokio::test]
+async fn test_max_session_operations() {
+    let (acceptor, connector) = common::tls_pair();
+    let searches = Arc::new(AtomicUsize::new(0));
+    let seen = searches.clone();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            // The first search is never answered.
+            LdapOp::SearchRequest(_) if seen.fetch_add(1, Ordering::SeqCst) == 0 => {
+                MockAction::Reply(vec![])
+            }
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            }]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.max_session_operations = Some(1);
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while searches.load(Ordering::SeqCst) == 0 {
+        assert!(Instant::now() < deadline, "backend never saw the search");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // The second search is refused while the first is in flight.
+    client.send(3, search_request()).await;
+    let msg = client.recv().await.expect("no response");
+    assert_eq!(msg.msgid, 3);
+    assert!(matches!(
+        msg.op,
+        LdapOp::SearchResultDone(LdapResult {
+            code: LdapResultCode::Busy,
+            ..
+        })
+    ));
+    assert_eq!(searches.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        app_state
+            .metrics
+            .get("session_operations_refused_total", &[]),
+        1
+    );
+
+    // Once the first is abandoned, and has stopped, the session may search
+    // again.
+    client.send(4, LdapOp::AbandonRequest(2)).await;
+    while app_state.metrics.get("searches_abandoned_total", &[]) == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    client.send(5, search_request()).await;
+    let msg = client.recv().await.expect("no response");
+    assert_eq!(msg.msgid, 5);
+    assert!(matches!(
+        msg.op,
+        LdapOp::SearchResultDone(LdapResult {
+            code: LdapResultCode::Success,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_filter_limits() {
+    let limits = FilterLimits {
+        max_depth: Some(2),
+        max_terms: Some(4),
+        max_substrings: Some(3),
+    };
+    let exceeded = |filter: &str| limits.exceeded_by(&parse_ldap_filter_str(filter).unwrap());
+    assert_eq!(exceeded("(&(uid=a)(!(cn=b)))"), Some("max_filter_depth"));
+    assert_eq!(exceeded("(|(uid=a)(uid=b)(uid=c))"), None);
+    assert_eq!(
+        exceeded("(|(uid=a)(uid=b)(uid=c)(uid=d))"),
+        Some("max_filter_terms")
+    );
+    assert_eq!(exceeded("(cn=a*b*c)"), None);
+    assert_eq!(exceeded("(cn=a*b*c*d)"), Some("max_filter_substrings"));
+    assert_eq!(
+        FilterLimits::default().exceeded_by(&parse_ldap_filter_str("(&(|(!(a=*))))").unwrap()),
+        None
+    );
+
+    let (acceptor, connector) = common::tls_pair();
+    let searches = Arc::new(AtomicUsize::new(0));
+    let seen = searches.clone();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => {
+                seen.fetch_add(1, Ordering::SeqCst);
+                MockAction::Reply(vec![LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                }])
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+    let binddn_map = BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+    let app_state = common::app_state(addr, connector, binddn_map);
+    app_state.update_policy(|policy| policy.filter_limits = limits);
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    let search = |filter: &str| {
+        let mut op = search_request();
+        if let LdapOp::SearchRequest(sr) = &mut op {
+            sr.filter = parse_ldap_filter_str(filter).unwrap();
+        }
+        op
+    };
+    client.send(2, search("(cn=a*b*c*d)")).await;
+    let msg = client.recv().await.expect("no response");
+    assert!(matches!(
+        msg.op,
+        LdapOp::SearchResultDone(LdapResult {
+            code: LdapResultCode::AdminLimitExceeded,
+            ..
+        })
+    ));
+    assert_eq!(searches.load(Ordering::SeqCst), 0);
+    assert_eq!(
+        app_state.metrics.get("searches_filter_limited_total", &[]),
+        1
+    );
+
+    client.send(3, search("(cn=a*b*c)")).await;
+    let msg = client.recv().await.expect("no response");
+    assert!(matches!(
+        msg.op,
+        LdapOp::SearchResultDone(LdapResult {
+            code: LdapResultCode::Success,
+            ..
+        })
+    ));
+    assert_eq!(searches.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_anonymous_bind_as_service_account() {
+    let (acceptor, connector) = common::tls_pair();
+    let binds = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let backend_binds = binds.clone();
+    let addr = common::mock_server(acceptor, move |msg| match msg.op {
+        // This backend refuses anonymous binds.
+        LdapOp::BindRequest(lbr) => {
+            let code = if lbr.dn.is_empty() {
+                LdapResultCode::InappropriateAuthentication
+            } else {
+                LdapResultCode::Success
+            };
+            backend_binds.lock().unwrap().push(lbr);
+            MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res: common::result(code),
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            }])
+        }
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let binddn_map = BTreeMap::from([
+        ("".to_string(), DnConfig::default()),
+        ("cn=user".to_string(), DnConfig::default()),
+    ]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    let service = LdapBindRequest {
+        dn: "cn=anonymous-svc,o=example".to_string(),
+        cred: LdapBindCred::Simple("secret".to_string()),
+    };
+    app_state.anonymous_bind = Some(service.clone());
+    let app_state = Arc::new(app_state);
+
+    let anonymous = LdapOp::BindRequest(LdapBindRequest {
+        dn: "".to_string(),
+        cred: LdapBindCred::Simple("".to_string()),
+    });
+    let mut client = common::connect(app_state.clone());
+    client.send(1, anonymous).await;
+    assert!(matches!(
+        client.recv().await,
+        Some(LdapMsg {
+            op: LdapOp::BindResponse(LdapBindResponse {
+                res: LdapResult {
+                    code: LdapResultCode::Success,
+                    ..
+                },
+                ..
+            }),
+            ..
+        })
+    ));
+
+    // Other binds are sent as they are, including an unauthenticated bind of
+    // the empty DN, which has a password.
+    assert_eq!(client.bind(2, "cn=user").await, LdapResultCode::Success);
+    assert_eq!(
+        client.bind(3, "").await,
+        LdapResultCode::InappropriateAuthentication
+    );
+    let binds = binds.lock().unwrap();
+    assert_eq!(binds[0], service);
+    assert_eq!(binds[1].dn, "cn=user");
+    assert_eq!(binds[2].dn, "");
+}
+
+#[tokio::test]
+async fn test_service_bind_shared_connection() {
+    let (acceptor, connector) = common::tls_pair();
+    let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let backend_events = events.clone();
+    let addr = common::mock_server(acceptor, move |msg| match msg.op {
+        LdapOp::BindRequest(lbr) => {
+            backend_events
+                .lock()
+                .unwrap()
+                .push(format!("bind {}", lbr.dn));
+            MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res: common::success(),
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            }])
+        }
+        LdapOp::SearchRequest(_) => {
+            backend_events.lock().unwrap().push("search".to_string());
+            MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            }])
+        }
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let binddn_map = BTreeMap::from([(
+        "cn=user".to_string(),
+        DnConfig {
+            allow_write: Some(true),
+            never_cache: true,
+            service_bind_dn: Some("cn=search-svc,o=example".to_string()),
+            service_bind_password: Some("secret".into()),
+            ..Default::default()
+        },
+    )]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    // Both sessions verify their credentials, and search over the one
+    // connection bound as the service account.
+    for _ in 0..2 {
+        let mut client = common::connect(app_state.clone());
+        assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+        client.send(2, search_request()).await;
+        assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+
+        // Writes would be made as the service account.
+        client
+            .send(3, LdapOp::DelRequest("uid=demo,o=example".to_string()))
+            .await;
+        assert!(matches!(
+            client.recv().await,
+            Some(LdapMsg {
+                op: LdapOp::DelResponse(LdapResult {
+                    code: LdapResultCode::UnwillingToPerform,
+                    ..
+                }),
+                ..
+            })
+        ));
+    }
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            "bind cn=user",
+            "bind cn=search-svc,o=example",
+            "search",
+            "bind cn=user",
+            "search",
+        ]
+    );
+}
+
+#[test]
+fn test_binddn_pattern_precedence() {
+    let config = toml::from_str::<Config>(&format!(
+        r#"{}
+[[binddn_patterns]]
+regex = "uid=[^,]+,ou=robots,dc=example,dc=com"
+allow_compare = true
+
+[[binddn_patterns]]
+glob = "uid=*,ou=robots,dc=example,dc=com"
+allow_write = true
+
+["uid=build,ou=robots,dc=example,dc=com"]
+"#,
+        MINIMAL_CONFIG
+    ))
+    .unwrap();
+
+    // A glob that matches wins over a regex listed before it.
+    let robot = config
+        .binddn_patterns
+        .lookup("uid=deploy,ou=robots,dc=example,dc=com")
+        .unwrap();
+    assert_eq!(robot.allow_write, Some(true));
+    assert!(!robot.allow_compare);
+
+    // And an exact entry wins over both.
+    let policy = Policy::from_config(&config);
+    let exact = policy
+        .dn_config("uid=build,ou=robots,dc=example,dc=com")
+        .unwrap();
+    assert_eq!(exact.allow_write, None);
+}
+
+#[tokio::test]
+async fn test_global_extended_oids() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::ExtendedRequest(ler) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                    res: common::success(),
+                    name: Some(ler.name),
+                    value: None,
+                }),
+                ctrl: vec![],
+            }]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=app".to_string(), DnConfig::default())]);
+    let app_state = common::app_state(addr, connector, binddn_map);
+    app_state.update_policy(|policy| {
+        policy.allowed_extended_oids = ["1.3.6.1.4.1.99999.1".to_string()].into_iter().collect()
+    });
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=app").await, LdapResultCode::Success);
+    for (msgid, oid, code) in [
+        (2, "1.3.6.1.4.1.99999.1", LdapResultCode::Success),
+        (3, "1.3.6.1.4.1.99999.2", LdapResultCode::ProtocolError),
+    ] {
+        client
+            .send(
+                msgid,
+                LdapOp::ExtendedRequest(LdapExtendedRequest {
+                    name: oid.to_string(),
+                    value: None,
+                }),
+            )
+            .await;
+        match client.recv().await.expect("no response").op {
+            LdapOp::ExtendedResponse(resp) => assert_eq!(resp.res.code, code, "{}", oid),
+            op => panic!("unexpected {:?}", op),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_server_side_sort() {
+    let (acceptor, connector) = common::tls_pair();
+    let sorts_forwarded = Arc::new(AtomicUsize::new(0));
+    let forwarded = sorts_forwarded.clone();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => {
+                if msg
+                    .ctrl
+                    .iter()
+                    .any(|c| matches!(c, LdapControl::ServerSort { .. }))
+                {
+                    forwarded.fetch_add(1, Ordering::SeqCst);
+                }
+                let entry = |uid: &str, cn: Option<&str>, number: &str| {
+                    let mut attributes = vec![LdapPartialAttribute {
+                        atype: "uidNumber".to_string(),
+                        vals: vec![number.as_bytes().to_vec()],
+                    }];
+                    attributes.extend(cn.map(|cn| LdapPartialAttribute {
+                        atype: "cn".to_string(),
+                        vals: vec![cn.as_bytes().to_vec()],
+                    }));
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: format!("uid={},o=example", uid),
+                            attributes,
+                        }),
+                        ctrl: vec![],
+                    }
+                };
+                MockAction::Reply(vec![
+                    entry("c", Some("Charlie"), "20"),
+                    entry("a", Some("alice"), "3"),
+                    entry("b", None, "100"),
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultDone(common::success()),
+                        ctrl: vec![],
+                    },
+                ])
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([(
+        "cn=addressbook".to_string(),
+        DnConfig {
+            denied_attrs: ["userPassword".to_string()].into_iter().collect(),
+            ..Default::default()
+        },
+    )]);
+    let mut app_state = common::app_state(addr, connector.clone(), binddn_map.clone());
+    app_state.backend_sorts = false;
+    let app_state = Arc::new(app_state);
+
+    let sort = |attribute_name: &str, ordering_rule: Option<&str>, reverse_order: bool| {
+        vec![LdapControl::ServerSort {
+            sort_requests: vec![ldap3_proto::control::ServerSortRequet {
+                attribute_name: attribute_name.to_string(),
+                ordering_rule: ordering_rule.map(str::to_string),
+                reverse_order,
+            }],
+        }]
+    };
+    // The DNs of the entries, and the result of the sort.
+    async fn recv_sorted(client: &mut common::TestClient) -> (Vec<String>, Option<LdapResultCode>) {
+        let mut dns = Vec::new();
+        loop {
+            let msg = client.recv().await.expect("no response");
+            match msg.op {
+                LdapOp::SearchResultEntry(entry) => dns.push(entry.dn),
+                LdapOp::SearchResultDone(_) => {
+                    let code = msg.ctrl.into_iter().find_map(|c| match c {
+                        LdapControl::ServerSortResult { sort_result } => {
+                            Some(sort_result.result_code)
+                        }
+                        _ => None,
+                    });
+                    break (dns, code);
+                }
+                op => panic!("unexpected {:?}", op),
+            }
+        }
+    }
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=addressbook").await,
+        LdapResultCode::Success
+    );
+
+    // By name, without regard to case. Entries without a name sort last.
+    client
+        .send_with_controls(2, search_request(), sort("cn", None, false))
+        .await;
+    assert_eq!(
+        recv_sorted(&mut client).await,
+        (
+            vec![
+                "uid=a,o=example".to_string(),
+                "uid=c,o=example".to_string(),
+                "uid=b,o=example".to_string()
+            ],
+            Some(LdapResultCode::Success)
+        )
+    );
+
+    // By number, highest first. This is answered from the cache.
+    client
+        .send_with_controls(
+            3,
+            search_request(),
+            sort("uidNumber", Some("integerOrderingMatch"), true),
+        )
+        .await;
+    let (dns, code) = recv_sorted(&mut client).await;
+    assert_eq!(
+        dns,
+        vec!["uid=b,o=example", "uid=c,o=example", "uid=a,o=example"]
+    );
+    assert_eq!(code, Some(LdapResultCode::Success));
+
+    // Attributes that may not be read are not sorted on.
+    client
+        .send_with_controls(4, search_request(), sort("userPassword", None, false))
+        .await;
+    let (dns, code) = recv_sorted(&mut client).await;
+    assert_eq!(
+        dns,
+        vec!["uid=c,o=example", "uid=a,o=example", "uid=b,o=example"]
+    );
+    assert_eq!(code, Some(LdapResultCode::InsufficentAccessRights));
+    assert_eq!(sorts_forwarded.load(Ordering::SeqCst), 0);
+
+    // A backend that sorts is sent the control.
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.backend_sorts = true;
+    let mut client = common::connect(Arc::new(app_state));
+    assert_eq!(
+        client.bind(1, "cn=addressbook").await,
+        LdapResultCode::Success
+    );
+    client
+        .send_with_controls(2, search_request(), sort("cn", None, false))
+        .await;
+    let (dns, _) = recv_sorted(&mut client).await;
+    assert_eq!(dns.len(), 3);
+    assert_eq!(sorts_forwarded.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_virtual_list_view() {
+    let (acceptor, connector) = common::tls_pair();
+    let searches = Arc::new(AtomicUsize::new(0));
+    let backend_searches = searches.clone();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => {
+                backend_searches.fetch_add(1, Ordering::SeqCst);
+                let mut responses: Vec<_> = ["dave", "alice", "erin", "carol", "bob"]
+                    .into_iter()
+                    .map(|cn| LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: format!("cn={},o=example", cn),
+                            attributes: vec![LdapPartialAttribute {
+                                atype: "cn".to_string(),
+                                vals: vec![cn.as_bytes().to_vec()],
+                            }],
+                        }),
+                        ctrl: vec![],
+                    })
+                    .collect();
+                responses.push(LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                });
+                MockAction::Reply(responses)
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=addressbook".to_string(), DnConfig::default())]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+    let mut client = common::connect(app_state);
+    assert_eq!(
+        client.bind(1, "cn=addressbook").await,
+        LdapResultCode::Success
+    );
+
+    let sort = common::ber(0x30, &common::ber(0x30, &common::ber(0x04, b"cn")));
+    let vlv = |target: Vec<u8>| {
+        let mut request = vec![0x02, 0x01, 0x01, 0x02, 0x01, 0x01];
+        request.extend(target);
+        common::ber(0x30, &request)
+    };
+    let vlv_response = |target_position: u8, content_count: u8, result: u8| {
+        let value = common::ber(
+            0x30,
+            &[
+                0x02,
+                0x01,
+                target_position,
+                0x02,
+                0x01,
+                content_count,
+                0x0a,
+                0x01,
+                result,
+            ],
+        );
+        let mut control = common::ber(0x04, b"2.16.840.1.113730.3.4.10");
+        control.extend(common::ber(0x04, &value));
+        common::ber(0x30, &control)
+    };
+    // The names of the entries, and the controls of the result.
+    async fn recv_window(client: &mut common::TestClient) -> (Vec<String>, Vec<u8>) {
+        let mut names = Vec::new();
+        loop {
+            match client.recv_with_raw_controls().await {
+                (
+                    LdapMsg {
+                        op: LdapOp::SearchResultEntry(entry),
+                        ..
+                    },
+                    _,
+                ) => names.push(entry.dn),
+                (
+                    LdapMsg {
+                        op: LdapOp::SearchResultDone(_),
+                        ..
+                    },
+                    controls,
+                ) => break (names, controls),
+                (msg, _) => panic!("unexpected {:?}", msg),
+            }
+        }
+    }
+
+    // The third of five entries, with one either side of it.
+    let by_offset = vlv(vec![0xa0, 0x06, 0x02, 0x01, 0x03, 0x02, 0x01, 0x00]);
+    client
+        .send_with_raw_controls(
+            2,
+            search_request(),
+            &[
+                (OID_SERVER_SORT, false, Some(&sort)),
+                (OID_VLV_REQUEST, true, Some(&by_offset)),
+            ],
+        )
+        .await;
+    let (names, controls) = recv_window(&mut client).await;
+    assert_eq!(
+        names,
+        vec![
+            "cn=bob,o=example",
+            "cn=carol,o=example",
+            "cn=dave,o=example"
+        ]
+    );
+    assert!(controls.ends_with(&vlv_response(3, 5, 0)));
+
+    // The first entry at or after "d", from the cache.
+    let by_value = vlv(common::ber(0x81, b"D"));
+    client
+        .send_with_raw_controls(
+            3,
+            search_request(),
+            &[
+                (OID_SERVER_SORT, false, Some(&sort)),
+                (OID_VLV_REQUEST, true, Some(&by_value)),
+            ],
+        )
+        .await;
+    let (names, controls) = recv_window(&mut client).await;
+    assert_eq!(
+        names,
+        vec![
+            "cn=carol,o=example",
+            "cn=dave,o=example",
+            "cn=erin,o=example"
+        ]
+    );
+    assert!(controls.ends_with(&vlv_response(4, 5, 0)));
+    assert_eq!(searches.load(Ordering::SeqCst), 1);
+
+    // A virtual list view must be sorted.
+    client
+        .send_with_raw_controls(
+            4,
+            search_request(),
+            &[(OID_VLV_REQUEST, true, Some(&by_offset))],
+        )
+        .await;
+    let (names, controls) = recv_window(&mut client).await;
+    assert!(names.is_empty());
+    assert_eq!(controls, vlv_response(0, 0, 60));
+}
+
+#[tokio::test]
+async fn test_persistent_search() {
+    let (acceptor, connector) = common::tls_pair();
+    let searches = Arc::new(AtomicUsize::new(0));
+    let abandons = Arc::new(AtomicUsize::new(0));
+    let (backend_searches, backend_abandons) = (searches.clone(), abandons.clone());
+    let change = RawControl {
+        oid: OID_ENTRY_CHANGE_NOTIFICATION.to_string(),
+        critical: false,
+        value: Some(vec![0x30, 0x03, 0x0a, 0x01, 0x04]),
+    };
+    let backend_change = change.clone();
+    let addr = common::mock_server_requests(acceptor, move |request| {
+        let msgid = request.msg.msgid;
+        match request.msg.op {
+            LdapOp::BindRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res: common::success(),
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            }]),
+            LdapOp::SearchRequest(_)
+                if request
+                    .raw_controls
+                    .iter()
+                    .any(|c| c.oid == OID_PERSISTENT_SEARCH && c.critical) =>
+            {
+                backend_searches.fetch_add(1, Ordering::SeqCst);
+                // The changes so far, and no result, as the search goes on.
+                let entry = |cn: &str| ClientResponse {
+                    msg: LdapMsg {
+                        msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: format!("cn={},o=example", cn),
+                            attributes: vec![],
+                        }),
+                        ctrl: vec![],
+                    },
+                    vlv: None,
+                    raw_controls: vec![backend_change.clone()],
+                };
+                MockAction::ReplyRaw(vec![entry("alice"), entry("bob")])
+            }
+            LdapOp::AbandonRequest(_) => {
+                backend_abandons.fetch_add(1, Ordering::SeqCst);
+                MockAction::Reply(vec![])
+            }
+            _ => MockAction::Disconnect,
+        }
+    })
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=watcher".to_string(), DnConfig::default())]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=watcher").await, LdapResultCode::Success);
+
+    // Any change, with entry change notifications.
+    let psearch = [
+        0x30, 0x09, 0x02, 0x01, 0x0f, 0x01, 0x01, 0xff, 0x01, 0x01, 0xff,
+    ];
+    for msgid in [2, 4] {
+        client
+            .send_with_raw_controls(
+                msgid,
+                search_request(),
+                &[(OID_PERSISTENT_SEARCH, true, Some(&psearch))],
+            )
+            .await;
+        for cn in ["alice", "bob"] {
+            let (msg, controls) = client.recv_with_raw_controls().await;
+            assert_eq!(msg.msgid, msgid);
+            match msg.op {
+                LdapOp::SearchResultEntry(entry) => {
+                    assert_eq!(entry.dn, format!("cn={},o=example", cn))
+                }
+                op => panic!("unexpected {:?}", op),
+            }
+            let mut expected = common::ber(0x04, change.oid.as_bytes());
+            expected.extend(common::ber(0x04, change.value.as_deref().unwrap()));
+            assert_eq!(controls, common::ber(0x30, &expected));
+        }
+
+        // Abandoning the search abandons it on the backend.
+        client.send(msgid + 1, LdapOp::AbandonRequest(msgid)).await;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while abandons.load(Ordering::SeqCst) < msgid as usize / 2 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+    assert_eq!(abandons.load(Ordering::SeqCst), 2);
+    // The results are never cached.
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_syncrepl() {
+    let (acceptor, connector) = common::tls_pair();
+    let searches = Arc::new(AtomicUsize::new(0));
+    let backend_searches = searches.clone();
+    let entry_uuid = uuid::Uuid::from_u128(1);
+    let addr = common::mock_server(acceptor, move |msg| match msg.op {
+        LdapOp::BindRequest(_) => MockAction::Reply(vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::BindResponse(LdapBindResponse {
+                res: common::success(),
+                saslcreds: None,
+            }),
+            ctrl: vec![],
+        }]),
+        LdapOp::SearchRequest(_)
+            if msg
+                .ctrl
+                .iter()
+                .any(|c| matches!(c, LdapControl::SyncRequest { .. })) =>
+        {
+            backend_searches.fetch_add(1, Ordering::SeqCst);
+            MockAction::Reply(vec![
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: "cn=alice,o=example".to_string(),
+                        attributes: vec![],
+                    }),
+                    ctrl: vec![LdapControl::SyncState {
+                        state: SyncStateValue::Add,
+                        entry_uuid,
+                        cookie: None,
+                    }],
+                },
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::IntermediateResponse(LdapIntermediateResponse::SyncInfoNewCookie {
+                        cookie: b"csn=1".to_vec(),
+                    }),
+                    ctrl: vec![],
+                },
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![LdapControl::SyncDone {
+                        cookie: Some(b"csn=2".to_vec()),
+                        refresh_deletes: false,
+                    }],
+                },
+            ])
+        }
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=replica".to_string(), DnConfig::default())]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=replica").await, LdapResultCode::Success);
+
+    for msgid in [2, 3] {
+        client
+            .send_with_controls(
+                msgid,
+                search_request(),
+                vec![LdapControl::SyncRequest {
+                    criticality: true,
+                    mode: SyncRequestMode::RefreshOnly,
+                    cookie: None,
+                    reload_hint: false,
+                }],
+            )
+            .await;
+        let msg = client.recv().await.expect("no entry");
+        assert!(matches!(msg.op, LdapOp::SearchResultEntry(_)));
+        assert!(matches!(
+            msg.ctrl.as_slice(),
+            [LdapControl::SyncState { entry_uuid: uuid, .. }] if *uuid == entry_uuid
+        ));
+        let msg = client.recv().await.expect("no intermediate response");
+        assert_eq!(
+            msg.op,
+            LdapOp::IntermediateResponse(LdapIntermediateResponse::SyncInfoNewCookie {
+                cookie: b"csn=1".to_vec(),
+            })
+        );
+        let msg = client.recv().await.expect("no result");
+        assert_eq!(msg.msgid, msgid);
+        assert!(
+            matches!(msg.op, LdapOp::SearchResultDone(ref res) if res.code == LdapResultCode::Success)
+        );
+        assert!(matches!(
+            msg.ctrl.as_slice(),
+            [LdapControl::SyncDone { cookie: Some(cookie), .. }] if cookie == b"csn=2"
+        ));
+    }
+    // The results depend on the cookie, so they are never cached.
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_upstream_pool_probe() {
+    let (acceptor, connector) = common::tls_pair();
+    let binds = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let probes = Arc::new(AtomicUsize::new(0));
+    let (backend_binds, backend_probes) = (binds.clone(), probes.clone());
+    let addr = common::mock_server(acceptor, move |msg| match msg.op {
+        LdapOp::BindRequest(lbr) => {
+            backend_binds.lock().unwrap().push(lbr.dn);
+            MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res: common::success(),
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            }])
+        }
+        // The first connection to be probed has been dropped by a firewall.
+        LdapOp::SearchRequest(sr) if sr.base.is_empty() => {
+            if backend_probes.fetch_add(1, Ordering::SeqCst) == 0 {
+                MockAction::Disconnect
+            } else {
+                MockAction::Reply(vec![LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                }])
+            }
+        }
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=user".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.upstream_pool =
+        UpstreamPool::new(1, Duration::from_secs(60), Duration::from_secs(600))
+            .with_neutral_bind("cn=pool", "pool-secret");
+    let app_state = Arc::new(app_state);
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+    client.send(2, LdapOp::UnbindRequest).await;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while app_state.upstream_pool.len() != 1 {
+        assert!(Instant::now() < deadline, "the connection was never pooled");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // The dead connection is replaced by a new one, bound as the neutral dn.
+    assert_eq!(probe_upstream_pool(&app_state).await, 1);
+    assert_eq!(app_state.upstream_pool.len(), 1);
+    assert_eq!(*binds.lock().unwrap(), ["cn=user", "cn=pool", "cn=pool"]);
+    assert_eq!(
+        app_state
+            .metrics
+            .get("upstream_pool_probe_failures_total", &[]),
+        1
+    );
+
+    // The new one answers, so it stays pooled, and a session takes it.
+    assert_eq!(probe_upstream_pool(&app_state).await, 0);
+    assert_eq!(probes.load(Ordering::SeqCst), 2);
+    assert_eq!(app_state.upstream_pool.len(), 1);
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+    assert_eq!(app_state.metrics.get("upstream_pool_hits_total", &[]), 1);
+    assert!(app_state.upstream_pool.is_empty());
+}
+
+#[tokio::test]
+async fn test_reconnect_routed_backend() {
+    let (acceptor, connector) = common::tls_pair();
+    let default = common::mock_server(
+        acceptor.clone(),
+        common::accept_binds(|_| MockAction::Disconnect),
+    )
+    .await;
+    let searches = Arc::new(AtomicUsize::new(0));
+    let tenant_searches = searches.clone();
+    let tenant = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            // The first search finds the backend has gone away.
+            LdapOp::SearchRequest(_) if tenant_searches.fetch_add(1, Ordering::SeqCst) == 0 => {
+                MockAction::Disconnect
+            }
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            }]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=app".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(default, connector.clone(), binddn_map);
+    app_state.backend_pools.insert(
+        "tenant".to_string(),
+        common::backend_pool("tenant", tenant, connector),
+    );
+    app_state.naming_contexts = vec![("ou=b,o=example".to_string(), "tenant".to_string())];
+    let app_state = Arc::new(app_state);
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=app").await, LdapResultCode::Success);
+
+    let mut sr = search_request();
+    if let LdapOp::SearchRequest(sr) = &mut sr {
+        sr.base = "ou=b,o=example".to_string();
+    }
+    client.send(2, sr).await;
+    let (entries, code) = recv_search(&mut client).await;
+    assert_eq!(entries, 0);
+    assert_eq!(code, LdapResultCode::Success);
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+    assert_eq!(
+        app_state
+            .metrics
+            .get("backend_reconnects_total", &[("pool", "tenant")]),
+        1
+    );
+}
+
+#[test]
+fn test_config_listeners() {
+    let config = toml::from_str::<Config>(
+        r#"
+bind = "0.0.0.0:636"
+tls_key = "/tmp/key.pem"
+tls_chain = "/tmp/chain.pem"
+ldap_ca = "/tmp/ca.pem"
+ldap_url = "ldaps://idm.example.com"
+
+[[listeners]]
+bind = "[::]:636"
+
+[[listeners]]
+bind = "0.0.0.0:389"
+mode = "starttls"
+"#,
+    )
+    .unwrap();
+    let listeners: Vec<_> = config
+        .listeners
+        .iter()
+        .map(|l| (l.bind.to_string(), l.mode))
+        .collect();
+    assert_eq!(
+        listeners,
+        [
+            ("[::]:636".to_string(), ListenerMode::Ldaps),
+            ("0.0.0.0:389".to_string(), ListenerMode::Starttls),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_per_dn_metrics() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: "cn=alice,o=example".to_string(),
+                        attributes: vec![],
+                    }),
+                    ctrl: vec![],
+                },
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                },
+            ]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=app".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.per_dn_metrics = true;
+    let app_state = Arc::new(app_state);
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=app").await, LdapResultCode::Success);
+    // The second search is answered from the cache.
+    for msgid in [2, 3] {
+        client.send(msgid, search_request()).await;
+        assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    }
+
+    let metrics = &app_state.metrics;
+    let dn = [("dn", "cn=app")];
+    assert_eq!(metrics.get("dn_binds_total", &dn), 1);
+    assert_eq!(metrics.get("dn_bind_failures_total", &dn), 0);
+    assert_eq!(metrics.get("dn_searches_total", &dn), 2);
+    assert_eq!(metrics.get("dn_search_entries_total", &dn), 2);
+    assert_eq!(metrics.get("dn_cache_hits_total", &dn), 1);
+    assert_eq!(metrics.get("dn_cache_misses_total", &dn), 1);
+    // Only the search that missed the cache went to the backend.
+    assert_eq!(metrics.get("dn_backend_search_duration_us_count", &dn), 1);
+    assert_eq!(
+        metrics.get(
+            "dn_backend_search_duration_us_bucket",
+            &[("dn", "cn=app"), ("le", "+Inf")]
+        ),
+        1
+    );
+    assert!(metrics
+        .snapshot()
+        .contains_key("dn_backend_search_duration_us_bucket{dn=\"cn=app\",le=\"5000000\"}"));
+}
+
+#[test]
+fn test_filter_redaction() {
+    let filter = parse_ldap_filter_str("(&(uid=alice)(cn=al*)(!(sn=Li)))").unwrap();
+    assert_eq!(
+        redacted_filter_string(&filter, FilterValues::Plain),
+        "(&(uid=alice)(cn=al*)(!(sn=Li)))"
+    );
+    assert_eq!(
+        redacted_filter_string(&filter, FilterValues::Mask),
+        "(&(uid=*)(cn=**)(!(sn=*)))"
+    );
+    assert_eq!(
+        redacted_filter_string(&filter, FilterValues::Truncate),
+        "(&(uid=ali...)(cn=al*)(!(sn=Li)))"
+    );
+
+    // The same value always hashes the same, and nothing of it is left.
+    let hashed = redacted_filter_string(&filter, FilterValues::Hash);
+    assert!(!hashed.contains("alice"), "{}", hashed);
+    let again = parse_ldap_filter_str("(uid=alice)").unwrap();
+    let uid = redacted_filter_string(&again, FilterValues::Hash);
+    assert_eq!(uid.len(), "(uid=#)".len() + 16);
+    assert!(hashed.contains(&uid), "{} {}", hashed, uid);
+
+    let config: AuditConfig = toml::from_str("syslog = true\nfilter_values = \"hash\"").unwrap();
+    assert_eq!(config.filter_values, Some(FilterValues::Hash));
+}
+
+#[test]
+fn test_config_fragments() {
+    let dir = std::env::temp_dir().join(format!("ldap-proxy-fragments-{}", std::process::id()));
+    let include = dir.join("conf.d");
+    std::fs::create_dir_all(&include).unwrap();
+    std::fs::write(
+        include.join("10-app.toml"),
+        r#"
+["cn=app,dc=example,dc=com"]
+allowed_queries = []
+
+[[binddn_patterns]]
+glob = "cn=svc-*,dc=example,dc=com"
+"#,
+    )
+    .unwrap();
+    std::fs::write(
+        include.join("20-other.toml"),
+        r#"
+["cn=other,dc=example,dc=com"]
+allow_compare = true
+"#,
+    )
+    .unwrap();
+    // Files that aren't TOML are ignored.
+    std::fs::write(include.join("README"), "not toml").unwrap();
+
+    let contents = format!(
+        r#"{}include_dir = "conf.d"
+
+["cn=main,dc=example,dc=com"]
+allowed_queries = []
+"#,
+        MINIMAL_CONFIG
+    );
+    let path = dir.join("config.toml");
+    let config = load_config(&contents, &path, Vec::new()).unwrap();
+    for dn in ["main", "app", "other"] {
+        let dn = format!("cn={},dc=example,dc=com", dn);
+        assert!(config.binddn_map.contains_key(&dn), "{}", dn);
+    }
+    assert_eq!(config.binddn_patterns.iter().count(), 1);
+
+    // A DN may only be configured once.
+    let duplicate = include.join("30-duplicate.toml");
+    std::fs::write(&duplicate, "[\"cn=main,dc=example,dc=com\"]\n").unwrap();
+    let err = load_config(&contents, &path, Vec::new()).unwrap_err();
+    assert_eq!(err.source, ConfigSource::File(duplicate.clone()));
+
+    // Other keys are refused.
+    std::fs::write(&duplicate, "allow_write = true\n").unwrap();
+    let err = load_config(&contents, &path, Vec::new()).unwrap_err();
+    assert_eq!(err.source, ConfigSource::File(duplicate));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_config_substitution() {
+    let dir = std::env::temp_dir().join(format!("ldap-proxy-substitution-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let secret = dir.join("svc");
+    std::fs::write(&secret, "from-file\n").unwrap();
+
+    let contents = format!(
+        r#"{}
+[cert_map.host1]
+bind_dn = "cn=${{SVC_NAME}},dc=example,dc=com"
+bind_password = "file:{}"
+
+[cert_map.host2]
+bind_dn = "cn=svc2"
+bind_password = "${{SVC2_PASSWORD}}$${{literal}}"
+"#,
+        MINIMAL_CONFIG,
+        secret.display()
+    );
+    let path = std::path::Path::new("/etc/ldap-proxy/config.toml");
+    let env = |vars: &[(&str, &str)]| {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>()
+    };
+
+    let config = load_config(
+        &contents,
+        path,
+        env(&[("SVC_NAME", "svc"), ("SVC2_PASSWORD", "from-env")]),
+    )
+    .unwrap();
+    let password = |key: &str| {
+        config.cert_map[key]
+            .bind_password
+            .as_ref()
+            .map(|p| p.expose().to_string())
+    };
+    assert_eq!(config.cert_map["host1"].bind_dn, "cn=svc,dc=example,dc=com");
+    assert_eq!(password("host1").as_deref(), Some("from-file"));
+    assert_eq!(password("host2").as_deref(), Some("from-env${literal}"));
+
+    // A variable that isn't set is an error that names it.
+    let err = load_config(&contents, path, env(&[("SVC_NAME", "svc")])).unwrap_err();
+    assert_eq!(err.source, ConfigSource::Env("SVC2_PASSWORD".to_string()));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_example_config() {
+    let example = example_config();
+    assert!(example.starts_with("# /data/config.toml"));
+    let config = load_config(
+        example,
+        std::path::Path::new("/etc/ldap-proxy/config.toml"),
+        Vec::new(),
+    )
+    .unwrap();
+    assert!(config.binddn_map.contains_key("cn=reader"));
+}
+
+#[tokio::test]
+async fn test_dry_run() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::SearchRequest(sr) => {
+                // The attributes are not restricted.
+                assert_eq!(sr.attrs, vec!["cn", "userPassword"]);
+                MockAction::Reply(vec![
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: "uid=demo,o=example".to_string(),
+                            attributes: ["cn", "userPassword"]
+                                .into_iter()
+                                .map(|atype| LdapPartialAttribute {
+                                    atype: atype.to_string(),
+                                    vals: vec![b"value".to_vec()],
+                                })
+                                .collect(),
+                        }),
+                        ctrl: vec![],
+                    },
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultDone(common::success()),
+                        ctrl: vec![],
+                    },
+                ])
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+    let config = DnConfig {
+        allowed_bases: vec!["ou=people,o=example".to_string()],
+        denied_attrs: ["userPassword".to_string()].into(),
+        dry_run: true,
+        ..Default::default()
+    };
+    let binddn_map = BTreeMap::from([("cn=sssd".to_string(), config)]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    let mut sr = search_request();
+    if let LdapOp::SearchRequest(sr) = &mut sr {
+        sr.base = "o=example".to_string();
+        sr.attrs = vec!["cn".to_string(), "userPassword".to_string()];
+    }
+    client.send(2, sr).await;
+    let msg = client.recv().await.expect("no response");
+    let LdapOp::SearchResultEntry(entry) = msg.op else {
+        panic!("unexpected {:?}", msg.op);
+    };
+    assert_eq!(entry.attributes.len(), 2);
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+
+    for check in ["base", "attrs"] {
+        assert_eq!(
+            app_state
+                .metrics
+                .get("searches_dry_run_denied_total", &[("check", check)]),
+            1,
+            "{}",
+            check
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_read_only() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr =
+        common::mock_server(acceptor, common::accept_binds(|_| MockAction::Disconnect)).await;
+
+    let binddn_map = BTreeMap::from([(
+        "cn=writer".to_string(),
+        DnConfig {
+            allow_write: Some(true),
+            allow_password_modify: true,
+            ..Default::default()
+        },
+    )]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+    app_state.update_policy(|policy| policy.read_only = true);
+
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=writer").await, LdapResultCode::Success);
+
+    client
+        .send(2, LdapOp::DelRequest("uid=demo,o=example".to_string()))
+        .await;
+    let msg = client.recv().await.expect("no response");
+    assert!(matches!(
+        msg.op,
+        LdapOp::DelResponse(LdapResult {
+            code: LdapResultCode::UnwillingToPerform,
+            ..
+        })
+    ));
+
+    client
+        .send(
+            3,
+            LdapOp::ExtendedRequest(
+                LdapPasswordModifyRequest {
+                    user_identity: None,
+                    old_password: Some("password".to_string()),
+                    new_password: Some("new".to_string()),
+                }
+                .into(),
+            ),
+        )
+        .await;
+    let msg = client.recv().await.expect("no response");
+    assert!(matches!(
+        msg.op,
+        LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResult {
+                code: LdapResultCode::UnwillingToPerform,
+                ..
+            },
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_relayed_search() {
+    let searches = Arc::new(AtomicUsize::new(0));
+    let c_searches = searches.clone();
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| {
+            let LdapOp::SearchRequest(sr) = &msg.op else {
+                return MockAction::Disconnect;
+            };
+            c_searches.fetch_add(1, Ordering::SeqCst);
+            // The base says how many entries there are.
+            let count = if sr.base == "o=large" { 5 } else { 1 };
+            let mut reply: Vec<_> = (0..count)
+                .map(|i| LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: format!("uid={},{}", i, sr.base),
+                        attributes: vec![],
+                    }),
+                    ctrl: vec![],
+                })
+                .collect();
+            reply.push(LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            });
+            MockAction::Reply(reply)
+        }),
+    )
+    .await;
+    let binddn_map = BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.max_cacheable_entries = Some(2);
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    let search = |base: &str| {
+        let mut sr = search_request();
+        if let LdapOp::SearchRequest(sr) = &mut sr {
+            sr.base = base.to_string();
+        }
+        sr
+    };
+
+    // A large search is relayed, and isn't cached.
+    for msgid in [2, 3] {
+        client.send(msgid, search("o=large")).await;
+        assert_eq!(recv_search(&mut client).await, (5, LdapResultCode::Success));
+    }
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+    assert_eq!(app_state.metrics.get("searches_relayed_total", &[]), 2);
+
+    // A small one is cached as usual.
+    for msgid in [4, 5] {
+        client.send(msgid, search("o=small")).await;
+        assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    }
+    assert_eq!(searches.load(Ordering::SeqCst), 3);
+    assert_eq!(app_state.metrics.get("searches_relayed_total", &[]), 2);
+}
+
+#[tokio::test]
+async fn test_backend_root_dse_restricted() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| {
+            let attr = |atype: &str, vals: &[&str]| LdapPartialAttribute {
+                atype: atype.to_string(),
+                vals: vals.iter().map(|v| v.as_bytes().to_vec()).collect(),
+            };
+            MockAction::Reply(vec![
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: "".to_string(),
+                        attributes: vec![
+                            attr(
+                                "supportedControl",
+                                &["1.2.840.113556.1.4.319", "1.3.6.1.4.1.4203.1.10.1"],
+                            ),
+                            attr(
+                                "supportedExtension",
+                                &["1.3.6.1.4.1.4203.1.11.1", "1.3.6.1.4.1.1466.20037"],
+                            ),
+                        ],
+                    }),
+                    ctrl: vec![],
+                },
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                },
+            ])
+        }),
+    )
+    .await;
+    let binddn_map = BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    let mut root_search = search_request();
+    if let LdapOp::SearchRequest(sr) = &mut root_search {
+        sr.base = "".to_string();
+        sr.scope = LdapSearchScope::Base;
+    }
+    client.send(2, root_search).await;
+    let entry = match client.recv().await.expect("no response").op {
+        LdapOp::SearchResultEntry(entry) => entry,
+        op => panic!("unexpected {:?}", op),
+    };
+    let values = |atype: &str| -> Vec<String> {
+        entry
+            .attributes
+            .iter()
+            .filter(|attr| attr.atype == atype)
+            .flat_map(|attr| attr.vals.iter())
+            .map(|v| String::from_utf8_lossy(v).to_string())
+            .collect()
+    };
+    // Controls that can't be relayed are removed, and those that the proxy
+    // handles are added.
+    assert_eq!(
+        values("supportedControl"),
+        vec![
+            "1.2.840.113556.1.4.319",
+            "1.2.840.113556.1.4.473",
+            "2.16.840.1.113730.3.4.9"
+        ]
+    );
+    // This DN may not modify passwords, and StartTLS is never possible.
+    assert_eq!(
+        values("supportedExtension"),
+        vec!["1.3.6.1.4.1.4203.1.11.3"]
+    );
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+}
+
+#[tokio::test]
+async fn test_schema_cache() {
+    let schema_searches = Arc::new(AtomicUsize::new(0));
+    let c_schema_searches = schema_searches.clone();
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| {
+            // The backend goes away after the second read of the schema.
+            if c_schema_searches.fetch_add(1, Ordering::SeqCst) >= 2 {
+                return MockAction::Disconnect;
+            }
+            let attr = |atype: &str, val: &str| LdapPartialAttribute {
+                atype: atype.to_string(),
+                vals: vec![val.as_bytes().to_vec()],
+            };
+            MockAction::Reply(vec![
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: "cn=Subschema".to_string(),
+                        attributes: vec![
+                            attr("attributeTypes", "( 2.5.4.3 NAME 'cn' )"),
+                            attr("objectClasses", "( 2.5.6.0 NAME 'top' )"),
+                        ],
+                    }),
+                    ctrl: vec![],
+                },
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                },
+            ])
+        }),
+    )
+    .await;
+    let mut schema_search = search_request();
+    if let LdapOp::SearchRequest(sr) = &mut schema_search {
+        sr.base = "cn=Subschema".to_string();
+        sr.scope = LdapSearchScope::Base;
+        sr.attrs = vec!["attributeTypes".to_string()];
+    }
+    let connect = |ttl: Duration| {
+        let binddn_map = BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+        let mut app_state = common::app_state(addr, connector.clone(), binddn_map);
+        app_state.schema = Some(SchemaCache::new("cn=subschema", ttl));
+        Arc::new(app_state)
+    };
+
+    // The schema is read once, and only the attributes asked for are returned.
+    let app_state = connect(Duration::from_secs(60));
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    for msgid in [2, 3] {
+        client.send(msgid, schema_search.clone()).await;
+        let LdapOp::SearchResultEntry(entry) = client.recv().await.expect("no response").op else {
+            panic!("expected the subschema subentry");
+        };
+        assert_eq!(entry.attributes.len(), 1);
+        assert_eq!(entry.attributes[0].atype, "attributeTypes");
+        assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    }
+    assert_eq!(schema_searches.load(Ordering::SeqCst), 1);
+    assert_eq!(app_state.metrics.get("schema_cache_hits_total", &[]), 1);
+
+    // An expired copy is used while the backend is unavailable.
+    let app_state = connect(Duration::ZERO);
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    for msgid in [2, 3] {
+        client.send(msgid, schema_search.clone()).await;
+        assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    }
+    assert_eq!(app_state.metrics.get("schema_cache_stale_total", &[]), 1);
+}
+
+// Refuses binds as cn=intern, and searches outside o=example by anyone.
+struct ExampleOnlyPolicy;
+
+impl AccessPolicy for ExampleOnlyPolicy {
+    fn bind<'a>(&'a self, bind: BindAccess<'a>) -> DecisionFuture<'a> {
+        Box::pin(async move {
+            if bind.dn == "cn=intern" {
+                Decision::Deny("interns may not bind".to_string())
+            } else {
+                Decision::Allow
+            }
+        })
+    }
+
+    fn search<'a>(&'a self, search: SearchAccess<'a>) -> DecisionFuture<'a> {
+        Box::pin(async move {
+            if search.request.base == "o=example" {
+                Decision::Allow
+            } else {
+                Decision::Deny("only o=example may be searched".to_string())
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_access_policy() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| {
+            MockAction::Reply(vec![
+                search_entry(msg.msgid, "uid=demo,o=example"),
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                },
+            ])
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([
+        ("cn=reader".to_string(), DnConfig::default()),
+        ("cn=intern".to_string(), DnConfig::default()),
+    ]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.access = Arc::new(ExampleOnlyPolicy);
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=intern").await,
+        LdapResultCode::InsufficentAccessRights
+    );
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=reader").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+
+    let LdapOp::SearchRequest(mut sr) = search_request() else {
+        unreachable!()
+    };
+    sr.base = "o=other".to_string();
+    client.send(3, LdapOp::SearchRequest(sr)).await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (0, LdapResultCode::InsufficentAccessRights)
+    );
+    assert_eq!(
+        app_state
+            .metrics
+            .get("access_policy_denied_total", &[("operation", "search")]),
+        1
+    );
+}
+
+// Searches for (uid=old) become (uid=new), searches of o=veto are refused,
+// entries of ou=hidden are dropped, and the description of the rest removed.
+struct ExampleHook;
+
+impl SearchHook for ExampleHook {
+    fn request<'a>(
+        &'a self,
+        _dn: &'a str,
+        request: &'a mut LdapSearchRequest,
+    ) -> DecisionFuture<'a> {
+        Box::pin(async move {
+            if request.base == "o=veto" {
+                return Decision::Deny("vetoed".to_string());
+            }
+            if request.filter == LdapFilter::Equality("uid".to_string(), "old".to_string()) {
+                request.filter = LdapFilter::Equality("uid".to_string(), "new".to_string());
+            }
+            Decision::Allow
+        })
+    }
+
+    fn entry(&self, _dn: &str, entry: &mut LdapSearchResultEntry) -> bool {
+        entry
+            .attributes
+            .retain(|attr| !attr.atype.eq_ignore_ascii_case("description"));
+        !entry.dn.ends_with("ou=hidden,o=example")
+    }
+}
+
+#[tokio::test]
+async fn test_search_hook() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| {
+            let LdapOp::SearchRequest(sr) = &msg.op else {
+                return MockAction::Disconnect;
+            };
+            // The filter that the backend was sent is the uid of an entry.
+            let LdapFilter::Equality(_, uid) = &sr.filter else {
+                return MockAction::Disconnect;
+            };
+            MockAction::Reply(vec![
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: format!("uid={},o=example", uid),
+                        attributes: vec![LdapPartialAttribute {
+                            atype: "description".to_string(),
+                            vals: vec![b"secret".to_vec()],
+                        }],
+                    }),
+                    ctrl: vec![],
+                },
+                search_entry(msg.msgid, "uid=x,ou=hidden,o=example"),
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                },
+            ])
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=reader".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.search_hook = Some(Arc::new(ExampleHook));
+    let mut client = common::connect(Arc::new(app_state));
+    assert_eq!(client.bind(1, "cn=reader").await, LdapResultCode::Success);
+
+    let search = |base: &str| {
+        LdapOp::SearchRequest(LdapSearchRequest {
+            base: base.to_string(),
+            scope: LdapSearchScope::Subtree,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Equality("uid".to_string(), "old".to_string()),
+            attrs: vec![],
+        })
+    };
+    client.send(2, search("o=example")).await;
+    let LdapOp::SearchResultEntry(entry) = client.recv().await.expect("no response").op else {
+        panic!("expected an entry");
+    };
+    assert_eq!(entry.dn, "uid=new,o=example");
+    assert!(entry.attributes.is_empty());
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+
+    client.send(3, search("o=veto")).await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (0, LdapResultCode::UnwillingToPerform)
+    );
+}
+
+#[tokio::test]
+async fn test_unexpected_messages() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr =
+        common::mock_server(acceptor, common::accept_binds(|_| MockAction::Disconnect)).await;
+    let app_state = Arc::new(common::app_state(addr, connector, BTreeMap::new()));
+    let mut client = common::connect(app_state);
+
+    // Requests before a bind are refused, and the session goes on.
+    client.send(1, search_request()).await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (0, LdapResultCode::OperationsError)
+    );
+    client.send(2, compare_request()).await;
+    let msg = client.recv().await.expect("no response");
+    assert!(matches!(
+        msg.op,
+        LdapOp::CompareResult(LdapResult {
+            code: LdapResultCode::OperationsError,
+            ..
+        })
+    ));
+
+    // A message that isn't a request ends it.
+    client
+        .send(3, LdapOp::SearchResultDone(common::success()))
+        .await;
+    let msg = client.recv().await.expect("no notice");
+    assert_eq!(msg.msgid, 0);
+    assert!(matches!(
+        msg.op,
+        LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResult {
+                code: LdapResultCode::ProtocolError,
+                ..
+            },
+            name: Some(_),
+            ..
+        })
+    ));
+    assert!(client.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn test_operation_timeout() {
+    let (acceptor, connector) = common::tls_pair();
+    // The backend never answers searches, or binds as cn=slow.
+    let addr = common::mock_server(acceptor, |msg: LdapMsg| match &msg.op {
+        LdapOp::BindRequest(lbr) if lbr.dn != "cn=slow" => MockAction::Reply(vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::BindResponse(LdapBindResponse {
+                res: common::success(),
+                saslcreds: None,
+            }),
+            ctrl: vec![],
+        }]),
+        _ => MockAction::Reply(vec![]),
+    })
+    .await;
+
+    let binddn_map = BTreeMap::from([
+        ("cn=reader".to_string(), DnConfig::default()),
+        ("cn=slow".to_string(), DnConfig::default()),
+    ]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state
+        .backend_pools
+        .get_mut(DEFAULT_BACKEND)
+        .expect("default pool")
+        .timeouts
+        .operation = Some(Duration::from_millis(200));
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=slow").await, LdapResultCode::Unavailable);
+
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=reader").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (0, LdapResultCode::TimeLimitExceeded)
+    );
+}
+
+#[test]
+fn test_oversized_response() {
+    // The header claims a 16MB response, more than the limit, and is refused
+    // before the rest has arrived.
+    let mut codec = BackendCodec::new(Some(1024 * 1024));
+    let mut buf = BytesMut::from(&[0x30, 0x84, 0x01, 0x00, 0x00, 0x00][..]);
+    assert!(codec.decode(&mut buf).is_err());
+
+    // One at the limit waits for the rest.
+    let mut codec = BackendCodec::new(Some(16 * 1024 * 1024 + 6));
+    let mut buf = BytesMut::from(&[0x30, 0x84, 0x01, 0x00, 0x00, 0x00][..]);
+    assert!(matches!(codec.decode(&mut buf), Ok(None)));
+}
+
+#[test]
+fn test_ad_bind_failure() {
+    let message = |data: &str| {
+        format!(
+            "80090308: LdapErr: DSID-0C09044E, comment: AcceptSecurityContext error, data {}, v4563\0",
+            data
+        )
+    };
+    assert_eq!(
+        AdBindFailure::parse(&message("52e")),
+        Some(AdBindFailure::InvalidCredentials)
+    );
+    assert_eq!(
+        AdBindFailure::parse(&message("775")),
+        Some(AdBindFailure::AccountLocked)
+    );
+    assert_eq!(
+        AdBindFailure::parse(&message("532")),
+        Some(AdBindFailure::PasswordExpired)
+    );
+    assert_eq!(AdBindFailure::parse(&message("999")), None);
+    assert_eq!(AdBindFailure::parse("invalid credentials"), None);
+    // Unknown users look like bad passwords to the client.
+    assert_eq!(
+        AdBindFailure::NoSuchUser.message(),
+        AdBindFailure::InvalidCredentials.message()
+    );
+}
+
+#[tokio::test]
+async fn test_ad_compat() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(acceptor, |msg: LdapMsg| match &msg.op {
+        LdapOp::BindRequest(_) => MockAction::Reply(vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::BindResponse(LdapBindResponse {
+                res: LdapResult {
+                    message: "80090308: LdapErr: DSID-0C09044E, comment: AcceptSecurityContext error, data 775, v4563".to_string(),
+                    ..common::result(LdapResultCode::InvalidCredentials)
+                },
+                saslcreds: None,
+            }),
+            ctrl: vec![],
+        }]),
+        _ => MockAction::Disconnect,
+    })
+    .await;
+
+    let path = std::env::temp_dir().join(format!("ldap-proxy-ad-audit-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let audit = AuditLog::open(&AuditConfig {
+        path: Some(path.clone()),
+        syslog: false,
+        redact_filter_values: false,
+        filter_values: None,
+    })
+    .unwrap();
+    let binddn_map = BTreeMap::from([("cn=user".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.ad_compat = true;
+    app_state.audit = Some(Arc::new(audit));
+    let app_state = Arc::new(app_state);
+    let mut client = common::connect(app_state.clone());
+
+    client
+        .send(
+            1,
+            LdapOp::BindRequest(LdapBindRequest {
+                dn: "cn=user".to_string(),
+                cred: LdapBindCred::Simple("password".to_string()),
+            }),
+        )
+        .await;
+    let Some(LdapMsg {
+        op: LdapOp::BindResponse(resp),
+        ..
+    }) = client.recv().await
+    else {
+        panic!("no bind response");
+    };
+    assert_eq!(resp.res.code, LdapResultCode::InvalidCredentials);
+    assert_eq!(resp.res.message, "account locked");
+    assert_eq!(
+        app_state
+            .metrics
+            .get("ad_bind_failures_total", &[("reason", "account_locked")]),
+        1
+    );
+
+    let mut records = Vec::new();
+    for _ in 0..50 {
+        let log = std::fs::read_to_string(&path).unwrap_or_default();
+        records = log
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect();
+        if !records.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["result"], "InvalidCredentials");
+    assert_eq!(records[0]["reason"], "account_locked");
+}
+
+#[tokio::test]
+async fn test_global_catalog_routing() {
+    // Each backend names itself in the entry that it returns.
+    let backend = |name: &'static str| {
+        common::accept_binds(move |msg| {
+            MockAction::Reply(vec![
+                search_entry(msg.msgid, &format!("cn={},dc=corp,dc=example", name)),
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                },
+            ])
+        })
+    };
+    let (acceptor, connector) = common::tls_pair();
+    let domain = common::mock_server(acceptor.clone(), backend("domain")).await;
+    let gc = common::mock_server(acceptor, backend("gc")).await;
+
+    let binddn_map = BTreeMap::from([("cn=app".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(domain, connector.clone(), binddn_map);
+    app_state
+        .backend_pools
+        .insert("gc".to_string(), common::backend_pool("gc", gc, connector));
+    app_state.global_catalogs = vec![("dc=corp,dc=example".to_string(), "gc".to_string())];
+    let mut client = common::connect(Arc::new(app_state));
+    assert_eq!(client.bind(1, "cn=app").await, LdapResultCode::Success);
+
+    let search = |base: &str, scope: LdapSearchScope| {
+        LdapOp::SearchRequest(LdapSearchRequest {
+            base: base.to_string(),
+            scope,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: vec![],
+        })
+    };
+    for (msgid, base, scope, expected) in [
+        (2, "DC=corp,DC=example", LdapSearchScope::Subtree, "gc"),
+        (3, "dc=corp,dc=example", LdapSearchScope::Base, "domain"),
+        (
+            4,
+            "dc=child,dc=corp,dc=example",
+            LdapSearchScope::Subtree,
+            "domain",
+        ),
+    ] {
+        client.send(msgid, search(base, scope)).await;
+        assert_eq!(
+            recv_search_refs(&mut client).await,
+            (vec![format!("cn={},dc=corp,dc=example", expected)], vec![])
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_fan_out_search() {
+    let backend = |dns: &'static [&'static str]| {
+        common::accept_binds(move |msg| {
+            let mut replies: Vec<_> = dns.iter().map(|dn| search_entry(msg.msgid, dn)).collect();
+            replies.push(LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(common::success()),
+                ctrl: vec![],
+            });
+            MockAction::Reply(replies)
+        })
+    };
+    let (acceptor, connector) = common::tls_pair();
+    let primary = common::mock_server(
+        acceptor.clone(),
+        backend(&["uid=shared,o=example", "uid=a,o=example"]),
+    )
+    .await;
+    let other = common::mock_server(
+        acceptor,
+        backend(&["UID=Shared,O=Example", "uid=b,o=example"]),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=app".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(primary, connector.clone(), binddn_map);
+    app_state.backend_pools.insert(
+        "other".to_string(),
+        common::backend_pool("other", other, connector),
+    );
+    app_state.fan_out = vec!["other".to_string()];
+    let mut client = common::connect(Arc::new(app_state));
+    assert_eq!(client.bind(1, "cn=app").await, LdapResultCode::Success);
+
+    // The entry that both directories hold is sent once, as the session's own
+    // backend has it.
+    client.send(2, search_request()).await;
+    assert_eq!(
+        recv_search_refs(&mut client).await,
+        (
+            vec![
+                "uid=shared,o=example".to_string(),
+                "uid=a,o=example".to_string(),
+                "uid=b,o=example".to_string(),
+            ],
+            vec![]
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_password_policy() {
+    // Expires in an hour, and the account is locked.
+    let expiring = [0x30, 0x06, 0xa0, 0x04, 0x80, 0x02, 0x0e, 0x10];
+    let locked = [0x30, 0x03, 0x81, 0x01, 0x01];
+    assert_eq!(
+        password_policy_response(&expiring),
+        Some(PasswordPolicyResponse {
+            warning: Some(PolicyWarning::TimeBeforeExpiration(3600)),
+            error: None,
+        })
+    );
+    assert_eq!(
+        password_policy_response(&locked),
+        Some(PasswordPolicyResponse {
+            warning: None,
+            error: Some(PolicyError::AccountLocked),
+        })
+    );
+
+    let (acceptor, connector) = common::tls_pair();
+    let requested = Arc::new(AtomicUsize::new(0));
+    let backend_requested = requested.clone();
+    let addr = common::mock_server(acceptor, move |msg| {
+        let LdapOp::BindRequest(lbr) = &msg.op else {
+            return MockAction::Disconnect;
+        };
+        if msg
+            .ctrl
+            .iter()
+            .any(|c| matches!(c, LdapControl::PasswordPolicyRequest { .. }))
+        {
+            backend_requested.fetch_add(1, Ordering::SeqCst);
+        }
+        let (code, value) = match lbr.dn.as_str() {
+            "cn=locked" => (LdapResultCode::InvalidCredentials, locked.to_vec()),
+            _ => (LdapResultCode::Success, expiring.to_vec()),
+        };
+        MockAction::ReplyRaw(vec![ClientResponse {
+            msg: LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res: common::result(code),
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            },
+            vlv: None,
+            raw_controls: vec![RawControl {
+                oid: OID_PASSWORD_POLICY.to_string(),
+                critical: false,
+                value: Some(value),
+            }],
+        }])
+    })
+    .await;
+
+    let binddn_map = BTreeMap::from([
+        ("cn=expiring".to_string(), DnConfig::default()),
+        ("cn=locked".to_string(), DnConfig::default()),
+    ]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    // The response control reaches the client as the backend sent it.
+    for (dn, code, value) in [
+        ("cn=expiring", LdapResultCode::Success, expiring.as_slice()),
+        (
+            "cn=locked",
+            LdapResultCode::InvalidCredentials,
+            locked.as_slice(),
+        ),
+    ] {
+        let mut client = common::connect(app_state.clone());
+        let lbr = LdapBindRequest {
+            dn: dn.to_string(),
+            cred: LdapBindCred::Simple("password".to_string()),
+        };
+        client
+            .send_with_controls(
+                1,
+                LdapOp::BindRequest(lbr),
+                vec![LdapControl::PasswordPolicyRequest { criticality: false }],
+            )
+            .await;
+        let (msg, controls) = client.recv_with_raw_controls().await;
+        match msg.op {
+            LdapOp::BindResponse(resp) => assert_eq!(resp.res.code, code),
+            op => panic!("unexpected {:?}", op),
+        }
+        let mut expected = common::ber(0x04, OID_PASSWORD_POLICY.as_bytes());
+        expected.extend(common::ber(0x04, value));
+        assert_eq!(controls, common::ber(0x30, &expected));
+    }
+    assert_eq!(requested.load(Ordering::SeqCst), 2);
+    assert_eq!(
+        app_state.metrics.get(
+            "password_policy_warnings_total",
+            &[("warning", "password_expiring")]
+        ),
+        1
+    );
+    assert_eq!(
+        app_state.metrics.get(
+            "password_policy_errors_total",
+            &[("error", "account_locked")]
+        ),
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_tcp_options() {
+    let config = toml::from_str::<Config>(
+        r#"
+bind = "127.0.0.1:3636"
+tls_key = "/tmp/key.pem"
+tls_chain = "/tmp/chain.pem"
+ldap_ca = "/tmp/ca.pem"
+ldap_url = "ldaps://a.example.com"
+tcp_keepalive_secs = 300
+client_tcp_keepalive_secs = 60
+tcp_send_buffer_bytes = 65536
+
+[backends.wan]
+ldap_url = "ldaps://far.example.com"
+tcp_nodelay = false
+tcp_recv_buffer_bytes = 262144
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        config.client_tcp(),
+        TcpOptions {
+            keepalive: Some(Duration::from_secs(60)),
+            nodelay: true,
+            send_buffer: Some(65536),
+            recv_buffer: None,
+        }
+    );
+    // Backends inherit the options they don't set.
+    let wan = TcpOptions {
+        keepalive: Some(Duration::from_secs(300)),
+        nodelay: false,
+        send_buffer: Some(65536),
+        recv_buffer: Some(262144),
+    };
+    assert_eq!(config.backend_tcp(Some(&config.backends["wan"])), wan);
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    wan.apply(&stream);
+    assert!(!stream.nodelay().unwrap());
+    TcpOptions::default().apply(&stream);
+    assert!(stream.nodelay().unwrap());
+}
+
+#[tokio::test]
+async fn test_intermediate_responses() {
+    let progress = |msgid: i32, step: &str| LdapMsg {
+        msgid,
+        op: LdapOp::IntermediateResponse(LdapIntermediateResponse::Raw {
+            name: Some("1.3.6.1.4.1.99999.2".to_string()),
+            value: Some(step.as_bytes().to_vec()),
+        }),
+        ctrl: vec![],
+    };
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::ExtendedRequest(ler) => MockAction::Reply(vec![
+                progress(msg.msgid, "1"),
+                progress(msg.msgid, "2"),
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                        res: common::success(),
+                        name: Some(ler.name),
+                        value: None,
+                    }),
+                    ctrl: vec![],
+                },
+            ]),
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![
+                search_entry(msg.msgid, "cn=alice,o=example"),
+                progress(msg.msgid, "1"),
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                },
+            ]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=app".to_string(), DnConfig::default())]);
+    let app_state = common::app_state(addr, connector, binddn_map);
+    app_state.update_policy(|policy| {
+        policy.allowed_extended_oids = ["1.3.6.1.4.1.99999.1".to_string()].into_iter().collect()
+    });
+    let mut client = common::connect(Arc::new(app_state));
+    assert_eq!(client.bind(1, "cn=app").await, LdapResultCode::Success);
+
+    // They are relayed in order, before the result.
+    client
+        .send(
+            2,
+            LdapOp::ExtendedRequest(LdapExtendedRequest {
+                name: "1.3.6.1.4.1.99999.1".to_string(),
+                value: None,
+            }),
+        )
+        .await;
+    for step in ["1", "2"] {
+        assert_eq!(client.recv().await, Some(progress(2, step)));
+    }
+    match client.recv().await.expect("no response").op {
+        LdapOp::ExtendedResponse(resp) => assert_eq!(resp.res.code, LdapResultCode::Success),
+        op => panic!("unexpected {:?}", op),
+    }
+
+    client.send(3, search_request()).await;
+    assert!(matches!(
+        client.recv().await.expect("no entry").op,
+        LdapOp::SearchResultEntry(_)
+    ));
+    assert_eq!(client.recv().await, Some(progress(3, "1")));
+    match client.recv().await.expect("no result").op {
+        LdapOp::SearchResultDone(res) => assert_eq!(res.code, LdapResultCode::Success),
+        op => panic!("unexpected {:?}", op),
+    }
+}
+
+#[test]
+fn test_config_profiles() {
+    let path = std::path::Path::new("/etc/ldap-proxy/config.toml");
+    let base = r#"
+bind = "127.0.0.1:3636"
+tls_key = "/tmp/key.pem"
+tls_chain = "/tmp/chain.pem"
+ldap_ca = "/tmp/ca.pem"
+ldap_url = "ldaps://a.example.com"
+
+[profiles.readonly-app]
+allowed_bases = ["ou=people,o=example"]
+allow_compare = true
+cache_ttl_secs = 60
+
+["cn=app1"]
+profile = "readonly-app"
+
+["cn=app2"]
+profile = "readonly-app"
+cache_ttl_secs = 5
+
+[[binddn_patterns]]
+glob = "cn=svc-*"
+profile = "readonly-app"
+"#;
+    let config = load_config(base, path, Vec::new()).unwrap();
+    assert!(!config.binddn_map.contains_key("profiles"));
+    let app1 = &config.binddn_map["cn=app1"];
+    assert_eq!(app1.allowed_bases, ["ou=people,o=example"]);
+    assert!(app1.allow_compare);
+    assert_eq!(app1.cache_ttl_secs, Some(60));
+    // An entry's own settings take precedence over its profile's.
+    let app2 = &config.binddn_map["cn=app2"];
+    assert!(app2.allow_compare);
+    assert_eq!(app2.cache_ttl_secs, Some(5));
+    let svc = config.binddn_patterns.lookup("cn=svc-backup").unwrap();
+    assert!(svc.allow_compare);
+
+    let err = load_config(
+        &base.replace(
+            "profile = \"readonly-app\"\ncache",
+            "profile = \"missing\"\ncache",
+        ),
+        path,
+        Vec::new(),
+    )
+    .unwrap_err();
+    assert_eq!(err.message, "there is no profile named missing");
+}
+
+#[tokio::test]
+async fn test_secret_providers() {
+    let command = SecretProvider {
+        source: SecretSource::Command {
+            command: vec!["echo".to_string(), "from-command".to_string()],
+        },
+        cache_secs: 300,
+    };
+    assert_eq!(command.fetch().await.unwrap().expose(), "from-command");
+
+    // A Vault style answer, with the password in its JSON, from an ipv6
+    // address.
+    let listener = tokio::net::TcpListener::bind("[::1]:0").await.unwrap();
+    let http_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = vec![0; 1024];
+        let len = stream.read(&mut request).await.unwrap();
+        let request = String::from_utf8_lossy(&request[..len]).to_string();
+        assert!(request.starts_with("GET /v1/secret/data/ldap HTTP/1.1\r\n"));
+        assert!(request.contains(&format!("Host: [::1]:{}\r\n", http_addr.port())));
+        assert!(request.contains("X-Vault-Token: token\r\n"));
+        let body = r#"{"data":{"data":{"password":"from-vault"}}}"#;
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+    });
+    let http = SecretProvider {
+        source: SecretSource::Http {
+            url: format!("http://{}/v1/secret/data/ldap", http_addr)
+                .parse()
+                .unwrap(),
+            headers: BTreeMap::from([("X-Vault-Token".to_string(), "token".into())]),
+            pointer: Some("/data/data/password".to_string()),
+            ca: None,
+            allow_http: true,
+        },
+        cache_secs: 300,
+    };
+    assert_eq!(http.fetch().await.unwrap().expose(), "from-vault");
+
+    // The token would be sent in the clear, so http has to be allowed.
+    let mut refused = http.clone();
+    if let SecretSource::Http { allow_http, .. } = &mut refused.source {
+        *allow_http = false;
+    }
+    assert_eq!(
+        refused.fetch().await.unwrap_err(),
+        "http urls are refused unless allow_http is set"
+    );
+
+    // Answers are read up to a limit.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let http_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        use tokio::io::AsyncWriteExt;
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let body = "x".repeat(128 * 1024);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let _ = stream.write_all(response.as_bytes()).await;
+    });
+    let large = SecretProvider {
+        source: SecretSource::Http {
+            url: format!("http://{}/large", http_addr).parse().unwrap(),
+            headers: BTreeMap::new(),
+            pointer: None,
+            ca: None,
+            allow_http: true,
+        },
+        cache_secs: 300,
+    };
+    assert_eq!(
+        large.fetch().await.unwrap_err(),
+        "the response is larger than 65536 bytes"
+    );
+
+    // The service account's password is rotated, and the proxy fetches it
+    // again once the backend refuses the one it has.
+    let path = std::env::temp_dir().join(format!("ldap-proxy-provider-{}", std::process::id()));
+    std::fs::write(&path, "old\n").unwrap();
+    let (acceptor, connector) = common::tls_pair();
+    let binds = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let backend_binds = binds.clone();
+    let addr = common::mock_server(acceptor, move |msg| match msg.op {
+        LdapOp::BindRequest(lbr) => {
+            let LdapBindCred::Simple(password) = &lbr.cred else {
+                return MockAction::Disconnect;
+            };
+            let mut res = common::success();
+            if lbr.dn == "cn=search-svc,o=example" {
+                backend_binds.lock().unwrap().push(password.clone());
+                if password != "new" {
+                    res.code = LdapResultCode::InvalidCredentials;
+                }
+            }
+            MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res,
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            }])
+        }
+        LdapOp::SearchRequest(_) => MockAction::Reply(vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::SearchResultDone(common::success()),
+            ctrl: vec![],
+        }]),
+        _ => MockAction::Disconnect,
+    })
+    .await;
+    let binddn_map = BTreeMap::from([(
+        "cn=user".to_string(),
+        DnConfig {
+            never_cache: true,
+            service_bind_dn: Some("cn=search-svc,o=example".to_string()),
+            service_bind_password_provider: Some(SecretProvider {
+                source: SecretSource::File { path: path.clone() },
+                cache_secs: 300,
+            }),
+            ..Default::default()
+        },
+    )]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    // The first session falls back to its own connection. Its search is
+    // answered once the bind has been dealt with.
+    for password in ["old", "new"] {
+        std::fs::write(&path, format!("{}\n", password)).unwrap();
+        let mut client = common::connect(app_state.clone());
+        assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+        client.send(2, search_request()).await;
+        assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    }
+    assert_eq!(*binds.lock().unwrap(), vec!["old", "new"]);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_backend_latency() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr =
+        common::mock_server(acceptor, common::accept_binds(|_| MockAction::Disconnect)).await;
+    let app_state = common::app_state(addr, connector.clone(), BTreeMap::new());
+
+    // A connection and its bind are measured.
+    let pool = &app_state.backend_pools[DEFAULT_BACKEND];
+    let client = BasicLdapClient::connect(&app_state, pool).await.unwrap();
+    let lbr = LdapBindRequest {
+        dn: "cn=user".to_string(),
+        cred: LdapBindCred::Simple("password".to_string()),
+    };
+    client.bind(lbr, vec![]).await.unwrap();
+    let latency = pool.backends[0].latency().get(&addr).unwrap();
+    for stage in [Stage::Connect, Stage::Handshake, Stage::Bind] {
+        assert_eq!(latency.counts(stage), (1, 0));
+        assert!(latency.average_us(stage).is_some());
+    }
+    assert_eq!(latency.error_rate, 0.0);
+
+    let (status, body) = admin_request(&app_state, "GET", "/backends", "secret").await;
+    assert_eq!(status, 200);
+    let address = &body[DEFAULT_BACKEND][0]["addresses"][0];
+    assert_eq!(address["latency"]["bind"]["attempts"], 1);
+    let (_, metrics) = admin_request(&app_state, "GET", "/metrics", "secret").await;
+    let key = format!(
+        "backend_address_attempts_total{{pool=\"default\",backend=\"{}\",address=\"{}\",stage=\"connect\"}}",
+        pool.backends[0].url, addr
+    );
+    assert_eq!(metrics[key], 1);
+
+    // The slow backend is rarely chosen, but is still the fallback.
+    let addrs = |port: u16| vec![std::net::SocketAddr::from(([127, 0, 0, 1], port))];
+    let backend = |port: u16| {
+        Backend::new(
+            url::Url::parse(&format!("ldaps://localhost:{}", port)).unwrap(),
+            "localhost".to_string(),
+            port,
+            addrs(port),
+        )
+    };
+    let pool = BackendPool::new(
+        "test",
+        connector,
+        vec![backend(1), backend(2), backend(3)],
+        BackendStrategy::Latency,
+    );
+    let record = |i: usize, ms: u64| {
+        let latency = pool.backends[i].latency();
+        latency.record(
+            addrs(i as u16 + 1)[0],
+            Stage::Connect,
+            Some(Duration::from_millis(ms)),
+        );
+    };
+    record(0, 200);
+    record(1, 1);
+    record(2, 2);
+    let firsts = (0..100).filter(|_| pool.order()[0].port == 1).count();
+    assert!(firsts < 10);
+    assert_eq!(pool.order().last().unwrap().port, 1);
+
+    // Failures make a fast backend slow.
+    for _ in 0..10 {
+        let latency = pool.backends[1].latency();
+        latency.record(addrs(2)[0], Stage::Connect, None);
+    }
+    let firsts: Vec<_> = (0..200).map(|_| pool.order()[0].port).collect();
+    let count = |port| firsts.iter().filter(|first| **first == port).count();
+    assert!(count(3) > 2 * count(2));
+}
+
+#[tokio::test]
+async fn test_hard_entry_limit() {
+    let (acceptor, connector) = common::tls_pair();
+    let sizelimits = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let backend_sizelimits = sizelimits.clone();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(sr) => {
+                backend_sizelimits.lock().unwrap().push(sr.sizelimit);
+                let mut msgs: Vec<_> = (0..5)
+                    .map(|i| LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: format!("uid=user{},o=example", i),
+                            attributes: vec![],
+                        }),
+                        ctrl: vec![],
+                    })
+                    .collect();
+                msgs.push(LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                });
+                MockAction::Reply(msgs)
+            }
+            LdapOp::AbandonRequest(_) => MockAction::Reply(vec![]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([
+        (
+            "cn=careless".to_string(),
+            DnConfig {
+                hard_entry_limit: Some(2),
+                ..Default::default()
+            },
+        ),
+        ("cn=sssd".to_string(), DnConfig::default()),
+    ]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=careless").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (2, LdapResultCode::SizeLimitExceeded)
+    );
+
+    // Other DNs aren't limited, and the backend never saw the limit.
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (5, LdapResultCode::Success));
+    assert_eq!(*sizelimits.lock().unwrap(), vec![0, 0]);
+}
+
+#[tokio::test]
+async fn test_hard_entry_limit_paged() {
+    let (acceptor, connector) = common::tls_pair();
+    let searches = Arc::new(AtomicUsize::new(0));
+    let seen = searches.clone();
+    // Five entries, in pages of two, or all at once without paging.
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => {
+                seen.fetch_add(1, Ordering::SeqCst);
+                let (names, paged): (&[&str], _) = match paged_results_cookie(&msg.ctrl) {
+                    Some(b"") => (&["cn=a", "cn=b"], Some(b"1".to_vec())),
+                    Some(b"1") => (&["cn=c", "cn=d"], Some(b"2".to_vec())),
+                    Some(b"2") => (&["cn=e"], Some(vec![])),
+                    Some(_) => return MockAction::Disconnect,
+                    None => (&["cn=a", "cn=b", "cn=c", "cn=d", "cn=e"], None),
+                };
+                let mut msgs: Vec<_> = names
+                    .iter()
+                    .map(|name| search_entry(msg.msgid, &format!("{},o=example", name)))
+                    .collect();
+                msgs.push(LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: paged
+                        .map(|cookie| LdapControl::SimplePagedResults { size: 5, cookie })
+                        .into_iter()
+                        .collect(),
+                });
+                MockAction::Reply(msgs)
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([
+        (
+            "cn=careless".to_string(),
+            DnConfig {
+                hard_entry_limit: Some(3),
+                ..Default::default()
+            },
+        ),
+        ("cn=sssd".to_string(), DnConfig::default()),
+    ]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    // Read every page of the search, returning the number of entries on each
+    // and the result of the last.
+    let mut msgid = 1;
+    let mut paged_search = async |client: &mut common::TestClient| {
+        let mut pages = Vec::new();
+        let mut cookie = Vec::new();
+        loop {
+            msgid += 1;
+            let paged = LdapControl::SimplePagedResults { size: 2, cookie };
+            client
+                .send_with_controls(msgid, search_request(), vec![paged])
+                .await;
+            let mut entries = 0;
+            let (code, done) = loop {
+                let msg = client.recv().await.expect("no response");
+                match msg.op {
+                    LdapOp::SearchResultEntry(_) => entries += 1,
+                    LdapOp::SearchResultDone(res) => break (res.code, msg.ctrl),
+                    op => panic!("unexpected {:?}", op),
+                }
+            };
+            pages.push(entries);
+            cookie = paged_results_cookie(&done)
+                .expect("no paged control")
+                .to_vec();
+            if cookie.is_empty() {
+                break (pages, code);
+            }
+        }
+    };
+
+    // The limit counts across the pages, and the search that went over it
+    // isn't cached.
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=careless").await, LdapResultCode::Success);
+    assert_eq!(
+        paged_search(&mut client).await,
+        (vec![2, 1], LdapResultCode::SizeLimitExceeded)
+    );
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+    client.send(10, search_request()).await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (3, LdapResultCode::SizeLimitExceeded)
+    );
+    assert_eq!(searches.load(Ordering::SeqCst), 3);
+
+    // A whole result set is cached, and a limit set later applies to it.
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    assert_eq!(
+        paged_search(&mut client).await,
+        (vec![2, 2, 1], LdapResultCode::Success)
+    );
+    assert_eq!(searches.load(Ordering::SeqCst), 6);
+    app_state.update_policy(|policy| {
+        policy
+            .binddn_map
+            .get_mut("cn=sssd")
+            .unwrap()
+            .hard_entry_limit = Some(3);
+    });
+    client.send(20, search_request()).await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (3, LdapResultCode::SizeLimitExceeded)
+    );
+    assert_eq!(
+        paged_search(&mut client).await,
+        (vec![2, 1], LdapResultCode::SizeLimitExceeded)
+    );
+    assert_eq!(searches.load(Ordering::SeqCst), 6);
+}
+
+#[tokio::test]
+async fn test_cldap_ping() {
+    use tokio_util::codec::Encoder;
+
+    let datagram = |attrs: &[&str]| {
+        let msg = LdapMsg {
+            msgid: 7,
+            op: LdapOp::SearchRequest(LdapSearchRequest {
+                base: "".to_string(),
+                scope: LdapSearchScope::Base,
+                aliases: LdapDerefAliases::Never,
+                sizelimit: 0,
+                timelimit: 0,
+                typesonly: false,
+                filter: parse_ldap_filter_str("(&(DnsDomain=example.com)(NtVer=\\06\\00\\00\\00))")
+                    .unwrap(),
+                attrs: attrs.iter().map(|attr| attr.to_string()).collect(),
+            }),
+            ctrl: vec![],
+        };
+        let mut buf = BytesMut::new();
+        ldap3_proto::LdapCodec::new(None)
+            .encode(msg, &mut buf)
+            .unwrap();
+        buf.to_vec()
+    };
+
+    // The DC answers each ping with its entry and result in one datagram, as
+    // an AD DC does.
+    let answer = |msgid: i32| {
+        let mut codec = ldap3_proto::LdapCodec::new(None);
+        let mut buf = BytesMut::new();
+        let entry = LdapSearchResultEntry {
+            dn: "".to_string(),
+            attributes: vec![LdapPartialAttribute {
+                atype: "Netlogon".to_string(),
+                vals: vec![b"dc1.example.com".to_vec()],
+            }],
+        };
+        for op in [
+            LdapOp::SearchResultEntry(entry),
+            LdapOp::SearchResultDone(LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            }),
+        ] {
+            let msg = LdapMsg {
+                msgid,
+                op,
+                ctrl: vec![],
+            };
+            codec.encode(msg, &mut buf).unwrap();
+        }
+        buf.to_vec()
+    };
+    let dc = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let dc_port = dc.local_addr().unwrap().port();
+    let pings_seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = pings_seen.clone();
+    tokio::spawn(async move {
+        let mut buf = vec![0; 65535];
+        loop {
+            let (len, from) = dc.recv_from(&mut buf).await.unwrap();
+            let ping = ldap3_proto::LdapCodec::new(None)
+                .decode(&mut BytesMut::from(&buf[..len]))
+                .unwrap()
+                .unwrap();
+            seen.lock().unwrap().push(from);
+            dc.send_to(&answer(ping.msgid), from).await.unwrap();
+        }
+    });
+
+    let (acceptor, connector) = common::tls_pair();
+    let addr =
+        common::mock_server(acceptor, common::accept_binds(|_| MockAction::Disconnect)).await;
+    let mut app_state = common::app_state(addr, connector, BTreeMap::new());
+    app_state.cldap_port = dc_port;
+    app_state.cldap_allowed_networks = vec!["127.0.0.0/8".parse().unwrap()];
+    app_state.update_policy(|policy| {
+        policy.denied_client_networks = vec!["127.0.0.2/32".parse().unwrap()];
+    });
+    let local = std::net::IpAddr::from([127, 0, 0, 1]);
+
+    // Pings of different clients with the same message id each get their own
+    // answer, with the id that they sent, and are relayed on the same socket.
+    let ping = datagram(&["Netlogon"]);
+    let (first, second) = tokio::join!(
+        cldap_answer(&app_state, &ping, local),
+        cldap_answer(&app_state, &ping, local)
+    );
+    assert_eq!(first.unwrap(), answer(7));
+    assert_eq!(second.unwrap(), answer(7));
+    let seen = pings_seen.lock().unwrap().clone();
+    assert_eq!(seen.len(), 2);
+    assert_eq!(seen[0], seen[1]);
+
+    // Other searches, and pings from elsewhere, are dropped.
+    assert!(cldap_answer(&app_state, &datagram(&["*"]), local)
+        .await
+        .is_none());
+    assert!(cldap_answer(&app_state, b"junk", local).await.is_none());
+    let elsewhere = std::net::IpAddr::from([192, 0, 2, 1]);
+    assert!(cldap_answer(&app_state, &ping, elsewhere).await.is_none());
+    // As are pings from denied client networks, even within
+    // cldap_allowed_networks.
+    let denied = std::net::IpAddr::from([127, 0, 0, 2]);
+    assert!(cldap_answer(&app_state, &ping, denied).await.is_none());
+    app_state.cldap_allowed_networks = vec![];
+    assert!(cldap_answer(&app_state, &ping, denied).await.is_none());
+    // When only client networks are allowed, they limit pings too.
+    app_state.update_policy(|policy| {
+        policy.allowed_client_networks = vec!["10.0.0.0/8".parse().unwrap()];
+    });
+    assert!(cldap_answer(&app_state, &ping, local).await.is_none());
+    assert_eq!(pings_seen.lock().unwrap().len(), 2);
+
+    let metrics = &app_state.metrics;
+    assert_eq!(
+        metrics.get("cldap_pings_total", &[("result", "answered")]),
+        2
+    );
+    assert_eq!(
+        metrics.get("cldap_pings_total", &[("result", "dropped")]),
+        6
+    );
+}
+
+#[tokio::test]
+async fn test_tap_log() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(|msg| match msg.op {
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: "uid=alice,o=example".to_string(),
+                        attributes: vec![
+                            LdapPartialAttribute {
+                                atype: "cn".to_string(),
+                                vals: vec![b"Alice".to_vec()],
+                            },
+                            LdapPartialAttribute {
+                                atype: "userPassword".to_string(),
+                                vals: vec![b"{SSHA}hash".to_vec()],
+                            },
+                        ],
+                    }),
+                    ctrl: vec![],
+                },
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                },
+            ]),
+            LdapOp::ModifyRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::ModifyResponse(common::success()),
+                ctrl: vec![],
+            }]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    // Each block is larger than max_bytes, so each is written to a file of its
+    // own.
+    let path = std::env::temp_dir().join(format!("ldap-proxy-tap-{}.log", std::process::id()));
+    let rotated = |n: usize| std::path::PathBuf::from(format!("{}.{}", path.display(), n));
+    let remove = || {
+        let _ = std::fs::remove_file(&path);
+        for n in 1..=10 {
+            let _ = std::fs::remove_file(rotated(n));
+        }
+    };
+    remove();
+    let tap = TapLog::open(&TapConfig {
+        path: path.clone(),
+        max_bytes: 1,
+        keep: 10,
+    })
+    .unwrap();
+    let binddn_map = BTreeMap::from([
+        (
+            "cn=tapped".to_string(),
+            DnConfig {
+                tap: true,
+                allow_write: Some(true),
+                ..Default::default()
+            },
+        ),
+        ("cn=user".to_string(), DnConfig::default()),
+    ]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    app_state.tap = Some(Arc::new(tap));
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "CN=Tapped").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    client
+        .send(
+            3,
+            LdapOp::ModifyRequest(LdapModifyRequest {
+                dn: "uid=alice,o=example".to_string(),
+                changes: vec![LdapModify {
+                    operation: LdapModifyType::Replace,
+                    modification: LdapPartialAttribute {
+                        atype: "userPassword".to_string(),
+                        vals: vec![b"new secret".to_vec()],
+                    },
+                }],
+            }),
+        )
+        .await;
+    assert!(matches!(
+        client.recv().await.map(|msg| msg.op),
+        Some(LdapOp::ModifyResponse(_))
+    ));
+
+    // The bind, search and modify of cn=tapped, and their responses. The
+    // blocks are written by a thread of their own.
+    let mut blocks = Vec::new();
+    for _ in 0..50 {
+        blocks = (1..=10)
+            .rev()
+            .map(rotated)
+            .chain([path.clone()])
+            .filter_map(|path| std::fs::read_to_string(path).ok())
+            .collect();
+        if blocks.len() == 7 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    remove();
+    assert_eq!(blocks.len(), 7);
+    let log = blocks.concat();
+    assert!(!log.contains("cn=user"));
+    assert!(!log.contains("password"));
+    assert!(!log.contains("secret"));
+    assert!(!log.contains("{SSHA}"));
+
+    assert!(blocks[0].starts_with("# request "));
+    assert!(blocks[0].contains("127.0.0.1"));
+    assert!(blocks[0].contains("msgid: 1\nop: bindRequest\ndn: CN=Tapped\nsimple: <redacted>\n"));
+    assert!(blocks[1].starts_with("# response "));
+    assert!(blocks[1].contains("op: bindResponse\nresult: Success\n"));
+    assert!(blocks[2].contains("op: searchRequest\n"));
+    assert!(blocks[3].contains(
+        "op: searchResultEntry\ndn: uid=alice,o=example\ncn: Alice\nuserPassword: <redacted>\n"
+    ));
+    assert!(blocks[4].contains("op: searchResultDone\nresult: Success\n"));
+    assert!(blocks[5].contains("replace: userPassword\nuserPassword: <redacted>\n-\n"));
+    assert!(blocks[6].contains("msgid: 3\nop: modifyResponse\n"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_embedded_server() {
+    let dir = std::env::temp_dir().join(format!("ldap-proxy-embedded-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = common::certificate("localhost", "localhost");
+    std::fs::write(dir.join("chain.pem"), cert.to_pem().unwrap()).unwrap();
+    std::fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+    let mut acceptor =
+        openssl::ssl::SslAcceptor::mozilla_intermediate_v5(openssl::ssl::SslMethod::tls()).unwrap();
+    acceptor.set_certificate(&cert).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    let addr = common::mock_server(
+        acceptor.build(),
+        common::accept_binds(|_| MockAction::Disconnect),
+    )
+    .await;
+    let mut connector =
+        openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls_client()).unwrap();
+    connector.cert_store_mut().add_cert(cert).unwrap();
+    let connector = connector.build();
+
+    let path = dir.join("config.toml");
+    let config = |dns: &str| {
+        let contents = format!(
+            r#"bind = "127.0.0.1:3636"
+tls_key = "{dir}/key.pem"
+tls_chain = "{dir}/chain.pem"
+ldap_ca = "{dir}/chain.pem"
+ldap_url = "ldaps://127.0.0.1:636"
+shutdown_grace_secs = 5
+{dns}
+"#,
+            dir = dir.display()
+        );
+        load_config(&contents, &path, Vec::new()).unwrap()
+    };
+
+    // The listener and backend are given to the builder, as are bind maps.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = ProxyBuilder::new(config(r#"["cn=reader"]"#))
+        .listener(listener)
+        .ldap_url(vec![url::Url::parse(&format!(
+            "ldaps://localhost:{}",
+            addr.port()
+        ))
+        .unwrap()])
+        .bind_dn("CN=App, O=Example", DnConfig::default())
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+    assert!(server.app_state().listening.load(Ordering::Relaxed));
+
+    let mut client = common::connect_ldaps(server.local_addr(), &connector).await;
+    assert_eq!(
+        client.bind(1, "cn=app,o=example").await,
+        LdapResultCode::Success
+    );
+    assert_eq!(client.bind(2, "cn=reader").await, LdapResultCode::Success);
+    assert_ne!(client.bind(3, "cn=other").await, LdapResultCode::Success);
+
+    // A reload replaces the bind maps, ending the session of cn=reader, but a
+    // DN of a new backend pool needs a restart.
+    assert!(server.reload(&config(r#"["cn=other"]"#)));
+    let mut client = common::connect_ldaps(server.local_addr(), &connector).await;
+    assert_ne!(
+        client.bind(1, "cn=app,o=example").await,
+        LdapResultCode::Success
+    );
+    assert_eq!(client.bind(2, "cn=other").await, LdapResultCode::Success);
+    let added = config(
+        r#"["cn=new"]
+backend = "new"
+
+[backends.new]
+ldap_url = "ldaps://127.0.0.1:636""#,
+    );
+    assert!(!server.reload(&added));
+    assert!(!server.reload_from_file());
+    drop(client);
+
+    let app_state = server.app_state().clone();
+    tokio::time::timeout(Duration::from_secs(10), server.shutdown())
+        .await
+        .unwrap();
+    assert!(!app_state.listening.load(Ordering::Relaxed));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_listener_budget() {
+    let dir = std::env::temp_dir().join(format!("ldap-proxy-budget-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = common::certificate("localhost", "localhost");
+    std::fs::write(dir.join("chain.pem"), cert.to_pem().unwrap()).unwrap();
+    std::fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+    let mut acceptor =
+        openssl::ssl::SslAcceptor::mozilla_intermediate_v5(openssl::ssl::SslMethod::tls()).unwrap();
+    acceptor.set_certificate(&cert).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    let addr = common::mock_server(
+        acceptor.build(),
+        common::accept_binds(|_| MockAction::Disconnect),
+    )
+    .await;
+    let mut connector =
+        openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls_client()).unwrap();
+    connector.cert_store_mut().add_cert(cert).unwrap();
+    let connector = connector.build();
+
+    let path = dir.join("config.toml");
+    let contents = format!(
+        r#"bind = "127.0.0.1:0"
+tls_key = "{dir}/key.pem"
+tls_chain = "{dir}/chain.pem"
+ldap_ca = "{dir}/chain.pem"
+ldap_url = "ldaps://localhost:{port}"
+accept_backlog = 16
+max_active_sessions = 1
+runtime = "current_thread"
+
+["cn=app"]
+"#,
+        dir = dir.display(),
+        port = addr.port()
+    );
+    let config = load_config(&contents, &path, Vec::new()).unwrap();
+    assert_eq!(config.accept_backlog, 16);
+    assert_eq!(config.runtime, RuntimeFlavor::CurrentThread);
+
+    // The runtime can't be built, or dropped, within this one.
+    let runtime_config = load_config(&contents, &path, Vec::new()).unwrap();
+    std::thread::spawn(move || {
+        let runtime = build_runtime(&runtime_config).unwrap();
+        assert_eq!(runtime.block_on(async { 1 + 1 }), 2);
+    })
+    .join()
+    .unwrap();
+
+    let server = ProxyBuilder::new(config).start().await.unwrap();
+    let local_addr = server.local_addr();
+    let mut first = common::connect_ldaps(local_addr, &connector).await;
+    assert_eq!(first.bind(1, "cn=app").await, LdapResultCode::Success);
+
+    // The second connection waits in the backlog until the first session ends.
+    let second_connector = connector.clone();
+    let second =
+        tokio::spawn(async move { common::connect_ldaps(local_addr, &second_connector).await });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(!second.is_finished());
+    assert_eq!(
+        server.app_state().metrics.get(
+            "listener_budget_exhausted_total",
+            &[("listener", &local_addr.to_string())]
+        ),
+        1
+    );
+    drop(first);
+    let mut second = tokio::time::timeout(Duration::from_secs(10), second)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(second.bind(1, "cn=app").await, LdapResultCode::Success);
+    drop(second);
+
+    tokio::time::timeout(Duration::from_secs(10), server.shutdown())
+        .await
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_search_coalescing() {
+    let searches = Arc::new(AtomicUsize::new(0));
+    let backend_searches = searches.clone();
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(sr) => {
+                backend_searches.fetch_add(1, Ordering::SeqCst);
+                // A slow backend, so that the other searches arrive while
+                // this one is in flight.
+                std::thread::sleep(Duration::from_millis(300));
+                let code = if sr.base == "o=truncated" {
+                    LdapResultCode::SizeLimitExceeded
+                } else {
+                    LdapResultCode::Success
+                };
+                MockAction::Reply(vec![
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: "uid=alice,o=example".to_string(),
+                            attributes: vec![],
+                        }),
+                        ctrl: vec![],
+                    },
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultDone(common::result(code)),
+                        ctrl: vec![],
+                    },
+                ])
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=user".to_string(), DnConfig::default())]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+    let mut clients = Vec::new();
+    for _ in 0..4 {
+        let mut client = common::connect(app_state.clone());
+        assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+        clients.push(client);
+    }
+
+    // The searches that arrive while the first is in flight share its results.
+    for client in clients.iter_mut() {
+        client.send(2, search_request()).await;
+    }
+    for client in clients.iter_mut() {
+        assert_eq!(recv_search(client).await, (1, LdapResultCode::Success));
+    }
+    assert_eq!(searches.load(Ordering::SeqCst), 1);
+    let metrics = &app_state.metrics;
+    assert_eq!(
+        metrics.get("search_coalesced_total", &[("outcome", "shared")]),
+        3
+    );
+    assert!(app_state.search_flights.as_ref().unwrap().is_empty());
+
+    // Results that can't be cached aren't shared, so each waiting search is
+    // sent to the backend once the first completes.
+    let truncated = || {
+        let mut sr = search_request();
+        if let LdapOp::SearchRequest(sr) = &mut sr {
+            sr.base = "o=truncated".to_string();
+        }
+        sr
+    };
+    for client in clients.iter_mut().take(2) {
+        client.send(3, truncated()).await;
+    }
+    for client in clients.iter_mut().take(2) {
+        assert_eq!(
+            recv_search(client).await,
+            (1, LdapResultCode::SizeLimitExceeded)
+        );
+    }
+    assert_eq!(searches.load(Ordering::SeqCst), 3);
+    assert_eq!(
+        metrics.get("search_coalesced_total", &[("outcome", "fallback")]),
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_stale_while_revalidate() {
+    let searches = Arc::new(AtomicUsize::new(0));
+    let backend_searches = searches.clone();
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => {
+                backend_searches.fetch_add(1, Ordering::SeqCst);
+                MockAction::Reply(vec![
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: "uid=alice,o=example".to_string(),
+                            attributes: vec![],
+                        }),
+                        ctrl: vec![],
+                    },
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultDone(common::result(LdapResultCode::Success)),
+                        ctrl: vec![],
+                    },
+                ])
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=user".to_string(), DnConfig::default())]);
+    let app_state = common::app_state(addr, connector, binddn_map);
+    app_state.update_policy(|policy| {
+        policy.cache_entry_timeout = Duration::from_millis(200);
+        policy.cache_max_stale = Some(Duration::from_secs(60));
+    });
+    let app_state = Arc::new(app_state);
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    assert_eq!(searches.load(Ordering::SeqCst), 1);
+
+    // Once expired, the search is answered from cache and refreshed after.
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    client.send(3, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    let metrics = &app_state.metrics;
+    assert_eq!(metrics.get("cache_stale_hits_total", &[]), 1);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while metrics.get("cache_refreshes_total", &[("result", "refreshed")]) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+
+    // The refreshed results are fresh, and an expired entry isn't swept while
+    // it may still be answered with.
+    client.send(4, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+    assert_eq!(metrics.get("cache_stale_hits_total", &[]), 1);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(sweep_expired_cache(&app_state).await.0, 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_dn_quotas() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(sr) => {
+                if sr.base == "o=slow" {
+                    std::thread::sleep(Duration::from_millis(300));
+                }
+                MockAction::Reply(vec![
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: "uid=alice,o=example".to_string(),
+                            attributes: vec![],
+                        }),
+                        ctrl: vec![],
+                    },
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultDone(common::result(LdapResultCode::Success)),
+                        ctrl: vec![],
+                    },
+                ])
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([
+        (
+            "cn=concurrent".to_string(),
+            DnConfig {
+                max_concurrent_operations: Some(1),
+                ..Default::default()
+            },
+        ),
+        (
+            "cn=searches".to_string(),
+            DnConfig {
+                max_searches_per_minute: Some(2),
+                ..Default::default()
+            },
+        ),
+        (
+            "cn=entries".to_string(),
+            DnConfig {
+                max_entries_per_minute: Some(1),
+                ..Default::default()
+            },
+        ),
+    ]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+    let metrics = &app_state.metrics;
+    let slow = || {
+        let mut sr = search_request();
+        if let LdapOp::SearchRequest(sr) = &mut sr {
+            sr.base = "o=slow".to_string();
+        }
+        sr
+    };
+
+    // The operations in flight are counted across the sessions of the DN.
+    let mut first = common::connect(app_state.clone());
+    let mut second = common::connect(app_state.clone());
+    assert_eq!(
+        first.bind(1, "cn=concurrent").await,
+        LdapResultCode::Success
+    );
+    assert_eq!(
+        second.bind(1, "cn=concurrent").await,
+        LdapResultCode::Success
+    );
+    first.send(2, slow()).await;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while app_state.dn_quotas.in_flight("cn=concurrent") == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    second.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut second).await, (0, LdapResultCode::Busy));
+    assert_eq!(recv_search(&mut first).await, (1, LdapResultCode::Success));
+    assert_eq!(app_state.dn_quotas.in_flight("cn=concurrent"), 0);
+    second.send(3, search_request()).await;
+    assert_eq!(recv_search(&mut second).await, (1, LdapResultCode::Success));
+    assert_eq!(
+        metrics.get("dn_quota_refused_total", &[("quota", "operations")]),
+        1
+    );
+
+    // Searches are counted whether or not they are answered from cache.
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=searches").await, LdapResultCode::Success);
+    for msgid in 2..4 {
+        client.send(msgid, search_request()).await;
+        assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    }
+    client.send(4, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Busy));
+    assert_eq!(
+        metrics.get("dn_quota_refused_total", &[("quota", "searches")]),
+        1
+    );
+
+    // A search that returns the last of the entries leaves none for the next.
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=entries").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    client.send(3, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Busy));
+    assert_eq!(
+        metrics.get("dn_quota_refused_total", &[("quota", "entries")]),
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_admin_controls() {
+    // The administrative controls that reach the backend, by oid.
+    let relayed = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let backend_relayed = relayed.clone();
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server_requests(acceptor, move |request| {
+        let msgid = request.msg.msgid;
+        let mut oids: Vec<String> = request.raw_controls.iter().map(|c| c.oid.clone()).collect();
+        if request
+            .msg
+            .ctrl
+            .iter()
+            .any(|c| matches!(c, LdapControl::ManageDsaIT { criticality: true }))
+        {
+            oids.push(OID_MANAGE_DSA_IT.to_string());
+        }
+        let op = match request.msg.op {
+            LdapOp::BindRequest(_) => LdapOp::BindResponse(LdapBindResponse {
+                res: common::success(),
+                saslcreds: None,
+            }),
+            LdapOp::ModifyRequest(_) => {
+                backend_relayed.lock().unwrap().extend(oids);
+                LdapOp::ModifyResponse(common::success())
+            }
+            _ => {
+                backend_relayed.lock().unwrap().extend(oids);
+                LdapOp::SearchResultDone(common::success())
+            }
+        };
+        MockAction::Reply(vec![LdapMsg {
+            msgid,
+            op,
+            ctrl: vec![],
+        }])
+    })
+    .await;
+
+    let dn_config = |action| DnConfig {
+        allow_write: Some(true),
+        manage_dsa_it: action,
+        relax_rules: action,
+        ..Default::default()
+    };
+    let binddn_map = BTreeMap::from([
+        ("cn=app".to_string(), DnConfig::default()),
+        ("cn=tool".to_string(), dn_config(AdminControlAction::Strip)),
+        (
+            "cn=admin".to_string(),
+            dn_config(AdminControlAction::Forward),
+        ),
+    ]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+    let modify = || {
+        LdapOp::ModifyRequest(LdapModifyRequest {
+            dn: "uid=demo,o=example".to_string(),
+            changes: vec![],
+        })
+    };
+    let manage_dsa_it = vec![LdapControl::ManageDsaIT { criticality: true }];
+
+    // By default they are refused, even when they aren't critical.
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=app").await, LdapResultCode::Success);
+    client
+        .send_with_controls(
+            2,
+            search_request(),
+            vec![LdapControl::ManageDsaIT { criticality: false }],
+        )
+        .await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (0, LdapResultCode::UnavailableCriticalExtension)
+    );
+    client
+        .send_with_raw_control(3, modify(), OID_RELAX_RULES, false)
+        .await;
+    let msg = client.recv().await.expect("no response");
+    assert!(matches!(
+        msg.op,
+        LdapOp::ModifyResponse(LdapResult {
+            code: LdapResultCode::UnavailableCriticalExtension,
+            ..
+        })
+    ));
+    assert_eq!(
+        app_state
+            .metrics
+            .get("admin_controls_refused_total", &[("oid", OID_RELAX_RULES)]),
+        1
+    );
+
+    // Stripped, the requests go ahead without them.
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=tool").await, LdapResultCode::Success);
+    client
+        .send_with_controls(2, search_request(), manage_dsa_it.clone())
+        .await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    client
+        .send_with_raw_control(3, modify(), OID_RELAX_RULES, true)
+        .await;
+    let msg = client.recv().await.expect("no response");
+    assert!(matches!(
+        msg.op,
+        LdapOp::ModifyResponse(LdapResult {
+            code: LdapResultCode::Success,
+            ..
+        })
+    ));
+    assert!(relayed.lock().unwrap().is_empty());
+
+    // Forwarded, they reach the backend, whatever the control lists say.
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=admin").await, LdapResultCode::Success);
+    client
+        .send_with_controls(2, search_request(), manage_dsa_it)
+        .await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    client
+        .send_with_raw_control(3, modify(), OID_RELAX_RULES, true)
+        .await;
+    let msg = client.recv().await.expect("no response");
+    assert!(matches!(
+        msg.op,
+        LdapOp::ModifyResponse(LdapResult {
+            code: LdapResultCode::Success,
+            ..
+        })
+    ));
+    assert_eq!(
+        *relayed.lock().unwrap(),
+        vec![OID_MANAGE_DSA_IT.to_string(), OID_RELAX_RULES.to_string()]
+    );
+}
+
+#[tokio::test]
+async fn test_upstream_span_msgid() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let recorder = common::SpanRecorder::default();
+    let subscriber = tracing_subscriber::registry().with(recorder.clone());
+    // The test's runtime is on this thread, so this sees the proxy's spans.
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let app_state = compare_app_state(true).await;
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=radius").await, LdapResultCode::Success);
+    client.send(42, compare_request()).await;
+    let msg = client.recv().await.expect("no response");
+    assert_eq!(msg.msgid, 42);
+
+    // The operation keeps the client's msgid, and the backend's is on the
+    // upstream span within it.
+    let spans = recorder.spans();
+    let operation = spans
+        .iter()
+        .position(|span| span.name == "operation")
+        .expect("no operation span");
+    assert_eq!(spans[operation].msgid, Some(42));
+    let upstream = spans
+        .iter()
+        .find(|span| span.name == "upstream" && span.parent == Some(operation))
+        .expect("no upstream span");
+    assert!(upstream.msgid.is_some_and(|msgid| msgid != 42));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_stale_refresh_once() {
+    let searches = Arc::new(AtomicUsize::new(0));
+    let backend_searches = searches.clone();
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => {
+                backend_searches.fetch_add(1, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(200));
+                MockAction::Reply(vec![
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                            dn: "uid=alice,o=example".to_string(),
+                            attributes: vec![],
+                        }),
+                        ctrl: vec![],
+                    },
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultDone(common::success()),
+                        ctrl: vec![],
+                    },
+                ])
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = BTreeMap::from([("cn=user".to_string(), DnConfig::default())]);
+    let mut app_state = common::app_state(addr, connector, binddn_map);
+    // Refreshes are sent once at a time without coalescing too.
+    app_state.search_flights = None;
+    app_state.upstream_pool =
+        UpstreamPool::new(4, Duration::from_secs(60), Duration::from_secs(600));
+    app_state.update_policy(|policy| {
+        policy.cache_entry_timeout = Duration::from_millis(100);
+        policy.cache_max_stale = Some(Duration::from_secs(60));
+    });
+    let app_state = Arc::new(app_state);
+    let mut clients = Vec::new();
+    for _ in 0..4 {
+        let mut client = common::connect(app_state.clone());
+        assert_eq!(client.bind(1, "cn=user").await, LdapResultCode::Success);
+        clients.push(client);
+    }
+    clients[0].send(2, search_request()).await;
+    assert_eq!(
+        recv_search(&mut clients[0]).await,
+        (1, LdapResultCode::Success)
+    );
+
+    // Every client is answered with the expired results, and the search is
+    // refreshed once.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    for client in clients.iter_mut() {
+        client.send(3, search_request()).await;
+    }
+    for client in clients.iter_mut() {
+        assert_eq!(recv_search(client).await, (1, LdapResultCode::Success));
+    }
+    let metrics = &app_state.metrics;
+    assert_eq!(metrics.get("cache_stale_hits_total", &[]), 4);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !app_state.cache_refreshes.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+    assert_eq!(
+        metrics.get("cache_refreshes_total", &[("result", "refreshed")]),
+        1
+    );
+    // The refresh had a connection of its own, which was pooled after.
+    assert_eq!(app_state.upstream_pool.len(), 1);
+}
+
+#[tokio::test]
+async fn test_notification_control_policy() {
+    let (acceptor, connector) = common::tls_pair();
+    let persistent = Arc::new(AtomicUsize::new(0));
+    let backend_persistent = persistent.clone();
+    let addr = common::mock_server_requests(acceptor, move |request| {
+        let msgid = request.msg.msgid;
+        match request.msg.op {
+            LdapOp::BindRequest(_) => MockAction::Reply(vec![LdapMsg {
+                msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res: common::success(),
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            }]),
+            LdapOp::SearchRequest(_) => {
+                if request
+                    .raw_controls
+                    .iter()
+                    .any(|c| c.oid == OID_PERSISTENT_SEARCH)
+                {
+                    backend_persistent.fetch_add(1, Ordering::SeqCst);
+                }
+                MockAction::Reply(vec![LdapMsg {
+                    msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                }])
+            }
+            _ => MockAction::Disconnect,
+        }
+    })
+    .await;
+
+    let binddn_map = BTreeMap::from([(
+        "cn=untrusted".to_string(),
+        DnConfig {
+            denied_controls: Some([OID_PERSISTENT_SEARCH.to_string()].into()),
+            ..Default::default()
+        },
+    )]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+    let mut client = common::connect(app_state);
+    assert_eq!(
+        client.bind(1, "cn=untrusted").await,
+        LdapResultCode::Success
+    );
+
+    // Persistent search is relayed as it was sent, but only if the DN's
+    // control lists permit it. A critical one refuses the search.
+    let psearch = [
+        0x30, 0x09, 0x02, 0x01, 0x0f, 0x01, 0x01, 0xff, 0x01, 0x01, 0xff,
+    ];
+    client
+        .send_with_raw_controls(
+            2,
+            search_request(),
+            &[(OID_PERSISTENT_SEARCH, true, Some(&psearch))],
+        )
+        .await;
+    assert_eq!(
+        recv_search(&mut client).await,
+        (0, LdapResultCode::UnavailableCriticalExtension)
+    );
+
+    // One that is not critical is removed, and the search is sent without it.
+    client
+        .send_with_raw_controls(
+            3,
+            search_request(),
+            &[(OID_PERSISTENT_SEARCH, false, Some(&psearch))],
+        )
+        .await;
+    assert_eq!(recv_search(&mut client).await, (0, LdapResultCode::Success));
+    assert_eq!(persistent.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn test_chase_plain_referral() {
+    let (acceptor, connector) = common::tls_pair();
+    let referred = |dn: &'static str| {
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(sr) => {
+                assert_eq!(sr.base, "ou=sub,o=example");
+                MockAction::Reply(vec![
+                    search_entry(msg.msgid, dn),
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: LdapOp::SearchResultDone(common::success()),
+                        ctrl: vec![],
+                    },
+                ])
+            }
+            _ => MockAction::Disconnect,
+        })
+    };
+    // The plain server refuses StartTLS, and must never see the bind.
+    let plain_binds = Arc::new(AtomicUsize::new(0));
+    let seen_binds = plain_binds.clone();
+    let plain_referred = referred("uid=plain,ou=sub,o=example");
+    let plain = common::mock_plain_server(move |msg: LdapMsg| {
+        if matches!(msg.op, LdapOp::BindRequest(_)) {
+            seen_binds.fetch_add(1, Ordering::SeqCst);
+        }
+        plain_referred(msg)
+    })
+    .await;
+    let starttls =
+        common::mock_starttls_server(acceptor.clone(), referred("uid=starttls,ou=sub,o=example"))
+            .await;
+
+    // The backend refers to an ldap:// server, on a port that isn't 389.
+    let target = Arc::new(std::sync::Mutex::new(String::new()));
+    let backend_target = target.clone();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultReference(LdapSearchResultReference {
+                        uris: vec![backend_target.lock().unwrap().clone()],
+                    }),
+                    ctrl: vec![],
+                },
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                },
+            ]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let session = || {
+        let binddn_map = BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+        let mut app_state = common::app_state(addr, connector.clone(), binddn_map);
+        app_state.referral_mode = ReferralMode::Chase;
+        app_state.referral_chase_hosts = vec!["LOCALHOST".to_string()];
+        let app_state = Arc::new(app_state);
+        (common::connect(app_state.clone()), app_state)
+    };
+
+    // A plain referral is upgraded with StartTLS before the bind is sent.
+    *target.lock().unwrap() = format!("ldap://localhost:{}/ou=sub,o=example??sub", starttls.port());
+    let (mut client, _) = session();
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    let (entries, references) = recv_search_refs(&mut client).await;
+    assert_eq!(entries, vec!["uid=starttls,ou=sub,o=example"]);
+    assert!(references.is_empty());
+
+    // A server that refuses StartTLS isn't chased, and the referral is
+    // returned as it is.
+    let plain_url = format!("ldap://localhost:{}/ou=sub,o=example??sub", plain.port());
+    *target.lock().unwrap() = plain_url.clone();
+    let (mut client, app_state) = session();
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    let (entries, references) = recv_search_refs(&mut client).await;
+    assert!(entries.is_empty());
+    assert_eq!(references, vec![plain_url]);
+    assert_eq!(plain_binds.load(Ordering::SeqCst), 0);
+    assert_eq!(
+        app_state.metrics.get("referral_chase_failures_total", &[]),
+        1
+    );
+
+    // Nor is a server that isn't in referral_chase_hosts.
+    let unlisted_url = format!("ldap://127.0.0.1:{}/ou=sub,o=example??sub", starttls.port());
+    *target.lock().unwrap() = unlisted_url.clone();
+    let (mut client, _) = session();
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    let (entries, references) = recv_search_refs(&mut client).await;
+    assert!(entries.is_empty());
+    assert_eq!(references, vec![unlisted_url]);
+}
+
+#[tokio::test]
+async fn test_paged_search_passthrough() {
+    let (acceptor, connector) = common::tls_pair();
+    let searches = Arc::new(AtomicUsize::new(0));
+    let seen = searches.clone();
+    // Five entries, in pages of two, with the backend's own cookies.
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => {
+                seen.fetch_add(1, Ordering::SeqCst);
+                let (names, next_cookie): (&[&str], &[u8]) = match paged_results_cookie(&msg.ctrl) {
+                    Some(b"") => (&["cn=a", "cn=b"], b"page2"),
+                    Some(b"page2") => (&["cn=c", "cn=d"], b"page3"),
+                    Some(b"page3") => (&["cn=e"], b""),
+                    _ => return MockAction::Disconnect,
+                };
+                let mut msgs: Vec<_> = names
+                    .iter()
+                    .map(|name| search_entry(msg.msgid, &format!("{},o=example", name)))
+                    .collect();
+                msgs.push(LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![LdapControl::SimplePagedResults {
+                        size: 5,
+                        cookie: next_cookie.to_vec(),
+                    }],
+                });
+                MockAction::Reply(msgs)
+            }
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    // Nothing is cached, so every page comes from the backend.
+    let binddn_map = BTreeMap::from([(
+        "cn=sssd".to_string(),
+        DnConfig {
+            cache_ttl_secs: Some(0),
+            ..Default::default()
+        },
+    )]);
+    let app_state = Arc::new(common::app_state(addr, connector, binddn_map));
+
+    // Send a page of a search, returning the entries and the result, and the
+    // cookie for the next page.
+    let page = async |client: &mut common::TestClient, msgid, op, cookie: &[u8]| {
+        let paged = LdapControl::SimplePagedResults {
+            size: 2,
+            cookie: cookie.to_vec(),
+        };
+        client.send_with_controls(msgid, op, vec![paged]).await;
+        let mut entries = 0;
+        loop {
+            let msg = client.recv().await.expect("no response");
+            match msg.op {
+                LdapOp::SearchResultEntry(_) => entries += 1,
+                LdapOp::SearchResultDone(res) => {
+                    let cookie = paged_results_cookie(&msg.ctrl).map(<[u8]>::to_vec);
+                    break (entries, res.code, cookie);
+                }
+                op => panic!("unexpected {:?}", op),
+            }
+        }
+    };
+
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    let (entries, code, cookie) = page(&mut client, 2, search_request(), b"").await;
+    assert_eq!((entries, code), (2, LdapResultCode::Success));
+    let cookie = cookie.expect("no paged control");
+    assert_eq!(cookie, b"page2");
+
+    // The cookie only continues the search that it was given for.
+    let mut other_search = search_request();
+    if let LdapOp::SearchRequest(sr) = &mut other_search {
+        sr.filter = LdapFilter::Present("uid".to_string());
+    }
+    let (_, code, _) = page(&mut client, 3, other_search, &cookie).await;
+    assert_eq!(code, LdapResultCode::UnwillingToPerform);
+    assert_eq!(searches.load(Ordering::SeqCst), 1);
+
+    // Refusing it forgot the search, so the rest of the pages can't be read.
+    let (_, code, _) = page(&mut client, 4, search_request(), &cookie).await;
+    assert_eq!(code, LdapResultCode::UnwillingToPerform);
+
+    // A fresh search reads every page, on the session's own connection.
+    let mut pages = Vec::new();
+    let mut cookie = Vec::new();
+    for msgid in 5.. {
+        let (entries, code, next) = page(&mut client, msgid, search_request(), &cookie).await;
+        assert_eq!(code, LdapResultCode::Success);
+        pages.push(entries);
+        cookie = next.expect("no paged control");
+        if cookie.is_empty() {
+            break;
+        }
+        // The cookies of one session aren't valid for another, even of the
+        // same DN.
+        let mut other = common::connect(app_state.clone());
+        assert_eq!(other.bind(1, "cn=sssd").await, LdapResultCode::Success);
+        let (_, code, _) = page(&mut other, 2, search_request(), &cookie).await;
+        assert_eq!(code, LdapResultCode::UnwillingToPerform);
+    }
+    assert_eq!(pages, vec![2, 2, 1]);
+    assert_eq!(searches.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn test_cache_persistence_validation() {
+    let (acceptor, connector) = common::tls_pair();
+    let addr = common::mock_server(
+        acceptor,
+        common::accept_binds(move |msg| match msg.op {
+            LdapOp::SearchRequest(_) => MockAction::Reply(vec![
+                search_entry(msg.msgid, "uid=user,o=example"),
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultDone(common::success()),
+                    ctrl: vec![],
+                },
+            ]),
+            _ => MockAction::Disconnect,
+        }),
+    )
+    .await;
+
+    let binddn_map = |cache_ttl_secs| {
+        BTreeMap::from([(
+            "cn=sssd".to_string(),
+            DnConfig {
+                cache_ttl_secs: Some(cache_ttl_secs),
+                ..Default::default()
+            },
+        )])
+    };
+    let path = std::env::temp_dir().join(format!(
+        "ldap-proxy-cache-validation-{}.json",
+        std::process::id()
+    ));
+
+    let app_state = Arc::new(common::app_state(addr, connector.clone(), binddn_map(1)));
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=sssd").await, LdapResultCode::Success);
+    client.send(2, search_request()).await;
+    assert_eq!(recv_search(&mut client).await, (1, LdapResultCode::Success));
+    assert_eq!(save_cache(&app_state, &path).unwrap(), 1);
+
+    // A restart loads the entries of DNs whose config still caches them.
+    let restarted =
+        |cache_ttl_secs| common::app_state(addr, connector.clone(), binddn_map(cache_ttl_secs));
+    assert_eq!(load_cache(&restarted(1), &path), 1);
+    assert_eq!(load_cache(&restarted(0), &path), 0);
+
+    // A file of another version is ignored, though its entries are valid.
+    let saved = std::fs::read(&path).unwrap();
+    let mut persisted: serde_json::Value = serde_json::from_slice(&saved).unwrap();
+    persisted["version"] = serde_json::json!(0);
+    std::fs::write(&path, serde_json::to_vec(&persisted).unwrap()).unwrap();
+    assert_eq!(load_cache(&restarted(1), &path), 0);
+
+    // Entries that have expired while the proxy was down are not loaded.
+    std::fs::write(&path, &saved).unwrap();
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(load_cache(&restarted(1), &path), 0);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_session_span() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let recorder = common::SpanRecorder::default();
+    let subscriber = tracing_subscriber::registry().with(recorder.clone());
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let app_state = compare_app_state(true).await;
+    let mut client = common::connect(app_state.clone());
+    assert_eq!(client.bind(1, "cn=radius").await, LdapResultCode::Success);
+    client.send(2, compare_request()).await;
+    client.recv().await.expect("no response");
+
+    // The session's span has the id that the admin API lists it by, and the
+    // DN that it bound as.
+    let spans = recorder.spans();
+    let session = spans
+        .iter()
+        .position(|span| span.name == "session")
+        .expect("no session span");
+    let sessions = app_state.sessions.list();
+    assert_eq!(spans[session].id, Some(sessions[0].id));
+    assert_eq!(spans[session].bind_dn.as_deref(), Some("cn=radius"));
+
+    // The operation, and its request to the backend, are within it.
+    let within_session = |mut idx: usize| loop {
+        match spans[idx].parent {
+            Some(parent) if parent == session => break true,
+            Some(parent) => idx = parent,
+            None => break false,
+        }
+    };
+    let operation = spans
+        .iter()
+        .rposition(|span| span.name == "operation" && span.msgid == Some(2))
+        .expect("no operation span");
+    assert!(within_session(operation));
+    let upstream = spans
+        .iter()
+        .rposition(|span| span.name == "upstream")
+        .expect("no upstream span");
+    assert!(within_session(upstream));
+
+    // As are the events of the task that reads from its backend connection,
+    // which the backend closes on a compare of another entry.
+    client
+        .send(
+            3,
+            LdapOp::CompareRequest(LdapCompareRequest {
+                dn: "uid=other,o=example".to_string(),
+                atype: "userPassword".to_string(),
+                val: b"password".to_vec(),
+            }),
+        )
+        .await;
+    client.recv().await.expect("no response");
+    let closed = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let closed = recorder
+                .events()
+                .into_iter()
+                .find(|event| event.message == "connection closed");
+            match closed {
+                Some(event) => break event,
+                None => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    })
+    .await
+    .expect("the backend connection was not closed");
+    let span = closed.span.expect("no span");
+    assert!(span == session || within_session(span));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tls_reload() {
+    let dir = std::env::temp_dir().join(format!("ldap-proxy-tls-reload-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (backend_cert, backend_key) = common::certificate("localhost", "localhost");
+    std::fs::write(dir.join("ca.pem"), backend_cert.to_pem().unwrap()).unwrap();
+    let mut acceptor =
+        openssl::ssl::SslAcceptor::mozilla_intermediate_v5(openssl::ssl::SslMethod::tls()).unwrap();
+    acceptor.set_certificate(&backend_cert).unwrap();
+    acceptor.set_private_key(&backend_key).unwrap();
+    let addr = common::mock_server(
+        acceptor.build(),
+        common::accept_binds(|_| MockAction::Disconnect),
+    )
+    .await;
+
+    let install = |(cert, key): &(openssl::x509::X509, openssl::pkey::PKey<_>)| {
+        std::fs::write(dir.join("chain.pem"), cert.to_pem().unwrap()).unwrap();
+        std::fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    };
+    let path = dir.join("config.toml");
+    let write_config = |interval: &str| {
+        let contents = format!(
+            r#"bind = "127.0.0.1:0"
+tls_key = "{dir}/key.pem"
+tls_chain = "{dir}/chain.pem"
+ldap_ca = "{dir}/ca.pem"
+ldap_url = "ldaps://localhost:{port}"
+shutdown_grace_secs = 1
+{interval}
+
+["cn=app"]
+"#,
+            dir = dir.display(),
+            port = addr.port()
+        );
+        std::fs::write(&path, contents).unwrap();
+    };
+    // The certificate that the listener serves to a new connection.
+    let served = async |addr: std::net::SocketAddr| {
+        let tcpstream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut connector =
+            openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls_client()).unwrap();
+        connector.set_verify(openssl::ssl::SslVerifyMode::NONE);
+        let ssl = connector
+            .build()
+            .configure()
+            .and_then(|config| config.into_ssl("localhost"))
+            .unwrap();
+        let mut tlsstream = tokio_openssl::SslStream::new(ssl, tcpstream).unwrap();
+        std::pin::Pin::new(&mut tlsstream).connect().await.unwrap();
+        tlsstream
+            .ssl()
+            .peer_certificate()
+            .unwrap()
+            .to_der()
+            .unwrap()
+    };
+
+    let first = common::certificate("localhost", "localhost");
+    let second = common::certificate("localhost", "localhost");
+    let der = |(cert, _): &(openssl::x509::X509, _)| cert.to_der().unwrap();
+
+    // A reload, as on SIGHUP, loads the certificate again.
+    install(&first);
+    write_config("");
+    let server = ProxyBuilder::from_path(&path)
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+    assert_eq!(served(server.local_addr()).await, der(&first));
+    install(&second);
+    assert!(server.reload_from_file());
+    assert_eq!(served(server.local_addr()).await, der(&second));
+    assert_eq!(server.app_state().metrics.get("tls_reloads_total", &[]), 1);
+
+    // A certificate that can't be loaded leaves the current one in place.
+    std::fs::write(dir.join("key.pem"), b"not a key").unwrap();
+    assert!(server.reload_from_file());
+    assert_eq!(served(server.local_addr()).await, der(&second));
+    assert_eq!(server.app_state().metrics.get("tls_reloads_total", &[]), 1);
+    tokio::time::timeout(Duration::from_secs(10), server.shutdown())
+        .await
+        .unwrap();
+
+    // With tls_reload_interval_secs, a change to the files is noticed.
+    install(&first);
+    write_config("tls_reload_interval_secs = 1");
+    let server = ProxyBuilder::from_path(&path)
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+    assert_eq!(served(server.local_addr()).await, der(&first));
+    install(&second);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while server.app_state().metrics.get("tls_reloads_total", &[]) == 0 {
+        assert!(
+            Instant::now() < deadline,
+            "the certificate was never reloaded"
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(served(server.local_addr()).await, der(&second));
+    tokio::time::timeout(Duration::from_secs(10), server.shutdown())
+        .await
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "otlp")]
+#[tokio::test]
+async fn test_otlp_export() {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use ldap_proxy::otlp::{OtlpConfig, OtlpExporter};
+
+    // A collector that answers every request, and reports its path and body.
+    // It is a thread of its own, as flushing the exporter blocks this one.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let collector = listener.local_addr().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { break };
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut stream = stream;
+            loop {
+                let mut request_line = String::new();
+                if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                    break;
+                }
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    let header = header.trim_end();
+                    if header.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let path = request_line.split(' ').nth(1).unwrap_or("").to_string();
+                let _ = tx.send((path, body));
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                    .unwrap();
+            }
+        }
+    });
+
+    let config = OtlpConfig {
+        endpoint: url::Url::parse(&format!("http://{}/v1/traces", collector)).unwrap(),
+        service_name: "ldap-proxy-test".to_string(),
+    };
+    let exporter = OtlpExporter::new(&config).unwrap();
+    let subscriber = tracing_subscriber::registry().with(exporter.layer());
+    let guard = tracing::subscriber::set_default(subscriber);
+
+    let app_state = compare_app_state(true).await;
+    let mut client = common::connect(app_state);
+    assert_eq!(client.bind(1, "cn=radius").await, LdapResultCode::Success);
+    client.send(2, compare_request()).await;
+    client.recv().await.expect("no response");
+    drop(client);
+    drop(guard);
+    exporter.flush().unwrap();
+
+    // The operation and the upstream request that it caused are exported, in
+    // the service of the config.
+    let expected: [&[u8]; 3] = [b"operation", b"upstream", b"ldap-proxy-test"];
+    let contains = |exported: &[u8], needle: &[u8]| {
+        exported
+            .windows(needle.len())
+            .any(|window| window == needle)
+    };
+    let mut exported = Vec::new();
+    while !expected.iter().all(|needle| contains(&exported, needle)) {
+        let (path, body) = rx
+            .recv_timeout(Duration::from_secs(10))
+            .expect("the spans were not exported");
+        assert_eq!(path, "/v1/traces");
+        exported.extend(body);
+    }
+    exporter.shutdown().unwrap();
+}
+
+#[cfg(feature = "rustls")]
+#[tokio::test]
+async fn test_rustls_backend() {
+    let (cert, key) = common::certificate("localhost", "localhost");
+    let backend_pin = SpkiPin::of(&cert).unwrap();
+
+    let mut acceptor =
+        openssl::ssl::SslAcceptor::mozilla_intermediate_v5(openssl::ssl::SslMethod::tls()).unwrap();
+    acceptor.set_certificate(&cert).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    let addr = common::mock_server(
+        acceptor.build(),
+        common::accept_binds(|_| MockAction::Disconnect),
+    )
+    .await;
+
+    let dir = std::env::temp_dir().join(format!("ldap-proxy-rustls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("ca.pem"), cert.to_pem().unwrap()).unwrap();
+    let (other_ca, _) = common::certificate("localhost", "localhost");
+    std::fs::write(dir.join("other.pem"), other_ca.to_pem().unwrap()).unwrap();
+
+    let pools = |ca: &str, extra: &str| {
+        let config = toml::from_str::<Config>(&format!(
+            r#"bind = "127.0.0.1:3636"
+tls_key = "/tmp/key.pem"
+tls_chain = "/tmp/chain.pem"
+ldap_ca = "{}"
+ldap_url = "ldaps://localhost:{}"
+{}
+"#,
+            dir.join(ca).display(),
+            addr.port(),
+            extra
+        ))
+        .unwrap();
+        ldap_proxy::server::build_backend_pools(&config)
+    };
+    let binddn_map = BTreeMap::from([("cn=sssd".to_string(), DnConfig::default())]);
+    let bind = async |ca: &str, extra: &str| {
+        let (_, connector) = common::tls_pair();
+        let mut app_state = common::app_state(addr, connector, binddn_map.clone());
+        app_state.backend_pools = pools(ca, extra).unwrap();
+        let mut client = common::connect(Arc::new(app_state));
+        client.bind(1, "cn=sssd").await
+    };
+
+    assert_eq!(bind("ca.pem", "").await, LdapResultCode::Success);
+    assert_eq!(
+        bind("ca.pem", "ldap_tls_min_version = \"1.3\"").await,
+        LdapResultCode::Success
+    );
+    assert_ne!(bind("other.pem", "").await, LdapResultCode::Success);
+
+    // The certificate doesn't name ldap.example.com, which only matters if
+    // hostnames are verified.
+    let sni = "ldap_tls_sni_name = \"ldap.example.com\"";
+    assert_ne!(bind("ca.pem", sni).await, LdapResultCode::Success);
+    assert_eq!(
+        bind(
+            "ca.pem",
+            &format!("{}\nldap_tls_verify_hostname = false", sni)
+        )
+        .await,
+        LdapResultCode::Success
+    );
+    assert_ne!(
+        bind("other.pem", "ldap_tls_verify_hostname = false").await,
+        LdapResultCode::Success
+    );
+
+    let other_pin = "sha256:".to_string() + &"ab".repeat(32);
+    assert_ne!(
+        bind("ca.pem", &format!("ldap_spki_pins = [\"{}\"]", other_pin)).await,
+        LdapResultCode::Success
+    );
+    assert_eq!(
+        bind(
+            "ca.pem",
+            &format!("ldap_spki_pins = [\"{}\", \"{}\"]", other_pin, backend_pin)
+        )
+        .await,
+        LdapResultCode::Success
+    );
+
+    // What rustls can't do is refused when the pools are built.
+    assert!(pools("ca.pem", "ldap_tls_max_version = \"1.1\"").is_none());
+    assert!(pools("ca.pem", "ldap_tls_cipher_list = \"HIGH\"").is_none());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_listener_certificate() {
+    let dir = std::env::temp_dir().join(format!("ldap-proxy-listener-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let install = |name: &str, (cert, key): &(openssl::x509::X509, openssl::pkey::PKey<_>)| {
+        std::fs::write(
+            dir.join(format!("{}-chain.pem", name)),
+            cert.to_pem().unwrap(),
+        )
+        .unwrap();
+        let key = key.private_key_to_pem_pkcs8().unwrap();
+        std::fs::write(dir.join(format!("{}-key.pem", name)), key).unwrap();
+    };
+    let backend = common::certificate("localhost", "localhost");
+    install("backend", &backend);
+    let mut acceptor =
+        openssl::ssl::SslAcceptor::mozilla_intermediate_v5(openssl::ssl::SslMethod::tls()).unwrap();
+    acceptor.set_certificate(&backend.0).unwrap();
+    acceptor.set_private_key(&backend.1).unwrap();
+    let addr = common::mock_server(
+        acceptor.build(),
+        common::accept_binds(|_| MockAction::Disconnect),
+    )
+    .await;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let listener_addr = listener.local_addr().unwrap();
+    drop(listener);
+    let path = dir.join("config.toml");
+    let write_config = |listener_tls: &str| {
+        let contents = format!(
+            r#"bind = "127.0.0.1:0"
+tls_key = "{dir}/main-key.pem"
+tls_chain = "{dir}/main-chain.pem"
+ldap_ca = "{dir}/backend-chain.pem"
+ldap_url = "ldaps://localhost:{port}"
+shutdown_grace_secs = 1
+listeners = [{{ bind = "{listener_addr}"{listener_tls} }}]
+
+["cn=app"]
+"#,
+            dir = dir.display(),
+            port = addr.port(),
+        );
+        std::fs::write(&path, contents).unwrap();
+    };
+    let served = async |addr: std::net::SocketAddr| {
+        let tcpstream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut connector =
+            openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls_client()).unwrap();
+        connector.set_verify(openssl::ssl::SslVerifyMode::NONE);
+        let ssl = connector
+            .build()
+            .configure()
+            .and_then(|config| config.into_ssl("localhost"))
+            .unwrap();
+        let mut tlsstream = tokio_openssl::SslStream::new(ssl, tcpstream).unwrap();
+        std::pin::Pin::new(&mut tlsstream).connect().await.unwrap();
+        tlsstream
+            .ssl()
+            .peer_certificate()
+            .unwrap()
+            .to_der()
+            .unwrap()
+    };
+    let der = |(cert, _): &(openssl::x509::X509, _)| cert.to_der().unwrap();
+
+    let main = common::certificate("localhost", "localhost");
+    let first = common::certificate("localhost", "localhost");
+    let second = common::certificate("localhost", "localhost");
+    install("main", &main);
+    install("listener", &first);
+
+    // Both or neither of the chain and key are needed.
+    write_config(&format!(
+        ", tls_chain = \"{}/listener-chain.pem\"",
+        dir.display()
+    ));
+    assert!(ProxyBuilder::from_path(&path)
+        .unwrap()
+        .start()
+        .await
+        .is_err());
+
+    write_config(&format!(
+        ", tls_chain = \"{dir}/listener-chain.pem\", tls_key = \"{dir}/listener-key.pem\"",
+        dir = dir.display()
+    ));
+    let server = ProxyBuilder::from_path(&path)
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+    assert_eq!(served(server.local_addr()).await, der(&main));
+    assert_eq!(served(listener_addr).await, der(&first));
+    let mut connector =
+        openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls_client()).unwrap();
+    connector
+        .cert_store_mut()
+        .add_cert(first.0.clone())
+        .unwrap();
+    let mut client = common::connect_ldaps(listener_addr, &connector.build()).await;
+    assert_eq!(client.bind(1, "cn=app").await, LdapResultCode::Success);
+
+    // The listener's certificate is reloaded with the top level one.
+    install("listener", &second);
+    assert!(server.reload_from_file());
+    assert_eq!(served(server.local_addr()).await, der(&main));
+    assert_eq!(served(listener_addr).await, der(&second));
+    assert_eq!(server.app_state().metrics.get("tls_reloads_total", &[]), 2);
+    drop(client);
+    tokio::time::timeout(Duration::from_secs(10), server.shutdown())
+        .await
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_chase_referrals_option() {
+    let config =
+        |extra: &str| toml::from_str::<Config>(&format!("{}\n{}\n", MINIMAL_CONFIG, extra));
+    let mode = |extra: &str| config(extra).unwrap().referral_mode;
+    assert_eq!(mode(""), ReferralMode::Passthrough);
+    assert_eq!(mode("chase_referrals = true"), ReferralMode::Chase);
+    assert_eq!(mode("chase_referrals = false"), ReferralMode::Passthrough);
+    assert_eq!(mode("referral_mode = \"rewrite\""), ReferralMode::Rewrite);
+
+    // The shorthand is the same option, so the two can't both be set.
+    let err = config("chase_referrals = true\nreferral_mode = \"strip\"").unwrap_err();
+    assert!(err.to_string().contains("duplicate field"), "{}", err);
+    assert!(config("referral_mode = \"follow\"").is_err());
 }